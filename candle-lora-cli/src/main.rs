@@ -0,0 +1,356 @@
+//! `candle-lora-convert`: convert PEFT adapters to candle-lora, inspect and compare adapters, and
+//! merge them into base model weights from the command line.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use candle_core::{quantized::gguf_file, DType, Device, Tensor};
+use candle_lora::{
+    convert_peft_dir_to_candle_lora_with_options, convert_peft_to_candle_lora_with_options,
+    load_peft_weights, merge_peft_adapter_with_config, save_gguf, save_torch_state_dict,
+    structural_order, AdapterInfo, ConvertOptions, GgufQuantization, PeftConfig,
+};
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a PEFT adapter directory or safetensors file to the candle-lora format.
+    Convert {
+        /// The PEFT adapter directory, or its `adapter_model.safetensors`.
+        input: String,
+
+        /// The converted safetensors file.
+        output: String,
+
+        #[arg(long, default_value = "lora_llama")]
+        prefix: String,
+
+        /// Write the tensors as f32, f16 or bf16 instead of the dtype of the adapter.
+        #[arg(long)]
+        dtype: Option<DType>,
+
+        /// Fail on the tensors that are not LoRA tensors instead of skipping them.
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Print the config, LoRA modules and tensors of an adapter directory or safetensors file.
+    Inspect {
+        input: String,
+
+        /// Only print the summary, not every tensor.
+        #[arg(long)]
+        summary: bool,
+    },
+    /// Merge a PEFT adapter directory into the base model weights.
+    Merge {
+        /// The base model safetensors files.
+        #[arg(long, required = true, num_args = 1..)]
+        base: Vec<String>,
+
+        /// The PEFT adapter directory, with its `adapter_config.json`.
+        #[arg(long)]
+        adapter: String,
+
+        /// The merged weights, a PyTorch state dict if it ends with `.bin`, GGUF if it ends with
+        /// `.gguf`, safetensors otherwise.
+        #[arg(long)]
+        output: String,
+
+        /// The quantization of a GGUF output, F16, Q8_0 or Q4_K_M.
+        #[arg(long, default_value = "Q8_0")]
+        quantization: GgufQuantization,
+
+        /// A GGUF file of the base model to copy the metadata of a GGUF output from, its
+        /// hyperparameters and tokenizer.
+        #[arg(long)]
+        gguf_metadata: Option<String>,
+    },
+    /// Compare the tensors of two adapter directories or safetensors files.
+    Diff {
+        left: String,
+        right: String,
+
+        /// The largest absolute difference for tensors to count as equal.
+        #[arg(long, default_value_t = 0.)]
+        tolerance: f64,
+    },
+}
+
+fn main() -> Result<()> {
+    match Args::parse().command {
+        Command::Convert {
+            input,
+            output,
+            prefix,
+            dtype,
+            strict,
+        } => convert(&input, &output, &prefix, dtype, strict),
+        Command::Inspect { input, summary } => inspect(&input, summary),
+        Command::Merge {
+            base,
+            adapter,
+            output,
+            quantization,
+            gguf_metadata,
+        } => merge(
+            &base,
+            &adapter,
+            &output,
+            quantization,
+            gguf_metadata.as_deref(),
+        ),
+        Command::Diff {
+            left,
+            right,
+            tolerance,
+        } => diff(&left, &right, tolerance),
+    }
+}
+
+fn convert(
+    input: &str,
+    output: &str,
+    prefix: &str,
+    dtype: Option<DType>,
+    strict: bool,
+) -> Result<()> {
+    let mut options = ConvertOptions::new().with_strict(strict);
+    options.target_dtype = dtype;
+    let report = if Path::new(input).is_dir() {
+        convert_peft_dir_to_candle_lora_with_options(input, output, prefix, &Device::Cpu, &options)?
+    } else {
+        convert_peft_to_candle_lora_with_options(input, output, prefix, &options)?
+    };
+    println!("converted {input} to {output}");
+    println!(
+        "  {} modules of rank {}, {} tensors",
+        report.num_modules, report.rank, report.num_tensors
+    );
+    if let (Some(alpha), Some(scaling)) = (report.alpha, report.scaling) {
+        println!("  lora_alpha {alpha}, scaling {scaling}");
+    }
+    if report.num_magnitudes > 0 {
+        println!("  {} DoRA magnitudes", report.num_magnitudes);
+    }
+    for (pattern, rank) in &report.rank_pattern {
+        println!("  rank {rank} for {pattern}");
+    }
+    for name in &report.modules_to_save {
+        println!("  full weight of {name}");
+    }
+    if !report.skipped.is_empty() {
+        println!("  {} tensors not converted:", report.skipped.len());
+        for name in &report.skipped {
+            println!("    {name}");
+        }
+    }
+    Ok(())
+}
+
+fn inspect(input: &str, summary: bool) -> Result<()> {
+    if let Some(config) = read_config(input)? {
+        println!(
+            "{} adapter of {}",
+            config.peft_type, config.base_model_name_or_path
+        );
+        println!(
+            "  r {}, lora_alpha {}, lora_dropout {}",
+            config.r, config.lora_alpha, config.lora_dropout
+        );
+        if config.use_rslora {
+            println!("  rsLoRA scaling lora_alpha / sqrt(r)");
+        }
+        println!("  target_modules: {}", config.target_modules.join(", "));
+    }
+
+    let path = adapter_file(input)?;
+    let info = AdapterInfo::from_file(&path).with_context(|| format!("cannot read {path}"))?;
+    let ranks = info
+        .ranks()
+        .iter()
+        .map(|(rank, count)| format!("{count} of rank {rank}"))
+        .collect::<Vec<_>>();
+    println!(
+        "{path}: {:?} adapter, {} tensors, {} parameters, {} bytes",
+        info.format, info.num_tensors, info.num_params, info.memory_bytes
+    );
+    if !ranks.is_empty() {
+        println!("  LoRA modules: {}", ranks.join(", "));
+    }
+    for (key, value) in &info.metadata {
+        println!("  {key}: {value}");
+    }
+
+    if !summary {
+        for layer in &info.layers {
+            let index = layer
+                .index
+                .map(|idx| format!("[{idx}]"))
+                .unwrap_or_default();
+            println!(
+                "  {}{index} rank {} A {:?} B {:?} {:?}",
+                layer.name, layer.rank, layer.a_shape, layer.b_shape, layer.dtype
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The safetensors file of an adapter, `input` itself or the weights of the adapter directory.
+fn adapter_file(input: &str) -> Result<String> {
+    if !Path::new(input).is_dir() {
+        return Ok(input.to_string());
+    }
+    ["adapter_model.safetensors", "adapter.safetensors"]
+        .iter()
+        .map(|name| Path::new(input).join(name))
+        .find(|path| path.exists())
+        .map(|path| path.display().to_string())
+        .with_context(|| format!("{input} has no adapter_model.safetensors"))
+}
+
+fn merge(
+    base: &[String],
+    adapter: &str,
+    output: &str,
+    quantization: GgufQuantization,
+    gguf_metadata: Option<&str>,
+) -> Result<()> {
+    let Some(config) = read_config(adapter)? else {
+        bail!("{adapter} has no adapter_config.json")
+    };
+    let mut base_tensors = HashMap::new();
+    for path in base {
+        base_tensors.extend(
+            candle_core::safetensors::load(path, &Device::Cpu)
+                .with_context(|| format!("cannot load {path}"))?,
+        );
+    }
+    let adapter_tensors = load_peft_weights(adapter, &Device::Cpu)?;
+    let merged = merge_peft_adapter_with_config(&base_tensors, &adapter_tensors, &config)?;
+    if output.ends_with(".bin") {
+        save_torch_state_dict(&merged, output)?;
+    } else if output.ends_with(".gguf") {
+        let content = match gguf_metadata {
+            Some(path) => {
+                let mut file =
+                    std::fs::File::open(path).with_context(|| format!("cannot open {path}"))?;
+                Some(
+                    gguf_file::Content::read(&mut file)
+                        .with_context(|| format!("invalid {path}"))?,
+                )
+            }
+            None => None,
+        };
+        let metadata = content
+            .iter()
+            .flat_map(|content| &content.metadata)
+            .map(|(key, value)| (key.as_str(), value))
+            .collect::<Vec<_>>();
+        save_gguf(&merged, output, quantization, &metadata)?;
+    } else {
+        candle_core::safetensors::save(&merged, output)?;
+    }
+    println!(
+        "merged {} LoRA modules into {} base tensors, saved to {output}",
+        adapter_tensors
+            .keys()
+            .filter(|name| is_lora_a(name))
+            .count(),
+        merged.len()
+    );
+    Ok(())
+}
+
+fn diff(left: &str, right: &str, tolerance: f64) -> Result<()> {
+    let left_tensors = load_tensors(left)?;
+    let right_tensors = load_tensors(right)?;
+    let mut num_differences = 0;
+    for (name, tensor) in sorted(&left_tensors) {
+        let Some(other) = right_tensors.get(name) else {
+            println!("- {name} {:?}", tensor.dims());
+            num_differences += 1;
+            continue;
+        };
+        if tensor.dims() != other.dims() || tensor.dtype() != other.dtype() {
+            println!(
+                "~ {name} {:?} {:?} -> {:?} {:?}",
+                tensor.dtype(),
+                tensor.dims(),
+                other.dtype(),
+                other.dims()
+            );
+            num_differences += 1;
+            continue;
+        }
+        let max_diff = (tensor.to_dtype(DType::F64)? - other.to_dtype(DType::F64)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f64>()?;
+        if max_diff > tolerance {
+            println!("~ {name} max abs difference {max_diff:e}");
+            num_differences += 1;
+        }
+    }
+    for (name, tensor) in sorted(&right_tensors) {
+        if !left_tensors.contains_key(name) {
+            println!("+ {name} {:?}", tensor.dims());
+            num_differences += 1;
+        }
+    }
+    if num_differences == 0 {
+        println!("the {} tensors are equal", left_tensors.len());
+    } else {
+        println!("{num_differences} differences");
+    }
+    Ok(())
+}
+
+/// The tensors of an adapter directory, see [`load_peft_weights`], or of a safetensors file.
+fn load_tensors(input: &str) -> Result<HashMap<String, Tensor>> {
+    let tensors = if Path::new(input).is_dir() {
+        load_peft_weights(input, &Device::Cpu)?
+    } else {
+        candle_core::safetensors::load(input, &Device::Cpu)
+            .with_context(|| format!("cannot load {input}"))?
+    };
+    Ok(tensors)
+}
+
+/// The `adapter_config.json` of an adapter directory, if any.
+fn read_config(input: &str) -> Result<Option<PeftConfig>> {
+    let path = Path::new(input).join("adapter_config.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let config = std::fs::read_to_string(&path)?;
+    let config =
+        serde_json::from_str(&config).with_context(|| format!("invalid {}", path.display()))?;
+    Ok(Some(config))
+}
+
+/// Whether `name` is a LoRA A matrix, in the PEFT (`.lora_A.weight`, `.lora_embedding_A`) or
+/// the candle-lora (`.a{idx}.weight`) naming.
+fn is_lora_a(name: &str) -> bool {
+    let name = name.strip_suffix(".weight").unwrap_or(name);
+    name.ends_with(".lora_A")
+        || name.ends_with(".lora_embedding_A")
+        || name.rsplit_once('.').is_some_and(|(_, last)| {
+            last.strip_prefix('a')
+                .is_some_and(|idx| !idx.is_empty() && idx.bytes().all(|b| b.is_ascii_digit()))
+        })
+}
+
+/// The tensors in the structural order of their names.
+fn sorted(tensors: &HashMap<String, Tensor>) -> Vec<(&String, &Tensor)> {
+    let mut tensors = tensors.iter().collect::<Vec<_>>();
+    tensors.sort_by(|a, b| structural_order(a.0, b.0));
+    tensors
+}
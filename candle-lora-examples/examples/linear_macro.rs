@@ -39,14 +39,16 @@ fn main() {
     let vb = VarBuilder::from_varmap(&varmap, dtype, &device);
 
     let loraconfig = LoraConfig::new(1, 1., None);
-    model.get_lora_model(
-        loraconfig,
-        &vb,
-        Some(LoraLinearConfig::new(10, 10)),
-        None,
-        None,
-        None,
-    );
+    model
+        .get_lora_model(
+            loraconfig,
+            &vb,
+            Some(LoraLinearConfig::new(10, 10)),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
 
     let dummy_image = Tensor::zeros((10, 10), DType::F32, &device).unwrap();
 
@@ -11,6 +11,12 @@ enum ModelLayers {
     Layer,
 }
 
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "layer")
+    }
+}
+
 #[derive(Debug)]
 struct Model {
     layer: Box<dyn LinearLayerLike>,
@@ -62,7 +68,7 @@ fn main() {
 
     let loraconfig = LoraConfig::new(1, 1., None);
 
-    let new_layers = Lora::convert_model(selected, loraconfig, &vb);
+    let new_layers = Lora::convert_model(selected, loraconfig, &vb).unwrap();
 
     model.insert_new(new_layers);
 
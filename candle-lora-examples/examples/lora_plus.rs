@@ -0,0 +1,71 @@
+//! LoRA+: train the B matrices with a larger learning rate than the A matrices, here 16 times
+//! larger, with an AdamW optimizer per parameter group of `Lora::lora_param_groups`.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_lora::{
+    LinearLayerLike, Lora, LoraConfig, LoraLinearConfig, NewLayers, SelectedLayersBuilder,
+};
+use candle_nn::{AdamW, Linear, Optimizer, ParamsAdamW, VarBuilder, VarMap};
+
+#[derive(PartialEq, Eq, Hash)]
+enum ModelLayers {
+    Layer,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "layer")
+    }
+}
+
+const LEARNING_RATE: f64 = 1e-2;
+const LORA_PLUS_RATIO: f64 = 16.;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let device = Device::Cpu;
+
+    // A frozen base layer and the layer it should learn to behave like, a rank 4 update away.
+    let base = Linear::new(Tensor::randn(0f32, 1., (16, 32), &device)?, None);
+    let u = Tensor::randn(0f32, 0.5, (16, 4), &device)?;
+    let v = Tensor::randn(0f32, 0.5, (4, 32), &device)?;
+    let target = Linear::new((base.weight() + u.matmul(&v)?)?, None);
+
+    let layers = HashMap::from([(ModelLayers::Layer, &base as &dyn LinearLayerLike)]);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(32, 16))
+        .build();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let new: NewLayers<ModelLayers> =
+        Lora::convert_model(selected, LoraConfig::new(4, 8., None), &vb.pp("lora"))?;
+    let layer = &new.linear[&ModelLayers::Layer];
+
+    let (a, b, magnitudes) = Lora::lora_param_groups(&new)?;
+    let params = |lr| ParamsAdamW {
+        lr,
+        ..Default::default()
+    };
+    let mut optimizers = [
+        AdamW::new(a, params(LEARNING_RATE))?,
+        AdamW::new(b, params(LEARNING_RATE * LORA_PLUS_RATIO))?,
+        AdamW::new(magnitudes, params(LEARNING_RATE))?,
+    ];
+
+    for step in 0..200 {
+        let xs = Tensor::randn(0f32, 1., (8, 32), &device)?;
+        let loss = (layer.forward(&xs)? - target.forward(&xs)?)?
+            .sqr()?
+            .mean_all()?;
+        // The gradients are computed once and each group steps with its own learning rate.
+        let grads = loss.backward()?;
+        for optimizer in &mut optimizers {
+            optimizer.step(&grads)?;
+        }
+        if step % 50 == 0 {
+            println!("step {step}: loss {:.5}", loss.to_scalar::<f32>()?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Migrate a candle-lora adapter file between the legacy index-based and the name-based naming
+//!
+//! Usage:
+//!   migrate_adapter to-named <input> <output> <prefix> [modules.txt]
+//!   migrate_adapter to-indexed <input> <output> <prefix>
+//!
+//! `modules.txt` lists the module of each index, one per line. Without it, the modules recorded
+//! by a previous `to-indexed` migration are used.
+
+use candle_lora::{migrate_adapter_file, Migration};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let migration = match args.as_slice() {
+        [direction, _, _, prefix, rest @ ..] if direction == "to-named" && rest.len() <= 1 => {
+            let modules = match rest.first() {
+                Some(path) => Some(
+                    std::fs::read_to_string(path)?
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(String::from)
+                        .collect(),
+                ),
+                None => None,
+            };
+            Migration::ToNamed {
+                prefix: prefix.clone(),
+                modules,
+            }
+        }
+        [direction, _, _, prefix] if direction == "to-indexed" => Migration::ToIndexed {
+            prefix: prefix.clone(),
+        },
+        _ => {
+            eprintln!("usage: migrate_adapter to-named <input> <output> <prefix> [modules.txt]");
+            eprintln!("       migrate_adapter to-indexed <input> <output> <prefix>");
+            std::process::exit(2);
+        }
+    };
+
+    migrate_adapter_file(&args[1], &args[2], &migration)?;
+    println!("✅ Migrated {} to {}", args[1], args[2]);
+
+    Ok(())
+}
@@ -40,7 +40,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Convert to candle-lora format
     println!("\n🔄 Converting to candle-lora format...");
-    convert_peft_to_candle_lora(peft_path, output_path, "lora_llama", &device)?;
+    convert_peft_to_candle_lora(peft_path, output_path, "lora_llama")?;
 
     // Load and verify the converted file
     println!("\n📋 Verifying converted file...");
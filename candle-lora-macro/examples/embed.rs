@@ -43,14 +43,16 @@ fn main() {
     let vb = VarBuilder::from_varmap(&varmap, dtype, &device);
 
     let loraconfig = LoraConfig::new(1, 1., None);
-    model.get_merged_lora_model(
-        loraconfig,
-        &vb,
-        Some(LoraLinearConfig::new(10, 10)),
-        None,
-        None,
-        Some(LoraEmbeddingConfig::new(10, 10)),
-    );
+    model
+        .get_merged_lora_model(
+            loraconfig,
+            &vb,
+            Some(LoraLinearConfig::new(10, 10)),
+            None,
+            None,
+            Some(LoraEmbeddingConfig::new(10, 10)),
+        )
+        .unwrap();
 
     let dummy_image = Tensor::zeros((10, 10), DType::U32, &device).unwrap();
 
@@ -1,705 +1,442 @@
 use proc_macro::TokenStream as TokenStream1;
 use proc_macro2::TokenStream;
+use quote::quote;
 use syn::{
-    parse::Parser, parse_macro_input, Data, DeriveInput, Fields, GenericArgument, Ident,
-    PathArguments, Type, TypeParamBound, Visibility,
+    parse_macro_input, Data, DeriveInput, Field, Fields, GenericArgument, Ident, PathArguments,
+    Type, TypeParamBound,
 };
 
-#[proc_macro_attribute]
-pub fn replace_layer_fields(_args: TokenStream1, input: TokenStream1) -> TokenStream1 {
-    let mut ast = parse_macro_input!(input as DeriveInput);
-    match &mut ast.data {
-        Data::Struct(ref mut struct_data) => {
-            match &mut struct_data.fields {
-                Fields::Named(fields) => {
-                    for field in fields.named.iter_mut() {
-                        let mut f = None;
-                        let ident = field.ident.clone().unwrap();
-                        let ty = field.ty.clone();
-                        if let Type::Path(path) = ty {
-                            if path.path.segments.len() == 1 {
-                                match path
-                                    .path
-                                    .segments
-                                    .first()
-                                    .unwrap()
-                                    .ident
-                                    .to_string()
-                                    .as_str()
-                                {
-                                    "Linear" => {
-                                        if let Visibility::Public(_) = field.vis {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Arc<dyn LinearLayerLike>)).unwrap());
-                                        } else {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Arc<dyn LinearLayerLike>)).unwrap());
-                                        }
-                                    }
-                                    "Conv1d" => {
-                                        if let Visibility::Public(_) = field.vis {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Arc<dyn Conv1dLayerLike>)).unwrap());
-                                        } else {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Arc<dyn Conv1dLayerLike>)).unwrap());
-                                        }
-                                    }
-                                    "Conv2d" => {
-                                        if let Visibility::Public(_) = field.vis {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Arc<dyn Conv2dLayerLike>)).unwrap());
-                                        } else {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Arc<dyn Conv2dLayerLike>)).unwrap());
-                                        }
-                                    }
-                                    "Embedding" => {
-                                        if let Visibility::Public(_) = field.vis {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Arc<dyn EmbeddingLayerLike>)).unwrap());
-                                        } else {
-                                            f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Arc<dyn EmbeddingLayerLike>)).unwrap());
-                                        }
-                                    }
-                                    "Option" => {
-                                        if let PathArguments::AngleBracketed(bracketed) =
-                                            &path.path.segments.first().unwrap().arguments
-                                        {
-                                            if bracketed.args.len() == 1 {
-                                                if let GenericArgument::Type(Type::Path(tp)) =
-                                                    bracketed.args.first().unwrap()
-                                                {
-                                                    if tp.path.segments.len() == 1 {
-                                                        match tp
-                                                            .path
-                                                            .segments
-                                                            .first()
-                                                            .unwrap()
-                                                            .ident
-                                                            .to_string()
-                                                            .as_str()
-                                                        {
-                                                            "Linear" => {
-                                                                if let Visibility::Public(_) =
-                                                                    field.vis
-                                                                {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Option<Arc<dyn LinearLayerLike>>)).unwrap());
-                                                                } else {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Option<Arc<dyn LinearLayerLike>>)).unwrap());
-                                                                }
-                                                            }
-                                                            "Conv1d" => {
-                                                                if let Visibility::Public(_) =
-                                                                    field.vis
-                                                                {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Option<Arc<dyn Conv1dLayerLike>>)).unwrap());
-                                                                } else {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Option<Arc<dyn Conv1dLayerLike>>)).unwrap());
-                                                                }
-                                                            }
-                                                            "Conv2d" => {
-                                                                if let Visibility::Public(_) =
-                                                                    field.vis
-                                                                {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Option<Arc<dyn Conv2dLayerLike>>)).unwrap());
-                                                                } else {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Option<Arc<dyn Conv2dLayerLike>>)).unwrap());
-                                                                }
-                                                            }
-                                                            "Embedding" => {
-                                                                if let Visibility::Public(_) =
-                                                                    field.vis
-                                                                {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(pub #ident: Option<Arc<dyn EmbeddingLayerLike>>)).unwrap());
-                                                                } else {
-                                                                    f = Some(syn::Field::parse_named.parse2(quote::quote!(#ident: Option<Arc<dyn EmbeddingLayerLike>>)).unwrap());
-                                                                }
-                                                            }
-                                                            _ => {}
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                        if let Some(f) = f {
-                            *field = f;
-                        }
-                    }
-                }
-                _ => {
-                    panic!("Named fields are required.")
-                }
-            }
-        }
-        _ => {
-            panic!("Cannot swap fields of non struct!");
-        }
-    }
-
-    quote::quote!(#ast).into()
+/// The layer types candle-lora converts.
+#[derive(Clone, Copy)]
+enum LayerKind {
+    Linear,
+    Conv1d,
+    Conv2d,
+    Embedding,
 }
 
-fn is_ident(ident: &Ident, name: &str) -> bool {
-    *ident == name
-}
-
-#[proc_macro_derive(AutoLoraConvert)]
-pub fn auto_lora_convert(tokens: TokenStream1) -> TokenStream1 {
-    let ast = parse_macro_input!(tokens as DeriveInput);
-    let mut linear_fields = Vec::new();
-    let mut conv1d_fields = Vec::new();
-    let mut conv2d_fields = Vec::new();
-    let mut embed_fields = Vec::new();
-
-    let mut linear_option1_fields = Vec::new();
-    let mut conv1d_option1_fields = Vec::new();
-    let mut conv2d_option1_fields = Vec::new();
-    let mut embed_option1_fields = Vec::new();
-    let st_name = &ast.ident;
+impl LayerKind {
+    const ALL: [Self; 4] = [Self::Linear, Self::Conv1d, Self::Conv2d, Self::Embedding];
 
-    match ast.data {
-        Data::Struct(st) => {
-            for field in st.fields {
-                match field.ty {
-                    Type::Path(path) => {
-                        let segments = path.path.segments.into_iter().collect::<Vec<_>>();
-                        if segments.len() != 1 {
-                            continue;
-                        }
-                        if is_ident(&segments[0].ident, "Option") {
-                            if let syn::PathArguments::AngleBracketed(bracketed) =
-                                &segments.first().as_ref().unwrap().arguments
-                            {
-                                if bracketed.args.len() != 1 {
-                                    continue;
-                                }
-                                if let GenericArgument::Type(Type::Path(ty)) = &bracketed.args[0] {
-                                    if ty.path.segments.len() != 1 {
-                                        continue;
-                                    }
-                                    let typname = &ty.path.segments.first().unwrap().ident;
-                                    if is_ident(typname, "Arc") {
-                                        if let syn::PathArguments::AngleBracketed(bracketed) =
-                                            &ty.path.segments.first().as_ref().unwrap().arguments
-                                        {
-                                            if bracketed.args.len() != 1 {
-                                                continue;
-                                            }
-                                            match &bracketed.args[0] {
-                                                GenericArgument::Type(Type::TraitObject(trobj)) => {
-                                                    let bounds = &trobj.bounds;
-                                                    if bounds.len() != 1 {
-                                                        continue;
-                                                    }
-                                                    match bounds.first().unwrap() {
-                                                        TypeParamBound::Trait(bound) => {
-                                                            if bound.path.segments.len() != 1 {
-                                                                continue;
-                                                            }
-                                                            let trt = &bound
-                                                                .path
-                                                                .segments
-                                                                .first()
-                                                                .unwrap()
-                                                                .ident;
-                                                            let value = (
-                                                                field.ident.clone().unwrap(),
-                                                                field
-                                                                    .ident
-                                                                    .as_ref()
-                                                                    .unwrap()
-                                                                    .to_string(),
-                                                            );
-                                                            if is_ident(trt, "LinearLayerLike") {
-                                                                linear_option1_fields.push(value);
-                                                            } else if is_ident(
-                                                                trt,
-                                                                "Conv1dLayerLike",
-                                                            ) {
-                                                                conv1d_option1_fields.push(value);
-                                                            } else if is_ident(
-                                                                trt,
-                                                                "Conv2dLayerLike",
-                                                            ) {
-                                                                conv2d_option1_fields.push(value);
-                                                            } else if is_ident(
-                                                                trt,
-                                                                "EmbeddingLayerLike",
-                                                            ) {
-                                                                embed_option1_fields.push(value);
-                                                            }
-                                                        }
-                                                        _ => continue,
-                                                    }
-                                                }
-                                                _ => continue,
-                                            }
-                                        } else {
-                                            continue;
-                                        }
-                                    }
-                                }
-                            }
-                            continue;
-                        }
-                        if !is_ident(&segments[0].ident, "Arc") {
-                            continue;
-                        }
-                        if let syn::PathArguments::AngleBracketed(bracketed) =
-                            &segments.first().as_ref().unwrap().arguments
-                        {
-                            if bracketed.args.len() != 1 {
-                                continue;
-                            }
-                            match &bracketed.args[0] {
-                                GenericArgument::Type(Type::TraitObject(trobj)) => {
-                                    let bounds = &trobj.bounds;
-                                    if bounds.len() != 1 {
-                                        continue;
-                                    }
-                                    match bounds.first().unwrap() {
-                                        TypeParamBound::Trait(bound) => {
-                                            if bound.path.segments.len() != 1 {
-                                                continue;
-                                            }
-                                            let trt = &bound.path.segments.first().unwrap().ident;
-                                            let value = (
-                                                field.ident.clone().unwrap(),
-                                                field.ident.as_ref().unwrap().to_string(),
-                                            );
-                                            if is_ident(trt, "LinearLayerLike") {
-                                                linear_fields.push(value);
-                                            } else if is_ident(trt, "Conv1dLayerLike") {
-                                                conv1d_fields.push(value);
-                                            } else if is_ident(trt, "Conv2dLayerLike") {
-                                                conv2d_fields.push(value);
-                                            } else if is_ident(trt, "EmbeddingLayerLike") {
-                                                embed_fields.push(value);
-                                            }
-                                        }
-                                        _ => continue,
-                                    }
-                                }
-                                _ => continue,
-                            }
-                        } else {
-                            continue;
-                        }
-                    }
-                    _ => continue,
-                }
-            }
-        }
-        _ => {
-            todo!()
+    /// The concrete candle-nn type.
+    fn concrete(self) -> &'static str {
+        match self {
+            Self::Linear => "Linear",
+            Self::Conv1d => "Conv1d",
+            Self::Conv2d => "Conv2d",
+            Self::Embedding => "Embedding",
         }
     }
 
-    let mut linear_stream = TokenStream::new();
-    if !linear_fields.is_empty() {
-        quote_into::quote_into!(linear_stream += [#{
-            for (namei,name) in linear_fields.iter() {
-                quote_into::quote_into!(linear_stream += (linear.insert(#name.to_string(), &*self.#namei)),)
-            }
-        }];);
+    /// The trait of the `dyn` type the concrete type is replaced with.
+    fn layer_trait(self) -> &'static str {
+        match self {
+            Self::Linear => "LinearLayerLike",
+            Self::Conv1d => "Conv1dLayerLike",
+            Self::Conv2d => "Conv2dLayerLike",
+            Self::Embedding => "EmbeddingLayerLike",
+        }
     }
 
-    let mut linear_get = TokenStream::new();
-    if !linear_fields.is_empty() {
-        quote_into::quote_into!(linear_get += [#{
-            for (namei,_) in linear_fields.iter() {
-                quote_into::quote_into!(linear_get += (self.#namei.get_tensors(&mut output)),)
-            }
-        }];);
+    /// The map of the layers of this kind, in the generated methods and in `NewLayers`.
+    fn map(self) -> Ident {
+        let name = match self {
+            Self::Linear => "linear",
+            Self::Conv1d => "conv1d",
+            Self::Conv2d => "conv2d",
+            Self::Embedding => "embed",
+        };
+        Ident::new(name, proc_macro2::Span::call_site())
     }
+}
 
-    let mut conv1d_stream = TokenStream::new();
-    if !conv1d_fields.is_empty() {
-        quote_into::quote_into!(conv1d_stream += [#{
-            for (namei,name) in conv1d_fields.iter() {
-                quote_into::quote_into!(conv1d_stream += (conv1d.insert(#name.to_string(), &*self.#namei)),)
-            }
-        }];);
+/// The single generic type argument of `ty` if it is `wrapper<T>`.
+fn generic_of<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    if path.path.segments.len() != 1 {
+        return None;
     }
-
-    let mut conv1d_get = TokenStream::new();
-    if !conv1d_fields.is_empty() {
-        quote_into::quote_into!(conv1d_get += [#{
-            for (namei,_) in conv1d_fields.iter() {
-                quote_into::quote_into!(conv1d_get += (self.#namei.get_tensors(&mut output)),)
-            }
-        }];);
+    let segment = path.path.segments.first().unwrap();
+    if segment.ident != wrapper {
+        return None;
     }
-
-    let mut conv2d_stream = TokenStream::new();
-    if !conv2d_fields.is_empty() {
-        quote_into::quote_into!(conv2d_stream += [#{
-            for (namei,name) in conv2d_fields.iter() {
-                quote_into::quote_into!(conv2d_stream += (conv2d.insert(#name.to_string(), &*self.#namei)),)
-            }
-        }];);
+    let PathArguments::AngleBracketed(bracketed) = &segment.arguments else {
+        return None;
+    };
+    match bracketed.args.first() {
+        Some(GenericArgument::Type(ty)) if bracketed.args.len() == 1 => Some(ty),
+        _ => None,
     }
+}
 
-    let mut conv2d_get = TokenStream::new();
-    if !conv2d_fields.is_empty() {
-        quote_into::quote_into!(conv2d_get += [#{
-            for (namei,_) in conv2d_fields.iter() {
-                quote_into::quote_into!(conv2d_get += (self.#namei.get_tensors(&mut output)),)
-            }
-        }];);
+/// The kind of the concrete layer type `ty`, e.g. `Linear`.
+fn concrete_layer(ty: &Type) -> Option<LayerKind> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    if path.path.segments.len() != 1 {
+        return None;
     }
+    let ident = &path.path.segments.first().unwrap().ident;
+    LayerKind::ALL
+        .into_iter()
+        .find(|kind| ident == kind.concrete())
+}
 
-    let mut embed_stream = TokenStream::new();
-    if !embed_fields.is_empty() {
-        quote_into::quote_into!(embed_stream += [#{
-            for (namei,name) in embed_fields.iter() {
-                quote_into::quote_into!(embed_stream += (embed.insert(#name.to_string(), &*self.#namei)),)
-            }
-        }];);
+/// The kind of the layer type `ty` if it is `Arc<dyn ...LayerLike>`.
+fn dyn_layer(ty: &Type) -> Option<LayerKind> {
+    let Some(Type::TraitObject(trobj)) = generic_of(ty, "Arc") else {
+        return None;
+    };
+    if trobj.bounds.len() != 1 {
+        return None;
     }
-
-    let mut embed_get = TokenStream::new();
-    if !embed_fields.is_empty() {
-        quote_into::quote_into!(embed_get += [#{
-            for (namei,_) in embed_fields.iter() {
-                quote_into::quote_into!(embed_get += (self.#namei.get_tensors(&mut output)),)
-            }
-        }];);
+    let TypeParamBound::Trait(bound) = trobj.bounds.first().unwrap() else {
+        return None;
+    };
+    if bound.path.segments.len() != 1 {
+        return None;
     }
+    let trt = &bound.path.segments.first().unwrap().ident;
+    LayerKind::ALL
+        .into_iter()
+        .find(|kind| trt == kind.layer_trait())
+}
 
-    let mut linear_stream_assign = TokenStream::new();
-    if !linear_fields.is_empty() {
-        quote_into::quote_into!(linear_stream_assign += [#{
-            for (name, n) in linear_fields.iter() {
-                linear_stream_assign.extend(quote::quote!((self.#name = ::std::sync::Arc::new(new_layers.linear.get(#n).unwrap().clone())),))
-            }
-        }];);
-    }
+/// The `dyn` type replacing the concrete layer type `ty`, within its `Option` or `Vec`.
+fn replaced_type(ty: &Type) -> Option<Type> {
+    let layer = |kind: LayerKind| {
+        let trt = Ident::new(kind.layer_trait(), proc_macro2::Span::call_site());
+        quote!(Arc<dyn #trt>)
+    };
+    let replaced = if let Some(kind) = concrete_layer(ty) {
+        layer(kind)
+    } else if let Some(kind) = generic_of(ty, "Option").and_then(concrete_layer) {
+        let layer = layer(kind);
+        quote!(Option<#layer>)
+    } else if let Some(kind) = generic_of(ty, "Vec").and_then(concrete_layer) {
+        let layer = layer(kind);
+        quote!(Vec<#layer>)
+    } else {
+        return None;
+    };
+    Some(syn::parse2(replaced).unwrap())
+}
 
-    let mut linear_merge_stream_assign = TokenStream::new();
-    if !linear_fields.is_empty() {
-        quote_into::quote_into!(linear_merge_stream_assign += [#{
-            for (name, n) in linear_fields.iter() {
-                linear_merge_stream_assign.extend(quote::quote!(({
-                    (new_layers.linear.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for linear.");
-                    self.#name = ::std::sync::Arc::new(new_layers.linear.get(#n).unwrap().clone())
-                }),))
+#[proc_macro_attribute]
+pub fn replace_layer_fields(_args: TokenStream1, input: TokenStream1) -> TokenStream1 {
+    let mut ast = parse_macro_input!(input as DeriveInput);
+    match &mut ast.data {
+        Data::Struct(ref mut struct_data) => match &mut struct_data.fields {
+            Fields::Named(fields) => {
+                for field in fields.named.iter_mut() {
+                    if let Some(ty) = replaced_type(&field.ty) {
+                        field.ty = ty;
+                    }
+                }
             }
-        }];);
-    }
-
-    let mut conv1d_stream_assign = TokenStream::new();
-    if !conv1d_fields.is_empty() {
-        quote_into::quote_into!(conv1d_stream_assign += [#{
-            for (name, n) in conv1d_fields.iter() {
-                conv1d_stream_assign.extend(quote::quote!((self.#name = ::std::sync::Arc::new(new_layers.conv1d.get(#n).unwrap().clone())),))
+            _ => {
+                panic!("Named fields are required.")
             }
-        }];);
+        },
+        _ => {
+            panic!("Cannot swap fields of non struct!");
+        }
     }
 
-    let mut conv1d_merge_stream_assign = TokenStream::new();
-    if !conv1d_fields.is_empty() {
-        quote_into::quote_into!(conv1d_merge_stream_assign += [#{
-            for (name, n) in conv1d_fields.iter() {
-                conv1d_merge_stream_assign.extend(quote::quote!(({
-                    (new_layers.conv1d.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for conv1d.");
-                    self.#name = ::std::sync::Arc::new(new_layers.conv1d.get(#n).unwrap().clone())
-                }),))
-            }
-        }];);
-    }
+    quote!(#ast).into()
+}
 
-    let mut conv2d_stream_assign = TokenStream::new();
-    if !conv2d_fields.is_empty() {
-        quote_into::quote_into!(conv2d_stream_assign += [#{
-            for (name, n) in conv2d_fields.iter() {
-                conv2d_stream_assign.extend(quote::quote!((self.#name = ::std::sync::Arc::new(new_layers.conv2d.get(#n).unwrap().clone())),))
-            }
-        }];);
-    }
+/// How a field of a model holds layers to convert.
+enum FieldLayers {
+    Layer(LayerKind),
+    OptionLayer(LayerKind),
+    VecLayer(LayerKind),
+    /// A struct deriving `AutoLoraConvert`, marked with `#[lora(nested)]`.
+    Nested,
+    OptionNested,
+    VecNested,
+}
 
-    let mut conv2d_merge_stream_assign = TokenStream::new();
-    if !conv2d_fields.is_empty() {
-        quote_into::quote_into!(conv2d_merge_stream_assign += [#{
-            for (name, n) in conv2d_fields.iter() {
-                conv2d_merge_stream_assign.extend(quote::quote!(({
-                    (new_layers.conv2d.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for conv2d.");
-                    self.#name = ::std::sync::Arc::new(new_layers.conv2d.get(#n).unwrap().clone())
-                }),))
-            }
-        }];);
+/// Whether `field` is marked with `#[lora(nested)]`.
+fn is_nested(field: &Field) -> syn::Result<bool> {
+    let mut nested = false;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("lora"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("nested") {
+                nested = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `nested`"))
+            }
+        })?;
     }
+    Ok(nested)
+}
 
-    let mut embed_stream_assign = TokenStream::new();
-    if !embed_fields.is_empty() {
-        quote_into::quote_into!(embed_stream_assign += [#{
-            for (name, n) in embed_fields.iter() {
-                embed_stream_assign.extend(quote::quote!((self.#name = ::std::sync::Arc::new(new_layers.embed.get(#n).unwrap().clone())),))
-            }
-        }];);
+fn field_layers(field: &Field) -> syn::Result<Option<FieldLayers>> {
+    let ty = &field.ty;
+    if is_nested(field)? {
+        return Ok(Some(if generic_of(ty, "Option").is_some() {
+            FieldLayers::OptionNested
+        } else if generic_of(ty, "Vec").is_some() {
+            FieldLayers::VecNested
+        } else {
+            FieldLayers::Nested
+        }));
     }
-
-    let mut embed_merge_stream_assign = TokenStream::new();
-    if !embed_fields.is_empty() {
-        quote_into::quote_into!(embed_merge_stream_assign += [#{
-            for (name, n) in embed_fields.iter() {
-                embed_merge_stream_assign.extend(quote::quote!(({
-                    (new_layers.embed.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for embed.");
-                    self.#name = ::std::sync::Arc::new(new_layers.embed.get(#n).unwrap().clone())
-                }),))
-            }
-        }];);
+    // A concrete layer type is left by a `replace_layer_fields` placed after the derive, which
+    // would convert nothing.
+    if replaced_type(ty).is_some() {
+        return Err(syn::Error::new_spanned(
+            ty,
+            "place #[replace_layer_fields] before #[derive(AutoLoraConvert)], or make the layer an \
+             `Arc<dyn ...LayerLike>`",
+        ));
     }
+    // Other fields, e.g. `Option<Box<dyn Module>>`, are opaque and left as they are.
+    Ok(if let Some(kind) = dyn_layer(ty) {
+        Some(FieldLayers::Layer(kind))
+    } else if let Some(kind) = generic_of(ty, "Option").and_then(dyn_layer) {
+        Some(FieldLayers::OptionLayer(kind))
+    } else {
+        generic_of(ty, "Vec")
+            .and_then(dyn_layer)
+            .map(FieldLayers::VecLayer)
+    })
+}
 
-    let mut linear_option1_stream = TokenStream::new();
-    if !linear_option1_fields.is_empty() {
-        quote_into::quote_into!(linear_option1_stream += [#{
-            for (namei,name) in linear_option1_fields.iter() {
-                quote_into::quote_into!(linear_option1_stream += (linear.insert(#name.to_string(), self.#namei.as_deref().unwrap())),)
-            }
-        }];);
+/// The statements inserting the layers of the field `ident` into the maps of
+/// `collect_lora_layers`, named after their PEFT module path under `prefix`.
+fn collect_stream(ident: &Ident, layers: &FieldLayers) -> TokenStream {
+    let name = ident.to_string();
+    let nested_args = quote!(linear, conv1d, conv2d, embed);
+    match layers {
+        FieldLayers::Layer(kind) => {
+            let map = kind.map();
+            quote!(#map.insert(format!("{}{}", prefix, #name), &*self.#ident);)
+        }
+        FieldLayers::OptionLayer(kind) => {
+            let map = kind.map();
+            quote!(if let Some(layer) = self.#ident.as_deref() {
+                #map.insert(format!("{}{}", prefix, #name), layer);
+            })
+        }
+        FieldLayers::VecLayer(kind) => {
+            let map = kind.map();
+            quote!(for (i, layer) in self.#ident.iter().enumerate() {
+                #map.insert(format!("{}{}.{}", prefix, #name, i), &**layer);
+            })
+        }
+        FieldLayers::Nested => quote!(
+            self.#ident.collect_lora_layers(&format!("{}{}.", prefix, #name), #nested_args);
+        ),
+        FieldLayers::OptionNested => quote!(if let Some(inner) = &self.#ident {
+            inner.collect_lora_layers(&format!("{}{}.", prefix, #name), #nested_args);
+        }),
+        FieldLayers::VecNested => quote!(for (i, inner) in self.#ident.iter().enumerate() {
+            inner.collect_lora_layers(&format!("{}{}.{}.", prefix, #name, i), #nested_args);
+        }),
     }
+}
 
-    let mut conv1d_option1_stream = TokenStream::new();
-    if !conv1d_option1_fields.is_empty() {
-        quote_into::quote_into!(conv1d_option1_stream += [#{
-            for (namei,name) in conv1d_option1_fields.iter() {
-                quote_into::quote_into!(conv1d_option1_stream += (conv1d.insert(#name.to_string(), self.#namei.as_deref().unwrap())),)
-            }
-        }];);
+/// The statements replacing the layers of the field `ident` with the converted layers of
+/// `new_layers`, merging them if `merge`.
+fn assign_stream(ident: &Ident, layers: &FieldLayers) -> TokenStream {
+    let name = ident.to_string();
+    let converted = |kind: &LayerKind, key: TokenStream, assign: TokenStream| {
+        let map = kind.map();
+        quote!(if let Some(layer) = new_layers.#map.get_mut(&#key) {
+            if merge {
+                candle_lora::Merge::merge_weights(layer)
+                    .map_err(|e| e.either(|e| candle_core::Error::Msg(e.to_string()), |e| e))?;
+            }
+            #assign
+        })
+    };
+    match layers {
+        FieldLayers::Layer(kind) => converted(
+            kind,
+            quote!(format!("{}{}", prefix, #name)),
+            quote!(self.#ident = ::std::sync::Arc::new(layer.clone());),
+        ),
+        FieldLayers::OptionLayer(kind) => {
+            let assign = converted(
+                kind,
+                quote!(format!("{}{}", prefix, #name)),
+                quote!(self.#ident = ::std::option::Option::Some(::std::sync::Arc::new(layer.clone()));),
+            );
+            quote!(if self.#ident.is_some() { #assign })
+        }
+        FieldLayers::VecLayer(kind) => {
+            let assign = converted(
+                kind,
+                quote!(format!("{}{}.{}", prefix, #name, i)),
+                quote!(*slot = ::std::sync::Arc::new(layer.clone());),
+            );
+            quote!(for (i, slot) in self.#ident.iter_mut().enumerate() { #assign })
+        }
+        FieldLayers::Nested => quote!(
+            self.#ident.assign_lora_layers(&format!("{}{}.", prefix, #name), new_layers, merge)?;
+        ),
+        FieldLayers::OptionNested => quote!(if let Some(inner) = &mut self.#ident {
+            inner.assign_lora_layers(&format!("{}{}.", prefix, #name), new_layers, merge)?;
+        }),
+        FieldLayers::VecNested => quote!(for (i, inner) in self.#ident.iter_mut().enumerate() {
+            inner.assign_lora_layers(&format!("{}{}.{}.", prefix, #name, i), new_layers, merge)?;
+        }),
     }
+}
 
-    let mut conv2d_option1_stream = TokenStream::new();
-    if !conv2d_option1_fields.is_empty() {
-        quote_into::quote_into!(conv2d_option1_stream += [#{
-            for (namei,name) in conv2d_option1_fields.iter() {
-                quote_into::quote_into!(conv2d_option1_stream += (conv2d.insert(#name.to_string(), self.#namei.as_deref().unwrap())),)
-            }
-        }];);
+/// The statements adding the adapter tensors of the layers of the field `ident` to `output`.
+fn tensors_stream(ident: &Ident, layers: &FieldLayers) -> TokenStream {
+    match layers {
+        FieldLayers::Layer(_) => {
+            quote!(candle_lora::Saveable::get_tensors(&*self.#ident, output)?;)
+        }
+        FieldLayers::OptionLayer(_) => quote!(if let Some(layer) = self.#ident.as_deref() {
+            candle_lora::Saveable::get_tensors(layer, output)?;
+        }),
+        FieldLayers::VecLayer(_) => quote!(for layer in self.#ident.iter() {
+            candle_lora::Saveable::get_tensors(&**layer, output)?;
+        }),
+        FieldLayers::Nested => quote!(self.#ident.collect_lora_tensors(output)?;),
+        FieldLayers::OptionNested => quote!(if let Some(inner) = &self.#ident {
+            inner.collect_lora_tensors(output)?;
+        }),
+        FieldLayers::VecNested => quote!(for inner in self.#ident.iter() {
+            inner.collect_lora_tensors(output)?;
+        }),
     }
+}
 
-    let mut embed_option1_stream = TokenStream::new();
-    if !embed_option1_fields.is_empty() {
-        quote_into::quote_into!(embed_option1_stream += [#{
-            for (namei,name) in embed_option1_fields.iter() {
-                quote_into::quote_into!(embed_option1_stream += (embed.insert(#name.to_string(), self.#namei.as_deref().unwrap())),)
-            }
-        }];);
+/// Derive `get_lora_model`, `get_merged_lora_model` and `get_tensors` for a model whose layers
+/// are `Arc<dyn ...LayerLike>` fields, or `Option`s or `Vec`s of them, see
+/// [`macro@replace_layer_fields`]. The conversion methods return an error when a layer cannot be
+/// converted or merged, or a layer type has no configuration.
+///
+/// A field of a concrete layer type, e.g. `Linear`, is a compile error: it is what the derive
+/// sees when `#[replace_layer_fields]` is placed after it, and would never be converted.
+///
+/// Fields marked with `#[lora(nested)]` hold structs which also derive `AutoLoraConvert`, or
+/// `Option`s or `Vec`s of them, whose layers are converted with the model. The layers are named
+/// after their path in the model like PEFT modules, e.g. `layers.0.self_attn.q_proj` for the
+/// field `q_proj` of the nested field `self_attn` of the first element of the nested field
+/// `layers`, which is what the module patterns and layer ranges of a `TargetSpec` match.
+#[proc_macro_derive(AutoLoraConvert, attributes(lora))]
+pub fn auto_lora_convert(tokens: TokenStream1) -> TokenStream1 {
+    let ast = parse_macro_input!(tokens as DeriveInput);
+    let st_name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let Data::Struct(st) = &ast.data else {
+        panic!("Cannot derive AutoLoraConvert for a non struct!");
+    };
+    let mut collect = TokenStream::new();
+    let mut assign = TokenStream::new();
+    let mut tensors = TokenStream::new();
+    for field in st.fields.iter() {
+        let Some(ident) = &field.ident else {
+            continue;
+        };
+        match field_layers(field) {
+            Ok(Some(layers)) => {
+                collect.extend(collect_stream(ident, &layers));
+                assign.extend(assign_stream(ident, &layers));
+                tensors.extend(tensors_stream(ident, &layers));
+            }
+            Ok(None) => {}
+            Err(e) => return e.to_compile_error().into(),
+        }
     }
 
-    let mut linear_option1_stream_assign = TokenStream::new();
-    if !linear_option1_fields.is_empty() {
-        quote_into::quote_into!(linear_option1_stream_assign += [#{
-            for (name, n) in linear_option1_fields.iter() {
-                linear_option1_stream_assign.extend(quote::quote!((self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.linear.get(#n).unwrap().clone()))),))
-            }
-        }];);
-    }
+    let convert = quote! {
+        let mut linear: ::std::collections::HashMap<String, &dyn candle_lora::LinearLayerLike> = ::std::collections::HashMap::new();
+        let mut conv1d: ::std::collections::HashMap<String, &dyn candle_lora::Conv1dLayerLike> = ::std::collections::HashMap::new();
+        let mut conv2d: ::std::collections::HashMap<String, &dyn candle_lora::Conv2dLayerLike> = ::std::collections::HashMap::new();
+        let mut embed: ::std::collections::HashMap<String, &dyn candle_lora::EmbeddingLayerLike> = ::std::collections::HashMap::new();
 
-    let mut linear_merge_option1_stream_assign = TokenStream::new();
-    if !linear_option1_fields.is_empty() {
-        quote_into::quote_into!(linear_merge_option1_stream_assign += [#{
-            for (name, n) in linear_option1_fields.iter() {
-                linear_merge_option1_stream_assign.extend(quote::quote!(({
-                    (new_layers.linear.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for option linear.");
-                    self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.linear.get(#n).unwrap().clone()))
-                }),))
-            }
-        }];);
-    }
+        self.collect_lora_layers("", &mut linear, &mut conv1d, &mut conv2d, &mut embed);
 
-    let mut conv1d_option1_stream_assign = TokenStream::new();
-    if !conv1d_option1_fields.is_empty() {
-        quote_into::quote_into!(conv1d_option1_stream_assign += [#{
-            for (name, n) in conv1d_option1_fields.iter() {
-                conv1d_option1_stream_assign.extend(quote::quote!((self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.conv1d.get(#n).unwrap().clone()))),))
-            }
-        }];);
-    }
+        if !linear.is_empty() && linear_config.is_none() {
+            candle_core::bail!("Config not specified for linear layers.");
+        }
+        if !conv1d.is_empty() && conv1d_config.is_none() {
+            candle_core::bail!("Config not specified for conv1d layers.");
+        }
+        if !conv2d.is_empty() && conv2d_config.is_none() {
+            candle_core::bail!("Config not specified for conv2d layers.");
+        }
+        if !embed.is_empty() && embed_config.is_none() {
+            candle_core::bail!("Config not specified for embedding layers.");
+        }
 
-    let mut conv1d_merge_option1_stream_assign = TokenStream::new();
-    if !conv1d_option1_fields.is_empty() {
-        quote_into::quote_into!(conv1d_merge_option1_stream_assign += [#{
-            for (name, n) in conv1d_option1_fields.iter() {
-                conv1d_merge_option1_stream_assign.extend(quote::quote!(({
-                    (new_layers.conv1d.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for option conv1d.");
-                    self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.conv1d.get(#n).unwrap().clone()))
-                }),))
-            }
-        }];);
-    }
+        let mut builder = candle_lora::SelectedLayersBuilder::new();
+        if let Some(linear_config) = linear_config {
+            builder = builder.add_linear_layers(linear, linear_config);
+        }
+        if let Some(conv1d_config) = conv1d_config {
+            builder = builder.add_conv1d_layers(conv1d, conv1d_config);
+        }
+        if let Some(conv2d_config) = conv2d_config {
+            builder = builder.add_conv2d_layers(conv2d, conv2d_config);
+        }
+        if let Some(embed_config) = embed_config {
+            builder = builder.add_embed_layers(embed, embed_config);
+        }
+        let selection = builder.build();
 
-    let mut conv2d_option1_stream_assign = TokenStream::new();
-    if !conv2d_option1_fields.is_empty() {
-        quote_into::quote_into!(conv2d_option1_stream_assign += [#{
-            for (name, n) in conv2d_option1_fields.iter() {
-                conv2d_option1_stream_assign.extend(quote::quote!((self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.conv2d.get(#n).unwrap().clone()))),))
-            }
-        }];);
-    }
+        let mut new_layers = candle_lora::Lora::convert_model(selection, lora_config, &vb)?;
+    };
 
-    let mut conv2d_merge_option1_stream_assign = TokenStream::new();
-    if !conv2d_option1_fields.is_empty() {
-        quote_into::quote_into!(conv2d_merge_option1_stream_assign += [#{
-            for (name, n) in conv2d_option1_fields.iter() {
-                conv2d_merge_option1_stream_assign.extend(quote::quote!(({
-                    (new_layers.conv2d.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for option conv2d.");
-                    self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.conv2d.get(#n).unwrap().clone()))
-                }),))
+    quote! {
+        impl #impl_generics #st_name #ty_generics #where_clause {
+            /// Be sure to provide a configuration for each type!
+            pub fn get_lora_model<'a>(&'a mut self, lora_config: candle_lora::LoraConfig, vb: &candle_nn::VarBuilder, linear_config: Option<candle_lora::LoraLinearConfig>, conv1d_config: Option<candle_lora::LoraConv1dConfig>, conv2d_config: Option<candle_lora::LoraConv2dConfig>, embed_config: Option<candle_lora::LoraEmbeddingConfig>) -> candle_core::Result<()> {
+                #convert
+                self.assign_lora_layers("", &mut new_layers, false)
             }
-        }];);
-    }
 
-    let mut embed_option1_stream_assign = TokenStream::new();
-    if !embed_option1_fields.is_empty() {
-        quote_into::quote_into!(embed_option1_stream_assign += [#{
-            for (name, n) in embed_option1_fields.iter() {
-                embed_option1_stream_assign.extend(quote::quote!((self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.embed.get(#n).unwrap().clone()))),))
+            /// Be sure to provide a configuration for each type!
+            pub fn get_merged_lora_model<'a>(&'a mut self, lora_config: candle_lora::LoraConfig, vb: &candle_nn::VarBuilder, linear_config: Option<candle_lora::LoraLinearConfig>, conv1d_config: Option<candle_lora::LoraConv1dConfig>, conv2d_config: Option<candle_lora::LoraConv2dConfig>, embed_config: Option<candle_lora::LoraEmbeddingConfig>) -> candle_core::Result<()> {
+                #convert
+                self.assign_lora_layers("", &mut new_layers, true)
             }
-        }];);
-    }
 
-    let mut embed_merge_option1_stream_assign = TokenStream::new();
-    if !embed_option1_fields.is_empty() {
-        quote_into::quote_into!(embed_merge_option1_stream_assign += [#{
-            for (name, n) in embed_option1_fields.iter() {
-                embed_merge_option1_stream_assign.extend(quote::quote!(({
-                    (new_layers.embed.get_mut(#n).unwrap().clone()).merge_weights().expect("Merge failed for option embed.");
-                    self.#name = ::std::option::Option::Some(::std::sync::Arc::new(new_layers.embed.get(#n).unwrap().clone()))
-                }),))
+            pub fn get_tensors(&self) -> candle_core::Result<::std::collections::HashMap<String, Tensor>> {
+                let mut output = ::std::collections::HashMap::new();
+                self.collect_lora_tensors(&mut output)?;
+                Ok(output)
             }
-        }];);
-    }
-
-    let mut stream = TokenStream::new();
-    quote_into::quote_into! { stream +=
-        impl #st_name {
-            /// Be sure to provide a configuration for each type!
-            pub fn get_lora_model<'a>(&'a mut self, lora_config: candle_lora::LoraConfig, vb: &candle_nn::VarBuilder, linear_config: Option<candle_lora::LoraLinearConfig>, conv1d_config: Option<candle_lora::LoraConv1dConfig>, conv2d_config: Option<candle_lora::LoraConv2dConfig>, embed_config: Option<candle_lora::LoraEmbeddingConfig>) {
-                let mut linear: ::std::collections::HashMap<String, &dyn candle_lora::LinearLayerLike> = ::std::collections::HashMap::new();
-                let mut conv1d: ::std::collections::HashMap<String, &dyn candle_lora::Conv1dLayerLike> = ::std::collections::HashMap::new();
-                let mut conv2d: ::std::collections::HashMap<String, &dyn candle_lora::Conv2dLayerLike> = ::std::collections::HashMap::new();
-                let mut embed: ::std::collections::HashMap<String, &dyn candle_lora::EmbeddingLayerLike> = ::std::collections::HashMap::new();
-
-                #linear_stream
-                #conv1d_stream
-                #conv2d_stream
-                #embed_stream
-
-                #linear_option1_stream
-                #conv1d_option1_stream
-                #conv2d_option1_stream
-                #embed_option1_stream
-
-                if !linear.is_empty() && linear_config.is_none() {
-                    panic!("Config not specified for linear layers.");
-                }
-                if !conv1d.is_empty() && conv1d_config.is_none() {
-                    panic!("Config not specified for conv1d layers.");
-                }
-                if !conv2d.is_empty() && conv2d_config.is_none() {
-                    panic!("Config not specified for conv2d layers.");
-                }
-                if !embed.is_empty() && embed_config.is_none() {
-                    panic!("Config not specified for embedding layers.");
-                }
 
-                let mut builder = candle_lora::SelectedLayersBuilder::new();
-                if linear_config.is_some() {
-                    builder = builder.add_linear_layers(linear, linear_config.unwrap());
-                }
-                if conv1d_config.is_some() {
-                    builder = builder.add_conv1d_layers(conv1d, conv1d_config.unwrap());
-                }
-                if conv2d_config.is_some() {
-                    builder = builder.add_conv2d_layers(conv2d, conv2d_config.unwrap());
-                }
-                if embed_config.is_some() {
-                    builder = builder.add_embed_layers(embed, embed_config.unwrap());
-                }
-                let selection = builder.build();
-
-                let new_layers = candle_lora::Lora::convert_model(selection, lora_config, &vb);
-
-                #linear_stream_assign
-                #conv1d_stream_assign
-                #conv2d_stream_assign
-                #embed_stream_assign
-
-                #linear_option1_stream_assign
-                #conv1d_option1_stream_assign
-                #conv2d_option1_stream_assign
-                #embed_option1_stream_assign
+            /// Add the layers of the model to the maps, named after their path under `prefix`.
+            #[doc(hidden)]
+            #[allow(unused_variables)]
+            pub fn collect_lora_layers<'a>(&'a self, prefix: &str, linear: &mut ::std::collections::HashMap<String, &'a dyn candle_lora::LinearLayerLike>, conv1d: &mut ::std::collections::HashMap<String, &'a dyn candle_lora::Conv1dLayerLike>, conv2d: &mut ::std::collections::HashMap<String, &'a dyn candle_lora::Conv2dLayerLike>, embed: &mut ::std::collections::HashMap<String, &'a dyn candle_lora::EmbeddingLayerLike>) {
+                #collect
             }
 
-            /// Be sure to provide a configuration for each type!
-            pub fn get_merged_lora_model<'a>(&'a mut self, lora_config: candle_lora::LoraConfig, vb: &candle_nn::VarBuilder, linear_config: Option<candle_lora::LoraLinearConfig>, conv1d_config: Option<candle_lora::LoraConv1dConfig>, conv2d_config: Option<candle_lora::LoraConv2dConfig>, embed_config: Option<candle_lora::LoraEmbeddingConfig>) {
-                use candle_lora::Merge;
-                let mut linear: ::std::collections::HashMap<String, &dyn candle_lora::LinearLayerLike> = ::std::collections::HashMap::new();
-                let mut conv1d: ::std::collections::HashMap<String, &dyn candle_lora::Conv1dLayerLike> = ::std::collections::HashMap::new();
-                let mut conv2d: ::std::collections::HashMap<String, &dyn candle_lora::Conv2dLayerLike> = ::std::collections::HashMap::new();
-                let mut embed: ::std::collections::HashMap<String, &dyn candle_lora::EmbeddingLayerLike> = ::std::collections::HashMap::new();
-
-                #linear_stream
-                #conv1d_stream
-                #conv2d_stream
-                #embed_stream
-
-                #linear_option1_stream
-                #conv1d_option1_stream
-                #conv2d_option1_stream
-                #embed_option1_stream
-
-                if !linear.is_empty() && linear_config.is_none() {
-                    panic!("Config not specified for linear layers.");
-                }
-                if !conv1d.is_empty() && conv1d_config.is_none() {
-                    panic!("Config not specified for conv1d layers.");
-                }
-                if !conv2d.is_empty() && conv2d_config.is_none() {
-                    panic!("Config not specified for conv2d layers.");
-                }
-                if !embed.is_empty() && embed_config.is_none() {
-                    panic!("Config not specified for embedding layers.");
-                }
-
-                let mut builder = candle_lora::SelectedLayersBuilder::new();
-                if linear_config.is_some() {
-                    builder = builder.add_linear_layers(linear, linear_config.unwrap());
-                }
-                if conv1d_config.is_some() {
-                    builder = builder.add_conv1d_layers(conv1d, conv1d_config.unwrap());
-                }
-                if conv2d_config.is_some() {
-                    builder = builder.add_conv2d_layers(conv2d, conv2d_config.unwrap());
-                }
-                if embed_config.is_some() {
-                    builder = builder.add_embed_layers(embed, embed_config.unwrap());
-                }
-                let selection = builder.build();
-
-                let mut new_layers = candle_lora::Lora::convert_model(selection, lora_config, &vb);
-
-                #linear_merge_stream_assign
-                #conv1d_merge_stream_assign
-                #conv2d_merge_stream_assign
-                #embed_merge_stream_assign
-
-                #linear_merge_option1_stream_assign
-                #conv1d_merge_option1_stream_assign
-                #conv2d_merge_option1_stream_assign
-                #embed_merge_option1_stream_assign
+            /// Replace the layers of the model under `prefix` with their converted layers.
+            #[doc(hidden)]
+            #[allow(unused_variables)]
+            pub fn assign_lora_layers(&mut self, prefix: &str, new_layers: &mut candle_lora::NewLayers<String>, merge: bool) -> candle_core::Result<()> {
+                #assign
+                Ok(())
             }
 
-            pub fn get_tensors(&self) -> ::std::collections::HashMap<String, Tensor> {
-                let mut output = ::std::collections::HashMap::new();
-                #linear_get
-                #conv1d_get
-                #conv2d_get
-                #embed_get
-                output
+            #[doc(hidden)]
+            #[allow(unused_variables)]
+            pub fn collect_lora_tensors(&self, output: &mut ::std::collections::HashMap<String, Tensor>) -> candle_core::Result<()> {
+                #tensors
+                Ok(())
             }
         }
     }
-
-    stream.into()
+    .into()
 }
@@ -42,14 +42,16 @@ fn linear() {
     let vb = VarBuilder::from_varmap(&varmap, dtype, &device);
 
     let loraconfig = LoraConfig::new(1, 1., None);
-    model.get_lora_model(
-        loraconfig,
-        &vb,
-        Some(LoraLinearConfig::new(10, 10)),
-        None,
-        None,
-        None,
-    );
+    model
+        .get_lora_model(
+            loraconfig,
+            &vb,
+            Some(LoraLinearConfig::new(10, 10)),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
 
     let dummy_image = Tensor::zeros((10, 10), DType::F32, &device).unwrap();
 
@@ -60,3 +62,36 @@ fn linear() {
     println!("{:?}", model.a);
     println!("{:?}", model.b);
 }
+
+#[test]
+fn conversion_errors_are_returned() -> Result<()> {
+    let device = Device::Cpu;
+    let new_model = || -> Result<Model> {
+        Ok(Model {
+            a: Arc::new(Linear::new(
+                Tensor::zeros((10, 10), DType::F32, &device)?,
+                None,
+            )),
+            b: 1,
+        })
+    };
+    let vb = VarBuilder::from_varmap(&VarMap::new(), DType::F32, &device);
+
+    // No configuration for the linear layers.
+    let config = LoraConfig::new(1, 1., None);
+    assert!(new_model()?
+        .get_lora_model(config.clone(), &vb, None, None, None, None)
+        .is_err());
+    // The configuration does not match the weight of the layer.
+    assert!(new_model()?
+        .get_merged_lora_model(
+            config,
+            &vb,
+            Some(LoraLinearConfig::new(8, 10)),
+            None,
+            None,
+            None
+        )
+        .is_err());
+    Ok(())
+}
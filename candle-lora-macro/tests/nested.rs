@@ -0,0 +1,202 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{LinearLayerLike, LoraConfig, LoraLinearConfig, TargetSpec};
+use candle_lora_macro::{replace_layer_fields, AutoLoraConvert};
+use candle_nn::{Activation, Linear, VarBuilder, VarMap};
+use std::sync::Arc;
+
+#[replace_layer_fields]
+#[derive(AutoLoraConvert)]
+struct Attention {
+    q_proj: Linear,
+    v_proj: Linear,
+}
+
+impl Module for Attention {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        self.v_proj.forward(&self.q_proj.forward(input)?)
+    }
+}
+
+#[replace_layer_fields]
+#[derive(AutoLoraConvert)]
+struct Block {
+    #[lora(nested)]
+    self_attn: Attention,
+    experts: Vec<Linear>,
+    gate: Option<Linear>,
+    act: Option<Box<dyn Module>>,
+}
+
+impl Module for Block {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let xs = self.self_attn.forward(input)?;
+        let mut out = xs.clone();
+        for expert in self.experts.iter() {
+            out = (out + expert.forward(&xs)?)?;
+        }
+        if let Some(gate) = &self.gate {
+            out = (out + gate.forward(&xs)?)?;
+        }
+        match &self.act {
+            Some(act) => act.forward(&out),
+            None => Ok(out),
+        }
+    }
+}
+
+#[replace_layer_fields]
+#[derive(AutoLoraConvert)]
+struct Model {
+    #[lora(nested)]
+    layers: Vec<Block>,
+    #[lora(nested)]
+    extra: Option<Attention>,
+    lm_head: Linear,
+}
+
+impl Module for Model {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let mut xs = input.clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs)?;
+        }
+        if let Some(extra) = &self.extra {
+            xs = extra.forward(&xs)?;
+        }
+        self.lm_head.forward(&xs)
+    }
+}
+
+/// A model of two blocks, the first with a gate and an activation, and 10 linear layers.
+fn model(weights: &[Tensor]) -> Model {
+    let mut weights = weights
+        .iter()
+        .map(|w| Arc::new(Linear::new(w.clone(), None)));
+    let mut next = || -> Arc<dyn LinearLayerLike> { weights.next().unwrap() };
+    let mut block = |gate: bool| Block {
+        self_attn: Attention {
+            q_proj: next(),
+            v_proj: next(),
+        },
+        experts: vec![next(), next()],
+        gate: gate.then(&mut next),
+        act: gate.then(|| Box::new(Activation::Relu) as Box<dyn Module>),
+    };
+    let layers = vec![block(true), block(false)];
+    Model {
+        layers,
+        extra: None,
+        lm_head: next(),
+    }
+}
+
+fn weights(device: &Device) -> Vec<Tensor> {
+    (0..10)
+        .map(|_| (Tensor::randn(0f32, 1., (4, 4), device).unwrap() * 0.5).unwrap())
+        .collect()
+}
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> f32 {
+    (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .max_all()
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap()
+}
+
+#[test]
+fn nested_layers_are_converted() {
+    let device = Device::Cpu;
+    let weights = weights(&device);
+    let xs = Tensor::randn(0f32, 1., (2, 4), &device).unwrap();
+    let base = model(&weights).forward(&xs).unwrap();
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut lora = model(&weights);
+    lora.get_lora_model(
+        LoraConfig::new(2, 2., None),
+        &vb.pp("model"),
+        Some(LoraLinearConfig::new(4, 4)),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    assert_eq!(varmap.all_vars().len(), 20);
+    assert_eq!(lora.get_tensors().unwrap().len(), 20);
+
+    // With non-zero B matrices, the merged model computes the same outputs.
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        if name.starts_with("model.b") {
+            var.set(&Tensor::randn(0f32, 1., var.shape(), &device).unwrap())
+                .unwrap();
+        }
+    }
+    let mut merged = model(&weights);
+    merged
+        .get_merged_lora_model(
+            LoraConfig::new(2, 2., None),
+            &vb.pp("model"),
+            Some(LoraLinearConfig::new(4, 4)),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+    let out = lora.forward(&xs).unwrap();
+    let scale = out
+        .abs()
+        .unwrap()
+        .max_all()
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap();
+    assert!(max_abs_diff(&out, &base) > 1e-3 * scale);
+    let diff = max_abs_diff(&out, &merged.forward(&xs).unwrap());
+    assert!(diff < 1e-4 * scale, "{diff} {scale}");
+}
+
+#[test]
+fn layers_are_named_after_their_module_path() {
+    let device = Device::Cpu;
+    let weights = weights(&device);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = model(&weights);
+    let target = TargetSpec::new()
+        .with_module_pattern(r"self_attn\.q_proj$|experts\.1$")
+        .unwrap()
+        .with_layers(1..=1);
+    model
+        .get_lora_model(
+            LoraConfig::new(2, 2., None).with_target(target),
+            &vb.pp("model"),
+            Some(LoraLinearConfig::new(4, 4)),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+    // `model.layers.1.self_attn.q_proj` and `model.layers.1.experts.1`.
+    let mut names = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "model.a0.weight",
+            "model.a1.weight",
+            "model.b0.weight",
+            "model.b1.weight"
+        ]
+    );
+}
@@ -0,0 +1,82 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{LinearLayerLike, LoraConfig, LoraLinearConfig, TargetSpec};
+use candle_lora_macro::{replace_layer_fields, AutoLoraConvert};
+use candle_nn::{Linear, VarBuilder, VarMap};
+use std::sync::Arc;
+
+#[replace_layer_fields]
+#[derive(AutoLoraConvert, Debug)]
+struct Attention {
+    q_proj: Linear,
+    v_proj: Linear,
+}
+
+impl Module for Attention {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        self.v_proj.forward(&self.q_proj.forward(input)?)
+    }
+}
+
+fn attention(device: &Device) -> Attention {
+    let weight = Tensor::eye(4, DType::F32, device).unwrap();
+    Attention {
+        q_proj: Arc::new(Linear::new(weight.clone(), None)),
+        v_proj: Arc::new(Linear::new(weight, None)),
+    }
+}
+
+#[test]
+fn targeted() {
+    let device = Device::Cpu;
+    let target = || {
+        TargetSpec::new()
+            .with_module_pattern("q_proj$")
+            .unwrap()
+            .with_layers(2..=3)
+    };
+
+    // Only q_proj of the layers in range is converted, the other layers stay as they are.
+    for (layer, vars) in [(2, 2), (0, 0)] {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let mut model = attention(&device);
+        model
+            .get_lora_model(
+                LoraConfig::new(1, 1., None).with_target(target()),
+                &vb.pp(format!("layers.{layer}")),
+                Some(LoraLinearConfig::new(4, 4)),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(varmap.all_vars().len(), vars);
+        assert!(varmap
+            .data()
+            .lock()
+            .unwrap()
+            .keys()
+            .all(|name| name.starts_with(&format!("layers.{layer}.a0"))
+                || name.starts_with(&format!("layers.{layer}.b0"))));
+
+        let mut merged = attention(&device);
+        merged
+            .get_merged_lora_model(
+                LoraConfig::new(1, 1., None).with_target(target()),
+                &vb.pp(format!("layers.{layer}")),
+                Some(LoraLinearConfig::new(4, 4)),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let xs = Tensor::ones((2, 4), DType::F32, &device).unwrap();
+        let diff = (model.forward(&xs).unwrap() - merged.forward(&xs).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap();
+        assert!(diff.to_scalar::<f32>().unwrap() < 1e-6);
+    }
+}
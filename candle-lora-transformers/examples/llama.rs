@@ -25,7 +25,7 @@ use candle_lora_transformers::{
     llama as model,
     varbuilder_utils::{from_mmaped_safetensors, from_npz_tensors},
 };
-use model::{Config, Llama, LlamaConfig};
+use model::{Config, Llama, LlamaConfig, LlamaEosToks};
 
 const EOS_TOKEN: &str = "</s>";
 const DEFAULT_PROMPT: &str = "My favorite theorem is ";
@@ -122,7 +122,7 @@ fn main() -> Result<()> {
         None => DType::F16,
     };
 
-    let (llama, tokenizer_filename, cache) = match args.npy {
+    let (llama, tokenizer_filename, cache, eos_token_id) = match args.npy {
         Some(filename) => {
             let config = if args.v1 {
                 Config::config_7b_v1(args.use_flash_attn)
@@ -146,10 +146,11 @@ fn main() -> Result<()> {
                     true,
                     loraconfig,
                     linearconfig,
-                    embedconfig,
+                    Some(embedconfig),
                 )?,
                 tokenizer,
                 cache,
+                config.eos_token_id.clone(),
             )
         }
         None => {
@@ -180,11 +181,22 @@ fn main() -> Result<()> {
             let config: LlamaConfig = serde_json::from_slice(&std::fs::read(config_filename)?)?;
             let config = config.into_config(args.use_flash_attn);
 
+            let index_filename = match &args.local_weights {
+                Some(path) => (path.to_owned() + "model.safetensors.index.json").into(),
+                _ => api.get("model.safetensors.index.json")?,
+            };
+            let index: serde_json::Value = serde_json::from_slice(&fs::read(index_filename)?)?;
+            let mut rfilenames = index["weight_map"]
+                .as_object()
+                .ok_or_else(|| E::msg("weight_map is not an object"))?
+                .values()
+                .filter_map(|v| v.as_str())
+                .collect::<Vec<_>>();
+            rfilenames.sort();
+            rfilenames.dedup();
+
             let mut filenames = vec![];
-            for rfilename in [
-                "model-00001-of-00002.safetensors",
-                "model-00002-of-00002.safetensors",
-            ] {
+            for rfilename in rfilenames {
                 match &args.local_weights {
                     Some(path) => {
                         filenames.push((path.to_owned() + rfilename).into());
@@ -213,15 +225,17 @@ fn main() -> Result<()> {
                     true,
                     loraconfig,
                     linearconfig,
-                    embedconfig,
+                    Some(embedconfig),
                 )?,
                 tokenizer_filename,
                 cache,
+                config.eos_token_id.clone(),
             )
         }
     };
     let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-    let eos_token_id = tokenizer.token_to_id(EOS_TOKEN);
+    let eos_token_id =
+        eos_token_id.or_else(|| tokenizer.token_to_id(EOS_TOKEN).map(LlamaEosToks::Single));
     let prompt = args.prompt.as_ref().map_or(DEFAULT_PROMPT, |p| p.as_str());
     let mut tokens = tokenizer
         .encode(prompt, true)
@@ -270,7 +284,10 @@ fn main() -> Result<()> {
             print!("{text}");
             std::io::stdout().flush()?;
         }
-        if Some(next_token) == eos_token_id {
+        if eos_token_id
+            .as_ref()
+            .is_some_and(|eos| eos.contains(next_token))
+        {
             break;
         }
     }
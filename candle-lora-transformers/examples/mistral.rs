@@ -5,7 +5,7 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use anyhow::{Error as E, Result};
-use candle_lora::LoraConfig;
+use candle_lora::{convert_peft_to_candle_lora_traced, LoraConfig, TracedArchitecture};
 use clap::Parser;
 
 use candle_lora_transformers::{
@@ -161,6 +161,18 @@ struct Args {
     #[arg(long)]
     weight_files: Option<String>,
 
+    /// A PEFT `adapter_model.safetensors` to apply on top of the base model.
+    #[arg(long)]
+    peft_adapter: Option<String>,
+
+    /// The LoRA rank of the adapter.
+    #[arg(long, default_value_t = 8)]
+    lora_rank: usize,
+
+    /// The LoRA alpha of the adapter.
+    #[arg(long, default_value_t = 16.)]
+    lora_alpha: f64,
+
     /// Penalty to be applied for repeating tokens, 1. means no penalty.
     #[arg(long, default_value_t = 1.1)]
     repeat_penalty: f32,
@@ -207,7 +219,7 @@ fn main() -> Result<()> {
         Some(file) => std::path::PathBuf::from(file),
         None => repo.get("tokenizer.json")?,
     };
-    let filenames = match args.weight_files {
+    let mut filenames = match args.weight_files {
         Some(files) => files
             .split(',')
             .map(std::path::PathBuf::from)
@@ -233,9 +245,20 @@ fn main() -> Result<()> {
         DType::F32
     };
 
+    if let Some(peft_adapter) = &args.peft_adapter {
+        let converted = std::env::temp_dir().join("mistral_lora.safetensors");
+        convert_peft_to_candle_lora_traced(
+            peft_adapter,
+            converted.to_str().unwrap(),
+            TracedArchitecture::Mistral,
+            &device,
+        )?;
+        filenames.push(converted);
+    }
+
     let vb = from_mmaped_safetensors(&filenames, dtype, &device, false)?;
 
-    let loraconfig = LoraConfig::new(1, 1., None);
+    let loraconfig = LoraConfig::new(args.lora_rank, args.lora_alpha, None);
     let model = Mistral::new(&config, vb, true, loraconfig)?;
 
     println!("loaded the model in {:?}", start.elapsed());
@@ -0,0 +1,239 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::collections::HashMap;
+
+use anyhow::{Error as E, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_lora::{FitConfig, LoraConfig, LoraTrainer, LrScheduler, TrainingConfig};
+use candle_lora_transformers::{
+    bert::Config,
+    token_classification::{
+        align_word_labels, token_classification_loss, BertForTokenClassification, MODULES_TO_SAVE,
+    },
+    varbuilder_utils::{trainable_vars, varmap_from_mmaped_safetensors},
+};
+use candle_nn::{AdamW, ParamsAdamW, VarBuilder};
+use clap::Parser;
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use serde::Deserialize;
+use tokenizers::{Tokenizer, TruncationParams};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    /// The training set, one JSON record with `tokens` and `ner_tags` per line as in the
+    /// HuggingFace conll2003 dataset.
+    #[arg(long)]
+    data: String,
+
+    /// The labels, in the order of the `ner_tags` ids.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "O,B-PER,I-PER,B-ORG,I-ORG,B-LOC,I-LOC,B-MISC,I-MISC"
+    )]
+    labels: Vec<String>,
+
+    #[arg(long, default_value = "bert-base-cased")]
+    model_id: String,
+
+    #[arg(long, default_value = "main")]
+    revision: String,
+
+    #[arg(long, default_value_t = 8)]
+    rank: usize,
+
+    #[arg(long, default_value_t = 16.)]
+    alpha: f64,
+
+    #[arg(long, default_value_t = 3)]
+    epochs: usize,
+
+    #[arg(long, default_value_t = 16)]
+    batch_size: usize,
+
+    #[arg(long, default_value_t = 1e-3)]
+    learning_rate: f64,
+
+    #[arg(long, default_value_t = 128)]
+    max_len: usize,
+
+    /// Where to save the adapter and the classification head.
+    #[arg(long, default_value = "ner_adapter.safetensors")]
+    output: String,
+
+    /// Tag this sentence after training.
+    #[arg(long)]
+    prompt: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    tokens: Vec<String>,
+    ner_tags: Vec<u32>,
+}
+
+struct Batch {
+    input_ids: Tensor,
+    attention_mask: Tensor,
+    labels: Tensor,
+    loss_mask: Tensor,
+}
+
+fn load_batches(
+    args: &Args,
+    tokenizer: &Tokenizer,
+    pad_token_id: u32,
+    device: &Device,
+) -> Result<Vec<Batch>> {
+    let records = std::fs::read_to_string(&args.data)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<Record>>>()?;
+    let mut examples = Vec::with_capacity(records.len());
+    for record in records {
+        let encoding = tokenizer.encode(record.tokens, true).map_err(E::msg)?;
+        let (labels, loss_mask) = align_word_labels(&encoding, &record.ner_tags);
+        examples.push((encoding.get_ids().to_vec(), labels, loss_mask));
+    }
+    examples
+        .chunks(args.batch_size)
+        .map(|chunk| {
+            let seq_len = chunk.iter().map(|(ids, _, _)| ids.len()).max().unwrap_or(0);
+            let (mut input_ids, mut attention_mask, mut labels, mut loss_mask) =
+                (vec![], vec![], vec![], vec![]);
+            for (ids, example_labels, example_mask) in chunk {
+                let padding = seq_len - ids.len();
+                input_ids.extend(ids.iter().copied().chain(vec![pad_token_id; padding]));
+                attention_mask.extend(vec![1u8; ids.len()].into_iter().chain(vec![0; padding]));
+                labels.extend(example_labels.iter().copied().chain(vec![0; padding]));
+                loss_mask.extend(example_mask.iter().copied().chain(vec![0; padding]));
+            }
+            let shape = (chunk.len(), seq_len);
+            Ok(Batch {
+                input_ids: Tensor::from_vec(input_ids, shape, device)?,
+                attention_mask: Tensor::from_vec(attention_mask, shape, device)?,
+                labels: Tensor::from_vec(labels, shape, device)?,
+                loss_mask: Tensor::from_vec(loss_mask, shape, device)?,
+            })
+        })
+        .collect()
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+
+    let repo = Repo::with_revision(
+        args.model_id.clone(),
+        RepoType::Model,
+        args.revision.clone(),
+    );
+    let api = Api::new()?.repo(repo);
+    let config: Config = serde_json::from_str(&std::fs::read_to_string(api.get("config.json")?)?)?;
+    let mut tokenizer = Tokenizer::from_file(api.get("tokenizer.json")?).map_err(E::msg)?;
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            max_length: args.max_len,
+            ..Default::default()
+        }))
+        .map_err(E::msg)?;
+    let pad_token_id = tokenizer.token_to_id("[PAD]").unwrap_or(0);
+
+    // The base weights and the new variables share the VarMap, only the adapters and the head
+    // are trained.
+    let varmap = varmap_from_mmaped_safetensors(
+        &[api.get("model.safetensors")?],
+        DType::F32,
+        &device,
+        false,
+    )?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let lora_config = LoraConfig::new(args.rank, args.alpha, None);
+    let model =
+        BertForTokenClassification::new(vb, &config, args.labels.len(), 0.1, false, lora_config)?;
+    let trainable = trainable_vars(&varmap, MODULES_TO_SAVE);
+    let num_params = trainable
+        .iter()
+        .map(|(_, var)| var.elem_count())
+        .sum::<usize>();
+    println!(
+        "training {} tensors, {num_params} parameters",
+        trainable.len()
+    );
+
+    let batches = load_batches(&args, &tokenizer, pad_token_id, &device)?;
+    let total_steps = batches.len() * args.epochs;
+    let config = TrainingConfig {
+        learning_rate: args.learning_rate,
+        lr_scheduler: LrScheduler::Linear,
+        warmup_steps: total_steps / 10,
+        total_steps,
+        max_grad_norm: Some(1.),
+        ..Default::default()
+    };
+    let vars = trainable.iter().map(|(_, var)| var.clone()).collect();
+    let mut trainer = LoraTrainer::<AdamW>::new(vars, ParamsAdamW::default(), config)?;
+    let fit_config = FitConfig {
+        epochs: args.epochs,
+        ..Default::default()
+    };
+    let summary = trainer.fit(
+        &batches,
+        &fit_config,
+        |batch: &Batch| {
+            let logits = model.forward(
+                &batch.input_ids,
+                &batch.input_ids.zeros_like()?,
+                Some(&batch.attention_mask),
+                true,
+            )?;
+            let loss = token_classification_loss(&logits, &batch.labels, &batch.loss_mask)?;
+            println!("loss {:.4}", loss.to_scalar::<f32>()?);
+            Ok(loss)
+        },
+        None,
+        None,
+        &mut [],
+    )?;
+    println!("trained for {} steps", summary.steps);
+
+    let tensors = trainable
+        .iter()
+        .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+        .collect::<HashMap<_, _>>();
+    candle_core::safetensors::save(&tensors, &args.output)?;
+    println!("saved the adapter to {}", args.output);
+
+    if let Some(prompt) = args.prompt {
+        let words = prompt
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let encoding = tokenizer.encode(words.clone(), true).map_err(E::msg)?;
+        let input_ids = Tensor::new(encoding.get_ids(), &device)?.unsqueeze(0)?;
+        let predictions = model
+            .predict(&input_ids, &input_ids.zeros_like()?, None)?
+            .squeeze(0)?
+            .to_vec1::<u32>()?;
+        let (_, first_tokens) = align_word_labels(&encoding, &vec![0; words.len()]);
+        let word_predictions = predictions
+            .iter()
+            .zip(first_tokens)
+            .filter(|(_, first)| *first == 1)
+            .map(|(label, _)| *label as usize);
+        for (word, label) in words.iter().zip(word_predictions) {
+            println!("{word}\t{}", args.labels[label]);
+        }
+    }
+    Ok(())
+}
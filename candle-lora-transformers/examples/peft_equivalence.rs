@@ -0,0 +1,120 @@
+// Compare a Mistral model with a PEFT adapter against the outputs of transformers and PEFT.
+//
+// The reference outputs are exported with the Python snippet of the `equivalence` module of
+// candle-lora, for the same base model and adapter, in f32:
+//
+// cargo run --example peft_equivalence --features equivalence --release -- \
+//     --model-dir mistral --peft-adapter adapter --reference reference.safetensors
+
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use candle_core::DType;
+use candle_lora::{
+    convert_peft_to_candle_lora_traced, LoraConfig, PeftConfig, ReferenceOutputs, Tolerance,
+    TracedArchitecture,
+};
+use clap::Parser;
+
+use candle_lora_transformers::{
+    mistral::{Config, Mistral},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    /// The directory of the base model, with its `config.json` and safetensors weights.
+    #[arg(long)]
+    model_dir: String,
+
+    /// The PEFT adapter directory, with `adapter_config.json` and `adapter_model.safetensors`.
+    #[arg(long)]
+    peft_adapter: String,
+
+    /// The reference outputs exported from transformers and PEFT.
+    #[arg(long)]
+    reference: String,
+
+    /// The absolute tolerance.
+    #[arg(long, default_value_t = 1e-3)]
+    atol: f64,
+
+    /// The relative tolerance.
+    #[arg(long, default_value_t = 1e-3)]
+    rtol: f64,
+
+    /// Merge the adapter into the base weights rather than applying it in the forward pass.
+    #[arg(long)]
+    merge: bool,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+
+    let model_dir = Path::new(&args.model_dir);
+    let config: Config =
+        serde_json::from_str(&std::fs::read_to_string(model_dir.join("config.json"))?)?;
+    let mut filenames = std::fs::read_dir(model_dir)?
+        .map(|entry| Ok(entry?.path()))
+        .filter(|path| {
+            path.as_ref().map_or(true, |path| {
+                path.extension().is_some_and(|e| e == "safetensors")
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    filenames.sort();
+    if filenames.is_empty() {
+        bail!("no safetensors weights in {}", args.model_dir)
+    }
+
+    let adapter_dir = Path::new(&args.peft_adapter);
+    let peft_config: PeftConfig = serde_json::from_str(&std::fs::read_to_string(
+        adapter_dir.join("adapter_config.json"),
+    )?)?;
+    let converted = std::env::temp_dir().join("peft_equivalence_lora.safetensors");
+    convert_peft_to_candle_lora_traced(
+        adapter_dir
+            .join("adapter_model.safetensors")
+            .to_str()
+            .unwrap(),
+        converted.to_str().unwrap(),
+        TracedArchitecture::Mistral,
+        &device,
+    )?;
+    filenames.push(converted);
+
+    let vb = from_mmaped_safetensors(&filenames, DType::F32, &device, false)?;
+    let lora_config = LoraConfig::new(peft_config.r, peft_config.lora_alpha, None)
+        .with_rslora(peft_config.use_rslora);
+    let mut model = Mistral::new(&config, vb, args.merge, lora_config)?;
+
+    let reference = ReferenceOutputs::load(&args.reference, &candle_core::Device::Cpu)?;
+    let input_ids = reference.input_ids()?.to_device(&device)?;
+    let (hidden_states, logits) = model.forward_with_hidden_states(&input_ids, 0)?;
+    let mut outputs = hidden_states
+        .into_iter()
+        .enumerate()
+        .map(|(i, hidden)| (format!("hidden_states.{i}"), hidden))
+        .collect::<Vec<_>>();
+    outputs.push(("logits".to_string(), logits));
+
+    let report = reference.compare(&outputs, Tolerance::new(args.atol, args.rtol))?;
+    print!("{report}");
+    match report.first_divergence() {
+        Some(divergence) => bail!("diverged first at {}", divergence.name),
+        None => println!("the outputs match the reference"),
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::{Error as E, Result};
+use candle_core::{DType, Tensor};
+use candle_lora::{convert_peft_to_candle_lora_traced, LoraConfig, TracedArchitecture};
+use candle_transformers::generation::LogitsProcessor;
+use clap::Parser;
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use std::io::Write;
+use tokenizers::Tokenizer;
+
+use candle_lora_transformers::{
+    qwen2::{Config, Qwen2},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    #[arg(long)]
+    use_flash_attn: bool,
+
+    #[arg(long, default_value = "There is a")]
+    prompt: String,
+
+    /// The temperature used to generate samples.
+    #[arg(long)]
+    temperature: Option<f64>,
+
+    /// Nucleus sampling probability cutoff.
+    #[arg(long)]
+    top_p: Option<f64>,
+
+    /// The seed to use when generating random samples.
+    #[arg(long, default_value_t = 299792458)]
+    seed: u64,
+
+    /// The length of the sample to generate (in tokens).
+    #[arg(long, short = 'n', default_value_t = 100)]
+    sample_len: usize,
+
+    #[arg(long, default_value = "Qwen/Qwen2-7B")]
+    model_id: String,
+
+    #[arg(long, default_value = "main")]
+    revision: String,
+
+    /// A PEFT `adapter_model.safetensors` to apply on top of the base model.
+    #[arg(long)]
+    peft_adapter: Option<String>,
+
+    /// The LoRA rank of the adapter.
+    #[arg(long, default_value_t = 8)]
+    lora_rank: usize,
+
+    /// The LoRA alpha of the adapter.
+    #[arg(long, default_value_t = 16.)]
+    lora_alpha: f64,
+
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    #[arg(long, default_value_t = 1.1)]
+    repeat_penalty: f32,
+
+    /// The context size to consider for the repeat penalty.
+    #[arg(long, default_value_t = 64)]
+    repeat_last_n: usize,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let device = candle_examples::device(args.cpu)?;
+    let dtype = if device.is_cuda() {
+        DType::BF16
+    } else {
+        DType::F32
+    };
+
+    let api = Api::new()?;
+    let repo = api.repo(Repo::with_revision(
+        args.model_id,
+        RepoType::Model,
+        args.revision,
+    ));
+    let tokenizer = Tokenizer::from_file(repo.get("tokenizer.json")?).map_err(E::msg)?;
+    let mut config: Config = serde_json::from_slice(&std::fs::read(repo.get("config.json")?)?)?;
+    config.use_flash_attn = args.use_flash_attn;
+
+    let index: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(repo.get("model.safetensors.index.json")?)?)?;
+    let mut filenames = index["weight_map"]
+        .as_object()
+        .ok_or_else(|| E::msg("weight_map is not an object"))?
+        .values()
+        .filter_map(|v| v.as_str())
+        .collect::<Vec<_>>();
+    filenames.sort();
+    filenames.dedup();
+    let mut filenames = filenames
+        .into_iter()
+        .map(|f| repo.get(f))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if let Some(peft_adapter) = &args.peft_adapter {
+        let converted = std::env::temp_dir().join("qwen2_lora.safetensors");
+        convert_peft_to_candle_lora_traced(
+            peft_adapter,
+            converted.to_str().unwrap(),
+            TracedArchitecture::Qwen2,
+            &device,
+        )?;
+        filenames.push(converted);
+    }
+
+    let vb = from_mmaped_safetensors(&filenames, dtype, &device, false)?;
+    let loraconfig = LoraConfig::new(args.lora_rank, args.lora_alpha, None);
+    let mut model = Qwen2::new(&config, vb, false, loraconfig)?;
+
+    let mut tokens = tokenizer
+        .encode(args.prompt.as_str(), true)
+        .map_err(E::msg)?
+        .get_ids()
+        .to_vec();
+    let eos_token_id = tokenizer.token_to_id("<|endoftext|>");
+
+    print!("{}", args.prompt);
+    let mut logits_processor = LogitsProcessor::new(args.seed, args.temperature, args.top_p);
+    let start_gen = std::time::Instant::now();
+    let mut generated_tokens = 0usize;
+    for index in 0..args.sample_len {
+        let context_size = if index > 0 { 1 } else { tokens.len() };
+        let start_pos = tokens.len().saturating_sub(context_size);
+        let input = Tensor::new(&tokens[start_pos..], &device)?.unsqueeze(0)?;
+        let logits = model.forward(&input, start_pos)?;
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        let logits = if args.repeat_penalty == 1. {
+            logits
+        } else {
+            let start_at = tokens.len().saturating_sub(args.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                args.repeat_penalty,
+                &tokens[start_at..],
+            )?
+        };
+
+        let next_token = logits_processor.sample(&logits)?;
+        tokens.push(next_token);
+        generated_tokens += 1;
+        if Some(next_token) == eos_token_id {
+            break;
+        }
+        if let Ok(text) = tokenizer.decode(&[next_token], false) {
+            print!("{text}");
+            std::io::stdout().flush()?;
+        }
+    }
+    let dt = start_gen.elapsed();
+    println!(
+        "\n{generated_tokens} tokens generated ({:.2} token/s)",
+        generated_tokens as f64 / dt.as_secs_f64(),
+    );
+    Ok(())
+}
@@ -0,0 +1,219 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor, D};
+use candle_lora::{FitConfig, LoraConfig, LoraTrainer, LrScheduler, TrainingConfig};
+use candle_lora_transformers::{
+    resnet,
+    varbuilder_utils::{trainable_vars, varmap_from_mmaped_safetensors},
+};
+use candle_nn::{AdamW, Module, ParamsAdamW, VarBuilder};
+use clap::{Parser, ValueEnum};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Which {
+    #[value(name = "18")]
+    Resnet18,
+    #[value(name = "34")]
+    Resnet34,
+    #[value(name = "50")]
+    Resnet50,
+}
+
+#[derive(Parser)]
+struct Args {
+    /// Image folder with one subfolder of images per class.
+    #[arg(long)]
+    data: PathBuf,
+
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    /// Variant of the model to use.
+    #[arg(value_enum, long, default_value_t = Which::Resnet18)]
+    which: Which,
+
+    #[arg(long, default_value_t = 8)]
+    rank: usize,
+
+    #[arg(long, default_value_t = 16.)]
+    alpha: f64,
+
+    #[arg(long, default_value_t = 5)]
+    epochs: usize,
+
+    #[arg(long, default_value_t = 8)]
+    batch_size: usize,
+
+    #[arg(long, default_value_t = 1e-3)]
+    learning_rate: f64,
+
+    /// Where to save the conv adapters and the classification head.
+    #[arg(long, default_value = "resnet_adapter.safetensors")]
+    output: String,
+}
+
+struct Batch {
+    images: Tensor,
+    labels: Tensor,
+}
+
+/// Read the images of each class folder, the classes sorted by folder name, into batches which
+/// take the classes in turn.
+fn load_dataset(args: &Args, device: &Device) -> Result<(Vec<String>, Vec<Batch>)> {
+    let mut classes = std::fs::read_dir(&args.data)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    classes.sort();
+    let mut images_by_class = vec![];
+    for class_dir in classes.iter() {
+        let mut images = vec![];
+        for entry in std::fs::read_dir(class_dir)? {
+            let image = candle_examples::imagenet::load_image224(entry?.path())?;
+            images.push(image.to_device(device)?);
+        }
+        images_by_class.push(images.into_iter());
+    }
+    let mut examples = vec![];
+    loop {
+        let len = examples.len();
+        for (label, images) in images_by_class.iter_mut().enumerate() {
+            examples.extend(images.next().map(|image| (image, label as u32)));
+        }
+        if examples.len() == len {
+            break;
+        }
+    }
+    let batches = examples
+        .chunks(args.batch_size)
+        .map(|chunk| {
+            let images = chunk.iter().map(|(image, _)| image).collect::<Vec<_>>();
+            let labels = chunk.iter().map(|(_, label)| *label).collect::<Vec<_>>();
+            Ok(Batch {
+                images: Tensor::stack(&images, 0)?,
+                labels: Tensor::new(labels, device)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let names = classes
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    Ok((names, batches))
+}
+
+pub fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+
+    let (classes, batches) = load_dataset(&args, &device)?;
+    println!("{} classes: {classes:?}", classes.len());
+
+    let model_file = match &args.model {
+        None => {
+            let api = hf_hub::api::sync::Api::new()?;
+            let api = api.model("lmz/candle-resnet".into());
+            let filename = match args.which {
+                Which::Resnet18 => "resnet18.safetensors",
+                Which::Resnet34 => "resnet34.safetensors",
+                Which::Resnet50 => "resnet50.safetensors",
+            };
+            api.get(filename)?
+        }
+        Some(model) => model.into(),
+    };
+    // The pretrained weights and the new variables share the VarMap, only the LoRA weights of the
+    // convolutions and the new head are trained.
+    let varmap = varmap_from_mmaped_safetensors(&[model_file], DType::F32, &device, false)?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let lora_config = LoraConfig::new(args.rank, args.alpha, None);
+    let (backbone, features) = match args.which {
+        Which::Resnet18 => (
+            resnet::resnet18_no_final_layer(vb.clone(), false, lora_config)?,
+            512,
+        ),
+        Which::Resnet34 => (
+            resnet::resnet34_no_final_layer(vb.clone(), false, lora_config)?,
+            512,
+        ),
+        Which::Resnet50 => (
+            resnet::resnet50_no_final_layer(vb.clone(), false, lora_config)?,
+            2048,
+        ),
+    };
+    let head = candle_nn::linear(features, classes.len(), vb.pp("classifier"))?;
+    let forward = |images: &Tensor| head.forward(&backbone.forward(images)?);
+
+    let trainable = trainable_vars(&varmap, &["classifier"]);
+    let num_params = trainable
+        .iter()
+        .map(|(_, var)| var.elem_count())
+        .sum::<usize>();
+    println!(
+        "training {} tensors, {num_params} parameters",
+        trainable.len()
+    );
+
+    let total_steps = batches.len() * args.epochs;
+    let config = TrainingConfig {
+        learning_rate: args.learning_rate,
+        lr_scheduler: LrScheduler::Cosine,
+        warmup_steps: total_steps / 10,
+        total_steps,
+        max_grad_norm: Some(1.),
+        ..Default::default()
+    };
+    let vars = trainable.iter().map(|(_, var)| var.clone()).collect();
+    let mut trainer = LoraTrainer::<AdamW>::new(vars, ParamsAdamW::default(), config)?;
+    let fit_config = FitConfig {
+        epochs: args.epochs,
+        ..Default::default()
+    };
+    let accuracy = || -> candle_core::Result<f64> {
+        let mut correct = 0f64;
+        let mut total = 0f64;
+        for batch in &batches {
+            let predictions = forward(&batch.images)?.argmax(D::Minus1)?;
+            correct += predictions
+                .eq(&batch.labels)?
+                .to_dtype(DType::F32)?
+                .sum_all()?
+                .to_scalar::<f32>()? as f64;
+            total += batch.labels.elem_count() as f64;
+        }
+        Ok(correct / total)
+    };
+    println!("accuracy before training: {:.2}", accuracy()?);
+    trainer.fit(
+        &batches,
+        &fit_config,
+        |batch: &Batch| {
+            let loss = candle_nn::loss::cross_entropy(&forward(&batch.images)?, &batch.labels)?;
+            println!("loss {:.4}", loss.to_scalar::<f32>()?);
+            Ok(loss)
+        },
+        None,
+        None,
+        &mut [],
+    )?;
+    println!("accuracy after training: {:.2}", accuracy()?);
+
+    let tensors = trainable
+        .iter()
+        .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+        .collect::<HashMap<_, _>>();
+    candle_core::safetensors::save(&tensors, &args.output)?;
+    println!("saved the adapter to {}", args.output);
+    Ok(())
+}
@@ -0,0 +1,101 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::{Error as E, Result};
+use candle_lora_transformers::sft::{
+    batches, load_jsonl, pack_examples, tokenize_example, PromptTemplate,
+};
+use clap::{Parser, ValueEnum};
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use tokenizers::Tokenizer;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Template {
+    Alpaca,
+    Plain,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    /// The instruction dataset, one JSON record per line.
+    #[arg(long)]
+    data: String,
+
+    /// The model whose tokenizer is used.
+    #[arg(long, default_value = "TinyLlama/TinyLlama-1.1B-Chat-v1.0")]
+    model_id: String,
+
+    #[arg(long, default_value = "main")]
+    revision: String,
+
+    #[arg(long, value_enum, default_value = "alpaca")]
+    template: Template,
+
+    /// Length of the packed training sequences.
+    #[arg(long, default_value_t = 512)]
+    seq_len: usize,
+
+    #[arg(long, default_value_t = 4)]
+    batch_size: usize,
+
+    /// Pad the examples to the longest of each batch instead of packing them.
+    #[arg(long)]
+    no_packing: bool,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+
+    let repo = Repo::with_revision(args.model_id, RepoType::Model, args.revision);
+    let api = Api::new()?.repo(repo);
+    let tokenizer = Tokenizer::from_file(api.get("tokenizer.json")?).map_err(E::msg)?;
+    let eos_token_id = ["</s>", "<|endoftext|>", "<|end_of_text|>"]
+        .iter()
+        .find_map(|token| tokenizer.token_to_id(token));
+    let pad_token_id = eos_token_id.unwrap_or(0);
+
+    let template = match args.template {
+        Template::Alpaca => PromptTemplate::Alpaca,
+        Template::Plain => PromptTemplate::Plain,
+    };
+    let examples = load_jsonl(&args.data)?
+        .iter()
+        .map(|example| tokenize_example(&tokenizer, example, template, eos_token_id, args.seq_len))
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    let num_tokens = examples.iter().map(|e| e.len()).sum::<usize>();
+    let num_loss_tokens = examples.iter().map(|e| e.num_loss_tokens()).sum::<usize>();
+    println!(
+        "{} examples, {num_tokens} tokens, {num_loss_tokens} trained on",
+        examples.len()
+    );
+
+    let sequences = if args.no_packing {
+        examples
+    } else {
+        let packed = pack_examples(&examples, args.seq_len, pad_token_id);
+        println!("packed into {} sequences of {}", packed.len(), args.seq_len);
+        packed
+    };
+    let batches = batches(&sequences, args.batch_size, pad_token_id, &device)?;
+    for (idx, batch) in batches.iter().enumerate() {
+        println!(
+            "batch {idx}: input_ids {:?}, {} loss tokens",
+            batch.input_ids.shape(),
+            batch
+                .loss_mask
+                .to_dtype(candle_core::DType::U32)?
+                .sum_all()?
+                .to_scalar::<u32>()?
+        );
+    }
+    Ok(())
+}
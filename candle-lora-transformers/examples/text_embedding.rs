@@ -0,0 +1,130 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+use candle_lora::LoraConfig;
+use candle_lora_transformers::{
+    bert::{Config, DTYPE},
+    text_embedding::{cosine_similarities, EmbeddingModelKind, TextEmbeddingModel},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+
+use anyhow::{Error as E, Result};
+use candle_core::Tensor;
+use clap::{Parser, ValueEnum};
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Which {
+    Bge,
+    E5,
+    Gte,
+}
+
+impl Which {
+    fn model_id(&self) -> &'static str {
+        match self {
+            Self::Bge => "BAAI/bge-small-en-v1.5",
+            Self::E5 => "intfloat/e5-small-v2",
+            Self::Gte => "thenlper/gte-small",
+        }
+    }
+
+    fn kind(&self) -> EmbeddingModelKind {
+        match self {
+            Self::Bge => EmbeddingModelKind::Bge,
+            Self::E5 => EmbeddingModelKind::E5,
+            Self::Gte => EmbeddingModelKind::Gte,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    #[arg(long, value_enum, default_value = "bge")]
+    which: Which,
+
+    /// Override the model of `which`, it must use the same pooling.
+    #[arg(long)]
+    model_id: Option<String>,
+
+    #[arg(long, default_value = "main")]
+    revision: String,
+
+    /// The query to rank the passages against.
+    #[arg(long, default_value = "What do cats like to do?")]
+    query: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+    let kind = args.which.kind();
+
+    let model_id = args
+        .model_id
+        .unwrap_or_else(|| args.which.model_id().to_string());
+    let repo = Repo::with_revision(model_id, RepoType::Model, args.revision);
+    let api = Api::new()?.repo(repo);
+    let config: Config = serde_json::from_str(&std::fs::read_to_string(api.get("config.json")?)?)?;
+    let mut tokenizer = Tokenizer::from_file(api.get("tokenizer.json")?).map_err(E::msg)?;
+    tokenizer
+        .with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }))
+        .with_truncation(None)
+        .map_err(E::msg)?;
+
+    let vb = from_mmaped_safetensors(&[api.get("model.safetensors")?], DTYPE, &device, false)?;
+    let loraconfig = LoraConfig::new(1, 1., None);
+    let model = TextEmbeddingModel::new(vb, &config, kind.pooling(), true, true, loraconfig)?;
+
+    let passages = [
+        "The cat sleeps on the sofa all afternoon.",
+        "Cats enjoy chasing mice and playing with string.",
+        "The stock market closed higher on Friday.",
+        "Pasta is best cooked al dente.",
+    ];
+    let mut texts = vec![format!("{}{}", kind.query_prefix(), args.query)];
+    texts.extend(
+        passages
+            .iter()
+            .map(|passage| format!("{}{passage}", kind.passage_prefix())),
+    );
+
+    let encodings = tokenizer.encode_batch(texts, true).map_err(E::msg)?;
+    let input_ids = encodings
+        .iter()
+        .map(|encoding| Ok(Tensor::new(encoding.get_ids(), &device)?))
+        .collect::<Result<Vec<_>>>()?;
+    let attention_mask = encodings
+        .iter()
+        .map(|encoding| Ok(Tensor::new(encoding.get_attention_mask(), &device)?))
+        .collect::<Result<Vec<_>>>()?;
+    let input_ids = Tensor::stack(&input_ids, 0)?;
+    let attention_mask = Tensor::stack(&attention_mask, 0)?;
+    let token_type_ids = input_ids.zeros_like()?;
+
+    let embeddings = model.forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+    println!("embeddings {:?}", embeddings.shape());
+    let scores = cosine_similarities(
+        &embeddings.narrow(0, 0, 1)?,
+        &embeddings.narrow(0, 1, passages.len())?,
+    )?
+    .squeeze(0)?
+    .to_vec1::<f32>()?;
+    let mut ranked = scores.iter().zip(passages.iter()).collect::<Vec<_>>();
+    ranked.sort_by(|u, v| v.0.total_cmp(u.0));
+    for (score, passage) in ranked {
+        println!("score: {score:.3} '{passage}'");
+    }
+    Ok(())
+}
@@ -0,0 +1,539 @@
+//! Baichuan and Baichuan2, https://github.com/baichuan-inc/Baichuan2
+//!
+//! The 7B models use rotary embeddings while the 13B models use ALiBi. Both pack the query, key
+//! and value projections into a single `W_pack` matrix, which is split at load time into separate
+//! q/k/v LoRA layers, the layout produced by `TracedArchitecture::Baichuan` conversion.
+//! Baichuan2 additionally normalizes the rows of its output head.
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{Activation, Linear, VarBuilder};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::with_tracing::{linear_no_bias, TracedLoraLinear};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: Option<usize>,
+    pub model_max_length: Option<usize>,
+    pub rms_norm_eps: f64,
+    /// Overrides the position embedding, which otherwise is ALiBi for the 40 layer 13B models.
+    #[serde(default)]
+    pub position_embedding: Option<PositionEmbedding>,
+    /// Overrides the output head normalization, which otherwise is enabled for the Baichuan2
+    /// vocabulary.
+    #[serde(default)]
+    pub norm_head: Option<bool>,
+    #[serde(default)]
+    pub use_flash_attn: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionEmbedding {
+    Rope,
+    Alibi,
+}
+
+const BAICHUAN2_VOCAB_SIZE: usize = 125696;
+
+impl Config {
+    pub fn max_seq_len(&self) -> usize {
+        self.max_position_embeddings
+            .or(self.model_max_length)
+            .unwrap_or(4096)
+    }
+
+    pub fn position_embedding(&self) -> PositionEmbedding {
+        self.position_embedding
+            .unwrap_or(if self.num_hidden_layers == 40 {
+                PositionEmbedding::Alibi
+            } else {
+                PositionEmbedding::Rope
+            })
+    }
+
+    pub fn norm_head(&self) -> bool {
+        self.norm_head
+            .unwrap_or(self.vocab_size == BAICHUAN2_VOCAB_SIZE)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn new(size: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+fn rotate_half(xs: &Tensor) -> Result<Tensor> {
+    let last_dim = xs.dim(D::Minus1)?;
+    let xs1 = xs.narrow(D::Minus1, 0, last_dim / 2)?;
+    let xs2 = xs.narrow(D::Minus1, last_dim / 2, last_dim - last_dim / 2)?;
+    Tensor::cat(&[&xs2.neg()?, &xs1], D::Minus1)
+}
+
+impl RotaryEmbedding {
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.hidden_size / cfg.num_attention_heads;
+        let max_seq_len = cfg.max_seq_len();
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / 10000f32.powf(i as f32 / dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(dtype)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
+        Ok(Self {
+            sin: freqs.sin()?,
+            cos: freqs.cos()?,
+        })
+    }
+
+    fn apply_rotary_emb_qkv(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
+        let k_embed = (k.broadcast_mul(&cos)? + rotate_half(k)?.broadcast_mul(&sin))?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: TracedLoraLinear,
+    up_proj: TracedLoraLinear,
+    down_proj: TracedLoraLinear,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let (h_size, i_size) = (cfg.hidden_size, cfg.intermediate_size);
+        let gate_proj = linear_no_bias(
+            h_size,
+            i_size,
+            vb.pp("gate_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let up_proj = linear_no_bias(h_size, i_size, vb.pp("up_proj"), merge, lora_config.clone())?;
+        let down_proj = linear_no_bias(i_size, h_size, vb.pp("down_proj"), merge, lora_config)?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+/// ALiBi slopes of each head, see https://arxiv.org/abs/2108.12409.
+fn alibi_slopes(num_heads: usize) -> Vec<f32> {
+    let slopes = |n: usize| -> Vec<f32> {
+        let start = 2f32.powf(-(2f32.powf(-((n as f32).log2() - 3.))));
+        (1..=n).map(|i| start.powi(i as i32)).collect()
+    };
+    let closest_power_of_2 = 1 << num_heads.ilog2();
+    let mut all = slopes(closest_power_of_2);
+    all.extend(
+        slopes(2 * closest_power_of_2)
+            .into_iter()
+            .step_by(2)
+            .take(num_heads - closest_power_of_2),
+    );
+    all
+}
+
+#[derive(Debug, Clone)]
+enum Positions {
+    Rope(Arc<RotaryEmbedding>),
+    /// Per-head ALiBi slopes of shape (num_heads, 1, 1).
+    Alibi(Tensor),
+}
+
+#[cfg(feature = "flash-attn")]
+fn flash_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    candle_flash_attn::flash_attn(q, k, v, softmax_scale, causal)
+}
+
+#[cfg(not(feature = "flash-attn"))]
+fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
+    unimplemented!("compile with '--features flash-attn'")
+}
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: TracedLoraLinear,
+    k_proj: TracedLoraLinear,
+    v_proj: TracedLoraLinear,
+    o_proj: TracedLoraLinear,
+    num_heads: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    positions: Positions,
+    kv_cache: Option<(Tensor, Tensor)>,
+    use_flash_attn: bool,
+}
+
+impl Attention {
+    fn new(
+        positions: Positions,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let head_dim = hidden_sz / num_heads;
+        let w_pack = vb.pp("W_pack").get((3 * hidden_sz, hidden_sz), "weight")?;
+        let proj = |idx: usize, name: &str| -> Result<TracedLoraLinear> {
+            TracedLoraLinear::from_weights(
+                w_pack.narrow(0, idx * hidden_sz, hidden_sz)?.contiguous()?,
+                None,
+                vb.pp(name),
+                merge,
+                lora_config.clone(),
+            )
+        };
+        let q_proj = proj(0, "q_proj")?;
+        let k_proj = proj(1, "k_proj")?;
+        let v_proj = proj(2, "v_proj")?;
+        let o_proj = linear_no_bias(
+            num_heads * head_dim,
+            hidden_sz,
+            vb.pp("o_proj"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            head_dim,
+            hidden_size: hidden_sz,
+            positions,
+            kv_cache: None,
+            use_flash_attn: cfg.use_flash_attn,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (query_states, key_states) = match &self.positions {
+            Positions::Rope(rotary_emb) => {
+                rotary_emb.apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?
+            }
+            Positions::Alibi(_) => (query_states, key_states),
+        };
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => {
+                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
+                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
+                (key_states, value_states)
+            }
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        let alibi = match &self.positions {
+            Positions::Rope(_) => None,
+            Positions::Alibi(slopes) => {
+                // Biasing by the key position only differs from the relative distance by a
+                // per-query constant, which the softmax cancels out.
+                let kv_len = key_states.dim(2)?;
+                let positions = Tensor::arange(0u32, kv_len as u32, slopes.device())?
+                    .to_dtype(slopes.dtype())?
+                    .reshape((1, 1, kv_len))?;
+                Some(slopes.broadcast_mul(&positions)?)
+            }
+        };
+
+        let attn_output = if self.use_flash_attn && alibi.is_none() {
+            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+            let q = query_states.transpose(1, 2)?;
+            let k = key_states.transpose(1, 2)?;
+            let v = value_states.transpose(1, 2)?;
+            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+        } else {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+
+            let attn_weights = match attention_mask {
+                None => attn_weights,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
+            };
+            let attn_weights = match &alibi {
+                None => attn_weights,
+                Some(alibi) => attn_weights.broadcast_add(alibi)?,
+            };
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_weights.matmul(&value_states)?
+        };
+        attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(
+        positions: Positions,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(
+            positions,
+            cfg,
+            vb.pp("self_attn"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"), merge, lora_config)?;
+        let input_layernorm =
+            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache()
+    }
+}
+
+#[derive(Debug)]
+pub struct Baichuan {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    device: Device,
+    dtype: DType,
+}
+
+impl Baichuan {
+    /// Load a Baichuan or Baichuan2 model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The output head is not adapted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let positions = match cfg.position_embedding() {
+            PositionEmbedding::Rope => Positions::Rope(Arc::new(RotaryEmbedding::new(
+                vb.dtype(),
+                cfg,
+                vb_m.device(),
+            )?)),
+            PositionEmbedding::Alibi => {
+                let slopes = alibi_slopes(cfg.num_attention_heads);
+                Positions::Alibi(
+                    Tensor::from_vec(slopes, (cfg.num_attention_heads, 1, 1), vb_m.device())?
+                        .to_dtype(vb.dtype())?,
+                )
+            }
+        };
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(
+                positions.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                merge,
+                lora_config.clone(),
+            )?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        let lm_head = vb
+            .pp("lm_head")
+            .get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+        let lm_head = if cfg.norm_head() {
+            // Baichuan2 NormHead: every row of the head is L2 normalized.
+            let norm = lm_head
+                .to_dtype(DType::F32)?
+                .sqr()?
+                .sum_keepdim(1)?
+                .sqrt()?
+                .clamp(1e-12, f64::INFINITY)?;
+            lm_head
+                .to_dtype(DType::F32)?
+                .broadcast_div(&norm)?
+                .to_dtype(lm_head.dtype())?
+        } else {
+            lm_head
+        };
+        let lm_head = Linear::new(lm_head, None);
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        b_size: usize,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)?
+            .to_dtype(DType::F32)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
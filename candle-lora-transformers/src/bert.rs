@@ -40,8 +40,8 @@ impl HiddenActLayer {
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 pub struct BertLinear {
     inner: Linear,
     span: tracing::Span,
@@ -54,9 +54,9 @@ impl BertLinear {
         bias: Option<Tensor>,
         merge: bool,
         lora_config: LoraConfig,
-    ) -> Self {
+    ) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "linear");
-        let dims = weight.dims2().unwrap();
+        let dims = weight.dims2()?;
         let linear_config = LoraLinearConfig::new(dims.1, dims.0);
         let mut this = Self {
             inner: Arc::new(Linear::new(weight, bias)),
@@ -71,7 +71,7 @@ impl BertLinear {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             this.get_lora_model(
                 lora_config,
@@ -80,10 +80,10 @@ impl BertLinear {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
-        this
+        Ok(this)
     }
 
     pub fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
@@ -185,6 +185,10 @@ impl Default for Config {
 }
 
 impl Config {
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
     fn _all_mini_lm_l6_v2() -> Self {
         // https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/blob/main/config.json
         Self {
@@ -222,13 +226,7 @@ fn linear(
 ) -> Result<BertLinear> {
     let weight = vb.get((size2, size1), "weight")?;
     let bias = vb.get(size2, "bias")?;
-    Ok(BertLinear::new(
-        vb.pp("lora_linear"),
-        weight,
-        Some(bias),
-        merge,
-        lora_config,
-    ))
+    BertLinear::new(vb.pp("lora_linear"), weight, Some(bias), merge, lora_config)
 }
 
 struct Dropout {
@@ -297,7 +295,7 @@ impl BertEmbedding {
                 None,
                 None,
                 Some(embed_config),
-            )
+            )?
         } else {
             this.get_lora_model(
                 lora_config,
@@ -306,7 +304,7 @@ impl BertEmbedding {
                 None,
                 None,
                 Some(embed_config),
-            )
+            )?
         }
 
         Ok(this)
@@ -439,7 +437,7 @@ impl BertSelfAttention {
         xs.contiguous()
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
         let query_layer = self.query.forward(hidden_states)?;
         let key_layer = self.key.forward(hidden_states)?;
@@ -451,6 +449,10 @@ impl BertSelfAttention {
 
         let attention_scores = query_layer.matmul(&key_layer.t()?)?;
         let attention_scores = (attention_scores / (self.attention_head_size as f64).sqrt())?;
+        let attention_scores = match attention_mask {
+            Some(attention_mask) => attention_scores.broadcast_add(attention_mask)?,
+            None => attention_scores,
+        };
         let attention_probs = {
             let _enter_sm = self.span_softmax.enter();
             candle_nn::ops::softmax(&attention_scores, candle_core::D::Minus1)?
@@ -521,9 +523,9 @@ impl BertAttention {
         })
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let self_outputs = self.self_attention.forward(hidden_states)?;
+        let self_outputs = self.self_attention.forward(hidden_states, attention_mask)?;
         let attention_output = self.self_output.forward(&self_outputs, hidden_states)?;
         Ok(attention_output)
     }
@@ -622,9 +624,9 @@ impl BertLayer {
         })
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let attention_output = self.attention.forward(hidden_states)?;
+        let attention_output = self.attention.forward(hidden_states, attention_mask)?;
         // TODO: Support cross-attention?
         // https://github.com/huggingface/transformers/blob/6eedfa6dd15dc1e22a55ae036f681914e5a0d9a1/src/transformers/models/bert/modeling_bert.py#L523
         // TODO: Support something similar to `apply_chunking_to_forward`?
@@ -658,12 +660,12 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
         let mut hidden_states = hidden_states.clone();
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
         for layer in self.layers.iter() {
-            hidden_states = layer.forward(&hidden_states)?
+            hidden_states = layer.forward(&hidden_states, attention_mask)?
         }
         Ok(hidden_states)
     }
@@ -674,6 +676,7 @@ pub struct BertModel {
     embeddings: BertEmbeddings,
     encoder: BertEncoder,
     pub device: Device,
+    dtype: DType,
     span: tracing::Span,
 }
 
@@ -718,14 +721,48 @@ impl BertModel {
             embeddings,
             encoder,
             device: vb.device().clone(),
+            dtype: vb.dtype(),
             span: tracing::span!(tracing::Level::TRACE, "model"),
         })
     }
 
     pub fn forward(&self, input_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        self.forward_with_mask(input_ids, token_type_ids, None)
+    }
+
+    /// Run the model on a padded batch. `attention_mask` has shape `(batch, seq_len)` and is 1
+    /// for the tokens to attend to and 0 for padding.
+    pub fn forward_with_mask(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
+        let attention_mask = match attention_mask {
+            Some(attention_mask) => Some(get_extended_attention_mask(attention_mask, self.dtype)?),
+            None => None,
+        };
         let embedding_output = self.embeddings.forward(input_ids, token_type_ids)?;
-        let sequence_output = self.encoder.forward(&embedding_output)?;
+        let sequence_output = self
+            .encoder
+            .forward(&embedding_output, attention_mask.as_ref())?;
         Ok(sequence_output)
     }
 }
+
+// https://github.com/huggingface/transformers/blob/6eedfa6dd15dc1e22a55ae036f681914e5a0d9a1/src/transformers/modeling_utils.py#L944
+fn get_extended_attention_mask(attention_mask: &Tensor, dtype: DType) -> Result<Tensor> {
+    let attention_mask = match attention_mask.rank() {
+        3 => attention_mask.unsqueeze(1)?,
+        2 => attention_mask.unsqueeze(1)?.unsqueeze(1)?,
+        _ => candle_core::bail!("Wrong shape for input_ids or attention_mask"),
+    };
+    let attention_mask = attention_mask.to_dtype(dtype)?;
+    // torch.finfo(dtype).min
+    (attention_mask.ones_like()? - &attention_mask)?.broadcast_mul(
+        &Tensor::try_from(f32::MIN)?
+            .to_dtype(dtype)?
+            .to_device(attention_mask.device())?,
+    )
+}
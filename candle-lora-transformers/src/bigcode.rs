@@ -9,8 +9,8 @@ use candle_nn::{Embedding, LayerNorm, Linear, Module, VarBuilder};
 use std::ops::Deref;
 use std::sync::Arc;
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct CustomLinear {
     inner: Linear,
 }
@@ -23,8 +23,8 @@ impl Deref for CustomLinear {
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct CustomEmbedding {
     inner: Embedding,
 }
@@ -64,7 +64,7 @@ fn linear(
             None,
             None,
             None,
-        )
+        )?
     } else {
         this.get_lora_model(
             lora_config,
@@ -73,7 +73,7 @@ fn linear(
             None,
             None,
             None,
-        )
+        )?
     }
 
     Ok(this)
@@ -100,7 +100,7 @@ fn embedding(
             None,
             None,
             Some(embed_config),
-        )
+        )?
     } else {
         this.get_lora_model(
             lora_config,
@@ -109,7 +109,7 @@ fn embedding(
             None,
             None,
             Some(embed_config),
-        )
+        )?
     }
 
     Ok(this)
@@ -0,0 +1,407 @@
+//! BLOOM, https://huggingface.co/bigscience/bloom
+//!
+//! Positions are encoded with ALiBi attention biases. The query, key and value projections are
+//! fused into a single `query_key_value` matrix laid out per head, which is split at load time
+//! into separate q/k/v LoRA layers (see [`candle_lora::split_packed_qkv`]), the layout produced by
+//! `TracedArchitecture::Bloom` conversion. The output head is tied to the token embeddings.
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_lora::{split_packed_qkv, LoraConfig};
+use candle_nn::{Embedding, LayerNorm, Linear, VarBuilder};
+use serde::Deserialize;
+
+use crate::with_tracing::{linear, TracedLoraLinear};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    #[serde(alias = "n_embed")]
+    pub hidden_size: usize,
+    #[serde(alias = "n_layer")]
+    pub num_hidden_layers: usize,
+    #[serde(alias = "n_head")]
+    pub num_attention_heads: usize,
+    pub layer_norm_epsilon: f64,
+    #[serde(default)]
+    pub apply_residual_connection_post_layernorm: bool,
+}
+
+impl Config {
+    /// bigscience/bloom-560m
+    pub fn bloom_560m() -> Self {
+        Self {
+            vocab_size: 250880,
+            hidden_size: 1024,
+            num_hidden_layers: 24,
+            num_attention_heads: 16,
+            layer_norm_epsilon: 1e-5,
+            apply_residual_connection_post_layernorm: false,
+        }
+    }
+
+    /// bigscience/bloom-7b1
+    pub fn bloom_7b1() -> Self {
+        Self {
+            hidden_size: 4096,
+            num_hidden_layers: 30,
+            num_attention_heads: 32,
+            ..Self::bloom_560m()
+        }
+    }
+}
+
+/// ALiBi slopes of each head, see https://arxiv.org/abs/2108.12409.
+fn alibi_slopes(num_heads: usize) -> Vec<f32> {
+    let slopes = |n: usize| -> Vec<f32> {
+        let start = 2f32.powf(-(2f32.powf(-((n as f32).log2() - 3.))));
+        (1..=n).map(|i| start.powi(i as i32)).collect()
+    };
+    let closest_power_of_2 = 1 << num_heads.ilog2();
+    let mut all = slopes(closest_power_of_2);
+    all.extend(
+        slopes(2 * closest_power_of_2)
+            .into_iter()
+            .step_by(2)
+            .take(num_heads - closest_power_of_2),
+    );
+    all
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    dense_h_to_4h: TracedLoraLinear,
+    dense_4h_to_h: TracedLoraLinear,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let (h_size, i_size) = (cfg.hidden_size, 4 * cfg.hidden_size);
+        let dense_h_to_4h = linear(
+            h_size,
+            i_size,
+            vb.pp("dense_h_to_4h"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let dense_4h_to_h = linear(i_size, h_size, vb.pp("dense_4h_to_h"), merge, lora_config)?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        // BLOOM uses the tanh approximation of gelu.
+        xs.apply(&self.dense_h_to_4h)?
+            .gelu()?
+            .apply(&self.dense_4h_to_h)
+    }
+}
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: TracedLoraLinear,
+    k_proj: TracedLoraLinear,
+    v_proj: TracedLoraLinear,
+    dense: TracedLoraLinear,
+    num_heads: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    /// Per-head ALiBi slopes of shape (num_heads, 1, 1).
+    alibi_slopes: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(
+        alibi_slopes: Tensor,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let head_dim = hidden_sz / num_heads;
+
+        // The fused rows are laid out per head as (query, key, value), which is the packed
+        // layout with one query head per key-value head.
+        let vb_qkv = vb.pp("query_key_value");
+        let (q_w, k_w, v_w) = split_packed_qkv(
+            &vb_qkv.get((3 * hidden_sz, hidden_sz), "weight")?,
+            num_heads,
+            num_heads,
+        )?;
+        let (q_b, k_b, v_b) =
+            split_packed_qkv(&vb_qkv.get(3 * hidden_sz, "bias")?, num_heads, num_heads)?;
+        let q_proj = TracedLoraLinear::from_weights(
+            q_w,
+            Some(q_b),
+            vb.pp("q_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let k_proj = TracedLoraLinear::from_weights(
+            k_w,
+            Some(k_b),
+            vb.pp("k_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let v_proj = TracedLoraLinear::from_weights(
+            v_w,
+            Some(v_b),
+            vb.pp("v_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let dense = linear(hidden_sz, hidden_sz, vb.pp("dense"), merge, lora_config)?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            dense,
+            num_heads,
+            head_dim,
+            hidden_size: hidden_sz,
+            alibi_slopes,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => {
+                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
+                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
+                (key_states, value_states)
+            }
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        // BLOOM biases by the key position, which only differs from the relative distance by a
+        // per-query constant that the softmax cancels out.
+        let kv_len = key_states.dim(2)?;
+        let positions = Tensor::arange(0u32, kv_len as u32, self.alibi_slopes.device())?
+            .to_dtype(self.alibi_slopes.dtype())?
+            .reshape((1, 1, kv_len))?;
+        let alibi = self.alibi_slopes.broadcast_mul(&positions)?;
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_weights =
+            (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?.broadcast_add(&alibi)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.dense)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    self_attention: Attention,
+    mlp: MLP,
+    input_layernorm: LayerNorm,
+    post_attention_layernorm: LayerNorm,
+    apply_residual_connection_post_layernorm: bool,
+}
+
+impl DecoderLayer {
+    fn new(
+        alibi_slopes: Tensor,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let self_attention = Attention::new(
+            alibi_slopes,
+            cfg,
+            vb.pp("self_attention"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"), merge, lora_config)?;
+        let input_layernorm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb.pp("input_layernorm"),
+        )?;
+        let post_attention_layernorm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attention,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+            apply_residual_connection_post_layernorm: cfg.apply_residual_connection_post_layernorm,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let layernorm_output = xs.apply(&self.input_layernorm)?;
+        let residual = if self.apply_residual_connection_post_layernorm {
+            &layernorm_output
+        } else {
+            xs
+        };
+        let xs = (self
+            .self_attention
+            .forward(&layernorm_output, attention_mask)?
+            + residual)?;
+        let layernorm_output = xs.apply(&self.post_attention_layernorm)?;
+        let residual = if self.apply_residual_connection_post_layernorm {
+            &layernorm_output
+        } else {
+            &xs
+        };
+        layernorm_output.apply(&self.mlp)? + residual
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attention.clear_kv_cache()
+    }
+}
+
+#[derive(Debug)]
+pub struct Bloom {
+    word_embeddings: Embedding,
+    word_embeddings_layernorm: LayerNorm,
+    layers: Vec<DecoderLayer>,
+    ln_f: LayerNorm,
+    lm_head: Linear,
+    device: Device,
+    dtype: DType,
+}
+
+impl Bloom {
+    /// Load a BLOOM model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The output head is tied to the token
+    /// embeddings and is not adapted. Both `BloomForCausalLM` checkpoints (`transformer.h.N...`)
+    /// and the bigscience checkpoints saved without the `transformer` prefix are accepted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let vb_m = if vb.contains_tensor("transformer.word_embeddings.weight") {
+            vb.pp("transformer")
+        } else {
+            vb.clone()
+        };
+        let word_embeddings =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("word_embeddings"))?;
+        let word_embeddings_layernorm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb_m.pp("word_embeddings_layernorm"),
+        )?;
+        let num_heads = cfg.num_attention_heads;
+        let alibi_slopes =
+            Tensor::from_vec(alibi_slopes(num_heads), (num_heads, 1, 1), vb.device())?
+                .to_dtype(vb.dtype())?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("h");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(
+                alibi_slopes.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                merge,
+                lora_config.clone(),
+            )?;
+            layers.push(layer)
+        }
+        let ln_f = candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_epsilon, vb_m.pp("ln_f"))?;
+        let lm_head = Linear::new(word_embeddings.embeddings().clone(), None);
+        Ok(Self {
+            word_embeddings,
+            word_embeddings_layernorm,
+            layers,
+            ln_f,
+            lm_head,
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        b_size: usize,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    /// Logits of the last position, `(batch, 1, vocab_size)`.
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self
+            .word_embeddings
+            .forward(input_ids)?
+            .apply(&self.word_embeddings_layernorm)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref())?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.ln_f)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
@@ -0,0 +1,600 @@
+//! ChatGLM2/3 and GLM-4, https://github.com/THUDM/ChatGLM3 and https://github.com/THUDM/GLM-4
+//!
+//! The query, key and value projections are packed into `query_key_value` and the gate and up
+//! projections into `dense_h_to_4h`. Both stay fused and carry a single LoRA layer each, matching
+//! the modules targeted by PEFT fine-tunes of these models.
+//!
+//! P-tuning v2 checkpoints are supported through `pre_seq_len`: the prefix encoder produces
+//! per-layer keys and values which are prepended to the attention of every layer.
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{Linear, VarBuilder};
+use serde::Deserialize;
+
+use crate::with_tracing::{linear, linear_no_bias, TracedLoraLinear};
+
+fn default_rope_ratio() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    pub num_layers: usize,
+    pub padded_vocab_size: usize,
+    pub hidden_size: usize,
+    pub ffn_hidden_size: usize,
+    pub kv_channels: usize,
+    pub num_attention_heads: usize,
+    pub seq_length: usize,
+    pub layernorm_epsilon: f64,
+    pub rmsnorm: bool,
+    pub apply_residual_connection_post_layernorm: bool,
+    pub post_layer_norm: bool,
+    pub add_bias_linear: bool,
+    pub add_qkv_bias: bool,
+    pub multi_query_attention: bool,
+    pub multi_query_group_num: usize,
+    pub apply_query_key_layer_scaling: bool,
+    pub fp32_residual_connection: bool,
+    /// Scales the rope base of 10000, e.g. 500 for GLM-4.
+    #[serde(default = "default_rope_ratio")]
+    pub rope_ratio: f64,
+    /// Length of the P-tuning v2 prefix, if the checkpoint has a prefix encoder.
+    #[serde(default)]
+    pub pre_seq_len: Option<usize>,
+    #[serde(default)]
+    pub prefix_projection: bool,
+}
+
+impl Config {
+    pub fn glm3_6b() -> Self {
+        Self {
+            num_layers: 28,
+            padded_vocab_size: 65024,
+            hidden_size: 4096,
+            ffn_hidden_size: 13696,
+            kv_channels: 128,
+            num_attention_heads: 32,
+            seq_length: 8192,
+            layernorm_epsilon: 1e-5,
+            rmsnorm: true,
+            apply_residual_connection_post_layernorm: false,
+            post_layer_norm: true,
+            add_bias_linear: false,
+            add_qkv_bias: true,
+            multi_query_attention: true,
+            multi_query_group_num: 2,
+            apply_query_key_layer_scaling: true,
+            fp32_residual_connection: false,
+            rope_ratio: 1.,
+            pre_seq_len: None,
+            prefix_projection: false,
+        }
+    }
+
+    pub fn glm4_9b() -> Self {
+        Self {
+            num_layers: 40,
+            padded_vocab_size: 151552,
+            seq_length: 131072,
+            rope_ratio: 500.,
+            ..Self::glm3_6b()
+        }
+    }
+
+    fn num_kv_groups(&self) -> usize {
+        if self.multi_query_attention {
+            self.multi_query_group_num
+        } else {
+            self.num_attention_heads
+        }
+    }
+}
+
+fn linear_b(
+    d1: usize,
+    d2: usize,
+    bias: bool,
+    vb: VarBuilder,
+    merge: bool,
+    lora_config: LoraConfig,
+) -> Result<TracedLoraLinear> {
+    if bias {
+        linear(d1, d2, vb, merge, lora_config)
+    } else {
+        linear_no_bias(d1, d2, vb, merge, lora_config)
+    }
+}
+
+fn norm(cfg: &Config, vb: VarBuilder) -> Result<candle_nn::LayerNorm> {
+    if cfg.rmsnorm {
+        Ok(candle_nn::rms_norm(cfg.hidden_size, cfg.layernorm_epsilon, vb)?.into_inner())
+    } else {
+        candle_nn::layer_norm(cfg.hidden_size, cfg.layernorm_epsilon, vb)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    cache: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dtype: DType, dev: &Device) -> Result<Self> {
+        let rotary_dim = cfg.kv_channels;
+        let n_elem = rotary_dim / 2;
+        let base = 10_000f64 * cfg.rope_ratio;
+        let inv_freq: Vec<_> = (0..n_elem)
+            .step_by(2)
+            .map(|i| 1f32 / base.powf(i as f64 / n_elem as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
+        let t = Tensor::arange(0u32, cfg.seq_length as u32, dev)?
+            .to_dtype(dtype)?
+            .reshape((cfg.seq_length, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        let cache = Tensor::stack(&[&freqs.cos()?, &freqs.sin()?], D::Minus1)?;
+        Ok(Self { cache })
+    }
+
+    fn apply(&self, xs: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (seqlen, _b, np, _hn) = xs.dims4()?;
+        let cache = self.cache.narrow(0, seqlen_offset, seqlen)?;
+        let rot_dim = cache.dim(D::Minus2)? * 2;
+        let (xs, xs_pass) = (
+            xs.narrow(D::Minus1, 0, rot_dim)?,
+            xs.narrow(D::Minus1, rot_dim, rot_dim)?,
+        );
+        let xshaped = xs.reshape((seqlen, (), np, rot_dim / 2, 2))?;
+        let cache = cache.reshape((seqlen, (), 1, rot_dim / 2, 2))?;
+        let (xshaped0, xshaped1) = (
+            xshaped.i((.., .., .., .., 0))?,
+            xshaped.i((.., .., .., .., 1))?,
+        );
+        let (cache0, cache1) = (cache.i((.., .., .., .., 0))?, cache.i((.., .., .., .., 1))?);
+        let xs_out = Tensor::stack(
+            &[
+                (xshaped0.broadcast_mul(&cache0)? - xshaped1.broadcast_mul(&cache1)?)?,
+                (xshaped1.broadcast_mul(&cache0)? + xshaped0.broadcast_mul(&cache1)?)?,
+            ],
+            D::Minus1,
+        )?;
+        let xs_out = xs_out.flatten_from(3)?;
+        Tensor::cat(&[xs_out, xs_pass], D::Minus1)
+    }
+}
+
+/// Causal mask of shape (q_len, kv_len), 1 where the key is hidden from the query. Keys before
+/// the last `q_len` ones (prefix and cache) are always visible.
+fn get_mask(q_len: usize, kv_len: usize, device: &Device) -> Result<Tensor> {
+    let offset = kv_len - q_len;
+    let mask: Vec<_> = (0..q_len)
+        .flat_map(|i| (0..kv_len).map(move |j| u8::from(j > i + offset)))
+        .collect();
+    Tensor::from_slice(&mask, (q_len, kv_len), device)
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?
+        .to_dtype(on_false.dtype())?
+        .broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+#[derive(Debug, Clone)]
+struct CoreAttention {
+    coeff: Option<f64>,
+    norm_factor: f64,
+}
+
+impl CoreAttention {
+    fn new(layer_number: usize, cfg: &Config) -> Self {
+        let norm_factor = (cfg.kv_channels as f64).sqrt();
+        let (norm_factor, coeff) = if cfg.apply_query_key_layer_scaling {
+            let coeff = f64::max(1.0, layer_number as f64);
+            (norm_factor * coeff, Some(coeff))
+        } else {
+            (norm_factor, None)
+        };
+        Self { coeff, norm_factor }
+    }
+
+    /// Inputs are (seq_len, b_sz, num_heads, head_dim), the output is (seq_len, b_sz, hidden).
+    fn forward(
+        &self,
+        query_layer: &Tensor,
+        key_layer: &Tensor,
+        value_layer: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        // (b_sz, num_heads, seq_len, head_dim)
+        let query_layer = query_layer.permute((1, 2, 0, 3))?.contiguous()?;
+        let key_layer = key_layer.permute((1, 2, 0, 3))?.contiguous()?;
+        let value_layer = value_layer.permute((1, 2, 0, 3))?.contiguous()?;
+        let scores = (query_layer.matmul(&key_layer.t()?)? / self.norm_factor)?;
+        let scores = match self.coeff {
+            None => scores,
+            Some(coeff) => (scores * coeff)?,
+        };
+        let scores = match attention_mask {
+            Some(mask) => masked_fill(
+                &scores,
+                &mask.broadcast_left((scores.dim(0)?, scores.dim(1)?))?,
+                f32::NEG_INFINITY,
+            )?,
+            None => scores,
+        };
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        probs
+            .matmul(&value_layer)?
+            .permute((2, 0, 1, 3))?
+            .contiguous()?
+            .flatten_from(D::Minus2)
+    }
+}
+
+#[derive(Debug)]
+struct SelfAttention {
+    query_key_value: TracedLoraLinear,
+    core_attention: CoreAttention,
+    dense: TracedLoraLinear,
+    num_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    /// P-tuning v2 keys and values, each (pre_seq_len, 1, num_kv_groups, head_dim).
+    prefix: Option<(Tensor, Tensor)>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl SelfAttention {
+    fn new(
+        layer_number: usize,
+        prefix: Option<(Tensor, Tensor)>,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let num_kv_groups = cfg.num_kv_groups();
+        let projection_size = cfg.kv_channels * cfg.num_attention_heads;
+        let qkv_hidden_size = projection_size + 2 * cfg.kv_channels * num_kv_groups;
+        let query_key_value = linear_b(
+            cfg.hidden_size,
+            qkv_hidden_size,
+            cfg.add_bias_linear || cfg.add_qkv_bias,
+            vb.pp("query_key_value"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let dense = linear_b(
+            projection_size,
+            cfg.hidden_size,
+            cfg.add_bias_linear,
+            vb.pp("dense"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            query_key_value,
+            core_attention: CoreAttention::new(layer_number, cfg),
+            dense,
+            num_heads: cfg.num_attention_heads,
+            num_kv_groups,
+            head_dim: cfg.kv_channels,
+            prefix,
+            kv_cache: None,
+        })
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+
+    fn repeat_kv(&self, xs: Tensor) -> Result<Tensor> {
+        let n_rep = self.num_heads / self.num_kv_groups;
+        if n_rep == 1 {
+            Ok(xs)
+        } else {
+            let (d0, d1, d2, d3) = xs.dims4()?;
+            xs.unsqueeze(D::Minus2)?
+                .expand((d0, d1, d2, n_rep, d3))?
+                .reshape((d0, d1, self.num_heads, d3))
+        }
+    }
+
+    fn forward(&mut self, xs: &Tensor, rotary_emb: &RotaryEmbedding) -> Result<Tensor> {
+        let (seq_len, b_sz, _) = xs.dims3()?;
+        let mixed_x_layer = xs.apply(&self.query_key_value)?;
+        let (hd, q_size, kv_size) = (
+            self.head_dim,
+            self.num_heads * self.head_dim,
+            self.num_kv_groups * self.head_dim,
+        );
+        let query_layer = mixed_x_layer.narrow(D::Minus1, 0, q_size)?.reshape((
+            seq_len,
+            b_sz,
+            self.num_heads,
+            hd,
+        ))?;
+        let key_layer = mixed_x_layer.narrow(D::Minus1, q_size, kv_size)?.reshape((
+            seq_len,
+            b_sz,
+            self.num_kv_groups,
+            hd,
+        ))?;
+        let value_layer = mixed_x_layer
+            .narrow(D::Minus1, q_size + kv_size, kv_size)?
+            .reshape((seq_len, b_sz, self.num_kv_groups, hd))?;
+
+        // The prefix has no position: rotary offsets only count the cached tokens.
+        let prefix_len = self.prefix.as_ref().map_or(Ok(0), |(k, _)| k.dim(0))?;
+        let seqlen_offset = match &self.kv_cache {
+            None => 0,
+            Some((prev_k, _)) => prev_k.dim(0)? - prefix_len,
+        };
+        let query_layer = rotary_emb.apply(&query_layer, seqlen_offset)?;
+        let key_layer = rotary_emb.apply(&key_layer, seqlen_offset)?;
+
+        let prev = match (&self.kv_cache, &self.prefix) {
+            (Some((prev_k, prev_v)), _) => Some((prev_k.clone(), prev_v.clone())),
+            (None, Some((prefix_k, prefix_v))) => {
+                let shape = (prefix_len, b_sz, self.num_kv_groups, hd);
+                Some((
+                    prefix_k.broadcast_as(shape)?.to_dtype(key_layer.dtype())?,
+                    prefix_v
+                        .broadcast_as(shape)?
+                        .to_dtype(value_layer.dtype())?,
+                ))
+            }
+            (None, None) => None,
+        };
+        let (key_layer, value_layer) = match prev {
+            None => (key_layer, value_layer),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[&prev_k, &key_layer], 0)?,
+                Tensor::cat(&[&prev_v, &value_layer], 0)?,
+            ),
+        };
+        self.kv_cache = Some((key_layer.clone(), value_layer.clone()));
+
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            Some(get_mask(seq_len, key_layer.dim(0)?, xs.device())?)
+        };
+        let key_layer = self.repeat_kv(key_layer)?;
+        let value_layer = self.repeat_kv(value_layer)?;
+        self.core_attention
+            .forward(
+                &query_layer,
+                &key_layer,
+                &value_layer,
+                attention_mask.as_ref(),
+            )?
+            .apply(&self.dense)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+struct MLP {
+    dense_h_to_4h: TracedLoraLinear,
+    dense_4h_to_h: TracedLoraLinear,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let dense_h_to_4h = linear_b(
+            cfg.hidden_size,
+            cfg.ffn_hidden_size * 2,
+            cfg.add_bias_linear,
+            vb.pp("dense_h_to_4h"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let dense_4h_to_h = linear_b(
+            cfg.ffn_hidden_size,
+            cfg.hidden_size,
+            cfg.add_bias_linear,
+            vb.pp("dense_4h_to_h"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.dense_h_to_4h)?
+            .apply(&candle_nn::Activation::Swiglu)?
+            .apply(&self.dense_4h_to_h)
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    input_layernorm: candle_nn::LayerNorm,
+    self_attention: SelfAttention,
+    post_attention_layernorm: candle_nn::LayerNorm,
+    mlp: MLP,
+    apply_residual_connection_post_layernorm: bool,
+}
+
+impl Block {
+    fn new(
+        layer_number: usize,
+        prefix: Option<(Tensor, Tensor)>,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let input_layernorm = norm(cfg, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = norm(cfg, vb.pp("post_attention_layernorm"))?;
+        let self_attention = SelfAttention::new(
+            layer_number,
+            prefix,
+            cfg,
+            vb.pp("self_attention"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"), merge, lora_config)?;
+        Ok(Self {
+            input_layernorm,
+            self_attention,
+            post_attention_layernorm,
+            mlp,
+            apply_residual_connection_post_layernorm: cfg.apply_residual_connection_post_layernorm,
+        })
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.self_attention.reset_kv_cache()
+    }
+
+    fn forward(&mut self, xs: &Tensor, rotary_emb: &RotaryEmbedding) -> Result<Tensor> {
+        let layernorm_output = xs.apply(&self.input_layernorm)?;
+        let attention_output = self.self_attention.forward(&layernorm_output, rotary_emb)?;
+        let residual = if self.apply_residual_connection_post_layernorm {
+            &layernorm_output
+        } else {
+            xs
+        };
+        let layernorm_input = (residual + attention_output)?;
+        let layernorm_output = layernorm_input.apply(&self.post_attention_layernorm)?;
+        let mlp_output = layernorm_output.apply(&self.mlp)?;
+        let residual = if self.apply_residual_connection_post_layernorm {
+            &layernorm_output
+        } else {
+            &layernorm_input
+        };
+        mlp_output + residual
+    }
+}
+
+/// Compute the P-tuning v2 keys and values of every layer from the prefix encoder.
+fn prefix_key_values(cfg: &Config, vb: VarBuilder) -> Result<Vec<(Tensor, Tensor)>> {
+    let pre_seq_len = match cfg.pre_seq_len {
+        Some(pre_seq_len) => pre_seq_len,
+        None => return Ok(Vec::new()),
+    };
+    let num_kv_groups = cfg.num_kv_groups();
+    let kv_size = cfg.num_layers * 2 * cfg.kv_channels * num_kv_groups;
+    let past_key_values = if cfg.prefix_projection {
+        let embedding = vb
+            .pp("embedding")
+            .get((pre_seq_len, cfg.hidden_size), "weight")?;
+        let trans0 = candle_nn::linear(cfg.hidden_size, cfg.hidden_size, vb.pp("trans.0"))?;
+        let trans2 = candle_nn::linear(cfg.hidden_size, kv_size, vb.pp("trans.2"))?;
+        embedding.apply(&trans0)?.tanh()?.apply(&trans2)?
+    } else {
+        vb.pp("embedding").get((pre_seq_len, kv_size), "weight")?
+    };
+    let past_key_values = past_key_values.reshape((
+        pre_seq_len,
+        cfg.num_layers,
+        2,
+        num_kv_groups,
+        cfg.kv_channels,
+    ))?;
+    let shape = (pre_seq_len, 1, num_kv_groups, cfg.kv_channels);
+    (0..cfg.num_layers)
+        .map(|layer_idx| {
+            let layer = past_key_values.i((.., layer_idx))?;
+            Ok((
+                layer.i((.., 0))?.contiguous()?.reshape(shape)?,
+                layer.i((.., 1))?.contiguous()?.reshape(shape)?,
+            ))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct ChatGLM {
+    word_embeddings: candle_nn::Embedding,
+    layers: Vec<Block>,
+    final_layernorm: Option<candle_nn::LayerNorm>,
+    rotary_emb: RotaryEmbedding,
+    output_layer: Linear,
+    fp32_residual_connection: bool,
+}
+
+impl ChatGLM {
+    /// Load a ChatGLM2/3 or GLM-4 model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The output layer is not adapted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let vb = vb.pp("transformer");
+        let word_embeddings = candle_nn::embedding(
+            cfg.padded_vocab_size,
+            cfg.hidden_size,
+            vb.pp("embedding").pp("word_embeddings"),
+        )?;
+        let mut prefix = prefix_key_values(cfg, vb.pp("prefix_encoder"))?.into_iter();
+        let vb_l = vb.pp("encoder").pp("layers");
+        let mut layers = Vec::with_capacity(cfg.num_layers);
+        for layer_idx in 0..cfg.num_layers {
+            layers.push(Block::new(
+                layer_idx + 1,
+                prefix.next(),
+                cfg,
+                vb_l.pp(layer_idx),
+                merge,
+                lora_config.clone(),
+            )?)
+        }
+        let final_layernorm = if cfg.post_layer_norm {
+            Some(norm(cfg, vb.pp("encoder").pp("final_layernorm"))?)
+        } else {
+            None
+        };
+        let rotary_emb = RotaryEmbedding::new(cfg, vb.dtype(), vb.device())?;
+        let output_layer = candle_nn::linear_no_bias(
+            cfg.hidden_size,
+            cfg.padded_vocab_size,
+            vb.pp("output_layer"),
+        )?;
+        Ok(Self {
+            word_embeddings,
+            layers,
+            final_layernorm,
+            rotary_emb,
+            output_layer,
+            fp32_residual_connection: cfg.fp32_residual_connection,
+        })
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.reset_kv_cache()
+        }
+    }
+
+    /// Run the model on `(b_sz, seq_len)` token ids and return the logits of the last position.
+    pub fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let (_b_size, seq_len) = xs.dims2()?;
+        // (b_sz, seq_len, hidden) -> (seq_len, b_sz, hidden)
+        let xs = self.word_embeddings.forward(xs)?.transpose(0, 1)?;
+        let mut xs = if self.fp32_residual_connection {
+            xs.to_dtype(DType::F32)?
+        } else {
+            xs.contiguous()?
+        };
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, &self.rotary_emb)?
+        }
+        let xs = match self.final_layernorm.as_ref() {
+            None => xs,
+            Some(ln) => xs.apply(ln)?,
+        };
+        xs.i(seq_len - 1)?.apply(&self.output_layer)
+    }
+}
@@ -0,0 +1,140 @@
+//! Cross-encoder rerankers, BERT sequence classification over (query, passage) pairs.
+//!
+//! The encoder carries the LoRA layers of the BERT model while the pooler and classifier are
+//! loaded as is, which matches reranking fine-tunes that train the classifier as a module to
+//! save, e.g. `cross-encoder/ms-marco-MiniLM-L-6-v2`.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, IndexOp, Module, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_nn::{linear, Linear, VarBuilder};
+use serde::Deserialize;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer};
+
+use crate::bert::{BertModel, Config};
+
+/// The labels of the classifier, read from the same `config.json` as the BERT `Config`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClassifierConfig {
+    #[serde(default)]
+    pub id2label: HashMap<String, String>,
+}
+
+impl ClassifierConfig {
+    pub fn num_labels(&self) -> usize {
+        // transformers defaults to two labels when none are given.
+        if self.id2label.is_empty() {
+            2
+        } else {
+            self.id2label.len()
+        }
+    }
+}
+
+pub struct CrossEncoder {
+    bert: BertModel,
+    pooler: Linear,
+    classifier: Linear,
+}
+
+impl CrossEncoder {
+    /// Load a cross-encoder which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights.
+    pub fn new(
+        vb: VarBuilder,
+        config: &Config,
+        num_labels: usize,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let hidden_size = config.hidden_size();
+        let bert = BertModel::load(vb.clone(), config, merge, lora_config)?;
+        let pooler = linear(hidden_size, hidden_size, vb.pp("bert.pooler.dense"))?;
+        let classifier = linear(hidden_size, num_labels, vb.pp("classifier"))?;
+        Ok(Self {
+            bert,
+            pooler,
+            classifier,
+        })
+    }
+
+    /// Classification logits `(batch, num_labels)` of a padded batch of pairs.
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let hidden_states =
+            self.bert
+                .forward_with_mask(input_ids, token_type_ids, attention_mask)?;
+        let pooled = self.pooler.forward(&hidden_states.i((.., 0))?)?.tanh()?;
+        self.classifier.forward(&pooled)
+    }
+
+    /// Relevance score of each pair, the logit of the last label, which is the positive one of
+    /// binary classifiers.
+    pub fn score(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let logits = self.forward(input_ids, token_type_ids, attention_mask)?;
+        logits.i((.., logits.dim(1)? - 1))
+    }
+
+    /// Score `passages` against `query` and return `(passage index, score)` sorted by
+    /// decreasing score. With `sigmoid`, scores are mapped to `(0, 1)`.
+    pub fn rank(
+        &self,
+        tokenizer: &Tokenizer,
+        query: &str,
+        passages: &[&str],
+        sigmoid: bool,
+    ) -> Result<Vec<(usize, f32)>> {
+        if passages.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut tokenizer = tokenizer.clone();
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }));
+        let pairs = passages
+            .iter()
+            .map(|passage| (query.to_string(), passage.to_string()))
+            .collect::<Vec<_>>();
+        let encodings = tokenizer
+            .encode_batch(pairs, true)
+            .map_err(candle_core::Error::msg)?;
+        let device = &self.bert.device;
+        let stack = |get: fn(&tokenizers::Encoding) -> &[u32]| {
+            let rows = encodings
+                .iter()
+                .map(|encoding| Tensor::new(get(encoding), device))
+                .collect::<Result<Vec<_>>>()?;
+            Tensor::stack(&rows, 0)
+        };
+        let input_ids = stack(|encoding| encoding.get_ids())?;
+        let token_type_ids = stack(|encoding| encoding.get_type_ids())?;
+        let attention_mask = stack(|encoding| encoding.get_attention_mask())?;
+
+        let scores = self.score(&input_ids, &token_type_ids, Some(&attention_mask))?;
+        let scores = if sigmoid {
+            candle_nn::ops::sigmoid(&scores)?
+        } else {
+            scores
+        };
+        let mut ranked = scores
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        ranked.sort_by(|u, v| v.1.total_cmp(&u.1));
+        Ok(ranked)
+    }
+}
@@ -0,0 +1,815 @@
+//! DeepSeek-V2 and DeepSeek-V3, https://github.com/deepseek-ai/DeepSeek-V2
+//!
+//! Attention is multi-head latent attention (MLA): queries and keys/values are projected through
+//! low rank bottlenecks (`q_a_proj` → `q_b_proj`, `kv_a_proj_with_mqa` → `kv_b_proj`). All
+//! attention projections carry LoRA adapters; the dense and mixture-of-experts MLPs are frozen.
+
+use std::{f32::consts::PI, sync::Arc};
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{embedding, rms_norm, Activation, Embedding, Linear, RmsNorm, VarBuilder};
+use serde::Deserialize;
+
+use crate::with_tracing::{linear, linear_no_bias, TracedLoraLinear};
+
+fn default_routed_scaling_factor() -> f64 {
+    1.0
+}
+
+fn default_moe_layer_freq() -> usize {
+    1
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::Silu
+}
+
+fn default_n_group() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum TopkMethod {
+    #[default]
+    #[serde(rename = "greedy")]
+    Greedy,
+    #[serde(rename = "group_limited_greedy")]
+    GroupLimitedGreedy,
+    /// DeepSeek-V3 routing with a per-expert score correction bias.
+    #[serde(rename = "noaux_tc")]
+    NoAuxTc,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum ScoringFunc {
+    #[default]
+    #[serde(rename = "softmax")]
+    Softmax,
+    #[serde(rename = "sigmoid")]
+    Sigmoid,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeepSeekV2RopeScaling {
+    pub original_max_position_embeddings: usize,
+    pub beta_fast: f32,
+    pub beta_slow: f32,
+    pub mscale: f32,
+    pub mscale_all_dim: f32,
+    pub factor: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeepSeekV2Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub moe_intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub n_shared_experts: Option<usize>,
+    pub n_routed_experts: Option<usize>,
+    #[serde(default = "default_routed_scaling_factor")]
+    pub routed_scaling_factor: f64,
+    #[serde(default)]
+    pub topk_method: TopkMethod,
+    pub num_experts_per_tok: Option<usize>,
+    #[serde(default = "default_moe_layer_freq")]
+    pub moe_layer_freq: usize,
+    #[serde(default)]
+    pub first_k_dense_replace: usize,
+    #[serde(default)]
+    pub norm_topk_prob: bool,
+    #[serde(default)]
+    pub scoring_func: ScoringFunc,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    pub rope_theta: f32,
+    /// Only YaRN scaling is supported, as used by all released DeepSeek-V2/V3 checkpoints.
+    pub rope_scaling: Option<DeepSeekV2RopeScaling>,
+    #[serde(default)]
+    pub attention_bias: bool,
+    pub q_lora_rank: Option<usize>,
+    pub qk_rope_head_dim: usize,
+    pub kv_lora_rank: usize,
+    pub v_head_dim: usize,
+    pub qk_nope_head_dim: usize,
+    #[serde(default = "default_n_group")]
+    pub n_group: usize,
+    #[serde(default = "default_n_group")]
+    pub topk_group: usize,
+}
+
+impl DeepSeekV2Config {
+    fn q_head_dim(&self) -> usize {
+        self.qk_rope_head_dim + self.qk_nope_head_dim
+    }
+
+    fn softmax_scale(&self) -> f32 {
+        let softmax_scale = 1.0 / (self.q_head_dim() as f32).sqrt();
+        match &self.rope_scaling {
+            Some(scaling) => {
+                let mscale = yarn_get_mscale(scaling.factor, scaling.mscale_all_dim);
+                softmax_scale * mscale * mscale
+            }
+            None => softmax_scale,
+        }
+    }
+}
+
+fn yarn_get_mscale(scale: f32, mscale: f32) -> f32 {
+    if scale <= 1. {
+        return 1.;
+    }
+    0.1 * mscale * scale.ln() + 1.
+}
+
+fn yarn_find_correction_dim(num_rot: f32, dim: usize, base: f32, max_pos: usize) -> f32 {
+    (dim as f32 * (max_pos as f32 / (num_rot * 2. * PI)).ln()) / (2. * base.ln())
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &DeepSeekV2Config, dtype: DType, dev: &Device) -> Result<Self> {
+        let dim = cfg.qk_rope_head_dim;
+        let max_seq_len = cfg.max_position_embeddings;
+        let freq_extra: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / dim as f32))
+            .collect();
+        let (inv_freq, mscale) = match &cfg.rope_scaling {
+            None => (freq_extra, 1.),
+            Some(scaling) => {
+                let low = yarn_find_correction_dim(
+                    scaling.beta_fast,
+                    dim,
+                    cfg.rope_theta,
+                    scaling.original_max_position_embeddings,
+                )
+                .floor()
+                .max(0.);
+                let high = yarn_find_correction_dim(
+                    scaling.beta_slow,
+                    dim,
+                    cfg.rope_theta,
+                    scaling.original_max_position_embeddings,
+                )
+                .ceil()
+                .min(dim as f32 - 1.);
+                // https://huggingface.co/deepseek-ai/DeepSeek-V2-Lite/blob/604d5664dddd88a0433dbae533b7fe9472482de0/modeling_deepseek.py#L255
+                let high = if low == high { high + 0.001 } else { high };
+                let inv_freq = freq_extra
+                    .iter()
+                    .enumerate()
+                    .map(|(i, extra)| {
+                        let ramp = ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                        let extra_mask = 1. - ramp;
+                        extra / scaling.factor * (1. - extra_mask) + extra * extra_mask
+                    })
+                    .collect();
+                let mscale = yarn_get_mscale(scaling.factor, scaling.mscale)
+                    / yarn_get_mscale(scaling.factor, scaling.mscale_all_dim);
+                (inv_freq, mscale as f64)
+            }
+        };
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: (freqs.sin()? * mscale)?.to_dtype(dtype)?,
+            cos: (freqs.cos()? * mscale)?.to_dtype(dtype)?,
+        })
+    }
+
+    fn forward(&self, q: &Tensor, k: &Tensor, seqlen_offset: usize) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let q_embed = candle_nn::rotary_emb::rope_i(&q.contiguous()?, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope_i(&k.contiguous()?, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+fn linear_b(
+    d1: usize,
+    d2: usize,
+    bias: bool,
+    vb: VarBuilder,
+    merge: bool,
+    lora_config: LoraConfig,
+) -> Result<TracedLoraLinear> {
+    if bias {
+        linear(d1, d2, vb, merge, lora_config)
+    } else {
+        linear_no_bias(d1, d2, vb, merge, lora_config)
+    }
+}
+
+/// The query projection, either a single matrix (DeepSeek-V2-Lite) or a compressed
+/// `q_a_proj` → `q_a_layernorm` → `q_b_proj` bottleneck.
+#[derive(Debug)]
+enum QProj {
+    Plain(TracedLoraLinear),
+    Compressed {
+        a: TracedLoraLinear,
+        norm: RmsNorm,
+        b: TracedLoraLinear,
+    },
+}
+
+impl Module for QProj {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Compressed { a, norm, b } => b.forward(&norm.forward(&a.forward(xs)?)?),
+            Self::Plain(lin) => lin.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Attention {
+    q: QProj,
+    kv_a_proj_with_mqa: TracedLoraLinear,
+    kv_a_layernorm: RmsNorm,
+    kv_b_proj: TracedLoraLinear,
+    o_proj: TracedLoraLinear,
+    rotary_emb: Arc<RotaryEmbedding>,
+    num_heads: usize,
+    qk_nope_head_dim: usize,
+    qk_rope_head_dim: usize,
+    kv_lora_rank: usize,
+    v_head_dim: usize,
+    softmax_scale: f64,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &DeepSeekV2Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let q_head_dim = cfg.q_head_dim();
+        let num_heads = cfg.num_attention_heads;
+        let q = match cfg.q_lora_rank {
+            Some(q_lora_rank) => QProj::Compressed {
+                a: linear_b(
+                    cfg.hidden_size,
+                    q_lora_rank,
+                    cfg.attention_bias,
+                    vb.pp("q_a_proj"),
+                    merge,
+                    lora_config.clone(),
+                )?,
+                norm: rms_norm(q_lora_rank, cfg.rms_norm_eps, vb.pp("q_a_layernorm"))?,
+                b: linear_no_bias(
+                    q_lora_rank,
+                    num_heads * q_head_dim,
+                    vb.pp("q_b_proj"),
+                    merge,
+                    lora_config.clone(),
+                )?,
+            },
+            None => QProj::Plain(linear_no_bias(
+                cfg.hidden_size,
+                num_heads * q_head_dim,
+                vb.pp("q_proj"),
+                merge,
+                lora_config.clone(),
+            )?),
+        };
+        let kv_a_proj_with_mqa = linear_b(
+            cfg.hidden_size,
+            cfg.kv_lora_rank + cfg.qk_rope_head_dim,
+            cfg.attention_bias,
+            vb.pp("kv_a_proj_with_mqa"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let kv_a_layernorm = rms_norm(cfg.kv_lora_rank, cfg.rms_norm_eps, vb.pp("kv_a_layernorm"))?;
+        let kv_b_proj = linear_no_bias(
+            cfg.kv_lora_rank,
+            num_heads * (cfg.qk_nope_head_dim + cfg.v_head_dim),
+            vb.pp("kv_b_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let o_proj = linear_b(
+            num_heads * cfg.v_head_dim,
+            cfg.hidden_size,
+            cfg.attention_bias,
+            vb.pp("o_proj"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            q,
+            kv_a_proj_with_mqa,
+            kv_a_layernorm,
+            kv_b_proj,
+            o_proj,
+            rotary_emb,
+            num_heads,
+            qk_nope_head_dim: cfg.qk_nope_head_dim,
+            qk_rope_head_dim: cfg.qk_rope_head_dim,
+            kv_lora_rank: cfg.kv_lora_rank,
+            v_head_dim: cfg.v_head_dim,
+            softmax_scale: cfg.softmax_scale() as f64,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+
+        let q = self
+            .q
+            .forward(xs)?
+            .reshape((
+                b_sz,
+                seq_len,
+                self.num_heads,
+                self.qk_nope_head_dim + self.qk_rope_head_dim,
+            ))?
+            .transpose(1, 2)?;
+        let q_nope = q.narrow(D::Minus1, 0, self.qk_nope_head_dim)?;
+        let q_pe = q.narrow(D::Minus1, self.qk_nope_head_dim, self.qk_rope_head_dim)?;
+
+        let compressed_kv = self.kv_a_proj_with_mqa.forward(xs)?;
+        let k_pe = compressed_kv
+            .narrow(D::Minus1, self.kv_lora_rank, self.qk_rope_head_dim)?
+            .reshape((b_sz, seq_len, 1, self.qk_rope_head_dim))?
+            .transpose(1, 2)?;
+        let compressed_kv = compressed_kv.narrow(D::Minus1, 0, self.kv_lora_rank)?;
+        let kv = self
+            .kv_b_proj
+            .forward(&self.kv_a_layernorm.forward(&compressed_kv)?)?
+            .reshape((
+                b_sz,
+                seq_len,
+                self.num_heads,
+                self.qk_nope_head_dim + self.v_head_dim,
+            ))?
+            .transpose(1, 2)?;
+        let k_nope = kv.narrow(D::Minus1, 0, self.qk_nope_head_dim)?;
+        let v = kv.narrow(D::Minus1, self.qk_nope_head_dim, self.v_head_dim)?;
+
+        let (q_pe, k_pe) = self.rotary_emb.forward(&q_pe, &k_pe, seqlen_offset)?;
+
+        let q = Tensor::cat(&[q_nope, q_pe], D::Minus1)?;
+        let k = Tensor::cat(
+            &[k_nope, k_pe.repeat((1, self.num_heads, 1, 1))?],
+            D::Minus1,
+        )?;
+
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => {
+                let k = Tensor::cat(&[prev_k, &k], 2)?;
+                let v = Tensor::cat(&[prev_v, &v], 2)?;
+                (k, v)
+            }
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? * self.softmax_scale)?;
+        let att = match attention_mask {
+            Some(mask) => att.broadcast_add(mask)?,
+            None => att,
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        att.matmul(&v.contiguous()?)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, ()))?
+            .apply(&self.o_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug)]
+struct Mlp {
+    gate: Linear,
+    up: Linear,
+    down: Linear,
+    act: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &DeepSeekV2Config, vb: VarBuilder, intermediate_size: usize) -> Result<Self> {
+        let hidden_size = cfg.hidden_size;
+        Ok(Self {
+            gate: candle_nn::linear_no_bias(hidden_size, intermediate_size, vb.pp("gate_proj"))?,
+            up: candle_nn::linear_no_bias(hidden_size, intermediate_size, vb.pp("up_proj"))?,
+            down: candle_nn::linear_no_bias(intermediate_size, hidden_size, vb.pp("down_proj"))?,
+            act: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = self.gate.forward(xs)?.apply(&self.act)?;
+        let rhs = self.up.forward(xs)?;
+        self.down.forward(&(&lhs * &rhs)?)
+    }
+}
+
+/// Indices of the `k` largest entries of each row, in descending order.
+fn topk_indices(xs: &Tensor, k: usize) -> Result<Tensor> {
+    xs.arg_sort_last_dim(false)?
+        .narrow(D::Minus1, 0, k)?
+        .contiguous()
+}
+
+#[derive(Debug)]
+struct MoeGate {
+    weight: Tensor,
+    e_score_correction_bias: Option<Tensor>,
+    top_k: usize,
+    n_routed_experts: usize,
+    n_group: usize,
+    topk_group: usize,
+    topk_method: TopkMethod,
+    scoring_func: ScoringFunc,
+    norm_topk_prob: bool,
+    routed_scaling_factor: f64,
+}
+
+impl MoeGate {
+    fn new(cfg: &DeepSeekV2Config, vb: VarBuilder, n_routed_experts: usize) -> Result<Self> {
+        let weight = vb.get((n_routed_experts, cfg.hidden_size), "weight")?;
+        let e_score_correction_bias = if cfg.topk_method == TopkMethod::NoAuxTc {
+            Some(vb.get(n_routed_experts, "e_score_correction_bias")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            weight,
+            e_score_correction_bias,
+            top_k: cfg.num_experts_per_tok.unwrap_or(1),
+            n_routed_experts,
+            n_group: cfg.n_group,
+            topk_group: cfg.topk_group,
+            topk_method: cfg.topk_method,
+            scoring_func: cfg.scoring_func,
+            norm_topk_prob: cfg.norm_topk_prob,
+            routed_scaling_factor: cfg.routed_scaling_factor,
+        })
+    }
+
+    /// Keep only the experts of the `topk_group` best groups, zeroing the others.
+    fn mask_groups(&self, scores: &Tensor, group_scores: &Tensor) -> Result<Tensor> {
+        let n = scores.dim(0)?;
+        let group_idx = topk_indices(group_scores, self.topk_group)?;
+        let group_mask = group_scores.zeros_like()?.scatter_add(
+            &group_idx,
+            &group_idx.ones_like()?.to_dtype(group_scores.dtype())?,
+            1,
+        )?;
+        let score_mask = group_mask
+            .unsqueeze(D::Minus1)?
+            .expand((n, self.n_group, self.n_routed_experts / self.n_group))?
+            .reshape((n, ()))?;
+        scores * score_mask
+    }
+
+    /// Returns `(topk_idx, topk_weight)`, both of shape `(tokens, top_k)`.
+    fn forward(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let xs = xs.reshape(((), xs.dim(D::Minus1)?))?;
+        let logits = xs
+            .to_dtype(DType::F32)?
+            .broadcast_matmul(&self.weight.t()?.to_dtype(DType::F32)?)?;
+        let scores = match self.scoring_func {
+            ScoringFunc::Softmax => candle_nn::ops::softmax_last_dim(&logits)?,
+            ScoringFunc::Sigmoid => candle_nn::ops::sigmoid(&logits)?,
+        };
+        let n = scores.dim(0)?;
+
+        let (topk_idx, mut topk_weight) = match (self.topk_method, &self.e_score_correction_bias) {
+            (TopkMethod::Greedy, _) => {
+                let idx = topk_indices(&scores, self.top_k)?;
+                let weight = scores.gather(&idx, D::Minus1)?;
+                (idx, weight)
+            }
+            (TopkMethod::GroupLimitedGreedy, _) => {
+                let group_scores = scores.reshape((n, self.n_group, ()))?.max(D::Minus1)?;
+                let tmp_scores = self.mask_groups(&scores, &group_scores)?;
+                let idx = topk_indices(&tmp_scores, self.top_k)?;
+                let weight = scores.gather(&idx, D::Minus1)?;
+                (idx, weight)
+            }
+            (TopkMethod::NoAuxTc, Some(bias)) => {
+                let choice = scores.broadcast_add(&bias.to_dtype(DType::F32)?.unsqueeze(0)?)?;
+                let grouped = choice.reshape((n, self.n_group, ()))?;
+                let top2 = grouped
+                    .arg_sort_last_dim(false)?
+                    .narrow(D::Minus1, 0, 2)?
+                    .contiguous()?;
+                let group_scores = grouped.gather(&top2, D::Minus1)?.sum(D::Minus1)?;
+                let tmp_scores = self.mask_groups(&choice, &group_scores)?;
+                let idx = topk_indices(&tmp_scores, self.top_k)?;
+                let weight = scores.gather(&idx, D::Minus1)?;
+                (idx, weight)
+            }
+            (TopkMethod::NoAuxTc, None) => unreachable!("noaux_tc gates load their bias"),
+        };
+
+        if self.top_k > 1 && self.norm_topk_prob {
+            let denominator = (topk_weight.sum_keepdim(D::Minus1)? + 1e-20)?;
+            topk_weight = topk_weight.broadcast_div(&denominator)?;
+            if self.topk_method == TopkMethod::NoAuxTc {
+                topk_weight = (topk_weight * self.routed_scaling_factor)?;
+            }
+        } else {
+            topk_weight = (topk_weight * self.routed_scaling_factor)?;
+        }
+        Ok((topk_idx, topk_weight))
+    }
+}
+
+#[derive(Debug)]
+struct Moe {
+    experts: Vec<Mlp>,
+    shared_experts: Option<Mlp>,
+    gate: MoeGate,
+}
+
+impl Moe {
+    fn new(
+        cfg: &DeepSeekV2Config,
+        vb: VarBuilder,
+        n_shared_experts: Option<usize>,
+        n_routed_experts: usize,
+    ) -> Result<Self> {
+        let mut experts = Vec::with_capacity(n_routed_experts);
+        for i in 0..n_routed_experts {
+            let vb_e = vb.pp("experts").pp(i);
+            experts.push(Mlp::new(cfg, vb_e, cfg.moe_intermediate_size)?);
+        }
+        let shared_experts = match n_shared_experts {
+            Some(n) => Some(Mlp::new(
+                cfg,
+                vb.pp("shared_experts"),
+                cfg.moe_intermediate_size * n,
+            )?),
+            None => None,
+        };
+        let gate = MoeGate::new(cfg, vb.pp("gate"), n_routed_experts)?;
+        Ok(Self {
+            experts,
+            shared_experts,
+            gate,
+        })
+    }
+
+    fn moe_infer(&self, xs: &Tensor, topk_idx: &Tensor, topk_weight: &Tensor) -> Result<Tensor> {
+        let topk_idx = topk_idx.to_vec2::<u32>()?;
+        let topk_weight = topk_weight.to_vec2::<f32>()?;
+
+        // Group the (token, weight) pairs routed to each expert.
+        let mut routed = vec![(Vec::new(), Vec::new()); self.experts.len()];
+        for (token, (experts, weights)) in topk_idx.iter().zip(topk_weight.iter()).enumerate() {
+            for (&expert, &weight) in experts.iter().zip(weights.iter()) {
+                routed[expert as usize].0.push(token as u32);
+                routed[expert as usize].1.push(weight);
+            }
+        }
+
+        let mut y = xs.zeros_like()?;
+        for (expert, (tokens, weights)) in self.experts.iter().zip(routed) {
+            if tokens.is_empty() {
+                continue;
+            }
+            let n_tokens = tokens.len();
+            let idx = Tensor::from_vec(tokens, n_tokens, xs.device())?;
+            let weights = Tensor::from_vec(weights, (n_tokens, 1), xs.device())?;
+            let out = expert
+                .forward(&xs.index_select(&idx, 0)?)?
+                .broadcast_mul(&weights.to_dtype(xs.dtype())?)?;
+            y = y.index_add(&idx, &out, 0)?;
+        }
+        Ok(y)
+    }
+}
+
+impl Module for Moe {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let orig_shape = xs.shape();
+        let (topk_idx, topk_weight) = self.gate.forward(xs)?;
+        let flat = xs.reshape(((), xs.dim(D::Minus1)?))?;
+        let mut y = self
+            .moe_infer(&flat, &topk_idx, &topk_weight)?
+            .reshape(orig_shape)?;
+        if let Some(shared_experts) = &self.shared_experts {
+            y = (y + shared_experts.forward(xs)?)?;
+        }
+        Ok(y)
+    }
+}
+
+#[derive(Debug)]
+enum MoeOrMlp {
+    Moe(Moe),
+    Mlp(Mlp),
+}
+
+impl Module for MoeOrMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Mlp(mlp) => mlp.forward(xs),
+            Self::Moe(moe) => moe.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    attn: Attention,
+    moe_or_mlp: MoeOrMlp,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &DeepSeekV2Config,
+        vb: VarBuilder,
+        layer_idx: usize,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"), merge, lora_config)?;
+        let input_layernorm =
+            rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = rms_norm(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        let moe_or_mlp = match cfg.n_routed_experts {
+            Some(n_routed_experts)
+                if layer_idx >= cfg.first_k_dense_replace
+                    && layer_idx.is_multiple_of(cfg.moe_layer_freq) =>
+            {
+                MoeOrMlp::Moe(Moe::new(
+                    cfg,
+                    vb.pp("mlp"),
+                    cfg.n_shared_experts,
+                    n_routed_experts,
+                )?)
+            }
+            _ => MoeOrMlp::Mlp(Mlp::new(cfg, vb.pp("mlp"), cfg.intermediate_size)?),
+        };
+        Ok(Self {
+            input_layernorm,
+            post_attention_layernorm,
+            attn,
+            moe_or_mlp,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.post_attention_layernorm)?
+            .apply(&self.moe_or_mlp)?;
+        residual + xs
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.attn.clear_kv_cache()
+    }
+}
+
+#[derive(Debug)]
+pub struct DeepSeekV2 {
+    lm_head: Linear,
+    embed_tokens: Embedding,
+    norm: RmsNorm,
+    layers: Vec<DecoderLayer>,
+    dtype: DType,
+    device: Device,
+}
+
+impl DeepSeekV2 {
+    /// Load a DeepSeek-V2/V3 model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. Only the attention projections are adapted.
+    pub fn new(
+        cfg: &DeepSeekV2Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            Linear::new(embed_tokens.embeddings().clone(), None)
+        } else {
+            candle_nn::linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        };
+        let norm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, vb.dtype(), vb.device())?);
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(
+                rotary_emb.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                layer_idx,
+                merge,
+                lora_config.clone(),
+            )?;
+            layers.push(layer)
+        }
+
+        Ok(Self {
+            lm_head,
+            embed_tokens,
+            norm,
+            layers,
+            dtype: vb.dtype(),
+            device: vb.device().clone(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        b_size: usize,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        let xs = xs.apply(&self.norm)?;
+        let xs = xs.i((.., seq_len - 1, ..))?.contiguous()?;
+        self.lm_head.forward(&xs)?.to_dtype(DType::F32)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
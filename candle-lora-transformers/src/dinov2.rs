@@ -21,8 +21,8 @@ fn linear(vb: VarBuilder, in_dim: usize, out_dim: usize, bias: bool) -> Result<L
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct DinoLinear {
     inner: Linear,
 }
@@ -70,7 +70,7 @@ impl Attention {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             qkv.get_lora_model(
                 lora_config.clone(),
@@ -79,7 +79,7 @@ impl Attention {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         let loraconfig_proj = LoraLinearConfig::new(dim, dim);
@@ -91,7 +91,7 @@ impl Attention {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             proj.get_lora_model(
                 lora_config.clone(),
@@ -100,7 +100,7 @@ impl Attention {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         Ok(Self {
@@ -122,9 +122,9 @@ impl Module for Attention {
             .transpose(1, 2)? // 02134
             .transpose(0, 1)? // 20134
             .transpose(2, 3)?; // 20314
-        let q = (qkv.i(0)? * self.scale)?;
-        let k = qkv.i(1)?;
-        let v = qkv.i(2)?;
+        let q = (qkv.i(0)?.contiguous()? * self.scale)?;
+        let k = qkv.i(1)?.contiguous()?;
+        let v = qkv.i(2)?.contiguous()?;
         let attn = candle_nn::ops::softmax(&q.matmul(&k.t()?)?, D::Minus1)?;
         let attn = attn.matmul(&v)?.transpose(1, 2)?.reshape((b, n, c))?;
         self.proj.forward(&attn)
@@ -181,7 +181,7 @@ impl Mlp {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             fc1.get_lora_model(
                 lora_config.clone(),
@@ -190,7 +190,7 @@ impl Mlp {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         let loraconfig_fc2 = LoraLinearConfig::new(hidden_features, out_features);
@@ -202,7 +202,7 @@ impl Mlp {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             fc2.get_lora_model(
                 lora_config.clone(),
@@ -211,7 +211,7 @@ impl Mlp {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         Ok(Self { fc1, fc2 })
@@ -283,8 +283,8 @@ impl Module for Block {
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct DinoConv2d {
     inner: Conv2d,
 }
@@ -338,7 +338,7 @@ impl PatchEmbed {
                 None,
                 Some(loraconfig_proj),
                 None,
-            )
+            )?
         } else {
             proj.get_lora_model(
                 lora_config.clone(),
@@ -347,7 +347,7 @@ impl PatchEmbed {
                 None,
                 Some(loraconfig_proj),
                 None,
-            )
+            )?
         }
 
         Ok(Self {
@@ -382,7 +382,8 @@ pub struct DinoVisionTransformer {
     pos_embed: Tensor,
     blocks: Vec<Block>,
     norm: LayerNorm,
-    head: DinoLinear,
+    head: Option<DinoLinear>,
+    embed_dim: usize,
 }
 
 impl DinoVisionTransformer {
@@ -393,6 +394,31 @@ impl DinoVisionTransformer {
         num_heads: usize,
         merge: bool,
         lora_config: LoraConfig,
+    ) -> Result<Self> {
+        Self::load(vb, depth, embed_dim, num_heads, true, merge, lora_config)
+    }
+
+    /// Load the backbone without the ImageNet classification head. `forward` then returns the
+    /// pooled features of `forward_features`.
+    pub fn new_backbone(
+        vb: VarBuilder,
+        depth: usize,
+        embed_dim: usize,
+        num_heads: usize,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        Self::load(vb, depth, embed_dim, num_heads, false, merge, lora_config)
+    }
+
+    fn load(
+        vb: VarBuilder,
+        depth: usize,
+        embed_dim: usize,
+        num_heads: usize,
+        with_head: bool,
+        merge: bool,
+        lora_config: LoraConfig,
     ) -> Result<Self> {
         let patch_embed = PatchEmbed::new(
             vb.pp("patch_embed"),
@@ -409,9 +435,6 @@ impl DinoVisionTransformer {
             (1, patch_embed.num_patches + num_tokens, embed_dim),
             "pos_embed",
         )?;
-        let mut head = DinoLinear {
-            inner: Arc::new(linear(vb.pp("head"), 2 * embed_dim, NUM_CLASSES, true)?),
-        };
         let norm = layer_norm(embed_dim, 1e-5, vb.pp("norm"))?;
         let vb_b = vb.pp("blocks");
         let blocks = (0..depth)
@@ -426,26 +449,34 @@ impl DinoVisionTransformer {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        let loraconfig_head = LoraLinearConfig::new(2 * embed_dim, NUM_CLASSES);
-        if merge {
-            head.get_merged_lora_model(
-                lora_config,
-                &vb.pp("lora_head"),
-                Some(loraconfig_head),
-                None,
-                None,
-                None,
-            )
+        let head = if with_head {
+            let mut head = DinoLinear {
+                inner: Arc::new(linear(vb.pp("head"), 2 * embed_dim, NUM_CLASSES, true)?),
+            };
+            let loraconfig_head = LoraLinearConfig::new(2 * embed_dim, NUM_CLASSES);
+            if merge {
+                head.get_merged_lora_model(
+                    lora_config,
+                    &vb.pp("lora_head"),
+                    Some(loraconfig_head),
+                    None,
+                    None,
+                    None,
+                )?
+            } else {
+                head.get_lora_model(
+                    lora_config,
+                    &vb.pp("lora_head"),
+                    Some(loraconfig_head),
+                    None,
+                    None,
+                    None,
+                )?
+            }
+            Some(head)
         } else {
-            head.get_lora_model(
-                lora_config,
-                &vb.pp("lora_head"),
-                Some(loraconfig_head),
-                None,
-                None,
-                None,
-            )
-        }
+            None
+        };
 
         Ok(Self {
             patch_embed,
@@ -454,15 +485,22 @@ impl DinoVisionTransformer {
             blocks,
             norm,
             head,
+            embed_dim,
         })
     }
 
+    /// Dimension of the pooled features, the class token concatenated with the mean of the
+    /// patch tokens.
+    pub fn features_dim(&self) -> usize {
+        2 * self.embed_dim
+    }
+
     fn interpolate_pos_encoding(&self, xs: &Tensor, w: usize, h: usize) -> Result<Tensor> {
         let npatch = xs.dim(1)? - 1;
         let n = self.pos_embed.dim(1)? - 1;
         let sqrt_n = (n as f64).sqrt();
         if npatch == n && w == h {
-            return Ok(xs.clone());
+            return Ok(self.pos_embed.clone());
         }
         let class_pos_embed = self.pos_embed.i((.., ..1))?;
         let patch_pos_embed = self.pos_embed.i((.., 1..))?;
@@ -484,15 +522,17 @@ impl DinoVisionTransformer {
     }
 
     fn prepare_tokens_with_mask(&self, xs: &Tensor) -> Result<Tensor> {
-        let (_b, _nc, w, h) = xs.dims4()?;
+        let (b, _nc, w, h) = xs.dims4()?;
         let xs = self.patch_embed.forward(xs)?;
-        let xs = Tensor::cat(&[&self.cls_token, &xs], 1)?;
-        &xs + &self.interpolate_pos_encoding(&xs, w, h)?
+        let cls_token = self.cls_token.broadcast_as((b, 1, self.embed_dim))?;
+        let xs = Tensor::cat(&[&cls_token, &xs], 1)?;
+        xs.broadcast_add(&self.interpolate_pos_encoding(&xs, w, h)?)
     }
 }
 
-impl Module for DinoVisionTransformer {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+impl DinoVisionTransformer {
+    /// Pooled `(b, 2 * embed_dim)` features, before the classification head.
+    pub fn forward_features(&self, xs: &Tensor) -> Result<Tensor> {
         let mut xs = self.prepare_tokens_with_mask(xs)?;
         for blk in self.blocks.iter() {
             xs = blk.forward(&xs)?
@@ -500,7 +540,49 @@ impl Module for DinoVisionTransformer {
         let xs = self.norm.forward(&xs)?;
         let xs_norm_clstoken = xs.i((.., 0))?;
         let xs_norm_patchtokens = xs.i((.., 1..))?.mean(1)?;
-        let xs = Tensor::cat(&[xs_norm_clstoken, xs_norm_patchtokens], D::Minus1)?;
+        Tensor::cat(&[xs_norm_clstoken, xs_norm_patchtokens], D::Minus1)
+    }
+}
+
+impl Module for DinoVisionTransformer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.forward_features(xs)?;
+        match &self.head {
+            Some(head) => head.forward(&xs),
+            None => Ok(xs),
+        }
+    }
+}
+
+/// A DINOv2 backbone with LoRA layers and a fully trainable linear head, for fine-tuning on a
+/// downstream classification task.
+#[derive(Debug)]
+pub struct DinoClassifier {
+    backbone: DinoVisionTransformer,
+    head: Linear,
+}
+
+impl DinoClassifier {
+    /// Wrap a backbone loaded with `DinoVisionTransformer::new_backbone`. The head is loaded from
+    /// `head_vb`, typically backed by a `VarMap` so that it is trained along with the LoRA
+    /// layers.
+    pub fn new(
+        backbone: DinoVisionTransformer,
+        num_classes: usize,
+        head_vb: VarBuilder,
+    ) -> Result<Self> {
+        let head = candle_nn::linear(backbone.features_dim(), num_classes, head_vb)?;
+        Ok(Self { backbone, head })
+    }
+
+    pub fn backbone(&self) -> &DinoVisionTransformer {
+        &self.backbone
+    }
+}
+
+impl Module for DinoClassifier {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.backbone.forward_features(xs)?;
         self.head.forward(&xs)
     }
 }
@@ -512,3 +594,19 @@ pub fn vit_small(
 ) -> Result<DinoVisionTransformer> {
     DinoVisionTransformer::new(vb, 12, 384, 6, merge, lora_config)
 }
+
+pub fn vit_base(
+    vb: VarBuilder,
+    merge: bool,
+    lora_config: LoraConfig,
+) -> Result<DinoVisionTransformer> {
+    DinoVisionTransformer::new(vb, 12, 768, 12, merge, lora_config)
+}
+
+pub fn vit_large(
+    vb: VarBuilder,
+    merge: bool,
+    lora_config: LoraConfig,
+) -> Result<DinoVisionTransformer> {
+    DinoVisionTransformer::new(vb, 24, 1024, 16, merge, lora_config)
+}
@@ -206,14 +206,14 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor>
     Ok(m)
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct AttentionQKV {
     query_key_value: Linear,
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct AttentionDense {
     dense: Linear,
 }
@@ -284,7 +284,7 @@ impl FalconAttention {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             query_key_value.get_lora_model(
                 lora_config.clone(),
@@ -293,7 +293,7 @@ impl FalconAttention {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         let loraconfig_dense = LoraLinearConfig::new(hidden_size, hidden_size);
@@ -305,7 +305,7 @@ impl FalconAttention {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             dense.get_lora_model(
                 lora_config,
@@ -314,7 +314,7 @@ impl FalconAttention {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         Ok(Self {
@@ -502,8 +502,8 @@ impl FalconDecoderLayer {
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 pub struct Falcon {
     word_embeddings: Embedding,
     blocks: Vec<FalconDecoderLayer>,
@@ -581,7 +581,7 @@ impl Falcon {
                 None,
                 None,
                 Some(embed_config),
-            )
+            )?
         } else {
             this.get_lora_model(
                 lora_config,
@@ -590,7 +590,7 @@ impl Falcon {
                 None,
                 None,
                 Some(embed_config),
-            )
+            )?
         }
 
         Ok(this)
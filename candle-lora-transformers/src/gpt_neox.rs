@@ -0,0 +1,458 @@
+//! GPT-NeoX, https://huggingface.co/EleutherAI/gpt-neox-20b
+//!
+//! Also covers the Pythia suite, e.g. https://huggingface.co/EleutherAI/pythia-1.4b. The
+//! attention and MLP blocks read the same normalized input and are added to the residual in
+//! parallel, and rotary embeddings only cover the first `rotary_pct` of each head. The
+//! query/key/value projection is a single fused `query_key_value` layer which carries one LoRA
+//! layer, as in PEFT adapters trained on these models.
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{Activation, Embedding, LayerNorm, Linear, VarBuilder};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::with_tracing::{linear, linear_no_bias, TracedLoraLinear};
+
+fn default_use_parallel_residual() -> bool {
+    true
+}
+
+fn default_attention_bias() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub hidden_act: Activation,
+    pub rotary_pct: f64,
+    pub rotary_emb_base: f64,
+    pub max_position_embeddings: usize,
+    pub layer_norm_eps: f64,
+    #[serde(default = "default_use_parallel_residual")]
+    pub use_parallel_residual: bool,
+    #[serde(default = "default_attention_bias")]
+    pub attention_bias: bool,
+}
+
+impl Config {
+    /// EleutherAI/pythia-160m
+    pub fn pythia_160m() -> Self {
+        Self {
+            vocab_size: 50304,
+            hidden_size: 768,
+            intermediate_size: 3072,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            hidden_act: Activation::Gelu,
+            rotary_pct: 0.25,
+            rotary_emb_base: 10000.,
+            max_position_embeddings: 2048,
+            layer_norm_eps: 1e-5,
+            use_parallel_residual: true,
+            attention_bias: true,
+        }
+    }
+
+    /// EleutherAI/pythia-1.4b
+    pub fn pythia_1_4b() -> Self {
+        Self {
+            hidden_size: 2048,
+            intermediate_size: 8192,
+            num_hidden_layers: 24,
+            num_attention_heads: 16,
+            ..Self::pythia_160m()
+        }
+    }
+
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    fn rotary_ndims(&self) -> usize {
+        (self.head_dim() as f64 * self.rotary_pct) as usize
+    }
+}
+
+fn linear_b(
+    d1: usize,
+    d2: usize,
+    bias: bool,
+    vb: VarBuilder,
+    merge: bool,
+    lora_config: LoraConfig,
+) -> Result<TracedLoraLinear> {
+    if bias {
+        linear(d1, d2, vb, merge, lora_config)
+    } else {
+        linear_no_bias(d1, d2, vb, merge, lora_config)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+    dim: usize,
+}
+
+fn rotate_half(xs: &Tensor) -> Result<Tensor> {
+    let last_dim = xs.dim(D::Minus1)?;
+    let xs1 = xs.narrow(D::Minus1, 0, last_dim / 2)?;
+    let xs2 = xs.narrow(D::Minus1, last_dim / 2, last_dim - last_dim / 2)?;
+    Tensor::cat(&[&xs2.neg()?, &xs1], D::Minus1)
+}
+
+impl RotaryEmbedding {
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.rotary_ndims();
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rotary_emb_base.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(dtype)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
+        Ok(Self {
+            sin: freqs.sin()?,
+            cos: freqs.cos()?,
+            dim,
+        })
+    }
+
+    /// Rotate the first `dim` channels of each head, the remaining ones are passed through.
+    fn apply(&self, xs: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_sz, _h, seq_len, head_dim) = xs.dims4()?;
+        if self.dim == 0 {
+            return Ok(xs.clone());
+        }
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let xs_rot = xs.narrow(D::Minus1, 0, self.dim)?;
+        let xs_rot = (xs_rot.broadcast_mul(&cos)? + rotate_half(&xs_rot)?.broadcast_mul(&sin))?;
+        if self.dim == head_dim {
+            Ok(xs_rot)
+        } else {
+            let xs_pass = xs.narrow(D::Minus1, self.dim, head_dim - self.dim)?;
+            Tensor::cat(&[&xs_rot, &xs_pass], D::Minus1)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    dense_h_to_4h: TracedLoraLinear,
+    dense_4h_to_h: TracedLoraLinear,
+    act: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let (h_size, i_size) = (cfg.hidden_size, cfg.intermediate_size);
+        let dense_h_to_4h = linear(
+            h_size,
+            i_size,
+            vb.pp("dense_h_to_4h"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let dense_4h_to_h = linear(i_size, h_size, vb.pp("dense_4h_to_h"), merge, lora_config)?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+            act: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.dense_h_to_4h)?
+            .apply(&self.act)?
+            .apply(&self.dense_4h_to_h)
+    }
+}
+
+#[derive(Debug)]
+struct Attention {
+    query_key_value: TracedLoraLinear,
+    dense: TracedLoraLinear,
+    num_heads: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let query_key_value = linear_b(
+            hidden_sz,
+            3 * hidden_sz,
+            cfg.attention_bias,
+            vb.pp("query_key_value"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let dense = linear_b(
+            hidden_sz,
+            hidden_sz,
+            cfg.attention_bias,
+            vb.pp("dense"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            query_key_value,
+            dense,
+            num_heads: cfg.num_attention_heads,
+            head_dim: cfg.head_dim(),
+            hidden_size: hidden_sz,
+            rotary_emb,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        // The fused projection is laid out per head as (query, key, value).
+        let qkv = self
+            .query_key_value
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_heads, 3 * self.head_dim))?
+            .transpose(1, 2)?;
+        let query_states = qkv.narrow(D::Minus1, 0, self.head_dim)?.contiguous()?;
+        let key_states = qkv
+            .narrow(D::Minus1, self.head_dim, self.head_dim)?
+            .contiguous()?;
+        let value_states = qkv
+            .narrow(D::Minus1, 2 * self.head_dim, self.head_dim)?
+            .contiguous()?;
+
+        let query_states = self
+            .rotary_emb
+            .apply(&query_states, seqlen_offset)?
+            .contiguous()?;
+        let key_states = self
+            .rotary_emb
+            .apply(&key_states, seqlen_offset)?
+            .contiguous()?;
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => {
+                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
+                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
+                (key_states, value_states)
+            }
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_weights = (query_states.matmul(&key_states.t()?)? * scale)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.dense)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    attention: Attention,
+    mlp: MLP,
+    input_layernorm: LayerNorm,
+    post_attention_layernorm: LayerNorm,
+    use_parallel_residual: bool,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let attention = Attention::new(
+            rotary_emb,
+            cfg,
+            vb.pp("attention"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"), merge, lora_config)?;
+        let input_layernorm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_eps,
+            vb.pp("input_layernorm"),
+        )?;
+        let post_attention_layernorm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            attention,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+            use_parallel_residual: cfg.use_parallel_residual,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let attn_output = self.attention.forward(
+            &xs.apply(&self.input_layernorm)?,
+            attention_mask,
+            seqlen_offset,
+        )?;
+        if self.use_parallel_residual {
+            // x + attn(ln1(x)) + mlp(ln2(x))
+            let mlp_output = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+            (mlp_output + attn_output)? + xs
+        } else {
+            let xs = (attn_output + xs)?;
+            let mlp_output = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+            mlp_output + xs
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.attention.clear_kv_cache()
+    }
+}
+
+#[derive(Debug)]
+pub struct GptNeoX {
+    embed_in: Embedding,
+    layers: Vec<DecoderLayer>,
+    final_layer_norm: LayerNorm,
+    embed_out: Linear,
+    device: Device,
+    dtype: DType,
+}
+
+impl GptNeoX {
+    /// Load a GPT-NeoX model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The token embeddings and the output head are
+    /// not adapted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let vb_m = vb.pp("gpt_neox");
+        let embed_in = candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_in"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb_m.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(
+                rotary_emb.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                merge,
+                lora_config.clone(),
+            )?;
+            layers.push(layer)
+        }
+        let final_layer_norm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_eps,
+            vb_m.pp("final_layer_norm"),
+        )?;
+        let embed_out =
+            candle_nn::linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("embed_out"))?;
+        Ok(Self {
+            embed_in,
+            layers,
+            final_layer_norm,
+            embed_out,
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        b_size: usize,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    /// Logits of the last position, `(batch, 1, vocab_size)`.
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_in.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.final_layer_norm)?
+            .apply(&self.embed_out)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! IBM Granite LLM, https://huggingface.co/ibm-granite
+//!
+//! Granite is Llama-like, but scales the embeddings, the attention logits, the residual branches
+//! and the final logits with constants from the config, and usually ties `lm_head` to the
+//! token embeddings.
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::with_tracing::{linear_no_bias, TracedLoraEmbedding, TracedLoraLinear};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GraniteConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_multiplier")]
+    pub embedding_multiplier: f64,
+    #[serde(default = "default_multiplier")]
+    pub attention_multiplier: f64,
+    #[serde(default = "default_multiplier")]
+    pub residual_multiplier: f64,
+    #[serde(default = "default_multiplier")]
+    pub logits_scaling: f64,
+    #[serde(default = "default_tie_word_embeddings")]
+    pub tie_word_embeddings: bool,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+fn default_tie_word_embeddings() -> bool {
+    true
+}
+
+impl GraniteConfig {
+    pub fn into_config(self, use_flash_attn: bool) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings,
+            embedding_multiplier: self.embedding_multiplier,
+            attention_multiplier: self.attention_multiplier,
+            residual_multiplier: self.residual_multiplier,
+            logits_scaling: self.logits_scaling,
+            tie_word_embeddings: self.tie_word_embeddings,
+            use_flash_attn,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    pub embedding_multiplier: f64,
+    pub attention_multiplier: f64,
+    pub residual_multiplier: f64,
+    pub logits_scaling: f64,
+    pub tie_word_embeddings: bool,
+    pub use_flash_attn: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    masks: HashMap<usize, Tensor>,
+    pub use_kv_cache: bool,
+    kvs: Vec<Option<(Tensor, Tensor)>>,
+    cos: Tensor,
+    sin: Tensor,
+    device: Device,
+}
+
+impl Cache {
+    pub fn new(use_kv_cache: bool, dtype: DType, cfg: &Config, device: &Device) -> Result<Self> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let theta: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / head_dim as f32))
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), device)?;
+        let idx_theta = Tensor::arange(0, cfg.max_position_embeddings as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((cfg.max_position_embeddings, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        Ok(Self {
+            masks: HashMap::new(),
+            use_kv_cache,
+            kvs: vec![None; cfg.num_hidden_layers],
+            cos: idx_theta.cos()?.to_dtype(dtype)?,
+            sin: idx_theta.sin()?.to_dtype(dtype)?,
+            device: device.clone(),
+        })
+    }
+
+    fn mask(&mut self, t: usize) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
+            self.masks.insert(t, mask.clone());
+            Ok(mask)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn new(size: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+#[cfg(feature = "flash-attn")]
+fn flash_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    candle_flash_attn::flash_attn(q, k, v, softmax_scale, causal)
+}
+
+#[cfg(not(feature = "flash-attn"))]
+fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
+    unimplemented!("compile with '--features flash-attn'")
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+#[derive(Debug)]
+struct CausalSelfAttention {
+    q_proj: TracedLoraLinear,
+    k_proj: TracedLoraLinear,
+    v_proj: TracedLoraLinear,
+    o_proj: TracedLoraLinear,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    attention_multiplier: f64,
+    use_flash_attn: bool,
+    span: tracing::Span,
+}
+
+impl CausalSelfAttention {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let size_q = head_dim * cfg.num_attention_heads;
+        let size_kv = head_dim * cfg.num_key_value_heads;
+        let q_proj = linear_no_bias(
+            cfg.hidden_size,
+            size_q,
+            vb.pp("q_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let k_proj = linear_no_bias(
+            cfg.hidden_size,
+            size_kv,
+            vb.pp("k_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let v_proj = linear_no_bias(
+            cfg.hidden_size,
+            size_kv,
+            vb.pp("v_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let o_proj = linear_no_bias(size_q, cfg.hidden_size, vb.pp("o_proj"), merge, lora_config)?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim,
+            attention_multiplier: cfg.attention_multiplier,
+            use_flash_attn: cfg.use_flash_attn,
+            span: tracing::span!(tracing::Level::TRACE, "attn"),
+        })
+    }
+
+    fn repeat_kv(&self, xs: Tensor) -> Result<Tensor> {
+        let n_rep = self.num_attention_heads / self.num_key_value_heads;
+        if n_rep == 1 {
+            Ok(xs)
+        } else {
+            let (b_sz, n_kv_head, seq_len, head_dim) = xs.dims4()?;
+            xs.unsqueeze(2)?
+                .expand((b_sz, n_kv_head, n_rep, seq_len, head_dim))?
+                .reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
+        }
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let mut v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let cos = cache.cos.narrow(0, index_pos, seq_len)?;
+        let sin = cache.sin.narrow(0, index_pos, seq_len)?;
+        let q = candle_nn::rotary_emb::rope(&q, &cos, &sin)?;
+        let mut k = candle_nn::rotary_emb::rope(&k, &cos, &sin)?;
+
+        if cache.use_kv_cache {
+            if let Some((cache_k, cache_v)) = &cache.kvs[block_idx] {
+                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
+                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
+            }
+            cache.kvs[block_idx] = Some((k.clone(), v.clone()))
+        }
+
+        let k = self.repeat_kv(k)?;
+        let v = self.repeat_kv(v)?;
+
+        // Granite replaces the usual 1/sqrt(head_dim) softmax scale with `attention_multiplier`.
+        let y = if self.use_flash_attn {
+            let q = q.transpose(1, 2)?;
+            let k = k.transpose(1, 2)?;
+            let v = v.transpose(1, 2)?;
+            flash_attn(&q, &k, &v, self.attention_multiplier as f32, seq_len > 1)?
+                .transpose(1, 2)?
+        } else {
+            let in_dtype = q.dtype();
+            let q = q.to_dtype(DType::F32)?;
+            let k = k.to_dtype(DType::F32)?;
+            let v = v.to_dtype(DType::F32)?;
+            let att = (q.matmul(&k.t()?)? * self.attention_multiplier)?;
+            let att = if seq_len == 1 {
+                att
+            } else {
+                let mask = cache.mask(seq_len)?;
+                let kv_len = att.dim(D::Minus1)?;
+                let mask = if kv_len > seq_len {
+                    let prefix = Tensor::zeros((seq_len, kv_len - seq_len), DType::U8, x.device())?;
+                    Tensor::cat(&[&prefix, &mask], D::Minus1)?
+                } else {
+                    mask
+                };
+                masked_fill(&att, &mask.broadcast_as(att.shape())?, f32::NEG_INFINITY)?
+            };
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+        };
+        let y = y.transpose(1, 2)?.reshape((
+            b_sz,
+            seq_len,
+            self.num_attention_heads * self.head_dim,
+        ))?;
+        self.o_proj.forward(&y)
+    }
+}
+
+#[derive(Debug)]
+struct Mlp {
+    gate_proj: TracedLoraLinear,
+    up_proj: TracedLoraLinear,
+    down_proj: TracedLoraLinear,
+    span: tracing::Span,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let gate_proj = linear_no_bias(
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            vb.pp("gate_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let up_proj = linear_no_bias(
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            vb.pp("up_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let down_proj = linear_no_bias(
+            cfg.intermediate_size,
+            cfg.hidden_size,
+            vb.pp("down_proj"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            span: tracing::span!(tracing::Level::TRACE, "mlp"),
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (candle_nn::ops::silu(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    input_layernorm: RmsNorm,
+    self_attn: CausalSelfAttention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+    residual_multiplier: f64,
+}
+
+impl Block {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let self_attn =
+            CausalSelfAttention::new(cfg, vb.pp("self_attn"), merge, lora_config.clone())?;
+        let mlp = Mlp::new(cfg, vb.pp("mlp"), merge, lora_config)?;
+        let input_layernorm =
+            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            input_layernorm,
+            self_attn,
+            post_attention_layernorm,
+            mlp,
+            residual_multiplier: cfg.residual_multiplier,
+        })
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.input_layernorm.forward(x)?;
+        let x = self.self_attn.forward(&x, index_pos, block_idx, cache)?;
+        let x = (residual + (x * self.residual_multiplier)?)?;
+        let residual = &x;
+        let x = self
+            .mlp
+            .forward(&self.post_attention_layernorm.forward(&x)?)?;
+        residual + (x * self.residual_multiplier)?
+    }
+}
+
+#[derive(Debug)]
+pub struct Granite {
+    embed_tokens: TracedLoraEmbedding,
+    blocks: Vec<Block>,
+    norm: RmsNorm,
+    lm_head: Option<TracedLoraLinear>,
+    embedding_multiplier: f64,
+    logits_scaling: f64,
+}
+
+impl Granite {
+    /// Load a Granite model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. When `tie_word_embeddings` is set, the output
+    /// projection reuses the base token embeddings and has no adapter of its own.
+    pub fn load(
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let embed_tokens = TracedLoraEmbedding::new(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            vb.pp("model.embed_tokens"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                Block::new(
+                    cfg,
+                    vb.pp(format!("model.layers.{i}")),
+                    merge,
+                    lora_config.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            None
+        } else {
+            Some(linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                vb.pp("lm_head"),
+                merge,
+                lora_config,
+            )?)
+        };
+        Ok(Self {
+            embed_tokens,
+            blocks,
+            norm,
+            lm_head,
+            embedding_multiplier: cfg.embedding_multiplier,
+            logits_scaling: cfg.logits_scaling,
+        })
+    }
+
+    pub fn forward(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mut x = (self.embed_tokens.forward(x)? * self.embedding_multiplier)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx, cache)?;
+        }
+        let x = self.norm.forward(&x)?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let logits = match &self.lm_head {
+            Some(lm_head) => lm_head.forward(&x)?,
+            None => x.matmul(&self.embed_tokens.embeddings().t()?)?,
+        };
+        (logits / self.logits_scaling)?.to_dtype(DType::F32)
+    }
+}
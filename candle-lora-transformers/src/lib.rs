@@ -1,17 +1,39 @@
 pub mod with_tracing;
 
+pub mod baichuan;
 pub mod bert;
 pub mod bigcode;
 pub mod blip;
 pub mod blip_text;
+pub mod bloom;
+pub mod chatglm;
+pub mod cross_encoder;
+pub mod deepseek2;
 pub mod dinov2;
 pub mod falcon;
+pub mod gpt_neox;
+pub mod granite;
+pub mod internlm2;
 pub mod llama;
+pub mod m2m100;
+pub mod marian;
 pub mod mistral;
 pub mod mpt;
+pub mod musicgen;
+pub mod phi3;
+pub mod qwen2;
 pub mod resnet;
+pub mod rwkv_v5;
+pub mod segment_anything;
+pub mod sft;
 pub mod stable_lm;
+pub mod starcoder2;
 pub mod t5;
+pub mod text_embedding;
+pub mod token_classification;
+pub mod wav2vec2;
+pub mod whisper;
+pub mod yi;
 
 pub mod unsync_func;
 pub mod varbuilder_utils;
@@ -1,15 +1,55 @@
-//! The LLama2 model.
+//! The Llama model, covering Llama 2 and Llama 3.x (GQA, rope scaling, tied embeddings).
 
 use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
-use candle_lora::{LinearLayerLike, LoraConfig, LoraEmbeddingConfig, LoraLinearConfig, Saveable};
+use candle_lora::{
+    LinearLayerLike, LoraConfig, LoraEmbeddingConfig, LoraLinear, LoraLinearConfig, Merge,
+    PrefixKeyValues, PromptEmbedding, Saveable,
+};
 use candle_lora_macro::{replace_layer_fields, AutoLoraConvert};
 use candle_nn::{Embedding, Module, VarBuilder};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::f32::consts::PI;
 use std::sync::{Arc, Mutex};
 
 pub const MAX_SEQ_LEN: usize = 4096;
 
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+pub enum Llama3RopeType {
+    #[serde(rename = "llama3")]
+    Llama3,
+    #[default]
+    #[serde(rename = "default")]
+    Default,
+}
+
+/// The `rope_scaling` block of Llama 3.1 and later configs.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Llama3RopeConfig {
+    pub factor: f32,
+    pub low_freq_factor: f32,
+    pub high_freq_factor: f32,
+    pub original_max_position_embeddings: usize,
+    pub rope_type: Llama3RopeType,
+}
+
+/// Llama 3 instruct models list several end-of-sequence tokens.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum LlamaEosToks {
+    Single(u32),
+    Multiple(Vec<u32>),
+}
+
+impl LlamaEosToks {
+    pub fn contains(&self, token: u32) -> bool {
+        match self {
+            Self::Single(eos) => *eos == token,
+            Self::Multiple(eos) => eos.contains(&token),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct LlamaConfig {
     pub hidden_size: usize,
@@ -21,12 +61,23 @@ pub struct LlamaConfig {
     pub rms_norm_eps: f64,
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<LlamaEosToks>,
+    pub rope_scaling: Option<Llama3RopeConfig>,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
 }
 
 fn default_rope() -> f32 {
     10_000.0
 }
 
+fn default_max_position_embeddings() -> usize {
+    MAX_SEQ_LEN
+}
+
 impl LlamaConfig {
     pub fn into_config(self, use_flash_attn: bool) -> Config {
         Config {
@@ -39,6 +90,11 @@ impl LlamaConfig {
             rms_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
             use_flash_attn,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id,
+            rope_scaling: self.rope_scaling,
+            max_position_embeddings: self.max_position_embeddings,
+            tie_word_embeddings: self.tie_word_embeddings,
         }
     }
 }
@@ -53,6 +109,11 @@ pub struct Config {
     pub use_flash_attn: bool,
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<LlamaEosToks>,
+    pub rope_scaling: Option<Llama3RopeConfig>,
+    pub max_position_embeddings: usize,
+    pub tie_word_embeddings: bool,
 }
 
 impl Config {
@@ -67,6 +128,11 @@ impl Config {
             use_flash_attn,
             rms_norm_eps: 1e-6,
             rope_theta: 10_000.0,
+            bos_token_id: None,
+            eos_token_id: None,
+            rope_scaling: None,
+            max_position_embeddings: MAX_SEQ_LEN,
+            tie_word_embeddings: false,
         }
     }
 
@@ -81,14 +147,19 @@ impl Config {
             use_flash_attn,
             rms_norm_eps: 1e-5,
             rope_theta: 10_000.0,
+            bos_token_id: None,
+            eos_token_id: None,
+            rope_scaling: None,
+            max_position_embeddings: MAX_SEQ_LEN,
+            tie_word_embeddings: false,
         }
     }
 }
 
 // We wrap the `LlamaLinear` layer here to add some tracing so that it's easier to profile the resulting
 // model.
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 pub struct LlamaLinear {
     inner: Box<dyn LinearLayerLike>,
     span: tracing::Span,
@@ -102,7 +173,7 @@ impl Module for LlamaLinear {
 }
 
 impl Saveable for LlamaLinear {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!()
     }
 }
@@ -121,27 +192,62 @@ impl LinearLayerLike for LlamaLinear {
 
 #[derive(Clone)]
 pub struct Cache {
-    masks: Arc<Mutex<HashMap<usize, Tensor>>>,
+    masks: Arc<Mutex<HashMap<(usize, usize), Tensor>>>,
     pub use_kv_cache: bool,
     #[allow(clippy::type_complexity)]
     kvs: Arc<Mutex<Vec<Option<(Tensor, Tensor)>>>>,
+    prefix: Arc<Mutex<Option<PrefixKeyValues>>>,
     cos: Tensor,
     sin: Tensor,
+    max_seq_len: usize,
     device: Device,
 }
 
+fn calculate_default_inv_freq(config: &Config) -> Vec<f32> {
+    let n_elem = config.hidden_size / config.num_attention_heads;
+    (0..n_elem)
+        .step_by(2)
+        .map(|i| 1f32 / config.rope_theta.powf(i as f32 / n_elem as f32))
+        .collect()
+}
+
 impl Cache {
     pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
         // precompute freqs_cis
-        let n_elem = config.hidden_size / config.num_attention_heads;
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / n_elem as f32))
-            .collect();
+        let theta = match &config.rope_scaling {
+            None
+            | Some(Llama3RopeConfig {
+                rope_type: Llama3RopeType::Default,
+                ..
+            }) => calculate_default_inv_freq(config),
+            Some(rope_scaling) => {
+                // Llama 3.1 frequency-dependent scaling: keep high frequencies, divide low
+                // frequencies by `factor` and interpolate in between.
+                let original_max = rope_scaling.original_max_position_embeddings as f32;
+                let low_freq_wavelen = original_max / rope_scaling.low_freq_factor;
+                let high_freq_wavelen = original_max / rope_scaling.high_freq_factor;
+                calculate_default_inv_freq(config)
+                    .into_iter()
+                    .map(|freq| {
+                        let wavelen = 2. * PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / rope_scaling.factor
+                        } else {
+                            let smooth = (original_max / wavelen - rope_scaling.low_freq_factor)
+                                / (rope_scaling.high_freq_factor - rope_scaling.low_freq_factor);
+                            (1. - smooth) * freq / rope_scaling.factor + smooth * freq
+                        }
+                    })
+                    .collect()
+            }
+        };
+        let max_seq_len = config.max_position_embeddings;
         let theta = Tensor::new(theta.as_slice(), device)?;
-        let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+        let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
             .to_dtype(DType::F32)?
-            .reshape((MAX_SEQ_LEN, 1))?
+            .reshape((max_seq_len, 1))?
             .matmul(&theta.reshape((1, theta.elem_count()))?)?;
         // This is different from the paper, see:
         // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
@@ -152,22 +258,41 @@ impl Cache {
             masks: Arc::new(Mutex::new(HashMap::new())),
             use_kv_cache,
             kvs: Arc::new(Mutex::new(vec![None; config.num_hidden_layers])),
+            prefix: Arc::new(Mutex::new(None)),
             device: device.clone(),
             cos,
             sin,
+            max_seq_len,
         })
     }
 
-    fn mask(&self, t: usize) -> Result<Tensor> {
+    /// Use the virtual keys and values of a prefix tuning adapter in every attention layer, or
+    /// none. The keys and values cached so far are dropped, and the positions of the tokens
+    /// start after the virtual ones.
+    pub fn set_prefix(&self, prefix: Option<PrefixKeyValues>) {
+        *self.prefix.lock().unwrap() = prefix;
+        self.kvs.lock().unwrap().fill(None);
+    }
+
+    fn prefix_len(&self) -> usize {
+        self.prefix
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, PrefixKeyValues::num_virtual_tokens)
+    }
+
+    /// The causal mask of `t` queries attending to `offset` earlier keys and to each other.
+    fn mask(&self, t: usize, offset: usize) -> Result<Tensor> {
         let mut masks = self.masks.lock().unwrap();
-        if let Some(mask) = masks.get(&t) {
+        if let Some(mask) = masks.get(&(t, offset)) {
             Ok(mask.clone())
         } else {
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..t + offset).map(move |j| u8::from(j > i + offset)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, t + offset), &self.device)?;
+            masks.insert((t, offset), mask.clone());
             Ok(mask)
         }
     }
@@ -271,24 +396,33 @@ impl CausalSelfAttention {
             .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
             .transpose(1, 2)?;
 
+        // The tokens are positioned after the virtual tokens of a prefix.
+        let index_pos = index_pos + self.cache.prefix_len();
         let q = self.apply_rotary_emb(&q, index_pos)?;
         let mut k = self.apply_rotary_emb(&k, index_pos)?;
 
+        let cached = self.cache.use_kv_cache && self.cache.kvs.lock().unwrap()[block_idx].is_some();
+        if !cached {
+            if let Some(prefix) = self.cache.prefix.lock().unwrap().as_ref() {
+                (k, v) = prefix.prepend(block_idx, &k, &v)?;
+            }
+        }
         if self.cache.use_kv_cache {
             let mut cache = self.cache.kvs.lock().unwrap();
             if let Some((cache_k, cache_v)) = &cache[block_idx] {
                 k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
                 v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
+                let max_seq_len = self.cache.max_seq_len;
                 let k_seq_len = k.dims()[1];
-                if k_seq_len > MAX_SEQ_LEN {
+                if k_seq_len > max_seq_len {
                     k = k
-                        .narrow(D::Minus1, k_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
+                        .narrow(D::Minus1, k_seq_len - max_seq_len, max_seq_len)?
                         .contiguous()?
                 }
                 let v_seq_len = v.dims()[1];
-                if v_seq_len > 2 * MAX_SEQ_LEN {
+                if v_seq_len > 2 * max_seq_len {
                     v = v
-                        .narrow(D::Minus1, v_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
+                        .narrow(D::Minus1, v_seq_len - max_seq_len, max_seq_len)?
                         .contiguous()?
                 }
             }
@@ -311,7 +445,11 @@ impl CausalSelfAttention {
             let k = k.to_dtype(DType::F32)?;
             let v = v.to_dtype(DType::F32)?;
             let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let mask = self.cache.mask(seq_len)?.broadcast_as(att.shape())?;
+            let offset = k.dim(2)? - seq_len;
+            let mask = self
+                .cache
+                .mask(seq_len, offset)?
+                .broadcast_as(att.shape())?;
             let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
@@ -376,7 +514,7 @@ impl CausalSelfAttention {
                 None,
                 None,
                 None,
-            )
+            )?
         } else {
             this.get_lora_model(
                 lora_config,
@@ -385,7 +523,7 @@ impl CausalSelfAttention {
                 None,
                 None,
                 None,
-            )
+            )?
         }
 
         Ok(this)
@@ -413,22 +551,65 @@ impl Mlp {
         self.c_proj.forward(&x)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    /// Load the MLP of the layer `layer_idx`, wrapping the projections named in `target_modules`
+    /// (`gate_proj`, `up_proj`, `down_proj`) in LoRA layers under `lora_vb`. The projections
+    /// have different shapes, so they are converted one by one, numbered in structural order
+    /// across the layers as the typed conversion of a PEFT adapter numbers them.
+    fn load(
+        vb: VarBuilder,
+        lora_vb: &VarBuilder,
+        layer_idx: usize,
+        cfg: &Config,
+        merge: bool,
+        lora_config: &LoraConfig,
+        target_modules: &[String],
+    ) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "mlp");
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
         let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
         let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
         let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
-        Ok(Self {
+        let mut this = Self {
             c_fc1,
             c_fc2,
             c_proj,
             span,
-        })
+        };
+
+        let projections = [
+            ("gate_proj", &mut this.c_fc1, (h_size, i_size)),
+            ("up_proj", &mut this.c_fc2, (h_size, i_size)),
+            ("down_proj", &mut this.c_proj, (i_size, h_size)),
+        ];
+        let targeted = projections
+            .into_iter()
+            .filter(|(name, _, _)| is_target(target_modules, name))
+            .collect::<Vec<_>>();
+        let first_id = layer_idx * targeted.len();
+        for (id, (_, layer, (in_features, out_features))) in (first_id..).zip(targeted) {
+            let linear_config = LoraLinearConfig::new(in_features, out_features);
+            let mut lora =
+                LoraLinear::new(&*layer.inner, &linear_config, lora_config, lora_vb, id)?;
+            if merge {
+                lora.merge_weights()
+                    .map_err(|e| e.either(|e| candle_core::Error::Msg(e.to_string()), |e| e))?;
+            }
+            layer.inner = Box::new(lora);
+        }
+
+        Ok(this)
     }
 }
 
+/// Whether `target_modules` names the module `name`, by itself or as the last part of a path
+/// like PEFT's `target_modules`.
+fn is_target(target_modules: &[String], name: &str) -> bool {
+    target_modules
+        .iter()
+        .any(|target| target.rsplit('.').next() == Some(name))
+}
+
 #[replace_layer_fields]
 #[derive(AutoLoraConvert)]
 struct Block {
@@ -450,14 +631,18 @@ impl Block {
         Ok(x)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn load(
         vb: VarBuilder,
+        mlp_lora_vb: &VarBuilder,
+        layer_idx: usize,
         cache: &Cache,
         cfg: &Config,
         merge: bool,
         lora_config: LoraConfig,
         linear_config: LoraLinearConfig,
         embed_config: Option<LoraEmbeddingConfig>,
+        target_modules: &[String],
     ) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "block");
         let attn = CausalSelfAttention::load(
@@ -468,7 +653,15 @@ impl Block {
             lora_config.clone(),
             linear_config.clone(),
         )?;
-        let mlp = Mlp::load(vb.pp("mlp"), cfg)?;
+        let mlp = Mlp::load(
+            vb.pp("mlp"),
+            mlp_lora_vb,
+            layer_idx,
+            cfg,
+            merge,
+            &lora_config,
+            target_modules,
+        )?;
         let rms_1 = RmsNorm::load(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
         let rms_2 = RmsNorm::load(
             cfg.hidden_size,
@@ -492,7 +685,7 @@ impl Block {
                 None,
                 None,
                 embed_config,
-            )
+            )?
         } else {
             this.get_lora_model(
                 lora_config,
@@ -501,7 +694,7 @@ impl Block {
                 None,
                 None,
                 embed_config,
-            )
+            )?
         }
 
         Ok(this)
@@ -519,8 +712,63 @@ pub struct Llama {
 
 impl Llama {
     pub fn forward(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
-        let (_b_sz, seq_len) = x.dims2()?;
-        let mut x = self.wte.forward(x)?;
+        self.forward_embeds(self.wte.forward(x)?, index_pos)
+    }
+
+    /// Forward the tokens `x` after the virtual tokens of a prompt tuning adapter, which are
+    /// prepended when `index_pos` is 0. `index_pos` counts the real tokens only.
+    pub fn forward_with_prompt(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        prompt: &PromptEmbedding,
+    ) -> Result<Tensor> {
+        let x = self.wte.forward(x)?;
+        if index_pos == 0 {
+            self.forward_embeds(prompt.prepend(&x)?, 0)
+        } else {
+            self.forward_embeds(x, index_pos + prompt.num_virtual_tokens())
+        }
+    }
+
+    /// The embeddings of the tokens `x`, the input of the first block.
+    pub fn embed(&self, x: &Tensor) -> Result<Tensor> {
+        self.wte.forward(x)
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Run the hidden states `x` through the block `block_idx`, e.g. as one of the blocks of
+    /// `candle_lora::LoraTrainer::checkpointed_step` for gradient checkpointing.
+    ///
+    /// Gradient checkpointing runs the blocks twice, so this fails when the model uses the KV
+    /// cache, which would hold the keys and values of both runs.
+    pub fn forward_block(&self, block_idx: usize, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        match self.blocks.get(block_idx) {
+            Some(block) if block.attn.cache.use_kv_cache => candle_core::bail!(
+                "forward_block needs a cache without the KV cache, use `Cache::new(false, ..)`"
+            ),
+            Some(block) => block.forward(x, index_pos, block_idx),
+            None => candle_core::bail!(
+                "block {block_idx} out of range, the model has {} blocks",
+                self.blocks.len()
+            ),
+        }
+    }
+
+    /// The logits of every position of the hidden states `x` of the last block, for a training
+    /// loss such as [`crate::sft::sft_loss`].
+    pub fn lm_logits(&self, x: &Tensor) -> Result<Tensor> {
+        self.lm_head
+            .forward(&self.ln_f.forward(x)?)?
+            .to_dtype(DType::F32)
+    }
+
+    fn forward_embeds(&self, x: Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len, _hidden_size) = x.dims3()?;
+        let mut x = x;
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = block.forward(&x, index_pos, block_idx)?;
         }
@@ -530,9 +778,10 @@ impl Llama {
         logits.to_dtype(DType::F32)
     }
 
-    /// Load a Mistral model which will be converted to a LoRA model.
+    /// Load a Llama model which will be converted to a LoRA model.
     ///
-    /// The `merge` parameter merges the weights.
+    /// The `merge` parameter merges the weights. With `tie_word_embeddings` (Llama 3.2 1B/3B)
+    /// the output head reuses the token embeddings.
     pub fn load(
         vb: VarBuilder,
         cache: &Cache,
@@ -541,20 +790,59 @@ impl Llama {
         lora_config: LoraConfig,
         linear_config: LoraLinearConfig,
         embed_config: Option<LoraEmbeddingConfig>,
+    ) -> Result<Self> {
+        Self::load_with_targets(
+            vb,
+            cache,
+            cfg,
+            merge,
+            lora_config,
+            linear_config,
+            embed_config,
+            &[],
+        )
+    }
+
+    /// Load a Llama model like [`Llama::load`], also converting the MLP projections named in
+    /// `target_modules`, e.g. the `target_modules` of a PEFT adapter config. Their LoRA weights
+    /// are read from `lora_llama_mlp`, numbered layer after layer in the order `gate_proj`,
+    /// `up_proj`, `down_proj` of the targeted projections, as written by
+    /// `candle_lora::convert_peft_to_candle_lora_typed`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_with_targets(
+        vb: VarBuilder,
+        cache: &Cache,
+        cfg: &Config,
+        merge: bool,
+        lora_config: LoraConfig,
+        linear_config: LoraLinearConfig,
+        embed_config: Option<LoraEmbeddingConfig>,
+        target_modules: &[String],
     ) -> Result<Self> {
         let wte = embedding(cfg, vb.pp("model.embed_tokens"))?;
-        let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            LlamaLinear {
+                inner: Box::new(candle_nn::Linear::new(wte.embeddings().clone(), None)),
+                span: tracing::span!(tracing::Level::TRACE, "linear"),
+            }
+        } else {
+            linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        };
         let ln_f = RmsNorm::load(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
+        let mlp_lora_vb = vb.pp("lora_llama_mlp");
         let blocks: Vec<_> = (0..cfg.num_hidden_layers)
             .map(|i| {
                 Block::load(
                     vb.pp(format!("model.layers.{i}")),
+                    &mlp_lora_vb,
+                    i,
                     cache,
                     cfg,
                     merge,
                     lora_config.clone(),
                     linear_config.clone(),
                     embed_config.clone(),
+                    target_modules,
                 )
                 .unwrap()
             })
@@ -575,7 +863,7 @@ impl Llama {
                 None,
                 None,
                 embed_config,
-            )
+            )?
         } else {
             this.get_lora_model(
                 lora_config,
@@ -584,7 +872,7 @@ impl Llama {
                 None,
                 None,
                 embed_config,
-            )
+            )?
         }
 
         Ok(this)
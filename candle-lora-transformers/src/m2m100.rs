@@ -0,0 +1,557 @@
+//! M2M100 and NLLB, https://github.com/facebookresearch/fairseq/tree/nllb
+//!
+//! The many-to-many translation encoder-decoder used by the `facebook/nllb-200-*` and
+//! `facebook/m2m100_*` checkpoints. Unlike Marian the layers normalize their inputs and both
+//! stacks end with a layer norm. Attention and feed-forward projections carry LoRA layers.
+
+use candle_core::{DType, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{layer_norm, Embedding, LayerNorm, Linear, VarBuilder};
+
+use crate::with_tracing::{linear, TracedLoraLinear};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub max_position_embeddings: usize,
+    pub encoder_layers: usize,
+    pub encoder_ffn_dim: usize,
+    pub encoder_attention_heads: usize,
+    pub decoder_layers: usize,
+    pub decoder_ffn_dim: usize,
+    pub decoder_attention_heads: usize,
+    pub activation_function: candle_nn::Activation,
+    pub d_model: usize,
+    pub decoder_start_token_id: u32,
+    pub scale_embedding: bool,
+    pub pad_token_id: u32,
+    pub eos_token_id: u32,
+}
+
+impl Config {
+    // https://huggingface.co/facebook/nllb-200-distilled-600M/blob/main/config.json
+    pub fn nllb_200_distilled_600m() -> Self {
+        Self {
+            vocab_size: 256206,
+            max_position_embeddings: 1024,
+            encoder_layers: 12,
+            encoder_ffn_dim: 4096,
+            encoder_attention_heads: 16,
+            decoder_layers: 12,
+            decoder_ffn_dim: 4096,
+            decoder_attention_heads: 16,
+            activation_function: candle_nn::Activation::Relu,
+            d_model: 1024,
+            decoder_start_token_id: 2,
+            scale_embedding: true,
+            pad_token_id: 1,
+            eos_token_id: 2,
+        }
+    }
+}
+
+/// Fairseq sinusoidal positions: position ids start after `pad_token_id` and padding tokens
+/// get the zero embedding of `pad_token_id`.
+#[derive(Debug, Clone)]
+struct SinusoidalPositionalEmbedding {
+    emb: Embedding,
+    padding_idx: u32,
+}
+
+impl SinusoidalPositionalEmbedding {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dev = vb.device();
+        let num_positions = cfg.max_position_embeddings + 2;
+        let half_dim = cfg.d_model / 2;
+        let scale = (10000f64).ln() / (half_dim - 1) as f64;
+        let inv_freq: Vec<_> = (0..half_dim)
+            .map(|i| (i as f64 * -scale).exp() as f32)
+            .collect();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, half_dim), dev)?;
+        let t = Tensor::arange(0u32, num_positions as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((num_positions, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        let weights = Tensor::cat(&[&freqs.sin()?, &freqs.cos()?], 1)?;
+        let padding_idx = cfg.pad_token_id;
+        let not_padding = Tensor::arange(0u32, num_positions as u32, dev)?
+            .ne(padding_idx)?
+            .to_dtype(DType::F32)?
+            .unsqueeze(1)?;
+        let weights = weights
+            .broadcast_mul(&not_padding)?
+            .to_dtype(vb.dtype())?
+            .contiguous()?;
+        let emb = Embedding::new(weights, cfg.d_model);
+        Ok(Self { emb, padding_idx })
+    }
+
+    fn forward(&self, input_ids: &Tensor, past_kv_len: usize) -> Result<Tensor> {
+        let mask = input_ids.ne(self.padding_idx)?.to_dtype(DType::F32)?;
+        let positions = ((mask.cumsum(D::Minus1)? + past_kv_len as f64)? * &mask)?;
+        (positions + self.padding_idx as f64)?
+            .to_dtype(DType::U32)?
+            .apply(&self.emb)
+    }
+}
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: TracedLoraLinear,
+    k_proj: TracedLoraLinear,
+    v_proj: TracedLoraLinear,
+    out_proj: TracedLoraLinear,
+    scaling: f64,
+    num_heads: usize,
+    head_dim: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+    is_decoder: bool,
+}
+
+impl Attention {
+    fn new(
+        cfg: &Config,
+        is_decoder: bool,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let num_heads = if is_decoder {
+            cfg.decoder_attention_heads
+        } else {
+            cfg.encoder_attention_heads
+        };
+        let embed_dim = cfg.d_model;
+        let head_dim = embed_dim / num_heads;
+        let scaling = (head_dim as f64).powf(-0.5);
+        let q_proj = linear(
+            embed_dim,
+            embed_dim,
+            vb.pp("q_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let k_proj = linear(
+            embed_dim,
+            embed_dim,
+            vb.pp("k_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let v_proj = linear(
+            embed_dim,
+            embed_dim,
+            vb.pp("v_proj"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let out_proj = linear(embed_dim, embed_dim, vb.pp("out_proj"), merge, lora_config)?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            out_proj,
+            scaling,
+            num_heads,
+            head_dim,
+            kv_cache: None,
+            is_decoder,
+        })
+    }
+
+    fn _shape(&self, tensor: &Tensor, bsz: usize) -> Result<Tensor> {
+        tensor
+            .reshape((bsz, (), self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        kv_states: Option<&Tensor>,
+        attn_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (b_sz, tgt_len, _) = xs.dims3()?;
+        let query_states = (xs.apply(&self.q_proj)? * self.scaling)?;
+        let (key_states, value_states) = match kv_states {
+            None => {
+                let key_states = self._shape(&xs.apply(&self.k_proj)?, b_sz)?;
+                let value_states = self._shape(&xs.apply(&self.v_proj)?, b_sz)?;
+                if self.is_decoder {
+                    let kv_states = match &self.kv_cache {
+                        None => (key_states, value_states),
+                        Some((p_key_states, p_value_states)) => {
+                            let key_states = Tensor::cat(&[p_key_states, &key_states], 2)?;
+                            let value_states = Tensor::cat(&[p_value_states, &value_states], 2)?;
+                            (key_states, value_states)
+                        }
+                    };
+                    self.kv_cache = Some(kv_states.clone());
+                    kv_states
+                } else {
+                    (key_states, value_states)
+                }
+            }
+            Some(kv_states) => {
+                let key_states = self._shape(&kv_states.apply(&self.k_proj)?, b_sz)?;
+                let value_states = self._shape(&kv_states.apply(&self.v_proj)?, b_sz)?;
+                (key_states, value_states)
+            }
+        };
+        let proj_shape = (b_sz * self.num_heads, (), self.head_dim);
+        let query_states = self._shape(&query_states, b_sz)?.reshape(proj_shape)?;
+        let key_states = key_states.reshape(proj_shape)?;
+        let value_states = value_states.reshape(proj_shape)?;
+        let attn_weights = query_states.matmul(&key_states.transpose(1, 2)?)?;
+        let attn_weights = match attn_mask {
+            None => attn_weights,
+            Some(attn_mask) => attn_weights.broadcast_add(attn_mask)?,
+        };
+        let attn_probs = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let attn_output = attn_probs.matmul(&value_states)?;
+        attn_output
+            .reshape((b_sz, self.num_heads, tgt_len, self.head_dim))?
+            .transpose(1, 2)?
+            .reshape((b_sz, tgt_len, self.head_dim * self.num_heads))?
+            .apply(&self.out_proj)
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug)]
+struct EncoderLayer {
+    self_attn: Attention,
+    self_attn_layer_norm: LayerNorm,
+    activation_fn: candle_nn::Activation,
+    fc1: TracedLoraLinear,
+    fc2: TracedLoraLinear,
+    final_layer_norm: LayerNorm,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let self_attn = Attention::new(cfg, false, vb.pp("self_attn"), merge, lora_config.clone())?;
+        let self_attn_layer_norm = layer_norm(cfg.d_model, 1e-5, vb.pp("self_attn_layer_norm"))?;
+        let fc1 = linear(
+            cfg.d_model,
+            cfg.encoder_ffn_dim,
+            vb.pp("fc1"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let fc2 = linear(
+            cfg.encoder_ffn_dim,
+            cfg.d_model,
+            vb.pp("fc2"),
+            merge,
+            lora_config,
+        )?;
+        let final_layer_norm = layer_norm(cfg.d_model, 1e-5, vb.pp("final_layer_norm"))?;
+        Ok(Self {
+            self_attn,
+            self_attn_layer_norm,
+            activation_fn: cfg.activation_function,
+            fc1,
+            fc2,
+            final_layer_norm,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let residual = xs;
+        let xs = xs.apply(&self.self_attn_layer_norm)?;
+        let xs = (self.self_attn.forward(&xs, None, None)? + residual)?;
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.final_layer_norm)?
+            .apply(&self.fc1)?
+            .apply(&self.activation_fn)?
+            .apply(&self.fc2)?;
+        xs + residual
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.self_attn.reset_kv_cache()
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    self_attn: Attention,
+    self_attn_layer_norm: LayerNorm,
+    activation_fn: candle_nn::Activation,
+    encoder_attn: Attention,
+    encoder_attn_layer_norm: LayerNorm,
+    fc1: TracedLoraLinear,
+    fc2: TracedLoraLinear,
+    final_layer_norm: LayerNorm,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let self_attn = Attention::new(cfg, true, vb.pp("self_attn"), merge, lora_config.clone())?;
+        let self_attn_layer_norm = layer_norm(cfg.d_model, 1e-5, vb.pp("self_attn_layer_norm"))?;
+        let encoder_attn =
+            Attention::new(cfg, true, vb.pp("encoder_attn"), merge, lora_config.clone())?;
+        let encoder_attn_layer_norm =
+            layer_norm(cfg.d_model, 1e-5, vb.pp("encoder_attn_layer_norm"))?;
+        let fc1 = linear(
+            cfg.d_model,
+            cfg.decoder_ffn_dim,
+            vb.pp("fc1"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let fc2 = linear(
+            cfg.decoder_ffn_dim,
+            cfg.d_model,
+            vb.pp("fc2"),
+            merge,
+            lora_config,
+        )?;
+        let final_layer_norm = layer_norm(cfg.d_model, 1e-5, vb.pp("final_layer_norm"))?;
+        Ok(Self {
+            self_attn,
+            self_attn_layer_norm,
+            activation_fn: cfg.activation_function,
+            encoder_attn,
+            encoder_attn_layer_norm,
+            fc1,
+            fc2,
+            final_layer_norm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        encoder_xs: Option<&Tensor>,
+        attn_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = xs.apply(&self.self_attn_layer_norm)?;
+        let xs = (self.self_attn.forward(&xs, None, Some(attn_mask))? + residual)?;
+        let xs = match encoder_xs {
+            None => xs,
+            Some(encoder_xs) => {
+                let residual = &xs;
+                let xs = xs.apply(&self.encoder_attn_layer_norm)?;
+                (self.encoder_attn.forward(&xs, Some(encoder_xs), None)? + residual)?
+            }
+        };
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.final_layer_norm)?
+            .apply(&self.fc1)?
+            .apply(&self.activation_fn)?
+            .apply(&self.fc2)?;
+        xs + residual
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.self_attn.reset_kv_cache();
+        self.encoder_attn.reset_kv_cache()
+    }
+}
+
+#[derive(Debug)]
+pub struct Encoder {
+    embed_tokens: Embedding,
+    embed_positions: SinusoidalPositionalEmbedding,
+    layers: Vec<EncoderLayer>,
+    layer_norm: LayerNorm,
+    embed_scale: Option<f64>,
+}
+
+impl Encoder {
+    fn new(
+        cfg: &Config,
+        embed_tokens: &Embedding,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let embed_positions = SinusoidalPositionalEmbedding::new(cfg, vb.pp("embed_positions"))?;
+        let mut layers = Vec::with_capacity(cfg.encoder_layers);
+        let vb_l = vb.pp("layers");
+        for idx in 0..cfg.encoder_layers {
+            let layer = EncoderLayer::new(cfg, vb_l.pp(idx), merge, lora_config.clone())?;
+            layers.push(layer)
+        }
+        let embed_scale = if cfg.scale_embedding {
+            Some((cfg.d_model as f64).sqrt())
+        } else {
+            None
+        };
+        let layer_norm = layer_norm(cfg.d_model, 1e-5, vb.pp("layer_norm"))?;
+        Ok(Self {
+            embed_tokens: embed_tokens.clone(),
+            embed_positions,
+            layers,
+            layer_norm,
+            embed_scale,
+        })
+    }
+
+    pub fn forward(&mut self, xs: &Tensor, past_kv_len: usize) -> Result<Tensor> {
+        let embed_pos = self.embed_positions.forward(xs, past_kv_len)?;
+        let xs = xs.apply(&self.embed_tokens)?;
+        let xs = match self.embed_scale {
+            None => xs,
+            Some(scale) => (xs * scale)?,
+        };
+        let mut xs = (xs + embed_pos)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs)?
+        }
+        xs.apply(&self.layer_norm)
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.reset_kv_cache()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    embed_tokens: Embedding,
+    embed_positions: SinusoidalPositionalEmbedding,
+    layers: Vec<DecoderLayer>,
+    layer_norm: LayerNorm,
+    embed_scale: Option<f64>,
+}
+
+impl Decoder {
+    fn new(
+        cfg: &Config,
+        embed_tokens: &Embedding,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let embed_positions = SinusoidalPositionalEmbedding::new(cfg, vb.pp("embed_positions"))?;
+        let mut layers = Vec::with_capacity(cfg.decoder_layers);
+        let vb_l = vb.pp("layers");
+        for idx in 0..cfg.decoder_layers {
+            let layer = DecoderLayer::new(cfg, vb_l.pp(idx), merge, lora_config.clone())?;
+            layers.push(layer)
+        }
+        let embed_scale = if cfg.scale_embedding {
+            Some((cfg.d_model as f64).sqrt())
+        } else {
+            None
+        };
+        let layer_norm = layer_norm(cfg.d_model, 1e-5, vb.pp("layer_norm"))?;
+        Ok(Self {
+            embed_tokens: embed_tokens.clone(),
+            embed_positions,
+            layers,
+            layer_norm,
+            embed_scale,
+        })
+    }
+
+    pub fn forward(
+        &mut self,
+        xs: &Tensor,
+        encoder_xs: Option<&Tensor>,
+        past_kv_len: usize,
+        attn_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let embed_pos = self.embed_positions.forward(xs, past_kv_len)?;
+        let xs = xs.apply(&self.embed_tokens)?;
+        let xs = match self.embed_scale {
+            None => xs,
+            Some(scale) => (xs * scale)?,
+        };
+        let mut xs = (xs + embed_pos)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, encoder_xs, attn_mask)?;
+        }
+        xs.apply(&self.layer_norm)
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.reset_kv_cache()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Model {
+    shared: Embedding,
+    encoder: Encoder,
+    decoder: Decoder,
+}
+
+impl Model {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let shared = candle_nn::embedding(cfg.vocab_size, cfg.d_model, vb.pp("shared"))?;
+        let encoder = Encoder::new(cfg, &shared, vb.pp("encoder"), merge, lora_config.clone())?;
+        let decoder = Decoder::new(cfg, &shared, vb.pp("decoder"), merge, lora_config)?;
+        Ok(Self {
+            shared,
+            encoder,
+            decoder,
+        })
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.encoder.reset_kv_cache();
+        self.decoder.reset_kv_cache();
+    }
+}
+
+#[derive(Debug)]
+pub struct MTModel {
+    model: Model,
+    lm_head: Linear,
+}
+
+impl MTModel {
+    /// Load an M2M100 or NLLB model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The shared embeddings, and so the tied output
+    /// head, are not adapted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let model = Model::new(cfg, vb.pp("model"), merge, lora_config)?;
+        let lm_head = Linear::new(model.shared.embeddings().clone(), None);
+        Ok(Self { model, lm_head })
+    }
+
+    pub fn encoder(&mut self) -> &mut Encoder {
+        &mut self.model.encoder
+    }
+
+    pub fn decoder(&mut self) -> &mut Decoder {
+        &mut self.model.decoder
+    }
+
+    pub fn decode(
+        &mut self,
+        xs: &Tensor,
+        encoder_xs: &Tensor,
+        past_kv_len: usize,
+    ) -> Result<Tensor> {
+        let seq_len = xs.dim(1)?;
+        let mask: Vec<_> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
+            .collect();
+        let mask = Tensor::from_vec(mask, (seq_len, seq_len), xs.device())?;
+        self.model
+            .decoder
+            .forward(xs, Some(encoder_xs), past_kv_len, &mask)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        self.model.reset_kv_cache();
+    }
+}
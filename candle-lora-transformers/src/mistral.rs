@@ -4,11 +4,12 @@ use candle_core::{DType, Device, Module, Result, Tensor, D};
 use candle_lora::LoraConfig;
 use candle_lora_macro::{replace_layer_fields, AutoLoraConvert};
 use candle_nn::{Activation, VarBuilder};
+use serde::Deserialize;
 use std::sync::Arc;
 
 use crate::with_tracing::{linear_no_bias, TracedLoraLinear};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
     pub vocab_size: usize,
     pub hidden_size: usize,
@@ -20,7 +21,8 @@ pub struct Config {
     pub max_position_embeddings: usize,
     pub rms_norm_eps: f64,
     pub rope_theta: f64,
-    pub sliding_window: usize,
+    pub sliding_window: Option<usize>,
+    #[serde(default)]
     pub use_flash_attn: bool,
 }
 
@@ -37,7 +39,7 @@ impl Config {
             max_position_embeddings: 32768,
             rms_norm_eps: 1e-5,
             rope_theta: 10_000.,
-            sliding_window: 4096,
+            sliding_window: Some(4096),
             use_flash_attn,
         }
     }
@@ -83,7 +85,7 @@ impl RotaryEmbedding {
         let max_seq_len = cfg.max_position_embeddings;
         let inv_freq: Vec<_> = (0..dim)
             .step_by(2)
-            .map(|i| 1f32 / 10000f32.powf(i as f32 / dim as f32))
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
             .collect();
         let inv_freq_len = inv_freq.len();
         let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
@@ -182,8 +184,8 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
     unimplemented!("compile with '--features flash-attn'")
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct Attention {
     q_proj: TracedLoraLinear,
     k_proj: TracedLoraLinear,
@@ -387,20 +389,20 @@ impl DecoderLayer {
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 pub struct Mistral {
     embed_tokens: candle_nn::Embedding,
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: TracedLoraLinear,
-    sliding_window: usize,
+    sliding_window: Option<usize>,
     device: Device,
     dtype: DType,
 }
 
 impl Mistral {
-    /// Load a Llama model which will be converted to a LoRA model.
+    /// Load a Mistral model which will be converted to a LoRA model.
     ///
     /// The `merge` parameter merges the weights.
     pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
@@ -445,11 +447,11 @@ impl Mistral {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        // Sliding window mask?
+        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| {
                 (0..tgt_len).map(move |j| {
-                    if i < j || j + self.sliding_window < i {
+                    if i < j || j + sliding_window < i {
                         f32::NEG_INFINITY
                     } else {
                         0.
@@ -484,4 +486,34 @@ impl Mistral {
             .apply(&self.norm)?
             .apply(&self.lm_head)
     }
+
+    /// Like [`Mistral::forward`], also returning the hidden states as transformers'
+    /// `output_hidden_states=True` does: the embeddings, the output of each layer but the last,
+    /// and the output of the last layer after the final norm. The logits are `(batch, vocab)`.
+    pub fn forward_with_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<(Vec<Tensor>, Tensor)> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        let mut hidden_states = Vec::with_capacity(self.layers.len() + 1);
+        for layer in self.layers.iter_mut() {
+            hidden_states.push(xs.clone());
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        let xs = xs.apply(&self.norm)?;
+        hidden_states.push(xs.clone());
+        let logits = xs
+            .narrow(1, seq_len - 1, 1)?
+            .squeeze(1)?
+            .apply(&self.lm_head)?;
+        Ok((hidden_states, logits))
+    }
 }
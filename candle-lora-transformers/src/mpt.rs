@@ -1,13 +1,15 @@
-//! MPT model used by replit-code-v1_5-3b
+//! MPT models, e.g. mosaicml/mpt-7b and replit-code-v1_5-3b
 //! https://huggingface.co/replit/replit-code-v1_5-3b/blob/main/modeling_mpt.py
 
 use super::with_tracing::{linear_no_bias, TracedLoraEmbedding, TracedLoraLinear};
 use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
 use candle_lora::LoraConfig;
-use candle_nn::{layer_norm, LayerNorm, VarBuilder};
+use candle_nn::{LayerNorm, VarBuilder};
+use serde::Deserialize;
 
 // https://huggingface.co/replit/replit-code-v1_5-3b/blob/main/configuration_mpt.py
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "HfConfig")]
 pub struct Config {
     pub(crate) d_model: usize,
     pub(crate) n_heads: usize,
@@ -19,6 +21,53 @@ pub struct Config {
     pub(crate) attn_prefix_lm: bool,
     pub(crate) attn_alibi: bool,
     pub(crate) attn_alibi_bias_max: usize,
+    pub(crate) attn_clip_qkv: Option<f64>,
+}
+
+fn default_alibi_bias_max() -> usize {
+    8
+}
+
+#[derive(Deserialize)]
+struct HfAttnConfig {
+    kv_n_heads: Option<usize>,
+    #[serde(default)]
+    prefix_lm: bool,
+    #[serde(default)]
+    alibi: bool,
+    #[serde(default = "default_alibi_bias_max")]
+    alibi_bias_max: usize,
+    clip_qkv: Option<f64>,
+}
+
+/// The `config.json` layout, with the attention settings nested in `attn_config`.
+#[derive(Deserialize)]
+struct HfConfig {
+    d_model: usize,
+    n_heads: usize,
+    n_layers: usize,
+    expansion_ratio: usize,
+    max_seq_len: usize,
+    vocab_size: usize,
+    attn_config: HfAttnConfig,
+}
+
+impl From<HfConfig> for Config {
+    fn from(cfg: HfConfig) -> Self {
+        Self {
+            d_model: cfg.d_model,
+            n_heads: cfg.n_heads,
+            n_layers: cfg.n_layers,
+            expansion_ratio: cfg.expansion_ratio,
+            max_seq_len: cfg.max_seq_len,
+            vocab_size: cfg.vocab_size,
+            kv_n_heads: cfg.attn_config.kv_n_heads.unwrap_or(cfg.n_heads),
+            attn_prefix_lm: cfg.attn_config.prefix_lm,
+            attn_alibi: cfg.attn_config.alibi,
+            attn_alibi_bias_max: cfg.attn_config.alibi_bias_max,
+            attn_clip_qkv: cfg.attn_config.clip_qkv,
+        }
+    }
 }
 
 impl Config {
@@ -34,6 +83,24 @@ impl Config {
             attn_prefix_lm: false,
             attn_alibi: true,
             attn_alibi_bias_max: 8,
+            attn_clip_qkv: None,
+        }
+    }
+
+    // https://huggingface.co/mosaicml/mpt-7b/blob/main/config.json
+    pub fn mpt_7b() -> Self {
+        Self {
+            d_model: 4096,
+            n_heads: 32,
+            n_layers: 32,
+            expansion_ratio: 4,
+            max_seq_len: 2048,
+            vocab_size: 50432,
+            kv_n_heads: 32,
+            attn_prefix_lm: false,
+            attn_alibi: true,
+            attn_alibi_bias_max: 8,
+            attn_clip_qkv: None,
         }
     }
 
@@ -53,6 +120,7 @@ struct GroupedQueryAttention {
     n_heads: usize,
     kv_n_heads: usize,
     attn_bias: Tensor,
+    clip_qkv: Option<f64>,
     span: tracing::Span,
 }
 
@@ -86,6 +154,7 @@ impl GroupedQueryAttention {
             n_heads: cfg.n_heads,
             kv_n_heads: cfg.kv_n_heads,
             attn_bias,
+            clip_qkv: cfg.attn_clip_qkv,
             span: tracing::span!(tracing::Level::TRACE, "gqa"),
         })
     }
@@ -94,6 +163,10 @@ impl GroupedQueryAttention {
         let _enter = self.span.enter();
         let (b_size, seq_len, _n_embd) = xs.dims3()?;
         let qkv = self.wqkv.forward(xs)?;
+        let qkv = match self.clip_qkv {
+            Some(clip) => qkv.clamp(-clip, clip)?,
+            None => qkv,
+        };
         let query = qkv.narrow(2, 0, self.d_model)?;
         let kv_size = self.kv_n_heads * self.head_dim;
         let key = qkv.narrow(2, self.d_model, kv_size)?;
@@ -190,6 +263,16 @@ impl Module for Ffn {
     }
 }
 
+/// MPT layer norms have no bias. Checkpoints such as mpt-7b store a weight, replit-code has none.
+fn layer_norm_no_bias(size: usize, vb: VarBuilder) -> Result<LayerNorm> {
+    let weight = if vb.contains_tensor("weight") {
+        vb.get(size, "weight")?
+    } else {
+        Tensor::ones(size, vb.dtype(), vb.device())?
+    };
+    Ok(LayerNorm::new_no_bias(weight, 1e-5))
+}
+
 #[derive(Debug)]
 struct MPTBlock {
     norm1: LayerNorm, // Do we need the low-precision variant?
@@ -200,12 +283,8 @@ struct MPTBlock {
 
 impl MPTBlock {
     fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
-        let ln_cfg = candle_nn::LayerNormConfig {
-            affine: false,
-            ..Default::default()
-        };
-        let norm1 = layer_norm(cfg.d_model, ln_cfg, vb.pp("norm_1"))?;
-        let norm2 = layer_norm(cfg.d_model, ln_cfg, vb.pp("norm_2"))?;
+        let norm1 = layer_norm_no_bias(cfg.d_model, vb.pp("norm_1"))?;
+        let norm2 = layer_norm_no_bias(cfg.d_model, vb.pp("norm_2"))?;
         let attn = GroupedQueryAttention::new(cfg, vb.pp("attn"), merge, lora_config.clone())?;
         let ffn = Ffn::new(cfg, vb.pp("ffn"), merge, lora_config)?;
         Ok(Self {
@@ -270,6 +349,9 @@ pub struct Model {
 
 impl Model {
     pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        if !cfg.attn_alibi {
+            candle_core::bail!("only MPT models with alibi attention are supported")
+        }
         let wte = TracedLoraEmbedding::new(
             cfg.vocab_size,
             cfg.d_model,
@@ -283,11 +365,7 @@ impl Model {
             let block = MPTBlock::new(cfg, vb_b.pp(i), merge, lora_config.clone())?;
             blocks.push(block)
         }
-        let ln_cfg = candle_nn::LayerNormConfig {
-            affine: false,
-            ..Default::default()
-        };
-        let norm_f = candle_nn::layer_norm(cfg.d_model, ln_cfg, vb.pp("norm_f"))?;
+        let norm_f = layer_norm_no_bias(cfg.d_model, vb.pp("norm_f"))?;
         Ok(Self {
             wte,
             blocks,
@@ -0,0 +1,379 @@
+//! MusicGen, https://github.com/facebookresearch/audiocraft
+//!
+//! The decoder predicts the EnCodec codebooks of the audio from T5 text embeddings. Its attention
+//! and feed-forward projections carry LoRA layers, which is where style transfer adapters are
+//! trained, while the text encoder is frozen. Decode the generated codes to audio with
+//! `candle_transformers::models::encodec`.
+
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{layer_norm, Activation, LayerNorm, Linear, VarBuilder};
+use candle_transformers::models::t5;
+use serde::Deserialize;
+
+use crate::with_tracing::{linear_no_bias, TracedLoraLinear};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DecoderConfig {
+    pub vocab_size: usize,
+    pub max_position_embeddings: usize,
+    pub num_hidden_layers: usize,
+    pub ffn_dim: usize,
+    pub num_attention_heads: usize,
+    pub activation_function: Activation,
+    pub hidden_size: usize,
+    pub num_codebooks: usize,
+    pub pad_token_id: u32,
+    pub bos_token_id: u32,
+}
+
+impl DecoderConfig {
+    // https://huggingface.co/facebook/musicgen-small/blob/main/config.json
+    pub fn musicgen_small() -> Self {
+        Self {
+            vocab_size: 2048,
+            max_position_embeddings: 2048,
+            num_hidden_layers: 24,
+            ffn_dim: 4096,
+            num_attention_heads: 16,
+            activation_function: Activation::Gelu,
+            hidden_size: 1024,
+            num_codebooks: 4,
+            pad_token_id: 2048,
+            bos_token_id: 2048,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub decoder: DecoderConfig,
+    pub text_encoder: t5::Config,
+}
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: TracedLoraLinear,
+    k_proj: TracedLoraLinear,
+    v_proj: TracedLoraLinear,
+    out_proj: TracedLoraLinear,
+    scaling: f64,
+    num_heads: usize,
+    head_dim: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(
+        cfg: &DecoderConfig,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let embed_dim = cfg.hidden_size;
+        let head_dim = embed_dim / cfg.num_attention_heads;
+        let proj = |name: &str, lora_config: LoraConfig| {
+            linear_no_bias(embed_dim, embed_dim, vb.pp(name), merge, lora_config)
+        };
+        Ok(Self {
+            q_proj: proj("q_proj", lora_config.clone())?,
+            k_proj: proj("k_proj", lora_config.clone())?,
+            v_proj: proj("v_proj", lora_config.clone())?,
+            out_proj: proj("out_proj", lora_config)?,
+            scaling: (head_dim as f64).powf(-0.5),
+            num_heads: cfg.num_attention_heads,
+            head_dim,
+            kv_cache: None,
+        })
+    }
+
+    fn shape(&self, xs: &Tensor, b_sz: usize) -> Result<Tensor> {
+        xs.reshape((b_sz, (), self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    /// Self attention when `key_value_states` is `None`, which is then cached across calls.
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        key_value_states: Option<&Tensor>,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (b_sz, tgt_len, _) = xs.dims3()?;
+        let query_states = self.shape(&(xs.apply(&self.q_proj)? * self.scaling)?, b_sz)?;
+        let (key_states, value_states) = match key_value_states {
+            Some(states) => (
+                self.shape(&states.apply(&self.k_proj)?, b_sz)?,
+                self.shape(&states.apply(&self.v_proj)?, b_sz)?,
+            ),
+            None => {
+                let key_states = self.shape(&xs.apply(&self.k_proj)?, b_sz)?;
+                let value_states = self.shape(&xs.apply(&self.v_proj)?, b_sz)?;
+                let (key_states, value_states) = match &self.kv_cache {
+                    None => (key_states, value_states),
+                    Some((prev_k, prev_v)) => (
+                        Tensor::cat(&[prev_k, &key_states], 2)?,
+                        Tensor::cat(&[prev_v, &value_states], 2)?,
+                    ),
+                };
+                self.kv_cache = Some((key_states.clone(), value_states.clone()));
+                (key_states, value_states)
+            }
+        };
+        let attn_weights = query_states.matmul(&key_states.transpose(2, 3)?)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        candle_nn::ops::softmax_last_dim(&attn_weights)?
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, tgt_len, ()))?
+            .apply(&self.out_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    self_attn: Attention,
+    self_attn_layer_norm: LayerNorm,
+    encoder_attn: Attention,
+    encoder_attn_layer_norm: LayerNorm,
+    fc1: TracedLoraLinear,
+    fc2: TracedLoraLinear,
+    final_layer_norm: LayerNorm,
+    activation: Activation,
+}
+
+impl DecoderLayer {
+    fn new(
+        cfg: &DecoderConfig,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let h = cfg.hidden_size;
+        let self_attn = Attention::new(cfg, vb.pp("self_attn"), merge, lora_config.clone())?;
+        let encoder_attn = Attention::new(cfg, vb.pp("encoder_attn"), merge, lora_config.clone())?;
+        let fc1 = linear_no_bias(h, cfg.ffn_dim, vb.pp("fc1"), merge, lora_config.clone())?;
+        let fc2 = linear_no_bias(cfg.ffn_dim, h, vb.pp("fc2"), merge, lora_config)?;
+        Ok(Self {
+            self_attn,
+            self_attn_layer_norm: layer_norm(h, 1e-5, vb.pp("self_attn_layer_norm"))?,
+            encoder_attn,
+            encoder_attn_layer_norm: layer_norm(h, 1e-5, vb.pp("encoder_attn_layer_norm"))?,
+            fc1,
+            fc2,
+            final_layer_norm: layer_norm(h, 1e-5, vb.pp("final_layer_norm"))?,
+            activation: cfg.activation_function,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        encoder_xs: &Tensor,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = xs.apply(&self.self_attn_layer_norm)?;
+        let xs = (self.self_attn.forward(&xs, None, attention_mask)? + residual)?;
+
+        let residual = &xs;
+        let xs = xs.apply(&self.encoder_attn_layer_norm)?;
+        let xs = (self.encoder_attn.forward(&xs, Some(encoder_xs), None)? + residual)?;
+
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.final_layer_norm)?
+            .apply(&self.fc1)?
+            .apply(&self.activation)?
+            .apply(&self.fc2)?;
+        residual + xs
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache();
+        self.encoder_attn.clear_kv_cache();
+    }
+}
+
+/// Sinusoidal position table of shape (num_positions, dim), cosines first.
+fn sinusoidal_positions(num_positions: usize, dim: usize, device: &Device) -> Result<Tensor> {
+    let half_dim = dim / 2;
+    let scale = (10000f64).ln() / (half_dim - 1) as f64;
+    let inv_freq: Vec<_> = (0..half_dim)
+        .map(|i| (i as f64 * -scale).exp() as f32)
+        .collect();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, half_dim), device)?;
+    let freqs = Tensor::arange(0u32, num_positions as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((num_positions, 1))?
+        .matmul(&inv_freq)?;
+    Tensor::cat(&[freqs.cos()?, freqs.sin()?], D::Minus1)
+}
+
+#[derive(Debug)]
+pub struct MusicgenForCausalLM {
+    embed_tokens: Vec<candle_nn::Embedding>,
+    embed_positions: Tensor,
+    layers: Vec<DecoderLayer>,
+    layer_norm: LayerNorm,
+    lm_heads: Vec<Linear>,
+    num_codebooks: usize,
+}
+
+impl MusicgenForCausalLM {
+    /// Load a MusicGen decoder which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The codebook embeddings and heads are not
+    /// adapted.
+    pub fn new(
+        cfg: &DecoderConfig,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let vb_d = vb.pp("model").pp("decoder");
+        let embed_tokens = (0..cfg.num_codebooks)
+            .map(|idx| {
+                candle_nn::embedding(
+                    cfg.vocab_size + 1,
+                    cfg.hidden_size,
+                    vb_d.pp("embed_tokens").pp(idx),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let embed_positions =
+            sinusoidal_positions(cfg.max_position_embeddings, cfg.hidden_size, vb.device())?
+                .to_dtype(vb.dtype())?;
+        let vb_l = vb_d.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|idx| DecoderLayer::new(cfg, vb_l.pp(idx), merge, lora_config.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let layer_norm = layer_norm(cfg.hidden_size, 1e-5, vb_d.pp("layer_norm"))?;
+        let lm_heads = (0..cfg.num_codebooks)
+            .map(|idx| {
+                candle_nn::linear_no_bias(
+                    cfg.hidden_size,
+                    cfg.vocab_size,
+                    vb.pp("lm_heads").pp(idx),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            embed_tokens,
+            embed_positions,
+            layers,
+            layer_norm,
+            lm_heads,
+            num_codebooks: cfg.num_codebooks,
+        })
+    }
+
+    /// Run the decoder on `(b_sz, num_codebooks, seq_len)` codes attending to `encoder_xs`, and
+    /// return the `(b_sz, num_codebooks, seq_len, vocab_size)` logits.
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        encoder_xs: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (_b_sz, num_codebooks, seq_len) = input_ids.dims3()?;
+        if num_codebooks != self.num_codebooks {
+            candle_core::bail!("unexpected num codebooks in input {:?}", input_ids.shape())
+        }
+        let mut xs = input_ids.i((.., 0))?.apply(&self.embed_tokens[0])?;
+        for (idx, embed_tokens) in self.embed_tokens.iter().enumerate().skip(1) {
+            xs = (xs + input_ids.i((.., idx))?.apply(embed_tokens)?)?;
+        }
+        let positions = self.embed_positions.narrow(0, seqlen_offset, seq_len)?;
+        let mut xs = xs.broadcast_add(&positions.unsqueeze(0)?)?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            Some(causal_mask(seq_len, seqlen_offset, xs.device())?.to_dtype(xs.dtype())?)
+        };
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), encoder_xs)?;
+        }
+        let xs = xs.apply(&self.layer_norm)?;
+        let logits = self
+            .lm_heads
+            .iter()
+            .map(|lm_head| xs.apply(lm_head))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&logits, 1)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
+
+fn causal_mask(tgt_len: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..tgt_len)
+        .flat_map(|i| {
+            (0..tgt_len + seqlen_offset).map(move |j| {
+                if j > i + seqlen_offset {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+        })
+        .collect();
+    Tensor::from_slice(&mask, (tgt_len, tgt_len + seqlen_offset), device)
+}
+
+#[derive(Debug)]
+pub struct MusicgenForConditionalGeneration {
+    pub text_encoder: t5::T5EncoderModel,
+    enc_to_dec_proj: Option<Linear>,
+    pub decoder: MusicgenForCausalLM,
+}
+
+impl MusicgenForConditionalGeneration {
+    /// Load a MusicGen model whose decoder will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The T5 text encoder is not adapted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let text_encoder = t5::T5EncoderModel::load(vb.pp("text_encoder"), &cfg.text_encoder)?;
+        let enc_to_dec_proj = if cfg.text_encoder.d_model != cfg.decoder.hidden_size {
+            Some(candle_nn::linear(
+                cfg.text_encoder.d_model,
+                cfg.decoder.hidden_size,
+                vb.pp("enc_to_dec_proj"),
+            )?)
+        } else {
+            None
+        };
+        let decoder = MusicgenForCausalLM::new(&cfg.decoder, vb.pp("decoder"), merge, lora_config)?;
+        Ok(Self {
+            text_encoder,
+            enc_to_dec_proj,
+            decoder,
+        })
+    }
+
+    /// Encode the `(b_sz, seq_len)` text tokens into the states the decoder attends to.
+    pub fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        let xs = self.text_encoder.forward(input_ids)?;
+        match &self.enc_to_dec_proj {
+            None => Ok(xs),
+            Some(proj) => xs.apply(proj),
+        }
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.text_encoder.clear_kv_cache();
+        self.decoder.clear_kv_cache();
+    }
+}
@@ -11,8 +11,9 @@ use std::sync::Arc;
 
 use crate::unsync_func::UnsyncFunc;
 
-#[derive(Debug, AutoLoraConvert)]
+// The fields are replaced before the derive so that it sees the LoRA layer fields.
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 struct ResnetConv2d {
     inner: Conv2d,
 }
@@ -58,7 +59,7 @@ fn conv2d(
             None,
             Some(conv2d_config),
             None,
-        );
+        )?;
     } else {
         this.get_lora_model(
             lora_config,
@@ -67,7 +68,7 @@ fn conv2d(
             None,
             Some(conv2d_config),
             None,
-        );
+        )?;
     }
 
     Ok(this)
@@ -165,6 +166,33 @@ fn basic_layer(
     }))
 }
 
+/// 3x3 max pooling with stride 2 of the padded stem output. Unlike
+/// `Tensor::max_pool2d_with_stride`, whose backward pass needs the stride to match the kernel
+/// size, this supports backpropagation to the LoRA weights of the stem convolution.
+fn stem_max_pool(xs: &Tensor) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    let strided = |xs: &Tensor, dim: usize, len: usize, offset: usize| {
+        let out_len = (len - 3) / 2 + 1;
+        let index = (0..out_len as u32)
+            .map(|i| 2 * i + offset as u32)
+            .collect::<Vec<_>>();
+        xs.contiguous()?
+            .index_select(&Tensor::new(index, xs.device())?, dim)
+    };
+    let mut pooled: Option<Tensor> = None;
+    for dy in 0..3 {
+        let rows = strided(xs, 2, h, dy)?;
+        for dx in 0..3 {
+            let window = strided(&rows, 3, w, dx)?;
+            pooled = Some(match pooled {
+                Some(pooled) => pooled.maximum(&window)?,
+                None => window,
+            });
+        }
+    }
+    Ok(pooled.unwrap())
+}
+
 #[allow(clippy::too_many_arguments)]
 fn resnet(
     nclasses: Option<usize>,
@@ -196,7 +224,7 @@ fn resnet(
             .relu()?
             .pad_with_same(D::Minus1, 1, 1)?
             .pad_with_same(D::Minus2, 1, 1)?
-            .max_pool2d_with_stride(3, 2)?
+            .apply(&stem_max_pool)?
             .apply(&layer1)?
             .apply(&layer2)?
             .apply(&layer3)?
@@ -384,7 +412,7 @@ fn bottleneck_resnet(
             .relu()?
             .pad_with_same(D::Minus1, 1, 1)?
             .pad_with_same(D::Minus2, 1, 1)?
-            .max_pool2d_with_stride(3, 2)?
+            .apply(&stem_max_pool)?
             .apply(&layer1)?
             .apply(&layer2)?
             .apply(&layer3)?
@@ -0,0 +1,342 @@
+//! RWKV v5 (Eagle), https://github.com/BlinkDL/RWKV-LM
+//!
+//! The time-mix (`key`, `value`, `receptance`, `gate`, `output`) and channel-mix (`key`,
+//! `receptance`, `value`) projections carry LoRA layers. The configuration, recurrent state and
+//! world tokenizer are the ones of `candle_transformers::models::rwkv_v5`.
+
+use candle_core::{IndexOp, Module, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_nn::{embedding, layer_norm, Embedding, LayerNorm, Linear, VarBuilder};
+pub use candle_transformers::models::rwkv_v5::{Config, State, StatePerLayer, Tokenizer};
+
+use crate::with_tracing::{linear_no_bias, TracedLoraLinear};
+
+/// The inputs of the previous positions, `previous` being the last input of the previous call.
+fn token_shift(xs: &Tensor, previous: &Tensor) -> Result<Tensor> {
+    let seq_len = xs.dim(1)?;
+    let previous = previous.unsqueeze(1)?.to_dtype(xs.dtype())?;
+    if seq_len == 1 {
+        Ok(previous)
+    } else {
+        Tensor::cat(&[&previous, &xs.narrow(1, 0, seq_len - 1)?], 1)
+    }
+}
+
+#[derive(Debug)]
+struct SelfAttention {
+    key: TracedLoraLinear,
+    receptance: TracedLoraLinear,
+    value: TracedLoraLinear,
+    gate: TracedLoraLinear,
+    output: TracedLoraLinear,
+    ln_x: candle_nn::GroupNorm,
+    time_mix_key: Tensor,
+    time_mix_value: Tensor,
+    time_mix_receptance: Tensor,
+    time_decay: Tensor,
+    time_faaaa: Tensor,
+    time_mix_gate: Tensor,
+    layer_id: usize,
+    n_attn_heads: usize,
+}
+
+impl SelfAttention {
+    fn new(
+        layer_id: usize,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let hidden_size = cfg.hidden_size;
+        let attn_hidden_size = cfg.attention_hidden_size;
+        let key = linear_no_bias(
+            hidden_size,
+            attn_hidden_size,
+            vb.pp("key"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let receptance = linear_no_bias(
+            hidden_size,
+            attn_hidden_size,
+            vb.pp("receptance"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let value = linear_no_bias(
+            hidden_size,
+            attn_hidden_size,
+            vb.pp("value"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let gate = linear_no_bias(
+            hidden_size,
+            attn_hidden_size,
+            vb.pp("gate"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let output = linear_no_bias(
+            attn_hidden_size,
+            hidden_size,
+            vb.pp("output"),
+            merge,
+            lora_config,
+        )?;
+        let ln_x = candle_nn::group_norm(
+            hidden_size / cfg.head_size,
+            hidden_size,
+            1e-5,
+            vb.pp("ln_x"),
+        )?;
+        let time_mix_key = vb.get((1, 1, cfg.hidden_size), "time_mix_key")?;
+        let time_mix_value = vb.get((1, 1, cfg.hidden_size), "time_mix_value")?;
+        let time_mix_receptance = vb.get((1, 1, cfg.hidden_size), "time_mix_receptance")?;
+        let n_attn_heads = cfg.hidden_size / cfg.head_size;
+        let time_decay = vb.get((n_attn_heads, cfg.head_size), "time_decay")?;
+        let time_faaaa = vb.get((n_attn_heads, cfg.head_size), "time_faaaa")?;
+        let time_mix_gate = vb.get((1, 1, cfg.hidden_size), "time_mix_gate")?;
+        Ok(Self {
+            key,
+            receptance,
+            value,
+            gate,
+            output,
+            ln_x,
+            time_mix_key,
+            time_mix_value,
+            time_mix_receptance,
+            time_decay,
+            time_faaaa,
+            time_mix_gate,
+            layer_id,
+            n_attn_heads,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, state: &mut State) -> Result<Tensor> {
+        let h = self.time_decay.dim(0)?;
+        let (b, t, s) = xs.dims3()?;
+        let s = s / h;
+        let (receptance, key, value, gate) = {
+            // extract key-value
+            let shifted = token_shift(xs, &state.per_layer[self.layer_id].extract_key_value)?;
+            let mix = |time_mix: &Tensor| -> Result<Tensor> {
+                xs.broadcast_mul(time_mix)? + shifted.broadcast_mul(&(1.0 - time_mix)?)?
+            };
+            let key = self.key.forward(&mix(&self.time_mix_key)?)?;
+            let value = self.value.forward(&mix(&self.time_mix_value)?)?;
+            let receptance = self.receptance.forward(&mix(&self.time_mix_receptance)?)?;
+            let gate = candle_nn::ops::silu(&self.gate.forward(&mix(&self.time_mix_gate)?)?)?;
+            state.per_layer[self.layer_id].extract_key_value = xs.i((.., t - 1))?;
+            (receptance, key, value, gate)
+        };
+        // linear attention
+        let mut state_ = state.per_layer[self.layer_id].linear_attention.clone();
+        let key = key.reshape((b, t, h, s))?.permute((0, 2, 3, 1))?;
+        let value = value.reshape((b, t, h, s))?.transpose(1, 2)?;
+        let receptance = receptance.reshape((b, t, h, s))?.transpose(1, 2)?;
+
+        let time_decay =
+            self.time_decay
+                .exp()?
+                .neg()?
+                .exp()?
+                .reshape((self.n_attn_heads, (), 1))?;
+        let time_faaaa = self.time_faaaa.reshape((self.n_attn_heads, (), 1))?;
+
+        let mut out: Vec<Tensor> = Vec::with_capacity(t);
+        for t_ in 0..t {
+            let rt = receptance.i((.., .., t_..t_ + 1))?.contiguous()?;
+            let kt = key.i((.., .., .., t_..t_ + 1))?.contiguous()?;
+            let vt = value.i((.., .., t_..t_ + 1))?.contiguous()?;
+            let at = kt.matmul(&vt)?;
+            let rhs = (time_faaaa.broadcast_mul(&at)? + &state_)?;
+            let out_ = rt.matmul(&rhs)?.squeeze(2)?;
+            state_ = (&at + time_decay.broadcast_mul(&state_))?;
+            out.push(out_)
+        }
+        let out = Tensor::cat(&out, 1)?.reshape((b * t, h * s, 1))?;
+        let out = out.apply(&self.ln_x)?.reshape((b, t, h * s))?;
+        let out = (out * gate)?.apply(&self.output)?;
+        state.per_layer[self.layer_id].linear_attention = state_;
+        Ok(out)
+    }
+}
+
+#[derive(Debug)]
+struct FeedForward {
+    time_mix_key: Tensor,
+    time_mix_receptance: Tensor,
+    key: TracedLoraLinear,
+    receptance: TracedLoraLinear,
+    value: TracedLoraLinear,
+    layer_id: usize,
+}
+
+impl FeedForward {
+    fn new(
+        layer_id: usize,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let int_size = cfg
+            .intermediate_size
+            .unwrap_or(((cfg.hidden_size as f64 * 3.5) as usize) / 32 * 32);
+        let key = linear_no_bias(
+            cfg.hidden_size,
+            int_size,
+            vb.pp("key"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let receptance = linear_no_bias(
+            cfg.hidden_size,
+            cfg.hidden_size,
+            vb.pp("receptance"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let value = linear_no_bias(
+            int_size,
+            cfg.hidden_size,
+            vb.pp("value"),
+            merge,
+            lora_config,
+        )?;
+        let time_mix_key = vb.get((1, 1, cfg.hidden_size), "time_mix_key")?;
+        let time_mix_receptance = vb.get((1, 1, cfg.hidden_size), "time_mix_receptance")?;
+        Ok(Self {
+            time_mix_key,
+            time_mix_receptance,
+            key,
+            receptance,
+            value,
+            layer_id,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, state: &mut State) -> Result<Tensor> {
+        let shifted = &token_shift(xs, &state.per_layer[self.layer_id].feed_forward)?;
+        let key = (xs.broadcast_mul(&self.time_mix_key)?
+            + shifted.broadcast_mul(&(1.0 - &self.time_mix_key)?)?)?;
+        let receptance = (xs.broadcast_mul(&self.time_mix_receptance)?
+            + shifted.broadcast_mul(&(1.0 - &self.time_mix_receptance)?)?)?;
+        let key = key.apply(&self.key)?.relu()?.sqr()?;
+        let value = key.apply(&self.value)?;
+        let receptance = candle_nn::ops::sigmoid(&receptance.apply(&self.receptance)?)?;
+        state.per_layer[self.layer_id].feed_forward = xs.i((.., xs.dim(1)? - 1))?;
+        receptance * value
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    pre_ln: Option<LayerNorm>,
+    ln1: LayerNorm,
+    ln2: LayerNorm,
+    attention: SelfAttention,
+    feed_forward: FeedForward,
+}
+
+impl Block {
+    fn new(
+        layer_id: usize,
+        cfg: &Config,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let ln1 = layer_norm(cfg.hidden_size, cfg.layer_norm_epsilon, vb.pp("ln1"))?;
+        let ln2 = layer_norm(cfg.hidden_size, cfg.layer_norm_epsilon, vb.pp("ln2"))?;
+        let pre_ln = if layer_id == 0 {
+            Some(layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_epsilon,
+                vb.pp("pre_ln"),
+            )?)
+        } else {
+            None
+        };
+        let attention = SelfAttention::new(
+            layer_id,
+            cfg,
+            vb.pp("attention"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let feed_forward =
+            FeedForward::new(layer_id, cfg, vb.pp("feed_forward"), merge, lora_config)?;
+        Ok(Self {
+            pre_ln,
+            ln1,
+            ln2,
+            attention,
+            feed_forward,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, state: &mut State) -> Result<Tensor> {
+        let xs = match self.pre_ln.as_ref() {
+            None => xs.clone(),
+            Some(pre_ln) => xs.apply(pre_ln)?,
+        };
+        let attention = self.attention.forward(&xs.apply(&self.ln1)?, state)?;
+        let xs = (xs + attention)?;
+        let feed_forward = self.feed_forward.forward(&xs.apply(&self.ln2)?, state)?;
+        xs + feed_forward
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    embeddings: Embedding,
+    blocks: Vec<Block>,
+    ln_out: LayerNorm,
+    head: Linear,
+}
+
+impl Model {
+    /// Load a RWKV v5 model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let vb_m = vb.pp("rwkv");
+        let embeddings = embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embeddings"))?;
+        let vb_b = vb_m.pp("blocks");
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|block_index| {
+                Block::new(
+                    block_index,
+                    cfg,
+                    vb_b.pp(block_index),
+                    merge,
+                    lora_config.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ln_out = layer_norm(cfg.hidden_size, 1e-5, vb_m.pp("ln_out"))?;
+        let head = candle_nn::linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("head"))?;
+        Ok(Self {
+            embeddings,
+            blocks,
+            ln_out,
+            head,
+        })
+    }
+
+    /// Logits `(batch, seq_len, vocab_size)` of the tokens, advancing the recurrent `state`.
+    pub fn forward(&self, xs: &Tensor, state: &mut State) -> Result<Tensor> {
+        let mut xs = xs.apply(&self.embeddings)?;
+        for block in self.blocks.iter() {
+            xs = block.forward(&xs, state)?;
+        }
+        let xs = xs.apply(&self.ln_out)?.apply(&self.head)?;
+        state.pos += 1;
+        Ok(xs)
+    }
+}
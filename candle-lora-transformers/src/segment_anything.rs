@@ -0,0 +1,557 @@
+//! Segment Anything (SAM), https://github.com/facebookresearch/segment-anything
+//!
+//! The ViT image encoder carries LoRA layers on the attention `qkv`/`proj` and MLP `lin1`/`lin2`
+//! projections of every block, as in the usual recipe for adapting SAM to medical or other
+//! out-of-domain images. The patch embedding, neck, prompt encoder and mask decoder are frozen.
+
+use candle_core::{DType, IndexOp, Module, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_nn::{layer_norm, Activation, LayerNorm, VarBuilder};
+use candle_transformers::models::segment_anything::{
+    mask_decoder::MaskDecoder, prompt_encoder::PromptEncoder, LayerNorm2d,
+};
+
+use crate::with_tracing::{linear, TracedLoraLinear};
+
+pub const IMAGE_SIZE: usize = 1024;
+const PATCH_SIZE: usize = 16;
+const PROMPT_EMBED_DIM: usize = 256;
+const WINDOW_SIZE: usize = 14;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub embed_dim: usize,
+    pub depth: usize,
+    pub num_heads: usize,
+    pub global_attn_indexes: Vec<usize>,
+}
+
+impl Config {
+    pub fn vit_b() -> Self {
+        Self {
+            embed_dim: 768,
+            depth: 12,
+            num_heads: 12,
+            global_attn_indexes: vec![2, 5, 8, 11],
+        }
+    }
+
+    pub fn vit_l() -> Self {
+        Self {
+            embed_dim: 1024,
+            depth: 24,
+            num_heads: 16,
+            global_attn_indexes: vec![5, 11, 17, 23],
+        }
+    }
+
+    pub fn vit_h() -> Self {
+        Self {
+            embed_dim: 1280,
+            depth: 32,
+            num_heads: 16,
+            global_attn_indexes: vec![7, 15, 23, 31],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PatchEmbed {
+    proj: candle_nn::Conv2d,
+}
+
+impl PatchEmbed {
+    fn new(in_chans: usize, embed_dim: usize, patch_size: usize, vb: VarBuilder) -> Result<Self> {
+        let cfg = candle_nn::Conv2dConfig {
+            stride: patch_size,
+            ..Default::default()
+        };
+        let proj = candle_nn::conv2d(in_chans, embed_dim, patch_size, cfg, vb.pp("proj"))?;
+        Ok(Self { proj })
+    }
+}
+
+impl Module for PatchEmbed {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.proj)?.permute((0, 2, 3, 1))
+    }
+}
+
+fn get_rel_pos(q_size: usize, k_size: usize, rel_pos: &Tensor) -> Result<Tensor> {
+    let max_rel_dist = 2 * usize::max(q_size, k_size) - 1;
+    if rel_pos.dim(0)? != max_rel_dist {
+        candle_core::bail!(
+            "relative positions of size {} do not match {max_rel_dist}",
+            rel_pos.dim(0)?
+        )
+    }
+    let dev = rel_pos.device();
+    let q_coords = Tensor::arange(0u32, q_size as u32, dev)?
+        .reshape((q_size, 1))?
+        .to_dtype(DType::F32)?;
+    let k_coords = Tensor::arange(0u32, k_size as u32, dev)?
+        .reshape((1, k_size))?
+        .to_dtype(DType::F32)?;
+    let q_coords = (q_coords * f64::max(1f64, k_size as f64 / q_size as f64))?;
+    let k_coords = (k_coords * f64::max(1f64, q_size as f64 / k_size as f64))?;
+    let relative_coords = (q_coords.broadcast_sub(&k_coords)?
+        + (k_size as f64 - 1.) * f64::max(1f64, q_size as f64 / k_size as f64))?;
+    let (d1, d2) = relative_coords.dims2()?;
+    let relative_coords = relative_coords.to_dtype(DType::U32)?;
+    rel_pos
+        .index_select(&relative_coords.reshape(d1 * d2)?, 0)?
+        .reshape((d1, d2, ()))
+}
+
+#[derive(Debug)]
+struct Attention {
+    qkv: TracedLoraLinear,
+    proj: TracedLoraLinear,
+    num_heads: usize,
+    scale: f64,
+    rel_pos_h: Tensor,
+    rel_pos_w: Tensor,
+}
+
+impl Attention {
+    fn new(
+        dim: usize,
+        num_heads: usize,
+        input_size: (usize, usize),
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let qkv = linear(dim, dim * 3, vb.pp("qkv"), merge, lora_config.clone())?;
+        let proj = linear(dim, dim, vb.pp("proj"), merge, lora_config)?;
+        let head_dim = dim / num_heads;
+        let rel_pos_h = vb.get((2 * input_size.0 - 1, head_dim), "rel_pos_h")?;
+        let rel_pos_w = vb.get((2 * input_size.1 - 1, head_dim), "rel_pos_w")?;
+        Ok(Self {
+            qkv,
+            proj,
+            num_heads,
+            scale: 1. / (head_dim as f64).sqrt(),
+            rel_pos_h,
+            rel_pos_w,
+        })
+    }
+
+    fn add_decomposed_rel_pos(
+        &self,
+        attn: Tensor,
+        q: &Tensor,
+        (q_h, q_w): (usize, usize),
+        (k_h, k_w): (usize, usize),
+    ) -> Result<Tensor> {
+        let r_h = get_rel_pos(q_h, k_h, &self.rel_pos_h)?;
+        let r_w = get_rel_pos(q_w, k_w, &self.rel_pos_w)?;
+        let (b, _, dim) = q.dims3()?;
+        let r_q = q.reshape((b, q_h, q_w, dim))?;
+        // rel_h = torch.einsum("bhwc,hkc->bhwk", r_q, Rh)
+        let rel_h = r_q.matmul(&r_h.broadcast_left(b)?.t()?.contiguous()?)?;
+        // rel_w = torch.einsum("bhwc,wkc->bhwk", r_q, Rw)
+        let rel_w = r_q
+            .transpose(1, 2)?
+            .contiguous()?
+            .matmul(&r_w.broadcast_left(b)?.t()?.contiguous()?)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        (attn.reshape((b, q_h, q_w, k_h, k_w))?
+            + rel_h.unsqueeze(4)?.broadcast_add(&rel_w.unsqueeze(3)?)?)?
+        .reshape((b, q_h * q_w, k_h * k_w))
+    }
+}
+
+impl Module for Attention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, h, w, c) = xs.dims4()?;
+        let head_dim = c / self.num_heads;
+        let qkv = self
+            .qkv
+            .forward(&xs.flatten_to(1)?)?
+            .reshape((b, h * w, 3, self.num_heads, head_dim))?
+            .permute((2, 0, 3, 1, 4))?
+            .reshape((3, b * self.num_heads, h * w, head_dim))?;
+        let q = qkv.i(0)?;
+        let k = qkv.i(1)?;
+        let v = qkv.i(2)?;
+        let attn = (&q * self.scale)?.matmul(&k.t()?)?;
+        let attn = self.add_decomposed_rel_pos(attn, &q, (h, w), (h, w))?;
+        let attn = candle_nn::ops::softmax_last_dim(&attn)?.matmul(&v)?;
+        let attn = attn
+            .reshape((b, self.num_heads, h, w, head_dim))?
+            .permute((0, 2, 3, 1, 4))?
+            .reshape((b, h * w, c))?;
+        self.proj.forward(&attn)?.reshape((b, h, w, c))
+    }
+}
+
+#[derive(Debug)]
+struct MlpBlock {
+    lin1: TracedLoraLinear,
+    lin2: TracedLoraLinear,
+}
+
+impl MlpBlock {
+    fn new(
+        embedding_dim: usize,
+        mlp_dim: usize,
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let lin1 = linear(
+            embedding_dim,
+            mlp_dim,
+            vb.pp("lin1"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let lin2 = linear(mlp_dim, embedding_dim, vb.pp("lin2"), merge, lora_config)?;
+        Ok(Self { lin1, lin2 })
+    }
+}
+
+impl Module for MlpBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.lin1)?
+            .apply(&Activation::Gelu)?
+            .apply(&self.lin2)
+    }
+}
+
+fn window_partition(xs: Tensor, window_size: usize) -> Result<(Tensor, (usize, usize))> {
+    let (b, h, w, c) = xs.dims4()?;
+    let pad_h = (window_size - h % window_size) % window_size;
+    let pad_w = (window_size - w % window_size) % window_size;
+    let xs = if pad_h > 0 {
+        xs.pad_with_zeros(1, 0, pad_h)?
+    } else {
+        xs
+    };
+    let xs = if pad_w > 0 {
+        xs.pad_with_zeros(2, 0, pad_w)?
+    } else {
+        xs
+    };
+    let (h_p, w_p) = (h + pad_h, w + pad_w);
+    let windows = xs
+        .reshape((
+            b,
+            h_p / window_size,
+            window_size,
+            w_p / window_size,
+            window_size,
+            c,
+        ))?
+        .transpose(2, 3)?
+        .contiguous()?
+        .flatten_to(2)?;
+    Ok((windows, (h_p, w_p)))
+}
+
+fn window_unpartition(
+    windows: Tensor,
+    window_size: usize,
+    (h_p, w_p): (usize, usize),
+    (h, w): (usize, usize),
+) -> Result<Tensor> {
+    let b = windows.dim(0)? / (h_p * w_p / window_size / window_size);
+    let xs = windows
+        .reshape((
+            b,
+            h_p / window_size,
+            w_p / window_size,
+            window_size,
+            window_size,
+            windows.elem_count() / b / h_p / w_p,
+        ))?
+        .transpose(2, 3)?
+        .contiguous()?
+        .reshape((b, h_p, w_p, ()))?;
+    let xs = if h_p > h { xs.narrow(1, 0, h)? } else { xs };
+    let xs = if w_p > w { xs.narrow(2, 0, w)? } else { xs };
+    Ok(xs)
+}
+
+#[derive(Debug)]
+struct Block {
+    norm1: LayerNorm,
+    attn: Attention,
+    norm2: LayerNorm,
+    mlp: MlpBlock,
+    window_size: usize,
+}
+
+impl Block {
+    fn new(
+        dim: usize,
+        num_heads: usize,
+        window_size: usize,
+        input_size: (usize, usize),
+        vb: VarBuilder,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let norm1 = layer_norm(dim, 1e-6, vb.pp("norm1"))?;
+        let norm2 = layer_norm(dim, 1e-6, vb.pp("norm2"))?;
+        let input_size_attn = if window_size == 0 {
+            input_size
+        } else {
+            (window_size, window_size)
+        };
+        let attn = Attention::new(
+            dim,
+            num_heads,
+            input_size_attn,
+            vb.pp("attn"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let mlp = MlpBlock::new(dim, dim * 4, vb.pp("mlp"), merge, lora_config)?;
+        Ok(Self {
+            norm1,
+            attn,
+            norm2,
+            mlp,
+            window_size,
+        })
+    }
+}
+
+impl Module for Block {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let shortcut = xs;
+        let xs = self.norm1.forward(xs)?;
+        let hw = (xs.dim(1)?, xs.dim(2)?);
+        let (xs, pad_hw) = if self.window_size > 0 {
+            window_partition(xs, self.window_size)?
+        } else {
+            (xs, (0, 0))
+        };
+        let xs = self.attn.forward(&xs)?;
+        let xs = if self.window_size > 0 {
+            window_unpartition(xs, self.window_size, pad_hw, hw)?
+        } else {
+            xs
+        };
+        let xs = (xs + shortcut)?;
+        &xs + xs.apply(&self.norm2)?.apply(&self.mlp)?
+    }
+}
+
+/// The SAM ViT image encoder, producing `(b, 256, 64, 64)` embeddings from `(b, 3, 1024, 1024)`
+/// normalized images.
+#[derive(Debug)]
+pub struct ImageEncoderViT {
+    patch_embed: PatchEmbed,
+    blocks: Vec<Block>,
+    neck_conv1: candle_nn::Conv2d,
+    neck_ln1: LayerNorm2d,
+    neck_conv2: candle_nn::Conv2d,
+    neck_ln2: LayerNorm2d,
+    pos_embed: Tensor,
+}
+
+impl ImageEncoderViT {
+    /// Load a SAM image encoder which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let grid_size = IMAGE_SIZE / PATCH_SIZE;
+        let patch_embed = PatchEmbed::new(3, cfg.embed_dim, PATCH_SIZE, vb.pp("patch_embed"))?;
+        let vb_b = vb.pp("blocks");
+        let blocks = (0..cfg.depth)
+            .map(|i| {
+                let window_size = if cfg.global_attn_indexes.contains(&i) {
+                    0
+                } else {
+                    WINDOW_SIZE
+                };
+                Block::new(
+                    cfg.embed_dim,
+                    cfg.num_heads,
+                    window_size,
+                    (grid_size, grid_size),
+                    vb_b.pp(i),
+                    merge,
+                    lora_config.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let neck_conv1 = candle_nn::conv2d_no_bias(
+            cfg.embed_dim,
+            PROMPT_EMBED_DIM,
+            1,
+            Default::default(),
+            vb.pp("neck.0"),
+        )?;
+        let neck_ln1 = LayerNorm2d::new(PROMPT_EMBED_DIM, 1e-6, vb.pp("neck.1"))?;
+        let conv_cfg = candle_nn::Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let neck_conv2 = candle_nn::conv2d_no_bias(
+            PROMPT_EMBED_DIM,
+            PROMPT_EMBED_DIM,
+            3,
+            conv_cfg,
+            vb.pp("neck.2"),
+        )?;
+        let neck_ln2 = LayerNorm2d::new(PROMPT_EMBED_DIM, 1e-6, vb.pp("neck.3"))?;
+        let pos_embed = vb.get((1, grid_size, grid_size, cfg.embed_dim), "pos_embed")?;
+        Ok(Self {
+            patch_embed,
+            blocks,
+            neck_conv1,
+            neck_ln1,
+            neck_conv2,
+            neck_ln2,
+            pos_embed,
+        })
+    }
+}
+
+impl Module for ImageEncoderViT {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.patch_embed.forward(xs)?;
+        let mut xs = xs.broadcast_add(&self.pos_embed)?;
+        for block in self.blocks.iter() {
+            xs = block.forward(&xs)?
+        }
+        xs.permute((0, 3, 1, 2))?
+            .apply(&self.neck_conv1)?
+            .apply(&self.neck_ln1)?
+            .apply(&self.neck_conv2)?
+            .apply(&self.neck_ln2)
+    }
+}
+
+#[derive(Debug)]
+pub struct Sam {
+    image_encoder: ImageEncoderViT,
+    prompt_encoder: PromptEncoder,
+    mask_decoder: MaskDecoder,
+    pixel_mean: Tensor,
+    pixel_std: Tensor,
+}
+
+impl Sam {
+    /// Load a SAM model whose image encoder will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The prompt encoder and mask decoder are
+    /// loaded as is.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let image_embedding_size = IMAGE_SIZE / PATCH_SIZE;
+        let image_encoder = ImageEncoderViT::new(cfg, vb.pp("image_encoder"), merge, lora_config)?;
+        let prompt_encoder = PromptEncoder::new(
+            PROMPT_EMBED_DIM,
+            (image_embedding_size, image_embedding_size),
+            (IMAGE_SIZE, IMAGE_SIZE),
+            16,
+            vb.pp("prompt_encoder"),
+        )?;
+        let mask_decoder = MaskDecoder::new(PROMPT_EMBED_DIM, 3, 3, 256, vb.pp("mask_decoder"))?;
+        let pixel_mean =
+            Tensor::new(&[123.675f32, 116.28, 103.53], vb.device())?.reshape((3, 1, 1))?;
+        let pixel_std =
+            Tensor::new(&[58.395f32, 57.12, 57.375], vb.device())?.reshape((3, 1, 1))?;
+        Ok(Self {
+            image_encoder,
+            prompt_encoder,
+            mask_decoder,
+            pixel_mean,
+            pixel_std,
+        })
+    }
+
+    /// Normalize a `(3, h, w)` image with values in `[0, 255]` and pad it to `IMAGE_SIZE`.
+    pub fn preprocess(&self, img: &Tensor) -> Result<Tensor> {
+        let (_c, h, w) = img.dims3()?;
+        if h > IMAGE_SIZE || w > IMAGE_SIZE {
+            candle_core::bail!("image is too large ({w}, {h}), maximum size {IMAGE_SIZE}")
+        }
+        let img = img
+            .to_dtype(DType::F32)?
+            .broadcast_sub(&self.pixel_mean)?
+            .broadcast_div(&self.pixel_std)?;
+        img.pad_with_zeros(1, 0, IMAGE_SIZE - h)?
+            .pad_with_zeros(2, 0, IMAGE_SIZE - w)
+    }
+
+    /// Image embeddings of a `(3, h, w)` image.
+    pub fn embeddings(&self, img: &Tensor) -> Result<Tensor> {
+        let img = self.preprocess(img)?.unsqueeze(0)?;
+        self.image_encoder.forward(&img)
+    }
+
+    /// Predict the masks and IOU scores of a `(3, h, w)` image.
+    ///
+    /// The prompt is a list of points `(x, y, b)` with `x` and `y` between 0 and 1, `b` being
+    /// `true` for points inside the mask and `false` for background points.
+    pub fn forward(
+        &self,
+        img: &Tensor,
+        points: &[(f64, f64, bool)],
+        multimask_output: bool,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_c, original_h, original_w) = img.dims3()?;
+        let img_embeddings = self.embeddings(img)?;
+        let (low_res_mask, iou) = self.forward_for_embeddings(
+            &img_embeddings,
+            original_h,
+            original_w,
+            points,
+            multimask_output,
+        )?;
+        let mask = low_res_mask
+            .upsample_nearest2d(IMAGE_SIZE, IMAGE_SIZE)?
+            .get(0)?
+            .i((.., ..original_h, ..original_w))?;
+        Ok((mask, iou))
+    }
+
+    /// Predict the low resolution masks and IOU scores from precomputed image embeddings.
+    pub fn forward_for_embeddings(
+        &self,
+        img_embeddings: &Tensor,
+        original_h: usize,
+        original_w: usize,
+        points: &[(f64, f64, bool)],
+        multimask_output: bool,
+    ) -> Result<(Tensor, Tensor)> {
+        let image_pe = self.prompt_encoder.get_dense_pe()?;
+        let points = if points.is_empty() {
+            None
+        } else {
+            let n_points = points.len();
+            let xys = points
+                .iter()
+                .flat_map(|(x, y, _b)| {
+                    [
+                        (*x as f32) * (original_w as f32),
+                        (*y as f32) * (original_h as f32),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let labels = points
+                .iter()
+                .map(|(_x, _y, b)| if *b { 1f32 } else { 0f32 })
+                .collect::<Vec<_>>();
+            let points = Tensor::from_vec(xys, (1, n_points, 2), img_embeddings.device())?;
+            let labels = Tensor::from_vec(labels, (1, n_points), img_embeddings.device())?;
+            Some((points, labels))
+        };
+        let points = points.as_ref().map(|(xy, labels)| (xy, labels));
+        let (sparse_prompt_embeddings, dense_prompt_embeddings) =
+            self.prompt_encoder.forward(points, None, None)?;
+        self.mask_decoder.forward(
+            img_embeddings,
+            &image_pe,
+            &sparse_prompt_embeddings,
+            &dense_prompt_embeddings,
+            multimask_output,
+        )
+    }
+}
@@ -0,0 +1,220 @@
+//! Supervised fine-tuning data: instruction datasets in JSON lines, tokenization with the prompt
+//! masked out of the loss, and packing into fixed-length training sequences.
+//!
+//! Loss masks follow the convention of [`candle_lora::sequence_log_probs`]: a mask value is
+//! attached to each token and selects whether predicting that token counts in the loss.
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+/// One record of an instruction dataset.
+///
+/// Alpaca-style records (`instruction`, `input`, `output`) and prompt/completion records
+/// (`prompt`, `completion` or `response`) are both accepted.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct InstructionExample {
+    #[serde(alias = "prompt")]
+    pub instruction: String,
+    #[serde(default)]
+    pub input: String,
+    #[serde(alias = "completion", alias = "response")]
+    pub output: String,
+}
+
+/// Read an instruction dataset with one JSON record per line. Blank lines are skipped.
+pub fn load_jsonl<P: AsRef<Path>>(path: P) -> Result<Vec<InstructionExample>> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                candle_core::Error::Msg(format!("{}:{}: {e}", path.display(), idx + 1))
+            })
+        })
+        .collect()
+}
+
+/// How the instruction and input of an example are turned into the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptTemplate {
+    /// The Stanford Alpaca prompt.
+    Alpaca,
+    /// The instruction and the input, each followed by a newline.
+    Plain,
+}
+
+impl PromptTemplate {
+    pub fn prompt(&self, example: &InstructionExample) -> String {
+        match (self, example.input.is_empty()) {
+            (Self::Alpaca, true) => format!(
+                "Below is an instruction that describes a task. Write a response that \
+                 appropriately completes the request.\n\n### Instruction:\n{}\n\n### Response:\n",
+                example.instruction
+            ),
+            (Self::Alpaca, false) => format!(
+                "Below is an instruction that describes a task, paired with an input that \
+                 provides further context. Write a response that appropriately completes the \
+                 request.\n\n### Instruction:\n{}\n\n### Input:\n{}\n\n### Response:\n",
+                example.instruction, example.input
+            ),
+            (Self::Plain, true) => format!("{}\n", example.instruction),
+            (Self::Plain, false) => format!("{}\n{}\n", example.instruction, example.input),
+        }
+    }
+}
+
+/// Token ids of a training sequence with the loss mask of each token.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TokenizedExample {
+    pub input_ids: Vec<u32>,
+    pub loss_mask: Vec<u8>,
+}
+
+impl TokenizedExample {
+    pub fn len(&self) -> usize {
+        self.input_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input_ids.is_empty()
+    }
+
+    /// Number of tokens counted in the loss.
+    pub fn num_loss_tokens(&self) -> usize {
+        self.loss_mask.iter().filter(|&&m| m != 0).count()
+    }
+}
+
+/// Tokenize `example` with the prompt tokens masked out of the loss. The prompt gets the special
+/// tokens of the tokenizer, the response is followed by `eos_token_id` when given, and the
+/// sequence is truncated to `max_len` tokens.
+pub fn tokenize_example(
+    tokenizer: &Tokenizer,
+    example: &InstructionExample,
+    template: PromptTemplate,
+    eos_token_id: Option<u32>,
+    max_len: usize,
+) -> Result<TokenizedExample> {
+    let prompt = tokenizer
+        .encode(template.prompt(example), true)
+        .map_err(candle_core::Error::msg)?;
+    let response = tokenizer
+        .encode(example.output.as_str(), false)
+        .map_err(candle_core::Error::msg)?;
+    let mut input_ids = prompt.get_ids().to_vec();
+    let mut loss_mask = vec![0; input_ids.len()];
+    input_ids.extend_from_slice(response.get_ids());
+    input_ids.extend(eos_token_id);
+    loss_mask.resize(input_ids.len(), 1);
+    input_ids.truncate(max_len);
+    loss_mask.truncate(max_len);
+    Ok(TokenizedExample {
+        input_ids,
+        loss_mask,
+    })
+}
+
+/// Pack examples into sequences of exactly `seq_len` tokens.
+///
+/// Examples are placed whole, in order, into the current sequence while they fit, and the rest
+/// of the sequence is filled with `pad_token_id` which is excluded from the loss. Examples
+/// longer than `seq_len` are truncated. Packed examples attend to each other, as with the usual
+/// causal LM packing.
+pub fn pack_examples(
+    examples: &[TokenizedExample],
+    seq_len: usize,
+    pad_token_id: u32,
+) -> Vec<TokenizedExample> {
+    let mut packed = Vec::new();
+    let mut current = TokenizedExample::default();
+    let finish = |mut sequence: TokenizedExample, packed: &mut Vec<TokenizedExample>| {
+        sequence.input_ids.resize(seq_len, pad_token_id);
+        sequence.loss_mask.resize(seq_len, 0);
+        packed.push(sequence);
+    };
+    for example in examples.iter().filter(|example| !example.is_empty()) {
+        let len = example.len().min(seq_len);
+        if current.len() + len > seq_len {
+            finish(std::mem::take(&mut current), &mut packed);
+        }
+        current
+            .input_ids
+            .extend_from_slice(&example.input_ids[..len]);
+        current
+            .loss_mask
+            .extend_from_slice(&example.loss_mask[..len]);
+    }
+    if !current.is_empty() {
+        finish(current, &mut packed);
+    }
+    packed
+}
+
+/// A batch of training sequences, `(batch, seq_len)` token ids and loss mask.
+#[derive(Debug, Clone)]
+pub struct SftBatch {
+    pub input_ids: Tensor,
+    pub loss_mask: Tensor,
+}
+
+/// Stack examples into a batch, right-padding them with `pad_token_id` to the longest one.
+pub fn collate(
+    examples: &[TokenizedExample],
+    pad_token_id: u32,
+    device: &Device,
+) -> Result<SftBatch> {
+    let seq_len = examples.iter().map(|e| e.len()).max().unwrap_or(0);
+    let mut input_ids = Vec::with_capacity(examples.len() * seq_len);
+    let mut loss_mask = Vec::with_capacity(examples.len() * seq_len);
+    for example in examples {
+        input_ids.extend_from_slice(&example.input_ids);
+        input_ids.resize(input_ids.len() + seq_len - example.len(), pad_token_id);
+        loss_mask.extend_from_slice(&example.loss_mask);
+        loss_mask.resize(loss_mask.len() + seq_len - example.len(), 0);
+    }
+    Ok(SftBatch {
+        input_ids: Tensor::from_vec(input_ids, (examples.len(), seq_len), device)?,
+        loss_mask: Tensor::from_vec(loss_mask, (examples.len(), seq_len), device)?,
+    })
+}
+
+/// Split examples into batches of `batch_size`, see [`collate`].
+pub fn batches(
+    examples: &[TokenizedExample],
+    batch_size: usize,
+    pad_token_id: u32,
+    device: &Device,
+) -> Result<Vec<SftBatch>> {
+    examples
+        .chunks(batch_size)
+        .map(|chunk| collate(chunk, pad_token_id, device))
+        .collect()
+}
+
+/// Mean cross-entropy of the masked tokens of `input_ids` under the `(batch, seq_len,
+/// vocab_size)` `logits` of a causal LM, where the logits at position `t` predict token `t + 1`.
+pub fn sft_loss(logits: &Tensor, input_ids: &Tensor, loss_mask: &Tensor) -> Result<Tensor> {
+    let seq_len = input_ids.dim(1)?;
+    if seq_len < 2 {
+        candle_core::bail!("sequences need at least two tokens, got {seq_len}")
+    }
+    let logits = logits.narrow(1, 0, seq_len - 1)?.to_dtype(DType::F32)?;
+    let targets = input_ids
+        .narrow(1, 1, seq_len - 1)?
+        .to_dtype(DType::U32)?
+        .contiguous()?;
+    let mask = loss_mask.narrow(1, 1, seq_len - 1)?.to_dtype(DType::F32)?;
+    let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+    let nll = log_probs
+        .gather(&targets.unsqueeze(2)?, 2)?
+        .squeeze(2)?
+        .neg()?;
+    let count = mask.sum_all()?.clamp(1f32, f32::MAX)?;
+    (nll * mask)?.sum_all()? / count
+}
@@ -0,0 +1,163 @@
+//! Text embedding models (BGE, E5, GTE) on top of the LoRA BERT model.
+//!
+//! These are BERT encoders whose token states are pooled into one vector per sentence, so
+//! retrieval fine-tunes distributed as LoRA adapters on the encoder can be used for vector
+//! search. Only the BERT-based checkpoints are supported (e.g. `BAAI/bge-base-en-v1.5`,
+//! `intfloat/e5-base-v2`, `thenlper/gte-base`).
+
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+
+use crate::bert::{BertModel, Config};
+
+/// How the token states are reduced to a sentence embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pooling {
+    /// The state of the first (`[CLS]`) token.
+    Cls,
+    /// The mean of the states of the non-padding tokens.
+    Mean,
+}
+
+/// The sentence-transformers pooling configuration, `1_Pooling/config.json`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PoolingConfig {
+    #[serde(default)]
+    pub pooling_mode_cls_token: bool,
+    #[serde(default)]
+    pub pooling_mode_mean_tokens: bool,
+}
+
+impl PoolingConfig {
+    pub fn pooling(&self) -> Result<Pooling> {
+        match (self.pooling_mode_cls_token, self.pooling_mode_mean_tokens) {
+            (true, false) => Ok(Pooling::Cls),
+            (false, true) => Ok(Pooling::Mean),
+            _ => candle_core::bail!("unsupported pooling configuration {self:?}"),
+        }
+    }
+}
+
+/// Model families with their pooling and the prefixes expected on the input text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingModelKind {
+    Bge,
+    E5,
+    Gte,
+}
+
+impl EmbeddingModelKind {
+    pub fn pooling(&self) -> Pooling {
+        match self {
+            Self::Bge => Pooling::Cls,
+            Self::E5 | Self::Gte => Pooling::Mean,
+        }
+    }
+
+    /// Prefix of search queries. BGE uses it for short queries against passages only.
+    pub fn query_prefix(&self) -> &'static str {
+        match self {
+            Self::Bge => "Represent this sentence for searching relevant passages: ",
+            Self::E5 => "query: ",
+            Self::Gte => "",
+        }
+    }
+
+    /// Prefix of the indexed passages.
+    pub fn passage_prefix(&self) -> &'static str {
+        match self {
+            Self::Bge | Self::Gte => "",
+            Self::E5 => "passage: ",
+        }
+    }
+}
+
+/// Pool `(batch, seq_len, hidden)` token states into `(batch, hidden)`. `attention_mask` has
+/// shape `(batch, seq_len)` and is 1 for tokens and 0 for padding.
+pub fn pool(
+    hidden_states: &Tensor,
+    attention_mask: Option<&Tensor>,
+    pooling: Pooling,
+) -> Result<Tensor> {
+    match pooling {
+        Pooling::Cls => hidden_states.i((.., 0)),
+        Pooling::Mean => match attention_mask {
+            Some(attention_mask) => {
+                let mask = attention_mask
+                    .to_dtype(hidden_states.dtype())?
+                    .unsqueeze(2)?;
+                let sum = hidden_states.broadcast_mul(&mask)?.sum(1)?;
+                let count = mask.sum(1)?.clamp(1e-9, f64::MAX)?;
+                sum.broadcast_div(&count)
+            }
+            None => hidden_states.mean(1),
+        },
+    }
+}
+
+pub fn normalize_l2(v: &Tensor) -> Result<Tensor> {
+    v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)
+}
+
+pub struct TextEmbeddingModel {
+    model: BertModel,
+    pooling: Pooling,
+    normalize: bool,
+}
+
+impl TextEmbeddingModel {
+    /// Load a text embedding model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. With `normalize`, embeddings are L2 normalized
+    /// so that dot products are cosine similarities.
+    pub fn new(
+        vb: VarBuilder,
+        config: &Config,
+        pooling: Pooling,
+        normalize: bool,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        let model = BertModel::load(vb, config, merge, lora_config)?;
+        Ok(Self {
+            model,
+            pooling,
+            normalize,
+        })
+    }
+
+    /// Embed a padded batch of `(batch, seq_len)` token ids into `(batch, hidden)` embeddings.
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let hidden_states =
+            self.model
+                .forward_with_mask(input_ids, token_type_ids, attention_mask)?;
+        let embeddings = pool(&hidden_states, attention_mask, self.pooling)?;
+        if self.normalize {
+            normalize_l2(&embeddings)
+        } else {
+            Ok(embeddings)
+        }
+    }
+
+    pub fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.model.device
+    }
+}
+
+/// Cosine similarities between `(n, hidden)` queries and `(m, hidden)` passages, as `(n, m)`.
+pub fn cosine_similarities(queries: &Tensor, passages: &Tensor) -> Result<Tensor> {
+    let queries = normalize_l2(&queries.to_dtype(DType::F32)?)?;
+    let passages = normalize_l2(&passages.to_dtype(DType::F32)?)?;
+    queries.matmul(&passages.t()?)
+}
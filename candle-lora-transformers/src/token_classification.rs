@@ -0,0 +1,130 @@
+//! Token classification, e.g. named entity recognition, with a BERT encoder carrying the LoRA
+//! layers and a linear classification head.
+//!
+//! The head is a module to save: it is trained in full along with the adapters and saved with
+//! them, see [`MODULES_TO_SAVE`] and [`crate::varbuilder_utils::trainable_vars`]. When the
+//! checkpoint has no head, as for a base BERT model, the head is initialized by the VarBuilder.
+
+use candle_core::{DType, Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{linear, Dropout, Linear, VarBuilder};
+use tokenizers::Encoding;
+
+use crate::bert::{BertModel, Config};
+
+/// Prefixes of the variables trained in full and saved with the adapter, as PEFT
+/// `modules_to_save` for `TaskType.TOKEN_CLS`.
+pub const MODULES_TO_SAVE: &[&str] = &["classifier"];
+
+pub struct BertForTokenClassification {
+    bert: BertModel,
+    dropout: Dropout,
+    classifier: Linear,
+}
+
+impl BertForTokenClassification {
+    /// Load a token classifier which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. `dropout` is applied to the hidden states
+    /// before the head when training.
+    pub fn new(
+        vb: VarBuilder,
+        config: &Config,
+        num_labels: usize,
+        dropout: f32,
+        merge: bool,
+        lora_config: LoraConfig,
+    ) -> Result<Self> {
+        // Token classification checkpoints nest the encoder under `bert.`, select it explicitly
+        // as a VarBuilder over a VarMap creates missing tensors instead of failing.
+        let bert_vb = if vb.contains_tensor("bert.embeddings.word_embeddings.weight") {
+            vb.pp("bert")
+        } else {
+            vb.clone()
+        };
+        let bert = BertModel::load(bert_vb, config, merge, lora_config)?;
+        let classifier = linear(config.hidden_size(), num_labels, vb.pp("classifier"))?;
+        Ok(Self {
+            bert,
+            dropout: Dropout::new(dropout),
+            classifier,
+        })
+    }
+
+    pub fn device(&self) -> &candle_core::Device {
+        &self.bert.device
+    }
+
+    /// Logits `(batch, seq_len, num_labels)` of each token of a padded batch.
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+        train: bool,
+    ) -> Result<Tensor> {
+        let hidden_states =
+            self.bert
+                .forward_with_mask(input_ids, token_type_ids, attention_mask)?;
+        let hidden_states = self.dropout.forward(&hidden_states, train)?;
+        self.classifier.forward(&hidden_states)
+    }
+
+    /// The most likely label `(batch, seq_len)` of each token.
+    pub fn predict(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, token_type_ids, attention_mask, false)?
+            .argmax(D::Minus1)
+    }
+}
+
+/// Mean cross-entropy of the `(batch, seq_len, num_labels)` `logits` against the `(batch,
+/// seq_len)` `labels`, over the tokens where `loss_mask` is non-zero.
+pub fn token_classification_loss(
+    logits: &Tensor,
+    labels: &Tensor,
+    loss_mask: &Tensor,
+) -> Result<Tensor> {
+    let log_probs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+    let labels = labels.to_dtype(DType::U32)?.contiguous()?;
+    let nll = log_probs
+        .gather(&labels.unsqueeze(2)?, 2)?
+        .squeeze(2)?
+        .neg()?;
+    let mask = loss_mask.to_dtype(DType::F32)?;
+    let count = mask.sum_all()?.clamp(1f32, f32::MAX)?;
+    (nll * mask)?.sum_all()? / count
+}
+
+/// Spread word-level labels over the tokens of `encoding`: the first token of each word gets the
+/// label of the word and is counted in the loss, the other tokens of the word and the special
+/// tokens get label 0 and are masked out. Returns the labels and the loss mask of the tokens.
+pub fn align_word_labels(encoding: &Encoding, word_labels: &[u32]) -> (Vec<u32>, Vec<u8>) {
+    let mut labels = Vec::with_capacity(encoding.len());
+    let mut loss_mask = Vec::with_capacity(encoding.len());
+    let mut previous_word = None;
+    for &word in encoding.get_word_ids() {
+        match word {
+            Some(word) if Some(word) != previous_word => match word_labels.get(word as usize) {
+                Some(&label) => {
+                    labels.push(label);
+                    loss_mask.push(1);
+                }
+                None => {
+                    labels.push(0);
+                    loss_mask.push(0);
+                }
+            },
+            _ => {
+                labels.push(0);
+                loss_mask.push(0);
+            }
+        }
+        previous_word = word;
+    }
+    (labels, loss_mask)
+}
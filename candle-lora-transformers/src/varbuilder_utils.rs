@@ -18,6 +18,19 @@ pub fn from_mmaped_safetensors<'a, P: AsRef<Path>>(
     device: &Device,
     silent: bool,
 ) -> Result<VarBuilderArgs<'a, Box<dyn SimpleBackend>>, Error> {
+    let map = varmap_from_mmaped_safetensors(paths, dtype, device, silent)?;
+    Ok(VarBuilder::from_varmap(&map, dtype, device))
+}
+
+/// Load tensors into a VarMap using MmapedSafetensors, e.g. to build a VarBuilder for training
+/// and select the variables to train with [`trainable_vars`] once the model is created.
+/// Set `silent` to not show a progress bar.
+pub fn varmap_from_mmaped_safetensors<P: AsRef<Path>>(
+    paths: &[P],
+    dtype: DType,
+    device: &Device,
+    silent: bool,
+) -> Result<VarMap, Error> {
     let map = VarMap::new();
     {
         let mut ws = map.data().lock().unwrap();
@@ -43,7 +56,26 @@ pub fn from_mmaped_safetensors<'a, P: AsRef<Path>>(
         };
     }
 
-    Ok(VarBuilder::from_varmap(&map, dtype, device))
+    Ok(map)
+}
+
+/// The variables of `varmap` to train and save with the adapter, sorted by name: the LoRA
+/// weights, whose names have a `lora` component, and the modules to save, e.g. a freshly
+/// initialized classification head, whose names start with one of `modules_to_save`.
+pub fn trainable_vars(varmap: &VarMap, modules_to_save: &[&str]) -> Vec<(String, Var)> {
+    let data = varmap.data().lock().unwrap();
+    let mut vars = data
+        .iter()
+        .filter(|(name, _)| {
+            name.split('.').any(|part| part.contains("lora"))
+                || modules_to_save
+                    .iter()
+                    .any(|module| name.starts_with(&format!("{module}.")))
+        })
+        .map(|(name, var)| (name.clone(), var.clone()))
+        .collect::<Vec<_>>();
+    vars.sort_by(|u, v| u.0.cmp(&v.0));
+    vars
 }
 
 /// Load tensors into a VarBuilder backed by a VarMap using NpzTensors.
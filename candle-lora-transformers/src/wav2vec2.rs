@@ -0,0 +1,485 @@
+//! Wav2Vec2 and HuBERT, https://huggingface.co/docs/transformers/model_doc/wav2vec2
+//!
+//! The convolutional feature encoder and positional convolution are frozen, while the attention
+//! and feed-forward projections of the transformer encoder carry LoRA layers. HuBERT shares the
+//! architecture and is selected by `model_type`, which is also the prefix of the weights.
+
+use candle_core::{Module, Result, Tensor, D};
+use candle_lora::LoraConfig;
+use candle_nn::{Activation, Conv1d, Conv1dConfig, LayerNorm, Linear, VarBuilder};
+use serde::Deserialize;
+
+use crate::with_tracing::{linear, TracedLoraLinear};
+
+fn default_model_type() -> String {
+    "wav2vec2".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatExtractNorm {
+    Group,
+    Layer,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_model_type")]
+    pub model_type: String,
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub intermediate_size: usize,
+    pub hidden_act: Activation,
+    pub layer_norm_eps: f64,
+    pub feat_extract_norm: FeatExtractNorm,
+    pub feat_extract_activation: Activation,
+    pub conv_dim: Vec<usize>,
+    pub conv_kernel: Vec<usize>,
+    pub conv_stride: Vec<usize>,
+    #[serde(default)]
+    pub conv_bias: bool,
+    pub num_conv_pos_embeddings: usize,
+    pub num_conv_pos_embedding_groups: usize,
+    #[serde(default)]
+    pub do_stable_layer_norm: bool,
+    #[serde(default = "default_true")]
+    pub feat_proj_layer_norm: bool,
+}
+
+impl Config {
+    // https://huggingface.co/facebook/wav2vec2-base-960h/blob/main/config.json
+    pub fn wav2vec2_base_960h() -> Self {
+        Self {
+            model_type: default_model_type(),
+            vocab_size: 32,
+            hidden_size: 768,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            intermediate_size: 3072,
+            hidden_act: Activation::Gelu,
+            layer_norm_eps: 1e-5,
+            feat_extract_norm: FeatExtractNorm::Group,
+            feat_extract_activation: Activation::Gelu,
+            conv_dim: vec![512; 7],
+            conv_kernel: vec![10, 3, 3, 3, 3, 2, 2],
+            conv_stride: vec![5, 2, 2, 2, 2, 2, 2],
+            conv_bias: false,
+            num_conv_pos_embeddings: 128,
+            num_conv_pos_embedding_groups: 16,
+            do_stable_layer_norm: false,
+            feat_proj_layer_norm: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ConvNorm {
+    Group(candle_nn::GroupNorm),
+    /// Layer norm over the channels of a (b_sz, channels, time) input.
+    Layer(LayerNorm),
+}
+
+impl Module for ConvNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Group(norm) => xs.apply(norm),
+            Self::Layer(norm) => xs.transpose(1, 2)?.apply(norm)?.transpose(1, 2),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FeatureEncoder {
+    conv_layers: Vec<(Conv1d, Option<ConvNorm>)>,
+    activation: Activation,
+}
+
+impl FeatureEncoder {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb = vb.pp("conv_layers");
+        let mut conv_layers = Vec::with_capacity(cfg.conv_dim.len());
+        let mut in_channels = 1;
+        for (idx, &out_channels) in cfg.conv_dim.iter().enumerate() {
+            let vb = vb.pp(idx);
+            let conv_cfg = Conv1dConfig {
+                stride: cfg.conv_stride[idx],
+                ..Default::default()
+            };
+            let kernel = cfg.conv_kernel[idx];
+            let conv = if cfg.conv_bias {
+                candle_nn::conv1d(in_channels, out_channels, kernel, conv_cfg, vb.pp("conv"))?
+            } else {
+                candle_nn::conv1d_no_bias(
+                    in_channels,
+                    out_channels,
+                    kernel,
+                    conv_cfg,
+                    vb.pp("conv"),
+                )?
+            };
+            let norm = match cfg.feat_extract_norm {
+                FeatExtractNorm::Group if idx == 0 => Some(ConvNorm::Group(candle_nn::group_norm(
+                    out_channels,
+                    out_channels,
+                    1e-5,
+                    vb.pp("layer_norm"),
+                )?)),
+                FeatExtractNorm::Group => None,
+                FeatExtractNorm::Layer => Some(ConvNorm::Layer(candle_nn::layer_norm(
+                    out_channels,
+                    1e-5,
+                    vb.pp("layer_norm"),
+                )?)),
+            };
+            conv_layers.push((conv, norm));
+            in_channels = out_channels;
+        }
+        Ok(Self {
+            conv_layers,
+            activation: cfg.feat_extract_activation,
+        })
+    }
+}
+
+impl Module for FeatureEncoder {
+    /// Maps a (b_sz, samples) waveform to (b_sz, frames, conv_dim) features.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.unsqueeze(1)?;
+        for (conv, norm) in self.conv_layers.iter() {
+            xs = xs.apply(conv)?;
+            if let Some(norm) = norm {
+                xs = xs.apply(norm)?;
+            }
+            xs = xs.apply(&self.activation)?;
+        }
+        xs.transpose(1, 2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FeatureProjection {
+    layer_norm: Option<LayerNorm>,
+    projection: Linear,
+}
+
+impl FeatureProjection {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let conv_dim = *cfg.conv_dim.last().expect("conv_dim must not be empty");
+        let layer_norm = if cfg.feat_proj_layer_norm {
+            Some(candle_nn::layer_norm(
+                conv_dim,
+                cfg.layer_norm_eps,
+                vb.pp("layer_norm"),
+            )?)
+        } else {
+            None
+        };
+        let projection = candle_nn::linear(conv_dim, cfg.hidden_size, vb.pp("projection"))?;
+        Ok(Self {
+            layer_norm,
+            projection,
+        })
+    }
+}
+
+impl Module for FeatureProjection {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = match &self.layer_norm {
+            Some(layer_norm) => xs.apply(layer_norm)?,
+            None => xs.clone(),
+        };
+        xs.apply(&self.projection)
+    }
+}
+
+/// Grouped convolution over time whose weight is stored weight normalized over the kernel
+/// dimension, either as `weight_g`/`weight_v` or as `parametrizations.weight.original{0,1}`.
+#[derive(Debug, Clone)]
+struct PositionalConvEmbedding {
+    conv: Conv1d,
+    remove_last: bool,
+    activation: Activation,
+}
+
+impl PositionalConvEmbedding {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb = vb.pp("conv");
+        let kernel = cfg.num_conv_pos_embeddings;
+        let groups = cfg.num_conv_pos_embedding_groups;
+        let v_shape = (cfg.hidden_size, cfg.hidden_size / groups, kernel);
+        let (weight_g, weight_v) = if vb.contains_tensor("weight_g") {
+            (
+                vb.get((1, 1, kernel), "weight_g")?,
+                vb.get(v_shape, "weight_v")?,
+            )
+        } else {
+            let vb = vb.pp("parametrizations").pp("weight");
+            (
+                vb.get((1, 1, kernel), "original0")?,
+                vb.get(v_shape, "original1")?,
+            )
+        };
+        let norm = weight_v.sqr()?.sum_keepdim(0)?.sum_keepdim(1)?.sqrt()?;
+        let weight = weight_v.broadcast_mul(&weight_g.broadcast_div(&norm)?)?;
+        let bias = vb.get(cfg.hidden_size, "bias")?;
+        let conv_cfg = Conv1dConfig {
+            padding: kernel / 2,
+            groups,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv: Conv1d::new(weight, Some(bias), conv_cfg),
+            remove_last: kernel.is_multiple_of(2),
+            activation: cfg.feat_extract_activation,
+        })
+    }
+}
+
+impl Module for PositionalConvEmbedding {
+    /// Maps (b_sz, frames, hidden) to (b_sz, frames, hidden).
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.transpose(1, 2)?.apply(&self.conv)?;
+        let xs = if self.remove_last {
+            xs.narrow(D::Minus1, 0, xs.dim(D::Minus1)? - 1)?
+        } else {
+            xs
+        };
+        xs.apply(&self.activation)?.transpose(1, 2)
+    }
+}
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: TracedLoraLinear,
+    k_proj: TracedLoraLinear,
+    v_proj: TracedLoraLinear,
+    out_proj: TracedLoraLinear,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let hidden_size = cfg.hidden_size;
+        let proj = |name: &str, lora_config: LoraConfig| {
+            linear(hidden_size, hidden_size, vb.pp(name), merge, lora_config)
+        };
+        Ok(Self {
+            q_proj: proj("q_proj", lora_config.clone())?,
+            k_proj: proj("k_proj", lora_config.clone())?,
+            v_proj: proj("v_proj", lora_config.clone())?,
+            out_proj: proj("out_proj", lora_config)?,
+            num_heads: cfg.num_attention_heads,
+            head_dim: hidden_size / cfg.num_attention_heads,
+        })
+    }
+}
+
+impl Module for Attention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = xs.dims3()?;
+        let shape = |xs: Tensor| {
+            xs.reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let q = shape((xs.apply(&self.q_proj)? * scale)?)?;
+        let k = shape(xs.apply(&self.k_proj)?)?;
+        let v = shape(xs.apply(&self.v_proj)?)?;
+        let attn_weights = candle_nn::ops::softmax_last_dim(&q.matmul(&k.t()?)?)?;
+        attn_weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, hidden_size))?
+            .apply(&self.out_proj)
+    }
+}
+
+#[derive(Debug)]
+struct FeedForward {
+    intermediate_dense: TracedLoraLinear,
+    output_dense: TracedLoraLinear,
+    activation: Activation,
+}
+
+impl FeedForward {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let intermediate_dense = linear(
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            vb.pp("intermediate_dense"),
+            merge,
+            lora_config.clone(),
+        )?;
+        let output_dense = linear(
+            cfg.intermediate_size,
+            cfg.hidden_size,
+            vb.pp("output_dense"),
+            merge,
+            lora_config,
+        )?;
+        Ok(Self {
+            intermediate_dense,
+            output_dense,
+            activation: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.intermediate_dense)?
+            .apply(&self.activation)?
+            .apply(&self.output_dense)
+    }
+}
+
+#[derive(Debug)]
+struct EncoderLayer {
+    attention: Attention,
+    layer_norm: LayerNorm,
+    feed_forward: FeedForward,
+    final_layer_norm: LayerNorm,
+    pre_norm: bool,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let attention = Attention::new(cfg, vb.pp("attention"), merge, lora_config.clone())?;
+        let layer_norm =
+            candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm"))?;
+        let feed_forward = FeedForward::new(cfg, vb.pp("feed_forward"), merge, lora_config)?;
+        let final_layer_norm = candle_nn::layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_eps,
+            vb.pp("final_layer_norm"),
+        )?;
+        Ok(Self {
+            attention,
+            layer_norm,
+            feed_forward,
+            final_layer_norm,
+            pre_norm: cfg.do_stable_layer_norm,
+        })
+    }
+}
+
+impl Module for EncoderLayer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        if self.pre_norm {
+            let xs = (xs.apply(&self.layer_norm)?.apply(&self.attention)? + xs)?;
+            xs.apply(&self.final_layer_norm)?
+                .apply(&self.feed_forward)?
+                + xs
+        } else {
+            let xs = (xs.apply(&self.attention)? + xs)?.apply(&self.layer_norm)?;
+            (xs.apply(&self.feed_forward)? + xs)?.apply(&self.final_layer_norm)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Encoder {
+    pos_conv_embed: PositionalConvEmbedding,
+    layer_norm: LayerNorm,
+    layers: Vec<EncoderLayer>,
+    pre_norm: bool,
+}
+
+impl Encoder {
+    fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let pos_conv_embed = PositionalConvEmbedding::new(cfg, vb.pp("pos_conv_embed"))?;
+        let layer_norm =
+            candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm"))?;
+        let vb_l = vb.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|idx| EncoderLayer::new(cfg, vb_l.pp(idx), merge, lora_config.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            pos_conv_embed,
+            layer_norm,
+            layers,
+            pre_norm: cfg.do_stable_layer_norm,
+        })
+    }
+}
+
+impl Module for Encoder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = (xs.apply(&self.pos_conv_embed)? + xs)?;
+        if !self.pre_norm {
+            xs = xs.apply(&self.layer_norm)?;
+        }
+        for layer in self.layers.iter() {
+            xs = xs.apply(layer)?;
+        }
+        if self.pre_norm {
+            xs = xs.apply(&self.layer_norm)?;
+        }
+        Ok(xs)
+    }
+}
+
+#[derive(Debug)]
+pub struct Wav2Vec2 {
+    feature_extractor: FeatureEncoder,
+    feature_projection: FeatureProjection,
+    encoder: Encoder,
+}
+
+impl Wav2Vec2 {
+    /// Load a Wav2Vec2 or HuBERT encoder which will be converted to a LoRA model. `vb` points at
+    /// the encoder weights, e.g. `wav2vec2` or `hubert` in a CTC checkpoint.
+    ///
+    /// The `merge` parameter merges the weights.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let feature_extractor = FeatureEncoder::new(cfg, vb.pp("feature_extractor"))?;
+        let feature_projection = FeatureProjection::new(cfg, vb.pp("feature_projection"))?;
+        let encoder = Encoder::new(cfg, vb.pp("encoder"), merge, lora_config)?;
+        Ok(Self {
+            feature_extractor,
+            feature_projection,
+            encoder,
+        })
+    }
+}
+
+impl Module for Wav2Vec2 {
+    /// Maps a (b_sz, samples) 16kHz waveform to (b_sz, frames, hidden_size) hidden states.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.feature_extractor)?
+            .apply(&self.feature_projection)?
+            .apply(&self.encoder)
+    }
+}
+
+#[derive(Debug)]
+pub struct Wav2Vec2ForCtc {
+    model: Wav2Vec2,
+    lm_head: Linear,
+}
+
+impl Wav2Vec2ForCtc {
+    /// Load a Wav2Vec2 or HuBERT CTC model which will be converted to a LoRA model.
+    ///
+    /// The `merge` parameter merges the weights. The CTC head is not adapted.
+    pub fn new(cfg: &Config, vb: VarBuilder, merge: bool, lora_config: LoraConfig) -> Result<Self> {
+        let model = Wav2Vec2::new(cfg, vb.pp(&cfg.model_type), merge, lora_config)?;
+        let lm_head = candle_nn::linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
+        Ok(Self { model, lm_head })
+    }
+}
+
+impl Module for Wav2Vec2ForCtc {
+    /// Maps a (b_sz, samples) 16kHz waveform to (b_sz, frames, vocab_size) CTC logits.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.model)?.apply(&self.lm_head)
+    }
+}
@@ -8,8 +8,8 @@ use candle_lora_macro::{replace_layer_fields, AutoLoraConvert};
 use candle_nn::{Conv2d, VarBuilder};
 use std::sync::Arc;
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 pub struct TracedLoraEmbedding {
     inner: Embedding,
     span: tracing::Span,
@@ -40,7 +40,7 @@ impl TracedLoraEmbedding {
                 None,
                 None,
                 Some(embed_config),
-            );
+            )?;
         } else {
             this.get_lora_model(
                 lora_config,
@@ -49,7 +49,7 @@ impl TracedLoraEmbedding {
                 None,
                 None,
                 Some(embed_config),
-            );
+            )?;
         }
 
         Ok(this)
@@ -67,8 +67,8 @@ impl Module for TracedLoraEmbedding {
     }
 }
 
-#[derive(Debug, AutoLoraConvert)]
 #[replace_layer_fields]
+#[derive(Debug, AutoLoraConvert)]
 pub struct TracedLoraLinear {
     inner: Linear,
     span: tracing::Span,
@@ -81,9 +81,10 @@ impl TracedLoraLinear {
         vb: VarBuilder,
         merge: bool,
         lora_config: LoraConfig,
-    ) -> Self {
-        let linear_config =
-            LoraLinearConfig::new(weights.dims2().unwrap().0, weights.dims2().unwrap().1);
+    ) -> Result<Self> {
+        // Linear weights are stored as (out_features, in_features).
+        let (out_features, in_features) = weights.dims2()?;
+        let linear_config = LoraLinearConfig::new(in_features, out_features);
         let inner = candle_nn::Linear::new(weights, bias);
         let span = tracing::span!(tracing::Level::TRACE, "linear");
         let mut this = Self {
@@ -98,7 +99,7 @@ impl TracedLoraLinear {
                 None,
                 None,
                 None,
-            );
+            )?;
         } else {
             this.get_lora_model(
                 lora_config,
@@ -107,9 +108,9 @@ impl TracedLoraLinear {
                 None,
                 None,
                 None,
-            );
+            )?;
         }
-        this
+        Ok(this)
     }
 }
 
@@ -135,7 +136,7 @@ pub fn linear(
             None,
             None,
             None,
-        );
+        )?;
     } else {
         this.get_lora_model(
             lora_config,
@@ -144,7 +145,7 @@ pub fn linear(
             None,
             None,
             None,
-        );
+        )?;
     }
     Ok(this)
 }
@@ -171,7 +172,7 @@ pub fn linear_no_bias(
             None,
             None,
             None,
-        );
+        )?;
     } else {
         this.get_lora_model(
             lora_config,
@@ -180,7 +181,7 @@ pub fn linear_no_bias(
             None,
             None,
             None,
-        );
+        )?;
     }
     Ok(this)
 }
@@ -0,0 +1,92 @@
+mod common;
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{LoraConfig, TracedArchitecture};
+use candle_lora_transformers::{
+    baichuan::{Baichuan, Config, PositionEmbedding},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+use candle_nn::{VarBuilder, VarMap};
+use common::{PeftRoundTrip, ALPHA, RANK};
+
+const MODULES: [&str; 5] = [
+    "self_attn.W_pack",
+    "self_attn.o_proj",
+    "mlp.gate_proj",
+    "mlp.up_proj",
+    "mlp.down_proj",
+];
+
+fn config(position_embedding: PositionEmbedding) -> Config {
+    let mut cfg: Config = serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "hidden_act": "silu",
+            "max_position_embeddings": 16, "model_max_length": null, "rms_norm_eps": 1e-6,
+            "norm_head": true}"#,
+    )
+    .unwrap();
+    cfg.position_embedding = Some(position_embedding);
+    cfg
+}
+
+fn forward(model: &mut Baichuan) -> Result<Tensor> {
+    model.forward(&Tensor::new(&[[1u32, 5, 7, 3]], &Device::Cpu)?, 0)
+}
+
+#[test]
+fn baichuan_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    for position_embedding in [PositionEmbedding::Rope, PositionEmbedding::Alibi] {
+        let cfg = config(position_embedding);
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let mut model = Baichuan::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+
+        // The packed `W_pack` is split into q, k and v adapters.
+        let projections = [
+            "self_attn.q_proj",
+            "self_attn.k_proj",
+            "self_attn.v_proj",
+            "self_attn.o_proj",
+            "mlp.gate_proj",
+            "mlp.up_proj",
+            "mlp.down_proj",
+        ];
+        common::assert_traced(
+            &varmap,
+            (0..cfg.num_hidden_layers).flat_map(|layer| {
+                projections.map(|projection| format!("model.layers.{layer}.{projection}"))
+            }),
+        );
+
+        let logits = forward(&mut model)?;
+        assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+        let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+        assert!(logits.iter().all(|logit| logit.is_finite()));
+    }
+    Ok(())
+}
+
+#[test]
+fn converted_peft_w_pack_adapter_matches_merged_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config(PositionEmbedding::Rope);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    Baichuan::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+    let base = common::random_base_weights(&varmap, &device)?;
+
+    let modules: Vec<_> = (0..cfg.num_hidden_layers)
+        .flat_map(|layer| MODULES.map(|module| format!("model.layers.{layer}.{module}")))
+        .collect();
+    let dir = std::env::temp_dir().join("candle_lora_baichuan_w_pack_adapter");
+    let round_trip =
+        PeftRoundTrip::save(&dir, &base, &modules, TracedArchitecture::Baichuan, &device)?;
+    round_trip.check(|paths: &[&Path], merge| {
+        let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+        let mut model = Baichuan::new(&cfg, vb, merge, LoraConfig::new(RANK, ALPHA, None))?;
+        forward(&mut model)
+    })
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::{
+    bert::{BertModel, Config},
+    text_embedding::{Pooling, TextEmbeddingModel},
+    token_classification::{
+        token_classification_loss, BertForTokenClassification, MODULES_TO_SAVE,
+    },
+    varbuilder_utils::{from_mmaped_safetensors, trainable_vars, varmap_from_mmaped_safetensors},
+};
+use candle_nn::{VarBuilder, VarMap};
+
+const NUM_LAYERS: usize = 2;
+
+const LINEARS: [&str; 6] = [
+    "attention.self.query",
+    "attention.self.key",
+    "attention.self.value",
+    "attention.output.dense",
+    "intermediate.dense",
+    "output.dense",
+];
+
+fn config() -> Config {
+    serde_json::from_str(&format!(
+        r#"{{"vocab_size": 32, "hidden_size": 16, "num_hidden_layers": {NUM_LAYERS},
+            "num_attention_heads": 2, "intermediate_size": 32, "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0, "max_position_embeddings": 16, "type_vocab_size": 2,
+            "initializer_range": 0.02, "layer_norm_eps": 1e-12, "pad_token_id": 0}}"#
+    ))
+    .unwrap()
+}
+
+fn var_names(varmap: &VarMap) -> Vec<String> {
+    varmap.data().lock().unwrap().keys().cloned().collect()
+}
+
+fn lora_config() -> LoraConfig {
+    LoraConfig::new(2, 4., None)
+}
+
+/// Save random base weights for `config()` to `dir/model.safetensors`, and an adapter of the
+/// query and value projections with a non-zero B to `dir/adapter.safetensors`.
+fn save_model_and_adapter(dir: &Path) -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    BertModel::load(vb, &config(), false, lora_config())?;
+
+    let mut base = HashMap::new();
+    let mut adapter = HashMap::new();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        let tensor = Tensor::randn(0f32, 0.5, var.shape(), &device)?;
+        if !name.contains("lora") {
+            base.insert(name.clone(), tensor);
+        } else if name.contains("self.query") || name.contains("self.value") {
+            adapter.insert(name.clone(), tensor);
+        }
+    }
+    std::fs::create_dir_all(dir)?;
+    candle_core::safetensors::save(&base, dir.join("model.safetensors"))?;
+    candle_core::safetensors::save(&adapter, dir.join("adapter.safetensors"))
+}
+
+fn embed(paths: &[&Path], merge: bool) -> Result<Tensor> {
+    let device = Device::Cpu;
+    let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+    let model = TextEmbeddingModel::new(vb, &config(), Pooling::Mean, true, merge, lora_config())?;
+    let input_ids = Tensor::new(&[[2u32, 7, 9, 4], [3, 5, 0, 0]], &device)?;
+    let attention_mask = Tensor::new(&[[1u32, 1, 1, 1], [1, 1, 0, 0]], &device)?;
+    model.forward(&input_ids, &input_ids.zeros_like()?, Some(&attention_mask))
+}
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+}
+
+#[test]
+fn every_encoder_layer_has_lora_linears() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    BertModel::load(vb, &config(), false, lora_config())?;
+
+    let names = var_names(&varmap);
+    for layer in 0..NUM_LAYERS {
+        for linear in LINEARS {
+            let prefix = format!("encoder.layer.{layer}.{linear}.lora_linear.");
+            assert!(
+                names.iter().any(|name| name.starts_with(&prefix)),
+                "no adapter under {prefix}"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn query_value_adapter_changes_text_embeddings() -> Result<()> {
+    let dir = std::env::temp_dir().join("candle_lora_text_embedding_adapter");
+    save_model_and_adapter(&dir)?;
+    let model = dir.join("model.safetensors");
+    let adapter = dir.join("adapter.safetensors");
+
+    let base = embed(&[&model], false)?;
+    let adapted = embed(&[&model, &adapter], false)?;
+    assert_eq!(adapted.dims(), [2, 16]);
+    assert!(max_abs_diff(&base, &adapted)? > 1e-3);
+    // Merging the adapter into the base weights gives the same embeddings.
+    let merged = embed(&[&model, &adapter], true)?;
+    assert!(max_abs_diff(&merged, &adapted)? < 1e-4);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn token_classifier_trains_the_encoder_adapters() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join("candle_lora_token_classification");
+    save_model_and_adapter(&dir)?;
+    let varmap = varmap_from_mmaped_safetensors(
+        &[dir.join("model.safetensors")],
+        DType::F32,
+        &device,
+        true,
+    )?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model = BertForTokenClassification::new(vb, &config(), 3, 0., false, lora_config())?;
+
+    let trainable = trainable_vars(&varmap, MODULES_TO_SAVE);
+    for layer in 0..NUM_LAYERS {
+        for linear in LINEARS {
+            let prefix = format!("encoder.layer.{layer}.{linear}.lora_linear.");
+            assert!(
+                trainable.iter().any(|(name, _)| name.starts_with(&prefix)),
+                "{prefix} is not trained"
+            );
+        }
+    }
+
+    let input_ids = Tensor::new(&[[2u32, 7, 9, 4]], &device)?;
+    let logits = model.forward(&input_ids, &input_ids.zeros_like()?, None, true)?;
+    let labels = Tensor::new(&[[0u32, 1, 2, 0]], &device)?;
+    let loss = token_classification_loss(&logits, &labels, &labels.ones_like()?)?;
+    let grads = loss.backward()?;
+    // While B is zero the gradients of A vanish, those of B do not.
+    for (name, var) in &trainable {
+        if name.starts_with("encoder.") && name.ends_with(".b0.weight") {
+            let grad = grads.get(var).expect(name);
+            let norm = grad.sqr()?.sum_all()?.to_scalar::<f32>()?;
+            assert!(norm > 0., "{name} has a zero gradient");
+        }
+    }
+    assert!(grads
+        .get(&varmap.data().lock().unwrap()["classifier.weight"])
+        .is_some());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{LoraConfig, TracedArchitecture};
+use candle_lora_transformers::{
+    bloom::{Bloom, Config},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+use candle_nn::{VarBuilder, VarMap};
+use common::{PeftRoundTrip, ALPHA, RANK};
+
+const MODULES: [&str; 4] = [
+    "self_attention.query_key_value",
+    "self_attention.dense",
+    "mlp.dense_h_to_4h",
+    "mlp.dense_4h_to_h",
+];
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{"vocab_size": 32, "n_embed": 16, "n_layer": 2, "n_head": 2,
+            "layer_norm_epsilon": 1e-5}"#,
+    )
+    .unwrap()
+}
+
+fn forward(model: &mut Bloom) -> Result<Tensor> {
+    model.forward(&Tensor::new(&[[1u32, 5, 7, 3]], &Device::Cpu)?, 0)
+}
+
+#[test]
+fn bloom_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    // Without `transformer.word_embeddings.weight`, the bigscience layout without the prefix.
+    let mut model = Bloom::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+
+    // The fused `query_key_value` is split into q, k and v adapters.
+    let projections = [
+        "self_attention.q_proj",
+        "self_attention.k_proj",
+        "self_attention.v_proj",
+        "self_attention.dense",
+        "mlp.dense_h_to_4h",
+        "mlp.dense_4h_to_h",
+    ];
+    common::assert_traced(
+        &varmap,
+        (0..cfg.num_hidden_layers)
+            .flat_map(|layer| projections.map(|projection| format!("h.{layer}.{projection}"))),
+    );
+
+    let logits = forward(&mut model)?;
+    assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
+
+#[test]
+fn converted_peft_query_key_value_adapter_matches_merged_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    Bloom::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+    // The `BloomForCausalLM` layout, which PEFT adapters are saved from.
+    let base = common::random_base_weights(&varmap, &device)?
+        .into_iter()
+        .map(|(name, tensor)| (format!("transformer.{name}"), tensor))
+        .collect();
+
+    let modules: Vec<_> = (0..cfg.num_hidden_layers)
+        .flat_map(|layer| MODULES.map(|module| format!("transformer.h.{layer}.{module}")))
+        .collect();
+    let arch = TracedArchitecture::Bloom {
+        num_attention_heads: cfg.num_attention_heads,
+    };
+    let dir = std::env::temp_dir().join("candle_lora_bloom_query_key_value_adapter");
+    let round_trip = PeftRoundTrip::save(&dir, &base, &modules, arch, &device)?;
+    round_trip.check(|paths: &[&Path], merge| {
+        let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+        let mut model = Bloom::new(&cfg, vb, merge, LoraConfig::new(RANK, ALPHA, None))?;
+        forward(&mut model)
+    })
+}
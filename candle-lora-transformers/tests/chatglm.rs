@@ -0,0 +1,89 @@
+mod common;
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{LoraConfig, TracedArchitecture};
+use candle_lora_transformers::{
+    chatglm::{ChatGLM, Config},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+use candle_nn::{VarBuilder, VarMap};
+use common::{PeftRoundTrip, ALPHA, RANK};
+
+const MODULES: [&str; 4] = [
+    "self_attention.query_key_value",
+    "self_attention.dense",
+    "mlp.dense_h_to_4h",
+    "mlp.dense_4h_to_h",
+];
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{"num_layers": 2, "padded_vocab_size": 32, "hidden_size": 16,
+            "ffn_hidden_size": 32, "kv_channels": 8, "num_attention_heads": 2,
+            "seq_length": 16, "layernorm_epsilon": 1e-5, "rmsnorm": true,
+            "apply_residual_connection_post_layernorm": false, "post_layer_norm": true,
+            "add_bias_linear": false, "add_qkv_bias": true, "multi_query_attention": true,
+            "multi_query_group_num": 1, "apply_query_key_layer_scaling": true,
+            "fp32_residual_connection": false}"#,
+    )
+    .unwrap()
+}
+
+fn modules(cfg: &Config) -> Vec<String> {
+    (0..cfg.num_layers)
+        .flat_map(|layer| {
+            MODULES.map(|module| format!("transformer.encoder.layers.{layer}.{module}"))
+        })
+        .collect()
+}
+
+fn forward(model: &mut ChatGLM) -> Result<Tensor> {
+    model.forward(&Tensor::new(&[[1u32, 5, 7, 3]], &Device::Cpu)?)
+}
+
+#[test]
+fn chatglm_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = ChatGLM::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+
+    // The packed projections keep a single adapter each.
+    common::assert_traced(&varmap, modules(&cfg));
+    assert!(!common::var_names(&varmap)
+        .iter()
+        .any(|name| name.contains("output_layer") && name.contains("lora")));
+
+    let logits = forward(&mut model)?;
+    assert_eq!(logits.dims(), [1, cfg.padded_vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
+
+#[test]
+fn converted_peft_query_key_value_adapter_matches_merged_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    ChatGLM::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+    let base = common::random_base_weights(&varmap, &device)?;
+
+    let dir = std::env::temp_dir().join("candle_lora_chatglm_query_key_value_adapter");
+    let round_trip = PeftRoundTrip::save(
+        &dir,
+        &base,
+        &modules(&cfg),
+        TracedArchitecture::ChatGLM,
+        &device,
+    )?;
+    round_trip.check(|paths: &[&Path], merge| {
+        let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+        let mut model = ChatGLM::new(&cfg, vb, merge, LoraConfig::new(RANK, ALPHA, None))?;
+        forward(&mut model)
+    })
+}
@@ -0,0 +1,133 @@
+//! Helpers shared by the model tests, each of which uses some of them.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{Device, Result, Tensor};
+use candle_lora::{convert_peft_to_candle_lora_traced, TracedArchitecture, TRACED_LORA_LINEAR};
+use candle_nn::VarMap;
+
+pub const RANK: usize = 2;
+pub const ALPHA: f64 = 4.;
+
+/// The names of the variables of `varmap`.
+pub fn var_names(varmap: &VarMap) -> Vec<String> {
+    varmap.data().lock().unwrap().keys().cloned().collect()
+}
+
+/// Assert that each of `modules` holds a traced LoRA layer.
+pub fn assert_traced<S: AsRef<str>>(varmap: &VarMap, modules: impl IntoIterator<Item = S>) {
+    let vars = varmap.data().lock().unwrap();
+    for module in modules {
+        for ab in ["a0", "b0"] {
+            let name = format!("{}.{TRACED_LORA_LINEAR}.{ab}.weight", module.as_ref());
+            assert!(vars.contains_key(&name), "missing {name}");
+        }
+    }
+}
+
+/// Random base weights for the variables of `varmap` a model was loaded from, its adapters
+/// excluded.
+pub fn random_base_weights(varmap: &VarMap, device: &Device) -> Result<HashMap<String, Tensor>> {
+    let mut base = HashMap::new();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        if !name.contains("lora") {
+            let tensor = Tensor::randn(0f32, 0.3, var.shape(), device)?;
+            base.insert(name.clone(), tensor);
+        }
+    }
+    Ok(base)
+}
+
+/// Replace the base weights of `varmap` with random ones, its adapters excluded, for the models
+/// whose layers are computed from weights that are not usable when zero.
+pub fn randomize_base_weights(varmap: &VarMap) -> Result<()> {
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        if !name.contains("lora") {
+            var.set(&Tensor::randn(0f32, 0.3, var.shape(), var.device())?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The largest absolute difference between the elements of `a` and `b`.
+pub fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+}
+
+/// The files of a PEFT adapter converted for a traced model, see [`PeftRoundTrip::save`].
+pub struct PeftRoundTrip {
+    pub dir: PathBuf,
+    /// The base weights.
+    pub base: PathBuf,
+    /// The base weights with the adapter merged in.
+    pub merged: PathBuf,
+    /// The adapter converted by `convert_peft_to_candle_lora_traced`.
+    pub converted: PathBuf,
+}
+
+impl PeftRoundTrip {
+    /// Save `base`, a random PEFT adapter of `modules` of `RANK` and `ALPHA`, the base weights
+    /// with it merged in, and the adapter converted for `arch` to `dir`.
+    ///
+    /// `modules` are the PEFT module names, whose weights are `<module>.weight` in `base`.
+    pub fn save(
+        dir: &Path,
+        base: &HashMap<String, Tensor>,
+        modules: &[String],
+        arch: TracedArchitecture,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut peft = HashMap::new();
+        let mut merged = base.clone();
+        for module in modules {
+            let weight_name = format!("{module}.weight");
+            let (out_features, in_features) = base[&weight_name].dims2()?;
+            let lora_a = Tensor::randn(0f32, 0.3, (RANK, in_features), device)?;
+            let lora_b = Tensor::randn(0f32, 0.3, (out_features, RANK), device)?;
+            let delta = (lora_b.matmul(&lora_a)? * (ALPHA / RANK as f64))?;
+            let weight = merged.remove(&weight_name).unwrap();
+            merged.insert(weight_name, (weight + delta)?);
+            peft.insert(format!("base_model.model.{module}.lora_A.weight"), lora_a);
+            peft.insert(format!("base_model.model.{module}.lora_B.weight"), lora_b);
+        }
+
+        std::fs::create_dir_all(dir)?;
+        let this = Self {
+            dir: dir.to_path_buf(),
+            base: dir.join("model.safetensors"),
+            merged: dir.join("merged.safetensors"),
+            converted: dir.join("converted.safetensors"),
+        };
+        let peft_path = dir.join("adapter_model.safetensors");
+        candle_core::safetensors::save(base, &this.base)?;
+        candle_core::safetensors::save(&merged, &this.merged)?;
+        candle_core::safetensors::save(&peft, &peft_path)?;
+        convert_peft_to_candle_lora_traced(
+            peft_path.to_str().unwrap(),
+            this.converted.to_str().unwrap(),
+            arch,
+            device,
+        )
+        .unwrap();
+        Ok(this)
+    }
+
+    /// Check that `forward`, loading the model from the given files and merging the adapter or
+    /// not, gives the outputs of the merged weights with the converted adapter.
+    pub fn check(&self, forward: impl Fn(&[&Path], bool) -> Result<Tensor>) -> Result<()> {
+        let expected = forward(&[&self.merged], false)?;
+        let plain = forward(&[&self.base], false)?;
+        assert!(max_abs_diff(&plain, &expected)? > 1e-3);
+        for merge in [false, true] {
+            let adapted = forward(&[&self.base, &self.converted], merge)?;
+            let diff = max_abs_diff(&adapted, &expected)?;
+            assert!(diff < 1e-4, "merge {merge}: {diff}");
+        }
+        std::fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
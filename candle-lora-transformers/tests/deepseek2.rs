@@ -0,0 +1,60 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::deepseek2::{DeepSeekV2, DeepSeekV2Config};
+use candle_nn::{VarBuilder, VarMap};
+
+fn config(q_lora_rank: Option<usize>) -> DeepSeekV2Config {
+    let mut cfg: DeepSeekV2Config = serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "moe_intermediate_size": 8, "num_hidden_layers": 2, "num_attention_heads": 2,
+            "n_shared_experts": 1, "n_routed_experts": 4, "num_experts_per_tok": 2,
+            "first_k_dense_replace": 1, "max_position_embeddings": 16, "rms_norm_eps": 1e-6,
+            "rope_theta": 10000.0, "rope_scaling": null, "q_lora_rank": null,
+            "qk_rope_head_dim": 4, "kv_lora_rank": 8, "v_head_dim": 4,
+            "qk_nope_head_dim": 4}"#,
+    )
+    .unwrap();
+    cfg.q_lora_rank = q_lora_rank;
+    cfg
+}
+
+#[test]
+fn deepseek2_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    for (q_lora_rank, q_projections) in [
+        (None, vec!["q_proj"]),
+        (Some(8), vec!["q_a_proj", "q_b_proj"]),
+    ] {
+        let cfg = config(q_lora_rank);
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let mut model = DeepSeekV2::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+        let projections = [
+            q_projections,
+            vec!["kv_a_proj_with_mqa", "kv_b_proj", "o_proj"],
+        ];
+        common::assert_traced(
+            &varmap,
+            (0..cfg.num_hidden_layers).flat_map(|layer| {
+                projections
+                    .concat()
+                    .into_iter()
+                    .map(move |projection| format!("model.layers.{layer}.self_attn.{projection}"))
+            }),
+        );
+        // Only the attention projections are adapted, not the dense or expert MLPs.
+        assert!(!common::var_names(&varmap)
+            .iter()
+            .any(|name| name.contains(".mlp.") && name.contains("lora")));
+
+        let input = Tensor::new(&[[1u32, 5, 7, 3]], &device)?;
+        let logits = model.forward(&input, 0)?;
+        assert_eq!(logits.dims(), [1, cfg.vocab_size]);
+        let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+        assert!(logits.iter().all(|logit| logit.is_finite()));
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::dinov2::DinoVisionTransformer;
+use candle_nn::{VarBuilder, VarMap};
+
+const DEPTH: usize = 2;
+
+#[test]
+fn dinov2_backbone_has_lora_adapters() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let backbone =
+        DinoVisionTransformer::new_backbone(vb, DEPTH, 16, 2, false, LoraConfig::new(2, 4., None))?;
+
+    let names = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    for block in 0..DEPTH {
+        for module in [
+            "attn.lora_qkv",
+            "attn.lora_proj",
+            "mlp.lora_fc1",
+            "mlp.lora_fc2",
+        ] {
+            let prefix = format!("blocks.{block}.{module}.");
+            assert!(
+                names.iter().any(|name| name.starts_with(&prefix)),
+                "no adapter under {prefix}"
+            );
+        }
+    }
+    assert!(names
+        .iter()
+        .any(|name| name.starts_with("patch_embed.lora_proj.")));
+
+    // B starts at zero, so the adapters are a no-op until they are trained.
+    let xs = Tensor::randn(0f32, 1., (1, 3, 28, 28), &device)?;
+    let before = backbone.forward(&xs)?;
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        if name.ends_with(".b0.weight") {
+            var.set(&Tensor::randn(0f32, 1., var.shape(), &device)?)?;
+        }
+    }
+    let after = backbone.forward(&xs)?;
+    assert_eq!(after.dims(), [1, 32]);
+    let diff = (after - before)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff > 1e-3, "{diff}");
+    Ok(())
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{LoraConfig, TracedArchitecture};
+use candle_lora_transformers::{
+    gpt_neox::{Config, GptNeoX},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+use candle_nn::{VarBuilder, VarMap};
+use common::{PeftRoundTrip, ALPHA, RANK};
+
+const MODULES: [&str; 4] = [
+    "attention.query_key_value",
+    "attention.dense",
+    "mlp.dense_h_to_4h",
+    "mlp.dense_4h_to_h",
+];
+
+fn config(use_parallel_residual: bool) -> Config {
+    let mut cfg: Config = serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "hidden_act": "gelu",
+            "rotary_pct": 0.5, "rotary_emb_base": 10000.0, "max_position_embeddings": 16,
+            "layer_norm_eps": 1e-5}"#,
+    )
+    .unwrap();
+    cfg.use_parallel_residual = use_parallel_residual;
+    cfg
+}
+
+fn modules(cfg: &Config) -> Vec<String> {
+    (0..cfg.num_hidden_layers)
+        .flat_map(|layer| MODULES.map(|module| format!("gpt_neox.layers.{layer}.{module}")))
+        .collect()
+}
+
+fn forward(model: &mut GptNeoX) -> Result<Tensor> {
+    model.forward(&Tensor::new(&[[1u32, 5, 7, 3]], &Device::Cpu)?, 0)
+}
+
+#[test]
+fn gpt_neox_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    for use_parallel_residual in [true, false] {
+        let cfg = config(use_parallel_residual);
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let mut model = GptNeoX::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+
+        // The fused `query_key_value` keeps a single adapter.
+        common::assert_traced(&varmap, modules(&cfg));
+        assert!(!common::var_names(&varmap)
+            .iter()
+            .any(|name| name.starts_with("embed_out") && name.contains("lora")));
+
+        let logits = forward(&mut model)?;
+        assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+        let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+        assert!(logits.iter().all(|logit| logit.is_finite()));
+    }
+    Ok(())
+}
+
+#[test]
+fn converted_peft_query_key_value_adapter_matches_merged_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config(true);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    GptNeoX::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+    let base = common::random_base_weights(&varmap, &device)?;
+
+    let dir = std::env::temp_dir().join("candle_lora_gpt_neox_query_key_value_adapter");
+    let round_trip = PeftRoundTrip::save(
+        &dir,
+        &base,
+        &modules(&cfg),
+        TracedArchitecture::GptNeoX,
+        &device,
+    )?;
+    round_trip.check(|paths: &[&Path], merge| {
+        let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+        let mut model = GptNeoX::new(&cfg, vb, merge, LoraConfig::new(RANK, ALPHA, None))?;
+        forward(&mut model)
+    })
+}
@@ -0,0 +1,57 @@
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::granite::{Cache, Granite, GraniteConfig};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 7] = [
+    "self_attn.q_proj",
+    "self_attn.k_proj",
+    "self_attn.v_proj",
+    "self_attn.o_proj",
+    "mlp.gate_proj",
+    "mlp.up_proj",
+    "mlp.down_proj",
+];
+
+fn config() -> GraniteConfig {
+    serde_json::from_str(
+        r#"{"hidden_size": 16, "intermediate_size": 32, "vocab_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+            "rms_norm_eps": 1e-5, "max_position_embeddings": 16,
+            "embedding_multiplier": 2.0, "attention_multiplier": 0.25,
+            "residual_multiplier": 0.5, "logits_scaling": 4.0}"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn granite_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config().into_config(false);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model = Granite::load(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    let vars = varmap.data().lock().unwrap();
+    for layer in 0..cfg.num_hidden_layers {
+        for projection in PROJECTIONS {
+            for ab in ["a0", "b0"] {
+                let name =
+                    format!("model.layers.{layer}.{projection}.traced_lora_linear.{ab}.weight");
+                assert!(vars.contains_key(&name), "missing {name}");
+            }
+        }
+    }
+    assert!(vars.contains_key("model.embed_tokens.traced_lora_embed.a0.weight"));
+    // The tied output projection has no adapter of its own.
+    assert!(!vars.keys().any(|name| name.starts_with("lm_head")));
+    drop(vars);
+
+    let mut cache = Cache::new(false, DType::F32, &cfg, &device)?;
+    let input = Tensor::new(&[[1u32, 5, 7, 3]], &device)?;
+    let logits = model.forward(&input, 0, &mut cache)?;
+    assert_eq!(logits.dims(), [1, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
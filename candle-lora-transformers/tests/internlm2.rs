@@ -0,0 +1,94 @@
+mod common;
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{LoraConfig, TracedArchitecture};
+use candle_lora_transformers::{
+    internlm2::{Config, InternLM2},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+use candle_nn::{VarBuilder, VarMap};
+use common::{PeftRoundTrip, ALPHA, RANK};
+
+const MODULES: [&str; 5] = [
+    "attention.wqkv",
+    "attention.wo",
+    "feed_forward.w1",
+    "feed_forward.w2",
+    "feed_forward.w3",
+];
+
+fn config() -> Config {
+    // Grouped-query attention, so that the split of `wqkv` is not the one of equal thirds.
+    serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 16, "rms_norm_eps": 1e-5}"#,
+    )
+    .unwrap()
+}
+
+fn forward(model: &mut InternLM2) -> Result<Tensor> {
+    model.forward(&Tensor::new(&[[1u32, 5, 7, 3]], &Device::Cpu)?, 0)
+}
+
+#[test]
+fn internlm2_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = InternLM2::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+
+    // The packed `wqkv` is split into q, k and v adapters.
+    let projections = [
+        "attention.q_proj",
+        "attention.k_proj",
+        "attention.v_proj",
+        "attention.wo",
+        "feed_forward.w1",
+        "feed_forward.w2",
+        "feed_forward.w3",
+    ];
+    common::assert_traced(
+        &varmap,
+        (0..cfg.num_hidden_layers)
+            .flat_map(|layer| {
+                projections.map(|projection| format!("model.layers.{layer}.{projection}"))
+            })
+            .chain(["output".to_string()]),
+    );
+
+    let logits = forward(&mut model)?;
+    assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
+
+#[test]
+fn converted_peft_wqkv_adapter_matches_merged_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    InternLM2::new(&cfg, vb, false, LoraConfig::new(RANK, ALPHA, None))?;
+    let base = common::random_base_weights(&varmap, &device)?;
+
+    let modules: Vec<_> = (0..cfg.num_hidden_layers)
+        .flat_map(|layer| MODULES.map(|module| format!("model.layers.{layer}.{module}")))
+        .chain(["output".to_string()])
+        .collect();
+    let arch = TracedArchitecture::InternLM2 {
+        num_attention_heads: cfg.num_attention_heads,
+        num_key_value_heads: cfg.num_key_value_heads,
+    };
+    let dir = std::env::temp_dir().join("candle_lora_internlm2_wqkv_adapter");
+    let round_trip = PeftRoundTrip::save(&dir, &base, &modules, arch, &device)?;
+    round_trip.check(|paths: &[&Path], merge| {
+        let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+        let mut model = InternLM2::new(&cfg, vb, merge, LoraConfig::new(RANK, ALPHA, None))?;
+        forward(&mut model)
+    })
+}
@@ -0,0 +1,164 @@
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    checkpointed_backward, convert_peft_to_candle_lora_typed, BlockFn, GradientCheckpointing,
+    LoraConfig, LoraLinearConfig,
+};
+use candle_lora_transformers::{
+    llama::{Cache, Config, Llama, LlamaConfig},
+    varbuilder_utils::from_mmaped_safetensors,
+};
+use candle_nn::{VarBuilder, VarMap};
+
+const RANK: usize = 2;
+const ALPHA: f64 = 4.;
+const TARGETS: [&str; 2] = ["gate_proj", "down_proj"];
+
+fn config() -> Config {
+    // The vocabulary is as large as the hidden states, as the LoRA config of the linear layers
+    // is shared by the attention projections and the output head.
+    let config: LlamaConfig = serde_json::from_str(
+        r#"{"hidden_size": 16, "intermediate_size": 32, "vocab_size": 16,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 16}"#,
+    )
+    .unwrap();
+    config.into_config(false)
+}
+
+fn load(paths: &[&Path], merge: bool, target_modules: &[String]) -> Result<Tensor> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let vb = from_mmaped_safetensors(paths, DType::F32, &device, true)?;
+    let cache = Cache::new(false, DType::F32, &cfg, &device)?;
+    let model = Llama::load_with_targets(
+        vb,
+        &cache,
+        &cfg,
+        merge,
+        LoraConfig::new(RANK, ALPHA, None),
+        LoraLinearConfig::new(cfg.hidden_size, cfg.hidden_size),
+        None,
+        target_modules,
+    )?;
+    model.forward(&Tensor::new(&[[1u32, 4, 9, 2, 7]], &device)?, 0)
+}
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+}
+
+#[test]
+fn converted_peft_mlp_adapter_matches_merged_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let dir = std::env::temp_dir().join("candle_lora_llama_mlp_adapter");
+    std::fs::create_dir_all(&dir)?;
+
+    // Random base weights, the names being those the model reads.
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let cache = Cache::new(false, DType::F32, &cfg, &device)?;
+    Llama::load(
+        vb,
+        &cache,
+        &cfg,
+        false,
+        LoraConfig::new(RANK, ALPHA, None),
+        LoraLinearConfig::new(cfg.hidden_size, cfg.hidden_size),
+        None,
+    )?;
+    let mut base = HashMap::new();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        if !name.contains("lora") {
+            base.insert(
+                name.clone(),
+                Tensor::randn(0f32, 0.3, var.shape(), &device)?,
+            );
+        }
+    }
+
+    // A PEFT adapter of some MLP projections, and the base weights with it merged in.
+    let mut peft = HashMap::new();
+    let mut merged = base.clone();
+    for layer in 0..cfg.num_hidden_layers {
+        for module in TARGETS {
+            let weight_name = format!("model.layers.{layer}.mlp.{module}.weight");
+            let (out_features, in_features) = base[&weight_name].dims2()?;
+            let lora_a = Tensor::randn(0f32, 0.3, (RANK, in_features), &device)?;
+            let lora_b = Tensor::randn(0f32, 0.3, (out_features, RANK), &device)?;
+            let delta = (lora_b.matmul(&lora_a)? * (ALPHA / RANK as f64))?;
+            let weight = merged.remove(&weight_name).unwrap();
+            merged.insert(weight_name, (weight + delta)?);
+            let peft_name = format!("base_model.model.model.layers.{layer}.mlp.{module}");
+            peft.insert(format!("{peft_name}.lora_A.weight"), lora_a);
+            peft.insert(format!("{peft_name}.lora_B.weight"), lora_b);
+        }
+    }
+    let base_path = dir.join("model.safetensors");
+    let merged_path = dir.join("merged.safetensors");
+    let peft_path = dir.join("adapter_model.safetensors");
+    let converted_path = dir.join("converted.safetensors");
+    candle_core::safetensors::save(&base, &base_path)?;
+    candle_core::safetensors::save(&merged, &merged_path)?;
+    candle_core::safetensors::save(&peft, &peft_path)?;
+    convert_peft_to_candle_lora_typed(
+        peft_path.to_str().unwrap(),
+        converted_path.to_str().unwrap(),
+        &device,
+        None,
+    )
+    .unwrap();
+
+    let targets = TARGETS.map(String::from);
+    let expected = load(&[&merged_path], false, &[])?;
+    let plain = load(&[&base_path], false, &[])?;
+    assert!(max_abs_diff(&plain, &expected)? > 1e-3);
+    for merge in [false, true] {
+        let adapted = load(&[&base_path, &converted_path], merge, &targets)?;
+        let diff = max_abs_diff(&adapted, &expected)?;
+        assert!(diff < 1e-4, "merge {merge}: {diff}");
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn gradient_checkpointing_needs_the_kv_cache_off() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    for use_kv_cache in [false, true] {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let cache = Cache::new(use_kv_cache, DType::F32, &cfg, &device)?;
+        let model = Llama::load(
+            vb,
+            &cache,
+            &cfg,
+            false,
+            LoraConfig::new(RANK, ALPHA, None),
+            LoraLinearConfig::new(cfg.hidden_size, cfg.hidden_size),
+            None,
+        )?;
+
+        let input = model.embed(&Tensor::new(&[[1u32, 4, 9, 2]], &device)?)?;
+        let mut fns = (0..model.num_blocks())
+            .map(|block_idx| {
+                let model = &model;
+                move |x: &Tensor| model.forward_block(block_idx, x, 0)
+            })
+            .collect::<Vec<_>>();
+        let mut blocks = fns.iter_mut().map(|f| f as BlockFn).collect::<Vec<_>>();
+        let backward = checkpointed_backward(
+            &input,
+            &mut blocks,
+            &GradientCheckpointing::default(),
+            &varmap.all_vars(),
+            |hidden| hidden.sqr()?.mean_all(),
+        );
+        assert_eq!(backward.is_err(), use_kv_cache);
+    }
+    Ok(())
+}
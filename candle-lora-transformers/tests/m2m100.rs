@@ -0,0 +1,61 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::m2m100::{Config, MTModel};
+use candle_nn::{VarBuilder, VarMap};
+
+const ATTENTION: [&str; 4] = ["q_proj", "k_proj", "v_proj", "out_proj"];
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{"vocab_size": 32, "max_position_embeddings": 16, "encoder_layers": 2,
+            "encoder_ffn_dim": 32, "encoder_attention_heads": 2, "decoder_layers": 2,
+            "decoder_ffn_dim": 32, "decoder_attention_heads": 2, "activation_function": "relu",
+            "d_model": 16, "decoder_start_token_id": 2, "scale_embedding": true,
+            "pad_token_id": 1, "eos_token_id": 2}"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn m2m100_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = MTModel::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    let encoder = (0..cfg.encoder_layers).flat_map(|layer| {
+        let prefix = format!("model.encoder.layers.{layer}");
+        ATTENTION
+            .map(|projection| format!("{prefix}.self_attn.{projection}"))
+            .into_iter()
+            .chain(["fc1", "fc2"].map(|fc| format!("{prefix}.{fc}")))
+    });
+    let decoder = (0..cfg.decoder_layers).flat_map(|layer| {
+        let prefix = format!("model.decoder.layers.{layer}");
+        ["self_attn", "encoder_attn"]
+            .into_iter()
+            .flat_map(move |attention| {
+                ATTENTION.map(|projection| format!("{attention}.{projection}"))
+            })
+            .chain(["fc1".to_string(), "fc2".to_string()])
+            .map(move |module| format!("{prefix}.{module}"))
+    });
+    common::assert_traced(&varmap, encoder.chain(decoder));
+    // The shared embeddings and the tied output head are not adapted.
+    assert!(!common::var_names(&varmap)
+        .iter()
+        .any(|name| name.contains("shared") && name.contains("lora")));
+
+    let encoder_xs = model
+        .encoder()
+        .forward(&Tensor::new(&[[4u32, 9, 7, 2]], &device)?, 0)?;
+    assert_eq!(encoder_xs.dims(), [1, 4, cfg.d_model]);
+    let logits = model.decode(&Tensor::new(&[[2u32, 5, 7]], &device)?, &encoder_xs, 0)?;
+    assert_eq!(logits.dims(), [1, 3, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
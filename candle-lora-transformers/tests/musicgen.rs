@@ -0,0 +1,71 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::musicgen::{Config, MusicgenForConditionalGeneration};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 10] = [
+    "self_attn.q_proj",
+    "self_attn.k_proj",
+    "self_attn.v_proj",
+    "self_attn.out_proj",
+    "encoder_attn.q_proj",
+    "encoder_attn.k_proj",
+    "encoder_attn.v_proj",
+    "encoder_attn.out_proj",
+    "fc1",
+    "fc2",
+];
+
+fn config() -> Config {
+    // The text encoder is narrower than the decoder, so that its states are projected.
+    serde_json::from_str(
+        r#"{"decoder": {"vocab_size": 32, "max_position_embeddings": 16,
+                        "num_hidden_layers": 2, "ffn_dim": 32, "num_attention_heads": 2,
+                        "activation_function": "gelu", "hidden_size": 16, "num_codebooks": 2,
+                        "pad_token_id": 32, "bos_token_id": 32},
+            "text_encoder": {"vocab_size": 32, "d_model": 8, "d_kv": 4, "d_ff": 16,
+                             "num_layers": 1, "num_heads": 2,
+                             "relative_attention_num_buckets": 8,
+                             "relative_attention_max_distance": 16, "dropout_rate": 0.0,
+                             "layer_norm_epsilon": 1e-6, "initializer_factor": 1.0,
+                             "feed_forward_proj": "relu", "is_encoder_decoder": true,
+                             "pad_token_id": 0, "eos_token_id": 1}}"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn musicgen_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model =
+        MusicgenForConditionalGeneration::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    common::assert_traced(
+        &varmap,
+        (0..cfg.decoder.num_hidden_layers).flat_map(|layer| {
+            PROJECTIONS
+                .map(|projection| format!("decoder.model.decoder.layers.{layer}.{projection}"))
+        }),
+    );
+    // Only the decoder layers are adapted.
+    assert!(!common::var_names(&varmap).iter().any(|name| {
+        name.contains("lora") && !name.starts_with("decoder.model.decoder.layers.")
+    }));
+
+    let encoder_xs = model.encode(&Tensor::new(&[[4u32, 9, 2, 1]], &device)?)?;
+    assert_eq!(encoder_xs.dims(), [1, 4, cfg.decoder.hidden_size]);
+    let codes = Tensor::new(&[[[32u32, 5, 7], [32, 3, 8]]], &device)?;
+    let logits = model.decoder.forward(&codes, &encoder_xs, 0)?;
+    assert_eq!(
+        logits.dims(),
+        [1, cfg.decoder.num_codebooks, 3, cfg.decoder.vocab_size]
+    );
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
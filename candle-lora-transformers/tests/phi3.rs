@@ -0,0 +1,50 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::phi3::{Config, Phi3};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 4] = [
+    "self_attn.qkv_proj",
+    "self_attn.o_proj",
+    "mlp.gate_up_proj",
+    "mlp.down_proj",
+];
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+            "hidden_act": "silu", "max_position_embeddings": 16, "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0, "rope_scaling": null, "sliding_window": 2}"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn phi3_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = Phi3::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    // The fused `qkv_proj` and `gate_up_proj` keep a single adapter each.
+    common::assert_traced(
+        &varmap,
+        (0..cfg.num_hidden_layers).flat_map(|layer| {
+            PROJECTIONS.map(|projection| format!("model.layers.{layer}.{projection}"))
+        }),
+    );
+    assert!(!common::var_names(&varmap)
+        .iter()
+        .any(|name| name.starts_with("lm_head") && name.contains("lora")));
+
+    let input = Tensor::new(&[[1u32, 5, 7, 3]], &device)?;
+    let logits = model.forward(&input, 0)?;
+    assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::qwen2::{Config, Qwen2};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 7] = [
+    "self_attn.q_proj",
+    "self_attn.k_proj",
+    "self_attn.v_proj",
+    "self_attn.o_proj",
+    "mlp.gate_proj",
+    "mlp.up_proj",
+    "mlp.down_proj",
+];
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+            "hidden_act": "silu", "max_position_embeddings": 16, "rms_norm_eps": 1e-6,
+            "rope_theta": 1000000.0, "tie_word_embeddings": true}"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn qwen2_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = Qwen2::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    common::assert_traced(
+        &varmap,
+        (0..cfg.num_hidden_layers).flat_map(|layer| {
+            PROJECTIONS.map(|projection| format!("model.layers.{layer}.{projection}"))
+        }),
+    );
+    // The tied output head has no weight, nor adapter, of its own.
+    assert!(!common::var_names(&varmap)
+        .iter()
+        .any(|name| name.starts_with("lm_head")));
+
+    let input = Tensor::new(&[[1u32, 5, 7, 3]], &device)?;
+    let logits = model.forward(&input, 0)?;
+    assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::rwkv_v5::{Config, Model, State};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 8] = [
+    "attention.key",
+    "attention.receptance",
+    "attention.value",
+    "attention.gate",
+    "attention.output",
+    "feed_forward.key",
+    "feed_forward.receptance",
+    "feed_forward.value",
+];
+
+fn config() -> Config {
+    // `num_attention_heads` is the size of the heads, as in `modeling_rwkv5.py`.
+    Config {
+        vocab_size: 32,
+        hidden_size: 16,
+        num_hidden_layers: 2,
+        attention_hidden_size: 16,
+        num_attention_heads: 8,
+        head_size: 8,
+        intermediate_size: Some(32),
+        layer_norm_epsilon: 1e-5,
+        rescale_every: 6,
+    }
+}
+
+#[test]
+fn rwkv_v5_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model = Model::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    common::assert_traced(
+        &varmap,
+        (0..cfg.num_hidden_layers).flat_map(|block| {
+            PROJECTIONS.map(|projection| format!("rwkv.blocks.{block}.{projection}"))
+        }),
+    );
+
+    let mut state = State::new(1, &cfg, &device)?;
+    let logits = model.forward(&Tensor::new(&[[1u32, 5, 7]], &device)?, &mut state)?;
+    assert_eq!(logits.dims(), [1, 3, cfg.vocab_size]);
+    // The recurrent state carries over to the next token.
+    let logits = model.forward(&Tensor::new(&[[3u32]], &device)?, &mut state)?;
+    assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
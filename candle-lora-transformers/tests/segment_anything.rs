@@ -0,0 +1,40 @@
+mod common;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::segment_anything::{Config, ImageEncoderViT, IMAGE_SIZE};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 4] = ["attn.qkv", "attn.proj", "mlp.lin1", "mlp.lin2"];
+
+#[test]
+fn sam_image_encoder_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    // A windowed and a global attention block.
+    let cfg = Config {
+        embed_dim: 8,
+        depth: 2,
+        num_heads: 2,
+        global_attn_indexes: vec![1],
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model = ImageEncoderViT::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+    common::assert_traced(
+        &varmap,
+        (0..cfg.depth)
+            .flat_map(|block| PROJECTIONS.map(|projection| format!("blocks.{block}.{projection}"))),
+    );
+    // The patch embedding and the neck are not adapted.
+    assert!(!common::var_names(&varmap)
+        .iter()
+        .any(|name| name.contains("lora") && !name.starts_with("blocks.")));
+
+    let image = Tensor::randn(0f32, 1., (1, 3, IMAGE_SIZE, IMAGE_SIZE), &device)?;
+    let embeddings = model.forward(&image)?;
+    assert_eq!(embeddings.dims(), [1, 256, 64, 64]);
+    let embeddings = embeddings.flatten_all()?.to_vec1::<f32>()?;
+    assert!(embeddings.iter().all(|x| x.is_finite()));
+    Ok(())
+}
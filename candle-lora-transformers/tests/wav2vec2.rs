@@ -0,0 +1,64 @@
+mod common;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::wav2vec2::{Config, Wav2Vec2ForCtc};
+use candle_nn::{VarBuilder, VarMap};
+
+const PROJECTIONS: [&str; 6] = [
+    "attention.q_proj",
+    "attention.k_proj",
+    "attention.v_proj",
+    "attention.out_proj",
+    "feed_forward.intermediate_dense",
+    "feed_forward.output_dense",
+];
+
+fn config(model_type: &str) -> Config {
+    let mut cfg: Config = serde_json::from_str(
+        r#"{"vocab_size": 32, "hidden_size": 16, "num_hidden_layers": 2,
+            "num_attention_heads": 2, "intermediate_size": 32, "hidden_act": "gelu",
+            "layer_norm_eps": 1e-5, "feat_extract_norm": "group",
+            "feat_extract_activation": "gelu", "conv_dim": [8, 8], "conv_kernel": [10, 3],
+            "conv_stride": [5, 2], "num_conv_pos_embeddings": 4,
+            "num_conv_pos_embedding_groups": 2}"#,
+    )
+    .unwrap();
+    cfg.model_type = model_type.to_string();
+    cfg
+}
+
+#[test]
+fn wav2vec2_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    for model_type in ["wav2vec2", "hubert"] {
+        let cfg = config(model_type);
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        Wav2Vec2ForCtc::new(&cfg, vb.clone(), false, LoraConfig::new(2, 4., None))?;
+        // The weight normalized positional convolution divides by the norm of its weight.
+        common::randomize_base_weights(&varmap)?;
+        let model = Wav2Vec2ForCtc::new(&cfg, vb, false, LoraConfig::new(2, 4., None))?;
+
+        common::assert_traced(
+            &varmap,
+            (0..cfg.num_hidden_layers).flat_map(|layer| {
+                PROJECTIONS
+                    .map(|projection| format!("{model_type}.encoder.layers.{layer}.{projection}"))
+            }),
+        );
+        // Only the transformer encoder is adapted.
+        assert!(!common::var_names(&varmap).iter().any(|name| {
+            name.contains("lora")
+                && (name.starts_with("lm_head") || name.contains("feature_projection"))
+        }));
+
+        // 400 samples make (400 - 10) / 5 + 1 = 79 and then (79 - 3) / 2 + 1 = 39 frames.
+        let waveform = Tensor::randn(0f32, 1., (1, 400), &device)?;
+        let logits = model.forward(&waveform)?;
+        assert_eq!(logits.dims(), [1, 39, cfg.vocab_size]);
+        let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+        assert!(logits.iter().all(|logit| logit.is_finite()));
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::LoraConfig;
+use candle_lora_transformers::whisper::{Config, Whisper};
+use candle_nn::{VarBuilder, VarMap};
+
+const ATTENTION: [&str; 4] = ["q_proj", "k_proj", "v_proj", "out_proj"];
+
+fn config() -> Config {
+    serde_json::from_str(
+        r#"{"num_mel_bins": 8, "max_source_positions": 8, "d_model": 16,
+            "encoder_attention_heads": 2, "encoder_layers": 2, "vocab_size": 32,
+            "max_target_positions": 8, "decoder_attention_heads": 2, "decoder_layers": 2}"#,
+    )
+    .unwrap()
+}
+
+/// The adapted modules of the `layers` layers of `stack`, with the given attentions.
+fn modules(stack: &str, layers: usize, attentions: &'static [&'static str]) -> Vec<String> {
+    (0..layers)
+        .flat_map(|layer| {
+            let prefix = format!("model.{stack}.layers.{layer}");
+            attentions
+                .iter()
+                .flat_map(|attention| {
+                    ATTENTION.map(|projection| format!("{attention}.{projection}"))
+                })
+                .chain(["fc1".to_string(), "fc2".to_string()])
+                .map(move |module| format!("{prefix}.{module}"))
+        })
+        .collect()
+}
+
+#[test]
+fn whisper_has_traced_lora_adapters_and_runs_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut model = Whisper::load(&vb, cfg.clone(), false, LoraConfig::new(2, 4., None))?;
+
+    common::assert_traced(
+        &varmap,
+        modules("encoder", cfg.encoder_layers, &["self_attn"]),
+    );
+    common::assert_traced(
+        &varmap,
+        modules(
+            "decoder",
+            cfg.decoder_layers,
+            &["self_attn", "encoder_attn"],
+        ),
+    );
+    // The convolutions and embeddings are not adapted.
+    assert!(!common::var_names(&varmap)
+        .iter()
+        .any(|name| name.contains("lora") && !name.contains(".layers.")));
+
+    // The second convolution halves the 16 frames.
+    let mel = Tensor::randn(0f32, 1., (1, cfg.num_mel_bins, 16), &device)?;
+    let audio_features = model.encoder.forward(&mel, true)?;
+    assert_eq!(audio_features.dims(), [1, 8, cfg.d_model]);
+    let tokens = Tensor::new(&[[1u32, 5, 7]], &device)?;
+    let xs = model.decoder.forward(&tokens, &audio_features, true)?;
+    let logits = model.decoder.final_linear(&xs)?;
+    assert_eq!(logits.dims(), [1, 3, cfg.vocab_size]);
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    assert!(logits.iter().all(|logit| logit.is_finite()));
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! AdamW with 8-bit optimizer states, after https://arxiv.org/abs/2110.02861.
+//!
+//! Both moments are stored as one byte per element, quantized block-wise: each block of
+//! `block_size` elements keeps its absolute maximum in f32 and the elements are encoded
+//! relative to it with a power-law code, which keeps the precision of small values of the second
+//! moment. The update itself is computed in f32 after dequantizing the states.
+
+use candle_core::{backprop::GradStore, DType, Result, Tensor, Var};
+use candle_nn::Optimizer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamsAdam8bit {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    pub weight_decay: f64,
+    /// Number of elements sharing a quantization scale.
+    pub block_size: usize,
+    /// Variables with fewer elements keep f32 states, as the quantization overhead and error are
+    /// not worth it for them.
+    pub min_8bit_size: usize,
+}
+
+impl Default for ParamsAdam8bit {
+    fn default() -> Self {
+        Self {
+            lr: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.01,
+            block_size: 256,
+            min_8bit_size: 4096,
+        }
+    }
+}
+
+/// A tensor quantized block-wise to one byte per element.
+#[derive(Debug)]
+struct QuantizedState {
+    codes: Tensor,
+    absmax: Tensor,
+    signed: bool,
+}
+
+// Codes are `round(sign(x) * |x / absmax|^(1/2) * 127) + 127` for the signed first moment and
+// `round((x / absmax)^(1/4) * 255)` for the non-negative second moment.
+impl QuantizedState {
+    fn quantize(xs: &Tensor, block_size: usize, signed: bool) -> Result<Self> {
+        let numel = xs.elem_count();
+        let num_blocks = numel.div_ceil(block_size);
+        let xs = xs.flatten_all()?.to_dtype(DType::F32)?;
+        let xs = if num_blocks * block_size > numel {
+            let padding = Tensor::zeros(num_blocks * block_size - numel, DType::F32, xs.device())?;
+            Tensor::cat(&[&xs, &padding], 0)?
+        } else {
+            xs
+        };
+        let xs = xs.reshape((num_blocks, block_size))?;
+        let absmax = xs.abs()?.max_keepdim(1)?;
+        let normalized = xs.broadcast_div(&absmax.clamp(f32::MIN_POSITIVE, f32::MAX)?)?;
+        let codes = if signed {
+            ((normalized.sign()? * normalized.abs()?.sqrt()?)? * 127.)?.round()? + 127.
+        } else {
+            (normalized.sqrt()?.sqrt()? * 255.)?.round()
+        }?;
+        Ok(Self {
+            codes: codes.to_dtype(DType::U8)?,
+            absmax,
+            signed,
+        })
+    }
+
+    fn dequantize(&self, like: &Tensor) -> Result<Tensor> {
+        let codes = self.codes.to_dtype(DType::F32)?;
+        let normalized = if self.signed {
+            let ys = ((codes - 127.)? / 127.)?;
+            (&ys * ys.abs()?)?
+        } else {
+            (codes / 255.)?.sqr()?.sqr()?
+        };
+        normalized
+            .broadcast_mul(&self.absmax)?
+            .flatten_all()?
+            .narrow(0, 0, like.elem_count())?
+            .reshape(like.shape())
+    }
+
+    fn bytes(&self) -> usize {
+        self.codes.elem_count() + self.absmax.elem_count() * DType::F32.size_in_bytes()
+    }
+}
+
+#[derive(Debug)]
+enum Moments {
+    Full {
+        m: Tensor,
+        v: Tensor,
+    },
+    Quantized {
+        m: QuantizedState,
+        v: QuantizedState,
+    },
+}
+
+#[derive(Debug)]
+struct VarAdam8bit {
+    var: Var,
+    moments: Moments,
+}
+
+/// AdamW, with decoupled weight decay as `candle_nn::AdamW`, keeping 8-bit moments for the
+/// variables with at least `min_8bit_size` elements.
+#[derive(Debug)]
+pub struct Adam8bit {
+    vars: Vec<VarAdam8bit>,
+    step_t: usize,
+    params: ParamsAdam8bit,
+}
+
+impl Adam8bit {
+    /// Memory used by the optimizer states, in bytes.
+    pub fn state_bytes(&self) -> usize {
+        self.vars
+            .iter()
+            .map(|var| match &var.moments {
+                Moments::Full { m, v } => {
+                    (m.elem_count() + v.elem_count()) * DType::F32.size_in_bytes()
+                }
+                Moments::Quantized { m, v } => m.bytes() + v.bytes(),
+            })
+            .sum()
+    }
+}
+
+impl Optimizer for Adam8bit {
+    type Config = ParamsAdam8bit;
+
+    fn new(vars: Vec<Var>, params: ParamsAdam8bit) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let zeros = Tensor::zeros(var.shape(), DType::F32, var.device())?;
+                let moments = if var.elem_count() >= params.min_8bit_size {
+                    Moments::Quantized {
+                        m: QuantizedState::quantize(&zeros, params.block_size, true)?,
+                        v: QuantizedState::quantize(&zeros, params.block_size, false)?,
+                    }
+                } else {
+                    Moments::Full {
+                        m: zeros.clone(),
+                        v: zeros,
+                    }
+                };
+                Ok(VarAdam8bit { var, moments })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        self.step_t += 1;
+        let ParamsAdam8bit {
+            lr,
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+            block_size,
+            ..
+        } = self.params;
+        let scale_m = 1f64 / (1f64 - beta1.powi(self.step_t as i32));
+        let scale_v = 1f64 / (1f64 - beta2.powi(self.step_t as i32));
+        for var in self.vars.iter_mut() {
+            let theta = &var.var;
+            let Some(g) = grads.get(theta) else {
+                continue;
+            };
+            let g = g.to_dtype(DType::F32)?;
+            let (m, v) = match &var.moments {
+                Moments::Full { m, v } => (m.clone(), v.clone()),
+                Moments::Quantized { m, v } => (m.dequantize(&g)?, v.dequantize(&g)?),
+            };
+            let next_m = ((m * beta1)? + (&g * (1.0 - beta1))?)?;
+            let next_v = ((v * beta2)? + (g.sqr()? * (1.0 - beta2))?)?;
+            let m_hat = (&next_m * scale_m)?;
+            let v_hat = (&next_v * scale_v)?;
+            let adjusted_grad = (m_hat / (v_hat.sqrt()? + eps)?)?;
+            let next_theta =
+                (theta.as_tensor().to_dtype(DType::F32)? * (1f64 - lr * weight_decay))?;
+            let next_theta = (next_theta - (adjusted_grad * lr)?)?;
+            theta.set(&next_theta.to_dtype(theta.dtype())?)?;
+            var.moments = match &var.moments {
+                Moments::Full { .. } => Moments::Full {
+                    m: next_m.detach(),
+                    v: next_v.detach(),
+                },
+                Moments::Quantized { .. } => Moments::Quantized {
+                    m: QuantizedState::quantize(&next_m, block_size, true)?,
+                    v: QuantizedState::quantize(&next_v, block_size, false)?,
+                },
+            };
+        }
+        Ok(())
+    }
+}
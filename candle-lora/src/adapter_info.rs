@@ -0,0 +1,205 @@
+//! Inspection of adapter files: the adapted layers, their ranks and shapes, and the size of the
+//! adapter, read from the safetensors header without loading the tensors.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use candle_core::{bail, safetensors::MmapedSafetensors, DType, Result};
+use safetensors::tensor::TensorView;
+
+use crate::{
+    migration::lora_weight,
+    peft_convert::{lora_pair_names, magnitude_name},
+    structural_order,
+};
+
+/// The naming of the tensors of an adapter file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdapterFormat {
+    /// PEFT, `<module>.lora_A.weight` and `<module>.lora_B.weight`.
+    Peft,
+    /// candle-lora, `<prefix>.a{idx}.weight` and `<prefix>.b{idx}.weight`, index-based or
+    /// name-based, see [`crate::Migration`].
+    CandleLora,
+    /// Neither, no LoRA pair was found.
+    Unknown,
+}
+
+/// One adapted layer of an adapter file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerInfo {
+    /// The PEFT module, or the candle-lora prefix or module of the `a{idx}`/`b{idx}` weights.
+    pub name: String,
+    /// The candle-lora index of the weights, `None` for PEFT adapters.
+    pub index: Option<usize>,
+    pub rank: usize,
+    pub a_shape: Vec<usize>,
+    pub b_shape: Vec<usize>,
+    pub dtype: DType,
+    /// The parameters of A and B, and of the DoRA magnitude if any.
+    pub num_params: usize,
+}
+
+/// What an adapter file holds, see [`AdapterInfo::from_file`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterInfo {
+    pub format: AdapterFormat,
+    /// The adapted layers, in structural order.
+    pub layers: Vec<LayerInfo>,
+    pub num_tensors: usize,
+    /// The parameters of all the tensors, the trainable parameters of the adapter.
+    pub num_params: usize,
+    /// The bytes the tensors take once loaded in their dtype.
+    pub memory_bytes: usize,
+    /// The safetensors metadata of the file.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl AdapterInfo {
+    /// Read the adapter file `path`, a PEFT or candle-lora safetensors file. Only its header is
+    /// read, the file is memory-mapped and the tensor data is not touched.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use candle_lora::AdapterInfo;
+    ///
+    /// let info = AdapterInfo::from_file("path/to/adapter_model.safetensors").unwrap();
+    /// for layer in &info.layers {
+    ///     println!("{} rank {} {:?}", layer.name, layer.rank, layer.dtype);
+    /// }
+    /// println!("{} parameters, {} bytes", info.num_params, info.memory_bytes);
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        // Safety: the file is only read, and must not be modified while being inspected.
+        let file = unsafe { MmapedSafetensors::new(path.as_ref())? };
+        let metadata = read_metadata(path.as_ref())?;
+        let views = file.tensors().into_iter().collect::<HashMap<_, _>>();
+        let mut info = Self::from_views(&views)?;
+        info.metadata = metadata;
+        Ok(info)
+    }
+
+    fn from_views(views: &HashMap<String, TensorView<'_>>) -> Result<Self> {
+        let mut num_params = 0;
+        let mut memory_bytes = 0;
+        for view in views.values() {
+            let numel = view.shape().iter().product::<usize>();
+            num_params += numel;
+            memory_bytes += numel * view.dtype().size();
+        }
+
+        let peft_pairs = lora_pair_names(views.keys().map(String::as_str), |name| {
+            views.contains_key(name)
+        });
+        let (format, mut layers) = if !peft_pairs.is_empty() {
+            let layers = peft_pairs
+                .iter()
+                .map(|(module, a, b)| {
+                    let magnitude = magnitude_name(module, |name| views.contains_key(name));
+                    let magnitude = magnitude.map(|name| &views[&name]);
+                    layer_info(module, None, &views[a], &views[b], magnitude)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            (AdapterFormat::Peft, layers)
+        } else {
+            let mut weights = BTreeMap::<(&str, usize), [Option<&TensorView>; 3]>::new();
+            for (name, view) in views {
+                if let Some((module, kind, idx)) = lora_weight(name) {
+                    let slot = match kind {
+                        'a' => 0,
+                        'b' => 1,
+                        _ => 2,
+                    };
+                    weights.entry((module, idx)).or_default()[slot] = Some(view);
+                }
+            }
+            let layers = weights
+                .into_iter()
+                .filter_map(|((module, idx), [a, b, m])| {
+                    Some(layer_info(module, Some(idx), a?, b?, m))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let format = if layers.is_empty() {
+                AdapterFormat::Unknown
+            } else {
+                AdapterFormat::CandleLora
+            };
+            (format, layers)
+        };
+        layers
+            .sort_by(|a, b| structural_order(&a.name, &b.name).then_with(|| a.index.cmp(&b.index)));
+
+        Ok(Self {
+            format,
+            layers,
+            num_tensors: views.len(),
+            num_params,
+            memory_bytes,
+            metadata: BTreeMap::new(),
+        })
+    }
+
+    /// The ranks of the layers and how many layers have each.
+    pub fn ranks(&self) -> BTreeMap<usize, usize> {
+        let mut ranks = BTreeMap::new();
+        for layer in &self.layers {
+            *ranks.entry(layer.rank).or_default() += 1;
+        }
+        ranks
+    }
+}
+
+fn layer_info(
+    name: &str,
+    index: Option<usize>,
+    a: &TensorView,
+    b: &TensorView,
+    magnitude: Option<&TensorView>,
+) -> Result<LayerInfo> {
+    let Some(&rank) = a.shape().first() else {
+        bail!("the A matrix of {name} is a scalar")
+    };
+    let dtype = match DType::try_from(a.dtype()) {
+        Ok(dtype) => dtype,
+        Err(_) => bail!(
+            "the A matrix of {name} has unsupported dtype {:?}",
+            a.dtype()
+        ),
+    };
+    let num_params = [Some(a), Some(b), magnitude]
+        .into_iter()
+        .flatten()
+        .map(|view| view.shape().iter().product::<usize>())
+        .sum();
+    Ok(LayerInfo {
+        name: name.to_string(),
+        index,
+        rank,
+        a_shape: a.shape().to_vec(),
+        b_shape: b.shape().to_vec(),
+        dtype,
+        num_params,
+    })
+}
+
+/// The `__metadata__` of the safetensors header of `path`.
+pub(crate) fn read_metadata(path: &Path) -> Result<BTreeMap<String, String>> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let mut header = vec![0u8; u64::from_le_bytes(len) as usize];
+    file.read_exact(&mut header)?;
+    #[derive(serde::Deserialize)]
+    struct Header {
+        #[serde(rename = "__metadata__", default)]
+        metadata: BTreeMap<String, String>,
+    }
+    match serde_json::from_slice::<Header>(&header) {
+        Ok(header) => Ok(header.metadata),
+        Err(e) => bail!("invalid safetensors header in {}: {e}", path.display()),
+    }
+}
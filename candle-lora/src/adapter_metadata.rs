@@ -0,0 +1,202 @@
+//! Self-describing adapter files: the source format, hyperparameters, target modules and
+//! naming of an adapter, stored in the `__metadata__` of its safetensors header.
+//!
+//! The PEFT conversions and [`crate::Lora::save_adapter`] write an [`AdapterMetadata`] under
+//! [`ADAPTER_METADATA_KEY`], as JSON. [`load_with_metadata`] reads it back with the tensors, and
+//! [`AdapterMetadata::lora_config`] gives the [`LoraConfig`] to convert the model with, so no
+//! side-car JSON has to be shipped with the adapter.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use candle_core::{bail, Device, Result, Tensor};
+use safetensors::SafeTensors;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adapter_info::read_metadata, peft_convert::with_patterns, AdapterConfig, ConversionReport,
+    LoraConfig,
+};
+
+/// Safetensors metadata key of the [`AdapterMetadata`] of an adapter file.
+pub const ADAPTER_METADATA_KEY: &str = "candle_lora.adapter";
+
+/// What an adapter file holds and how it was made.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdapterMetadata {
+    /// The format the adapter comes from: `peft` for converted PEFT adapters, `candle-lora` for
+    /// adapters trained with candle-lora.
+    pub source_format: String,
+    /// The rank of the adapter, the most common one when the modules have different ranks.
+    pub rank: usize,
+    /// The alpha of the adapter, `None` when it is not known, e.g. converted without its
+    /// `adapter_config.json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpha: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropout: Option<f32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub use_rslora: bool,
+    /// The adapted modules, in the order of the indices of index-based names.
+    #[serde(default)]
+    pub target_modules: Vec<String>,
+    /// The ranks of the modules not of rank `rank`, by pattern, like PEFT's `rank_pattern`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rank_pattern: BTreeMap<String, usize>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub alpha_pattern: BTreeMap<String, f64>,
+    /// The naming of the tensors: `prefix:<prefix>` for the index-based
+    /// `<prefix>.a{idx}.weight` of [`crate::convert_peft_to_candle_lora`], `named` for the
+    /// `<module>.a0.weight` of [`crate::Migration::ToNamed`], `typed` for the Llama prefixes of
+    /// [`crate::convert_peft_to_candle_lora_typed`], `traced:<architecture>` for
+    /// [`crate::TracedArchitecture`] and `key_rules` for a [`crate::KeyRules`] file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+    /// The version of candle-lora that wrote the file.
+    pub candle_lora_version: String,
+}
+
+impl AdapterMetadata {
+    /// The metadata of an adapter of rank `rank` from `source_format`, written by this version
+    /// of candle-lora.
+    pub fn new(source_format: &str, rank: usize) -> Self {
+        Self {
+            source_format: source_format.to_string(),
+            rank,
+            alpha: None,
+            dropout: None,
+            use_rslora: false,
+            target_modules: Vec::new(),
+            rank_pattern: BTreeMap::new(),
+            alpha_pattern: BTreeMap::new(),
+            architecture: None,
+            candle_lora_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// The metadata of a PEFT adapter converted with `report`.
+    pub fn from_report(report: &ConversionReport) -> Self {
+        Self {
+            alpha: report.alpha,
+            dropout: report.dropout,
+            use_rslora: report.use_rslora,
+            target_modules: report.target_modules.clone(),
+            rank_pattern: report.rank_pattern.clone(),
+            alpha_pattern: report.alpha_pattern.clone(),
+            ..Self::new("peft", report.rank)
+        }
+    }
+
+    /// The metadata of an adapter trained with candle-lora and saved with `config`.
+    pub fn from_adapter_config(config: &AdapterConfig) -> Self {
+        Self {
+            alpha: Some(config.alpha),
+            dropout: config.dropout,
+            use_rslora: config.use_rslora,
+            target_modules: config.target_modules.clone(),
+            ..Self::new("candle-lora", config.rank)
+        }
+    }
+
+    pub fn with_architecture(mut self, architecture: impl Into<String>) -> Self {
+        self.architecture = Some(architecture.into());
+        self
+    }
+
+    /// The LoRA config to convert the model with, with the rank and alpha patterns, `None` when
+    /// the alpha is not known or a pattern is not a valid regular expression.
+    pub fn lora_config(&self) -> Option<LoraConfig> {
+        let config =
+            LoraConfig::new(self.rank, self.alpha?, self.dropout).with_rslora(self.use_rslora);
+        with_patterns(config, &self.rank_pattern, &self.alpha_pattern).ok()
+    }
+
+    /// The checkpoint configuration of the adapter, `None` when the alpha is not known.
+    pub(crate) fn adapter_config(&self) -> Option<AdapterConfig> {
+        Some(AdapterConfig {
+            rank: self.rank,
+            alpha: self.alpha?,
+            dropout: self.dropout,
+            target_modules: self.target_modules.clone(),
+            use_rslora: self.use_rslora,
+            step: None,
+        })
+    }
+
+    /// The metadata under [`ADAPTER_METADATA_KEY`] of the safetensors `metadata`, if any.
+    pub fn from_metadata(metadata: &BTreeMap<String, String>) -> Result<Option<Self>> {
+        metadata
+            .get(ADAPTER_METADATA_KEY)
+            .map(|value| Self::parse(value))
+            .transpose()
+    }
+
+    /// The metadata of the JSON `value` of [`ADAPTER_METADATA_KEY`].
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        match serde_json::from_str(value) {
+            Ok(metadata) => Ok(metadata),
+            Err(e) => bail!("invalid {ADAPTER_METADATA_KEY} metadata: {e}"),
+        }
+    }
+
+    /// The metadata of the adapter file `path`, if any. Only the header is read.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        Self::from_metadata(&read_metadata(path.as_ref())?)
+    }
+
+    /// The `(key, value)` of the metadata, to write in a safetensors header.
+    pub(crate) fn entry(&self) -> (String, String) {
+        let value = serde_json::to_string(self).expect("the metadata is serializable");
+        (ADAPTER_METADATA_KEY.to_string(), value)
+    }
+
+    /// Add the metadata to an existing adapter file, keeping its other metadata.
+    pub fn stamp<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+        let (_, header) = SafeTensors::read_metadata(&data)?;
+        let mut metadata = header.metadata().clone().unwrap_or_default();
+        metadata.extend([self.entry()]);
+        let tensors = candle_core::safetensors::load_buffer(&data, &Device::Cpu)?;
+        safetensors::serialize_to_file(tensors.iter(), &Some(metadata), path)?;
+        Ok(())
+    }
+}
+
+/// Save adapter tensors with their `metadata`.
+pub fn save_with_metadata<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    metadata: &AdapterMetadata,
+    path: P,
+) -> Result<()> {
+    let metadata = HashMap::from([metadata.entry()]);
+    safetensors::serialize_to_file(tensors.iter(), &Some(metadata), path.as_ref())?;
+    Ok(())
+}
+
+/// Load an adapter file with its metadata, if any.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::load_with_metadata;
+///
+/// let (tensors, metadata) = load_with_metadata("path/to/converted.safetensors", &Device::Cpu)?;
+/// let lora_config = metadata.and_then(|metadata| metadata.lora_config());
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub fn load_with_metadata<P: AsRef<Path>>(
+    path: P,
+    device: &Device,
+) -> Result<(HashMap<String, Tensor>, Option<AdapterMetadata>)> {
+    let data = std::fs::read(path)?;
+    let (_, header) = SafeTensors::read_metadata(&data)?;
+    let metadata = header.metadata().clone().unwrap_or_default();
+    let metadata = AdapterMetadata::from_metadata(&metadata.into_iter().collect())?;
+    Ok((
+        candle_core::safetensors::load_buffer(&data, device)?,
+        metadata,
+    ))
+}
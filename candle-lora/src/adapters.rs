@@ -0,0 +1,83 @@
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+    static ADAPTERS_DISABLED: Cell<bool> = const { Cell::new(false) };
+    static BATCH_ADAPTERS: RefCell<Option<Vec<u32>>> = const { RefCell::new(None) };
+}
+
+/// Whether the LoRA layers apply their adapters on the current thread.
+pub fn adapters_enabled() -> bool {
+    !ADAPTERS_DISABLED.with(Cell::get)
+}
+
+/// Disable the adapters of all LoRA layers on the current thread until the returned guard is
+/// dropped, so that a LoRA model computes the outputs of its base model.
+///
+/// This is the counterpart of PEFT's `disable_adapter()` context and is used to get the
+/// reference policy of DPO without a second copy of the model. Merged layers have the adapter
+/// folded into their weights and are not affected.
+///
+/// ```ignore
+/// let reference_logits = {
+///     let _disabled = candle_lora::disable_adapters();
+///     model.forward(&input_ids)?
+/// };
+/// ```
+#[must_use = "the adapters are enabled again when the guard is dropped"]
+pub fn disable_adapters() -> DisabledAdapters {
+    let previous = ADAPTERS_DISABLED.with(|disabled| disabled.replace(true));
+    DisabledAdapters { previous }
+}
+
+/// Guard returned by [`disable_adapters`], restores the previous state when dropped.
+#[derive(Debug)]
+pub struct DisabledAdapters {
+    previous: bool,
+}
+
+impl Drop for DisabledAdapters {
+    fn drop(&mut self) {
+        ADAPTERS_DISABLED.with(|disabled| disabled.set(self.previous));
+    }
+}
+
+/// The adapter of each batch element on the current thread, see [`select_batch_adapters`].
+pub(crate) fn batch_adapter_ids() -> Option<Vec<u32>> {
+    BATCH_ADAPTERS.with(|ids| ids.borrow().clone())
+}
+
+/// Apply a different adapter to each element of the batch in the linear and embedding LoRA
+/// layers on the current thread until the returned guard is dropped, S-LoRA style: element `i`
+/// goes through the adapter `adapter_ids[i]`, an index into
+/// [`crate::MultiAdapter::adapter_names`], 0 being the default adapter.
+///
+/// The A and B matrices of the adapters are stacked, padded to the largest rank, and gathered
+/// per element, so the batch is run at once rather than split per adapter. The selection takes
+/// precedence over the adapter weights. Merged layers have their adapter folded into the
+/// weights and apply it to every element, and conv layers refuse a selection.
+///
+/// ```ignore
+/// Lora::add_adapter(&mut new, "support", &support_vb, &config)?;
+/// // The first two sequences use the default adapter, the third the support one.
+/// let logits = {
+///     let _adapters = candle_lora::select_batch_adapters(&[0, 0, 1]);
+///     model.forward(&input_ids)?
+/// };
+/// ```
+#[must_use = "the adapters are no longer selected per element when the guard is dropped"]
+pub fn select_batch_adapters(adapter_ids: &[u32]) -> BatchAdapters {
+    let previous = BATCH_ADAPTERS.with(|ids| ids.replace(Some(adapter_ids.to_vec())));
+    BatchAdapters { previous }
+}
+
+/// Guard returned by [`select_batch_adapters`], restores the previous selection when dropped.
+#[derive(Debug)]
+pub struct BatchAdapters {
+    previous: Option<Vec<u32>>,
+}
+
+impl Drop for BatchAdapters {
+    fn drop(&mut self) {
+        BATCH_ADAPTERS.with(|ids| *ids.borrow_mut() = self.previous.take());
+    }
+}
@@ -0,0 +1,129 @@
+//! Averaging of adapter checkpoints saved along a training run, which usually generalizes better
+//! than the last checkpoint alone.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{DType, Device, Result, Tensor};
+
+/// Weighted average of adapter checkpoints holding the same tensors.
+///
+/// `weights` has one non-negative weight per checkpoint and is normalized to sum to 1, all the
+/// checkpoints have the same weight when it is `None`. The average is computed in f32 and
+/// converted back to the dtype of each tensor.
+pub fn average_adapters(
+    checkpoints: &[HashMap<String, Tensor>],
+    weights: Option<&[f64]>,
+) -> Result<HashMap<String, Tensor>> {
+    let Some(first) = checkpoints.first() else {
+        candle_core::bail!("no checkpoint to average")
+    };
+    let weights = match weights {
+        Some(weights) if weights.len() != checkpoints.len() => candle_core::bail!(
+            "got {} weights for {} checkpoints",
+            weights.len(),
+            checkpoints.len()
+        ),
+        Some(weights) => weights.to_vec(),
+        None => vec![1.; checkpoints.len()],
+    };
+    let total = weights.iter().sum::<f64>();
+    if weights.iter().any(|&w| w < 0.) || total <= 0. {
+        candle_core::bail!("checkpoint weights must be non-negative with a positive sum")
+    }
+
+    let mut averaged = HashMap::new();
+    for (name, reference) in first {
+        let mut sum = Tensor::zeros(reference.shape(), DType::F32, reference.device())?;
+        for (idx, (checkpoint, weight)) in checkpoints.iter().zip(weights.iter()).enumerate() {
+            let Some(tensor) = checkpoint.get(name) else {
+                candle_core::bail!("checkpoint {idx} has no tensor {name}")
+            };
+            if tensor.shape() != reference.shape() {
+                candle_core::bail!(
+                    "checkpoint {idx} has shape {:?} for {name}, expected {:?}",
+                    tensor.shape(),
+                    reference.shape()
+                )
+            }
+            sum = (sum + (tensor.to_dtype(DType::F32)? * (weight / total))?)?;
+        }
+        averaged.insert(name.clone(), sum.to_dtype(reference.dtype())?);
+    }
+    for (idx, checkpoint) in checkpoints.iter().enumerate() {
+        if let Some(name) = checkpoint.keys().find(|name| !first.contains_key(*name)) {
+            candle_core::bail!("checkpoint {idx} has tensor {name} missing from checkpoint 0")
+        }
+    }
+    Ok(averaged)
+}
+
+/// Average the adapter safetensors files `checkpoint_paths` into `output_path`, see
+/// [`average_adapters`].
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::{average_checkpoints, last_checkpoints};
+///
+/// let paths = last_checkpoints("path/to/run", 5).unwrap();
+/// average_checkpoints(&paths, None, "path/to/averaged.safetensors", &Device::Cpu).unwrap();
+/// ```
+pub fn average_checkpoints<P: AsRef<Path>>(
+    checkpoint_paths: &[P],
+    weights: Option<&[f64]>,
+    output_path: &str,
+    device: &Device,
+) -> Result<()> {
+    let checkpoints = checkpoint_paths
+        .iter()
+        .map(|path| candle_core::safetensors::load(path, device))
+        .collect::<Result<Vec<_>>>()?;
+    let averaged = average_adapters(&checkpoints, weights)?;
+    candle_core::safetensors::save(&averaged, output_path)?;
+    Ok(())
+}
+
+/// Step of a checkpoint, the last number of its name, e.g. 500 for `checkpoint-500` or
+/// `adapter_step500.safetensors`.
+fn checkpoint_step(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    let end = stem.rfind(|c: char| c.is_ascii_digit())? + 1;
+    let start = stem[..end]
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |idx| idx + 1);
+    stem[start..end].parse().ok()
+}
+
+/// The adapter files of the last `n` checkpoints saved in `run_dir`, oldest first.
+///
+/// Checkpoints are either `.safetensors` files or subdirectories holding an
+/// `adapter_model.safetensors` file as saved by PEFT (`checkpoint-500/adapter_model.safetensors`),
+/// and are ordered by the last number of their name.
+pub fn last_checkpoints<P: AsRef<Path>>(run_dir: P, n: usize) -> Result<Vec<PathBuf>> {
+    let mut checkpoints = Vec::new();
+    for entry in std::fs::read_dir(run_dir)? {
+        let path = entry?.path();
+        let adapter_path = if path.is_dir() {
+            path.join("adapter_model.safetensors")
+        } else {
+            path.clone()
+        };
+        let is_adapter = adapter_path.is_file()
+            && adapter_path
+                .extension()
+                .is_some_and(|ext| ext == "safetensors");
+        if let (true, Some(step)) = (is_adapter, checkpoint_step(&path)) {
+            checkpoints.push((step, adapter_path));
+        }
+    }
+    checkpoints.sort();
+    let skip = checkpoints.len().saturating_sub(n);
+    Ok(checkpoints
+        .into_iter()
+        .skip(skip)
+        .map(|(_, path)| path)
+        .collect())
+}
@@ -0,0 +1,187 @@
+//! A training loop around [`LoraTrainer`] with callbacks to monitor and control the run,
+//! including early stopping on the evaluation loss.
+
+use std::path::{Path, PathBuf};
+
+use candle_core::{DType, Result, Tensor};
+use candle_nn::Optimizer;
+
+use crate::{LoraTrainer, StepInfo};
+
+/// Whether training should go on after a callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrainerControl {
+    #[default]
+    Continue,
+    Stop,
+}
+
+/// Hooks called by [`LoraTrainer::fit`]. Training stops after the current hook when any callback
+/// returns [`TrainerControl::Stop`].
+pub trait TrainerCallback {
+    /// Called after each optimizer step with the training loss of the batch.
+    fn on_step(&mut self, _info: &StepInfo, _loss: f64) -> Result<TrainerControl> {
+        Ok(TrainerControl::Continue)
+    }
+
+    /// Called after each evaluation with the number of steps taken so far.
+    fn on_eval(&mut self, _step: usize, _eval_loss: f64) -> Result<TrainerControl> {
+        Ok(TrainerControl::Continue)
+    }
+
+    /// Called after a checkpoint was saved to `path`.
+    fn on_save(&mut self, _step: usize, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Stops training when the evaluation loss has not improved by more than `min_delta` for
+/// `patience` evaluations in a row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EarlyStopping {
+    patience: usize,
+    min_delta: f64,
+    best_loss: Option<f64>,
+    best_step: Option<usize>,
+    evals_without_improvement: usize,
+}
+
+impl EarlyStopping {
+    pub fn new(patience: usize, min_delta: f64) -> Self {
+        Self {
+            patience,
+            min_delta,
+            best_loss: None,
+            best_step: None,
+            evals_without_improvement: 0,
+        }
+    }
+
+    /// The lowest evaluation loss seen so far.
+    pub fn best_loss(&self) -> Option<f64> {
+        self.best_loss
+    }
+
+    /// The step of the lowest evaluation loss.
+    pub fn best_step(&self) -> Option<usize> {
+        self.best_step
+    }
+}
+
+impl TrainerCallback for EarlyStopping {
+    fn on_eval(&mut self, step: usize, eval_loss: f64) -> Result<TrainerControl> {
+        match self.best_loss {
+            Some(best) if eval_loss >= best - self.min_delta => {
+                self.evals_without_improvement += 1;
+            }
+            _ => {
+                self.best_loss = Some(eval_loss);
+                self.best_step = Some(step);
+                self.evals_without_improvement = 0;
+            }
+        }
+        if self.evals_without_improvement >= self.patience {
+            Ok(TrainerControl::Stop)
+        } else {
+            Ok(TrainerControl::Continue)
+        }
+    }
+}
+
+/// Loop settings of [`LoraTrainer::fit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FitConfig {
+    pub epochs: usize,
+    /// Evaluate every `eval_steps` optimizer steps.
+    pub eval_steps: Option<usize>,
+    /// Save a checkpoint every `save_steps` optimizer steps.
+    pub save_steps: Option<usize>,
+}
+
+impl Default for FitConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 1,
+            eval_steps: None,
+            save_steps: None,
+        }
+    }
+}
+
+/// How a run of [`LoraTrainer::fit`] ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FitSummary {
+    /// Number of optimizer steps taken by the trainer.
+    pub steps: usize,
+    /// Whether a callback stopped the run before the last epoch ended.
+    pub stopped_early: bool,
+    pub last_eval_loss: Option<f64>,
+}
+
+impl<O: Optimizer> LoraTrainer<O> {
+    /// Train for `config.epochs` passes over `batches`.
+    ///
+    /// `loss_fn` computes the training loss of a batch. `eval_fn` returns the evaluation loss and
+    /// is called every `config.eval_steps` steps, `save_fn` saves a checkpoint of the adapters
+    /// for the given step and returns its path, and is called every `config.save_steps` steps.
+    pub fn fit<B, L>(
+        &mut self,
+        batches: &[B],
+        config: &FitConfig,
+        mut loss_fn: L,
+        mut eval_fn: Option<&mut dyn FnMut() -> Result<f64>>,
+        mut save_fn: Option<&mut dyn FnMut(usize) -> Result<PathBuf>>,
+        callbacks: &mut [&mut dyn TrainerCallback],
+    ) -> Result<FitSummary>
+    where
+        L: FnMut(&B) -> Result<Tensor>,
+    {
+        let is_due =
+            |every: Option<usize>, step: usize| every.is_some_and(|n| step.is_multiple_of(n));
+        let mut summary = FitSummary {
+            steps: self.steps(),
+            stopped_early: false,
+            last_eval_loss: None,
+        };
+        for _ in 0..config.epochs {
+            for batch in batches {
+                let loss = loss_fn(batch)?;
+                let info = self.backward_step(&loss)?;
+                let loss = loss.to_dtype(DType::F32)?.to_scalar::<f32>()? as f64;
+                let mut control = TrainerControl::Continue;
+                for callback in callbacks.iter_mut() {
+                    if callback.on_step(&info, loss)? == TrainerControl::Stop {
+                        control = TrainerControl::Stop;
+                    }
+                }
+                summary.steps = self.steps();
+                // Skipped steps do not count towards evaluation and saving.
+                let step = (!info.skipped).then_some(summary.steps);
+                if let (Some(step), Some(eval_fn)) = (step, &mut eval_fn) {
+                    if is_due(config.eval_steps, step) {
+                        let eval_loss = eval_fn()?;
+                        summary.last_eval_loss = Some(eval_loss);
+                        for callback in callbacks.iter_mut() {
+                            if callback.on_eval(step, eval_loss)? == TrainerControl::Stop {
+                                control = TrainerControl::Stop;
+                            }
+                        }
+                    }
+                }
+                if let (Some(step), Some(save_fn)) = (step, &mut save_fn) {
+                    if is_due(config.save_steps, step) {
+                        let path = save_fn(step)?;
+                        for callback in callbacks.iter_mut() {
+                            callback.on_save(step, &path)?;
+                        }
+                    }
+                }
+                if control == TrainerControl::Stop {
+                    summary.stopped_early = true;
+                    return Ok(summary);
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
@@ -0,0 +1,136 @@
+//! Adapter checkpoints: the adapter tensors of a converted model and the configuration they were
+//! trained with, saved to a directory to resume training or to load the adapter later.
+//!
+//! A checkpoint directory holds `adapter_model.safetensors`, found by
+//! [`crate::last_checkpoints`], and `candle_lora_config.json`. The configuration is also
+//! written in the [`AdapterMetadata`] of the weights file, which is enough to load it. The tensors are the trained
+//! variables of the layers under their `VarMap` names, e.g. `lora.a0.weight`, the same names as
+//! [`crate::Lora::trainable_params`] gives the optimizer, so optimizer state kept by name carries
+//! over to a resumed run.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use candle_core::{bail, Device, Result, Tensor, Var};
+use candle_nn::VarMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{AdapterMetadata, LoraConfig};
+
+/// The adapter tensors of a checkpoint directory.
+pub const ADAPTER_WEIGHTS_FILE: &str = "adapter_model.safetensors";
+/// The adapter configuration of a checkpoint directory.
+pub const ADAPTER_CONFIG_FILE: &str = "candle_lora_config.json";
+
+/// The configuration saved with an adapter checkpoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdapterConfig {
+    pub rank: usize,
+    pub alpha: f64,
+    #[serde(default)]
+    pub dropout: Option<f32>,
+    /// The names of the converted layers, sorted.
+    #[serde(default)]
+    pub target_modules: Vec<String>,
+    /// Whether the adapter is scaled by `alpha / sqrt(rank)`, see [`LoraConfig::with_rslora`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub use_rslora: bool,
+    /// The training step the checkpoint was saved at, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+}
+
+impl AdapterConfig {
+    /// The LoRA config to convert the model with before loading the checkpoint. Tied-LoRA
+    /// settings are not saved and have to be set again.
+    pub fn lora_config(&self) -> LoraConfig {
+        LoraConfig::new(self.rank, self.alpha, self.dropout).with_rslora(self.use_rslora)
+    }
+}
+
+impl LoraConfig {
+    pub(crate) fn adapter_config(
+        &self,
+        target_modules: Vec<String>,
+        step: Option<usize>,
+    ) -> AdapterConfig {
+        AdapterConfig {
+            rank: self.rank,
+            alpha: self.alpha,
+            dropout: self.dropout,
+            target_modules,
+            use_rslora: self.use_rslora,
+            step,
+        }
+    }
+}
+
+/// Write the adapter `tensors` and `config` to the checkpoint directory `dir`, created if needed.
+pub(crate) fn save_checkpoint(
+    tensors: &BTreeMap<String, Tensor>,
+    config: &AdapterConfig,
+    dir: &Path,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let tensors = tensors
+        .iter()
+        .map(|(name, tensor)| (name.clone(), tensor.detach()))
+        .collect::<HashMap<_, _>>();
+    let metadata = HashMap::from([AdapterMetadata::from_adapter_config(config).entry()]);
+    safetensors::serialize_to_file(
+        tensors.iter(),
+        &Some(metadata),
+        &dir.join(ADAPTER_WEIGHTS_FILE),
+    )?;
+    let config = serde_json::to_string_pretty(config).map_err(candle_core::Error::wrap)?;
+    std::fs::write(dir.join(ADAPTER_CONFIG_FILE), config)?;
+    Ok(())
+}
+
+/// Read the configuration of the checkpoint directory `dir`, from the [`AdapterMetadata`] of its
+/// weights when it has no `candle_lora_config.json`. The training step is only saved in the
+/// latter.
+pub fn load_adapter_config<P: AsRef<Path>>(dir: P) -> Result<AdapterConfig> {
+    let path = dir.as_ref().join(ADAPTER_CONFIG_FILE);
+    let weights = dir.as_ref().join(ADAPTER_WEIGHTS_FILE);
+    if !path.exists() && weights.exists() {
+        if let Some(config) = AdapterMetadata::read(&weights)?.and_then(|m| m.adapter_config()) {
+            return Ok(config);
+        }
+    }
+    let config = std::fs::read_to_string(&path)
+        .map_err(|e| candle_core::Error::Msg(format!("cannot read {}: {e}", path.display())))?;
+    serde_json::from_str(&config).map_err(candle_core::Error::wrap)
+}
+
+/// Load the checkpoint directory `dir` into `varmap` on `device`, returning its configuration.
+///
+/// The variables of `varmap` named like a saved tensor take its value, which must have the same
+/// shape, and the other saved tensors are inserted as new variables. Loading into an empty
+/// `VarMap` before converting the model makes the converted layers pick the saved adapter up,
+/// loading after converting updates their variables in place.
+pub(crate) fn load_checkpoint(
+    dir: &Path,
+    varmap: &VarMap,
+    device: &Device,
+) -> Result<AdapterConfig> {
+    let config = load_adapter_config(dir)?;
+    let tensors = candle_core::safetensors::load(dir.join(ADAPTER_WEIGHTS_FILE), device)?;
+    let mut data = varmap.data().lock().unwrap();
+    for (name, tensor) in tensors {
+        match data.get(&name) {
+            Some(var) if var.shape() != tensor.shape() => bail!(
+                "checkpoint tensor {name} has shape {:?}, the variable {:?}",
+                tensor.shape(),
+                var.shape()
+            ),
+            Some(var) => var.set(&tensor.to_dtype(var.dtype())?)?,
+            None => {
+                data.insert(name, Var::from_tensor(&tensor)?);
+            }
+        }
+    }
+    Ok(config)
+}
@@ -0,0 +1,157 @@
+//! Per-tensor transforms run by the PEFT converters, to adapt checkpoints whose layout differs
+//! from what the converters expect without forking them.
+
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use candle_core::{bail, DType, Result, Tensor};
+
+type TensorHook = dyn Fn(&str, Tensor) -> Result<Vec<(String, Tensor)>> + Send + Sync;
+
+/// An ordered list of hooks applied to every tensor of a PEFT adapter after it is loaded and
+/// before its LoRA pairs are collected.
+///
+/// A hook maps a `(name, tensor)` to any number of renamed or transformed tensors, returning
+/// none drops the tensor. Each hook sees the output of the previous ones.
+///
+/// ```no_run
+/// use candle_core::{DType, Device};
+/// use candle_lora::{convert_peft_to_candle_lora_with_hooks, ConversionHooks};
+///
+/// let hooks = ConversionHooks::new()
+///     .rename("transformer.blocks.", "model.layers.")
+///     .split_fused("qkv_proj", &["q_proj", "k_proj", "v_proj"])
+///     .cast(DType::F32);
+/// convert_peft_to_candle_lora_with_hooks(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     "lora_llama",
+///     &Device::Cpu,
+///     &hooks,
+/// ).unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct ConversionHooks {
+    hooks: Vec<Arc<TensorHook>>,
+}
+
+impl Debug for ConversionHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConversionHooks")
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+impl ConversionHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a custom hook.
+    pub fn hook(
+        mut self,
+        hook: impl Fn(&str, Tensor) -> Result<Vec<(String, Tensor)>> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Add a hook transforming the tensors whose name contains `pattern`, keeping the name.
+    pub fn map(
+        self,
+        pattern: &str,
+        f: impl Fn(Tensor) -> Result<Tensor> + Send + Sync + 'static,
+    ) -> Self {
+        let pattern = pattern.to_string();
+        self.hook(move |name, tensor| {
+            let tensor = if name.contains(&pattern) {
+                f(tensor)?
+            } else {
+                tensor
+            };
+            Ok(vec![(name.to_string(), tensor)])
+        })
+    }
+
+    /// Transpose the 2D tensors whose name contains `pattern`, e.g. for checkpoints storing
+    /// `(in_features, rank)` A matrices.
+    pub fn transpose(self, pattern: &str) -> Self {
+        self.map(pattern, |tensor| tensor.t()?.contiguous())
+    }
+
+    /// Cast every tensor to `dtype`.
+    pub fn cast(self, dtype: DType) -> Self {
+        self.map("", move |tensor| tensor.to_dtype(dtype))
+    }
+
+    /// Replace `from` with `to` in every tensor name.
+    pub fn rename(self, from: &str, to: &str) -> Self {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.hook(move |name, tensor| Ok(vec![(name.replace(&from, &to), tensor)]))
+    }
+
+    /// Drop the tensors whose name contains `pattern`.
+    pub fn drop_matching(self, pattern: &str) -> Self {
+        let pattern = pattern.to_string();
+        self.hook(move |name, tensor| {
+            Ok(if name.contains(&pattern) {
+                vec![]
+            } else {
+                vec![(name.to_string(), tensor)]
+            })
+        })
+    }
+
+    /// Split the LoRA pair of every fused module named `fused` into one pair per part, with
+    /// the part names replacing `fused`: B is split into equal row blocks, in the order of
+    /// `parts`, and A is shared, as `delta = B A`.
+    pub fn split_fused(self, fused: &str, parts: &[&str]) -> Self {
+        let fused = format!(".{fused}.");
+        let parts = parts
+            .iter()
+            .map(|part| format!(".{part}."))
+            .collect::<Vec<_>>();
+        self.hook(move |name, tensor| {
+            if !name.contains(&fused) {
+                return Ok(vec![(name.to_string(), tensor)]);
+            }
+            let names = parts.iter().map(|part| name.replace(&fused, part));
+            if name.contains(".lora_A") {
+                return Ok(names.map(|name| (name, tensor.clone())).collect());
+            }
+            let rows = tensor.dim(0)?;
+            if !rows.is_multiple_of(parts.len()) {
+                bail!(
+                    "{name}: {rows} rows cannot be split into {} parts",
+                    parts.len()
+                );
+            }
+            let rows = rows / parts.len();
+            names
+                .enumerate()
+                .map(|(i, name)| Ok((name, tensor.narrow(0, i * rows, rows)?)))
+                .collect()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Run the hooks over `tensors`. Two tensors mapped to the same name are an error.
+    pub fn apply(&self, tensors: HashMap<String, Tensor>) -> Result<HashMap<String, Tensor>> {
+        let mut tensors = tensors;
+        for hook in &self.hooks {
+            let mut transformed = HashMap::new();
+            for (name, tensor) in tensors {
+                for (new_name, tensor) in hook(&name, tensor)? {
+                    if transformed.insert(new_name.clone(), tensor).is_some() {
+                        bail!("conversion hook maps two tensors to {new_name}");
+                    }
+                }
+            }
+            tensors = transformed;
+        }
+        Ok(tensors)
+    }
+}
@@ -0,0 +1,90 @@
+//! Placement of the adapters of a model sharded across devices.
+//!
+//! A LoRA layer adds its adapter to the output of its base layer, so both must be on the same
+//! device. A [`DeviceMap`] set with [`crate::LoraConfig::with_device_map`] moves the adapter of
+//! each converted layer to the device of its rule, matched against the path of the layer like a
+//! [`TargetSpec`], or to the device of its base weights. The adapters are loaded with the
+//! `VarBuilder` given to [`crate::Lora::convert_model`] first, one layer at a time.
+//!
+//! Moved adapters are copies: the adapter of a model trained across devices should be created
+//! from a `VarMap` per device instead, its variables being the tensors of the `VarMap`.
+
+use candle_core::{Device, Result};
+
+use crate::TargetSpec;
+
+/// LoRA layers whose adapter can be moved to another device.
+pub trait AdapterDevice: Sized {
+    /// The layer with its adapter tensors (A and B, and the DoRA magnitudes, Tied-LoRA scalings
+    /// and extra adapters if any) on `device`. The base weights stay where they are.
+    fn to_device(&self, device: &Device) -> Result<Self>;
+}
+
+/// Which device the adapter of each converted layer goes to: that of the first rule matching
+/// the layer, else the device of its base weights with
+/// [`DeviceMap::with_base_layer_devices`], else the device of the `VarBuilder` it was loaded
+/// with.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceMap {
+    rules: Vec<(TargetSpec, Device)>,
+    base_layer_devices: bool,
+}
+
+impl DeviceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put the adapters of the layers `target` matches on `device`, e.g. layers 16 to 31 on the
+    /// second GPU, or `lm_head$` on the last one.
+    pub fn with_device(mut self, target: TargetSpec, device: Device) -> Self {
+        self.rules.push((target, device));
+        self
+    }
+
+    /// Put the adapters of the layers no rule matches next to their base weights.
+    pub fn with_base_layer_devices(mut self) -> Self {
+        self.base_layer_devices = true;
+        self
+    }
+
+    /// Split the layers `0..num_layers` into contiguous ranges of about the same size, one per
+    /// device in order, like the pipeline-parallel sharding of a model. Layers without an index,
+    /// e.g. `lm_head`, need a rule of their own.
+    pub fn split_layers(num_layers: usize, devices: &[Device]) -> Self {
+        let mut map = Self::new();
+        let mut start = 0;
+        for (i, device) in devices.iter().enumerate() {
+            let end = num_layers * (i + 1) / devices.len();
+            if end > start {
+                let target = TargetSpec::new().with_layers(start..=end - 1);
+                map = map.with_device(target, device.clone());
+            }
+            start = end;
+        }
+        map
+    }
+
+    /// The device of the adapter of the layer at `path` whose base weights are on `base`, `None`
+    /// to leave it on the device it was loaded on.
+    pub fn device<'a>(&'a self, path: &str, base: &'a Device) -> Option<&'a Device> {
+        self.rules
+            .iter()
+            .find(|(target, _)| target.matches(path))
+            .map(|(_, device)| device)
+            .or(self.base_layer_devices.then_some(base))
+    }
+}
+
+/// Move `layer` to the device `map` gives it, if any.
+pub(crate) fn place<L: AdapterDevice>(
+    layer: L,
+    map: Option<&DeviceMap>,
+    path: &str,
+    base: &Device,
+) -> Result<L> {
+    match map.and_then(|map| map.device(path, base)) {
+        Some(device) => layer.to_device(device),
+        None => Ok(layer),
+    }
+}
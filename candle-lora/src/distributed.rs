@@ -0,0 +1,154 @@
+//! Data-parallel training: each process computes the gradients of its own batches and the
+//! gradients of the LoRA variables are averaged over all the processes before the optimizer step.
+//!
+//! Only the adapter gradients are exchanged, and they are sent in a single flattened buffer, so
+//! the communication volume is a small fraction of full fine-tuning.
+
+use candle_core::{backprop::GradStore, DType, Result, Tensor, Var};
+
+/// Collective communication between the processes of a data-parallel run.
+pub trait Communicator {
+    /// Index of this process, from 0 to `world_size - 1`.
+    fn rank(&self) -> usize;
+
+    fn world_size(&self) -> usize;
+
+    /// Elementwise sum of `xs` over all the processes, returned to each of them.
+    fn all_reduce_sum(&self, xs: &Tensor) -> Result<Tensor>;
+}
+
+/// Replace the gradients of `vars` with their mean over all the processes of `communicator`.
+///
+/// Every process must call this with the same `vars`, in the same order. A variable without a
+/// gradient on a process contributes zeros.
+pub fn all_reduce_grads(
+    grads: &mut GradStore,
+    vars: &[Var],
+    communicator: &dyn Communicator,
+) -> Result<()> {
+    if vars.is_empty() {
+        return Ok(());
+    }
+    let flat = vars
+        .iter()
+        .map(|var| match grads.get(var.as_tensor()) {
+            Some(grad) => grad.to_dtype(DType::F32)?.flatten_all(),
+            None => Tensor::zeros(var.elem_count(), DType::F32, var.device()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let reduced =
+        (communicator.all_reduce_sum(&Tensor::cat(&flat, 0)?)? / communicator.world_size() as f64)?;
+    let mut offset = 0;
+    for var in vars {
+        let dtype = grads
+            .get(var.as_tensor())
+            .map_or(var.dtype(), |grad| grad.dtype());
+        let grad = reduced
+            .narrow(0, offset, var.elem_count())?
+            .reshape(var.shape())?
+            .to_dtype(dtype)?;
+        grads.insert(var.as_tensor(), grad);
+        offset += var.elem_count();
+    }
+    Ok(())
+}
+
+#[cfg(feature = "nccl")]
+pub use nccl::{NcclCommunicator, NcclId};
+
+#[cfg(feature = "nccl")]
+mod nccl {
+    use std::rc::Rc;
+
+    use candle_core::{CustomOp1, DType, Device, Layout, Result, Shape, Tensor};
+    use cudarc::nccl::safe::{Comm, ReduceOp};
+
+    pub use cudarc::nccl::safe::Id as NcclId;
+
+    use super::Communicator;
+
+    /// A [`Communicator`] over NCCL, with one process per GPU.
+    pub struct NcclCommunicator {
+        comm: Rc<Comm>,
+    }
+
+    impl NcclCommunicator {
+        /// Join the NCCL communicator of `world_size` processes identified by `id`, as the
+        /// process `rank` running on the CUDA `device`.
+        ///
+        /// `id` is created once with `NcclId::new()`, typically by rank 0, and shared with the
+        /// other processes, e.g. by writing `id.internal()` to a file read back with
+        /// `NcclId::uninit`.
+        pub fn new(device: &Device, rank: usize, world_size: usize, id: NcclId) -> Result<Self> {
+            let Device::Cuda(cuda_device) = device else {
+                candle_core::bail!("NCCL needs a CUDA device, got {device:?}")
+            };
+            let comm = Comm::from_rank(cuda_device.cuda_stream(), rank, world_size, id)
+                .map_err(|e| candle_core::Error::Msg(format!("nccl: {:?}", e.0)))?;
+            Ok(Self {
+                comm: Rc::new(comm),
+            })
+        }
+    }
+
+    impl Communicator for NcclCommunicator {
+        fn rank(&self) -> usize {
+            self.comm.rank()
+        }
+
+        fn world_size(&self) -> usize {
+            self.comm.world_size()
+        }
+
+        fn all_reduce_sum(&self, xs: &Tensor) -> Result<Tensor> {
+            xs.contiguous()?.apply_op1_no_bwd(&AllReduce {
+                comm: self.comm.clone(),
+            })
+        }
+    }
+
+    struct AllReduce {
+        comm: Rc<Comm>,
+    }
+
+    impl CustomOp1 for AllReduce {
+        fn name(&self) -> &'static str {
+            "nccl-all-reduce"
+        }
+
+        fn cpu_fwd(
+            &self,
+            _s: &candle_core::CpuStorage,
+            _l: &Layout,
+        ) -> Result<(candle_core::CpuStorage, Shape)> {
+            candle_core::bail!("nccl-all-reduce is only implemented on CUDA")
+        }
+
+        fn cuda_fwd(
+            &self,
+            s: &candle_core::CudaStorage,
+            l: &Layout,
+        ) -> Result<(candle_core::CudaStorage, Shape)> {
+            use cudarc::driver::DeviceSlice;
+
+            if s.dtype() != DType::F32 {
+                candle_core::bail!("nccl-all-reduce expects f32, got {:?}", s.dtype())
+            }
+            let elem_count = l.shape().elem_count();
+            let dev = s.device().clone();
+            let src = s.as_cuda_slice::<f32>()?;
+            let src = match l.contiguous_offsets() {
+                Some((0, len)) if len == src.len() => src,
+                Some(_) | None => candle_core::bail!("nccl-all-reduce input has to be contiguous"),
+            };
+            let mut dst = unsafe { dev.alloc::<f32>(elem_count) }?;
+            self.comm
+                .all_reduce(src, &mut dst, &ReduceOp::Sum)
+                .map_err(|e| candle_core::Error::Msg(format!("nccl: {:?}", e.0)))?;
+            Ok((
+                candle_core::CudaStorage::wrap_cuda_slice(dst, dev),
+                l.shape().clone(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! DoRA, weight-decomposed low-rank adaptation, see "DoRA: Weight-Decomposed Low-Rank
+//! Adaptation" Liu et al. 2024 <https://arxiv.org/abs/2402.09353>.
+//!
+//! The adapted weight is decomposed into a trained magnitude per output channel and a direction,
+//! which LoRA updates: `W' = m * (W + scale * B A) / ||W + scale * B A||`, the norm being taken
+//! over each output channel and treated as a constant in the backward pass as in the paper.
+//!
+//! The magnitude is stored as `m{id}.weight`, PEFT's `lora_magnitude_vector`. A `VarBuilder`
+//! cannot initialize a variable from the base weight, so a fresh adapter trains the offset
+//! `dm{id}.weight` of the magnitude from the base weight norms instead, which is zero at first:
+//! the layer then starts as its base layer. The layers always save the magnitude itself.
+
+use candle_core::{Device, Result, Tensor, D};
+use candle_nn::{init, VarBuilder};
+
+use crate::swap::{adapter_vb, weight_name};
+
+/// The magnitude vector of a DoRA layer.
+#[derive(Debug, Clone)]
+pub(crate) struct DoraMagnitude {
+    /// The magnitude, or its offset from `base_norms`.
+    magnitude: Tensor,
+    base_norms: Option<Tensor>,
+    /// The delta added to the base weight by merging, subtracted again by unmerging: the
+    /// magnitude scale depends on the base weight, which merging changes.
+    pub(crate) merged_delta: Option<Tensor>,
+}
+
+impl DoraMagnitude {
+    /// Load the magnitude of the layer `id` of `vb`, or create its offset from the norms of the
+    /// base `weight`.
+    pub(crate) fn new(vb: &VarBuilder, id: usize, weight: &Tensor) -> Result<Self> {
+        let out_channels = weight.dim(0)?;
+        let m_vb = vb.pp(format!("m{id}"));
+        if m_vb.contains_tensor("weight") {
+            return Ok(Self {
+                magnitude: m_vb.get(out_channels, "weight")?,
+                base_norms: None,
+                merged_delta: None,
+            });
+        }
+        let offset = vb
+            .pp(format!("dm{id}"))
+            .get_with_hints(out_channels, "weight", init::ZERO)?;
+        Ok(Self {
+            base_norms: Some(channel_norms(weight)?.to_dtype(offset.dtype())?),
+            magnitude: offset,
+            merged_delta: None,
+        })
+    }
+
+    /// The magnitude of the layer `id` created under `prefix`, read from another adapter.
+    pub(crate) fn load(vb: &VarBuilder, prefix: &str, id: usize, shape: usize) -> Result<Self> {
+        Ok(Self {
+            magnitude: adapter_vb(vb, prefix, &format!("m{id}")).get(shape, "weight")?,
+            base_norms: None,
+            merged_delta: None,
+        })
+    }
+
+    /// The name and tensor of the trained variable of the layer `id` created under `prefix`, the
+    /// magnitude or its offset.
+    pub(crate) fn variable(&self, prefix: &str, id: usize) -> (String, Tensor) {
+        let name = match self.base_norms {
+            Some(_) => format!("dm{id}"),
+            None => format!("m{id}"),
+        };
+        (weight_name(prefix, &name), self.magnitude.clone())
+    }
+
+    /// The magnitude moved to `device`. The merged delta stays with the base weight.
+    pub(crate) fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            magnitude: self.magnitude.to_device(device)?,
+            base_norms: match &self.base_norms {
+                Some(norms) => Some(norms.to_device(device)?),
+                None => None,
+            },
+            merged_delta: self.merged_delta.clone(),
+        })
+    }
+
+    /// The `(out_channels,)` magnitude.
+    pub(crate) fn magnitude(&self) -> Result<Tensor> {
+        match &self.base_norms {
+            Some(norms) => norms + &self.magnitude,
+            None => Ok(self.magnitude.clone()),
+        }
+    }
+
+    /// The `(out_channels,)` factor `m / ||weight||` applied to the outputs of the adapted
+    /// `weight`, without gradient through the norm.
+    pub(crate) fn scale(&self, adapted_weight: &Tensor) -> Result<Tensor> {
+        let norms = channel_norms(&adapted_weight.detach())?;
+        self.magnitude()?.to_dtype(norms.dtype())? / norms
+    }
+}
+
+/// The L2 norm of each output channel, dim 0, of a weight.
+pub(crate) fn channel_norms(weight: &Tensor) -> Result<Tensor> {
+    weight.flatten_from(1)?.sqr()?.sum(D::Minus1)?.sqrt()
+}
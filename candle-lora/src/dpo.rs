@@ -0,0 +1,210 @@
+//! Direct Preference Optimization, https://arxiv.org/abs/2305.18290
+//!
+//! The reference policy is the base model: its log probabilities are computed by the same LoRA
+//! model with the adapters disabled (see [`disable_adapters`]), so preference fine-tuning of an
+//! adapter needs a single copy of the weights.
+
+use candle_core::{DType, Result, Tensor, D};
+use candle_nn::Optimizer;
+
+use crate::{disable_adapters, LoraTrainer, StepInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DpoConfig {
+    /// Temperature of the implicit reward, the deviation from the reference is penalized more
+    /// for larger values.
+    pub beta: f64,
+    /// Assumed probability that a preference label is flipped (conservative DPO). 0 gives the
+    /// original DPO loss.
+    pub label_smoothing: f64,
+}
+
+impl Default for DpoConfig {
+    fn default() -> Self {
+        Self {
+            beta: 0.1,
+            label_smoothing: 0.,
+        }
+    }
+}
+
+/// Losses and implicit rewards of a batch of preference pairs.
+#[derive(Debug, Clone)]
+pub struct DpoOutput {
+    /// Mean loss over the batch, a scalar.
+    pub loss: Tensor,
+    /// `beta * (policy - reference)` log probabilities of the chosen responses, `(batch,)`.
+    pub chosen_rewards: Tensor,
+    /// `beta * (policy - reference)` log probabilities of the rejected responses, `(batch,)`.
+    pub rejected_rewards: Tensor,
+}
+
+impl DpoOutput {
+    /// Fraction of the pairs where the chosen response has the larger reward.
+    pub fn reward_accuracy(&self) -> Result<f32> {
+        self.chosen_rewards
+            .gt(&self.rejected_rewards)?
+            .to_dtype(DType::F32)?
+            .mean_all()?
+            .to_scalar()
+    }
+
+    /// Mean difference between the chosen and rejected rewards.
+    pub fn reward_margin(&self) -> Result<f32> {
+        (&self.chosen_rewards - &self.rejected_rewards)?
+            .to_dtype(DType::F32)?
+            .mean_all()?
+            .to_scalar()
+    }
+}
+
+/// `log(sigmoid(xs))`, computed as `-softplus(-xs)` without overflow.
+fn log_sigmoid(xs: &Tensor) -> Result<Tensor> {
+    let softplus = (xs.neg()?.relu()? + (xs.abs()?.neg()?.exp()? + 1.)?.log()?)?;
+    softplus.neg()
+}
+
+/// The DPO loss of `(batch,)` summed log probabilities of the chosen and rejected responses under
+/// the policy and the reference model.
+pub fn dpo_loss(
+    policy_chosen_logps: &Tensor,
+    policy_rejected_logps: &Tensor,
+    reference_chosen_logps: &Tensor,
+    reference_rejected_logps: &Tensor,
+    config: &DpoConfig,
+) -> Result<DpoOutput> {
+    let chosen_rewards = ((policy_chosen_logps - reference_chosen_logps)? * config.beta)?;
+    let rejected_rewards = ((policy_rejected_logps - reference_rejected_logps)? * config.beta)?;
+    let logits = (&chosen_rewards - &rejected_rewards)?;
+    let losses = (log_sigmoid(&logits)? * -(1. - config.label_smoothing))?;
+    let losses = if config.label_smoothing > 0. {
+        (losses - (log_sigmoid(&logits.neg()?)? * config.label_smoothing)?)?
+    } else {
+        losses
+    };
+    Ok(DpoOutput {
+        loss: losses.mean_all()?,
+        chosen_rewards: chosen_rewards.detach(),
+        rejected_rewards: rejected_rewards.detach(),
+    })
+}
+
+/// Summed log probabilities `(batch,)` of the tokens of `input_ids` under the `(batch, seq_len,
+/// vocab_size)` `logits` of a causal LM, where the logits at position `t` predict token `t + 1`.
+///
+/// Only the tokens where `loss_mask` is non-zero are counted, typically the response tokens
+/// after the prompt.
+pub fn sequence_log_probs(
+    logits: &Tensor,
+    input_ids: &Tensor,
+    loss_mask: &Tensor,
+) -> Result<Tensor> {
+    let seq_len = input_ids.dim(1)?;
+    if seq_len < 2 {
+        candle_core::bail!("sequences need at least two tokens, got {seq_len}")
+    }
+    let logits = logits.narrow(1, 0, seq_len - 1)?.to_dtype(DType::F32)?;
+    let targets = input_ids
+        .narrow(1, 1, seq_len - 1)?
+        .to_dtype(DType::U32)?
+        .contiguous()?;
+    let mask = loss_mask.narrow(1, 1, seq_len - 1)?.to_dtype(DType::F32)?;
+    let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+    let token_log_probs = log_probs.gather(&targets.unsqueeze(2)?, 2)?.squeeze(2)?;
+    (token_log_probs * mask)?.sum(1)
+}
+
+/// A batch of preference pairs. Chosen and rejected sequences are `(batch, seq_len)` token ids,
+/// each with a loss mask of the same shape selecting the response tokens.
+#[derive(Debug, Clone)]
+pub struct DpoBatch {
+    pub chosen_ids: Tensor,
+    pub chosen_mask: Tensor,
+    pub rejected_ids: Tensor,
+    pub rejected_mask: Tensor,
+}
+
+/// Trains the adapters of a LoRA model with the DPO loss, taking the optimizer steps with a
+/// [`LoraTrainer`].
+pub struct DpoTrainer<O: Optimizer> {
+    trainer: LoraTrainer<O>,
+    config: DpoConfig,
+}
+
+impl<O: Optimizer> DpoTrainer<O> {
+    pub fn new(trainer: LoraTrainer<O>, config: DpoConfig) -> Self {
+        Self { trainer, config }
+    }
+
+    pub fn trainer(&self) -> &LoraTrainer<O> {
+        &self.trainer
+    }
+
+    pub fn trainer_mut(&mut self) -> &mut LoraTrainer<O> {
+        &mut self.trainer
+    }
+
+    pub fn config(&self) -> &DpoConfig {
+        &self.config
+    }
+
+    /// Compute the DPO loss of `batch` without updating the adapters.
+    ///
+    /// `forward` maps `(batch, seq_len)` token ids to the `(batch, seq_len, vocab_size)` logits
+    /// of every position.
+    pub fn evaluate<F>(&self, forward: &mut F, batch: &DpoBatch) -> Result<DpoOutput>
+    where
+        F: FnMut(&Tensor) -> Result<Tensor>,
+    {
+        let log_probs = |forward: &mut F, ids: &Tensor, mask: &Tensor| -> Result<Tensor> {
+            sequence_log_probs(&forward(ids)?, ids, mask)
+        };
+        let (reference_chosen, reference_rejected) = {
+            let _disabled = disable_adapters();
+            (
+                log_probs(forward, &batch.chosen_ids, &batch.chosen_mask)?.detach(),
+                log_probs(forward, &batch.rejected_ids, &batch.rejected_mask)?.detach(),
+            )
+        };
+        let policy_chosen = log_probs(forward, &batch.chosen_ids, &batch.chosen_mask)?;
+        let policy_rejected = log_probs(forward, &batch.rejected_ids, &batch.rejected_mask)?;
+        dpo_loss(
+            &policy_chosen,
+            &policy_rejected,
+            &reference_chosen,
+            &reference_rejected,
+            &self.config,
+        )
+    }
+
+    /// Compute the DPO loss of `batch` and take an optimizer step on it.
+    pub fn step<F>(&mut self, forward: &mut F, batch: &DpoBatch) -> Result<(DpoOutput, StepInfo)>
+    where
+        F: FnMut(&Tensor) -> Result<Tensor>,
+    {
+        let output = self.evaluate(forward, batch)?;
+        let info = self.trainer.backward_step(&output.loss)?;
+        Ok((output, info))
+    }
+
+    /// Run `epochs` passes over `batches`, calling `on_step` after each step.
+    pub fn train<F, C>(
+        &mut self,
+        forward: &mut F,
+        batches: &[DpoBatch],
+        epochs: usize,
+        mut on_step: C,
+    ) -> Result<()>
+    where
+        F: FnMut(&Tensor) -> Result<Tensor>,
+        C: FnMut(&StepInfo, &DpoOutput) -> Result<()>,
+    {
+        for _ in 0..epochs {
+            for batch in batches {
+                let (output, info) = self.step(forward, batch)?;
+                on_step(&info, &output)?;
+            }
+        }
+        Ok(())
+    }
+}
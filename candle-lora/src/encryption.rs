@@ -0,0 +1,78 @@
+//! Adapters encrypted at rest with AES-256-GCM, decrypted in memory only.
+//!
+//! An encrypted adapter file is the magic bytes `CLORAENC`, a 12 byte random nonce and the
+//! AES-256-GCM encryption of the safetensors file of the adapter, metadata included.
+
+use std::{collections::HashMap, path::Path};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use candle_core::{bail, Device, Result, Tensor};
+
+const MAGIC: &[u8; 8] = b"CLORAENC";
+const NONCE_LEN: usize = 12;
+
+/// Encrypt the bytes of a safetensors file with the 32 byte `key`.
+pub fn encrypt_safetensors(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let Ok(ciphertext) = cipher.encrypt(&nonce, data) else {
+        bail!("adapter encryption failed")
+    };
+    let mut encrypted = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+    encrypted.extend_from_slice(MAGIC);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(encrypted)
+}
+
+/// Decrypt an encrypted adapter into the bytes of its safetensors file. A wrong key or a
+/// tampered file is an error.
+pub fn decrypt_safetensors(encrypted: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+    let Some(rest) = encrypted.strip_prefix(MAGIC) else {
+        bail!("not an encrypted candle-lora adapter")
+    };
+    if rest.len() < NONCE_LEN {
+        bail!("truncated encrypted adapter")
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(data) => Ok(data),
+        Err(_) => bail!("cannot decrypt the adapter: wrong key or corrupted file"),
+    }
+}
+
+/// Save adapter tensors encrypted with the 32 byte `key`.
+pub fn save_encrypted<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    key: &[u8; 32],
+    path: P,
+) -> Result<()> {
+    let data = safetensors::serialize(tensors.iter(), &None)?;
+    std::fs::write(path, encrypt_safetensors(&data, key)?)?;
+    Ok(())
+}
+
+/// Encrypt an existing adapter file, e.g. the output of a PEFT converter, into `output`.
+pub fn encrypt_adapter_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    key: &[u8; 32],
+) -> Result<()> {
+    let data = std::fs::read(input)?;
+    std::fs::write(output, encrypt_safetensors(&data, key)?)?;
+    Ok(())
+}
+
+/// Load an encrypted adapter, the plaintext only ever being held in memory.
+pub fn load_encrypted<P: AsRef<Path>>(
+    path: P,
+    key: &[u8; 32],
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let data = decrypt_safetensors(&std::fs::read(path)?, key)?;
+    candle_core::safetensors::load_buffer(&data, device)
+}
@@ -0,0 +1,207 @@
+//! Numerical equivalence checks against the outputs of transformers and PEFT, with the
+//! `equivalence` feature.
+//!
+//! The reference outputs are exported from Python for the same base model, adapter and input
+//! ids, as safetensors: the `input_ids`, the `logits` of the last position and the
+//! `hidden_states.{i}` of `output_hidden_states=True`, the embeddings being `hidden_states.0`.
+//!
+//! ```python
+//! import torch
+//! from peft import PeftModel
+//! from safetensors.torch import save_file
+//! from transformers import AutoModelForCausalLM, AutoTokenizer
+//!
+//! base = AutoModelForCausalLM.from_pretrained(base_id, torch_dtype=torch.float32)
+//! model = PeftModel.from_pretrained(base, adapter_dir).eval()
+//! input_ids = AutoTokenizer.from_pretrained(base_id)(prompt, return_tensors="pt").input_ids
+//! with torch.no_grad():
+//!     out = model(input_ids, output_hidden_states=True)
+//! tensors = {"input_ids": input_ids, "logits": out.logits[:, -1]}
+//! for i, hidden in enumerate(out.hidden_states):
+//!     tensors[f"hidden_states.{i}"] = hidden
+//! save_file({k: v.contiguous() for k, v in tensors.items()}, "reference.safetensors")
+//! ```
+//!
+//! [`ReferenceOutputs::compare`] then reports the divergence of each output of the candle-lora
+//! model, the hidden states locating the first layer where the two stacks part ways.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use candle_core::{bail, DType, Device, Result, Tensor};
+
+/// The tolerance of a comparison: an output element `x` matches its reference `y` when
+/// `|x - y| <= atol + rtol * |y|`, as for `torch.allclose`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub atol: f64,
+    pub rtol: f64,
+}
+
+impl Tolerance {
+    pub fn new(atol: f64, rtol: f64) -> Self {
+        Self { atol, rtol }
+    }
+}
+
+impl Default for Tolerance {
+    /// A tolerance for f32 models. Half precision models need about `1e-1`.
+    fn default() -> Self {
+        Self::new(1e-3, 1e-3)
+    }
+}
+
+/// The outputs of transformers and PEFT to compare a model against.
+#[derive(Debug, Clone)]
+pub struct ReferenceOutputs {
+    tensors: HashMap<String, Tensor>,
+}
+
+impl ReferenceOutputs {
+    /// Load the reference outputs of the safetensors file `path`.
+    pub fn load<P: AsRef<Path>>(path: P, device: &Device) -> Result<Self> {
+        Ok(Self::from_tensors(candle_core::safetensors::load(
+            path, device,
+        )?))
+    }
+
+    pub fn from_tensors(tensors: HashMap<String, Tensor>) -> Self {
+        Self { tensors }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tensor> {
+        self.tensors.get(name)
+    }
+
+    /// The input ids the reference was computed for, as `u32`.
+    pub fn input_ids(&self) -> Result<Tensor> {
+        match self.get("input_ids") {
+            Some(ids) => ids.to_dtype(DType::U32),
+            None => bail!("the reference outputs have no input_ids"),
+        }
+    }
+
+    /// The `hidden_states.{i}` from 0 on, the embeddings then the output of each layer.
+    pub fn hidden_states(&self) -> Vec<&Tensor> {
+        (0..)
+            .map_while(|i| self.get(&format!("hidden_states.{i}")))
+            .collect()
+    }
+
+    /// Compare the `outputs` of a model, by reference name, e.g. `logits` or `hidden_states.3`,
+    /// to the reference, in the given order. Each output must have a reference of its shape.
+    pub fn compare(
+        &self,
+        outputs: &[(String, Tensor)],
+        tolerance: Tolerance,
+    ) -> Result<EquivalenceReport> {
+        let outputs = outputs
+            .iter()
+            .map(|(name, output)| {
+                let Some(reference) = self.get(name) else {
+                    bail!("no reference output named {name}")
+                };
+                if output.dims() != reference.dims() {
+                    bail!(
+                        "{name} is of shape {:?}, its reference of shape {:?}",
+                        output.dims(),
+                        reference.dims()
+                    )
+                }
+                Divergence::new(name, output, reference, tolerance)
+            })
+            .collect::<Result<_>>()?;
+        Ok(EquivalenceReport { tolerance, outputs })
+    }
+}
+
+/// How far an output is from its reference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub name: String,
+    /// The largest absolute difference.
+    pub max_abs: f64,
+    /// The mean absolute difference.
+    pub mean_abs: f64,
+    /// The number of elements out of the tolerance.
+    pub num_mismatched: usize,
+    pub num_elements: usize,
+}
+
+impl Divergence {
+    fn new(name: &str, output: &Tensor, reference: &Tensor, tolerance: Tolerance) -> Result<Self> {
+        let reference = reference.to_dtype(DType::F64)?;
+        let output = output.to_device(reference.device())?.to_dtype(DType::F64)?;
+        let diff = (output - &reference)?.abs()?;
+        let bound = ((reference.abs()? * tolerance.rtol)? + tolerance.atol)?;
+        let num_mismatched = diff
+            .gt(&bound)?
+            .to_dtype(DType::U32)?
+            .sum_all()?
+            .to_scalar::<u32>()? as usize;
+        Ok(Self {
+            name: name.to_string(),
+            max_abs: diff.max_all()?.to_scalar()?,
+            mean_abs: diff.mean_all()?.to_scalar()?,
+            num_mismatched,
+            num_elements: diff.elem_count(),
+        })
+    }
+
+    pub fn is_within_tolerance(&self) -> bool {
+        self.num_mismatched == 0
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: max abs {:.3e}, mean abs {:.3e}, {}/{} out of tolerance",
+            self.name, self.max_abs, self.mean_abs, self.num_mismatched, self.num_elements
+        )
+    }
+}
+
+/// The result of [`ReferenceOutputs::compare`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquivalenceReport {
+    pub tolerance: Tolerance,
+    /// The divergence of each output, in the order they were compared.
+    pub outputs: Vec<Divergence>,
+}
+
+impl EquivalenceReport {
+    pub fn is_equivalent(&self) -> bool {
+        self.outputs.iter().all(Divergence::is_within_tolerance)
+    }
+
+    /// The first output out of tolerance, e.g. the hidden states of the first layer where the
+    /// model parts ways with the reference.
+    pub fn first_divergence(&self) -> Option<&Divergence> {
+        self.outputs
+            .iter()
+            .find(|output| !output.is_within_tolerance())
+    }
+
+    /// An error naming the first output out of tolerance, if any.
+    pub fn into_result(self) -> Result<()> {
+        match self.first_divergence() {
+            Some(divergence) => bail!("the outputs diverge from the reference at {divergence}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for EquivalenceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "tolerance: atol {:e}, rtol {:e}",
+            self.tolerance.atol, self.tolerance.rtol
+        )?;
+        for output in &self.outputs {
+            writeln!(f, "{output}")?;
+        }
+        Ok(())
+    }
+}
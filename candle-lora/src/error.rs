@@ -0,0 +1,158 @@
+//! The errors of the conversion and loading of PEFT adapters.
+
+use std::path::PathBuf;
+
+use candle_core::{DType, Error};
+use thiserror::Error;
+
+use crate::MergeError;
+
+/// Why a PEFT adapter could not be converted or loaded, to branch on rather than parse error
+/// messages.
+///
+/// Converting it to a [`candle_core::Error`] with `?` keeps its message, but not its kind, except
+/// for the wrapped [`LoraError::Candle`] and [`LoraError::Io`] errors, which are unwrapped.
+#[derive(Error, Debug)]
+pub enum LoraError {
+    /// A PEFT directory without adapter weights.
+    #[error(
+        "no adapter weights found in {dir} (tried adapter_model.safetensors, \
+         adapter.safetensors, sharded adapter_model index files and adapter_model.bin)"
+    )]
+    MissingAdapterFile { dir: String },
+
+    /// A PEFT directory without `adapter_config.json`, where one is required.
+    #[error("{dir} has no adapter_config.json")]
+    MissingAdapterConfig { dir: String },
+
+    /// A tensor the shard index lists that its shard does not hold.
+    #[error("{name} is missing from its shard {shard}")]
+    MissingShardTensor { name: String, shard: String },
+
+    /// An adapter config or shard index that is not valid JSON for its structure.
+    #[error("invalid {}: {source}", path.display())]
+    InvalidJson {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    /// An adapter of another `peft_type` than the function converts.
+    #[error("expected a {expected} adapter, got a {found} adapter")]
+    UnsupportedPeftType { expected: String, found: String },
+
+    /// An adapter config with invalid hyperparameters or patterns.
+    #[error("{0}")]
+    InvalidConfig(String),
+
+    /// An adapter config whose `r` and `rank_pattern` give none of the ranks of the LoRA tensors.
+    #[error("adapter_config.json gives r = {r}, the LoRA tensors have ranks {ranks:?}")]
+    ConfigRankMismatch { r: usize, ranks: Vec<usize> },
+
+    /// A LoRA B whose rank is not that of its A.
+    #[error("the lora_B of {layer} is of rank {found}, its lora_A of rank {expected}")]
+    RankMismatch {
+        layer: String,
+        expected: usize,
+        found: usize,
+    },
+
+    /// A LoRA A or B without its pair, in strict mode.
+    #[error("{name} has no LoRA A or B to pair with")]
+    UnpairedTensor { name: String },
+
+    /// Tensors that are not LoRA tensors, in strict mode.
+    #[error(
+        "{} PEFT tensors are not LoRA tensors and cannot be converted: {}",
+        names.len(),
+        names.join(", ")
+    )]
+    UnconvertedTensors { names: Vec<String> },
+
+    /// LoRA modules the model has no layer for, in strict mode or when no module matches.
+    #[error(
+        "{} PEFT modules match no module of the model: {}",
+        modules.len(),
+        modules.join(", ")
+    )]
+    UnmatchedModules { modules: Vec<String> },
+
+    /// An adapter without tensors of its kind, e.g. `LoRA tensors` or `IA3 vectors`.
+    #[error("no {kind} found")]
+    NoAdapterTensors { kind: &'static str },
+
+    /// A tensor of the wrong shape.
+    #[error("{name} is of shape {found:?}, expected {expected:?}")]
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+
+    /// A scalar where LoRA weights are matrices or convolution kernels.
+    #[error("{name} is a scalar")]
+    ScalarTensor { name: String },
+
+    /// An adapter tensor that is not f32, f16 or bf16, e.g. f64 or quantized.
+    #[error("{name} is {}", unsupported_dtype(*dtype))]
+    UnsupportedDType {
+        name: String,
+        dtype: safetensors::Dtype,
+    },
+
+    /// A target dtype other than f32, f16 or bf16.
+    #[error("cannot convert adapters to {0:?}, only to f32, f16 or bf16")]
+    UnsupportedTargetDType(DType),
+
+    /// Two tensors converted to the same name.
+    #[error("{name} is given twice")]
+    DuplicateTensor { name: String },
+
+    /// A PEFT module mapped to the same module as another by a non-numbered key rule.
+    #[error("{peft_module} is mapped to {module} like another module, use a numbered rule")]
+    DuplicateMapping { peft_module: String, module: String },
+
+    /// A candle-lora tensor that is not a LoRA weight PEFT can load.
+    #[error("{name} is not a LoRA weight PEFT can load")]
+    NotLoraWeight { name: String },
+
+    /// An adapter whose number of LoRA modules is not the number of layers loading it.
+    #[error("the adapter has {modules} LoRA modules for {layers} layers")]
+    ModuleCountMismatch { modules: usize, layers: usize },
+
+    /// An adapter or layer the conversion does not support, e.g. Tied-LoRA layers loading a
+    /// PEFT adapter.
+    #[error("{0}")]
+    Unsupported(String),
+
+    #[error(transparent)]
+    Merge(#[from] MergeError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Candle(#[from] Error),
+}
+
+impl From<LoraError> for Error {
+    fn from(e: LoraError) -> Self {
+        match e {
+            LoraError::Candle(e) => e,
+            LoraError::Io(e) => Error::Io(e),
+            e => Error::wrap(e),
+        }
+    }
+}
+
+/// The message of [`LoraError::UnsupportedDType`], after the tensor name.
+fn unsupported_dtype(dtype: safetensors::Dtype) -> String {
+    match dtype {
+        safetensors::Dtype::F64 => {
+            "f64, cast f64 adapters to f32 first, e.g. with a ConversionHooks cast".to_string()
+        }
+        dtype => format!(
+            "{dtype:?}, quantized or integer adapters cannot be converted, dequantize them to \
+             f32, f16 or bf16 first"
+        ),
+    }
+}
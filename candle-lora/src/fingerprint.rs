@@ -0,0 +1,137 @@
+//! Fingerprints of the base model stored in the metadata of adapter files, so an adapter loaded
+//! onto another base model is rejected instead of silently producing garbage.
+
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+use candle_core::{bail, Device, Result, Tensor};
+use safetensors::{SafeTensors, View};
+use sha2::{Digest, Sha256};
+
+/// Safetensors metadata key of the base model fingerprint.
+pub const FINGERPRINT_METADATA_KEY: &str = "candle_lora.base_model_fingerprint";
+
+/// A SHA-256 fingerprint of a base model, `sha256:<hex digest>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BaseModelFingerprint(String);
+
+impl BaseModelFingerprint {
+    /// Fingerprint the names, dtypes, shapes and values of the base model weights.
+    ///
+    /// The order of `tensors` does not matter. All the weights are read, which takes a while
+    /// for large models: [`BaseModelFingerprint::from_config`] is cheaper but does not tell
+    /// apart two fine-tunes of the same architecture.
+    pub fn from_tensors(tensors: &HashMap<String, Tensor>) -> Self {
+        let mut names = tensors.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut hasher = Sha256::new();
+        for name in names {
+            let tensor = &tensors[name];
+            hasher.update(name.as_bytes());
+            hasher.update(format!("{:?}{:?}", tensor.dtype(), tensor.dims()).as_bytes());
+            hasher.update(View::data(&tensor));
+        }
+        Self::from_digest(hasher)
+    }
+
+    /// Fingerprint a model configuration, e.g. the `config.json` of a HuggingFace model. The
+    /// JSON is normalized first, so key order and whitespace do not matter.
+    pub fn from_config(config: &str) -> Result<Self> {
+        let config = match serde_json::from_str::<serde_json::Value>(config) {
+            Ok(config) => config,
+            Err(e) => bail!("invalid model config: {e}"),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(config.to_string().as_bytes());
+        Ok(Self::from_digest(hasher))
+    }
+
+    fn from_digest(hasher: Sha256) -> Self {
+        let hex = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        Self(format!("sha256:{hex}"))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for BaseModelFingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Save adapter tensors with the fingerprint of their base model in the file metadata.
+pub fn save_with_fingerprint<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    fingerprint: &BaseModelFingerprint,
+    path: P,
+) -> Result<()> {
+    let metadata = HashMap::from([(
+        FINGERPRINT_METADATA_KEY.to_string(),
+        fingerprint.to_string(),
+    )]);
+    safetensors::serialize_to_file(tensors.iter(), &Some(metadata), path.as_ref())?;
+    Ok(())
+}
+
+/// Add the fingerprint to an existing adapter file, e.g. the output of a PEFT converter,
+/// keeping its other metadata.
+pub fn stamp_fingerprint<P: AsRef<Path>>(
+    path: P,
+    fingerprint: &BaseModelFingerprint,
+) -> Result<()> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+    let (_, header) = SafeTensors::read_metadata(&data)?;
+    let mut metadata = header.metadata().clone().unwrap_or_default();
+    metadata.insert(
+        FINGERPRINT_METADATA_KEY.to_string(),
+        fingerprint.to_string(),
+    );
+    let tensors = candle_core::safetensors::load_buffer(&data, &Device::Cpu)?;
+    safetensors::serialize_to_file(tensors.iter(), &Some(metadata), path)?;
+    Ok(())
+}
+
+/// The base model fingerprint stored in an adapter file, if any.
+pub fn read_fingerprint<P: AsRef<Path>>(path: P) -> Result<Option<BaseModelFingerprint>> {
+    let data = std::fs::read(path)?;
+    fingerprint_of_buffer(&data)
+}
+
+fn fingerprint_of_buffer(data: &[u8]) -> Result<Option<BaseModelFingerprint>> {
+    let (_, header) = SafeTensors::read_metadata(data)?;
+    Ok(header
+        .metadata()
+        .as_ref()
+        .and_then(|metadata| metadata.get(FINGERPRINT_METADATA_KEY))
+        .map(|fingerprint| BaseModelFingerprint(fingerprint.clone())))
+}
+
+/// Load an adapter file, checking that it was made for the base model with fingerprint
+/// `expected`.
+///
+/// Adapters without a fingerprint, e.g. from older versions, are loaded unless `require` is
+/// set. An adapter made for another base model is an error.
+pub fn load_verified<P: AsRef<Path>>(
+    path: P,
+    expected: &BaseModelFingerprint,
+    require: bool,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+    match fingerprint_of_buffer(&data)? {
+        Some(fingerprint) if &fingerprint != expected => bail!(
+            "adapter {} was made for the base model {fingerprint}, not {expected}",
+            path.display()
+        ),
+        None if require => bail!("adapter {} has no base model fingerprint", path.display()),
+        _ => candle_core::safetensors::load_buffer(&data, device),
+    }
+}
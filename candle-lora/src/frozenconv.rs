@@ -45,7 +45,7 @@ impl Module for FrozenConv1d {
 }
 
 impl Saveable for FrozenConv1d {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for frozen layers, only for candle_lora layers.");
     }
 }
@@ -102,7 +102,7 @@ impl Module for FrozenConv2d {
 }
 
 impl Saveable for FrozenConv2d {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for frozen layers, only for candle_lora layers.");
     }
 }
@@ -30,7 +30,7 @@ impl crate::Module for FrozenEmbedding {
 }
 
 impl Saveable for FrozenEmbedding {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for frozen layers, only for candle_lora layers.");
     }
 }
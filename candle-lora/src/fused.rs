@@ -0,0 +1,67 @@
+//! Forward passes of frozen adapters from a precomputed delta weight, cached per device.
+//!
+//! The factored forward `W x + scale * B (A x)` launches two extra matmuls and an add per layer,
+//! which dominates at batch size 1 on GPUs where the launch overhead outweighs the small
+//! matmuls, e.g. on Metal. A frozen adapter does not change between forward passes, so its delta
+//! `scale * B A`, or the adapted weight `W + scale * B A`, is computed once per device instead.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use candle_core::{Device, DeviceLocation, Result, Tensor};
+
+/// How a LoRA layer applies a frozen adapter in its forward pass, see
+/// [`crate::LoraLinear::set_forward`].
+///
+/// Trainable adapters, whose weights are variables, layers in training mode, DoRA layers, and
+/// the weighted and per-element adapters of [`crate::MultiAdapter`] always use the factored
+/// forward, since their delta changes between passes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoraForward {
+    /// `W x + scale * B (A x)`, with no precomputed weight. Uses the least memory and the least
+    /// compute for large batches.
+    #[default]
+    Factored,
+    /// `W x + D x`, the delta `D = scale * B A` being computed once per device. One matmul per
+    /// layer fewer than the factored forward, for an extra weight of the size of `W`.
+    CachedDelta,
+    /// `(W + D) x`, the delta being added to the base weight once per device, so the add is
+    /// fused into the base matmul: one matmul per layer, as for a merged layer, but the adapter
+    /// can still be swapped, disabled with [`crate::disable_adapters`] or unmerged.
+    FusedWeight,
+}
+
+/// The weights precomputed from a frozen adapter, by device. Clones of a layer share the cache
+/// until one of them clears it, which gives it a cache of its own.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WeightCache {
+    weights: Arc<Mutex<HashMap<DeviceLocation, Tensor>>>,
+}
+
+impl WeightCache {
+    /// The weight cached for `device`, computed by `compute` and moved to `device` if there is
+    /// none.
+    pub(crate) fn get_or_insert(
+        &self,
+        device: &Device,
+        compute: impl FnOnce() -> Result<Tensor>,
+    ) -> Result<Tensor> {
+        let mut weights = self
+            .weights
+            .lock()
+            .expect("the weight cache is not poisoned");
+        if let Some(weight) = weights.get(&device.location()) {
+            return Ok(weight.clone());
+        }
+        let weight = compute()?.detach().to_device(device)?;
+        weights.insert(device.location(), weight.clone());
+        Ok(weight)
+    }
+
+    /// Drop the cached weights, after the adapter or the base weight changed.
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
@@ -0,0 +1,241 @@
+//! Export of merged weights as GGUF, quantized for llama.cpp and candle's quantized models.
+//!
+//! The transformers names of Llama-style models are renamed to the GGUF names llama.cpp loads,
+//! e.g. `model.layers.0.self_attn.q_proj.weight` to `blk.0.attn_q.weight`, and the matrices are
+//! quantized with the mix of a llama.cpp file type, the vectors staying f32.
+
+use std::{collections::HashMap, fs::File, io::BufWriter, str::FromStr};
+
+use candle_core::{
+    bail,
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Result, Tensor,
+};
+
+use crate::structural_order;
+
+/// The quantization of the matrices of an exported GGUF file, named after llama.cpp's file types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgufQuantization {
+    /// Every matrix in f16.
+    F16,
+    /// Every matrix in 8-bit blocks of 32 weights.
+    Q8_0,
+    /// 4-bit k-quants, with the value projections and the down projections of the first,
+    /// last and every third layer, and the output layer, in 6-bit k-quants.
+    Q4KM,
+}
+
+impl GgufQuantization {
+    /// llama.cpp's `general.file_type` of the quantization.
+    fn file_type(self) -> u32 {
+        match self {
+            Self::F16 => 1,
+            Self::Q8_0 => 7,
+            Self::Q4KM => 15,
+        }
+    }
+
+    /// The dtype of the GGUF tensor `name` of `num_layers` layers, before the fallbacks for
+    /// rows not a multiple of the block size.
+    fn dtype(self, name: &str, num_layers: usize) -> GgmlDType {
+        match self {
+            Self::F16 => GgmlDType::F16,
+            Self::Q8_0 => GgmlDType::Q8_0,
+            Self::Q4KM => {
+                let more_bits = match block_index(name) {
+                    Some((layer, rest)) if rest.starts_with("attn_v.") => {
+                        use_more_bits(layer, num_layers)
+                    }
+                    Some((layer, rest)) if rest.starts_with("ffn_down.") => {
+                        use_more_bits(layer, num_layers)
+                    }
+                    _ => name == "output.weight",
+                };
+                if more_bits {
+                    GgmlDType::Q6K
+                } else {
+                    GgmlDType::Q4K
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for GgufQuantization {
+    type Err = candle_core::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "F16" => Ok(Self::F16),
+            "Q8_0" => Ok(Self::Q8_0),
+            "Q4_K_M" => Ok(Self::Q4KM),
+            _ => bail!("unknown GGUF quantization {s}, expected F16, Q8_0 or Q4_K_M"),
+        }
+    }
+}
+
+/// llama.cpp's choice of the layers whose value and down projections keep more bits: the first
+/// and last eighth of the layers and every third layer in between.
+fn use_more_bits(layer: usize, num_layers: usize) -> bool {
+    layer < num_layers / 8 || layer >= 7 * num_layers / 8 || (layer - num_layers / 8) % 3 == 2
+}
+
+/// The layer of the GGUF tensor `blk.<layer>.<rest>` and `rest`.
+fn block_index(name: &str) -> Option<(usize, &str)> {
+    let (layer, rest) = name.strip_prefix("blk.")?.split_once('.')?;
+    Some((layer.parse().ok()?, rest))
+}
+
+/// The GGUF name of the transformers tensor `name` of a Llama-style model, `name` itself when it
+/// has no GGUF name.
+pub fn gguf_tensor_name(name: &str) -> String {
+    let (module, suffix) = match name.rsplit_once('.') {
+        Some((module, suffix @ ("weight" | "bias"))) => (module, suffix),
+        _ => return name.to_string(),
+    };
+    let renamed = match module {
+        "model.embed_tokens" => Some("token_embd".to_string()),
+        "model.norm" => Some("output_norm".to_string()),
+        "lm_head" => Some("output".to_string()),
+        _ => module
+            .strip_prefix("model.layers.")
+            .and_then(|rest| rest.split_once('.'))
+            .and_then(|(layer, rest)| {
+                let renamed = match rest {
+                    "self_attn.q_proj" => "attn_q",
+                    "self_attn.k_proj" => "attn_k",
+                    "self_attn.v_proj" => "attn_v",
+                    "self_attn.o_proj" => "attn_output",
+                    "mlp.gate_proj" => "ffn_gate",
+                    "mlp.up_proj" => "ffn_up",
+                    "mlp.down_proj" => "ffn_down",
+                    "input_layernorm" => "attn_norm",
+                    "post_attention_layernorm" => "ffn_norm",
+                    _ => return None,
+                };
+                Some(format!("blk.{layer}.{renamed}"))
+            }),
+    };
+    match renamed {
+        Some(module) => format!("{module}.{suffix}"),
+        None => name.to_string(),
+    }
+}
+
+/// Save the weights `tensors`, e.g. merged by [`crate::merge_peft_adapter_with_config`], as a
+/// GGUF file quantized with `quantization`.
+///
+/// The tensors are renamed with [`gguf_tensor_name`]. Matrices are quantized, falling back to
+/// Q8_0 and then f16 when their rows are not a multiple of the block size of their dtype, and
+/// vectors such as the norms are kept in f32. `metadata` is written as is, and should hold the
+/// hyperparameters and tokenizer llama.cpp needs, e.g. the metadata of the GGUF file of the base
+/// model; `general.file_type` and `general.quantization_version` are added when missing. With the
+/// `llama.attention.head_count` metadata, the query and key projections are permuted to the
+/// rotary embedding layout of llama.cpp, as its conversion script does.
+///
+/// # Example
+/// ```no_run
+/// use std::collections::HashMap;
+///
+/// use candle_core::{quantized::gguf_file, Device};
+/// use candle_lora::{merge_peft_adapter, save_gguf, GgufQuantization};
+///
+/// let base = candle_core::safetensors::load("model.safetensors", &Device::Cpu).unwrap();
+/// let adapter = candle_core::safetensors::load("adapter_model.safetensors", &Device::Cpu).unwrap();
+/// let merged = merge_peft_adapter(&base, &adapter, 16.).unwrap();
+///
+/// let mut file = std::fs::File::open("base_model.gguf").unwrap();
+/// let content = gguf_file::Content::read(&mut file).unwrap();
+/// let metadata = content
+///     .metadata
+///     .iter()
+///     .map(|(key, value)| (key.as_str(), value))
+///     .collect::<Vec<_>>();
+/// save_gguf(&merged, "merged.Q4_K_M.gguf", GgufQuantization::Q4KM, &metadata).unwrap();
+/// ```
+pub fn save_gguf(
+    tensors: &HashMap<String, Tensor>,
+    output_path: &str,
+    quantization: GgufQuantization,
+    metadata: &[(&str, &gguf_file::Value)],
+) -> Result<()> {
+    let head_count = metadata_u32(metadata, "llama.attention.head_count")?;
+    let head_count_kv = metadata_u32(metadata, "llama.attention.head_count_kv")?.or(head_count);
+
+    let mut renamed = tensors
+        .iter()
+        .map(|(name, tensor)| (gguf_tensor_name(name), tensor))
+        .collect::<Vec<_>>();
+    renamed.sort_by(|a, b| structural_order(&a.0, &b.0));
+    let num_layers = renamed
+        .iter()
+        .filter_map(|(name, _)| block_index(name))
+        .map(|(layer, _)| layer + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut qtensors = Vec::with_capacity(renamed.len());
+    for (name, tensor) in &renamed {
+        let mut tensor = tensor.to_dtype(DType::F32)?;
+        let heads = match block_index(name) {
+            Some((_, "attn_q.weight")) => head_count,
+            Some((_, "attn_k.weight")) => head_count_kv,
+            _ => None,
+        };
+        if let Some(heads) = heads {
+            tensor = permute_rotary(&tensor, heads as usize)?;
+        }
+        let dtype = if tensor.rank() < 2 {
+            GgmlDType::F32
+        } else {
+            let row = tensor.dims()[tensor.rank() - 1];
+            [quantization.dtype(name, num_layers), GgmlDType::Q8_0]
+                .into_iter()
+                .find(|dtype| row % dtype.block_size() == 0)
+                .unwrap_or(GgmlDType::F16)
+        };
+        qtensors.push(QTensor::quantize(&tensor, dtype)?);
+    }
+
+    let file_type = gguf_file::Value::U32(quantization.file_type());
+    let quantization_version = gguf_file::Value::U32(2);
+    let mut metadata = metadata.to_vec();
+    for (key, value) in [
+        ("general.file_type", &file_type),
+        ("general.quantization_version", &quantization_version),
+    ] {
+        if !metadata.iter().any(|(k, _)| *k == key) {
+            metadata.push((key, value));
+        }
+    }
+    let tensors = renamed
+        .iter()
+        .zip(&qtensors)
+        .map(|((name, _), qtensor)| (name.as_str(), qtensor))
+        .collect::<Vec<_>>();
+    let mut file = BufWriter::new(File::create(output_path)?);
+    gguf_file::write(&mut file, &metadata, &tensors)
+}
+
+/// The u32 metadata `key`, if any.
+fn metadata_u32(metadata: &[(&str, &gguf_file::Value)], key: &str) -> Result<Option<u32>> {
+    metadata
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value.to_u32())
+        .transpose()
+}
+
+/// The query or key projection `weight` of `heads` heads with the halves of each head's rotary
+/// dimensions interleaved, the layout of llama.cpp's rotary embeddings.
+fn permute_rotary(weight: &Tensor, heads: usize) -> Result<Tensor> {
+    let (out_features, in_features) = weight.dims2()?;
+    if heads == 0 || out_features % (2 * heads) != 0 {
+        bail!("{out_features} features cannot be split in {heads} rotary heads")
+    }
+    weight
+        .reshape((heads, 2, out_features / heads / 2, in_features))?
+        .transpose(1, 2)?
+        .reshape((out_features, in_features))
+}
@@ -0,0 +1,141 @@
+//! Download of PEFT adapters from the Hugging Face Hub, with the `hub` feature.
+//!
+//! The adapter files are kept in the hf-hub cache (`HF_HOME`), and converted adapters are cached
+//! next to them, in the snapshot directory of the downloaded revision, so each revision is
+//! downloaded and converted once.
+
+use std::path::{Path, PathBuf};
+
+use candle_core::Device;
+use hf_hub::{api::sync::Api, Repo, RepoType};
+
+use crate::{
+    convert_peft_dir_to_candle_lora, load_peft_adapter,
+    peft_convert::{read_peft_config, read_shard_index, shard_files},
+    ConversionReport, LoraConfig, LoraError, NewLayers, PeftConfig,
+};
+
+/// A PEFT adapter directory, downloaded from the Hub or local, with its `adapter_config.json`.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::PeftAdapter;
+///
+/// let adapter = PeftAdapter::from_hub("user/my-lora-adapter", None).unwrap();
+/// let converted = adapter.convert("lora_llama", &Device::Cpu).unwrap();
+/// let config = adapter.lora_config();
+/// ```
+#[derive(Debug, Clone)]
+pub struct PeftAdapter {
+    dir: PathBuf,
+    config: PeftConfig,
+}
+
+impl PeftAdapter {
+    /// Download the adapter of the model repository `repo_id` at `revision`, `main` by default:
+    /// `adapter_config.json` and `adapter_model.safetensors`, its shards or `adapter_model.bin`.
+    /// The token of the hf-hub cache is used for private repositories.
+    pub fn from_hub(repo_id: &str, revision: Option<&str>) -> std::result::Result<Self, LoraError> {
+        let api = Api::new().map_err(candle_core::Error::wrap)?;
+        let repo = api.repo(Repo::with_revision(
+            repo_id.to_string(),
+            RepoType::Model,
+            revision.unwrap_or("main").to_string(),
+        ));
+        let get = |file: &str| repo.get(file).map_err(candle_core::Error::wrap);
+
+        let config_path = get("adapter_config.json")?;
+        if repo.get("adapter_model.safetensors").is_err() {
+            match repo.get("adapter_model.safetensors.index.json") {
+                Ok(index_path) => {
+                    for shard in shard_files(&read_shard_index(&index_path)?) {
+                        get(shard)?;
+                    }
+                }
+                Err(_) => {
+                    get("adapter_model.bin")?;
+                }
+            }
+        }
+        let Some(dir) = config_path.parent() else {
+            let message = format!("{} has no parent directory", config_path.display());
+            return Err(candle_core::Error::msg(message).into());
+        };
+        Self::from_dir(dir)
+    }
+
+    /// The adapter of a local PEFT directory, which must have an `adapter_config.json`.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> std::result::Result<Self, LoraError> {
+        let dir = dir.as_ref().to_path_buf();
+        let Some(path) = dir.to_str() else {
+            let message = format!("{} is not a UTF-8 path", dir.display());
+            return Err(candle_core::Error::msg(message).into());
+        };
+        let Some(config) = read_peft_config(path)? else {
+            return Err(LoraError::MissingAdapterConfig {
+                dir: path.to_string(),
+            });
+        };
+        Ok(Self { dir, config })
+    }
+
+    /// The directory of the adapter files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn config(&self) -> &PeftConfig {
+        &self.config
+    }
+
+    /// The LoRA config of the adapter, from its `r`, `lora_alpha`, `lora_dropout` and
+    /// `use_rslora`.
+    pub fn lora_config(&self) -> LoraConfig {
+        let dropout = Some(self.config.lora_dropout as f32).filter(|&dropout| dropout > 0.);
+        LoraConfig::new(self.config.r, self.config.lora_alpha, dropout)
+            .with_rslora(self.config.use_rslora)
+    }
+
+    /// Convert the adapter to candle-lora format with `prefix`, see
+    /// [`convert_peft_dir_to_candle_lora`], and return the path of the converted file.
+    ///
+    /// The converted file, `candle_lora.<prefix>.safetensors` in the adapter directory, is
+    /// reused when it exists.
+    pub fn convert(
+        &self,
+        prefix: &str,
+        device: &Device,
+    ) -> std::result::Result<PathBuf, LoraError> {
+        let path = self.dir.join(format!("candle_lora.{prefix}.safetensors"));
+        if path.exists() {
+            return Ok(path);
+        }
+        // Written under a temporary name first, so an interrupted conversion is not reused.
+        let partial = self
+            .dir
+            .join(format!("candle_lora.{prefix}.safetensors.partial"));
+        convert_peft_dir_to_candle_lora(
+            self.dir_str(),
+            &partial.to_string_lossy(),
+            prefix,
+            device,
+        )?;
+        std::fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+
+    /// Load the adapter into the converted layers `new` without writing a converted file, see
+    /// [`load_peft_adapter`].
+    pub fn load<T: Eq + PartialEq + std::hash::Hash>(
+        &self,
+        new: &mut NewLayers<T>,
+        device: &Device,
+    ) -> std::result::Result<ConversionReport, LoraError> {
+        load_peft_adapter(self.dir_str(), new, device)
+    }
+
+    fn dir_str(&self) -> &str {
+        self.dir.to_str().expect("checked by from_dir")
+    }
+}
@@ -0,0 +1,218 @@
+//! (IA)³, adapters that rescale activations with learned vectors, see "Few-Shot
+//! Parameter-Efficient Fine-Tuning is Better and Cheaper than In-Context Learning" Liu et al.
+//! 2022 <https://arxiv.org/abs/2205.05638>.
+//!
+//! An IA3 layer multiplies the outputs of its base layer by a vector `l`, or its inputs for the
+//! feedforward layers, the `l` vectors of PEFT's `feedforward_modules`. The vector is stored as
+//! `l{id}.weight` and starts at ones, so a fresh layer computes its base layer.
+
+use std::{collections::HashMap, sync::Arc};
+
+use candle_core::{bail, Module, Result, Shape, Tensor};
+use candle_nn::{init, VarBuilder};
+use either::Either;
+
+use crate::{
+    adapters_enabled,
+    frozenlinear::FrozenLinear,
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterSwap, LinearLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError, Saveable,
+    Trainable,
+};
+
+#[derive(Clone, Debug)]
+/// Configuration for Ia3Linear
+pub struct Ia3LinearConfig {
+    in_features: usize,
+    out_features: usize,
+    feedforward: bool,
+}
+
+impl Ia3LinearConfig {
+    pub fn new(in_features: usize, out_features: usize) -> Self {
+        Ia3LinearConfig {
+            in_features,
+            out_features,
+            feedforward: false,
+        }
+    }
+
+    /// Rescale the inputs of the layer rather than its outputs, as PEFT does for its
+    /// `feedforward_modules` (e.g. `down_proj`).
+    pub fn with_feedforward(mut self) -> Self {
+        self.feedforward = true;
+        self
+    }
+}
+
+/// An IA3 adapter on a linear layer.
+#[derive(Debug, Clone)]
+pub struct Ia3Linear {
+    old: Arc<FrozenLinear>,
+    /// The base layer before merging, restored when unmerging.
+    unmerged: Option<Arc<FrozenLinear>>,
+    scaling: Tensor,
+    feedforward: bool,
+    merged: bool,
+    prefix: String,
+    id: usize,
+}
+
+impl Ia3Linear {
+    pub fn new(
+        old: &dyn LinearLayerLike,
+        ia3_config: &Ia3LinearConfig,
+        vb: &VarBuilder,
+        id: usize,
+    ) -> Result<Self> {
+        let (out_features, in_features) = old.weight().dims2()?;
+        let features = (ia3_config.in_features, ia3_config.out_features);
+        if features != (in_features, out_features) {
+            let layer = (in_features, out_features);
+            bail!("features {features:?} do not match the layer's {layer:?}");
+        }
+        let size = match ia3_config.feedforward {
+            true => in_features,
+            false => out_features,
+        };
+        let scaling =
+            vb.pp(format!("l{id}"))
+                .get_with_hints(size, "weight", init::Init::Const(1.))?;
+
+        Ok(Ia3Linear {
+            old: Arc::new(FrozenLinear::new_from_linear(old)?),
+            unmerged: None,
+            scaling,
+            feedforward: ia3_config.feedforward,
+            merged: false,
+            prefix: vb.prefix(),
+            id,
+        })
+    }
+
+    /// The scaling vector `l`, over the inputs of feedforward layers and the outputs otherwise.
+    pub fn scaling(&self) -> &Tensor {
+        &self.scaling
+    }
+
+    pub fn is_feedforward(&self) -> bool {
+        self.feedforward
+    }
+
+    /// The base layer the adapter applies to, before merging.
+    fn base(&self) -> &FrozenLinear {
+        self.unmerged.as_deref().unwrap_or(&self.old)
+    }
+
+    /// The base weight rescaled by the adapter.
+    fn scaled_weight(&self) -> Result<Tensor> {
+        let weight = self.base().weight();
+        let scaling = self.scaling.to_dtype(weight.dtype())?;
+        match self.feedforward {
+            true => weight.broadcast_mul(&scaling.unsqueeze(0)?),
+            false => weight.broadcast_mul(&scaling.unsqueeze(1)?),
+        }
+    }
+}
+
+impl Merge for Ia3Linear {
+    /// The weight delta `W diag(l) - W`, or `diag(l) W - W`. Merging also rescales the bias of
+    /// layers whose outputs are rescaled.
+    fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
+        self.scaled_weight()
+            .and_then(|scaled| scaled - self.base().weight())
+            .map_err(Either::Right)
+    }
+
+    fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if self.merged {
+            Err(Either::Left(MergeError::AlreadyMerged))
+        } else {
+            let bias = match (self.old.bias(), self.feedforward) {
+                (Some(bias), false) => Some(
+                    self.scaling
+                        .to_dtype(bias.dtype())
+                        .and_then(|scaling| bias * scaling)
+                        .map_err(Either::Right)?,
+                ),
+                (bias, _) => bias.cloned(),
+            };
+            let merged = self
+                .scaled_weight()
+                .and_then(|weight| FrozenLinear::new(weight, bias))
+                .map_err(Either::Right)?;
+            self.unmerged = Some(std::mem::replace(&mut self.old, Arc::new(merged)));
+            self.merged = true;
+            Ok(())
+        }
+    }
+
+    fn unmerge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        match self.unmerged.take() {
+            Some(old) if self.merged => {
+                self.old = old;
+                self.merged = false;
+                Ok(())
+            }
+            _ => Err(Either::Left(MergeError::NotMerged)),
+        }
+    }
+}
+
+impl AdapterSwap for Ia3Linear {
+    /// Replace the scaling vector by that of another adapter, `config` is not used.
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        _config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let scaling = adapter_vb(vb, &self.prefix, &format!("l{}", self.id))
+            .get(self.scaling.shape(), "weight")
+            .map_err(Either::Right)?;
+        swap_weights(self, self.merged, |layer| layer.scaling = scaling)
+    }
+}
+
+impl Module for Ia3Linear {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        if self.merged || !adapters_enabled() {
+            return self.old.forward(input);
+        }
+        let scaling = self.scaling.to_dtype(input.dtype())?;
+        match self.feedforward {
+            true => self.old.forward(&input.broadcast_mul(&scaling)?),
+            false => self.old.forward(input)?.broadcast_mul(&scaling),
+        }
+    }
+}
+
+impl Saveable for Ia3Linear {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        accum.insert(
+            self.prefix.clone() + &format!(".l{}.weight", self.id),
+            self.scaling.clone(),
+        );
+        Ok(())
+    }
+}
+
+impl Trainable for Ia3Linear {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        vec![(
+            weight_name(&self.prefix, &format!("l{}", self.id)),
+            self.scaling.clone(),
+        )]
+    }
+}
+
+impl LinearLayerLike for Ia3Linear {
+    fn bias(&self) -> Option<&Tensor> {
+        self.old.bias()
+    }
+    fn weight(&self) -> &Tensor {
+        self.old.weight()
+    }
+    fn shape(&self) -> &Shape {
+        self.old.shape()
+    }
+}
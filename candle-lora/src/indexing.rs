@@ -0,0 +1,91 @@
+//! Deterministic ordering of the adapted layers, from which the `a{idx}`/`b{idx}` indices of the
+//! LoRA weights are derived both when converting adapters and when loading them into a model.
+//!
+//! Names are compared component by component (split on `.`): layer numbers numerically, known
+//! module names in the order the model runs them (`q_proj` before `k_proj`, attention before the
+//! MLP), and any other component, such as a `model` wrapper, by name before the known ones.
+//! `layers.2` thus comes before `layers.10`, which a lexicographic sort gets wrong, and `lm_head`
+//! after `model.layers`.
+
+use std::{cmp::Ordering, collections::HashMap, fmt::Display};
+
+/// Module names in the order of the forward pass of the common architectures.
+const MODULE_ORDER: &[&str] = &[
+    "embed_tokens",
+    "wte",
+    "word_embeddings",
+    "embeddings",
+    "layers",
+    "h",
+    "blocks",
+    "self_attn",
+    "attention",
+    "attn",
+    "q_proj",
+    "k_proj",
+    "v_proj",
+    "qkv_proj",
+    "query",
+    "key",
+    "value",
+    "query_key_value",
+    "c_attn",
+    "Wqkv",
+    "o_proj",
+    "out_proj",
+    "dense",
+    "mlp",
+    "feed_forward",
+    "ffn",
+    "gate_proj",
+    "up_proj",
+    "gate_up_proj",
+    "w1",
+    "w3",
+    "fc1",
+    "c_fc",
+    "dense_h_to_4h",
+    "down_proj",
+    "w2",
+    "fc2",
+    "c_proj",
+    "dense_4h_to_h",
+    "lm_head",
+];
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Component<'a> {
+    Index(u64),
+    Name(&'a str),
+    Module(usize),
+}
+
+fn components(name: &str) -> impl Iterator<Item = Component<'_>> {
+    name.split('.').map(|part| {
+        if let Ok(index) = part.parse() {
+            Component::Index(index)
+        } else if let Some(position) = MODULE_ORDER.iter().position(|module| *module == part) {
+            Component::Module(position)
+        } else {
+            Component::Name(part)
+        }
+    })
+}
+
+/// Compare two layer names by their position in the model, see the module documentation.
+pub fn structural_order(a: &str, b: &str) -> Ordering {
+    components(a).cmp(components(b)).then_with(|| a.cmp(b))
+}
+
+/// The entries of `layers` sorted by the structural order of their names.
+pub(crate) fn in_structural_order<T: Display, L>(layers: HashMap<T, L>) -> Vec<(T, L)> {
+    let mut layers = layers
+        .into_iter()
+        .map(|(name, layer)| (name.to_string(), name, layer))
+        .collect::<Vec<_>>();
+    layers.sort_by(|a, b| structural_order(&a.0, &b.0));
+    layers
+        .into_iter()
+        .map(|(_, name, layer)| (name, layer))
+        .collect()
+}
@@ -0,0 +1,103 @@
+//! Declarative PEFT to candle-lora key mapping, loaded from a JSON or YAML rules file so new
+//! model families can be converted without writing Rust.
+//!
+//! ```yaml
+//! rules:
+//!   # Traced layers: one pair per module, stored as `<template>.a0.weight`.
+//!   - pattern: '^base_model\.model\.transformer\.h\.(\d+)\.attn\.c_attn$'
+//!     template: 'model.layers.$1.self_attn.qkv_proj.traced_lora_linear'
+//!   # Layers numbered in the structural order of the model: `lora_mlp.a{idx}.weight`.
+//!   - pattern: '\.mlp\.'
+//!     template: 'lora_mlp'
+//!     numbered: true
+//!   # No template: the matching modules are dropped.
+//!   - pattern: 'lm_head'
+//! ```
+
+use std::path::Path;
+
+use candle_core::{bail, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+/// A rule mapping the PEFT modules matching `pattern` to the candle-lora module `template`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyRule {
+    /// Regular expression matched against the PEFT module name, e.g.
+    /// `base_model.model.model.layers.0.self_attn.q_proj`.
+    pub pattern: String,
+    /// The candle-lora module name, with `$1` or `${name}` for the capture groups of the
+    /// pattern. `None` drops the matching modules.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Whether the modules mapped to the same name get increasing `a{idx}`/`b{idx}` indices,
+    /// instead of all being stored as `a0`/`b0`.
+    #[serde(default)]
+    pub numbered: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    rules: Vec<KeyRule>,
+}
+
+/// An ordered list of [`KeyRule`]s, the first matching rule applying to each module. Modules
+/// matching no rule are dropped.
+#[derive(Debug, Clone)]
+pub struct KeyRules {
+    rules: Vec<(Regex, KeyRule)>,
+}
+
+impl KeyRules {
+    pub fn new(rules: Vec<KeyRule>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| match Regex::new(&rule.pattern) {
+                Ok(regex) => Ok((regex, rule)),
+                Err(e) => bail!("invalid key rule pattern {}: {e}", rule.pattern),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn from_json_str(rules: &str) -> Result<Self> {
+        match serde_json::from_str::<RulesFile>(rules) {
+            Ok(file) => Self::new(file.rules),
+            Err(e) => bail!("invalid key rules: {e}"),
+        }
+    }
+
+    pub fn from_yaml_str(rules: &str) -> Result<Self> {
+        match serde_yaml::from_str::<RulesFile>(rules) {
+            Ok(file) => Self::new(file.rules),
+            Err(e) => bail!("invalid key rules: {e}"),
+        }
+    }
+
+    /// Load a rules file, parsed as YAML for a `.yaml` or `.yml` extension and as JSON otherwise.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let rules = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::from_yaml_str(&rules),
+            _ => Self::from_json_str(&rules),
+        }
+    }
+
+    /// Whether a rule matches the PEFT `module`, mapping or dropping it.
+    pub fn matches(&self, module: &str) -> bool {
+        self.rules.iter().any(|(regex, _)| regex.is_match(module))
+    }
+
+    /// The candle-lora module of the PEFT `module` and whether it is numbered, `None` if the
+    /// module is dropped or matches no rule.
+    pub fn resolve(&self, module: &str) -> Option<(String, bool)> {
+        let (captures, rule) = self
+            .rules
+            .iter()
+            .find_map(|(regex, rule)| Some((regex.captures(module)?, rule)))?;
+        let mut target = String::new();
+        captures.expand(rule.template.as_ref()?, &mut target);
+        Some((target, rule.numbered))
+    }
+}
@@ -0,0 +1,223 @@
+//! Conversion of the LoRA checkpoints of Stable Diffusion models trained with kohya's sd-scripts
+//! and shared on civitai, whose tensors are named `lora_unet_<module>` and `lora_te_<module>`
+//! with the dots of the module paths replaced by underscores.
+
+use std::collections::{BTreeMap, HashMap};
+
+use candle_core::{bail, DType, Device, Result, Tensor};
+
+use crate::{structural_order, ConversionReport};
+
+/// The kohya prefixes and the candle-lora components they are converted to, the text encoder
+/// prefixes of SDXL first.
+const COMPONENTS: [(&str, &str); 4] = [
+    ("lora_unet_", "unet"),
+    ("lora_te1_", "text_encoder"),
+    ("lora_te2_", "text_encoder_2"),
+    ("lora_te_", "text_encoder"),
+];
+
+/// The module names of the diffusers and original Stable Diffusion UNets and of the CLIP text
+/// encoders that contain underscores, restored as one path component.
+const COMPOUND_NAMES: [&str; 35] = [
+    "down_blocks",
+    "up_blocks",
+    "mid_block",
+    "transformer_blocks",
+    "proj_in",
+    "proj_out",
+    "to_q",
+    "to_k",
+    "to_v",
+    "to_out",
+    "add_k_proj",
+    "add_v_proj",
+    "time_emb_proj",
+    "time_embedding",
+    "time_embed",
+    "add_embedding",
+    "label_emb",
+    "linear_1",
+    "linear_2",
+    "conv_in",
+    "conv_out",
+    "conv_shortcut",
+    "text_model",
+    "self_attn",
+    "q_proj",
+    "k_proj",
+    "v_proj",
+    "out_proj",
+    "input_blocks",
+    "middle_block",
+    "output_blocks",
+    "emb_layers",
+    "in_layers",
+    "out_layers",
+    "skip_connection",
+];
+
+/// The suffixes of the tensors of the LoRA variants kohya's scripts also train, which have no
+/// candle-lora layer.
+const UNSUPPORTED: [&str; 5] = [".lora_mid.", ".hada_", ".lokr_", ".dora_scale", ".diff"];
+
+/// Convert a kohya LoRA checkpoint to the name-based candle-lora format
+///
+/// `lora_unet_<module>` is converted to `unet.<path>.a0.weight` and `unet.<path>.b0.weight`,
+/// `lora_te_<module>` and `lora_te1_<module>` to `text_encoder.<path>`, and the `lora_te2_`
+/// modules of SDXL to `text_encoder_2.<path>`. `lora_down` is A and `lora_up` is B: the kernels
+/// of the LoRA of convolutions, `(rank, in, kh, kw)` and `(out, rank, 1, 1)`, are those of
+/// [`crate::LoraConv2d`], and the 1x1 convolutions and linear layers load each other's weights,
+/// so that the adapter loads at `vb.pp("unet").pp(path)` with the id 0.
+///
+/// The module paths are found in `base_modules`, the paths of the modules of the component
+/// without its prefix, e.g. `down_blocks.0.attentions.0.proj_in`, when given. Otherwise the
+/// underscores are read as dots, except in the known module names with underscores such as
+/// `transformer_blocks` or `to_q`.
+///
+/// The `alpha` of each module, `rank` when missing, is reported: the report has the most common
+/// rank and alpha, and the others as rank and alpha patterns of the module paths, so that
+/// [`ConversionReport::lora_config`] scales every module as kohya's scripts do.
+///
+/// LoCon modules with a middle kernel, LoHa, LoKr and DoRA checkpoints are not supported.
+///
+/// # Example
+/// ```no_run
+/// use candle_lora::convert_kohya_to_candle_lora;
+///
+/// let report =
+///     convert_kohya_to_candle_lora("path/to/civitai_lora.safetensors", "converted.safetensors", None)
+///         .unwrap();
+/// println!("{} modules of rank {}", report.num_modules, report.rank);
+/// ```
+pub fn convert_kohya_to_candle_lora(
+    kohya_path: &str,
+    output_path: &str,
+    base_modules: Option<&[String]>,
+) -> Result<ConversionReport> {
+    let kohya_tensors = candle_core::safetensors::load(kohya_path, &Device::Cpu)?;
+    let base_modules = base_modules.map(|modules| {
+        modules
+            .iter()
+            .map(|path| (path.replace('.', "_"), path.as_str()))
+            .collect::<HashMap<_, _>>()
+    });
+
+    let mut modules = BTreeMap::<String, [Option<&Tensor>; 3]>::new();
+    for (name, tensor) in &kohya_tensors {
+        if let Some(suffix) = UNSUPPORTED.iter().find(|suffix| name.contains(*suffix)) {
+            bail!("{name}: the `{suffix}` tensors of LoCon, LoHa, LoKr and DoRA are not supported")
+        }
+        let (module, slot) = if let Some(module) = name.strip_suffix(".lora_down.weight") {
+            (module, 0)
+        } else if let Some(module) = name.strip_suffix(".lora_up.weight") {
+            (module, 1)
+        } else if let Some(module) = name.strip_suffix(".alpha") {
+            (module, 2)
+        } else {
+            bail!("{name} is not a kohya LoRA tensor")
+        };
+        modules.entry(module.to_string()).or_default()[slot] = Some(tensor);
+    }
+
+    let mut converted = HashMap::new();
+    let mut layers = Vec::new();
+    for (module, [down, up, alpha]) in &modules {
+        let (Some(down), Some(up)) = (down, up) else {
+            bail!("{module} has no lora_down and lora_up pair")
+        };
+        let path = module_path(module, base_modules.as_ref())?;
+        let rank = down.dim(0)?;
+        let alpha = match alpha {
+            Some(alpha) => alpha
+                .to_dtype(DType::F64)?
+                .flatten_all()?
+                .get(0)?
+                .to_scalar()?,
+            None => rank as f64,
+        };
+        converted.insert(format!("{path}.a0.weight"), (*down).clone());
+        converted.insert(format!("{path}.b0.weight"), (*up).clone());
+        layers.push((path, rank, alpha));
+    }
+    if layers.is_empty() {
+        bail!("no LoRA tensors found in {kohya_path}")
+    }
+    candle_core::safetensors::save(&converted, output_path)?;
+
+    layers.sort_by(|a, b| structural_order(&a.0, &b.0));
+    let rank = most_common(layers.iter().map(|(_, rank, _)| *rank));
+    let alpha = most_common(layers.iter().map(|(_, _, alpha)| alpha.to_bits()));
+    let alpha = f64::from_bits(alpha);
+    Ok(ConversionReport {
+        rank,
+        alpha: Some(alpha),
+        scaling: Some(alpha / rank as f64),
+        dropout: None,
+        target_modules: layers.iter().map(|(path, _, _)| path.clone()).collect(),
+        num_modules: layers.len(),
+        num_magnitudes: 0,
+        num_tensors: converted.len(),
+        rank_pattern: layers
+            .iter()
+            .filter(|(_, layer_rank, _)| *layer_rank != rank)
+            .map(|(path, layer_rank, _)| (regex::escape(path), *layer_rank))
+            .collect(),
+        alpha_pattern: layers
+            .iter()
+            .filter(|(_, _, layer_alpha)| *layer_alpha != alpha)
+            .map(|(path, _, layer_alpha)| (regex::escape(path), *layer_alpha))
+            .collect(),
+        use_rslora: false,
+        skipped: Vec::new(),
+        modules_to_save: Vec::new(),
+    })
+}
+
+/// The candle-lora path, `<component>.<path>`, of the kohya module `module`.
+fn module_path(module: &str, base_modules: Option<&HashMap<String, &str>>) -> Result<String> {
+    let Some((component, name)) = COMPONENTS
+        .iter()
+        .find_map(|(prefix, component)| Some((component, module.strip_prefix(prefix)?)))
+    else {
+        bail!("{module} is not a lora_unet_ or lora_te_ module")
+    };
+    let path = match base_modules {
+        Some(base_modules) => match base_modules.get(name) {
+            Some(path) => path.to_string(),
+            None => bail!("{module} is not a module of the base model"),
+        },
+        None => restore_dots(name),
+    };
+    Ok(format!("{component}.{path}"))
+}
+
+/// The module path of the underscored name `name`, taking the longest known module name at each
+/// component.
+fn restore_dots(name: &str) -> String {
+    let words = name.split('_').collect::<Vec<_>>();
+    let mut components = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        let len = (2..=words.len() - i)
+            .rev()
+            .find(|&len| COMPOUND_NAMES.contains(&words[i..i + len].join("_").as_str()))
+            .unwrap_or(1);
+        components.push(words[i..i + len].join("_"));
+        i += len;
+    }
+    components.join(".")
+}
+
+/// The most common of `values`, the largest one on ties.
+fn most_common<V: Ord + Copy>(values: impl Iterator<Item = V>) -> V {
+    let mut counts = BTreeMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0usize) += 1;
+    }
+    let (&value, _) = counts
+        .iter()
+        .max_by_key(|(&value, &count)| (count, value))
+        .expect("at least one value");
+    value
+}
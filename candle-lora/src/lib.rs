@@ -1,37 +1,177 @@
-use candle_core::{Error, Shape, Tensor};
+use candle_core::{Device, Error, Result, Shape, Tensor, Var};
 use candle_nn::{
-    Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, Embedding, Linear, Module, VarBuilder,
+    Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, Embedding, Linear, Module, VarBuilder, VarMap,
 };
 use either::Either;
+use indexing::in_structural_order;
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hash,
+};
+use thiserror::Error;
+use training::trainable_var;
+
+pub use adam8bit::{Adam8bit, ParamsAdam8bit};
+pub use adapter_info::{AdapterFormat, AdapterInfo, LayerInfo};
+pub use adapter_metadata::{
+    load_with_metadata, save_with_metadata, AdapterMetadata, ADAPTER_METADATA_KEY,
+};
+pub use adapters::{
+    adapters_enabled, disable_adapters, select_batch_adapters, BatchAdapters, DisabledAdapters,
+};
+pub use averaging::{average_adapters, average_checkpoints, last_checkpoints};
+pub use callbacks::{EarlyStopping, FitConfig, FitSummary, TrainerCallback, TrainerControl};
+pub use checkpoint::{
+    load_adapter_config, AdapterConfig, ADAPTER_CONFIG_FILE, ADAPTER_WEIGHTS_FILE,
+};
+pub use conversion_hooks::ConversionHooks;
+pub use device_map::{AdapterDevice, DeviceMap};
+pub use distributed::{all_reduce_grads, Communicator};
+#[cfg(feature = "nccl")]
+pub use distributed::{NcclCommunicator, NcclId};
+pub use dpo::{dpo_loss, sequence_log_probs, DpoBatch, DpoConfig, DpoOutput, DpoTrainer};
+#[cfg(feature = "encryption")]
+pub use encryption::{
+    decrypt_safetensors, encrypt_adapter_file, encrypt_safetensors, load_encrypted, save_encrypted,
+};
+#[cfg(feature = "equivalence")]
+pub use equivalence::{Divergence, EquivalenceReport, ReferenceOutputs, Tolerance};
+pub use error::LoraError;
+pub use fingerprint::{
+    load_verified, read_fingerprint, save_with_fingerprint, stamp_fingerprint,
+    BaseModelFingerprint, FINGERPRINT_METADATA_KEY,
+};
+pub use fused::LoraForward;
+pub use gguf_export::{gguf_tensor_name, save_gguf, GgufQuantization};
+#[cfg(feature = "hub")]
+pub use hub::PeftAdapter;
+pub use ia3::{Ia3Linear, Ia3LinearConfig};
+pub use indexing::structural_order;
+pub use key_rules::{KeyRule, KeyRules};
+pub use kohya::convert_kohya_to_candle_lora;
 pub use loraconv1d::{LoraConv1d, LoraConv1dConfig};
 pub use loraconv2d::{LoraConv2d, LoraConv2dConfig};
+pub use loraconvtranspose::{LoraConvTranspose1d, LoraConvTranspose2d, LoraConvTransposeConfig};
 pub use loraembed::{LoraEmbedding, LoraEmbeddingConfig};
 pub use loralinear::{LoraLinear, LoraLinearConfig};
+pub use manifest::{load_with_manifest, ConversionManifest, ManifestEntry};
+pub use memory::{
+    activation_bytes, checkpointed_backward, tensors_bytes, BlockFn, CheckpointedBackward,
+    GradientCheckpointing, MemoryStats,
+};
+pub use migration::{
+    migrate_adapter_file, migrate_to_indexed, migrate_to_named, Migration, MODULES_METADATA_KEY,
+};
+pub use modules_to_save::{apply_modules_to_save, modules_to_save_weights};
+pub use multi_adapter::{MultiAdapter, DEFAULT_ADAPTER};
 pub use peft_convert::{
-    convert_peft_dir_to_candle_lora, convert_peft_dir_to_candle_lora_typed,
-    convert_peft_to_candle_lora, convert_peft_to_candle_lora_typed, CandleLoraPrefix, PeftConfig,
+    convert_candle_lora_to_peft, convert_candle_lora_to_peft_dir, convert_peft_dir_to_candle_lora,
+    convert_peft_dir_to_candle_lora_typed, convert_peft_dir_to_candle_lora_typed_with_options,
+    convert_peft_dir_to_candle_lora_with_options, convert_peft_ia3_to_candle_lora,
+    convert_peft_prompt_to_candle_lora, convert_peft_to_candle_lora,
+    convert_peft_to_candle_lora_traced, convert_peft_to_candle_lora_traced_with_hooks,
+    convert_peft_to_candle_lora_typed, convert_peft_to_candle_lora_typed_with_hooks,
+    convert_peft_to_candle_lora_with_hooks, convert_peft_to_candle_lora_with_manifest,
+    convert_peft_to_candle_lora_with_options, convert_peft_to_candle_lora_with_rules,
+    load_peft_adapter, load_peft_weights, split_packed_qkv, CandleLoraPrefix, ConversionReport,
+    ConvertOptions, PeftConfig, PeftIa3Config, PeftPromptConfig, TracedArchitecture,
+    TRACED_LORA_LINEAR,
 };
-use std::{collections::HashMap, fmt::Debug, hash::Hash};
-use thiserror::Error;
+pub use prompt_tuning::{PrefixKeyValues, PrefixTuningConfig, PromptEmbedding, PromptTuningConfig};
+pub use pruning::{adapter_sparsity, prune_adapter, prune_checkpoint, prune_varmap, Pruning};
+pub use qalora::{GroupQuantizedLinear, QaLoraLinear};
+pub use qlora::{LoraQuantizedLinear, QuantizedLinear};
+pub use resize::{resize_adapter_rank, resize_checkpoint_rank};
+pub use store::{AdapterStore, LoadedAdapter};
+pub use swap::AdapterSwap;
+pub use target::TargetSpec;
+pub use torch_export::{
+    export_merged_to_pytorch, extract_lora_from_diff, merge_peft_adapter,
+    merge_peft_adapter_with_config, save_torch_state_dict,
+};
+pub use training::{
+    clip_grad_norm, delta_l2_penalty, freeze_base_weights, grad_norm, LoraTrainer, LossScaler,
+    LrScheduler, MixedPrecisionConfig, StepInfo, Trainable, TrainingConfig,
+};
+pub use unfreezing::{layer_groups, UnfreezeOrder, UnfreezeSchedule};
+pub use validation::{validate_adapter, BaseModelConfig, ValidationIssue, ValidationReport};
 
+mod adam8bit;
+mod adapter_info;
+mod adapter_metadata;
+mod adapters;
+mod averaging;
+mod callbacks;
+mod checkpoint;
+mod conversion_hooks;
+mod device_map;
+mod distributed;
+mod dora;
+mod dpo;
+#[cfg(feature = "encryption")]
+mod encryption;
+#[cfg(feature = "equivalence")]
+mod equivalence;
+mod error;
+mod fingerprint;
 mod frozenconv;
 mod frozenembed;
 mod frozenlinear;
+mod fused;
+mod gguf_export;
+#[cfg(feature = "hub")]
+mod hub;
+mod ia3;
+mod indexing;
+mod key_rules;
+mod kohya;
 mod loraconv1d;
 mod loraconv2d;
+mod loraconvtranspose;
 mod loraembed;
 mod loralinear;
+mod manifest;
+mod memory;
+mod migration;
+mod modules_to_save;
+mod multi_adapter;
 mod peft_convert;
+mod prompt_tuning;
+mod pruning;
+mod qalora;
+mod qlora;
+mod resize;
+mod store;
+mod svd;
+mod swap;
+mod target;
+mod torch_export;
+mod training;
+mod unfreezing;
+mod validation;
 
 pub struct Lora;
 
 impl Lora {
     /// Convert the selected layers into their LoRA counterparts.
+    ///
+    /// `config` applies to the layer kinds without their own config, see
+    /// [`SelectedLayersBuilder::with_linear_lora_config`] and the like.
+    ///
+    /// The layers of each kind get their ids in the [`structural_order`] of their names, so the
+    /// `a{id}`/`b{id}` weights of a saved or converted adapter always line up with the model.
+    /// Layers the [`TargetSpec`] of their config does not match are not converted.
+    ///
+    /// Fails if the LoRA weights of a linear or conv layer cannot be created, or a converted
+    /// layer cannot be moved to the device of its [`DeviceMap`].
     pub fn convert_model<T: Eq + PartialEq + Hash + std::fmt::Display>(
         selected: SelectedLayers<'_, T>,
         config: LoraConfig,
         vb: &VarBuilder,
-    ) -> NewLayers<T> {
+    ) -> Result<NewLayers<T>> {
         let mut new = NewLayers {
             linear: HashMap::new(),
             conv1d: HashMap::new(),
@@ -41,55 +181,78 @@ impl Lora {
 
         let mut id = 0;
 
-        for (name, layer) in selected.linear {
-            new.linear.insert(
-                name,
-                LoraLinear::new(
-                    layer,
-                    selected.linear_config.as_ref().unwrap(),
-                    &config,
-                    vb,
-                    id,
-                )
-                .unwrap(),
-            );
+        for (name, layer) in in_structural_order(selected.linear) {
+            let path = target::layer_path(vb, &name);
+            let lora_config = selected.linear_lora_config.as_ref().unwrap_or(&config);
+            if !lora_config.targets(&path) {
+                continue;
+            }
+            let lora_config = lora_config.layer_config(&path);
+            let new_layer = LoraLinear::new(
+                layer,
+                selected.linear_config.as_ref().unwrap(),
+                &lora_config,
+                vb,
+                id,
+            )?;
+            let device = layer.weight().device();
+            new.linear
+                .insert(name, lora_config.place(new_layer, &path, device)?);
             id += 1;
         }
 
-        for (name, layer) in selected.conv1d {
-            new.conv1d.insert(
-                name,
-                LoraConv1d::new(
-                    layer,
-                    selected.conv1d_config.as_ref().unwrap(),
-                    &config,
-                    vb,
-                    id,
-                )
-                .unwrap(),
-            );
+        for (name, layer) in in_structural_order(selected.conv1d) {
+            let path = target::layer_path(vb, &name);
+            let lora_config = selected.conv1d_lora_config.as_ref().unwrap_or(&config);
+            if !lora_config.targets(&path) {
+                continue;
+            }
+            let lora_config = lora_config.layer_config(&path);
+            let new_layer = LoraConv1d::new(
+                layer,
+                selected.conv1d_config.as_ref().unwrap(),
+                &lora_config,
+                vb,
+                id,
+            )?;
+            let device = layer.weight().device();
+            new.conv1d
+                .insert(name, lora_config.place(new_layer, &path, device)?);
             id += 1;
         }
 
-        for (name, layer) in selected.conv2d {
-            new.conv2d.insert(
-                name,
-                LoraConv2d::new(
-                    layer,
-                    selected.conv2d_config.as_ref().unwrap(),
-                    &config,
-                    vb,
-                    id,
-                )
-                .unwrap(),
-            );
+        for (name, layer) in in_structural_order(selected.conv2d) {
+            let path = target::layer_path(vb, &name);
+            let lora_config = selected.conv2d_lora_config.as_ref().unwrap_or(&config);
+            if !lora_config.targets(&path) {
+                continue;
+            }
+            let lora_config = lora_config.layer_config(&path);
+            let new_layer = LoraConv2d::new(
+                layer,
+                selected.conv2d_config.as_ref().unwrap(),
+                &lora_config,
+                vb,
+                id,
+            )?;
+            let device = layer.weight().device();
+            new.conv2d
+                .insert(name, lora_config.place(new_layer, &path, device)?);
             id += 1;
         }
 
-        for (name, layer) in selected.embed {
+        for (name, layer) in in_structural_order(selected.embed) {
             if let Some(embed_config) = selected.embed_config.as_ref() {
-                match LoraEmbedding::new(layer, embed_config, &config, vb, id) {
+                let path = target::layer_path(vb, &name);
+                let embed_lora_config = selected.embed_lora_config.as_ref().unwrap_or(&config);
+                if !embed_lora_config.targets(&path) {
+                    continue;
+                }
+                let embed_lora_config = embed_lora_config.layer_config(&path);
+                match LoraEmbedding::new(layer, embed_config, &embed_lora_config, vb, id) {
                     Ok(lora_embed) => {
+                        let device = layer.embeddings().device();
+                        let lora_embed = embed_lora_config.place(lora_embed, &path, device)?;
                         new.embed.insert(name, lora_embed);
                         id += 1;
                     }
@@ -109,7 +272,355 @@ impl Lora {
             }
         }
 
-        new
+        Ok(new)
+    }
+
+    /// Merge the LoRA weights of all the converted layers into their base weights, see
+    /// [`Merge::merge_weights`]. Layers which are already merged are left as is.
+    pub fn merge_all<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let layers = new
+            .linear
+            .values_mut()
+            .map(|layer| layer as &mut dyn Merge)
+            .chain(new.conv1d.values_mut().map(|layer| layer as &mut dyn Merge))
+            .chain(new.conv2d.values_mut().map(|layer| layer as &mut dyn Merge))
+            .chain(new.embed.values_mut().map(|layer| layer as &mut dyn Merge));
+        for layer in layers {
+            match layer.merge_weights() {
+                Ok(()) | Err(Either::Left(MergeError::AlreadyMerged)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The adapter variables of all the converted layers, sorted by name and each given once, for
+    /// a `candle_nn::Optimizer`, see [`Trainable::trainable_params`].
+    pub fn trainable_params<T: Eq + PartialEq + Hash>(new: &NewLayers<T>) -> Result<Vec<Var>> {
+        Self::trainable_tensors(new)
+            .iter()
+            .map(|(name, tensor)| trainable_var(name, tensor))
+            .collect()
+    }
+
+    /// The adapter variables of all the converted layers split into the A matrices, the B
+    /// matrices and the vectors (DoRA magnitudes, Tied-LoRA scalings), each sorted by name, for
+    /// an optimizer per group, e.g. LoRA+ with a larger learning rate for B than for A.
+    pub fn lora_param_groups<T: Eq + PartialEq + Hash>(
+        new: &NewLayers<T>,
+    ) -> Result<(Vec<Var>, Vec<Var>, Vec<Var>)> {
+        let (mut a, mut b, mut magnitudes) = (Vec::new(), Vec::new(), Vec::new());
+        for (name, tensor) in Self::trainable_tensors(new) {
+            let var = trainable_var(&name, &tensor)?;
+            // `a{id}` or the tied `a_{features}`, likewise for B, the vectors being the others.
+            let weight = name.strip_suffix(".weight").unwrap_or(&name);
+            match weight
+                .rsplit('.')
+                .next()
+                .and_then(|weight| weight.chars().next())
+            {
+                Some('a') => a.push(var),
+                Some('b') => b.push(var),
+                _ => magnitudes.push(var),
+            }
+        }
+        Ok((a, b, magnitudes))
+    }
+
+    /// Put all the converted layers in training mode, applying the LoRA dropout, or in inference
+    /// mode, see [`LoraLinear::train`]. Embedding layers have no dropout.
+    pub fn train<T: Eq + PartialEq + Hash>(new: &mut NewLayers<T>, training: bool) {
+        new.linear
+            .values_mut()
+            .for_each(|layer| layer.train(training));
+        new.conv1d
+            .values_mut()
+            .for_each(|layer| layer.train(training));
+        new.conv2d
+            .values_mut()
+            .for_each(|layer| layer.train(training));
+    }
+
+    /// Move the adapters of all the converted layers to `device`, see
+    /// [`AdapterDevice::to_device`]. The base weights stay where they are.
+    pub fn to_device<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        device: &Device,
+    ) -> Result<()> {
+        fn moved<L: AdapterDevice>(
+            layers: &HashMap<impl Hash + Eq, L>,
+            device: &Device,
+        ) -> Result<Vec<L>> {
+            layers
+                .values()
+                .map(|layer| layer.to_device(device))
+                .collect()
+        }
+        let linear = moved(&new.linear, device)?;
+        let conv1d = moved(&new.conv1d, device)?;
+        let conv2d = moved(&new.conv2d, device)?;
+        let embed = moved(&new.embed, device)?;
+        new.linear
+            .values_mut()
+            .zip(linear)
+            .for_each(|(old, new)| *old = new);
+        new.conv1d
+            .values_mut()
+            .zip(conv1d)
+            .for_each(|(old, new)| *old = new);
+        new.conv2d
+            .values_mut()
+            .zip(conv2d)
+            .for_each(|(old, new)| *old = new);
+        new.embed
+            .values_mut()
+            .zip(embed)
+            .for_each(|(old, new)| *old = new);
+        Ok(())
+    }
+
+    /// Apply the frozen adapters of all the converted linear layers with `forward`, see
+    /// [`LoraLinear::set_forward`]. The other layers keep the factored forward.
+    pub fn set_forward<T: Eq + PartialEq + Hash>(new: &mut NewLayers<T>, forward: LoraForward) {
+        new.linear
+            .values_mut()
+            .for_each(|layer| layer.set_forward(forward));
+    }
+
+    /// The adapter tensors of all the converted layers by name, see
+    /// [`Trainable::trainable_tensors`].
+    fn trainable_tensors<T: Eq + PartialEq + Hash>(new: &NewLayers<T>) -> BTreeMap<String, Tensor> {
+        let layers = new
+            .linear
+            .values()
+            .map(|layer| layer as &dyn Trainable)
+            .chain(new.conv1d.values().map(|layer| layer as &dyn Trainable))
+            .chain(new.conv2d.values().map(|layer| layer as &dyn Trainable))
+            .chain(new.embed.values().map(|layer| layer as &dyn Trainable));
+        layers.flat_map(|layer| layer.trainable_tensors()).collect()
+    }
+
+    /// Save the adapter tensors of all the converted layers and `config` to the checkpoint
+    /// directory `dir`, e.g. `run/checkpoint-500`, with the training `step` if any. The base
+    /// weights are not saved. See [`Lora::load_adapter`] to resume training from it.
+    pub fn save_adapter<T: Eq + PartialEq + Hash + std::fmt::Display, P: AsRef<std::path::Path>>(
+        new: &NewLayers<T>,
+        config: &LoraConfig,
+        step: Option<usize>,
+        dir: P,
+    ) -> Result<()> {
+        let mut target_modules = new
+            .linear
+            .keys()
+            .chain(new.conv1d.keys())
+            .chain(new.conv2d.keys())
+            .chain(new.embed.keys())
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        target_modules.sort();
+        let adapter_config = config.adapter_config(target_modules, step);
+        checkpoint::save_checkpoint(&Self::trainable_tensors(new), &adapter_config, dir.as_ref())
+    }
+
+    /// Load the checkpoint directory `dir` saved by [`Lora::save_adapter`] into `varmap`,
+    /// returning the configuration it was saved with.
+    ///
+    /// Load it into the `VarMap` of the adapter before converting the model to resume training:
+    /// the converted layers then take the saved tensors as their variables. Loading it after
+    /// converting the model sets the variables of the layers instead.
+    pub fn load_adapter<P: AsRef<std::path::Path>>(
+        dir: P,
+        varmap: &VarMap,
+        device: &Device,
+    ) -> Result<AdapterConfig> {
+        checkpoint::load_checkpoint(dir.as_ref(), varmap, device)
+    }
+
+    /// Swap the adapter of all the converted layers for the adapter of `vb`, see
+    /// [`AdapterSwap::swap_adapter`]. Either all the layers get the new adapter or, on error,
+    /// none does.
+    pub fn swap_adapter<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        Self::swap_adapter_by_id(new, vb, config, &HashMap::new())
+    }
+
+    /// Swap the adapter like [`Lora::swap_adapter`], the layers whose id is in `layer_configs`
+    /// taking their scale from their own config, e.g. from PEFT's `alpha_pattern`.
+    pub(crate) fn swap_adapter_by_id<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+        layer_configs: &HashMap<usize, LoraConfig>,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        fn swapped<L: AdapterSwap + Saveable + Clone>(
+            layers: &HashMap<impl Hash + Eq, L>,
+            vb: &VarBuilder,
+            config: &LoraConfig,
+            layer_configs: &HashMap<usize, LoraConfig>,
+        ) -> std::result::Result<Vec<L>, MergeErrorOrError> {
+            layers
+                .values()
+                .map(|layer| {
+                    let config = match layer_configs.is_empty() {
+                        true => config,
+                        false => swap::adapter_id(layer)
+                            .map_err(Either::Right)?
+                            .and_then(|(_, id, _)| layer_configs.get(&id))
+                            .unwrap_or(config),
+                    };
+                    let mut layer = layer.clone();
+                    layer.swap_adapter(vb, config)?;
+                    Ok(layer)
+                })
+                .collect()
+        }
+        // The clones share their base weights, so the swap stays O(adapter size).
+        let linear = swapped(&new.linear, vb, config, layer_configs)?;
+        let conv1d = swapped(&new.conv1d, vb, config, layer_configs)?;
+        let conv2d = swapped(&new.conv2d, vb, config, layer_configs)?;
+        let embed = swapped(&new.embed, vb, config, layer_configs)?;
+        new.linear
+            .values_mut()
+            .zip(linear)
+            .for_each(|(old, new)| *old = new);
+        new.conv1d
+            .values_mut()
+            .zip(conv1d)
+            .for_each(|(old, new)| *old = new);
+        new.conv2d
+            .values_mut()
+            .zip(conv2d)
+            .for_each(|(old, new)| *old = new);
+        new.embed
+            .values_mut()
+            .zip(embed)
+            .for_each(|(old, new)| *old = new);
+        Ok(())
+    }
+
+    /// Load the adapter `name` from `vb` into all the converted layers, next to their own, see
+    /// [`MultiAdapter::add_adapter`]. Either all the layers get the adapter or, on error, none
+    /// does.
+    pub fn add_adapter<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        name: &str,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> Result<()> {
+        Self::update_adapters(new, &|layer| layer.add_adapter(name, vb, config))
+    }
+
+    /// Remove the adapter `name` from all the converted layers, see
+    /// [`MultiAdapter::remove_adapter`].
+    pub fn remove_adapter<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        name: &str,
+    ) -> Result<()> {
+        Self::update_adapters(new, &|layer| layer.remove_adapter(name).map(|_| ()))
+    }
+
+    /// Combine the adapters of all the converted layers with the given weights, e.g.
+    /// `[("style", 0.7), ("domain", 0.3)]`, see [`MultiAdapter::set_adapter_weights`].
+    pub fn set_adapter_weights<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        weights: &[(&str, f64)],
+    ) -> Result<()> {
+        Self::update_adapters(new, &|layer| layer.set_adapter_weights(weights))
+    }
+
+    /// Replace the adapter of all the converted layers by their weighted combination of
+    /// adapters, see [`MultiAdapter::bake_adapter_weights`]. The layers can then be saved or
+    /// merged as a single adapter.
+    pub fn bake_adapter_weights<T: Eq + PartialEq + Hash>(new: &mut NewLayers<T>) -> Result<()> {
+        Self::update_adapters(new, &|layer| layer.bake_adapter_weights())
+    }
+
+    /// Run `update` on clones of all the converted layers, which replace the layers once it
+    /// succeeded for each of them.
+    fn update_adapters<T: Eq + PartialEq + Hash>(
+        new: &mut NewLayers<T>,
+        update: &dyn Fn(&mut dyn MultiAdapter) -> Result<()>,
+    ) -> Result<()> {
+        fn updated<L: MultiAdapter + Clone>(
+            layers: &HashMap<impl Hash + Eq, L>,
+            update: &dyn Fn(&mut dyn MultiAdapter) -> Result<()>,
+        ) -> Result<Vec<L>> {
+            layers
+                .values()
+                .map(|layer| {
+                    let mut layer = layer.clone();
+                    update(&mut layer)?;
+                    Ok(layer)
+                })
+                .collect()
+        }
+        let linear = updated(&new.linear, update)?;
+        let conv1d = updated(&new.conv1d, update)?;
+        let conv2d = updated(&new.conv2d, update)?;
+        let embed = updated(&new.embed, update)?;
+        new.linear
+            .values_mut()
+            .zip(linear)
+            .for_each(|(old, new)| *old = new);
+        new.conv1d
+            .values_mut()
+            .zip(conv1d)
+            .for_each(|(old, new)| *old = new);
+        new.conv2d
+            .values_mut()
+            .zip(conv2d)
+            .for_each(|(old, new)| *old = new);
+        new.embed
+            .values_mut()
+            .zip(embed)
+            .for_each(|(old, new)| *old = new);
+        Ok(())
+    }
+
+    /// The weights of the converted layers with their LoRA weights folded in, named
+    /// `<layer>.weight` and `<layer>.bias` after the layer names, e.g. to save the merged model
+    /// with [`Lora::save_merged`]. The layers themselves are not changed.
+    pub fn merged_tensors<T: Eq + PartialEq + Hash + std::fmt::Display>(
+        new: &NewLayers<T>,
+    ) -> std::result::Result<HashMap<String, Tensor>, MergeErrorOrError> {
+        let mut tensors = HashMap::new();
+        let mut insert = |name: &T, weight: &Tensor, bias: Option<&Tensor>| {
+            tensors.insert(format!("{name}.weight"), weight.clone());
+            if let Some(bias) = bias {
+                tensors.insert(format!("{name}.bias"), bias.clone());
+            }
+        };
+        for (name, layer) in &new.linear {
+            let merged = layer.merged_linear()?;
+            insert(name, merged.weight(), merged.bias());
+        }
+        for (name, layer) in &new.conv1d {
+            let merged = layer.merged_conv1d()?;
+            insert(name, merged.weight(), merged.bias());
+        }
+        for (name, layer) in &new.conv2d {
+            let merged = layer.merged_conv2d()?;
+            insert(name, merged.weight(), merged.bias());
+        }
+        for (name, layer) in &new.embed {
+            insert(name, layer.merged_embedding()?.embeddings(), None);
+        }
+        Ok(tensors)
+    }
+
+    /// Save the merged weights of the converted layers as safetensors, see
+    /// [`Lora::merged_tensors`].
+    pub fn save_merged<T: Eq + PartialEq + Hash + std::fmt::Display, P: AsRef<std::path::Path>>(
+        new: &NewLayers<T>,
+        path: P,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let tensors = Self::merged_tensors(new)?;
+        candle_core::safetensors::save(&tensors, path).map_err(Either::Right)
     }
 }
 
@@ -118,11 +629,19 @@ pub struct LoraConfig {
     rank: usize,
     alpha: f64,
     dropout: Option<f32>,
+    tied: Option<TiedLoraConfig>,
+    init: LoraInit,
+    target: Option<TargetSpec>,
+    rank_pattern: Vec<(Regex, usize)>,
+    alpha_pattern: Vec<(Regex, f64)>,
+    use_rslora: bool,
+    device_map: Option<DeviceMap>,
 }
 
 impl LoraConfig {
     /// Create a new LoRA config.
-    /// - `rank`: The dimensions of low-rank matrices.
+    /// - `rank`: The dimensions of low-rank matrices. Layers loaded from weights of another rank,
+    ///   e.g. from adapters trained with per-layer ranks, keep the stored rank.
     /// - `alpha`: Scaling factor for the LoRA signal.
     /// - `dropout`: Dropout probability for the LoRA layers.
     pub const fn new(rank: usize, alpha: f64, dropout: Option<f32>) -> Self {
@@ -130,19 +649,241 @@ impl LoraConfig {
             rank,
             alpha,
             dropout,
+            tied: None,
+            init: LoraInit::Kaiming,
+            target: None,
+            rank_pattern: Vec::new(),
+            alpha_pattern: Vec::new(),
+            use_rslora: false,
+            device_map: None,
+        }
+    }
+
+    /// Scale the LoRA signal by `alpha / sqrt(rank)` instead of `alpha / rank`, rank-stabilized
+    /// LoRA, like PEFT's `use_rslora`. Applies to the forward passes and to merging.
+    pub fn with_rslora(mut self, use_rslora: bool) -> Self {
+        self.use_rslora = use_rslora;
+        self
+    }
+
+    /// Initialize the A matrices of fresh adapters with `init`, B starting at zero.
+    pub fn with_init(mut self, init: LoraInit) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// The initialization of a fresh A matrix of the given rank.
+    pub(crate) fn a_init(&self, rank: usize) -> candle_nn::Init {
+        match self.init {
+            LoraInit::Kaiming => candle_nn::init::DEFAULT_KAIMING_NORMAL,
+            LoraInit::Gaussian => candle_nn::Init::Randn {
+                mean: 0.,
+                stdev: 1. / rank.max(1) as f64,
+            },
+        }
+    }
+
+    /// Share the LoRA matrices of the linear layers across layers (Tied-LoRA).
+    pub fn with_tied(mut self, tied: TiedLoraConfig) -> Self {
+        self.tied = Some(tied);
+        self
+    }
+
+    /// Convert only the selected layers `target` matches, rather than all of them.
+    pub fn with_target(mut self, target: TargetSpec) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Whether the layer at `path` is targeted.
+    pub(crate) fn targets(&self, path: &str) -> bool {
+        self.target
+            .as_ref()
+            .is_none_or(|target| target.matches(path))
+    }
+
+    /// Move the adapter of each converted layer to the device `device_map` gives it, e.g. next
+    /// to its base layer in a model sharded across GPUs.
+    pub fn with_device_map(mut self, device_map: DeviceMap) -> Self {
+        self.device_map = Some(device_map);
+        self
+    }
+
+    /// Move the converted layer at `path`, whose base weights are on `base`, to its device.
+    pub(crate) fn place<L: AdapterDevice>(&self, layer: L, path: &str, base: &Device) -> Result<L> {
+        device_map::place(layer, self.device_map.as_ref(), path, base)
+    }
+
+    /// Give the layers whose path ends with components matching the regular expression
+    /// `pattern` the rank `rank`, like PEFT's `rank_pattern`: e.g. `q_proj` or
+    /// `layers\.0\.self_attn\.v_proj`. The first matching pattern applies.
+    pub fn with_rank_pattern(mut self, pattern: &str, rank: usize) -> Result<Self> {
+        self.rank_pattern
+            .push((target::suffix_regex(pattern)?, rank));
+        Ok(self)
+    }
+
+    /// Give the layers whose path ends with components matching `pattern` the alpha `alpha`,
+    /// like PEFT's `alpha_pattern`, see [`LoraConfig::with_rank_pattern`].
+    pub fn with_alpha_pattern(mut self, pattern: &str, alpha: f64) -> Result<Self> {
+        self.alpha_pattern
+            .push((target::suffix_regex(pattern)?, alpha));
+        Ok(self)
+    }
+
+    /// The config of the layer at `path`, with the rank and alpha of its patterns.
+    pub(crate) fn layer_config(&self, path: &str) -> Cow<'_, Self> {
+        let rank = target::pattern_value(&self.rank_pattern, path);
+        let alpha = target::pattern_value(&self.alpha_pattern, path);
+        if rank.is_none() && alpha.is_none() {
+            return Cow::Borrowed(self);
         }
+        let mut config = self.clone();
+        config.rank = rank.unwrap_or(self.rank);
+        config.alpha = alpha.unwrap_or(self.alpha);
+        Cow::Owned(config)
+    }
+
+    /// The scale `alpha / rank`, or `alpha / sqrt(rank)` with rsLoRA, of a layer of the given
+    /// rank, `None` for rank 0.
+    pub(crate) fn scale(&self, rank: usize) -> Option<f64> {
+        if rank > 0 {
+            Some(lora_scaling(self.alpha, rank, self.use_rslora))
+        } else {
+            None
+        }
+    }
+}
+
+/// The scaling of a LoRA delta of the given rank, `alpha / rank`, or `alpha / sqrt(rank)` for
+/// rank-stabilized LoRA.
+pub(crate) fn lora_scaling(alpha: f64, rank: usize, use_rslora: bool) -> f64 {
+    if use_rslora {
+        alpha / (rank as f64).sqrt()
+    } else {
+        alpha / rank as f64
+    }
+}
+
+/// The initialization of the A matrices of fresh adapters, the B matrices starting at zero so
+/// that a fresh adapter leaves its layer unchanged. Embedding layers initialize A at zero and B
+/// from a normal distribution instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoraInit {
+    /// Kaiming normal, scaled by the input features.
+    #[default]
+    Kaiming,
+    /// Normal with a standard deviation of `1 / rank`, PEFT's `init_lora_weights="gaussian"`.
+    Gaussian,
+}
+
+/// Tied-LoRA, see "Tied-LoRA: Enhancing parameter efficiency of LoRA with weight tying"
+/// Renduchintala et al. 2023 <https://arxiv.org/abs/2311.09578>.
+///
+/// The linear layers with the same input features share one A matrix, and with `share_b` the
+/// layers with the same output features share one B matrix. Each layer trains a scaling vector
+/// `u` over its outputs and `v` over the rank: `delta_weight = diag(u) B diag(v) A`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TiedLoraConfig {
+    /// Whether B is shared as well, otherwise each layer has its own.
+    pub share_b: bool,
+    /// Root prefix of the shared matrices, e.g. `tied_lora.a_4096.weight` for the A matrix of
+    /// the layers with 4096 input features.
+    pub prefix: String,
+}
+
+impl Default for TiedLoraConfig {
+    fn default() -> Self {
+        Self {
+            share_b: true,
+            prefix: "tied_lora".to_string(),
+        }
+    }
+}
+
+/// The input of the LoRA branch of a layer, with the LoRA `dropout` applied in training mode, see
+/// [`LoraLinear::train`].
+pub(crate) fn lora_input(
+    dropout: Option<&candle_nn::Dropout>,
+    training: bool,
+    input: &Tensor,
+) -> Result<Tensor> {
+    match dropout {
+        Some(dropout) if training => dropout.forward(input, true),
+        _ => Ok(input.clone()),
+    }
+}
+
+/// Get the LoRA weight `weight` of `vb` with the given shape, or with the stored shape if it
+/// only differs in the rank dimension `rank_dim`.
+///
+/// Adapters trained with per-layer ranks (AdaLoRA, PEFT `rank_pattern`) store weights whose rank
+/// differs from [`LoraConfig`]'s, so the rank of a loaded layer is taken from its weights.
+/// Weights of 1x1 convolutions, `(rank, in, 1, 1)`, are also loaded as the `(rank, in)` weights
+/// of linear layers and the other way around, as Stable Diffusion models use either for the
+/// same projections.
+pub(crate) fn get_lora_weight<S: Into<Shape>>(
+    vb: &VarBuilder,
+    shape: S,
+    hints: candle_nn::Init,
+    rank_dim: usize,
+) -> candle_core::Result<Tensor> {
+    let shape = shape.into();
+    match vb.get_with_hints(shape.clone(), "weight", hints) {
+        Err(e) => match stored_shape(&e) {
+            Some(stored) => {
+                let (stored_dims, dims) = (matrix_dims(stored), matrix_dims(&shape));
+                if stored_dims.len() != dims.len()
+                    || (0..dims.len()).any(|dim| dim != rank_dim && stored_dims[dim] != dims[dim])
+                {
+                    return Err(e);
+                }
+                let weight = vb.get_with_hints(stored.clone(), "weight", hints)?;
+                if stored.rank() == shape.rank() {
+                    return Ok(weight);
+                }
+                let mut dims = stored_dims.to_vec();
+                dims.resize(shape.rank(), 1);
+                weight.reshape(dims)
+            }
+            None => Err(e),
+        },
+        weight => weight,
+    }
+}
+
+/// The dims of `shape`, without the trailing `1, 1` of a 1x1 convolution kernel.
+fn matrix_dims(shape: &Shape) -> &[usize] {
+    match shape.dims() {
+        [dims @ .., 1, 1] if dims.len() == 2 => dims,
+        dims => dims,
+    }
+}
+
+/// The shape of the stored tensor of a shape mismatch error.
+fn stored_shape(e: &Error) -> Option<&Shape> {
+    match e {
+        Error::UnexpectedShape { got, .. } => Some(got),
+        Error::WithBacktrace { inner, .. }
+        | Error::WithPath { inner, .. }
+        | Error::Context { inner, .. } => stored_shape(inner),
+        _ => None,
     }
 }
 
 pub struct SelectedLayers<'a, T: Eq + PartialEq + Hash> {
     linear: HashMap<T, &'a dyn LinearLayerLike>,
     linear_config: Option<LoraLinearConfig>,
+    linear_lora_config: Option<LoraConfig>,
     conv1d: HashMap<T, &'a dyn Conv1dLayerLike>,
     conv1d_config: Option<LoraConv1dConfig>,
+    conv1d_lora_config: Option<LoraConfig>,
     conv2d: HashMap<T, &'a dyn Conv2dLayerLike>,
     conv2d_config: Option<LoraConv2dConfig>,
+    conv2d_lora_config: Option<LoraConfig>,
     embed: HashMap<T, &'a dyn EmbeddingLayerLike>,
     embed_config: Option<LoraEmbeddingConfig>,
+    embed_lora_config: Option<LoraConfig>,
 }
 
 pub struct SelectedLayersBuilder<'a, T: Eq + PartialEq + Hash> {
@@ -161,12 +902,16 @@ impl<'a, T: Eq + PartialEq + Hash> SelectedLayersBuilder<'a, T> {
             selected: SelectedLayers {
                 linear: HashMap::new(),
                 linear_config: None,
+                linear_lora_config: None,
                 conv1d: HashMap::new(),
                 conv1d_config: None,
+                conv1d_lora_config: None,
                 conv2d: HashMap::new(),
                 conv2d_config: None,
+                conv2d_lora_config: None,
                 embed: HashMap::new(),
                 embed_config: None,
+                embed_lora_config: None,
             },
         }
     }
@@ -211,6 +956,31 @@ impl<'a, T: Eq + PartialEq + Hash> SelectedLayersBuilder<'a, T> {
         self
     }
 
+    /// Use `config` instead of the one given to [`Lora::convert_model`] for the linear layers.
+    pub fn with_linear_lora_config(mut self, config: LoraConfig) -> Self {
+        self.selected.linear_lora_config = Some(config);
+        self
+    }
+
+    /// Use `config` instead of the one given to [`Lora::convert_model`] for the conv1d layers.
+    pub fn with_conv1d_lora_config(mut self, config: LoraConfig) -> Self {
+        self.selected.conv1d_lora_config = Some(config);
+        self
+    }
+
+    /// Use `config` instead of the one given to [`Lora::convert_model`] for the conv2d layers.
+    pub fn with_conv2d_lora_config(mut self, config: LoraConfig) -> Self {
+        self.selected.conv2d_lora_config = Some(config);
+        self
+    }
+
+    /// Use `config` instead of the one given to [`Lora::convert_model`] for the embedding
+    /// layers, which usually want a higher rank than the attention projections.
+    pub fn with_embed_lora_config(mut self, config: LoraConfig) -> Self {
+        self.selected.embed_lora_config = Some(config);
+        self
+    }
+
     pub fn build(self) -> SelectedLayers<'a, T> {
         self.selected
     }
@@ -224,8 +994,10 @@ pub struct NewLayers<T: Eq + PartialEq + Hash> {
     pub embed: HashMap<T, LoraEmbedding>,
 }
 
+/// A layer whose adapter tensors can be saved.
 pub trait Saveable {
-    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>);
+    /// Add the adapter tensors of the layer to `accum`, by their names in the saved adapter.
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()>;
 }
 
 /// Any layer that is linear-like.
@@ -236,7 +1008,7 @@ pub trait LinearLayerLike: Module + Debug + Saveable + Send + Sync {
 }
 
 impl Saveable for Linear {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for candle_nn layers, only for candle_lora layers.");
     }
 }
@@ -261,7 +1033,7 @@ pub trait Conv1dLayerLike: Module + Debug + Saveable + Send + Sync {
 }
 
 impl Saveable for Conv1d {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for candle_nn layers, only for candle_lora layers.");
     }
 }
@@ -286,7 +1058,7 @@ pub trait Conv2dLayerLike: Module + Debug + Saveable + Send + Sync {
 }
 
 impl Saveable for Conv2d {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for candle_nn layers, only for candle_lora layers.");
     }
 }
@@ -310,7 +1082,7 @@ pub trait EmbeddingLayerLike: Module + Debug + Saveable + Send + Sync {
 }
 
 impl Saveable for Embedding {
-    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
         unimplemented!("Saving not supported for candle_nn layers, only for candle_lora layers.");
     }
 }
@@ -1,12 +1,18 @@
 use std::{collections::HashMap, ops::Mul, sync::Arc};
 
-use candle_core::{Module, Result, Tensor};
+use candle_core::{bail, Device, Module, Result, Tensor};
 use candle_nn::{init, Conv1d, Conv1dConfig, Dropout, VarBuilder};
 use either::Either;
 
 use crate::{
-    frozenconv::FrozenConv1d, Conv1dLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError,
-    Saveable,
+    adapters::batch_adapter_ids,
+    adapters_enabled,
+    frozenconv::FrozenConv1d,
+    get_lora_weight,
+    multi_adapter::{AdapterSet, AdapterWeights},
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterDevice, AdapterSwap, Conv1dLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError,
+    MultiAdapter, Saveable, Trainable,
 };
 
 #[derive(Debug, Clone)]
@@ -16,9 +22,11 @@ pub struct LoraConv1d {
     b: Tensor,
     scale: Option<f64>,
     dropout: Option<Arc<Dropout>>,
+    training: bool,
     merged: bool,
     prefix: String,
     id: usize,
+    adapters: AdapterSet,
 }
 
 #[derive(Clone, Debug)]
@@ -47,48 +55,88 @@ impl LoraConv1d {
         vb: &VarBuilder,
         id: usize,
     ) -> Result<Self> {
-        let a = vb.pp(format!("a{id}")).get_with_hints(
+        let a = get_lora_weight(
+            &vb.pp(format!("a{id}")),
             (
                 config.rank * conv_config.kernel_size,
                 conv_config.in_channels * conv_config.kernel_size,
             ),
-            "weight",
-            init::DEFAULT_KAIMING_NORMAL,
+            config.a_init(config.rank),
+            0,
         )?;
+        let rank = a.dim(0)? / conv_config.kernel_size;
         let b = vb.pp(format!("b{id}")).get_with_hints(
             (
                 conv_config.out_channels / old.config().groups * conv_config.kernel_size,
-                config.rank * conv_config.kernel_size,
+                rank * conv_config.kernel_size,
             ),
             "weight",
             init::ZERO,
         )?;
 
+        let training = a.is_variable();
         Ok(LoraConv1d {
             old: Arc::new(FrozenConv1d::new_from_conv1d(old)?),
             a,
             b,
-            scale: if config.rank > 0 {
-                Some(config.alpha / config.rank as f64)
-            } else {
-                None
-            },
+            scale: config.scale(rank),
             dropout: config.dropout.map(|x| Arc::new(Dropout::new(x))),
+            training,
             merged: false,
             prefix: vb.prefix(),
             id,
+            adapters: AdapterSet::default(),
         })
     }
+
+    /// Apply the LoRA dropout or not, see [`LoraLinear::train`](crate::LoraLinear::train).
+    pub fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn default_adapter(&self) -> AdapterWeights {
+        AdapterWeights {
+            a: self.a.clone(),
+            b: self.b.clone(),
+            scale: self.scale,
+        }
+    }
+
+    /// The unscaled kernel `B A` of an adapter.
+    fn delta_kernel(&self, a: &Tensor, b: &Tensor) -> Result<Tensor> {
+        b.matmul(a)?.reshape(self.old.weight().shape())
+    }
+
+    /// A plain `Conv1d` with the LoRA weights folded in, `W + alpha / r * B A`, to run inference
+    /// without the LoRA overhead.
+    pub fn merged_conv1d(&self) -> std::result::Result<Conv1d, MergeErrorOrError> {
+        let weight = if self.merged {
+            self.old.weight().clone()
+        } else {
+            (self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?
+        };
+        Ok(Conv1d::new(
+            weight,
+            self.old.bias().cloned(),
+            *self.old.config(),
+        ))
+    }
 }
 
 impl Merge for LoraConv1d {
     fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
-        let result = self
-            .b
-            .matmul(&self.a)
-            .map_err(Either::Right)?
-            .reshape(self.old.weight().shape())
+        let weighted = self
+            .adapters
+            .weighted_sum(&self.default_adapter(), |a, b| self.delta_kernel(a, b))
             .map_err(Either::Right)?;
+        if let Some(delta) = weighted {
+            return Ok(delta);
+        }
+        let result = self.delta_kernel(&self.a, &self.b).map_err(Either::Right)?;
 
         Ok(match self.scale {
             Some(scale) => result.mul(scale).map_err(Either::Right)?,
@@ -131,27 +179,99 @@ impl Merge for LoraConv1d {
     }
 }
 
+impl AdapterSwap for LoraConv1d {
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.a.shape(), init::ZERO, 0).map_err(Either::Right)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.b.shape(), init::ZERO, 1).map_err(Either::Right)?;
+        let kernel_size = self.old.weight().dim(2).map_err(Either::Right)?;
+        let rank = a.dim(0).map_err(Either::Right)? / kernel_size;
+        swap_weights(self, self.merged, |layer| {
+            layer.a = a;
+            layer.b = b;
+            layer.scale = config.scale(rank);
+        })
+    }
+}
+
+impl MultiAdapter for LoraConv1d {
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, config: &LoraConfig) -> Result<()> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.a.shape(), init::ZERO, 0)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.b.shape(), init::ZERO, 1)?;
+        let scale = config.scale(a.dim(0)? / self.old.weight().dim(2)?);
+        self.adapters.insert(name, AdapterWeights { a, b, scale })
+    }
+
+    fn remove_adapter(&mut self, name: &str) -> Result<bool> {
+        if self.merged {
+            bail!("unmerge the layer before removing an adapter")
+        }
+        Ok(self.adapters.remove(name))
+    }
+
+    fn adapter_names(&self) -> Vec<String> {
+        self.adapters.names()
+    }
+
+    fn set_adapter_weights(&mut self, weights: &[(&str, f64)]) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before changing its adapter weights")
+        }
+        self.adapters.set_weights(weights)
+    }
+
+    fn bake_adapter_weights(&mut self) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before baking its adapter weights")
+        }
+        if let Some(baked) = self.adapters.bake(&self.default_adapter(), 0, 1)? {
+            self.a = baked.a;
+            self.b = baked.b;
+            self.scale = baked.scale;
+        }
+        Ok(())
+    }
+}
+
 impl Module for LoraConv1d {
     fn forward(&self, input: &Tensor) -> Result<Tensor> {
-        if self.merged {
+        if self.merged || !adapters_enabled() {
             return self.old.forward(input);
         }
+        if batch_adapter_ids().is_some() {
+            bail!("adapters are selected per batch element in linear and embedding layers only")
+        }
+
+        // Weighted adapters are applied through their merged kernel.
+        let weighted = self
+            .adapters
+            .weighted_sum(&self.default_adapter(), |a, b| self.delta_kernel(a, b))?;
+        if let Some(delta) = weighted {
+            let weight = (self.old.weight() + delta)?;
+            let conv = Conv1d::new(weight, self.bias().cloned(), *self.config());
+            return conv.forward(input);
+        }
 
         if let Some(scale) = self.scale {
             let bias = self.bias().cloned();
 
-            let mut weight = self.old.weight().clone();
-            if self.dropout.is_some() {
-                weight = self.dropout.as_ref().unwrap().forward(input, true)?;
+            let weight = self.old.weight();
+            let delta = self.delta_kernel(&self.a, &self.b)?.mul(scale)?;
+
+            // The dropout applies to the input of the adapter only, which then needs its own conv.
+            if let (true, Some(dropout)) = (self.training, &self.dropout) {
+                let a_input = dropout.forward(input, true)?;
+                let lora = Conv1d::new(delta, None, *self.config()).forward(&a_input)?;
+                return self.old.forward(input)? + lora;
             }
-            let weight = (&weight
-                + &self
-                    .b
-                    .broadcast_matmul(&self.a.broadcast_matmul(&weight)?)?
-                    .reshape(self.old.weight().shape())?
-                    .mul(scale)?)?;
-
-            let conv = Conv1d::new(weight, bias, *self.config());
+            let conv = Conv1d::new((weight + delta)?, bias, *self.config());
             conv.forward(input)
         } else {
             self.old.forward(input)
@@ -160,7 +280,7 @@ impl Module for LoraConv1d {
 }
 
 impl Saveable for LoraConv1d {
-    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
         accum.insert(
             self.prefix.clone() + &format!(".a{}.weight", self.id),
             self.a.clone(),
@@ -169,6 +289,32 @@ impl Saveable for LoraConv1d {
             self.prefix.clone() + &format!(".b{}.weight", self.id),
             self.b.clone(),
         );
+        Ok(())
+    }
+}
+
+impl Trainable for LoraConv1d {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        vec![
+            (
+                weight_name(&self.prefix, &format!("a{}", self.id)),
+                self.a.clone(),
+            ),
+            (
+                weight_name(&self.prefix, &format!("b{}", self.id)),
+                self.b.clone(),
+            ),
+        ]
+    }
+}
+
+impl AdapterDevice for LoraConv1d {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        let mut layer = self.clone();
+        layer.a = self.a.to_device(device)?;
+        layer.b = self.b.to_device(device)?;
+        layer.adapters = self.adapters.to_device(device)?;
+        Ok(layer)
     }
 }
 
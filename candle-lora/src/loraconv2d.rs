@@ -1,12 +1,19 @@
 use std::{collections::HashMap, ops::Mul, sync::Arc};
 
-use candle_core::{Module, Result, Tensor};
+use candle_core::{bail, Device, Module, Result, Tensor};
 use candle_nn::{init, Conv2d, Conv2dConfig, Dropout, VarBuilder};
 use either::Either;
 
 use crate::{
-    frozenconv::FrozenConv2d, Conv2dLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError,
-    Saveable,
+    adapters::batch_adapter_ids,
+    adapters_enabled,
+    dora::DoraMagnitude,
+    frozenconv::FrozenConv2d,
+    get_lora_weight, lora_input,
+    multi_adapter::{AdapterSet, AdapterWeights},
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterDevice, AdapterSwap, Conv2dLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError,
+    MultiAdapter, Saveable, Trainable,
 };
 
 #[derive(Debug, Clone)]
@@ -16,9 +23,12 @@ pub struct LoraConv2d {
     b_conv: Conv2d,
     scale: Option<f64>,
     dropout: Option<Arc<Dropout>>,
+    training: bool,
     merged: bool,
     prefix: String,
     id: usize,
+    adapters: AdapterSet,
+    dora: Option<DoraMagnitude>,
 }
 
 #[derive(Clone, Debug)]
@@ -26,6 +36,7 @@ pub struct LoraConv2d {
 pub struct LoraConv2dConfig {
     in_channels: usize,
     out_channels: usize,
+    use_dora: bool,
 }
 
 impl LoraConv2dConfig {
@@ -33,8 +44,16 @@ impl LoraConv2dConfig {
         LoraConv2dConfig {
             in_channels,
             out_channels,
+            use_dora: false,
         }
     }
+
+    /// Adapt the layer with DoRA (weight-decomposed LoRA), which trains a magnitude per output
+    /// channel on top of LoRA. Fresh magnitudes start at the norms of the base weight.
+    pub fn with_dora(mut self) -> Self {
+        self.use_dora = true;
+        self
+    }
 }
 
 impl LoraConv2d {
@@ -45,109 +64,164 @@ impl LoraConv2d {
         vb: &VarBuilder,
         id: usize,
     ) -> Result<Self> {
-        let a = vb.pp(format!("a{id}")).get_with_hints(
+        let a = get_lora_weight(
+            &vb.pp(format!("a{id}")),
             (
                 config.rank,
                 conv_config.in_channels / old.config().groups,
                 old.weight().dim(2).unwrap(),
                 old.weight().dim(3).unwrap(),
             ),
-            "weight",
-            init::DEFAULT_KAIMING_NORMAL,
+            config.a_init(config.rank),
+            0,
         )?;
-        let b = vb.pp(format!("b{id}")).get_with_hints(
-            (
-                conv_config.out_channels,
-                config.rank / old.config().groups,
-                1,
-                1,
-            ),
-            "weight",
+        let rank = a.dim(0)?;
+        let b = get_lora_weight(
+            &vb.pp(format!("b{id}")),
+            (conv_config.out_channels, rank / old.config().groups, 1, 1),
             init::ZERO,
+            1,
         )?;
+        if b.dim(1)? != rank / old.config().groups {
+            bail!("B of rank {} for A of rank {rank}", b.dim(1)?)
+        }
 
         let a_conv = Conv2d::new(a, None, *old.config());
+        // B is a 1x1 conv over the output of A, which already has the output size.
         let b_conv = Conv2d::new(
             b,
             None,
             Conv2dConfig {
+                padding: 0,
                 stride: 1,
+                dilation: 1,
                 ..*old.config()
             },
         );
 
+        let training = a_conv.weight().is_variable();
         Ok(LoraConv2d {
             old: Arc::new(FrozenConv2d::new_from_conv2d(old)?),
             a_conv,
             b_conv,
-            scale: if config.rank > 0 {
-                Some(config.alpha / config.rank as f64)
-            } else {
-                None
-            },
+            scale: config.scale(rank),
             dropout: config.dropout.map(|x| Arc::new(Dropout::new(x))),
+            training,
             merged: false,
             prefix: vb.prefix(),
             id,
+            adapters: AdapterSet::default(),
+            dora: match conv_config.use_dora {
+                true => Some(DoraMagnitude::new(vb, id, old.weight())?),
+                false => None,
+            },
         })
     }
+
+    /// Apply the LoRA dropout or not, see [`LoraLinear::train`](crate::LoraLinear::train).
+    pub fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// The LoRA delta kernel `scale * B A` of the adapter the layer was created with.
+    fn lora_delta(&self) -> Result<Tensor> {
+        let result = delta_kernel(self.a_conv.weight(), self.b_conv.weight())?;
+        match self.scale {
+            Some(scale) => result.mul(scale),
+            None => Ok(result),
+        }
+    }
+
+    fn default_adapter(&self) -> AdapterWeights {
+        AdapterWeights {
+            a: self.a_conv.weight().clone(),
+            b: self.b_conv.weight().clone(),
+            scale: self.scale,
+        }
+    }
+
+    /// A plain `Conv2d` with the LoRA weights folded in, `W + alpha / r * B A`, to run inference
+    /// without the LoRA overhead.
+    pub fn merged_conv2d(&self) -> std::result::Result<Conv2d, MergeErrorOrError> {
+        let weight = if self.merged {
+            self.old.weight().clone()
+        } else {
+            (self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?
+        };
+        Ok(Conv2d::new(
+            weight,
+            self.old.bias().cloned(),
+            *self.old.config(),
+        ))
+    }
+}
+
+/// The unscaled kernel of an adapter, B applied after A.
+fn delta_kernel(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    match a.dims()[2..4] {
+        [1, 1] => b
+            .squeeze(3)?
+            .squeeze(2)?
+            .matmul(&a.squeeze(3)?.squeeze(2)?)?
+            .unsqueeze(2)?
+            .unsqueeze(3),
+        _ => {
+            // conv2d(A^T, B)^T as in PEFT, the (out, in, k, k) kernel of B applied after A.
+            let conv = Conv2d::new(b.clone(), None, Conv2dConfig::default());
+            conv.forward(&a.permute((1, 0, 2, 3))?)?
+                .permute((1, 0, 2, 3))
+        }
+    }
 }
 
 impl Merge for LoraConv2d {
     fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
-        let result = match self.old.weight().shape().dims()[2..4] {
-            [1, 1] => self
-                .b_conv
-                .weight()
-                .squeeze(3)
-                .map_err(Either::Right)?
-                .squeeze(2)
-                .map_err(Either::Right)?
-                .matmul(
-                    &self
-                        .a_conv
-                        .weight()
-                        .squeeze(3)
-                        .map_err(Either::Right)?
-                        .squeeze(2)
-                        .map_err(Either::Right)?,
-                )
-                .map_err(Either::Right)?
-                .unsqueeze(2)
-                .map_err(Either::Right)?
-                .unsqueeze(3)
-                .map_err(Either::Right)?,
-            _ => {
-                let conv = Conv2d::new(self.b_conv.weight().clone(), None, *self.old.config());
-                conv.forward(
-                    &self
-                        .a_conv
-                        .weight()
-                        .permute((1, 0, 2, 3))
-                        .map_err(Either::Right)?,
-                )
-                .map_err(Either::Right)?
+        let weighted = self
+            .adapters
+            .weighted_sum(&self.default_adapter(), delta_kernel)
+            .map_err(Either::Right)?;
+        if let Some(delta) = weighted {
+            return Ok(delta);
+        }
+        match &self.dora {
+            Some(DoraMagnitude {
+                merged_delta: Some(delta),
+                ..
+            }) => Ok(delta.clone()),
+            // m * (W + delta) / ||W + delta|| - W
+            Some(dora) => {
+                let weight = self.old.weight();
+                let dora_delta = self.lora_delta().and_then(|delta| {
+                    let adapted = (weight + delta)?;
+                    let scale = dora.scale(&adapted)?.reshape(((), 1, 1, 1))?;
+                    adapted.broadcast_mul(&scale)? - weight
+                });
+                dora_delta.map_err(Either::Right)
             }
-        };
-
-        Ok(match self.scale {
-            Some(scale) => result.mul(scale).map_err(Either::Right)?,
-            None => result,
-        })
+            None => self.lora_delta().map_err(Either::Right),
+        }
     }
 
     fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
         if self.merged {
             Err(Either::Left(MergeError::AlreadyMerged))
         } else {
+            let delta = self.get_delta_weight()?;
             self.old = Arc::new(
                 FrozenConv2d::new(
-                    &(self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?,
+                    &(self.old.weight() + &delta).map_err(Either::Right)?,
                     self.old.bias(),
                     *self.old.config(),
                 )
                 .map_err(Either::Right)?,
             );
+            if let Some(dora) = &mut self.dora {
+                dora.merged_delta = Some(delta);
+            }
             self.merged = true;
             Ok(())
         }
@@ -165,27 +239,140 @@ impl Merge for LoraConv2d {
                 )
                 .map_err(Either::Right)?,
             );
+            if let Some(dora) = &mut self.dora {
+                dora.merged_delta = None;
+            }
             self.merged = false;
             Ok(())
         }
     }
 }
 
+impl AdapterSwap for LoraConv2d {
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.a_conv.weight().shape(), init::ZERO, 0)
+            .map_err(Either::Right)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.b_conv.weight().shape(), init::ZERO, 1)
+            .map_err(Either::Right)?;
+        let rank = a.dim(0).map_err(Either::Right)?;
+        let dora = match &self.dora {
+            Some(_) => Some(
+                self.old
+                    .weight()
+                    .dim(0)
+                    .and_then(|out| DoraMagnitude::load(vb, &self.prefix, self.id, out))
+                    .map_err(Either::Right)?,
+            ),
+            None => None,
+        };
+        swap_weights(self, self.merged, |layer| {
+            layer.a_conv = Conv2d::new(a, None, *layer.a_conv.config());
+            layer.b_conv = Conv2d::new(b, None, *layer.b_conv.config());
+            layer.scale = config.scale(rank);
+            layer.dora = dora;
+        })
+    }
+}
+
+impl MultiAdapter for LoraConv2d {
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, config: &LoraConfig) -> Result<()> {
+        if self.dora.is_some() {
+            bail!("DoRA layers hold a single adapter")
+        }
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.a_conv.weight().shape(), init::ZERO, 0)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.b_conv.weight().shape(), init::ZERO, 1)?;
+        let scale = config.scale(a.dim(0)?);
+        self.adapters.insert(name, AdapterWeights { a, b, scale })
+    }
+
+    fn remove_adapter(&mut self, name: &str) -> Result<bool> {
+        if self.merged {
+            bail!("unmerge the layer before removing an adapter")
+        }
+        Ok(self.adapters.remove(name))
+    }
+
+    fn adapter_names(&self) -> Vec<String> {
+        self.adapters.names()
+    }
+
+    fn set_adapter_weights(&mut self, weights: &[(&str, f64)]) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before changing its adapter weights")
+        }
+        if self.dora.is_some() {
+            bail!("DoRA layers hold a single adapter")
+        }
+        self.adapters.set_weights(weights)
+    }
+
+    fn bake_adapter_weights(&mut self) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before baking its adapter weights")
+        }
+        // The rank of B is split across the groups, which concatenation would mix up.
+        if self.old.config().groups != 1 && self.adapters.is_weighted() {
+            bail!("the adapters of grouped convolutions cannot be baked")
+        }
+        if let Some(baked) = self.adapters.bake(&self.default_adapter(), 0, 1)? {
+            self.a_conv = Conv2d::new(baked.a, None, *self.a_conv.config());
+            self.b_conv = Conv2d::new(baked.b, None, *self.b_conv.config());
+            self.scale = baked.scale;
+        }
+        Ok(())
+    }
+}
+
 impl Module for LoraConv2d {
     fn forward(&self, input: &Tensor) -> Result<Tensor> {
-        if self.merged {
+        if self.merged || !adapters_enabled() {
             return self.old.forward(input);
         }
+        if batch_adapter_ids().is_some() {
+            bail!("adapters are selected per batch element in linear and embedding layers only")
+        }
+
+        if self.adapters.is_weighted() {
+            let a_input = lora_input(self.dropout.as_deref(), self.training, input)?;
+            let lora = self
+                .adapters
+                .weighted_sum(&self.default_adapter(), |a, b| {
+                    let after_a =
+                        Conv2d::new(a.clone(), None, *self.a_conv.config()).forward(&a_input)?;
+                    Conv2d::new(b.clone(), None, *self.b_conv.config()).forward(&after_a)
+                })?;
+            return match lora {
+                Some(lora) => self.old.forward(input)? + lora,
+                None => self.old.forward(input),
+            };
+        }
 
         if let Some(scale) = self.scale {
             let weight = self.old.forward(input)?;
-            let mut a_input = input.clone();
-            if self.dropout.is_some() {
-                a_input = self.dropout.as_ref().unwrap().forward(input, true)?;
-            }
+            let a_input = lora_input(self.dropout.as_deref(), self.training, input)?;
 
             let tmp = self.b_conv.forward(&self.a_conv.forward(&a_input)?)?;
 
+            if let Some(dora) = &self.dora {
+                // (m / ||W + delta|| - 1) W x + m / ||W + delta|| * delta x, on top of W x.
+                let kernel = self.old.weight();
+                let magnitude_scale =
+                    dora.scale(&(kernel + self.lora_delta()?)?)?
+                        .reshape((1, (), 1, 1))?;
+                let base = Conv2d::new(kernel.clone(), None, *self.config()).forward(&a_input)?;
+                let dora_result = (base.broadcast_mul(&(&magnitude_scale - 1.)?)?
+                    + tmp.mul(scale)?.broadcast_mul(&magnitude_scale)?)?;
+                return weight + dora_result;
+            }
+
             &weight + tmp.mul(scale)?
         } else {
             self.old.forward(input)
@@ -194,7 +381,7 @@ impl Module for LoraConv2d {
 }
 
 impl Saveable for LoraConv2d {
-    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
         accum.insert(
             self.prefix.clone() + &format!(".a{}.weight", self.id),
             self.a_conv.weight().clone(),
@@ -203,6 +390,47 @@ impl Saveable for LoraConv2d {
             self.prefix.clone() + &format!(".b{}.weight", self.id),
             self.b_conv.weight().clone(),
         );
+        if let Some(dora) = &self.dora {
+            accum.insert(
+                weight_name(&self.prefix, &format!("m{}", self.id)),
+                dora.magnitude()?,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Trainable for LoraConv2d {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut tensors = vec![
+            (
+                weight_name(&self.prefix, &format!("a{}", self.id)),
+                self.a_conv.weight().clone(),
+            ),
+            (
+                weight_name(&self.prefix, &format!("b{}", self.id)),
+                self.b_conv.weight().clone(),
+            ),
+        ];
+        if let Some(dora) = &self.dora {
+            tensors.push(dora.variable(&self.prefix, self.id));
+        }
+        tensors
+    }
+}
+
+impl AdapterDevice for LoraConv2d {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        let mut layer = self.clone();
+        let a = self.a_conv.weight().to_device(device)?;
+        let b = self.b_conv.weight().to_device(device)?;
+        layer.a_conv = Conv2d::new(a, None, *self.a_conv.config());
+        layer.b_conv = Conv2d::new(b, None, *self.b_conv.config());
+        if let Some(dora) = &self.dora {
+            layer.dora = Some(dora.to_device(device)?);
+        }
+        layer.adapters = self.adapters.to_device(device)?;
+        Ok(layer)
     }
 }
 
@@ -0,0 +1,339 @@
+//! LoRA on transposed convolutions, the upsampling layers of decoders and vocoders.
+//!
+//! The kernel of a transposed convolution is `(in_channels, out_channels / groups, kernel..)`.
+//! Its adapter reduces the input channels with A, `(rank, in_channels)`, and upsamples with B, a
+//! transposed kernel `(rank, out_channels / groups, kernel..)` of the size of the base kernel,
+//! so that `B A` is the delta kernel `A^T B`. The layers are created on their own, like
+//! [`crate::Ia3Linear`], and are not selected by [`crate::Lora::convert_model`].
+
+use std::{collections::HashMap, ops::Mul, sync::Arc};
+
+use candle_core::{bail, Module, Result, Tensor};
+use candle_nn::{
+    init, ConvTranspose1d, ConvTranspose1dConfig, ConvTranspose2d, ConvTranspose2dConfig, Dropout,
+    VarBuilder,
+};
+use either::Either;
+
+use crate::{
+    adapters::batch_adapter_ids, adapters_enabled, get_lora_weight, swap::weight_name, LoraConfig,
+    Merge, MergeError, MergeErrorOrError, Saveable, Trainable,
+};
+
+#[derive(Clone, Debug)]
+/// Configuration for LoraConvTranspose1d and LoraConvTranspose2d. Other configurations are
+/// inherited from the base layer.
+pub struct LoraConvTransposeConfig {
+    in_channels: usize,
+    out_channels: usize,
+}
+
+impl LoraConvTransposeConfig {
+    pub fn new(in_channels: usize, out_channels: usize) -> Self {
+        LoraConvTransposeConfig {
+            in_channels,
+            out_channels,
+        }
+    }
+}
+
+/// The adapter of a transposed convolution, shared by the 1D and 2D layers.
+#[derive(Debug, Clone)]
+struct TransposeAdapter {
+    a: Tensor,
+    b: Tensor,
+    scale: Option<f64>,
+    dropout: Option<Arc<Dropout>>,
+    training: bool,
+    merged: bool,
+    prefix: String,
+    id: usize,
+}
+
+impl TransposeAdapter {
+    fn new(
+        weight: &Tensor,
+        groups: usize,
+        conv_config: &LoraConvTransposeConfig,
+        config: &LoraConfig,
+        vb: &VarBuilder,
+        id: usize,
+    ) -> Result<Self> {
+        let dims = weight.dims();
+        let channels = (conv_config.in_channels, conv_config.out_channels);
+        if channels != (dims[0], dims[1] * groups) {
+            let layer = (dims[0], dims[1] * groups);
+            bail!("channels {channels:?} do not match the layer's {layer:?}");
+        }
+        let a = get_lora_weight(
+            &vb.pp(format!("a{id}")),
+            (config.rank, conv_config.in_channels),
+            config.a_init(config.rank),
+            0,
+        )?;
+        let rank = a.dim(0)?;
+        let mut b_shape = dims.to_vec();
+        b_shape[0] = rank;
+        let b = vb
+            .pp(format!("b{id}"))
+            .get_with_hints(b_shape, "weight", init::ZERO)?;
+
+        let training = a.is_variable();
+        Ok(Self {
+            a,
+            b,
+            scale: config.scale(rank),
+            dropout: config.dropout.map(|x| Arc::new(Dropout::new(x))),
+            training,
+            merged: false,
+            prefix: vb.prefix(),
+            id,
+        })
+    }
+
+    /// The delta kernel `scale * A^T B` in the shape of the base kernel.
+    fn delta(&self, weight: &Tensor) -> Result<Tensor> {
+        let delta = self
+            .a
+            .t()?
+            .matmul(&self.b.flatten_from(1)?)?
+            .reshape(weight.shape())?;
+        match self.scale {
+            Some(scale) => delta.mul(scale),
+            None => Ok(delta),
+        }
+    }
+
+    /// The input of the adapter, with dropout in training mode, or `None` when the adapter
+    /// does not apply.
+    fn lora_input(&self, input: &Tensor) -> Result<Option<Tensor>> {
+        if self.merged || !adapters_enabled() || self.scale.is_none() {
+            return Ok(None);
+        }
+        if batch_adapter_ids().is_some() {
+            bail!("adapters are selected per batch element in linear and embedding layers only")
+        }
+        match (self.training, &self.dropout) {
+            (true, Some(dropout)) => Ok(Some(dropout.forward(input, true)?)),
+            _ => Ok(Some(input.clone())),
+        }
+    }
+
+    fn tensors(&self) -> Vec<(String, Tensor)> {
+        vec![
+            (
+                weight_name(&self.prefix, &format!("a{}", self.id)),
+                self.a.clone(),
+            ),
+            (
+                weight_name(&self.prefix, &format!("b{}", self.id)),
+                self.b.clone(),
+            ),
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoraConvTranspose1d {
+    old: ConvTranspose1d,
+    adapter: TransposeAdapter,
+}
+
+impl LoraConvTranspose1d {
+    pub fn new(
+        old: &ConvTranspose1d,
+        conv_config: &LoraConvTransposeConfig,
+        config: &LoraConfig,
+        vb: &VarBuilder,
+        id: usize,
+    ) -> Result<Self> {
+        let weight = old.weight();
+        let adapter =
+            TransposeAdapter::new(weight, old.config().groups, conv_config, config, vb, id)?;
+        Ok(Self {
+            old: frozen_transpose1d(weight, old.bias(), *old.config()),
+            adapter,
+        })
+    }
+
+    /// Apply the LoRA dropout or not, see [`LoraLinear::train`](crate::LoraLinear::train).
+    pub fn train(&mut self, training: bool) {
+        self.adapter.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.adapter.training
+    }
+
+    /// A plain `ConvTranspose1d` with the LoRA weights folded in, to run inference without the
+    /// LoRA overhead.
+    pub fn merged_conv_transpose1d(&self) -> Result<ConvTranspose1d> {
+        let weight = if self.adapter.merged {
+            self.old.weight().clone()
+        } else {
+            (self.old.weight() + self.adapter.delta(self.old.weight())?)?
+        };
+        let bias = self.old.bias().cloned();
+        Ok(ConvTranspose1d::new(weight, bias, *self.old.config()))
+    }
+}
+
+fn frozen_transpose1d(
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    config: ConvTranspose1dConfig,
+) -> ConvTranspose1d {
+    ConvTranspose1d::new(weight.detach(), bias.cloned(), config)
+}
+
+impl Merge for LoraConvTranspose1d {
+    fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
+        self.adapter.delta(self.old.weight()).map_err(Either::Right)
+    }
+
+    fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if self.adapter.merged {
+            return Err(Either::Left(MergeError::AlreadyMerged));
+        }
+        let weight = (self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?;
+        self.old = frozen_transpose1d(&weight, self.old.bias(), *self.old.config());
+        self.adapter.merged = true;
+        Ok(())
+    }
+
+    fn unmerge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if !self.adapter.merged {
+            return Err(Either::Left(MergeError::NotMerged));
+        }
+        let weight = (self.old.weight() - self.get_delta_weight()?).map_err(Either::Right)?;
+        self.old = frozen_transpose1d(&weight, self.old.bias(), *self.old.config());
+        self.adapter.merged = false;
+        Ok(())
+    }
+}
+
+impl Module for LoraConvTranspose1d {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let Some(lora_input) = self.adapter.lora_input(input)? else {
+            return self.old.forward(input);
+        };
+        let delta = self.adapter.delta(self.old.weight())?;
+        let lora = ConvTranspose1d::new(delta, None, *self.old.config()).forward(&lora_input)?;
+        self.old.forward(input)? + lora
+    }
+}
+
+impl Saveable for LoraConvTranspose1d {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        accum.extend(self.adapter.tensors());
+        Ok(())
+    }
+}
+
+impl Trainable for LoraConvTranspose1d {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        self.adapter.tensors()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoraConvTranspose2d {
+    old: ConvTranspose2d,
+    adapter: TransposeAdapter,
+}
+
+impl LoraConvTranspose2d {
+    pub fn new(
+        old: &ConvTranspose2d,
+        conv_config: &LoraConvTransposeConfig,
+        config: &LoraConfig,
+        vb: &VarBuilder,
+        id: usize,
+    ) -> Result<Self> {
+        let weight = old.weight();
+        let adapter = TransposeAdapter::new(weight, 1, conv_config, config, vb, id)?;
+        Ok(Self {
+            old: frozen_transpose2d(weight, old.bias(), *old.config()),
+            adapter,
+        })
+    }
+
+    /// Apply the LoRA dropout or not, see [`LoraLinear::train`](crate::LoraLinear::train).
+    pub fn train(&mut self, training: bool) {
+        self.adapter.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.adapter.training
+    }
+
+    /// A plain `ConvTranspose2d` with the LoRA weights folded in, to run inference without the
+    /// LoRA overhead.
+    pub fn merged_conv_transpose2d(&self) -> Result<ConvTranspose2d> {
+        let weight = if self.adapter.merged {
+            self.old.weight().clone()
+        } else {
+            (self.old.weight() + self.adapter.delta(self.old.weight())?)?
+        };
+        let bias = self.old.bias().cloned();
+        Ok(ConvTranspose2d::new(weight, bias, *self.old.config()))
+    }
+}
+
+fn frozen_transpose2d(
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    config: ConvTranspose2dConfig,
+) -> ConvTranspose2d {
+    ConvTranspose2d::new(weight.detach(), bias.cloned(), config)
+}
+
+impl Merge for LoraConvTranspose2d {
+    fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
+        self.adapter.delta(self.old.weight()).map_err(Either::Right)
+    }
+
+    fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if self.adapter.merged {
+            return Err(Either::Left(MergeError::AlreadyMerged));
+        }
+        let weight = (self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?;
+        self.old = frozen_transpose2d(&weight, self.old.bias(), *self.old.config());
+        self.adapter.merged = true;
+        Ok(())
+    }
+
+    fn unmerge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if !self.adapter.merged {
+            return Err(Either::Left(MergeError::NotMerged));
+        }
+        let weight = (self.old.weight() - self.get_delta_weight()?).map_err(Either::Right)?;
+        self.old = frozen_transpose2d(&weight, self.old.bias(), *self.old.config());
+        self.adapter.merged = false;
+        Ok(())
+    }
+}
+
+impl Module for LoraConvTranspose2d {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let Some(lora_input) = self.adapter.lora_input(input)? else {
+            return self.old.forward(input);
+        };
+        let delta = self.adapter.delta(self.old.weight())?;
+        let lora = ConvTranspose2d::new(delta, None, *self.old.config()).forward(&lora_input)?;
+        self.old.forward(input)? + lora
+    }
+}
+
+impl Saveable for LoraConvTranspose2d {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        accum.extend(self.adapter.tensors());
+        Ok(())
+    }
+}
+
+impl Trainable for LoraConvTranspose2d {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        self.adapter.tensors()
+    }
+}
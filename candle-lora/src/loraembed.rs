@@ -1,12 +1,18 @@
 use std::{collections::HashMap, ops::Mul, sync::Arc};
 
-use candle_core::{Module, Result, Tensor};
+use candle_core::{bail, DType, Device, Module, Result, Tensor};
 use candle_nn::{init, Embedding, Init, VarBuilder};
 use either::Either;
 
 use crate::{
-    frozenembed::FrozenEmbedding, EmbeddingLayerLike, LoraConfig, Merge, MergeError,
-    MergeErrorOrError, Saveable,
+    adapters::batch_adapter_ids,
+    adapters_enabled,
+    frozenembed::FrozenEmbedding,
+    get_lora_weight,
+    multi_adapter::{AdapterSet, AdapterWeights},
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterDevice, AdapterSwap, EmbeddingLayerLike, LoraConfig, Merge, MergeError,
+    MergeErrorOrError, MultiAdapter, Saveable, Trainable,
 };
 
 #[derive(Debug, Clone)]
@@ -19,6 +25,7 @@ pub struct LoraEmbedding {
     merged: bool,
     prefix: String,
     id: usize,
+    adapters: AdapterSet,
 }
 
 #[derive(Clone, Debug)]
@@ -45,13 +52,15 @@ impl LoraEmbedding {
         vb: &VarBuilder,
         id: usize,
     ) -> Result<Self> {
-        let a = vb.pp(format!("a{id}")).get_with_hints(
+        let a = get_lora_weight(
+            &vb.pp(format!("a{id}")),
             (config.rank, embed_config.num_embeddings),
-            "weight",
             init::ZERO,
+            0,
         )?;
+        let rank = a.dim(0)?;
         let b: Tensor = vb.pp(format!("b{id}")).get_with_hints(
-            (embed_config.embedding_dim, config.rank),
+            (embed_config.embedding_dim, rank),
             "weight",
             Init::Randn {
                 mean: 0.0,
@@ -68,20 +77,71 @@ impl LoraEmbedding {
             embed_a,
             a,
             b,
-            scale: if config.rank > 0 {
-                Some(config.alpha / config.rank as f64)
-            } else {
-                None
-            },
+            scale: config.scale(rank),
             merged: false,
             prefix: vb.prefix(),
             id,
+            adapters: AdapterSet::default(),
         })
     }
+
+    /// `scale_k * (B_k A_k)^T` at the tokens of each element of the batch, `k` being its adapter,
+    /// see [`crate::select_batch_adapters`].
+    fn batched_lora(&self, input: &Tensor, adapter_ids: &[u32]) -> Result<Tensor> {
+        let dims = input.dims();
+        let Some(&batch_size) = dims.first() else {
+            bail!("expected a batch of token ids, got a scalar")
+        };
+        let ids = self
+            .adapters
+            .batch_ids(adapter_ids, batch_size, input.device())?;
+        let (a, b) = self.adapters.stacked(&self.default_adapter())?;
+        let (num_adapters, rank, vocab) = a.dims3()?;
+        // The rows of the A^T of all the adapters, token t of adapter k at k * vocab + t.
+        let rows = a.transpose(1, 2)?.reshape((num_adapters * vocab, rank))?;
+        let offsets = adapter_ids
+            .iter()
+            .map(|&id| id * vocab as u32)
+            .collect::<Vec<_>>();
+        let offsets = Tensor::from_vec(offsets, (batch_size, 1), input.device())?;
+        let tokens = input.reshape((batch_size, ()))?.to_dtype(DType::U32)?;
+        let rows = rows.index_select(&tokens.broadcast_add(&offsets)?.flatten_all()?, 0)?;
+        let after_a = rows.reshape((batch_size, (), rank))?;
+        let lora = after_a.matmul(&b.index_select(&ids, 0)?.t()?)?;
+        let mut out_dims = dims.to_vec();
+        out_dims.push(lora.dim(2)?);
+        lora.reshape(out_dims)
+    }
+
+    fn default_adapter(&self) -> AdapterWeights {
+        AdapterWeights {
+            a: self.a.clone(),
+            b: self.b.clone(),
+            scale: self.scale,
+        }
+    }
+
+    /// A plain `Embedding` with the LoRA weights folded in, `W + alpha / r * (B A)^T`, to run
+    /// inference without the LoRA overhead.
+    pub fn merged_embedding(&self) -> std::result::Result<Embedding, MergeErrorOrError> {
+        let weight = if self.merged {
+            self.embeddings().clone()
+        } else {
+            (self.embeddings() + self.get_delta_weight()?.transpose(0, 1)).map_err(Either::Right)?
+        };
+        Ok(Embedding::new(weight, self.hidden_size()))
+    }
 }
 
 impl Merge for LoraEmbedding {
     fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
+        let weighted = self
+            .adapters
+            .weighted_sum(&self.default_adapter(), |a, b| b.matmul(a))
+            .map_err(Either::Right)?;
+        if let Some(delta) = weighted {
+            return Ok(delta);
+        }
         let result = self.b.matmul(&self.a).map_err(Either::Right)?;
         Ok(match self.scale {
             Some(scale) => result.mul(scale).map_err(Either::Right)?,
@@ -124,10 +184,92 @@ impl Merge for LoraEmbedding {
     }
 }
 
+impl AdapterSwap for LoraEmbedding {
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.a.shape(), init::ZERO, 0).map_err(Either::Right)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.b.shape(), init::ZERO, 1).map_err(Either::Right)?;
+        let rank = a.dim(0).map_err(Either::Right)?;
+        let a_t = a
+            .t()
+            .and_then(|a_t| a_t.contiguous())
+            .map_err(Either::Right)?;
+        swap_weights(self, self.merged, |layer| {
+            layer.embed_a = Embedding::new(a_t, rank);
+            layer.a = a;
+            layer.b = b;
+            layer.scale = config.scale(rank);
+        })
+    }
+}
+
+impl MultiAdapter for LoraEmbedding {
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, config: &LoraConfig) -> Result<()> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.a.shape(), init::ZERO, 0)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.b.shape(), init::ZERO, 1)?;
+        let scale = config.scale(a.dim(0)?);
+        self.adapters.insert(name, AdapterWeights { a, b, scale })
+    }
+
+    fn remove_adapter(&mut self, name: &str) -> Result<bool> {
+        if self.merged {
+            bail!("unmerge the layer before removing an adapter")
+        }
+        Ok(self.adapters.remove(name))
+    }
+
+    fn adapter_names(&self) -> Vec<String> {
+        self.adapters.names()
+    }
+
+    fn set_adapter_weights(&mut self, weights: &[(&str, f64)]) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before changing its adapter weights")
+        }
+        self.adapters.set_weights(weights)
+    }
+
+    fn bake_adapter_weights(&mut self) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before baking its adapter weights")
+        }
+        if let Some(baked) = self.adapters.bake(&self.default_adapter(), 0, 1)? {
+            let rank = baked.a.dim(0)?;
+            self.embed_a = Embedding::new(baked.a.t()?.contiguous()?, rank);
+            self.a = baked.a;
+            self.b = baked.b;
+            self.scale = baked.scale;
+        }
+        Ok(())
+    }
+}
+
 impl Module for LoraEmbedding {
     fn forward(&self, input: &Tensor) -> Result<Tensor> {
         let mut result = self.old.forward(input)?;
-        if let Some(scale) = self.scale {
+        if self.merged || !adapters_enabled() {
+            return Ok(result);
+        }
+        if let Some(adapter_ids) = batch_adapter_ids() {
+            result = (result + self.batched_lora(input, &adapter_ids)?)?;
+        } else if self.adapters.is_weighted() {
+            let lora = self
+                .adapters
+                .weighted_sum(&self.default_adapter(), |a, b| {
+                    let embed_a = Embedding::new(a.t()?.contiguous()?, a.dim(0)?);
+                    embed_a.forward(input)?.broadcast_matmul(&b.t()?)
+                })?;
+            if let Some(lora) = lora {
+                result = (result + lora)?;
+            }
+        } else if let Some(scale) = self.scale {
             let b = self.b.t()?;
             let b = b.reshape(b.shape())?;
 
@@ -139,7 +281,7 @@ impl Module for LoraEmbedding {
 }
 
 impl Saveable for LoraEmbedding {
-    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
         accum.insert(
             self.prefix.clone() + &format!(".a{}.weight", self.id),
             self.a.clone(),
@@ -148,6 +290,34 @@ impl Saveable for LoraEmbedding {
             self.prefix.clone() + &format!(".b{}.weight", self.id),
             self.b.clone(),
         );
+        Ok(())
+    }
+}
+
+impl Trainable for LoraEmbedding {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        vec![
+            (
+                weight_name(&self.prefix, &format!("a{}", self.id)),
+                self.a.clone(),
+            ),
+            (
+                weight_name(&self.prefix, &format!("b{}", self.id)),
+                self.b.clone(),
+            ),
+        ]
+    }
+}
+
+impl AdapterDevice for LoraEmbedding {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        let mut layer = self.clone();
+        layer.a = self.a.to_device(device)?;
+        layer.b = self.b.to_device(device)?;
+        let a_t = self.embed_a.embeddings().to_device(device)?;
+        layer.embed_a = Embedding::new(a_t, self.embed_a.hidden_size());
+        layer.adapters = self.adapters.to_device(device)?;
+        Ok(layer)
     }
 }
 
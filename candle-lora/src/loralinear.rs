@@ -1,12 +1,20 @@
 use std::{collections::HashMap, ops::Mul, sync::Arc};
 
-use candle_core::{Module, Result, Shape, Tensor};
+use candle_core::{bail, Device, Module, Result, Shape, Tensor};
 use candle_nn::{init, Dropout, Linear, VarBuilder};
 use either::Either;
 
 use crate::{
-    frozenlinear::FrozenLinear, LinearLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError,
-    Saveable,
+    adapters::batch_adapter_ids,
+    adapters_enabled,
+    dora::DoraMagnitude,
+    frozenlinear::FrozenLinear,
+    fused::{LoraForward, WeightCache},
+    get_lora_weight, lora_input,
+    multi_adapter::{AdapterSet, AdapterWeights},
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterDevice, AdapterSwap, LinearLayerLike, LoraConfig, Merge, MergeError, MergeErrorOrError,
+    MultiAdapter, Saveable, Trainable,
 };
 
 #[derive(Debug, Clone)]
@@ -16,16 +24,32 @@ pub struct LoraLinear {
     ff_b: Linear,
     scale: Option<f64>,
     dropout: Option<Arc<Dropout>>,
+    training: bool,
     merged: bool,
     prefix: String,
     id: usize,
+    tied: Option<TiedScaling>,
+    /// Names of A and B, which are shared across layers with Tied-LoRA.
+    names: (String, String),
+    adapters: AdapterSet,
+    dora: Option<DoraMagnitude>,
+    forward: LoraForward,
+    cache: WeightCache,
+}
+
+/// The per-layer scaling vectors of Tied-LoRA, `u` over the outputs and `v` over the rank.
+#[derive(Debug, Clone)]
+struct TiedScaling {
+    u: Tensor,
+    v: Tensor,
 }
 
 #[derive(Clone, Debug)]
 /// Configuration for LoraLinear
 pub struct LoraLinearConfig {
-    in_features: usize,
-    out_features: usize,
+    pub(crate) in_features: usize,
+    pub(crate) out_features: usize,
+    pub(crate) use_dora: bool,
 }
 
 impl LoraLinearConfig {
@@ -33,8 +57,16 @@ impl LoraLinearConfig {
         LoraLinearConfig {
             in_features,
             out_features,
+            use_dora: false,
         }
     }
+
+    /// Adapt the layer with DoRA (weight-decomposed LoRA), which trains a magnitude per output
+    /// feature on top of LoRA. Fresh magnitudes start at the norms of the base weight.
+    pub fn with_dora(mut self) -> Self {
+        self.use_dora = true;
+        self
+    }
 }
 
 impl LoraLinear {
@@ -45,58 +77,247 @@ impl LoraLinear {
         vb: &VarBuilder,
         id: usize,
     ) -> Result<Self> {
-        let a = vb.pp(format!("a{id}")).get_with_hints(
+        if linear_config.use_dora && config.tied.is_some() {
+            bail!("DoRA is not supported with Tied-LoRA")
+        }
+        // Tied matrices are shared through a root prefix, keyed by their size.
+        let (a_vb, b_vb) = match &config.tied {
+            Some(tied) => {
+                let shared = vb.root().pp(&tied.prefix);
+                let b_vb = if tied.share_b {
+                    shared.pp(format!("b_{}", linear_config.out_features))
+                } else {
+                    vb.pp(format!("b{id}"))
+                };
+                (shared.pp(format!("a_{}", linear_config.in_features)), b_vb)
+            }
+            None => (vb.pp(format!("a{id}")), vb.pp(format!("b{id}"))),
+        };
+        let a = get_lora_weight(
+            &a_vb,
             (config.rank, linear_config.in_features),
-            "weight",
-            init::DEFAULT_KAIMING_NORMAL,
-        )?;
-        let b = vb.pp(format!("b{id}")).get_with_hints(
-            (linear_config.out_features, config.rank),
-            "weight",
-            init::ZERO,
+            config.a_init(config.rank),
+            0,
         )?;
+        let rank = a.dim(0)?;
+        let b = get_lora_weight(&b_vb, (linear_config.out_features, rank), init::ZERO, 1)?;
+        if b.dim(1)? != rank {
+            bail!("B of rank {} for A of rank {rank}", b.dim(1)?)
+        }
+        let tied = match config.tied {
+            Some(_) => Some(TiedScaling {
+                u: vb.pp(format!("u{id}")).get_with_hints(
+                    linear_config.out_features,
+                    "weight",
+                    init::Init::Const(1.),
+                )?,
+                v: vb
+                    .pp(format!("v{id}"))
+                    .get_with_hints(rank, "weight", init::Init::Const(1.))?,
+            }),
+            None => None,
+        };
 
+        let training = a.is_variable();
         Ok(LoraLinear {
             old: Arc::new(FrozenLinear::new_from_linear(old)?),
             ff_a: Linear::new(a, None),
             ff_b: Linear::new(b, None),
-            scale: if config.rank > 0 {
-                Some(config.alpha / config.rank as f64)
-            } else {
-                None
-            },
+            scale: config.scale(rank),
             dropout: config.dropout.map(|x| Arc::new(Dropout::new(x))),
+            training,
             merged: false,
             prefix: vb.prefix(),
             id,
+            tied,
+            names: match config.tied {
+                Some(_) => (
+                    format!("{}.weight", a_vb.prefix()),
+                    format!("{}.weight", b_vb.prefix()),
+                ),
+                None => (
+                    weight_name(&vb.prefix(), &format!("a{id}")),
+                    weight_name(&vb.prefix(), &format!("b{id}")),
+                ),
+            },
+            adapters: AdapterSet::default(),
+            dora: match linear_config.use_dora {
+                true => Some(DoraMagnitude::new(vb, id, old.weight())?),
+                false => None,
+            },
+            forward: LoraForward::default(),
+            cache: WeightCache::default(),
+        })
+    }
+
+    /// Apply the LoRA dropout of the config in the forward pass (`true`), as PEFT does in
+    /// training mode, or not (`false`), for inference. Layers whose adapter is trainable,
+    /// created from a `VarMap`, start in training mode, layers loaded from files do not.
+    pub fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    /// Whether the layer applies the LoRA dropout, see [`LoraLinear::train`].
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// Apply a frozen adapter from its delta weight, or from the base weight with the delta
+    /// added, computed once per device, rather than through A and B, see [`LoraForward`].
+    pub fn set_forward(&mut self, forward: LoraForward) {
+        self.forward = forward;
+        self.cache.clear();
+    }
+
+    /// How the layer applies a frozen adapter, see [`LoraLinear::set_forward`].
+    pub fn forward_mode(&self) -> LoraForward {
+        self.forward
+    }
+
+    /// Whether the forward pass uses the weights cached for a frozen adapter: the adapter is not
+    /// trainable, the layer is in inference mode, and a single adapter applies to the batch.
+    fn uses_cache(&self) -> bool {
+        let variable = |tensor: &Tensor| tensor.is_variable();
+        let tied_variable = match &self.tied {
+            Some(TiedScaling { u, v }) => variable(u) || variable(v),
+            None => false,
+        };
+        self.forward != LoraForward::Factored
+            && self.scale.is_some()
+            && !self.training
+            && self.dora.is_none()
+            && !variable(self.ff_a.weight())
+            && !variable(self.ff_b.weight())
+            && !tied_variable
+            && !self.adapters.is_weighted()
+            && batch_adapter_ids().is_none()
+    }
+
+    /// `W x + D x` or `(W + D) x` with the weight cached for the device of `input`.
+    fn cached_forward(&self, input: &Tensor) -> Result<Tensor> {
+        let device = input.device();
+        match self.forward {
+            LoraForward::FusedWeight => {
+                let weight = self
+                    .cache
+                    .get_or_insert(device, || self.old.weight() + self.lora_delta()?)?;
+                let bias = self.old.bias().map(|bias| bias.to_device(device));
+                Linear::new(weight, bias.transpose()?).forward(input)
+            }
+            _ => {
+                let delta = self.cache.get_or_insert(device, || self.lora_delta())?;
+                self.old.forward(input)? + Linear::new(delta, None).forward(input)?
+            }
+        }
+    }
+
+    /// The B matrix scaled by the Tied-LoRA vectors, `diag(u) B diag(v)`.
+    fn scaled_b(&self) -> Result<Tensor> {
+        let b = self.ff_b.weight();
+        match &self.tied {
+            Some(TiedScaling { u, v }) => b
+                .broadcast_mul(&u.unsqueeze(1)?)?
+                .broadcast_mul(&v.unsqueeze(0)?),
+            None => Ok(b.clone()),
+        }
+    }
+
+    /// The LoRA delta weight `scale * B A` of the adapter the layer was created with.
+    fn lora_delta(&self) -> Result<Tensor> {
+        let result = self.scaled_b()?.matmul(self.ff_a.weight())?;
+        match self.scale {
+            Some(scale) => result.mul(scale),
+            None => Ok(result),
+        }
+    }
+
+    /// The adapter the layer was created with, B being scaled by the Tied-LoRA vectors.
+    /// `scale_k * B_k A_k x` for each element `x` of the batch, `k` being its adapter, see
+    /// [`crate::select_batch_adapters`].
+    fn batched_lora(&self, input: &Tensor, adapter_ids: &[u32]) -> Result<Tensor> {
+        if self.tied.is_some() || self.dora.is_some() {
+            bail!("Tied-LoRA and DoRA layers hold a single adapter")
+        }
+        let dims = input.dims();
+        if dims.len() < 2 {
+            bail!("expected a batch of inputs, got shape {dims:?}")
+        }
+        let ids = self
+            .adapters
+            .batch_ids(adapter_ids, dims[0], input.device())?;
+        let (a, b) = self.adapters.stacked(&self.default_adapter()?)?;
+        let (a, b) = (a.index_select(&ids, 0)?, b.index_select(&ids, 0)?);
+        let xs = input.reshape((dims[0], (), dims[dims.len() - 1]))?;
+        let lora = xs.matmul(&a.t()?)?.matmul(&b.t()?)?;
+        let mut out_dims = dims.to_vec();
+        out_dims[dims.len() - 1] = b.dim(1)?;
+        lora.reshape(out_dims)
+    }
+
+    fn default_adapter(&self) -> Result<AdapterWeights> {
+        Ok(AdapterWeights {
+            a: self.ff_a.weight().clone(),
+            b: self.scaled_b()?,
+            scale: self.scale,
         })
     }
+
+    /// A plain `Linear` with the LoRA weights folded in, `W + alpha / r * B A`, to run inference
+    /// without the LoRA overhead.
+    pub fn merged_linear(&self) -> std::result::Result<Linear, MergeErrorOrError> {
+        let weight = if self.merged {
+            self.old.weight().clone()
+        } else {
+            (self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?
+        };
+        Ok(Linear::new(weight, self.old.bias().cloned()))
+    }
 }
 
 impl Merge for LoraLinear {
     fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
-        let result = self
-            .ff_b
-            .weight()
-            .matmul(self.ff_a.weight())
+        let weighted = self
+            .default_adapter()
+            .and_then(|default| self.adapters.weighted_sum(&default, |a, b| b.matmul(a)))
             .map_err(Either::Right)?;
-        Ok(match self.scale {
-            Some(scale) => result.mul(scale).map_err(Either::Right)?,
-            None => result,
-        })
+        if let Some(delta) = weighted {
+            return Ok(delta);
+        }
+        match &self.dora {
+            Some(DoraMagnitude {
+                merged_delta: Some(delta),
+                ..
+            }) => Ok(delta.clone()),
+            // m * (W + delta) / ||W + delta|| - W
+            Some(dora) => {
+                let weight = self.old.weight();
+                let dora_delta = self.lora_delta().and_then(|delta| {
+                    let adapted = (weight + delta)?;
+                    let scale = dora.scale(&adapted)?.unsqueeze(1)?;
+                    adapted.broadcast_mul(&scale)? - weight
+                });
+                dora_delta.map_err(Either::Right)
+            }
+            None => self.lora_delta().map_err(Either::Right),
+        }
     }
 
     fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
         if self.merged {
             Err(Either::Left(MergeError::AlreadyMerged))
         } else {
+            let delta = self.get_delta_weight()?;
             self.old = Arc::new(
                 FrozenLinear::new(
-                    (self.old.weight() + self.get_delta_weight()?).map_err(Either::Right)?,
+                    (self.old.weight() + &delta).map_err(Either::Right)?,
                     self.old.bias().cloned(),
                 )
                 .map_err(Either::Right)?,
             );
+            if let Some(dora) = &mut self.dora {
+                dora.merged_delta = Some(delta);
+            }
+            self.cache.clear();
             self.merged = true;
             Ok(())
         }
@@ -113,28 +334,166 @@ impl Merge for LoraLinear {
                 )
                 .map_err(Either::Right)?,
             );
+            if let Some(dora) = &mut self.dora {
+                dora.merged_delta = None;
+            }
+            self.cache.clear();
             self.merged = false;
             Ok(())
         }
     }
 }
 
+impl AdapterSwap for LoraLinear {
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        // Tied matrices are read from their shared names.
+        let weight_vb = |name: &str| {
+            let path = name.strip_suffix(".weight").unwrap_or(name);
+            vb.pp(path.trim_start_matches('.'))
+        };
+        let a = get_lora_weight(
+            &weight_vb(&self.names.0),
+            self.ff_a.weight().shape(),
+            init::ZERO,
+            0,
+        )
+        .map_err(Either::Right)?;
+        let rank = a.dim(0).map_err(Either::Right)?;
+        let b = get_lora_weight(
+            &weight_vb(&self.names.1),
+            self.ff_b.weight().shape(),
+            init::ZERO,
+            1,
+        )
+        .map_err(Either::Right)?;
+        let tied = match &self.tied {
+            Some(TiedScaling { u, .. }) => Some(TiedScaling {
+                u: adapter_vb(vb, &self.prefix, &format!("u{}", self.id))
+                    .get_with_hints(u.shape(), "weight", init::Init::Const(1.))
+                    .map_err(Either::Right)?,
+                v: adapter_vb(vb, &self.prefix, &format!("v{}", self.id))
+                    .get_with_hints(rank, "weight", init::Init::Const(1.))
+                    .map_err(Either::Right)?,
+            }),
+            None => None,
+        };
+        let dora = match &self.dora {
+            Some(_) => Some(
+                self.ff_b
+                    .weight()
+                    .dim(0)
+                    .and_then(|out| DoraMagnitude::load(vb, &self.prefix, self.id, out))
+                    .map_err(Either::Right)?,
+            ),
+            None => None,
+        };
+        swap_weights(self, self.merged, |layer| {
+            layer.ff_a = Linear::new(a, None);
+            layer.ff_b = Linear::new(b, None);
+            layer.scale = config.scale(rank);
+            layer.tied = tied;
+            layer.dora = dora;
+            layer.cache.clear();
+        })
+    }
+}
+
+impl MultiAdapter for LoraLinear {
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, config: &LoraConfig) -> Result<()> {
+        if self.tied.is_some() || self.dora.is_some() {
+            bail!("Tied-LoRA and DoRA layers hold a single adapter")
+        }
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.ff_a.weight().shape(), init::ZERO, 0)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.ff_b.weight().shape(), init::ZERO, 1)?;
+        let scale = config.scale(a.dim(0)?);
+        self.adapters.insert(name, AdapterWeights { a, b, scale })
+    }
+
+    fn remove_adapter(&mut self, name: &str) -> Result<bool> {
+        if self.merged {
+            bail!("unmerge the layer before removing an adapter")
+        }
+        Ok(self.adapters.remove(name))
+    }
+
+    fn adapter_names(&self) -> Vec<String> {
+        self.adapters.names()
+    }
+
+    fn set_adapter_weights(&mut self, weights: &[(&str, f64)]) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before changing its adapter weights")
+        }
+        if self.tied.is_some() || self.dora.is_some() {
+            bail!("Tied-LoRA and DoRA layers hold a single adapter")
+        }
+        self.adapters.set_weights(weights)
+    }
+
+    fn bake_adapter_weights(&mut self) -> Result<()> {
+        if self.merged {
+            bail!("unmerge the layer before baking its adapter weights")
+        }
+        if let Some(baked) = self.adapters.bake(&self.default_adapter()?, 0, 1)? {
+            self.ff_a = Linear::new(baked.a, None);
+            self.ff_b = Linear::new(baked.b, None);
+            self.scale = baked.scale;
+            self.cache.clear();
+        }
+        Ok(())
+    }
+}
+
 impl Module for LoraLinear {
     fn forward(&self, input: &Tensor) -> Result<Tensor> {
-        if self.merged {
+        if self.merged || !adapters_enabled() {
             self.old.forward(input)
+        } else if self.uses_cache() {
+            self.cached_forward(input)
         } else {
             //No fan_in_fan_out so no weight.transpose(0,1)
             let mut result = self.old.forward(input)?;
-            if let Some(scale) = self.scale {
-                let input_new = if self.dropout.is_some() {
-                    self.dropout.as_ref().unwrap().forward(input, true)?
-                } else {
-                    input.clone()
-                };
+            if let Some(adapter_ids) = batch_adapter_ids() {
+                let input_new = lora_input(self.dropout.as_deref(), self.training, input)?;
+                result = (result + self.batched_lora(&input_new, &adapter_ids)?)?;
+            } else if self.adapters.is_weighted() {
+                let input_new = lora_input(self.dropout.as_deref(), self.training, input)?;
+                let lora = self
+                    .adapters
+                    .weighted_sum(&self.default_adapter()?, |a, b| {
+                        let after_a = Linear::new(a.clone(), None).forward(&input_new)?;
+                        Linear::new(b.clone(), None).forward(&after_a)
+                    })?;
+                if let Some(lora) = lora {
+                    result = (result + lora)?;
+                }
+            } else if let Some(scale) = self.scale {
+                let input_new = lora_input(self.dropout.as_deref(), self.training, input)?;
+                if let Some(dora) = &self.dora {
+                    // (m / ||W + delta|| - 1) W x + m / ||W + delta|| * delta x, on top of W x.
+                    let weight = self.old.weight();
+                    let magnitude_scale = dora.scale(&(weight + self.lora_delta()?)?)?;
+                    let base = Linear::new(weight.clone(), None).forward(&input_new)?;
+                    let lora = self.ff_b.forward(&self.ff_a.forward(&input_new)?)?;
+                    let dora_result = (base.broadcast_mul(&(&magnitude_scale - 1.)?)?
+                        + lora.mul(scale)?.broadcast_mul(&magnitude_scale)?)?;
+                    return result + dora_result;
+                }
 
-                result =
-                    (result + self.ff_b.forward(&self.ff_a.forward(&input_new)?))?.mul(scale)?;
+                let lora = match &self.tied {
+                    Some(TiedScaling { u, v }) => self
+                        .ff_b
+                        .forward(&self.ff_a.forward(&input_new)?.broadcast_mul(v)?)?
+                        .broadcast_mul(u)?,
+                    None => self.ff_b.forward(&self.ff_a.forward(&input_new)?)?,
+                };
+                result = (result + lora.mul(scale)?)?;
             }
             Ok(result)
         }
@@ -142,15 +501,69 @@ impl Module for LoraLinear {
 }
 
 impl Saveable for LoraLinear {
-    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) {
-        accum.insert(
-            self.prefix.clone() + &format!(".a{}.weight", self.id),
-            self.ff_a.weight().clone(),
-        );
-        accum.insert(
-            self.prefix.clone() + &format!(".b{}.weight", self.id),
-            self.ff_b.weight().clone(),
-        );
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        // Shared matrices are inserted by each layer under the same name.
+        accum.insert(self.names.0.clone(), self.ff_a.weight().clone());
+        accum.insert(self.names.1.clone(), self.ff_b.weight().clone());
+        if let Some(dora) = &self.dora {
+            accum.insert(
+                weight_name(&self.prefix, &format!("m{}", self.id)),
+                dora.magnitude()?,
+            );
+        }
+        if let Some(TiedScaling { u, v }) = &self.tied {
+            accum.insert(
+                weight_name(&self.prefix, &format!("u{}", self.id)),
+                u.clone(),
+            );
+            accum.insert(
+                weight_name(&self.prefix, &format!("v{}", self.id)),
+                v.clone(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Trainable for LoraLinear {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut tensors = vec![
+            (self.names.0.clone(), self.ff_a.weight().clone()),
+            (self.names.1.clone(), self.ff_b.weight().clone()),
+        ];
+        if let Some(dora) = &self.dora {
+            tensors.push(dora.variable(&self.prefix, self.id));
+        }
+        if let Some(TiedScaling { u, v }) = &self.tied {
+            tensors.push((
+                weight_name(&self.prefix, &format!("u{}", self.id)),
+                u.clone(),
+            ));
+            tensors.push((
+                weight_name(&self.prefix, &format!("v{}", self.id)),
+                v.clone(),
+            ));
+        }
+        tensors
+    }
+}
+
+impl AdapterDevice for LoraLinear {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        let mut layer = self.clone();
+        layer.ff_a = Linear::new(self.ff_a.weight().to_device(device)?, None);
+        layer.ff_b = Linear::new(self.ff_b.weight().to_device(device)?, None);
+        if let Some(TiedScaling { u, v }) = &self.tied {
+            layer.tied = Some(TiedScaling {
+                u: u.to_device(device)?,
+                v: v.to_device(device)?,
+            });
+        }
+        if let Some(dora) = &self.dora {
+            layer.dora = Some(dora.to_device(device)?);
+        }
+        layer.adapters = self.adapters.to_device(device)?;
+        Ok(layer)
     }
 }
 
@@ -0,0 +1,143 @@
+//! Manifests of PEFT conversions: which candle-lora weights each PEFT module was converted to,
+//! so an index such as `a3` can be traced back to its layer and checked when loading.
+
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{bail, Device, Result, Tensor};
+use serde::{Deserialize, Serialize};
+
+/// The candle-lora weights of one PEFT module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The PEFT module, e.g. `base_model.model.model.layers.0.self_attn.q_proj`.
+    pub peft_module: String,
+    /// The candle-lora name of the A matrix, e.g. `lora_llama.a0.weight`.
+    pub lora_a: String,
+    /// The candle-lora name of the B matrix, e.g. `lora_llama.b0.weight`.
+    pub lora_b: String,
+    pub a_shape: Vec<usize>,
+    pub b_shape: Vec<usize>,
+    /// The candle-lora name of the DoRA magnitude of shape `(b_shape[0],)`, e.g.
+    /// `lora_llama.m0.weight`, for DoRA adapters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub magnitude: Option<String>,
+}
+
+/// The mapping of a PEFT conversion from the PEFT modules to the candle-lora weights, in the
+/// order of the candle-lora indices.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversionManifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl ConversionManifest {
+    pub(crate) fn push(
+        &mut self,
+        peft_module: &str,
+        a_name: &str,
+        a_shape: &[usize],
+        b_name: &str,
+        b_shape: &[usize],
+        magnitude_name: Option<&str>,
+    ) {
+        self.entries.push(ManifestEntry {
+            peft_module: peft_module.to_string(),
+            lora_a: a_name.to_string(),
+            lora_b: b_name.to_string(),
+            a_shape: a_shape.to_vec(),
+            b_shape: b_shape.to_vec(),
+            magnitude: magnitude_name.map(str::to_string),
+        });
+    }
+
+    /// The candle-lora `(A, B)` names of the PEFT module.
+    pub fn candle_names(&self, peft_module: &str) -> Option<(&str, &str)> {
+        self.entries
+            .iter()
+            .find(|entry| entry.peft_module == peft_module)
+            .map(|entry| (entry.lora_a.as_str(), entry.lora_b.as_str()))
+    }
+
+    /// The PEFT module a candle-lora weight, A, B or the DoRA magnitude, was converted from.
+    pub fn peft_module(&self, candle_name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| {
+                entry.lora_a == candle_name
+                    || entry.lora_b == candle_name
+                    || entry.magnitude.as_deref() == Some(candle_name)
+            })
+            .map(|entry| entry.peft_module.as_str())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("manifests serialize")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        match serde_json::from_str(json) {
+            Ok(manifest) => Ok(manifest),
+            Err(e) => bail!("invalid conversion manifest: {e}"),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_json())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Check that the candle-lora tensors hold the weights of the manifest with their recorded
+    /// shapes, and nothing else.
+    pub fn verify(&self, tensors: &HashMap<String, Tensor>) -> Result<()> {
+        for entry in &self.entries {
+            let magnitude_shape = entry.b_shape.first().map(|rows| vec![*rows]);
+            let magnitude = entry.magnitude.as_ref().zip(magnitude_shape.as_ref());
+            for (name, shape) in [
+                (&entry.lora_a, &entry.a_shape),
+                (&entry.lora_b, &entry.b_shape),
+            ]
+            .into_iter()
+            .chain(magnitude)
+            {
+                match tensors.get(name) {
+                    None => bail!("{name} of {} is missing", entry.peft_module),
+                    Some(tensor) if tensor.dims() != shape.as_slice() => bail!(
+                        "{name} of {} has shape {:?} instead of {shape:?}",
+                        entry.peft_module,
+                        tensor.dims()
+                    ),
+                    Some(_) => {}
+                }
+            }
+        }
+        let expected = self.entries.len() * 2
+            + self
+                .entries
+                .iter()
+                .filter(|entry| entry.magnitude.is_some())
+                .count();
+        if tensors.len() != expected {
+            bail!(
+                "the adapter has {} tensors, the manifest {expected}",
+                tensors.len()
+            )
+        }
+        Ok(())
+    }
+}
+
+/// Load a converted adapter, checking it against the manifest of its conversion, see
+/// [`ConversionManifest::verify`].
+pub fn load_with_manifest<P: AsRef<Path>>(
+    path: P,
+    manifest: &ConversionManifest,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let tensors = candle_core::safetensors::load(path, device)?;
+    manifest.verify(&tensors)?;
+    Ok(tensors)
+}
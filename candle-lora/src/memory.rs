@@ -0,0 +1,188 @@
+//! Memory of LoRA training: gradient checkpointing of the model blocks, which keeps the
+//! activations of one segment of blocks at a time rather than those of the whole model, and the
+//! accounting of the memory of the adapters, the base weights and the activations.
+
+use std::fmt;
+
+use candle_core::{backprop::GradStore, Result, Tensor, Var};
+use serde::Deserialize;
+
+fn default_blocks_per_segment() -> usize {
+    1
+}
+
+/// Gradient checkpointing, as `gradient_checkpointing_enable` of transformers.
+///
+/// The blocks are run without keeping their activations, but for the hidden states between two
+/// segments of `blocks_per_segment` blocks. Backpropagation runs each segment again from its
+/// input, from the last to the first, trading about one more forward pass for the activations of
+/// all but one segment.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct GradientCheckpointing {
+    #[serde(default = "default_blocks_per_segment")]
+    pub blocks_per_segment: usize,
+}
+
+impl Default for GradientCheckpointing {
+    fn default() -> Self {
+        Self {
+            blocks_per_segment: default_blocks_per_segment(),
+        }
+    }
+}
+
+/// A block of a model, e.g. a LoRA transformer layer, mapping its input hidden states to its
+/// output hidden states.
+pub type BlockFn<'a> = &'a mut dyn FnMut(&Tensor) -> Result<Tensor>;
+
+/// The result of [`checkpointed_backward`].
+#[derive(Debug)]
+pub struct CheckpointedBackward {
+    pub loss: Tensor,
+    /// The gradients of the variables given to [`checkpointed_backward`].
+    pub grads: GradStore,
+    /// The largest activation memory held at once, the hidden states between the segments
+    /// included, see [`activation_bytes`].
+    pub activation_bytes: usize,
+}
+
+/// Run `input` through `blocks` then `loss_fn` and backpropagate the loss with gradient
+/// checkpointing, returning the gradients of `vars`, e.g. the LoRA variables of a trainer.
+///
+/// The blocks are run twice, so they must give the same output for the same input: with LoRA
+/// dropout the recomputed masks differ from those of the loss. The variables `input` was computed
+/// from, e.g. LoRA embeddings, receive their gradients too.
+pub fn checkpointed_backward<L>(
+    input: &Tensor,
+    blocks: &mut [BlockFn],
+    checkpointing: &GradientCheckpointing,
+    vars: &[Var],
+    loss_fn: L,
+) -> Result<CheckpointedBackward>
+where
+    L: FnOnce(&Tensor) -> Result<Tensor>,
+{
+    if checkpointing.blocks_per_segment == 0 {
+        candle_core::bail!("gradient checkpointing needs at least one block per segment")
+    }
+    // The inputs of the segments, the graph of each segment being dropped once it has run.
+    let mut segment_inputs = Vec::new();
+    let mut hidden = input.detach();
+    for segment in blocks.chunks_mut(checkpointing.blocks_per_segment) {
+        segment_inputs.push(hidden.clone());
+        for block in segment.iter_mut() {
+            hidden = block(&hidden)?;
+        }
+        hidden = hidden.detach();
+    }
+    let boundary_bytes = tensors_bytes(&segment_inputs);
+
+    let hidden = Var::from_tensor(&hidden)?;
+    let loss = loss_fn(hidden.as_tensor())?;
+    let mut peak_bytes = activation_bytes(&loss);
+    let mut grads = loss.backward()?;
+    // The gradients of candle keep the graph they were computed with, which is cut so that the
+    // activations of each segment are freed once it is backpropagated.
+    let mut grad = grads.remove(hidden.as_tensor()).map(|grad| grad.detach());
+    // Free the activations of the loss before running the segments again.
+    let loss = loss.detach();
+
+    for (segment, segment_input) in blocks
+        .chunks_mut(checkpointing.blocks_per_segment)
+        .zip(segment_inputs)
+        .rev()
+    {
+        let Some(output_grad) = grad else {
+            break;
+        };
+        let segment_input = Var::from_tensor(&segment_input)?;
+        let mut hidden = segment_input.as_tensor().clone();
+        for block in segment.iter_mut() {
+            hidden = block(&hidden)?;
+        }
+        // The gradient of `sum(output * output_grad)` is `output_grad` for the output.
+        let surrogate = (hidden * output_grad)?.sum_all()?;
+        peak_bytes = peak_bytes.max(activation_bytes(&surrogate));
+        let mut segment_grads = surrogate.backward()?;
+        grad = segment_grads
+            .remove(segment_input.as_tensor())
+            .map(|grad| grad.detach());
+        accumulate_grads(&mut grads, segment_grads, vars)?;
+    }
+
+    if let (Some(input_grad), true) = (grad, input.track_op()) {
+        let surrogate = (input * input_grad)?.sum_all()?;
+        accumulate_grads(&mut grads, surrogate.backward()?, vars)?;
+    }
+    Ok(CheckpointedBackward {
+        loss,
+        grads,
+        activation_bytes: boundary_bytes + peak_bytes,
+    })
+}
+
+/// Add the gradients of `vars` in `from` to those of `grads`.
+fn accumulate_grads(grads: &mut GradStore, mut from: GradStore, vars: &[Var]) -> Result<()> {
+    for var in vars {
+        if let Some(grad) = from.remove(var.as_tensor()) {
+            let grad = match grads.remove(var.as_tensor()) {
+                Some(previous) => (previous + grad)?,
+                None => grad,
+            };
+            grads.insert(var.as_tensor(), grad);
+        }
+    }
+    Ok(())
+}
+
+/// Number of bytes of the elements of `tensors`.
+pub fn tensors_bytes<'a>(tensors: impl IntoIterator<Item = &'a Tensor>) -> usize {
+    tensors
+        .into_iter()
+        .map(|tensor| tensor.elem_count() * tensor.dtype().size_in_bytes())
+        .sum()
+}
+
+/// Number of bytes of the activations backpropagating `loss` keeps alive: the intermediate
+/// tensors of its graph, the variables excluded. Views are counted as copies, so this is an upper
+/// bound.
+pub fn activation_bytes(loss: &Tensor) -> usize {
+    tensors_bytes(
+        loss.sorted_nodes()
+            .into_iter()
+            .filter(|tensor| !tensor.is_variable()),
+    )
+}
+
+/// The memory of a training step, in bytes, see [`crate::LoraTrainer::with_memory_profiling`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryStats {
+    /// The adapter variables and their f32 master copies in mixed precision. The optimizer state
+    /// is not included, e.g. twice the adapter variables for AdamW.
+    pub adapter_bytes: usize,
+    /// The frozen base weights.
+    pub base_bytes: usize,
+    /// The activations kept for backpropagation, see [`activation_bytes`]. 0 for gradients given
+    /// to [`crate::LoraTrainer::step_with_grads`], which are computed elsewhere.
+    pub activation_bytes: usize,
+}
+
+impl MemoryStats {
+    pub fn total_bytes(&self) -> usize {
+        self.adapter_bytes + self.base_bytes + self.activation_bytes
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |bytes: usize| bytes as f64 / (1024. * 1024.);
+        write!(
+            f,
+            "adapter {:.1} MiB, base {:.1} MiB, activations {:.1} MiB, total {:.1} MiB",
+            mib(self.adapter_bytes),
+            mib(self.base_bytes),
+            mib(self.activation_bytes),
+            mib(self.total_bytes())
+        )
+    }
+}
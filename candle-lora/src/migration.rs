@@ -0,0 +1,193 @@
+//! Migration of adapter files between the legacy index-based naming, `<prefix>.a{idx}.weight`
+//! and `<prefix>.b{idx}.weight`, and the name-based naming, `<module>.a0.weight` and
+//! `<module>.b0.weight`, in both directions.
+//!
+//! The indices of an index-based file do not say which module they belong to: migrating to the
+//! name-based naming takes the modules in index order, e.g. the PEFT modules of a converted
+//! adapter sorted by [`structural_order`]. Migrating to the index-based naming records them in
+//! the file metadata, under `candle_lora.modules.<prefix>`, so the migration can be undone
+//! without them, as do the PEFT conversions to the index-based naming.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use candle_core::{bail, Device, Result, Tensor};
+use safetensors::SafeTensors;
+
+use crate::{structural_order, AdapterMetadata, ADAPTER_METADATA_KEY};
+
+/// Prefix of the safetensors metadata keys holding the modules of the indices of each prefix.
+pub const MODULES_METADATA_KEY: &str = "candle_lora.modules";
+
+/// The direction of an adapter file migration, see [`migrate_adapter_file`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Migration {
+    /// Rename `<prefix>.a{idx}` to `<modules[idx]>.a0`. Without `modules`, the modules recorded
+    /// in the metadata by a previous [`Migration::ToIndexed`] are used.
+    ToNamed {
+        prefix: String,
+        modules: Option<Vec<String>>,
+    },
+    /// Rename `<module>.a0` to `<prefix>.a{idx}`, indexing the modules in structural order.
+    ToIndexed { prefix: String },
+}
+
+/// Split `name` into the module, the weight (`a`, `b` or the DoRA magnitude `m`) and the index of
+/// a LoRA weight name.
+pub(crate) fn lora_weight(name: &str) -> Option<(&str, char, usize)> {
+    let (module, weight) = name.strip_suffix(".weight")?.rsplit_once('.')?;
+    let mut chars = weight.chars();
+    let kind = chars
+        .next()
+        .filter(|kind| matches!(kind, 'a' | 'b' | 'm'))?;
+    let idx = chars.as_str();
+    if idx.is_empty() || !idx.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((module, kind, idx.parse().ok()?))
+}
+
+fn insert_unique(
+    migrated: &mut HashMap<String, Tensor>,
+    name: String,
+    tensor: Tensor,
+) -> Result<()> {
+    if migrated.contains_key(&name) {
+        bail!("migrating the adapter gives {name} twice")
+    }
+    migrated.insert(name, tensor);
+    Ok(())
+}
+
+/// Rename the index-based weights `<prefix>.a{idx}.weight` and `<prefix>.b{idx}.weight` to
+/// `<modules[idx]>.a0.weight` and `<modules[idx]>.b0.weight`, and the DoRA magnitudes
+/// `<prefix>.m{idx}.weight` alike. Other tensors are kept as is.
+pub fn migrate_to_named(
+    tensors: &HashMap<String, Tensor>,
+    prefix: &str,
+    modules: &[String],
+) -> Result<HashMap<String, Tensor>> {
+    let mut migrated = HashMap::new();
+    for (name, tensor) in tensors {
+        let name = match lora_weight(name) {
+            Some((module, kind, idx)) if module == prefix => match modules.get(idx) {
+                Some(module) => format!("{module}.{kind}0.weight"),
+                None => bail!(
+                    "{name} has index {idx} but only {} modules are given",
+                    modules.len()
+                ),
+            },
+            _ => name.clone(),
+        };
+        insert_unique(&mut migrated, name, tensor.clone())?;
+    }
+    Ok(migrated)
+}
+
+/// Rename the name-based weights `<module>.a0.weight` and `<module>.b0.weight` to
+/// `<prefix>.a{idx}.weight` and `<prefix>.b{idx}.weight`, the modules being indexed in
+/// structural order, and the DoRA magnitudes `<module>.m0.weight` alike. Other tensors are kept
+/// as is.
+///
+/// Returns the migrated tensors and the modules in index order, for [`migrate_to_named`].
+pub fn migrate_to_indexed(
+    tensors: &HashMap<String, Tensor>,
+    prefix: &str,
+) -> Result<(HashMap<String, Tensor>, Vec<String>)> {
+    let mut modules = tensors
+        .keys()
+        .filter_map(|name| match lora_weight(name) {
+            Some((module, _, 0)) if module != prefix => Some(module.to_string()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    modules.sort_by(|a, b| structural_order(a, b));
+    let indices = modules
+        .iter()
+        .enumerate()
+        .map(|(idx, module)| (module.as_str(), idx))
+        .collect::<HashMap<_, _>>();
+
+    let mut migrated = HashMap::new();
+    for (name, tensor) in tensors {
+        let name = match lora_weight(name) {
+            Some((module, kind, 0)) if indices.contains_key(module) => {
+                format!("{prefix}.{kind}{}.weight", indices[module])
+            }
+            _ => name.clone(),
+        };
+        insert_unique(&mut migrated, name, tensor.clone())?;
+    }
+    Ok((migrated, modules))
+}
+
+/// Migrate the adapter file `input` into `output`, keeping its metadata, the naming of its
+/// [`AdapterMetadata`] being updated.
+///
+/// # Example
+/// ```no_run
+/// use candle_lora::{migrate_adapter_file, Migration};
+///
+/// // Upgrade a legacy adapter of two layers.
+/// let modules = vec![
+///     "model.layers.0.self_attn.q_proj".to_string(),
+///     "model.layers.0.self_attn.v_proj".to_string(),
+/// ];
+/// migrate_adapter_file(
+///     "path/to/legacy.safetensors",
+///     "path/to/named.safetensors",
+///     &Migration::ToNamed {
+///         prefix: "lora_llama".to_string(),
+///         modules: Some(modules),
+///     },
+/// ).unwrap();
+/// ```
+pub fn migrate_adapter_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    migration: &Migration,
+) -> Result<()> {
+    let data = std::fs::read(input)?;
+    let (_, header) = SafeTensors::read_metadata(&data)?;
+    let mut metadata = header.metadata().clone().unwrap_or_default();
+    let tensors = candle_core::safetensors::load_buffer(&data, &Device::Cpu)?;
+
+    let (migrated, architecture) = match migration {
+        Migration::ToNamed { prefix, modules } => {
+            let key = format!("{MODULES_METADATA_KEY}.{prefix}");
+            let recorded = metadata.remove(&key);
+            let modules = match (modules, recorded) {
+                (Some(modules), _) => modules.clone(),
+                (None, Some(recorded)) => match serde_json::from_str(&recorded) {
+                    Ok(modules) => modules,
+                    Err(e) => bail!("invalid {key} metadata: {e}"),
+                },
+                (None, None) => bail!("the adapter has no {key} metadata, give the modules"),
+            };
+            (
+                migrate_to_named(&tensors, prefix, &modules)?,
+                "named".to_string(),
+            )
+        }
+        Migration::ToIndexed { prefix } => {
+            let (migrated, modules) = migrate_to_indexed(&tensors, prefix)?;
+            metadata.insert(
+                format!("{MODULES_METADATA_KEY}.{prefix}"),
+                serde_json::to_string(&modules).expect("module names serialize"),
+            );
+            (migrated, format!("prefix:{prefix}"))
+        }
+    };
+    if let Some(adapter) = metadata.get(ADAPTER_METADATA_KEY) {
+        let adapter = AdapterMetadata::parse(adapter)?.with_architecture(architecture);
+        metadata.extend([adapter.entry()]);
+    }
+
+    let metadata = (!metadata.is_empty()).then_some(metadata);
+    safetensors::serialize_to_file(migrated.iter(), &metadata, output.as_ref())?;
+    Ok(())
+}
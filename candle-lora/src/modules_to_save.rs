@@ -0,0 +1,112 @@
+//! The full weights PEFT adapters save for `modules_to_save`, e.g. a fine-tuned `lm_head` or
+//! `embed_tokens`, which replace the weights of the base model instead of adding a delta.
+//!
+//! PEFT saves them as `base_model.model.<module>.<param>`, or
+//! `base_model.model.<module>.modules_to_save.<adapter>.<param>` in the state dict of a
+//! `PeftModel`. The converters write them as `modules_to_save.<module>.<param>`, the name of the
+//! base model weight after a `modules_to_save` component, see [`apply_modules_to_save`].
+
+use std::collections::HashMap;
+
+use candle_core::{bail, Result, Tensor};
+
+use crate::PeftConfig;
+
+/// The first component of the converted full weights.
+pub(crate) const MODULES_TO_SAVE: &str = "modules_to_save";
+
+/// The `(PEFT name, base model name)` of the full weights of `modules_to_save` among the PEFT
+/// tensors `names`, sorted by base model name. The `<module>.modules_to_save.<adapter>.<param>`
+/// weights are always recognized, the `<module>.<param>` weights only if the last components of
+/// `module` are one of the `modules_to_save` of the adapter `config`.
+pub(crate) fn full_weight_names<'a>(
+    names: impl Iterator<Item = &'a str>,
+    config: Option<&PeftConfig>,
+) -> Vec<(String, String)> {
+    let modules_to_save = config.and_then(|config| config.modules_to_save.as_deref());
+    let mut full_weights = names
+        .filter_map(|name| {
+            let path = name.strip_prefix("base_model.model.")?;
+            let base_name = match path.split_once(&format!(".{MODULES_TO_SAVE}.")) {
+                Some((module, rest)) => format!("{module}.{}", rest.split_once('.')?.1),
+                None => {
+                    let (module, _) = path.rsplit_once('.')?;
+                    let saved = modules_to_save?
+                        .iter()
+                        .any(|saved| module == saved || module.ends_with(&format!(".{saved}")));
+                    if !saved {
+                        return None;
+                    }
+                    path.to_string()
+                }
+            };
+            Some((name.to_string(), base_name))
+        })
+        .collect::<Vec<_>>();
+    full_weights.sort_by(|a, b| a.1.cmp(&b.1));
+    full_weights
+}
+
+/// The full weights of `modules_to_save` among the converted `adapter` tensors, by the name of
+/// the base model weight they replace.
+pub fn modules_to_save_weights(adapter: &HashMap<String, Tensor>) -> HashMap<String, Tensor> {
+    adapter
+        .iter()
+        .filter_map(|(name, tensor)| {
+            let base_name = name.strip_prefix(&format!("{MODULES_TO_SAVE}."))?;
+            Some((base_name.to_string(), tensor.clone()))
+        })
+        .collect()
+}
+
+/// Replace the weights of the base model `base` by the full weights of `modules_to_save` of the
+/// converted `adapter`, e.g. before building a `VarBuilder` of the model, returning the names of
+/// the replaced weights, sorted.
+///
+/// The full weights are cast to the dtype and moved to the device of the base weights, and must
+/// have their shape: the vocabulary of an `lm_head` trained with added tokens must be resized in
+/// the base model first.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::{DType, Device};
+/// use candle_lora::apply_modules_to_save;
+/// use candle_nn::VarBuilder;
+///
+/// let device = Device::Cpu;
+/// let mut base = candle_core::safetensors::load("model.safetensors", &device).unwrap();
+/// let adapter = candle_core::safetensors::load("converted.safetensors", &device).unwrap();
+/// let replaced = apply_modules_to_save(&mut base, &adapter).unwrap();
+/// println!("replaced {replaced:?}");
+/// let vb = VarBuilder::from_tensors(base, DType::F32, &device);
+/// ```
+pub fn apply_modules_to_save(
+    base: &mut HashMap<String, Tensor>,
+    adapter: &HashMap<String, Tensor>,
+) -> Result<Vec<String>> {
+    let mut full_weights = modules_to_save_weights(adapter)
+        .into_iter()
+        .collect::<Vec<_>>();
+    full_weights.sort_by(|a, b| a.0.cmp(&b.0));
+    // Check every weight before replacing any.
+    let mut replaced = Vec::with_capacity(full_weights.len());
+    for (name, tensor) in full_weights {
+        let Some(weight) = base.get(&name) else {
+            bail!("{MODULES_TO_SAVE}.{name} replaces {name}, which the base model does not have")
+        };
+        if weight.dims() != tensor.dims() {
+            bail!(
+                "{MODULES_TO_SAVE}.{name} is {:?} but the base weight is {:?}",
+                tensor.dims(),
+                weight.dims()
+            )
+        }
+        let tensor = tensor
+            .to_dtype(weight.dtype())?
+            .to_device(weight.device())?;
+        replaced.push((name, tensor));
+    }
+    let names = replaced.iter().map(|(name, _)| name.clone()).collect();
+    base.extend(replaced);
+    Ok(names)
+}
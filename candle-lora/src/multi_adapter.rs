@@ -0,0 +1,249 @@
+//! Several adapters loaded side by side in the LoRA layers and combined with user weights, like
+//! PEFT's `add_weighted_adapter`.
+//!
+//! The adapter a layer is created with is named [`DEFAULT_ADAPTER`]. Once weights are set, the
+//! layers add `sum_i w_i * scale_i * B_i A_i x` to the base output instead of their default
+//! adapter. [`MultiAdapter::bake_adapter_weights`] folds the weighted combination into the
+//! default adapter exactly, by concatenating the adapters along the rank.
+//! [`crate::select_batch_adapters`] instead applies one adapter to each element of a batch.
+
+use candle_core::{bail, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+
+use crate::LoraConfig;
+
+/// The name of the adapter a LoRA layer is created with.
+pub const DEFAULT_ADAPTER: &str = "default";
+
+/// LoRA layers holding several named adapters.
+pub trait MultiAdapter {
+    /// Load the adapter `name` from `vb`, under the names the layer was created with (e.g.
+    /// `lora_llama.a3.weight`), with the scale of `config`. An adapter of the same name is
+    /// replaced.
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, config: &LoraConfig) -> Result<()>;
+
+    /// Remove the adapter `name` and its weight, returning whether it was loaded. The default
+    /// adapter cannot be removed.
+    fn remove_adapter(&mut self, name: &str) -> Result<bool>;
+
+    /// The names of the adapters, the default one first.
+    fn adapter_names(&self) -> Vec<String>;
+
+    /// Combine the adapters with the given weights in the forward pass, e.g.
+    /// `[("style", 0.7), ("domain", 0.3)]`. Adapters without a weight are not applied. An empty
+    /// slice goes back to the default adapter alone. Merging the layer merges the weighted
+    /// combination, so the weights cannot change while it is merged.
+    fn set_adapter_weights(&mut self, weights: &[(&str, f64)]) -> Result<()>;
+
+    /// Replace the default adapter by the weighted combination of the adapters, whose rank is
+    /// the sum of their ranks, and clear the weights. The layer then computes the same outputs
+    /// with a single adapter, which can be merged or saved like any other. The scales being
+    /// folded into B, a saved baked adapter is loaded back with an alpha equal to its rank.
+    fn bake_adapter_weights(&mut self) -> Result<()>;
+}
+
+/// The LoRA matrices of an adapter of a layer, in the layer's layout.
+#[derive(Debug, Clone)]
+pub(crate) struct AdapterWeights {
+    pub(crate) a: Tensor,
+    pub(crate) b: Tensor,
+    pub(crate) scale: Option<f64>,
+}
+
+impl AdapterWeights {
+    pub(crate) fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            a: self.a.to_device(device)?,
+            b: self.b.to_device(device)?,
+            scale: self.scale,
+        })
+    }
+}
+
+/// The extra adapters of a layer and their weights.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AdapterSet {
+    adapters: Vec<(String, AdapterWeights)>,
+    weights: Option<Vec<(String, f64)>>,
+}
+
+impl AdapterSet {
+    pub(crate) fn insert(&mut self, name: &str, adapter: AdapterWeights) -> Result<()> {
+        if name == DEFAULT_ADAPTER {
+            bail!("the default adapter cannot be replaced, swap it instead")
+        }
+        match self.adapters.iter_mut().find(|(other, _)| other == name) {
+            Some((_, old)) => *old = adapter,
+            None => self.adapters.push((name.to_string(), adapter)),
+        }
+        Ok(())
+    }
+
+    /// Remove the adapter `name` and its weight. Without any weight left, the layer goes back to
+    /// its default adapter alone.
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        let len = self.adapters.len();
+        self.adapters.retain(|(other, _)| other != name);
+        if let Some(weights) = &mut self.weights {
+            weights.retain(|(other, _)| other != name);
+            if weights.is_empty() {
+                self.weights = None;
+            }
+        }
+        self.adapters.len() != len
+    }
+
+    /// The adapters moved to `device`, with their weights.
+    pub(crate) fn to_device(&self, device: &Device) -> Result<Self> {
+        let adapters = self
+            .adapters
+            .iter()
+            .map(|(name, adapter)| Ok((name.clone(), adapter.to_device(device)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            adapters,
+            weights: self.weights.clone(),
+        })
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        std::iter::once(DEFAULT_ADAPTER.to_string())
+            .chain(self.adapters.iter().map(|(name, _)| name.clone()))
+            .collect()
+    }
+
+    pub(crate) fn set_weights(&mut self, weights: &[(&str, f64)]) -> Result<()> {
+        for (name, _) in weights {
+            if *name != DEFAULT_ADAPTER && !self.adapters.iter().any(|(other, _)| other == name) {
+                bail!("no adapter named {name}")
+            }
+        }
+        self.weights = (!weights.is_empty()).then(|| {
+            weights
+                .iter()
+                .map(|(name, weight)| (name.to_string(), *weight))
+                .collect()
+        });
+        Ok(())
+    }
+
+    pub(crate) fn is_weighted(&self) -> bool {
+        self.weights.is_some()
+    }
+
+    /// The `(A, B, weight * scale)` of the weighted adapters, `None` without weights. Adapters of
+    /// rank 0, without a scale, contribute nothing.
+    fn weighted(&self, default: &AdapterWeights) -> Option<Vec<(Tensor, Tensor, f64)>> {
+        let weights = self.weights.as_ref()?;
+        let weighted = weights
+            .iter()
+            .map(|(name, weight)| {
+                let adapter = match name.as_str() {
+                    DEFAULT_ADAPTER => default,
+                    name => {
+                        &self
+                            .adapters
+                            .iter()
+                            .find(|(other, _)| other == name)
+                            .expect("weighted adapters are registered")
+                            .1
+                    }
+                };
+                let coefficient = weight * adapter.scale.unwrap_or(0.);
+                (adapter.a.clone(), adapter.b.clone(), coefficient)
+            })
+            .collect();
+        Some(weighted)
+    }
+
+    /// `sum_i w_i * scale_i * lora(A_i, B_i)` over the weighted adapters, `None` without weights.
+    pub(crate) fn weighted_sum(
+        &self,
+        default: &AdapterWeights,
+        lora: impl Fn(&Tensor, &Tensor) -> Result<Tensor>,
+    ) -> Result<Option<Tensor>> {
+        let Some(weighted) = self.weighted(default) else {
+            return Ok(None);
+        };
+        let mut sum: Option<Tensor> = None;
+        for (a, b, coefficient) in weighted {
+            let term = (lora(&a, &b)? * coefficient)?;
+            sum = Some(match sum {
+                Some(sum) => (sum + term)?,
+                None => term,
+            });
+        }
+        Ok(sum)
+    }
+
+    /// The weighted combination as one adapter of scale 1, the A matrices being concatenated
+    /// along `a_rank_dim` and the weighted B matrices along `b_rank_dim`. `None` without
+    /// weights, which are cleared otherwise.
+    pub(crate) fn bake(
+        &mut self,
+        default: &AdapterWeights,
+        a_rank_dim: usize,
+        b_rank_dim: usize,
+    ) -> Result<Option<AdapterWeights>> {
+        let Some(weighted) = self.weighted(default) else {
+            return Ok(None);
+        };
+        let a = weighted
+            .iter()
+            .map(|(a, _, _)| a.clone())
+            .collect::<Vec<_>>();
+        let b = weighted
+            .iter()
+            .map(|(_, b, coefficient)| b * *coefficient)
+            .collect::<Result<Vec<_>>>()?;
+        self.weights = None;
+        Ok(Some(AdapterWeights {
+            a: Tensor::cat(&a, a_rank_dim)?,
+            b: Tensor::cat(&b, b_rank_dim)?,
+            scale: Some(1.),
+        }))
+    }
+
+    /// The A and B matrices of all the adapters, the default one first, stacked along a new
+    /// first dimension: `(adapters, rank, in)` and `(adapters, out, rank)` with the scales folded
+    /// into B, the adapters of lower rank padded with zeros to the largest.
+    pub(crate) fn stacked(&self, default: &AdapterWeights) -> Result<(Tensor, Tensor)> {
+        let adapters = std::iter::once(default)
+            .chain(self.adapters.iter().map(|(_, adapter)| adapter))
+            .collect::<Vec<_>>();
+        let mut rank = 0;
+        for adapter in &adapters {
+            rank = rank.max(adapter.a.dim(0)?);
+        }
+        let mut a = Vec::with_capacity(adapters.len());
+        let mut b = Vec::with_capacity(adapters.len());
+        for adapter in adapters {
+            let padding = rank - adapter.a.dim(0)?;
+            a.push(adapter.a.pad_with_zeros(0, 0, padding)?);
+            let scaled = (&adapter.b * adapter.scale.unwrap_or(0.))?;
+            b.push(scaled.pad_with_zeros(1, 0, padding)?);
+        }
+        Ok((Tensor::stack(&a, 0)?, Tensor::stack(&b, 0)?))
+    }
+
+    /// The `adapter_ids` of a batch of `batch_size` elements as a tensor on `device`, checked
+    /// against the adapters.
+    pub(crate) fn batch_ids(
+        &self,
+        adapter_ids: &[u32],
+        batch_size: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        if adapter_ids.len() != batch_size {
+            bail!(
+                "{} adapters selected for a batch of {batch_size}",
+                adapter_ids.len()
+            )
+        }
+        let num_adapters = self.adapters.len() + 1;
+        if let Some(id) = adapter_ids.iter().find(|&&id| id as usize >= num_adapters) {
+            bail!("adapter {id} selected, the layer has {num_adapters} adapters")
+        }
+        Tensor::new(adapter_ids, device)
+    }
+}
@@ -3,11 +3,29 @@
 //! This module provides functionality to convert between HuggingFace PEFT format
 //! and candle-lora format for seamless integration with PEFT adapters.
 
-use candle_core::{DType, Device, Result, Tensor};
-use serde::Deserialize;
-use std::collections::HashMap;
+use candle_core::{
+    safetensors::{Load, MmapedSafetensors},
+    DType, Device, Result, Tensor,
+};
+use candle_nn::VarBuilder;
+use safetensors::tensor::TensorView;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
 use std::path::Path;
 
+use crate::{
+    indexing::in_structural_order,
+    lora_scaling,
+    migration::{lora_weight, migrate_to_named, MODULES_METADATA_KEY},
+    modules_to_save::{full_weight_names, MODULES_TO_SAVE},
+    structural_order,
+    swap::{adapter_id, weight_name},
+    AdapterMetadata, ConversionHooks, ConversionManifest, KeyRules, Lora, LoraConfig, LoraError,
+    NewLayers, PrefixTuningConfig, PromptTuningConfig, Saveable, TargetSpec,
+};
+
 /// candle-lora naming prefixes for different layer types
 /// Based on: https://github.com/EricLBuehler/candle-lora/blob/main/candle-lora-transformers/src/llama.rs
 #[derive(Debug, Clone)]
@@ -16,6 +34,9 @@ pub enum CandleLoraPrefix {
     Llama,
     /// For CausalSelfAttention layers (q_proj, k_proj, v_proj, o_proj)
     LlamaCsa,
+    /// For MLP layers (gate_proj, up_proj, down_proj), numbered in structural order across the
+    /// layers as `Llama::load_with_targets` of candle-lora-transformers reads them
+    LlamaMlp,
     /// For transformer Block layers
     LlamaBlock,
 }
@@ -26,6 +47,7 @@ impl CandleLoraPrefix {
         match self {
             Self::Llama => "lora_llama",
             Self::LlamaCsa => "lora_llama_csa",
+            Self::LlamaMlp => "lora_llama_mlp",
             Self::LlamaBlock => "lora_llama_block",
         }
     }
@@ -41,139 +63,1645 @@ impl CandleLoraPrefix {
                 || name.contains("o_proj"))
         {
             Self::LlamaCsa
+        } else if name.contains("mlp")
+            || name.contains("gate_proj")
+            || name.contains("up_proj")
+            || name.contains("down_proj")
+        {
+            Self::LlamaMlp
         } else {
             Self::LlamaBlock
         }
     }
-}
+}
+
+/// PEFT adapter_config.json structure
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeftConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    #[serde(default)]
+    pub lora_dropout: f64,
+    pub target_modules: Vec<String>,
+    pub peft_type: String,
+    #[serde(default)]
+    pub base_model_name_or_path: String,
+    /// Whether the adapter is a DoRA adapter, with a `lora_magnitude_vector` per module.
+    #[serde(default)]
+    pub use_dora: bool,
+    /// The indices of the layers the adapter applies to, all of them if `None`.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub layers_to_transform: Option<Vec<usize>>,
+    /// The names in front of the layer index, e.g. `layers`, the common ones if `None`.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub layers_pattern: Option<Vec<String>>,
+    /// The ranks of the modules matching each pattern, instead of `r`.
+    #[serde(default)]
+    pub rank_pattern: BTreeMap<String, usize>,
+    /// The alphas of the modules matching each pattern, instead of `lora_alpha`.
+    #[serde(default)]
+    pub alpha_pattern: BTreeMap<String, f64>,
+    /// Whether the adapter is scaled by `lora_alpha / sqrt(r)`, rank-stabilized LoRA, instead
+    /// of `lora_alpha / r`.
+    #[serde(default)]
+    pub use_rslora: bool,
+    /// The modules trained and saved in full instead of adapted, e.g. `lm_head`, see
+    /// [`apply_modules_to_save`](crate::apply_modules_to_save).
+    #[serde(default)]
+    pub modules_to_save: Option<Vec<String>>,
+}
+
+/// Read a value PEFT allows to be given alone or as a list.
+fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(
+        Option::<OneOrMany<T>>::deserialize(deserializer)?.map(|value| match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }),
+    )
+}
+
+impl PeftConfig {
+    /// Check that the config describes a LoRA adapter with valid hyperparameters and patterns,
+    /// one of whose modules has rank `r`, or a rank of `rank_pattern`, among the ranks of the
+    /// LoRA tensors.
+    pub fn validate(&self, ranks: &[usize]) -> std::result::Result<(), LoraError> {
+        if !self.peft_type.eq_ignore_ascii_case("LORA") {
+            return Err(LoraError::UnsupportedPeftType {
+                expected: "LORA".to_string(),
+                found: self.peft_type.clone(),
+            });
+        }
+        if !(self.lora_alpha.is_finite() && self.lora_alpha > 0.) {
+            return Err(LoraError::InvalidConfig(format!(
+                "lora_alpha must be positive, got {}",
+                self.lora_alpha
+            )));
+        }
+        if !(0. ..1.).contains(&self.lora_dropout) {
+            return Err(LoraError::InvalidConfig(format!(
+                "lora_dropout must be in [0, 1), got {}",
+                self.lora_dropout
+            )));
+        }
+        for (pattern, alpha) in &self.alpha_pattern {
+            if !(alpha.is_finite() && *alpha > 0.) {
+                return Err(LoraError::InvalidConfig(format!(
+                    "alpha_pattern gives {pattern} alpha {alpha}, it must be positive"
+                )));
+            }
+        }
+        let config = LoraConfig::new(self.r, self.lora_alpha, None);
+        with_patterns(config, &self.rank_pattern, &self.alpha_pattern)
+            .map_err(|e| LoraError::InvalidConfig(e.to_string()))?;
+        let mut config_ranks = self.rank_pattern.values().chain([&self.r]);
+        if !config_ranks.any(|r| ranks.contains(r)) {
+            return Err(LoraError::ConfigRankMismatch {
+                r: self.r,
+                ranks: ranks.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The [`TargetSpec`] of the modules and layers the adapter applies to, to convert a model
+    /// with. Module names match the end of the layer paths.
+    pub fn target_spec(&self) -> std::result::Result<TargetSpec, LoraError> {
+        let mut spec = TargetSpec::new();
+        for module in &self.target_modules {
+            spec = spec.with_module_pattern(&format!(r"(?:^|\.){}$", regex::escape(module)))?;
+        }
+        for &layer in self.layers_to_transform.iter().flatten() {
+            spec = spec.with_layers(layer..=layer);
+        }
+        if let Some(patterns) = &self.layers_pattern {
+            let patterns = patterns
+                .iter()
+                .map(|p| regex::escape(p))
+                .collect::<Vec<_>>();
+            spec = spec.with_layers_pattern(&patterns.join("|"))?;
+        }
+        Ok(spec)
+    }
+}
+
+/// Summary of a PEFT LoRA conversion, with what is needed to build the [`LoraConfig`] of the
+/// converted adapter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionReport {
+    /// The rank of the LoRA tensors, the most common one when the modules have different ranks.
+    pub rank: usize,
+    /// `lora_alpha` of the adapter config, `None` when no config was read.
+    pub alpha: Option<f64>,
+    /// The PEFT scaling `lora_alpha / r`, or `lora_alpha / sqrt(r)` with rsLoRA, of the modules
+    /// of rank `rank`.
+    pub scaling: Option<f64>,
+    /// `lora_dropout` of the adapter config, `None` when it is 0 or no config was read.
+    pub dropout: Option<f32>,
+    /// The converted PEFT modules, in structural order.
+    pub target_modules: Vec<String>,
+    /// The number of converted LoRA pairs.
+    pub num_modules: usize,
+    /// The number of DoRA magnitudes among the converted tensors.
+    pub num_magnitudes: usize,
+    /// The number of tensors written, dummy embeddings included.
+    pub num_tensors: usize,
+    /// `rank_pattern` of the adapter config, the ranks of the modules not of rank `rank`.
+    pub rank_pattern: BTreeMap<String, usize>,
+    /// `alpha_pattern` of the adapter config.
+    pub alpha_pattern: BTreeMap<String, f64>,
+    /// `use_rslora` of the adapter config.
+    pub use_rslora: bool,
+    /// The PEFT tensors that were not converted, e.g. biases, sorted. Empty in strict mode, see
+    /// [`ConvertOptions::strict`].
+    pub skipped: Vec<String>,
+    /// The base model weights the full weights of `modules_to_save` replace, written as
+    /// `modules_to_save.<name>`, sorted, see [`apply_modules_to_save`](crate::apply_modules_to_save).
+    pub modules_to_save: Vec<String>,
+}
+
+impl ConversionReport {
+    /// The report of the conversion of `lora_pairs`, checked against the adapter `config`, and
+    /// the `(PEFT name, base model name)` of the full weights of `modules_to_save` to write.
+    fn from_pairs(
+        lora_pairs: &[(String, Tensor, Tensor)],
+        peft_tensors: &HashMap<String, Tensor>,
+        config: Option<&PeftConfig>,
+    ) -> std::result::Result<(Self, Vec<(String, String)>), LoraError> {
+        let modules = lora_pairs
+            .iter()
+            .map(|(module, lora_a, lora_b)| {
+                let rank = lora_a.dim(0)?;
+                check_rank(module, rank, lora_b.dims())?;
+                Ok((module.as_str(), rank))
+            })
+            .collect::<std::result::Result<Vec<_>, LoraError>>()?;
+        let num_magnitudes = lora_pairs
+            .iter()
+            .filter(|(module, _, _)| lora_magnitude(peft_tensors, module).is_some())
+            .count();
+        let mut report = Self::new(&modules, num_magnitudes, config)?;
+        let unconverted = unconverted_tensors(peft_tensors.keys().map(String::as_str), |name| {
+            peft_tensors.contains_key(name)
+        });
+        let full_weights = report.split_unconverted(unconverted, config);
+        Ok((report, full_weights))
+    }
+
+    /// The report of the conversion of the `matched` LoRA pairs of `peft_tensors`, the tensors
+    /// of the `unmatched` modules, which the model has no layer for, and of the `dropped` ones
+    /// being skipped. The unmatched modules are an error in strict mode, or when no module
+    /// matches, as are the other skipped tensors in strict mode.
+    fn from_matched_pairs(
+        matched: &[(String, Tensor, Tensor)],
+        unmatched: &[String],
+        dropped: &[String],
+        peft_tensors: &HashMap<String, Tensor>,
+        options: &ConvertOptions,
+    ) -> std::result::Result<(Self, Vec<(String, String)>), LoraError> {
+        if !unmatched.is_empty() && (options.strict || matched.is_empty()) {
+            return Err(LoraError::UnmatchedModules {
+                modules: unmatched.to_vec(),
+            });
+        }
+        let (mut report, full_weights) = Self::from_pairs(matched, peft_tensors, None)?;
+        options.check_skipped(&report.skipped)?;
+        let contains = |name: &str| peft_tensors.contains_key(name);
+        for (module, a, b) in lora_pair_names(peft_tensors.keys().map(String::as_str), contains) {
+            if unmatched.contains(&module) || dropped.contains(&module) {
+                report.skipped.extend(magnitude_name(&module, contains));
+                report.skipped.extend([a, b]);
+            }
+        }
+        report.skipped.sort();
+        Ok((report, full_weights))
+    }
+
+    /// Split the PEFT tensors `unconverted` by the LoRA pairs into the full weights of
+    /// `modules_to_save` of the adapter `config`, returned as `(PEFT name, base model name)`, and
+    /// the skipped tensors.
+    fn split_unconverted(
+        &mut self,
+        unconverted: Vec<String>,
+        config: Option<&PeftConfig>,
+    ) -> Vec<(String, String)> {
+        let full_weights = full_weight_names(unconverted.iter().map(String::as_str), config);
+        self.skipped = unconverted
+            .into_iter()
+            .filter(|name| !full_weights.iter().any(|(peft_name, _)| peft_name == name))
+            .collect();
+        self.modules_to_save = full_weights.iter().map(|(_, name)| name.clone()).collect();
+        self.num_tensors += full_weights.len();
+        full_weights
+    }
+
+    /// The report of the conversion of the `(module, rank)` LoRA pairs `modules`, checked
+    /// against the adapter `config`.
+    fn new(
+        modules: &[(&str, usize)],
+        num_magnitudes: usize,
+        config: Option<&PeftConfig>,
+    ) -> std::result::Result<Self, LoraError> {
+        let Some(rank) = most_common_rank(modules.iter().map(|&(_, rank)| rank)) else {
+            return Err(LoraError::NoAdapterTensors {
+                kind: "LoRA tensors",
+            });
+        };
+        if let Some(config) = config {
+            let mut ranks = modules.iter().map(|&(_, rank)| rank).collect::<Vec<_>>();
+            ranks.sort();
+            ranks.dedup();
+            config.validate(&ranks)?;
+        }
+        let rank = config.map_or(rank, |config| config.r);
+        Ok(Self {
+            rank,
+            alpha: config.map(|config| config.lora_alpha),
+            scaling: config.map(|config| lora_scaling(config.lora_alpha, rank, config.use_rslora)),
+            use_rslora: config.is_some_and(|config| config.use_rslora),
+            dropout: config
+                .map(|config| config.lora_dropout as f32)
+                .filter(|&dropout| dropout > 0.),
+            target_modules: modules
+                .iter()
+                .map(|(module, _)| module.to_string())
+                .collect(),
+            num_modules: modules.len(),
+            num_magnitudes,
+            num_tensors: 2 * modules.len() + num_magnitudes,
+            rank_pattern: config.map(|c| c.rank_pattern.clone()).unwrap_or_default(),
+            alpha_pattern: config.map(|c| c.alpha_pattern.clone()).unwrap_or_default(),
+            skipped: Vec::new(),
+            modules_to_save: Vec::new(),
+        })
+    }
+
+    /// The LoRA config of the converted adapter, with its rank and alpha patterns, `None` when
+    /// no adapter config was read or a pattern is not a valid regular expression.
+    pub fn lora_config(&self) -> Option<LoraConfig> {
+        let config =
+            LoraConfig::new(self.rank, self.alpha?, self.dropout).with_rslora(self.use_rslora);
+        with_patterns(config, &self.rank_pattern, &self.alpha_pattern).ok()
+    }
+}
+
+/// The most common of `ranks`, the largest one among the most common, `None` if there are none.
+fn most_common_rank(ranks: impl Iterator<Item = usize>) -> Option<usize> {
+    let mut counts = HashMap::new();
+    for rank in ranks {
+        *counts.entry(rank).or_insert(0usize) += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(rank, count)| (count, rank))
+        .map(|(rank, _)| rank)
+}
+
+/// The safetensors metadata of a conversion to the index-based naming of `prefix`: its
+/// [`AdapterMetadata`] and the modules of the indices, see [`MODULES_METADATA_KEY`].
+fn indexed_metadata(report: &ConversionReport, prefix: &str) -> HashMap<String, String> {
+    let metadata =
+        AdapterMetadata::from_report(report).with_architecture(format!("prefix:{prefix}"));
+    let modules = serde_json::to_string(&report.target_modules).expect("module names serialize");
+    HashMap::from([
+        metadata.entry(),
+        (format!("{MODULES_METADATA_KEY}.{prefix}"), modules),
+    ])
+}
+
+/// The safetensors metadata of a conversion of the PEFT `(module, rank)` LoRA pairs `modules`
+/// without an adapter config, with the naming `architecture`.
+fn named_metadata(modules: &[(String, usize)], architecture: String) -> HashMap<String, String> {
+    let Some(rank) = most_common_rank(modules.iter().map(|(_, rank)| *rank)) else {
+        return HashMap::new();
+    };
+    let mut metadata = AdapterMetadata::new("peft", rank).with_architecture(architecture);
+    metadata.target_modules = modules.iter().map(|(module, _)| module.clone()).collect();
+    HashMap::from([metadata.entry()])
+}
+
+/// Save the `tensors` of a conversion with their `metadata`.
+fn save_converted(
+    tensors: &HashMap<String, Tensor>,
+    metadata: HashMap<String, String>,
+    output_path: &str,
+) -> std::result::Result<(), LoraError> {
+    let metadata = (!metadata.is_empty()).then_some(metadata);
+    safetensors::serialize_to_file(tensors.iter(), &metadata, Path::new(output_path))
+        .map_err(candle_core::Error::from)?;
+    Ok(())
+}
+
+/// Check that the LoRA B of shape `b_dims` of `module` is of the `rank` of its A, its second
+/// dimension.
+fn check_rank(module: &str, rank: usize, b_dims: &[usize]) -> std::result::Result<(), LoraError> {
+    match b_dims.get(1) {
+        Some(&found) if found != rank => Err(LoraError::RankMismatch {
+            layer: module.to_string(),
+            expected: rank,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// `config` with PEFT's `rank_pattern` and `alpha_pattern`.
+pub(crate) fn with_patterns(
+    mut config: LoraConfig,
+    rank_pattern: &BTreeMap<String, usize>,
+    alpha_pattern: &BTreeMap<String, f64>,
+) -> Result<LoraConfig> {
+    for (pattern, &rank) in rank_pattern {
+        config = config.with_rank_pattern(pattern, rank)?;
+    }
+    for (pattern, &alpha) in alpha_pattern {
+        config = config.with_alpha_pattern(pattern, alpha)?;
+    }
+    Ok(config)
+}
+
+/// The weights of a PEFT directory, in the first of these forms found:
+/// `adapter_model.safetensors`, `adapter.safetensors`, shards listed by
+/// `adapter_model.safetensors.index.json` or `adapter_model.bin.index.json`, or a PyTorch
+/// `adapter_model.bin`.
+pub fn load_peft_weights(
+    peft_dir: &str,
+    device: &Device,
+) -> std::result::Result<HashMap<String, Tensor>, LoraError> {
+    let peft_path = Path::new(peft_dir);
+    for name in ["adapter_model.safetensors", "adapter.safetensors"] {
+        let path = peft_path.join(name);
+        if path.exists() {
+            return Ok(candle_core::safetensors::load(path, device)?);
+        }
+    }
+    for name in [
+        "adapter_model.safetensors.index.json",
+        "adapter_model.bin.index.json",
+    ] {
+        let path = peft_path.join(name);
+        if path.exists() {
+            return load_sharded_weights(&path, device);
+        }
+    }
+    let path = peft_path.join("adapter_model.bin");
+    if path.exists() {
+        return Ok(load_weights_file(&path, device)?);
+    }
+    Err(LoraError::MissingAdapterFile {
+        dir: peft_dir.to_string(),
+    })
+}
+
+/// The weights of a PEFT directory memory-mapped, in the order of [`load_peft_weights`], or
+/// `None` if they are not safetensors files.
+fn mmap_peft_weights(peft_dir: &str) -> std::result::Result<Option<MmapedSafetensors>, LoraError> {
+    let peft_path = Path::new(peft_dir);
+    for name in ["adapter_model.safetensors", "adapter.safetensors"] {
+        let path = peft_path.join(name);
+        if path.exists() {
+            return Ok(Some(mmap_weights(&[path])?));
+        }
+    }
+    let index_path = peft_path.join("adapter_model.safetensors.index.json");
+    if !index_path.exists() {
+        return Ok(None);
+    }
+    let weight_map = read_shard_index(&index_path)?;
+    let shards = shard_files(&weight_map)
+        .into_iter()
+        .map(|shard| peft_path.join(shard))
+        .collect::<Vec<_>>();
+    let weights = mmap_weights(&shards)?;
+    let names = weights.tensors().into_iter().map(|(name, _)| name);
+    let names = names.collect::<std::collections::HashSet<_>>();
+    if let Some((name, shard)) = weight_map.iter().find(|(name, _)| !names.contains(*name)) {
+        return Err(LoraError::MissingShardTensor {
+            name: name.clone(),
+            shard: shard.clone(),
+        });
+    }
+    Ok(Some(weights))
+}
+
+/// The safetensors files `paths` memory-mapped.
+fn mmap_weights(paths: &[std::path::PathBuf]) -> Result<MmapedSafetensors> {
+    // Safety: the files are only read, and must not be modified while being converted.
+    unsafe { MmapedSafetensors::multi(paths) }
+}
+
+/// The tensors of a safetensors file, or of a PyTorch checkpoint for other extensions.
+fn load_weights_file(path: &Path, device: &Device) -> Result<HashMap<String, Tensor>> {
+    if path.extension().is_some_and(|ext| ext == "safetensors") {
+        return candle_core::safetensors::load(path, device);
+    }
+    candle_core::pickle::read_all(path)?
+        .into_iter()
+        .map(|(name, tensor)| Ok((name, tensor.to_device(device)?)))
+        .collect()
+}
+
+/// The tensors of the shards listed by the `weight_map` of the index file `index_path`.
+fn load_sharded_weights(
+    index_path: &Path,
+    device: &Device,
+) -> std::result::Result<HashMap<String, Tensor>, LoraError> {
+    let weight_map = read_shard_index(index_path)?;
+    let dir = index_path.parent().unwrap_or(Path::new("."));
+    let mut tensors = HashMap::new();
+    for shard in shard_files(&weight_map) {
+        tensors.extend(load_weights_file(&dir.join(shard), device)?);
+    }
+    if let Some((name, shard)) = weight_map
+        .iter()
+        .find(|(name, _)| !tensors.contains_key(*name))
+    {
+        return Err(LoraError::MissingShardTensor {
+            name: name.clone(),
+            shard: shard.clone(),
+        });
+    }
+    Ok(tensors)
+}
+
+/// The `weight_map` of a shard index file, from tensor names to shard files.
+pub(crate) fn read_shard_index(
+    index_path: &Path,
+) -> std::result::Result<HashMap<String, String>, LoraError> {
+    #[derive(Deserialize)]
+    struct ShardIndex {
+        weight_map: HashMap<String, String>,
+    }
+    let index = std::fs::read_to_string(index_path)?;
+    let index =
+        serde_json::from_str::<ShardIndex>(&index).map_err(|source| LoraError::InvalidJson {
+            path: index_path.to_path_buf(),
+            source,
+        })?;
+    Ok(index.weight_map)
+}
+
+/// The shard files of a `weight_map`, sorted and each given once.
+pub(crate) fn shard_files(weight_map: &HashMap<String, String>) -> Vec<&String> {
+    let mut shards = weight_map.values().collect::<Vec<_>>();
+    shards.sort();
+    shards.dedup();
+    shards
+}
+
+/// The `adapter_config.json` of a PEFT directory, if any.
+pub(crate) fn read_peft_config(
+    peft_dir: &str,
+) -> std::result::Result<Option<PeftConfig>, LoraError> {
+    let config_path = Path::new(peft_dir).join("adapter_config.json");
+    if !config_path.exists() {
+        return Ok(None);
+    }
+    let config_str = std::fs::read_to_string(&config_path)?;
+    let config = serde_json::from_str::<PeftConfig>(&config_str).map_err(|source| {
+        LoraError::InvalidJson {
+            path: config_path,
+            source,
+        }
+    })?;
+    Ok(Some(config))
+}
+
+/// PEFT adapter_config.json structure of an IA3 adapter
+#[derive(Debug, Deserialize)]
+pub struct PeftIa3Config {
+    pub target_modules: Vec<String>,
+    /// The modules whose inputs rather than outputs are rescaled.
+    #[serde(default)]
+    pub feedforward_modules: Vec<String>,
+    pub peft_type: String,
+    #[serde(default)]
+    pub base_model_name_or_path: String,
+}
+
+impl PeftIa3Config {
+    /// Whether the IA3 vector of `module`, e.g. `model.layers.0.mlp.down_proj`, rescales the
+    /// inputs of the layer, see [`Ia3LinearConfig::with_feedforward`](crate::Ia3LinearConfig).
+    pub fn is_feedforward(&self, module: &str) -> bool {
+        let name = module.rsplit('.').next().unwrap_or(module);
+        self.feedforward_modules
+            .iter()
+            .any(|feedforward| feedforward == name || feedforward == module)
+    }
+}
+
+/// PEFT adapter_config.json structure of a prompt tuning or prefix tuning adapter
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeftPromptConfig {
+    pub peft_type: String,
+    pub num_virtual_tokens: usize,
+    /// The hidden size of the base model.
+    pub token_dim: usize,
+    /// 2 for encoder-decoder models, whose encoder and decoder each get virtual tokens.
+    #[serde(default)]
+    pub num_transformer_submodules: Option<usize>,
+    pub num_attention_heads: usize,
+    pub num_layers: usize,
+    /// Whether prefix tuning trained the virtual keys and values through an MLP.
+    #[serde(default)]
+    pub prefix_projection: bool,
+    #[serde(default)]
+    pub base_model_name_or_path: String,
+}
+
+impl PeftPromptConfig {
+    /// Whether the adapter is a prefix tuning adapter rather than a prompt tuning one.
+    pub fn is_prefix_tuning(&self) -> bool {
+        self.peft_type.eq_ignore_ascii_case("PREFIX_TUNING")
+    }
+
+    /// Check that the config describes a prompt tuning or prefix tuning adapter of a
+    /// decoder-only model.
+    pub fn validate(&self) -> std::result::Result<(), LoraError> {
+        if !self.is_prefix_tuning() && !self.peft_type.eq_ignore_ascii_case("PROMPT_TUNING") {
+            return Err(LoraError::UnsupportedPeftType {
+                expected: "PROMPT_TUNING or PREFIX_TUNING".to_string(),
+                found: self.peft_type.clone(),
+            });
+        }
+        if self.num_transformer_submodules.unwrap_or(1) != 1 {
+            return Err(LoraError::Unsupported(
+                "only the virtual tokens of decoder-only models are supported".to_string(),
+            ));
+        }
+        if self.num_attention_heads == 0 || !self.token_dim.is_multiple_of(self.num_attention_heads)
+        {
+            return Err(LoraError::InvalidConfig(format!(
+                "token_dim {} is not a multiple of num_attention_heads {}",
+                self.token_dim, self.num_attention_heads
+            )));
+        }
+        Ok(())
+    }
+
+    /// The [`PromptTuningConfig`] of a prompt tuning adapter.
+    pub fn prompt_tuning_config(&self) -> std::result::Result<PromptTuningConfig, LoraError> {
+        self.validate()?;
+        if self.is_prefix_tuning() {
+            return Err(LoraError::UnsupportedPeftType {
+                expected: "PROMPT_TUNING".to_string(),
+                found: self.peft_type.clone(),
+            });
+        }
+        Ok(PromptTuningConfig::new(
+            self.num_virtual_tokens,
+            self.token_dim,
+        ))
+    }
+
+    /// The [`PrefixTuningConfig`] of a prefix tuning adapter.
+    pub fn prefix_tuning_config(&self) -> std::result::Result<PrefixTuningConfig, LoraError> {
+        self.validate()?;
+        if !self.is_prefix_tuning() {
+            return Err(LoraError::UnsupportedPeftType {
+                expected: "PREFIX_TUNING".to_string(),
+                found: self.peft_type.clone(),
+            });
+        }
+        Ok(PrefixTuningConfig::new(
+            self.num_virtual_tokens,
+            self.num_layers,
+            self.num_attention_heads,
+            self.token_dim / self.num_attention_heads,
+        ))
+    }
+}
+
+/// A module, its LoRA A and B and its DoRA magnitude if any.
+type LoraPair = (String, Tensor, Tensor, Option<Tensor>);
+
+/// Name of the sub-module holding the LoRA weights of each traced linear layer in
+/// candle-lora-transformers (see `with_tracing::TracedLoraLinear`).
+pub const TRACED_LORA_LINEAR: &str = "traced_lora_linear";
+
+/// Architectures in candle-lora-transformers built on the traced LoRA layers.
+///
+/// Every adapted module `<path>` of these models owns exactly one LoRA pair, stored as
+/// `<path>.traced_lora_linear.a0.weight` and `<path>.traced_lora_linear.b0.weight`, so
+/// conversion is a rename of the PEFT module path rather than a positional renumbering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracedArchitecture {
+    /// IBM Granite (`model.layers.N.self_attn.*_proj`, `model.layers.N.mlp.*_proj`)
+    Granite,
+    /// StarCoder2 (`model.layers.N.self_attn.*_proj`, `model.layers.N.mlp.c_fc`/`c_proj`)
+    Starcoder2,
+    /// DeepSeek-V2/V3 (`model.layers.N.self_attn.{q_a_proj,q_b_proj,kv_a_proj_with_mqa,kv_b_proj,o_proj}`).
+    /// Only attention is adapted; MLP and expert modules of the adapter are dropped.
+    DeepSeekV2,
+    /// InternLM2 (`model.layers.N.attention.{wqkv,wo}`, `model.layers.N.feed_forward.w{1,2,3}`).
+    /// The packed `wqkv` is split into `q_proj`/`k_proj`/`v_proj` LoRA layers, so both native
+    /// `wqkv` adapters and Llama-style `q_proj`/`k_proj`/`v_proj` adapters can be applied.
+    InternLM2 {
+        num_attention_heads: usize,
+        num_key_value_heads: usize,
+    },
+    /// Yi (Llama naming: `model.layers.N.self_attn.*_proj`, `model.layers.N.mlp.*_proj`)
+    Yi,
+    /// Baichuan and Baichuan2 (`model.layers.N.self_attn.{W_pack,o_proj}`, `model.layers.N.mlp.*_proj`).
+    /// The packed `W_pack` is split into `q_proj`/`k_proj`/`v_proj` LoRA layers.
+    Baichuan,
+    /// ChatGLM2/3 and GLM-4 (`transformer.encoder.layers.N.self_attention.{query_key_value,dense}`,
+    /// `transformer.encoder.layers.N.mlp.{dense_h_to_4h,dense_4h_to_h}`). The packed projections
+    /// keep a single LoRA layer each.
+    ChatGLM,
+    /// Marian (`model.{encoder,decoder}.layers.N.{self_attn,encoder_attn}.*_proj`,
+    /// `model.{encoder,decoder}.layers.N.fc{1,2}`)
+    Marian,
+    /// M2M100 and NLLB, named like Marian.
+    M2M100,
+    /// Wav2Vec2 and HuBERT (`{wav2vec2,hubert}.encoder.layers.N.attention.*_proj`,
+    /// `{wav2vec2,hubert}.encoder.layers.N.feed_forward.{intermediate,output}_dense`).
+    /// Only the transformer encoder is adapted.
+    Wav2Vec2,
+    /// MusicGen (`decoder.model.decoder.layers.N.{self_attn,encoder_attn}.*_proj`,
+    /// `decoder.model.decoder.layers.N.fc{1,2}`). Only the decoder is adapted; adapters saved
+    /// from the decoder alone (`model.decoder.layers.N...`) are moved under `decoder`.
+    Musicgen,
+    /// Segment Anything (`image_encoder.blocks.N.attn.{qkv,proj}`,
+    /// `image_encoder.blocks.N.mlp.lin{1,2}`). Only the image encoder is adapted; HuggingFace
+    /// `SamModel` adapters (`vision_encoder.layers.N...`) are renamed to the original naming.
+    Sam,
+    /// RWKV v5 (`rwkv.blocks.N.attention.{key,value,receptance,gate,output}`,
+    /// `rwkv.blocks.N.feed_forward.{key,receptance,value}`). Checkpoints of the RWKV-LoRA
+    /// trainers (`blocks.N.att.key.lora_A`, `blocks.N.ffn.value.lora_B`, ...) are renamed to the
+    /// HuggingFace naming.
+    Rwkv,
+    /// MPT (`transformer.blocks.N.attn.{Wqkv,out_proj}`, `transformer.blocks.N.ffn.{up,down}_proj`).
+    /// The fused `Wqkv` keeps a single LoRA layer. Adapters of the token embedding, which MPT
+    /// ties to the output head, are dropped.
+    Mpt,
+    /// GPT-NeoX and Pythia (`gpt_neox.layers.N.attention.{query_key_value,dense}`,
+    /// `gpt_neox.layers.N.mlp.{dense_h_to_4h,dense_4h_to_h}`). The fused `query_key_value`
+    /// keeps a single LoRA layer.
+    GptNeoX,
+    /// BLOOM (`transformer.h.N.self_attention.{query_key_value,dense}`,
+    /// `transformer.h.N.mlp.{dense_h_to_4h,dense_4h_to_h}`). The fused `query_key_value`, laid
+    /// out per head, is split into `q_proj`/`k_proj`/`v_proj` LoRA layers.
+    Bloom { num_attention_heads: usize },
+    /// Mistral (Llama naming: `model.layers.N.self_attn.*_proj`, `model.layers.N.mlp.*_proj`,
+    /// `lm_head`)
+    Mistral,
+    /// Phi-3 (`model.layers.N.self_attn.{qkv_proj,o_proj}`, `model.layers.N.mlp.{gate_up_proj,down_proj}`).
+    /// The fused `qkv_proj` and `gate_up_proj` keep a single LoRA layer each.
+    Phi3,
+    /// Qwen2 and Qwen2.5 (Llama naming: `model.layers.N.self_attn.*_proj`, `model.layers.N.mlp.*_proj`)
+    Qwen2,
+    /// T5, mT5 and Flan-T5 (`{encoder,decoder}.block.N.layer.M.{SelfAttention,EncDecAttention}.{q,k,v,o}`,
+    /// `{encoder,decoder}.block.N.layer.M.DenseReluDense.{wi,wi_0,wi_1,wo}`, `lm_head`). Adapters
+    /// of the shared token embedding are dropped.
+    T5,
+    /// Whisper (`model.{encoder,decoder}.layers.N.{self_attn,encoder_attn}.{q,k,v,out}_proj`,
+    /// `model.{encoder,decoder}.layers.N.fc{1,2}`). The convolutions, embeddings and the tied
+    /// output head are not adapted.
+    Whisper,
+}
+
+impl TracedArchitecture {
+    /// Module renames from HuggingFace transformers naming to the candle-lora-transformers
+    /// VarBuilder paths, applied in order.
+    fn renames(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Granite
+            | Self::Starcoder2
+            | Self::DeepSeekV2
+            | Self::Yi
+            | Self::Baichuan
+            | Self::ChatGLM
+            | Self::Marian
+            | Self::M2M100
+            | Self::Wav2Vec2
+            | Self::Musicgen
+            | Self::Mpt
+            | Self::GptNeoX
+            | Self::Bloom { .. }
+            | Self::Mistral
+            | Self::Phi3
+            | Self::Qwen2
+            | Self::T5
+            | Self::Whisper => &[],
+            Self::Rwkv => &[(".att.", ".attention."), (".ffn.", ".feed_forward.")],
+            Self::Sam => &[("vision_encoder.layers.", "image_encoder.blocks.")],
+            Self::InternLM2 { .. } => &[
+                (".self_attn.o_proj", ".attention.wo"),
+                (".self_attn.", ".attention."),
+                (".mlp.gate_proj", ".feed_forward.w1"),
+                (".mlp.down_proj", ".feed_forward.w2"),
+                (".mlp.up_proj", ".feed_forward.w3"),
+            ],
+        }
+    }
+
+    /// Whether the candle-lora-transformers model has a LoRA layer at `module`.
+    pub fn adapts(&self, module: &str) -> bool {
+        match self {
+            Self::Granite
+            | Self::Starcoder2
+            | Self::InternLM2 { .. }
+            | Self::Yi
+            | Self::Baichuan
+            | Self::ChatGLM
+            | Self::Marian
+            | Self::M2M100
+            | Self::Rwkv
+            | Self::Mistral
+            | Self::Phi3
+            | Self::Qwen2 => true,
+            Self::DeepSeekV2 => module.contains(".self_attn."),
+            Self::Wav2Vec2 => module.contains(".encoder.layers."),
+            Self::Musicgen => module.contains("model.decoder.layers."),
+            Self::Sam => module.starts_with("image_encoder.blocks."),
+            Self::Mpt => module.contains(".blocks."),
+            Self::GptNeoX => module.contains(".layers."),
+            Self::Bloom { .. } => module.contains(".h."),
+            Self::T5 => module.contains(".block.") || module == "lm_head",
+            Self::Whisper => module.contains(".layers."),
+        }
+    }
+
+    /// Map a PEFT module name (e.g. `base_model.model.model.layers.0.self_attn.q_proj`)
+    /// to the path of the matching module in candle-lora-transformers.
+    pub fn module_path(&self, peft_module: &str) -> String {
+        let module = peft_module
+            .strip_prefix("base_model.model.")
+            .unwrap_or(peft_module);
+        let module = match self {
+            Self::Musicgen if module.starts_with("model.decoder.") => format!("decoder.{module}"),
+            Self::Rwkv if module.starts_with("blocks.") => format!("rwkv.{module}"),
+            _ => module.to_string(),
+        };
+        self.renames()
+            .iter()
+            .fold(module, |module, (from, to)| module.replace(from, to))
+    }
+
+    /// The attention module whose packed q, k and v projections `module` is, if any.
+    fn packed_attention<'a>(&self, module: &'a str) -> Option<&'a str> {
+        match self {
+            Self::InternLM2 { .. } => module.strip_suffix(".wqkv"),
+            Self::Baichuan => module.strip_suffix(".W_pack"),
+            Self::Bloom { .. } => module.strip_suffix(".query_key_value"),
+            _ => None,
+        }
+    }
+
+    /// Split the rows of a tensor of a packed attention module into its q, k and v parts.
+    fn split_rows(&self, packed: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        match self {
+            Self::InternLM2 {
+                num_attention_heads,
+                num_key_value_heads,
+            } => split_packed_qkv(packed, *num_attention_heads, *num_key_value_heads),
+            Self::Bloom {
+                num_attention_heads,
+            } => split_packed_qkv(packed, *num_attention_heads, *num_attention_heads),
+            // Baichuan's `W_pack` holds q, k and v one after the other.
+            _ => {
+                let rows = packed.dim(0)? / 3;
+                Ok((
+                    packed.narrow(0, 0, rows)?,
+                    packed.narrow(0, rows, rows)?,
+                    packed.narrow(0, 2 * rows, rows)?,
+                ))
+            }
+        }
+    }
+
+    /// Split a LoRA pair and its DoRA magnitude into those of the layers the model actually
+    /// holds.
+    fn split_pair(
+        &self,
+        module: String,
+        lora_a: Tensor,
+        lora_b: Tensor,
+        magnitude: Option<Tensor>,
+    ) -> Result<Vec<LoraPair>> {
+        let Some(attention) = self.packed_attention(&module) else {
+            return Ok(vec![(module, lora_a, lora_b, magnitude)]);
+        };
+        // delta = B A, so splitting the rows of B splits the delta of the packed weight. The
+        // magnitude has a row per output channel as well.
+        let (q, k, v) = self.split_rows(&lora_b)?;
+        let (m_q, m_k, m_v) = match &magnitude {
+            Some(magnitude) => {
+                let (q, k, v) = self.split_rows(magnitude)?;
+                (Some(q), Some(k), Some(v))
+            }
+            None => (None, None, None),
+        };
+        Ok(vec![
+            (format!("{attention}.q_proj"), lora_a.clone(), q, m_q),
+            (format!("{attention}.k_proj"), lora_a.clone(), k, m_k),
+            (format!("{attention}.v_proj"), lora_a, v, m_v),
+        ])
+    }
+}
+
+/// Split the rows of an InternLM2-style packed `wqkv` tensor into its q, k and v parts.
+///
+/// The rows are laid out per key-value head as `[q heads of the group, k, v]`, each head
+/// `head_dim` rows long. Works on weights, biases and LoRA B matrices alike.
+pub fn split_packed_qkv(
+    packed: &Tensor,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    let num_kv_groups = num_attention_heads / num_key_value_heads;
+    let rows = packed.dim(0)?;
+    let head_dim = rows / (num_attention_heads + 2 * num_key_value_heads);
+    let mut grouped_shape = vec![num_key_value_heads, num_kv_groups + 2, head_dim];
+    grouped_shape.extend_from_slice(&packed.dims()[1..]);
+    let grouped = packed.reshape(grouped_shape)?;
+    let part = |start: usize, len: usize, heads: usize| -> Result<Tensor> {
+        let mut shape = vec![heads * head_dim];
+        shape.extend_from_slice(&packed.dims()[1..]);
+        grouped.narrow(1, start, len)?.contiguous()?.reshape(shape)
+    };
+    Ok((
+        part(0, num_kv_groups, num_attention_heads)?,
+        part(num_kv_groups, 1, num_key_value_heads)?,
+        part(num_kv_groups + 1, 1, num_key_value_heads)?,
+    ))
+}
+
+/// Collect the `(module, lora_A, lora_B)` triples of a PEFT adapter, in the [`structural_order`]
+/// of the module names, the embedding adapters last as the ids of [`Lora::convert_model`].
+///
+/// Besides the PEFT `<module>.lora_A.weight` naming, the bare `<module>.lora_A` parameters
+/// written by the RWKV-LoRA trainers are recognized, and so are the `<module>.lora_embedding_A`
+/// and `<module>.lora_embedding_B` parameters of PEFT embedding adapters. Those are
+/// `(rank, num_embeddings)` and `(embedding_dim, rank)`, the layout of
+/// [`LoraEmbedding`](crate::LoraEmbedding), their delta `B A` being the transpose of the
+/// `(num_embeddings, embedding_dim)` embedding weight.
+pub(crate) fn collect_lora_pairs(
+    peft_tensors: &HashMap<String, Tensor>,
+) -> Vec<(String, Tensor, Tensor)> {
+    lora_pair_names(peft_tensors.keys().map(String::as_str), |name| {
+        peft_tensors.contains_key(name)
+    })
+    .into_iter()
+    .map(|(module, a, b)| (module, peft_tensors[&a].clone(), peft_tensors[&b].clone()))
+    .collect()
+}
+
+/// The `(module, lora_A name, lora_B name)` triples of the PEFT tensor `names`, see
+/// [`collect_lora_pairs`], `contains` telling whether a tensor exists.
+pub(crate) fn lora_pair_names<'a>(
+    names: impl Iterator<Item = &'a str>,
+    contains: impl Fn(&str) -> bool,
+) -> Vec<(String, String, String)> {
+    let mut lora_pairs = Vec::new();
+    for name in names {
+        let pair = [
+            (".lora_A.weight", ".lora_B.weight"),
+            (".lora_A", ".lora_B"),
+            (EMBEDDING_A, EMBEDDING_B),
+        ]
+        .iter()
+        .find_map(|(a_suffix, b_suffix)| {
+            let base_name = name.strip_suffix(a_suffix)?;
+            let b_name = format!("{base_name}{b_suffix}");
+            contains(&b_name).then(|| (base_name.to_string(), name.to_string(), b_name))
+        });
+        if let Some(pair) = pair {
+            lora_pairs.push(pair);
+        }
+    }
+    // Embedding layers are converted after the other layers by `Lora::convert_model`.
+    lora_pairs.sort_by(|a, b| {
+        let embedding = |pair: &(String, String, String)| pair.1.ends_with(EMBEDDING_A);
+        embedding(a)
+            .cmp(&embedding(b))
+            .then_with(|| structural_order(&a.0, &b.0))
+    });
+    lora_pairs
+}
+
+/// The PEFT tensors of `names` that are neither the A or B of a LoRA pair nor the DoRA magnitude
+/// of its module, sorted, `contains` telling whether a tensor exists.
+fn unconverted_tensors<'a>(
+    names: impl Iterator<Item = &'a str> + Clone,
+    contains: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let mut converted = HashSet::new();
+    for (module, a, b) in lora_pair_names(names.clone(), &contains) {
+        converted.extend(magnitude_name(&module, &contains));
+        converted.insert(a);
+        converted.insert(b);
+    }
+    let mut skipped = names
+        .filter(|name| !converted.contains(*name))
+        .map(String::from)
+        .collect::<Vec<_>>();
+    skipped.sort();
+    skipped
+}
+
+/// The suffixes of the A and B parameters of PEFT embedding adapters.
+pub(crate) const EMBEDDING_A: &str = ".lora_embedding_A";
+pub(crate) const EMBEDDING_B: &str = ".lora_embedding_B";
+
+/// The last components of the embedding modules whose adapters are saved as PEFT embedding
+/// adapters by [`convert_candle_lora_to_peft`].
+const EMBEDDING_MODULES: [&str; 6] = [
+    "embed_tokens",
+    "wte",
+    "word_embeddings",
+    "embed_in",
+    "tok_embeddings",
+    "shared",
+];
+
+/// Whether the last component of `module` is one of [`EMBEDDING_MODULES`].
+pub(crate) fn is_embedding_module(module: &str) -> bool {
+    module
+        .rsplit('.')
+        .next()
+        .is_some_and(|last| EMBEDDING_MODULES.contains(&last))
+}
+
+/// Whether the PEFT `module` is an embedding adapter, with `lora_embedding_A` and
+/// `lora_embedding_B` parameters, `contains` telling whether a tensor exists.
+pub(crate) fn is_embedding_adapter(module: &str, contains: impl Fn(&str) -> bool) -> bool {
+    contains(&format!("{module}{EMBEDDING_A}"))
+}
+
+/// The DoRA magnitude of a PEFT module, `<module>.lora_magnitude_vector` or
+/// `<module>.lora_magnitude_vector.weight`, if the adapter is a DoRA adapter.
+pub(crate) fn lora_magnitude(
+    peft_tensors: &HashMap<String, Tensor>,
+    module: &str,
+) -> Option<Tensor> {
+    magnitude_name(module, |name| peft_tensors.contains_key(name))
+        .and_then(|name| peft_tensors.get(&name))
+        .cloned()
+}
+
+/// The name of the DoRA magnitude of a PEFT module, see [`lora_magnitude`].
+pub(crate) fn magnitude_name(module: &str, contains: impl Fn(&str) -> bool) -> Option<String> {
+    [".lora_magnitude_vector", ".lora_magnitude_vector.weight"]
+        .iter()
+        .map(|suffix| format!("{module}{suffix}"))
+        .find(|name| contains(name))
+}
+
+/// Collect the `(module, ia3_l)` pairs of a PEFT IA3 adapter, in the [`structural_order`] of the
+/// module names.
+pub(crate) fn collect_ia3_vectors(peft_tensors: &HashMap<String, Tensor>) -> Vec<(String, Tensor)> {
+    let mut vectors = peft_tensors
+        .iter()
+        .filter_map(|(name, tensor)| {
+            let module = name
+                .strip_suffix(".ia3_l")
+                .or_else(|| name.strip_suffix(".ia3_l.weight"))?;
+            Some((module.to_string(), tensor.clone()))
+        })
+        .collect::<Vec<_>>();
+    vectors.sort_by(|a, b| structural_order(&a.0, &b.0));
+    vectors
+}
+
+/// Convert PEFT format IA3 weights to candle-lora format
+///
+/// The `ia3_l` vector of each PEFT module, of shape `(out_features, 1)` or `(1, in_features)`
+/// for the feedforward modules, is saved as `<prefix>.l{idx}.weight` of shape `(out_features,)`
+/// or `(in_features,)`, the modules being indexed in [`structural_order`]. The layers are then
+/// created with [`Ia3Linear::new`](crate::Ia3Linear::new), see [`PeftIa3Config::is_feedforward`].
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::convert_peft_ia3_to_candle_lora;
+///
+/// convert_peft_ia3_to_candle_lora(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     "ia3_llama",
+///     &Device::Cpu,
+/// ).unwrap();
+/// ```
+pub fn convert_peft_ia3_to_candle_lora(
+    peft_path: &str,
+    output_path: &str,
+    prefix: &str,
+    device: &Device,
+) -> std::result::Result<(), LoraError> {
+    let peft_tensors = candle_core::safetensors::load(peft_path, device)?;
+
+    let mut candle_tensors = HashMap::new();
+    for (idx, (_peft_name, vector)) in collect_ia3_vectors(&peft_tensors).into_iter().enumerate() {
+        candle_tensors.insert(format!("{prefix}.l{idx}.weight"), vector.flatten_all()?);
+    }
+    if candle_tensors.is_empty() {
+        return Err(LoraError::NoAdapterTensors {
+            kind: "IA3 vectors",
+        });
+    }
+
+    candle_core::safetensors::save(&candle_tensors, output_path)?;
+
+    Ok(())
+}
+
+/// Convert a PEFT prompt tuning or prefix tuning adapter to candle-lora format
+///
+/// The `prompt_embeddings` tensor of the adapter is saved as `prompt_embeddings.weight` for
+/// prompt tuning, loaded with [`PromptEmbedding::new`](crate::PromptEmbedding::new), or as
+/// `prefix_key_values.weight` for prefix tuning, loaded with
+/// [`PrefixKeyValues::new`](crate::PrefixKeyValues::new), after checking its shape against
+/// `config`. Prefix tuning adapters with a `prefix_projection` must have been saved by PEFT
+/// outside of inference mode, with the projected keys and values.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::{convert_peft_prompt_to_candle_lora, PeftPromptConfig};
+///
+/// let config: PeftPromptConfig =
+///     serde_json::from_str(&std::fs::read_to_string("path/to/adapter_config.json")?)?;
+/// convert_peft_prompt_to_candle_lora(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     &config,
+///     &Device::Cpu,
+/// )?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn convert_peft_prompt_to_candle_lora(
+    peft_path: &str,
+    output_path: &str,
+    config: &PeftPromptConfig,
+    device: &Device,
+) -> std::result::Result<(), LoraError> {
+    config.validate()?;
+    let peft_tensors = candle_core::safetensors::load(peft_path, device)?;
+    let Some(embeddings) = peft_tensors
+        .iter()
+        .find(|(name, _)| name.ends_with("prompt_embeddings"))
+        .map(|(_, tensor)| tensor)
+    else {
+        return Err(LoraError::NoAdapterTensors {
+            kind: "prompt_embeddings",
+        });
+    };
+
+    let (name, shape) = if config.is_prefix_tuning() {
+        let width = config.num_layers * 2 * config.token_dim;
+        if config.prefix_projection
+            && embeddings.dims2()? == (config.num_virtual_tokens, config.token_dim)
+        {
+            return Err(LoraError::Unsupported(format!(
+                "{peft_path} holds the prefix embeddings of a prefix projection, as saved in \
+                 inference mode, save the projected keys and values outside of inference mode"
+            )));
+        }
+        (
+            "prefix_key_values.weight",
+            (config.num_virtual_tokens, width),
+        )
+    } else {
+        (
+            "prompt_embeddings.weight",
+            (config.num_virtual_tokens, config.token_dim),
+        )
+    };
+    if embeddings.dims2()? != shape {
+        return Err(LoraError::ShapeMismatch {
+            name: "prompt_embeddings".to_string(),
+            expected: vec![shape.0, shape.1],
+            found: embeddings.dims().to_vec(),
+        });
+    }
+
+    let candle_tensors = HashMap::from([(name.to_string(), embeddings.clone())]);
+    candle_core::safetensors::save(&candle_tensors, output_path)?;
+
+    Ok(())
+}
+
+/// Options of [`convert_peft_to_candle_lora_with_options`],
+/// [`convert_peft_dir_to_candle_lora_with_options`] and of the typed, traced and key rules
+/// conversions.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// The dtype to write the adapter tensors in, e.g. the dtype of the base model, or `None` to
+    /// keep the dtype of the PEFT adapter. Must be f32, f16 or bf16.
+    pub target_dtype: Option<DType>,
+    /// Fail on the PEFT tensors that cannot be converted, e.g. biases or the full weights of
+    /// `modules_to_save`, and on the LoRA modules the model has no layer for, instead of listing
+    /// them in [`ConversionReport::skipped`].
+    pub strict: bool,
+}
+
+impl ConvertOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the adapter tensors in `dtype`.
+    pub fn with_target_dtype(mut self, dtype: DType) -> Self {
+        self.target_dtype = Some(dtype);
+        self
+    }
+
+    /// Fail on the PEFT tensors that cannot be converted, see [`ConvertOptions::strict`].
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Check the PEFT tensors `skipped` by a conversion, which are an error in strict mode.
+    fn check_skipped(&self, skipped: &[String]) -> std::result::Result<(), LoraError> {
+        if !self.strict || skipped.is_empty() {
+            return Ok(());
+        }
+        if let Some(name) = skipped.iter().find(|name| is_lora_weight_name(name)) {
+            return Err(LoraError::UnpairedTensor { name: name.clone() });
+        }
+        Err(LoraError::UnconvertedTensors {
+            names: skipped.to_vec(),
+        })
+    }
+
+    /// Check the options and the dtype of the PEFT tensor `name`, returning the dtype to write
+    /// it in. Only f32, f16 and bf16 adapters can be converted.
+    fn output_dtype(
+        &self,
+        name: &str,
+        dtype: safetensors::Dtype,
+    ) -> std::result::Result<DType, LoraError> {
+        if let Some(target) = self.target_dtype.filter(|&dtype| !is_adapter_dtype(dtype)) {
+            return Err(LoraError::UnsupportedTargetDType(target));
+        }
+        match DType::try_from(dtype) {
+            Ok(source) if is_adapter_dtype(source) => Ok(self.target_dtype.unwrap_or(source)),
+            _ => Err(LoraError::UnsupportedDType {
+                name: name.to_string(),
+                dtype,
+            }),
+        }
+    }
+}
+
+/// Whether the PEFT tensor `name` is the A or B of a LoRA pair, by its suffix.
+fn is_lora_weight_name(name: &str) -> bool {
+    [
+        ".lora_A.weight",
+        ".lora_B.weight",
+        ".lora_A",
+        ".lora_B",
+        EMBEDDING_A,
+        EMBEDDING_B,
+    ]
+    .iter()
+    .any(|suffix| name.ends_with(suffix))
+}
+
+fn is_adapter_dtype(dtype: DType) -> bool {
+    matches!(dtype, DType::F32 | DType::F16 | DType::BF16)
+}
+
+/// Cast the converted `tensors` to the dtype they are written in, see
+/// [`ConvertOptions::target_dtype`].
+fn cast_tensors(
+    tensors: HashMap<String, Tensor>,
+    options: &ConvertOptions,
+) -> std::result::Result<HashMap<String, Tensor>, LoraError> {
+    tensors
+        .into_iter()
+        .map(|(name, tensor)| {
+            let dtype = options.output_dtype(&name, tensor.dtype().into())?;
+            Ok((name, tensor.to_dtype(dtype)?))
+        })
+        .collect()
+}
+
+/// A memory-mapped tensor written in `dtype`, cast when its data is written so that only one
+/// tensor is loaded at a time.
+struct CastView<'a> {
+    view: &'a TensorView<'a>,
+    dtype: DType,
+}
+
+impl safetensors::View for CastView<'_> {
+    fn dtype(&self) -> safetensors::Dtype {
+        self.dtype.into()
+    }
+
+    fn shape(&self) -> &[usize] {
+        self.view.shape()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        if safetensors::Dtype::from(self.dtype) == self.view.dtype() {
+            return Cow::Borrowed(self.view.data());
+        }
+        // The source dtype was checked by `ConvertOptions::output_dtype`, the cast cannot fail.
+        let tensor = self
+            .view
+            .load(&Device::Cpu)
+            .and_then(|tensor| tensor.to_dtype(self.dtype))
+            .expect("float adapter tensors can be cast");
+        Cow::Owned(safetensors::View::data(&tensor).into_owned())
+    }
 
-/// PEFT adapter_config.json structure
-#[derive(Debug, Deserialize)]
-pub struct PeftConfig {
-    pub r: usize,
-    pub lora_alpha: f64,
-    #[serde(default)]
-    pub lora_dropout: f64,
-    pub target_modules: Vec<String>,
-    pub peft_type: String,
-    #[serde(default)]
-    pub base_model_name_or_path: String,
+    fn data_len(&self) -> usize {
+        self.shape().iter().product::<usize>() * self.dtype.size_in_bytes()
+    }
 }
 
 /// Convert PEFT format LoRA weights to candle-lora format
 ///
 /// This function takes a PEFT format safetensors file and converts it to
-/// the candle-lora naming convention.
+/// the candle-lora naming convention. The file is memory-mapped and its tensors are written to
+/// the output one by one without being loaded, so that converting an adapter of any size takes
+/// little memory. The full weights saved for `modules_to_save` as
+/// `<module>.modules_to_save.<adapter>.<param>` are written as `modules_to_save.<module>.<param>`,
+/// see [`apply_modules_to_save`](crate::apply_modules_to_save); those saved as `<module>.<param>`
+/// are recognized by the `modules_to_save` of the adapter config, when converting a directory.
 ///
 /// # Arguments
 /// * `peft_path` - Path to PEFT format safetensors file (e.g., adapter_model.safetensors)
 /// * `output_path` - Path where the converted safetensors will be saved
 /// * `prefix` - Prefix for the converted tensors (e.g., "lora_llama")
-/// * `device` - Device to load tensors on
 ///
 /// # Example
 /// ```no_run
-/// use candle_core::Device;
 /// use candle_lora::convert_peft_to_candle_lora;
 ///
-/// let device = Device::Cpu;
 /// convert_peft_to_candle_lora(
 ///     "path/to/adapter_model.safetensors",
 ///     "path/to/converted.safetensors",
 ///     "lora_llama",
-///     &device
 /// ).unwrap();
 /// ```
 pub fn convert_peft_to_candle_lora(
     peft_path: &str,
     output_path: &str,
     prefix: &str,
+) -> std::result::Result<(), LoraError> {
+    convert_peft_to_candle_lora_with_options(
+        peft_path,
+        output_path,
+        prefix,
+        &ConvertOptions::default(),
+    )?;
+    Ok(())
+}
+
+/// Convert PEFT format LoRA weights to candle-lora format with `options`, e.g. casting an f32
+/// adapter to the bf16 of the base model
+///
+/// See [`convert_peft_to_candle_lora`]: the tensors are cast one by one as they are written. The
+/// returned report lists the tensors that were not converted, unless `options` are strict and
+/// such tensors are an error. No adapter config is read: its rank is the most common one and it
+/// has no alpha.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::DType;
+/// use candle_lora::{convert_peft_to_candle_lora_with_options, ConvertOptions};
+///
+/// let report = convert_peft_to_candle_lora_with_options(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     "lora_llama",
+///     &ConvertOptions::new().with_target_dtype(DType::BF16),
+/// ).unwrap();
+/// for name in &report.skipped {
+///     println!("{name} was not converted");
+/// }
+/// ```
+pub fn convert_peft_to_candle_lora_with_options(
+    peft_path: &str,
+    output_path: &str,
+    prefix: &str,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
+    let peft_tensors = mmap_weights(&[peft_path.into()])?;
+    let (_, report) = stream_indexed(&peft_tensors, output_path, prefix, None, options)?;
+    Ok(report)
+}
+
+/// Convert PEFT format LoRA weights to candle-lora format, running `hooks` over the PEFT
+/// tensors first
+///
+/// See [`convert_peft_to_candle_lora`] and [`ConversionHooks`]. The hooks work on loaded
+/// tensors, the whole adapter is loaded on `device`.
+pub fn convert_peft_to_candle_lora_with_hooks(
+    peft_path: &str,
+    output_path: &str,
+    prefix: &str,
     device: &Device,
-) -> Result<()> {
-    // Load the PEFT safetensors file
-    let peft_tensors = candle_core::safetensors::load(peft_path, device)?;
+    hooks: &ConversionHooks,
+) -> std::result::Result<(), LoraError> {
+    let peft_tensors = hooks.apply(candle_core::safetensors::load(peft_path, device)?)?;
+    convert_indexed(
+        peft_tensors,
+        output_path,
+        prefix,
+        None,
+        &ConvertOptions::default(),
+    )?;
+    Ok(())
+}
 
-    // Group LoRA pairs
-    let mut lora_pairs: Vec<(String, Tensor, Tensor)> = Vec::new();
-    let mut processed_keys = std::collections::HashSet::new();
-
-    for (name, tensor) in peft_tensors.iter() {
-        if name.contains(".lora_A.weight") && !processed_keys.contains(name) {
-            let base_name = name.replace(".lora_A.weight", "");
-            let b_name = format!("{}.lora_B.weight", base_name);
-
-            if let Some(lora_b_tensor) = peft_tensors.get(&b_name) {
-                processed_keys.insert(name.clone());
-                processed_keys.insert(b_name.clone());
-                lora_pairs.push((base_name, tensor.clone(), lora_b_tensor.clone()));
-            }
-        }
-    }
+/// Convert PEFT format LoRA weights to candle-lora format, returning the manifest of the
+/// conversion
+///
+/// See [`convert_peft_to_candle_lora`]. The manifest tells which PEFT module each `a{idx}`/
+/// `b{idx}` pair, and DoRA magnitude `m{idx}`, comes from. Save it next to the converted adapter
+/// to check the adapter with [`load_with_manifest`](crate::load_with_manifest) when loading it.
+///
+/// # Example
+/// ```no_run
+/// use candle_lora::convert_peft_to_candle_lora_with_manifest;
+///
+/// let manifest = convert_peft_to_candle_lora_with_manifest(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     "lora_llama",
+/// ).unwrap();
+/// manifest.save("path/to/converted.manifest.json").unwrap();
+/// ```
+pub fn convert_peft_to_candle_lora_with_manifest(
+    peft_path: &str,
+    output_path: &str,
+    prefix: &str,
+) -> std::result::Result<ConversionManifest, LoraError> {
+    let peft_tensors = mmap_weights(&[peft_path.into()])?;
+    let options = ConvertOptions::default();
+    let (manifest, _) = stream_indexed(&peft_tensors, output_path, prefix, None, &options)?;
+    Ok(manifest)
+}
 
-    // Sort for consistent ordering
-    lora_pairs.sort_by(|a, b| a.0.cmp(&b.0));
+fn convert_indexed(
+    peft_tensors: HashMap<String, Tensor>,
+    output_path: &str,
+    prefix: &str,
+    config: Option<&PeftConfig>,
+    options: &ConvertOptions,
+) -> std::result::Result<(ConversionManifest, ConversionReport), LoraError> {
+    // Group LoRA pairs
+    let lora_pairs = collect_lora_pairs(&peft_tensors);
+    let (report, full_weights) = ConversionReport::from_pairs(&lora_pairs, &peft_tensors, config)?;
+    options.check_skipped(&report.skipped)?;
 
     // Convert to candle-lora format
     let mut candle_tensors = HashMap::new();
+    let mut manifest = ConversionManifest::default();
 
-    for (idx, (_peft_name, lora_a, lora_b)) in lora_pairs.iter().enumerate() {
+    for (idx, (peft_name, lora_a, lora_b)) in lora_pairs.iter().enumerate() {
         let a_name = format!("{}.a{}.weight", prefix, idx);
         let b_name = format!("{}.b{}.weight", prefix, idx);
+        let magnitude = lora_magnitude(&peft_tensors, peft_name);
+        let m_name = format!("{}.m{}.weight", prefix, idx);
 
-        candle_tensors.insert(a_name, lora_a.clone());
-        candle_tensors.insert(b_name, lora_b.clone());
+        manifest.push(
+            peft_name,
+            &a_name,
+            lora_a.dims(),
+            &b_name,
+            lora_b.dims(),
+            magnitude.as_ref().map(|_| m_name.as_str()),
+        );
+        let mut tensors = vec![(a_name, lora_a.clone()), (b_name, lora_b.clone())];
+        tensors.extend(magnitude.map(|magnitude| (m_name, magnitude)));
+        for (name, tensor) in tensors {
+            let dtype = options.output_dtype(peft_name, tensor.dtype().into())?;
+            candle_tensors.insert(name, tensor.to_dtype(dtype)?);
+        }
+    }
+    for (peft_name, name) in full_weights {
+        let tensor = &peft_tensors[&peft_name];
+        let dtype = options.output_dtype(&peft_name, tensor.dtype().into())?;
+        candle_tensors.insert(format!("{MODULES_TO_SAVE}.{name}"), tensor.to_dtype(dtype)?);
     }
 
     // Save as safetensors
-    candle_core::safetensors::save(&candle_tensors, output_path)?;
+    save_converted(
+        &candle_tensors,
+        indexed_metadata(&report, prefix),
+        output_path,
+    )?;
 
-    Ok(())
+    Ok((manifest, report))
+}
+
+/// [`convert_indexed`] over memory-mapped `peft_tensors`, the tensors being written as they are
+/// from the mapped files, or cast one by one.
+fn stream_indexed(
+    peft_tensors: &MmapedSafetensors,
+    output_path: &str,
+    prefix: &str,
+    config: Option<&PeftConfig>,
+    options: &ConvertOptions,
+) -> std::result::Result<(ConversionManifest, ConversionReport), LoraError> {
+    let views = peft_tensors
+        .tensors()
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let lora_pairs = lora_pair_names(views.keys().map(String::as_str), |name| {
+        views.contains_key(name)
+    });
+
+    let mut modules = Vec::with_capacity(lora_pairs.len());
+    let mut candle_tensors = Vec::new();
+    let mut manifest = ConversionManifest::default();
+    for (idx, (peft_name, a, b)) in lora_pairs.iter().enumerate() {
+        let (lora_a, lora_b) = (&views[a], &views[b]);
+        let Some(&rank) = lora_a.shape().first() else {
+            return Err(LoraError::ScalarTensor { name: a.clone() });
+        };
+        check_rank(peft_name, rank, lora_b.shape())?;
+        modules.push((peft_name.as_str(), rank));
+
+        let a_name = format!("{}.a{}.weight", prefix, idx);
+        let b_name = format!("{}.b{}.weight", prefix, idx);
+        let magnitude = magnitude_name(peft_name, |name| views.contains_key(name));
+        let m_name = format!("{}.m{}.weight", prefix, idx);
+
+        manifest.push(
+            peft_name,
+            &a_name,
+            lora_a.shape(),
+            &b_name,
+            lora_b.shape(),
+            magnitude.as_ref().map(|_| m_name.as_str()),
+        );
+        candle_tensors.push((a_name, lora_a));
+        candle_tensors.push((b_name, lora_b));
+        if let Some(magnitude) = magnitude {
+            candle_tensors.push((m_name, &views[&magnitude]));
+        }
+    }
+    let num_magnitudes = candle_tensors.len() - 2 * lora_pairs.len();
+    let mut report = ConversionReport::new(&modules, num_magnitudes, config)?;
+    let unconverted = unconverted_tensors(views.keys().map(String::as_str), |name| {
+        views.contains_key(name)
+    });
+    for (peft_name, name) in report.split_unconverted(unconverted, config) {
+        candle_tensors.push((format!("{MODULES_TO_SAVE}.{name}"), &views[&peft_name]));
+    }
+    options.check_skipped(&report.skipped)?;
+    let candle_tensors = candle_tensors
+        .into_iter()
+        .map(|(name, view)| {
+            let dtype = options.output_dtype(&name, view.dtype())?;
+            Ok((name, CastView { view, dtype }))
+        })
+        .collect::<std::result::Result<Vec<_>, LoraError>>()?;
+
+    let metadata = Some(indexed_metadata(&report, prefix));
+    safetensors::serialize_to_file(candle_tensors, &metadata, Path::new(output_path))
+        .map_err(candle_core::Error::from)?;
+
+    Ok((manifest, report))
 }
 
 /// Convert PEFT directory to candle-lora format
 ///
 /// This function takes a PEFT format directory (containing adapter_config.json
-/// and adapter_model.safetensors) and converts it to candle-lora format.
+/// and the adapter weights, see [`load_peft_weights`]) and converts it to candle-lora format. The
+/// config, if present, is checked against the LoRA tensors, see [`PeftConfig::validate`],
+/// and describes the converted adapter in the returned [`ConversionReport`], which also lists the
+/// tensors that were not converted, see [`ConvertOptions::strict`].
 ///
 /// # Arguments
 /// * `peft_dir` - Path to PEFT format directory
 /// * `output_path` - Path where the converted safetensors will be saved
 /// * `prefix` - Prefix for the converted tensors (e.g., "lora_llama")
-/// * `device` - Device to load tensors on
+/// * `device` - Device to load tensors on, when they are PyTorch checkpoints: safetensors
+///   weights are memory-mapped and streamed to the output, see [`convert_peft_to_candle_lora`]
 pub fn convert_peft_dir_to_candle_lora(
     peft_dir: &str,
     output_path: &str,
     prefix: &str,
     device: &Device,
-) -> Result<()> {
-    let peft_path = Path::new(peft_dir);
+) -> std::result::Result<ConversionReport, LoraError> {
+    convert_peft_dir_to_candle_lora_with_options(
+        peft_dir,
+        output_path,
+        prefix,
+        device,
+        &ConvertOptions::default(),
+    )
+}
 
-    // Check for adapter files
-    let adapter_path = peft_path.join("adapter_model.safetensors");
-    let adapter_path_alt = peft_path.join("adapter.safetensors");
+/// Convert PEFT directory to candle-lora format with `options`
+///
+/// See [`convert_peft_dir_to_candle_lora`] and [`convert_peft_to_candle_lora_with_options`].
+pub fn convert_peft_dir_to_candle_lora_with_options(
+    peft_dir: &str,
+    output_path: &str,
+    prefix: &str,
+    device: &Device,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
+    let config = read_peft_config(peft_dir)?;
+    let config = config.as_ref();
+    let (_, report) = match mmap_peft_weights(peft_dir)? {
+        Some(peft_tensors) => stream_indexed(&peft_tensors, output_path, prefix, config, options)?,
+        None => {
+            let peft_tensors = load_peft_weights(peft_dir, device)?;
+            convert_indexed(peft_tensors, output_path, prefix, config, options)?
+        }
+    };
+    Ok(report)
+}
 
-    let weights_path = if adapter_path.exists() {
-        adapter_path
-    } else if adapter_path_alt.exists() {
-        adapter_path_alt
-    } else {
-        return Err(candle_core::Error::Msg(
-            "No adapter weights found (tried adapter_model.safetensors and adapter.safetensors)"
-                .to_string(),
-        ));
+/// Load the PEFT adapter of the directory `peft_dir` into the converted layers `new`, without
+/// writing a converted file
+///
+/// The adapter replaces the adapter of the layers as [`Lora::swap_adapter`] does, the PEFT
+/// modules being given to the layers in [`structural_order`], as by
+/// [`convert_peft_to_candle_lora`]: the layers must be the converted layers of the same
+/// modules. The scale of the layers is taken from `adapter_config.json`, which is required.
+/// Tied-LoRA layers cannot load PEFT adapters. The full weights of `modules_to_save` replace base
+/// model weights and are not loaded, see [`ConversionReport::modules_to_save`].
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::{load_peft_adapter, NewLayers};
+///
+/// fn load(new: &mut NewLayers<String>) {
+///     let report = load_peft_adapter("path/to/peft_model_dir", new, &Device::Cpu).unwrap();
+///     println!("loaded {} modules of rank {}", report.num_modules, report.rank);
+/// }
+/// ```
+pub fn load_peft_adapter<T: Eq + PartialEq + Hash>(
+    peft_dir: &str,
+    new: &mut NewLayers<T>,
+    device: &Device,
+) -> std::result::Result<ConversionReport, LoraError> {
+    let Some(config) = read_peft_config(peft_dir)? else {
+        return Err(LoraError::MissingAdapterConfig {
+            dir: peft_dir.to_string(),
+        });
     };
+    let peft_tensors = load_peft_weights(peft_dir, device)?;
+    let lora_pairs = collect_lora_pairs(&peft_tensors);
+    let (report, _) = ConversionReport::from_pairs(&lora_pairs, &peft_tensors, Some(&config))?;
 
-    // Load and display config if available
-    let config_path = peft_path.join("adapter_config.json");
-    if config_path.exists() {
-        if let Ok(config_str) = std::fs::read_to_string(&config_path) {
-            if let Ok(_config) = serde_json::from_str::<PeftConfig>(&config_str) {
-                // Config loaded successfully
-            }
-        }
+    // The prefix and dtype of the A weight of each layer, by id.
+    let mut layers = BTreeMap::new();
+    let saveable = new
+        .linear
+        .values()
+        .map(|layer| layer as &dyn Saveable)
+        .chain(new.conv1d.values().map(|layer| layer as &dyn Saveable))
+        .chain(new.conv2d.values().map(|layer| layer as &dyn Saveable))
+        .chain(new.embed.values().map(|layer| layer as &dyn Saveable));
+    for layer in saveable {
+        let Some((prefix, id, dtype)) = adapter_id(layer)? else {
+            return Err(LoraError::Unsupported(
+                "Tied-LoRA layers cannot load PEFT adapters".to_string(),
+            ));
+        };
+        layers.insert(id, (prefix, dtype));
+    }
+    if layers.len() != lora_pairs.len() {
+        return Err(LoraError::ModuleCountMismatch {
+            modules: lora_pairs.len(),
+            layers: layers.len(),
+        });
     }
 
-    convert_peft_to_candle_lora(weights_path.to_str().unwrap(), output_path, prefix, device)
+    // The layers of modules matching `alpha_pattern` are scaled with their own alpha.
+    let lora_config = report.lora_config().expect("the adapter config was read");
+    let mut layer_configs = HashMap::new();
+    let mut tensors = HashMap::new();
+    let mut dtype = DType::F32;
+    for ((id, (prefix, layer_dtype)), (module, lora_a, lora_b)) in layers.iter().zip(lora_pairs) {
+        dtype = *layer_dtype;
+        if let Cow::Owned(config) = lora_config.layer_config(&module) {
+            layer_configs.insert(*id, config);
+        }
+        tensors.insert(weight_name(prefix, &format!("a{id}")), lora_a);
+        tensors.insert(weight_name(prefix, &format!("b{id}")), lora_b);
+        if let Some(magnitude) = lora_magnitude(&peft_tensors, &module) {
+            tensors.insert(weight_name(prefix, &format!("m{id}")), magnitude);
+        }
+    }
+    let vb = VarBuilder::from_tensors(tensors, dtype, device);
+    Lora::swap_adapter_by_id(new, &vb, &lora_config, &layer_configs)
+        .map_err(|e| e.either(LoraError::Merge, LoraError::Candle))?;
+    Ok(report)
 }
 
 /// Convert PEFT format to candle-lora format with layer type awareness
@@ -185,36 +1713,68 @@ pub fn convert_peft_dir_to_candle_lora(
 /// * `peft_path` - Path to PEFT format safetensors file
 /// * `output_path` - Path where the converted safetensors will be saved
 /// * `device` - Device to load tensors on
-/// * `add_dummy_embeddings` - Whether to add dummy embedding tensors if not present
+/// * `dummy_embeddings` - The `(vocab_size, hidden_size)` of the model, to add zero embedding
+///   LoRA tensors of the adapter's rank if not present: the `lora_embedding_A` and
+///   `lora_embedding_B` of a PEFT embedding adapter are converted as they are, and dummies are
+///   only added when requested
 pub fn convert_peft_to_candle_lora_typed(
     peft_path: &str,
     output_path: &str,
     device: &Device,
-    add_dummy_embeddings: bool,
-) -> Result<()> {
+    dummy_embeddings: Option<(usize, usize)>,
+) -> std::result::Result<ConversionReport, LoraError> {
+    convert_peft_to_candle_lora_typed_with_hooks(
+        peft_path,
+        output_path,
+        device,
+        dummy_embeddings,
+        &ConversionHooks::default(),
+        &ConvertOptions::default(),
+    )
+}
+
+/// Convert PEFT format LoRA weights with layer type awareness, running `hooks` over the PEFT
+/// tensors first and converting them with `options`
+///
+/// See [`convert_peft_to_candle_lora_typed`], [`ConversionHooks`] and [`ConvertOptions`].
+pub fn convert_peft_to_candle_lora_typed_with_hooks(
+    peft_path: &str,
+    output_path: &str,
+    device: &Device,
+    dummy_embeddings: Option<(usize, usize)>,
+    hooks: &ConversionHooks,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
     // Load the PEFT safetensors file
-    let peft_tensors = candle_core::safetensors::load(peft_path, device)?;
+    let peft_tensors = hooks.apply(candle_core::safetensors::load(peft_path, device)?)?;
+    convert_typed(
+        peft_tensors,
+        output_path,
+        device,
+        dummy_embeddings,
+        None,
+        options,
+    )
+}
 
+fn convert_typed(
+    peft_tensors: HashMap<String, Tensor>,
+    output_path: &str,
+    device: &Device,
+    dummy_embeddings: Option<(usize, usize)>,
+    config: Option<&PeftConfig>,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
     // Group LoRA pairs
-    let mut lora_pairs: Vec<(String, Tensor, Tensor)> = Vec::new();
-    let mut processed_keys = std::collections::HashSet::new();
-
-    for (name, tensor) in peft_tensors.iter() {
-        if name.contains(".lora_A.weight") && !processed_keys.contains(name) {
-            let base_name = name.replace(".lora_A.weight", "");
-            let b_name = format!("{}.lora_B.weight", base_name);
-
-            if let Some(lora_b_tensor) = peft_tensors.get(&b_name) {
-                processed_keys.insert(name.clone());
-                processed_keys.insert(b_name.clone());
-                lora_pairs.push((base_name, tensor.clone(), lora_b_tensor.clone()));
-            }
-        }
-    }
+    let lora_pairs = collect_lora_pairs(&peft_tensors);
+    let (mut report, full_weights) =
+        ConversionReport::from_pairs(&lora_pairs, &peft_tensors, config)?;
+    options.check_skipped(&report.skipped)?;
 
-    // Group weights by prefix type
+    // Group weights by prefix type, keeping the structural order of the pairs
     let mut llama_weights = Vec::new();
     let mut llama_csa_weights = Vec::new();
+    let mut llama_mlp_weights = Vec::new();
     let mut llama_block_weights = Vec::new();
 
     for (key, lora_a, lora_b) in &lora_pairs {
@@ -222,29 +1782,36 @@ pub fn convert_peft_to_candle_lora_typed(
         match prefix_type {
             CandleLoraPrefix::Llama => llama_weights.push((key, lora_a, lora_b)),
             CandleLoraPrefix::LlamaCsa => llama_csa_weights.push((key, lora_a, lora_b)),
+            CandleLoraPrefix::LlamaMlp => llama_mlp_weights.push((key, lora_a, lora_b)),
             CandleLoraPrefix::LlamaBlock => llama_block_weights.push((key, lora_a, lora_b)),
         }
     }
 
-    // Sort each group for consistent ordering
-    llama_weights.sort_by_key(|(key, _, _)| *key);
-    llama_csa_weights.sort_by_key(|(key, _, _)| *key);
-    llama_block_weights.sort_by_key(|(key, _, _)| *key);
-
     // Convert to candle-lora format
     let mut candle_tensors = HashMap::new();
+    let mut metadata = HashMap::from([AdapterMetadata::from_report(&report)
+        .with_architecture("typed")
+        .entry()]);
 
     // Helper closure to process each group
     let mut process_group = |weights: Vec<(&String, &Tensor, &Tensor)>,
                              prefix: CandleLoraPrefix| {
-        let mut counter = 0;
-        for (_key, lora_a, lora_b) in weights {
+        let modules = weights.iter().map(|(key, _, _)| key).collect::<Vec<_>>();
+        let modules = serde_json::to_string(&modules).expect("module names serialize");
+        metadata.insert(
+            format!("{MODULES_METADATA_KEY}.{}", prefix.as_str()),
+            modules,
+        );
+        for (counter, (key, lora_a, lora_b)) in weights.into_iter().enumerate() {
             let a_name = format!("{}.a{}.weight", prefix.as_str(), counter);
             let b_name = format!("{}.b{}.weight", prefix.as_str(), counter);
 
             candle_tensors.insert(a_name.clone(), lora_a.clone());
             candle_tensors.insert(b_name.clone(), lora_b.clone());
-            counter += 1;
+            if let Some(magnitude) = lora_magnitude(&peft_tensors, key) {
+                let m_name = format!("{}.m{}.weight", prefix.as_str(), counter);
+                candle_tensors.insert(m_name, magnitude);
+            }
         }
     };
 
@@ -254,19 +1821,22 @@ pub fn convert_peft_to_candle_lora_typed(
     if !llama_csa_weights.is_empty() {
         process_group(llama_csa_weights, CandleLoraPrefix::LlamaCsa);
     }
+    if !llama_mlp_weights.is_empty() {
+        process_group(llama_mlp_weights, CandleLoraPrefix::LlamaMlp);
+    }
     if !llama_block_weights.is_empty() {
         process_group(llama_block_weights, CandleLoraPrefix::LlamaBlock);
     }
+    for (peft_name, name) in full_weights {
+        let tensor = peft_tensors[&peft_name].clone();
+        candle_tensors.insert(format!("{MODULES_TO_SAVE}.{name}"), tensor);
+    }
 
     // Add dummy embedding LoRA tensors if not present and requested
-    if add_dummy_embeddings {
+    if let Some((vocab_size, hidden_size)) = dummy_embeddings {
         let has_llama_tensors = candle_tensors.keys().any(|k| k.starts_with("lora_llama."));
         if !has_llama_tensors {
-            // Default sizes for TinyLlama, but should be configurable
-            let vocab_size = 32000;
-            let hidden_size = 2048;
-            let rank = 4; // Default rank, should match actual config
-
+            let rank = report.rank;
             let dummy_a = Tensor::zeros((rank, vocab_size), DType::F32, device)?;
             let dummy_b = Tensor::zeros((hidden_size, rank), DType::F32, device)?;
 
@@ -274,59 +1844,414 @@ pub fn convert_peft_to_candle_lora_typed(
             candle_tensors.insert("lora_llama.b0.weight".to_string(), dummy_b);
         }
     }
+    report.num_tensors = candle_tensors.len();
+    let candle_tensors = cast_tensors(candle_tensors, options)?;
 
     // Save as safetensors
-    candle_core::safetensors::save(&candle_tensors, output_path)?;
+    save_converted(&candle_tensors, metadata, output_path)?;
 
-    Ok(())
+    Ok(report)
 }
 
 /// Convert PEFT directory to candle-lora format with layer type awareness
 ///
 /// This function takes a PEFT format directory and converts it using the typed conversion.
+/// The config, if present, is checked against the LoRA tensors, see
+/// [`PeftConfig::validate`], and describes the converted adapter in the returned
+/// [`ConversionReport`].
 ///
 /// # Arguments
 /// * `peft_dir` - Path to PEFT format directory
 /// * `output_path` - Path where the converted safetensors will be saved
 /// * `device` - Device to load tensors on
-/// * `add_dummy_embeddings` - Whether to add dummy embedding tensors if not present
+/// * `dummy_embeddings` - The `(vocab_size, hidden_size)` of the model, to add zero embedding
+///   LoRA tensors of the adapter's rank if not present: the `lora_embedding_A` and
+///   `lora_embedding_B` of a PEFT embedding adapter are converted as they are, and dummies are
+///   only added when requested
 pub fn convert_peft_dir_to_candle_lora_typed(
     peft_dir: &str,
     output_path: &str,
     device: &Device,
-    add_dummy_embeddings: bool,
-) -> Result<()> {
-    let peft_path = Path::new(peft_dir);
+    dummy_embeddings: Option<(usize, usize)>,
+) -> std::result::Result<ConversionReport, LoraError> {
+    convert_peft_dir_to_candle_lora_typed_with_options(
+        peft_dir,
+        output_path,
+        device,
+        dummy_embeddings,
+        &ConvertOptions::default(),
+    )
+}
 
-    // Check for adapter files
-    let adapter_path = peft_path.join("adapter_model.safetensors");
-    let adapter_path_alt = peft_path.join("adapter.safetensors");
+/// Convert PEFT directory to candle-lora format with layer type awareness and `options`
+///
+/// See [`convert_peft_dir_to_candle_lora_typed`] and [`ConvertOptions`].
+pub fn convert_peft_dir_to_candle_lora_typed_with_options(
+    peft_dir: &str,
+    output_path: &str,
+    device: &Device,
+    dummy_embeddings: Option<(usize, usize)>,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
+    let peft_tensors = load_peft_weights(peft_dir, device)?;
+    let config = read_peft_config(peft_dir)?;
+    convert_typed(
+        peft_tensors,
+        output_path,
+        device,
+        dummy_embeddings,
+        config.as_ref(),
+        options,
+    )
+}
 
-    let weights_path = if adapter_path.exists() {
-        adapter_path
-    } else if adapter_path_alt.exists() {
-        adapter_path_alt
-    } else {
-        return Err(candle_core::Error::Msg(
-            "No adapter weights found (tried adapter_model.safetensors and adapter.safetensors)"
-                .to_string(),
-        ));
-    };
+/// Convert PEFT format LoRA weights for a traced candle-lora-transformers model
+///
+/// Unlike [`convert_peft_to_candle_lora`], tensors keep the module path of the layer they
+/// adapt, so the output can be loaded directly by the model's VarBuilder. The tensors of the
+/// modules the model does not adapt (see [`TracedArchitecture::adapts`]) are listed in
+/// [`ConversionReport::skipped`], see [`ConvertOptions::strict`]. The full weights of
+/// `modules_to_save` are written as `modules_to_save.<name>`, see
+/// [`apply_modules_to_save`](crate::apply_modules_to_save).
+///
+/// # Arguments
+/// * `peft_path` - Path to PEFT format safetensors file
+/// * `output_path` - Path where the converted safetensors will be saved
+/// * `arch` - Architecture whose key rules are applied
+/// * `device` - Device to load tensors on
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::{convert_peft_to_candle_lora_traced, TracedArchitecture};
+///
+/// let report = convert_peft_to_candle_lora_traced(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     TracedArchitecture::Granite,
+///     &Device::Cpu,
+/// ).unwrap();
+/// for name in &report.skipped {
+///     println!("{name} was not converted");
+/// }
+/// ```
+pub fn convert_peft_to_candle_lora_traced(
+    peft_path: &str,
+    output_path: &str,
+    arch: TracedArchitecture,
+    device: &Device,
+) -> std::result::Result<ConversionReport, LoraError> {
+    convert_peft_to_candle_lora_traced_with_hooks(
+        peft_path,
+        output_path,
+        arch,
+        device,
+        &ConversionHooks::default(),
+        &ConvertOptions::default(),
+    )
+}
+
+/// Convert PEFT format LoRA weights for a traced candle-lora-transformers model, running
+/// `hooks` over the PEFT tensors first and converting them with `options`
+///
+/// The hooks see the PEFT names, before the architecture's renames. See
+/// [`convert_peft_to_candle_lora_traced`], [`ConversionHooks`] and [`ConvertOptions`].
+pub fn convert_peft_to_candle_lora_traced_with_hooks(
+    peft_path: &str,
+    output_path: &str,
+    arch: TracedArchitecture,
+    device: &Device,
+    hooks: &ConversionHooks,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
+    let peft_tensors = hooks.apply(candle_core::safetensors::load(peft_path, device)?)?;
 
-    // Load and display config if available
-    let config_path = peft_path.join("adapter_config.json");
-    if config_path.exists() {
-        if let Ok(config_str) = std::fs::read_to_string(&config_path) {
-            if let Ok(_config) = serde_json::from_str::<PeftConfig>(&config_str) {
-                // Config loaded successfully
+    let (matched, unmatched): (Vec<_>, Vec<_>) = collect_lora_pairs(&peft_tensors)
+        .into_iter()
+        .partition(|(peft_name, _, _)| arch.adapts(&arch.module_path(peft_name)));
+    let unmatched = unmatched
+        .into_iter()
+        .map(|(peft_name, _, _)| peft_name)
+        .collect::<Vec<_>>();
+    let (mut report, full_weights) =
+        ConversionReport::from_matched_pairs(&matched, &unmatched, &[], &peft_tensors, options)?;
+
+    let mut candle_tensors = HashMap::new();
+    let mut modules = Vec::new();
+    for (peft_name, lora_a, lora_b) in matched {
+        let module = arch.module_path(&peft_name);
+        modules.push((peft_name.clone(), lora_a.dim(0)?));
+        let magnitude = lora_magnitude(&peft_tensors, &peft_name);
+        for (module, lora_a, lora_b, magnitude) in
+            arch.split_pair(module, lora_a, lora_b, magnitude)?
+        {
+            candle_tensors.insert(format!("{module}.{TRACED_LORA_LINEAR}.a0.weight"), lora_a);
+            candle_tensors.insert(format!("{module}.{TRACED_LORA_LINEAR}.b0.weight"), lora_b);
+            if let Some(magnitude) = magnitude {
+                let m_name = format!("{module}.{TRACED_LORA_LINEAR}.m0.weight");
+                candle_tensors.insert(m_name, magnitude);
             }
         }
     }
+    for (peft_name, name) in full_weights {
+        let tensor = peft_tensors[&peft_name].clone();
+        candle_tensors.insert(format!("{MODULES_TO_SAVE}.{name}"), tensor);
+    }
+    report.num_tensors = candle_tensors.len();
 
-    convert_peft_to_candle_lora_typed(
-        weights_path.to_str().unwrap(),
+    let metadata = named_metadata(&modules, format!("traced:{arch:?}"));
+    save_converted(
+        &cast_tensors(candle_tensors, options)?,
+        metadata,
         output_path,
-        device,
-        add_dummy_embeddings,
-    )
+    )?;
+
+    Ok(report)
+}
+
+/// Convert PEFT format LoRA weights with the key mapping of a rules file
+///
+/// Each PEFT module is renamed by the first matching rule of `rules` and stored as
+/// `<module>.a0.weight`/`<module>.b0.weight`, or with increasing indices in the
+/// [`structural_order`] of the PEFT modules for numbered rules. The tensors of the modules
+/// dropped by a rule or matching no rule are listed in [`ConversionReport::skipped`], the latter
+/// being an error in strict mode, see [`ConvertOptions::strict`].
+/// The full weights of `modules_to_save` are written as `modules_to_save.<name>`, see
+/// [`apply_modules_to_save`](crate::apply_modules_to_save).
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::{convert_peft_to_candle_lora_with_rules, ConvertOptions, KeyRules};
+///
+/// let rules = KeyRules::from_file("rules.yaml").unwrap();
+/// convert_peft_to_candle_lora_with_rules(
+///     "path/to/adapter_model.safetensors",
+///     "path/to/converted.safetensors",
+///     &rules,
+///     &Device::Cpu,
+///     &ConvertOptions::new().with_strict(true),
+/// ).unwrap();
+/// ```
+pub fn convert_peft_to_candle_lora_with_rules(
+    peft_path: &str,
+    output_path: &str,
+    rules: &KeyRules,
+    device: &Device,
+    options: &ConvertOptions,
+) -> std::result::Result<ConversionReport, LoraError> {
+    let peft_tensors = candle_core::safetensors::load(peft_path, device)?;
+
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    let mut dropped = Vec::new();
+    for (peft_name, lora_a, lora_b) in collect_lora_pairs(&peft_tensors) {
+        match rules.resolve(&peft_name) {
+            Some(resolved) => matched.push(((peft_name, lora_a, lora_b), resolved)),
+            None if rules.matches(&peft_name) => dropped.push(peft_name),
+            None => unmatched.push(peft_name),
+        }
+    }
+    let (pairs, resolved): (Vec<_>, Vec<_>) = matched.into_iter().unzip();
+    let (mut report, full_weights) =
+        ConversionReport::from_matched_pairs(&pairs, &unmatched, &dropped, &peft_tensors, options)?;
+
+    let mut candle_tensors = HashMap::new();
+    let mut counters = HashMap::new();
+    let mut modules = Vec::new();
+    for ((peft_name, lora_a, lora_b), (module, numbered)) in pairs.into_iter().zip(resolved) {
+        modules.push((peft_name.clone(), lora_a.dim(0)?));
+        let idx = if numbered {
+            let counter = counters.entry(module.clone()).or_insert(0);
+            *counter += 1;
+            *counter - 1
+        } else {
+            0
+        };
+        let a_name = format!("{module}.a{idx}.weight");
+        if candle_tensors.contains_key(&a_name) {
+            return Err(LoraError::DuplicateMapping {
+                peft_module: peft_name,
+                module,
+            });
+        }
+        candle_tensors.insert(a_name, lora_a);
+        candle_tensors.insert(format!("{module}.b{idx}.weight"), lora_b);
+        if let Some(magnitude) = lora_magnitude(&peft_tensors, &peft_name) {
+            candle_tensors.insert(format!("{module}.m{idx}.weight"), magnitude);
+        }
+    }
+    for (peft_name, name) in full_weights {
+        let tensor = peft_tensors[&peft_name].clone();
+        candle_tensors.insert(format!("{MODULES_TO_SAVE}.{name}"), tensor);
+    }
+    report.num_tensors = candle_tensors.len();
+
+    let metadata = named_metadata(&modules, "key_rules".to_string());
+    save_converted(
+        &cast_tensors(candle_tensors, options)?,
+        metadata,
+        output_path,
+    )?;
+
+    Ok(report)
+}
+
+/// Convert candle-lora format LoRA weights to PEFT format
+///
+/// The index-based weights `<prefix>.a{idx}.weight`/`<prefix>.b{idx}.weight` belong to
+/// `modules[idx]`, e.g. the modules of the model in [`structural_order`]. Name-based weights
+/// (see [`Migration`](crate::Migration)), including the `<module>.traced_lora_linear` weights
+/// of candle-lora-transformers, need no `modules`. They are saved as
+/// `base_model.model.<module>.lora_A.weight`/`base_model.model.<module>.lora_B.weight`, and
+/// the DoRA magnitudes `m{idx}` as `base_model.model.<module>.lora_magnitude_vector`. The
+/// adapters of embedding modules (`embed_tokens`, `wte`, `word_embeddings`, `embed_in`,
+/// `tok_embeddings` and `shared`) are saved as `lora_embedding_A`/`lora_embedding_B`, in the
+/// layout of [`LoraEmbedding`](crate::LoraEmbedding) which PEFT shares. The full weights
+/// `modules_to_save.<name>` are saved as `base_model.model.<name>`.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::convert_candle_lora_to_peft;
+///
+/// let modules = vec![
+///     "model.layers.0.self_attn.q_proj".to_string(),
+///     "model.layers.0.self_attn.v_proj".to_string(),
+/// ];
+/// convert_candle_lora_to_peft(
+///     "path/to/adapter.safetensors",
+///     "path/to/adapter_model.safetensors",
+///     "lora_llama",
+///     &modules,
+///     &Device::Cpu,
+/// ).unwrap();
+/// ```
+pub fn convert_candle_lora_to_peft(
+    candle_path: &str,
+    output_path: &str,
+    prefix: &str,
+    modules: &[String],
+    device: &Device,
+) -> std::result::Result<(), LoraError> {
+    let candle_tensors = candle_core::safetensors::load(candle_path, device)?;
+    let peft_tensors = candle_lora_to_peft_tensors(&candle_tensors, prefix, modules)?;
+    candle_core::safetensors::save(&peft_tensors, output_path)?;
+
+    Ok(())
+}
+
+fn candle_lora_to_peft_tensors(
+    candle_tensors: &HashMap<String, Tensor>,
+    prefix: &str,
+    modules: &[String],
+) -> std::result::Result<HashMap<String, Tensor>, LoraError> {
+    let named = migrate_to_named(candle_tensors, prefix, modules)?;
+
+    let mut peft_tensors = HashMap::new();
+    for (name, tensor) in named {
+        if let Some(name) = name.strip_prefix(&format!("{MODULES_TO_SAVE}.")) {
+            peft_tensors.insert(format!("base_model.model.{name}"), tensor);
+            continue;
+        }
+        let Some((module, kind, 0)) = lora_weight(&name) else {
+            return Err(LoraError::NotLoraWeight { name });
+        };
+        let module = module
+            .strip_suffix(&format!(".{TRACED_LORA_LINEAR}"))
+            .unwrap_or(module);
+        let module = module.strip_prefix("base_model.model.").unwrap_or(module);
+        let peft_name = match (kind, is_embedding_module(module)) {
+            ('a', false) => format!("base_model.model.{module}.lora_A.weight"),
+            ('b', false) => format!("base_model.model.{module}.lora_B.weight"),
+            ('a', true) => format!("base_model.model.{module}{EMBEDDING_A}"),
+            ('b', true) => format!("base_model.model.{module}{EMBEDDING_B}"),
+            _ => format!("base_model.model.{module}.lora_magnitude_vector"),
+        };
+        if peft_tensors.insert(peft_name.clone(), tensor).is_some() {
+            return Err(LoraError::DuplicateTensor { name: peft_name });
+        }
+    }
+    Ok(peft_tensors)
+}
+
+/// Convert candle-lora format LoRA weights to a PEFT directory
+///
+/// Writes `adapter_model.safetensors`, see [`convert_candle_lora_to_peft`], and the
+/// `adapter_config.json` of the LoRA `config`, so the adapter can be loaded with
+/// `PeftModel.from_pretrained`. The target modules are the last components of the module
+/// names, and layers whose stored rank differs from the config's are listed in `rank_pattern`.
+/// Adapters with DoRA magnitudes are saved with `use_dora`, and the modules of the full weights
+/// `modules_to_save.<module>.<param>` are listed in `modules_to_save`.
+pub fn convert_candle_lora_to_peft_dir(
+    candle_path: &str,
+    output_dir: &str,
+    prefix: &str,
+    modules: &[String],
+    config: &LoraConfig,
+    base_model_name_or_path: &str,
+    device: &Device,
+) -> std::result::Result<(), LoraError> {
+    let candle_tensors = candle_core::safetensors::load(candle_path, device)?;
+    let peft_tensors = candle_lora_to_peft_tensors(&candle_tensors, prefix, modules)?;
+
+    let mut target_modules = Vec::new();
+    let mut rank_pattern = serde_json::Map::new();
+    for (name, tensor) in in_structural_order(peft_tensors.clone()) {
+        let Some(module) = name
+            .strip_suffix(".lora_A.weight")
+            .or_else(|| name.strip_suffix(EMBEDDING_A))
+        else {
+            continue;
+        };
+        let target = module.rsplit('.').next().unwrap_or(module).to_string();
+        if !target_modules.contains(&target) {
+            target_modules.push(target);
+        }
+        let rank = tensor.dim(0)?;
+        if rank != config.rank {
+            let module = module.strip_prefix("base_model.model.").unwrap_or(module);
+            rank_pattern.insert(module.to_string(), rank.into());
+        }
+    }
+    let use_dora = peft_tensors
+        .keys()
+        .any(|name| name.ends_with(".lora_magnitude_vector"));
+    let mut modules_to_save = candle_tensors
+        .keys()
+        .filter_map(|name| {
+            let (module, _) = name
+                .strip_prefix(&format!("{MODULES_TO_SAVE}."))?
+                .rsplit_once('.')?;
+            Some(module.rsplit('.').next().unwrap_or(module).to_string())
+        })
+        .collect::<Vec<_>>();
+    modules_to_save.sort();
+    modules_to_save.dedup();
+    let adapter_config = serde_json::json!({
+        "peft_type": "LORA",
+        "task_type": "CAUSAL_LM",
+        "base_model_name_or_path": base_model_name_or_path,
+        "r": config.rank,
+        "lora_alpha": config.alpha,
+        "lora_dropout": config.dropout.unwrap_or(0.),
+        "target_modules": target_modules,
+        "rank_pattern": rank_pattern,
+        "bias": "none",
+        "fan_in_fan_out": false,
+        "use_dora": use_dora,
+        "use_rslora": config.use_rslora,
+        "modules_to_save": (!modules_to_save.is_empty()).then_some(modules_to_save),
+        "inference_mode": true,
+    });
+
+    let output_dir = Path::new(output_dir);
+    std::fs::create_dir_all(output_dir)?;
+    std::fs::write(
+        output_dir.join("adapter_config.json"),
+        serde_json::to_string_pretty(&adapter_config).unwrap(),
+    )?;
+    candle_core::safetensors::save(&peft_tensors, output_dir.join("adapter_model.safetensors"))?;
+
+    Ok(())
 }
@@ -0,0 +1,218 @@
+//! Prompt tuning and prefix tuning, adapters that leave the weights of the model alone and
+//! learn virtual tokens instead, see "The Power of Scale for Parameter-Efficient Prompt Tuning"
+//! Lester et al. 2021 <https://arxiv.org/abs/2104.08691> and "Prefix-Tuning: Optimizing
+//! Continuous Prompts for Generation" Li and Liang 2021 <https://arxiv.org/abs/2101.00190>.
+//!
+//! A prompt tuning adapter holds the embeddings of its virtual tokens, stored as
+//! `prompt_embeddings.weight`, which the model prepends to the embeddings of its input. A prefix
+//! tuning adapter holds the keys and values of its virtual tokens in every layer, stored as
+//! `prefix_key_values.weight` in the layout of PEFT's `prompt_embeddings`, which the model
+//! prepends to the keys and values of its attention layers. The real tokens are then positioned
+//! after the virtual ones, as in PEFT.
+
+use candle_core::{bail, IndexOp, Result, Tensor};
+use candle_nn::{init, VarBuilder};
+
+/// Configuration for [`PromptEmbedding`], with `num_virtual_tokens` embeddings of `token_dim`
+/// size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PromptTuningConfig {
+    num_virtual_tokens: usize,
+    token_dim: usize,
+}
+
+impl PromptTuningConfig {
+    pub fn new(num_virtual_tokens: usize, token_dim: usize) -> Self {
+        Self {
+            num_virtual_tokens,
+            token_dim,
+        }
+    }
+}
+
+/// A prompt tuning adapter: embeddings of virtual tokens prepended to the input embeddings.
+#[derive(Debug, Clone)]
+pub struct PromptEmbedding {
+    /// (num_virtual_tokens, token_dim)
+    embeddings: Tensor,
+}
+
+impl PromptEmbedding {
+    /// Load the virtual token embeddings `prompt_embeddings.weight` from `vb`. A VarMap backed
+    /// `vb` without them creates them, normally distributed like PEFT's, for training.
+    pub fn new(config: &PromptTuningConfig, vb: &VarBuilder) -> Result<Self> {
+        let embeddings = vb.pp("prompt_embeddings").get_with_hints(
+            (config.num_virtual_tokens, config.token_dim),
+            "weight",
+            init::Init::Randn {
+                mean: 0.,
+                stdev: 1.,
+            },
+        )?;
+        Ok(Self { embeddings })
+    }
+
+    pub fn num_virtual_tokens(&self) -> usize {
+        self.embeddings.dims()[0]
+    }
+
+    /// The virtual token embeddings, of shape (num_virtual_tokens, token_dim).
+    pub fn embeddings(&self) -> &Tensor {
+        &self.embeddings
+    }
+
+    /// Prepend the virtual tokens to the input embeddings of shape (batch, seq_len, token_dim).
+    pub fn prepend(&self, input_embeds: &Tensor) -> Result<Tensor> {
+        let (b_sz, _seq_len, token_dim) = input_embeds.dims3()?;
+        if token_dim != self.embeddings.dims()[1] {
+            bail!(
+                "the virtual tokens have {} features, the input embeddings {token_dim}",
+                self.embeddings.dims()[1]
+            )
+        }
+        let prompt = self
+            .embeddings
+            .to_dtype(input_embeds.dtype())?
+            .unsqueeze(0)?
+            .broadcast_as((b_sz, self.num_virtual_tokens(), token_dim))?;
+        Tensor::cat(&[&prompt, input_embeds], 1)
+    }
+}
+
+/// Configuration for [`PrefixKeyValues`], with `num_virtual_tokens` keys and values of
+/// `num_heads` heads of `head_dim` size in each of `num_layers` layers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixTuningConfig {
+    num_virtual_tokens: usize,
+    num_layers: usize,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl PrefixTuningConfig {
+    pub fn new(
+        num_virtual_tokens: usize,
+        num_layers: usize,
+        num_heads: usize,
+        head_dim: usize,
+    ) -> Self {
+        Self {
+            num_virtual_tokens,
+            num_layers,
+            num_heads,
+            head_dim,
+        }
+    }
+
+    /// The shape of `prefix_key_values.weight`, that of PEFT's `prompt_embeddings`.
+    fn weight_shape(&self) -> (usize, usize) {
+        let width = self.num_layers * 2 * self.num_heads * self.head_dim;
+        (self.num_virtual_tokens, width)
+    }
+}
+
+/// A prefix tuning adapter: keys and values of virtual tokens prepended to those of the
+/// attention layers.
+#[derive(Debug, Clone)]
+pub struct PrefixKeyValues {
+    /// The keys and values of each layer, each (num_heads, num_virtual_tokens, head_dim).
+    key_values: Vec<(Tensor, Tensor)>,
+    num_virtual_tokens: usize,
+}
+
+impl PrefixKeyValues {
+    /// Load the virtual keys and values `prefix_key_values.weight` from `vb`. A VarMap backed
+    /// `vb` without them creates them, normally distributed like PEFT's, for training.
+    pub fn new(config: &PrefixTuningConfig, vb: &VarBuilder) -> Result<Self> {
+        let weight = vb.pp("prefix_key_values").get_with_hints(
+            config.weight_shape(),
+            "weight",
+            init::Init::Randn {
+                mean: 0.,
+                stdev: 1.,
+            },
+        )?;
+        Self::from_weight(&weight, config)
+    }
+
+    /// Split a tensor laid out like PEFT's `prompt_embeddings`, of shape
+    /// (num_virtual_tokens, num_layers * 2 * num_heads * head_dim), into the keys and values of
+    /// each layer.
+    pub fn from_weight(weight: &Tensor, config: &PrefixTuningConfig) -> Result<Self> {
+        let shape = config.weight_shape();
+        if weight.dims2()? != shape {
+            bail!(
+                "prefix key values of shape {:?} do not match the config's {shape:?}",
+                weight.dims()
+            )
+        }
+        // PEFT views the tensor as (tokens, layers * 2, heads, head_dim), with the keys and
+        // values of each layer one after the other.
+        let weight = weight
+            .reshape((
+                config.num_virtual_tokens,
+                config.num_layers * 2,
+                config.num_heads,
+                config.head_dim,
+            ))?
+            .permute((1, 2, 0, 3))?;
+        let key_values = (0..config.num_layers)
+            .map(|layer| {
+                let keys = weight.i(2 * layer)?.contiguous()?;
+                let values = weight.i(2 * layer + 1)?.contiguous()?;
+                Ok((keys, values))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            key_values,
+            num_virtual_tokens: config.num_virtual_tokens,
+        })
+    }
+
+    pub fn num_virtual_tokens(&self) -> usize {
+        self.num_virtual_tokens
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.key_values.len()
+    }
+
+    /// The keys and values of `layer`, each of shape (num_heads, num_virtual_tokens, head_dim).
+    pub fn layer(&self, layer: usize) -> Option<(&Tensor, &Tensor)> {
+        self.key_values.get(layer).map(|(k, v)| (k, v))
+    }
+
+    /// Prepend the virtual keys and values of `layer` to the keys and values of shape
+    /// (batch, num_heads, seq_len, head_dim) of an attention layer.
+    pub fn prepend(
+        &self,
+        layer: usize,
+        keys: &Tensor,
+        values: &Tensor,
+    ) -> Result<(Tensor, Tensor)> {
+        let Some((prefix_k, prefix_v)) = self.layer(layer) else {
+            bail!(
+                "the prefix has {} layers, not {}",
+                self.num_layers(),
+                layer + 1
+            )
+        };
+        let (b_sz, num_heads, _seq_len, head_dim) = keys.dims4()?;
+        let (prefix_heads, num_virtual_tokens, prefix_head_dim) = prefix_k.dims3()?;
+        if (num_heads, head_dim) != (prefix_heads, prefix_head_dim) {
+            bail!(
+                "the prefix has {prefix_heads} heads of size {prefix_head_dim}, the attention \
+                 {num_heads} heads of size {head_dim}"
+            )
+        }
+        let shape = (b_sz, num_heads, num_virtual_tokens, head_dim);
+        let prepend = |prefix: &Tensor, xs: &Tensor| -> Result<Tensor> {
+            let prefix = prefix
+                .to_dtype(xs.dtype())?
+                .unsqueeze(0)?
+                .broadcast_as(shape)?;
+            Tensor::cat(&[&prefix, xs], 2)
+        };
+        Ok((prepend(prefix_k, keys)?, prepend(prefix_v, values)?))
+    }
+}
@@ -0,0 +1,123 @@
+//! Magnitude pruning of adapters: zeroing their near-zero entries before merging or saving
+//! reduces the interference between combined adapters and makes them compress better.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarMap;
+
+/// Which entries of an adapter [`prune_adapter`] zeroes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pruning {
+    /// Zero the entries of magnitude at most the threshold.
+    Threshold(f64),
+    /// Zero the given fraction of the entries of each tensor, smallest magnitudes first.
+    Sparsity(f64),
+    /// Zero the given fraction of the entries of the whole adapter, smallest magnitudes first,
+    /// so tensors with smaller entries are pruned more.
+    GlobalSparsity(f64),
+}
+
+/// The magnitudes of the entries of `tensors`, as f32.
+fn magnitudes<'a>(tensors: impl Iterator<Item = &'a Tensor>) -> Result<Vec<f32>> {
+    let mut magnitudes = Vec::new();
+    for tensor in tensors {
+        magnitudes.extend(
+            tensor
+                .abs()?
+                .to_dtype(DType::F32)?
+                .flatten_all()?
+                .to_vec1::<f32>()?,
+        );
+    }
+    Ok(magnitudes)
+}
+
+/// The threshold zeroing the `sparsity` fraction of the smallest `magnitudes`, `None` if no
+/// entry is zeroed.
+fn sparsity_threshold(mut magnitudes: Vec<f32>, sparsity: f64) -> Result<Option<f64>> {
+    if !(0. ..=1.).contains(&sparsity) {
+        candle_core::bail!("sparsity must be between 0 and 1, got {sparsity}")
+    }
+    let pruned = (sparsity * magnitudes.len() as f64).floor() as usize;
+    if pruned == 0 {
+        return Ok(None);
+    }
+    let (_, threshold, _) = magnitudes.select_nth_unstable_by(pruned - 1, f32::total_cmp);
+    Ok(Some(*threshold as f64))
+}
+
+/// Zero the entries of `tensor` of magnitude at most `threshold`.
+fn prune_tensor(tensor: &Tensor, threshold: Option<f64>) -> Result<Tensor> {
+    match threshold {
+        Some(threshold) => tensor
+            .abs()?
+            .to_dtype(DType::F32)?
+            .gt(threshold)?
+            .where_cond(tensor, &tensor.zeros_like()?),
+        None => Ok(tensor.clone()),
+    }
+}
+
+/// Prune the entries of the adapter tensors, see [`Pruning`]. Shapes and dtypes are kept.
+pub fn prune_adapter(
+    tensors: &HashMap<String, Tensor>,
+    pruning: Pruning,
+) -> Result<HashMap<String, Tensor>> {
+    let global = match pruning {
+        Pruning::GlobalSparsity(sparsity) => {
+            sparsity_threshold(magnitudes(tensors.values())?, sparsity)?
+        }
+        _ => None,
+    };
+    let mut pruned = HashMap::new();
+    for (name, tensor) in tensors {
+        let threshold = match pruning {
+            Pruning::Threshold(threshold) if threshold < 0. => {
+                candle_core::bail!("pruning threshold must be non-negative, got {threshold}")
+            }
+            Pruning::Threshold(threshold) => Some(threshold),
+            Pruning::Sparsity(sparsity) => {
+                sparsity_threshold(magnitudes(std::iter::once(tensor))?, sparsity)?
+            }
+            Pruning::GlobalSparsity(_) => global,
+        };
+        pruned.insert(name.clone(), prune_tensor(tensor, threshold)?);
+    }
+    Ok(pruned)
+}
+
+/// Prune the variables of `varmap` in place, e.g. the adapter of a model before merging it.
+pub fn prune_varmap(varmap: &VarMap, pruning: Pruning) -> Result<()> {
+    let data = varmap.data().lock().unwrap();
+    let tensors = data
+        .iter()
+        .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+        .collect();
+    for (name, tensor) in prune_adapter(&tensors, pruning)? {
+        data[&name].set(&tensor)?;
+    }
+    Ok(())
+}
+
+/// Prune the adapter safetensors file `input_path` into `output_path`, see [`prune_adapter`].
+pub fn prune_checkpoint(
+    input_path: &str,
+    output_path: &str,
+    pruning: Pruning,
+    device: &Device,
+) -> Result<()> {
+    let tensors = candle_core::safetensors::load(input_path, device)?;
+    candle_core::safetensors::save(&prune_adapter(&tensors, pruning)?, output_path)?;
+    Ok(())
+}
+
+/// The fraction of zero entries of the adapter tensors.
+pub fn adapter_sparsity(tensors: &HashMap<String, Tensor>) -> Result<f64> {
+    let magnitudes = magnitudes(tensors.values())?;
+    if magnitudes.is_empty() {
+        return Ok(0.);
+    }
+    let zeros = magnitudes.iter().filter(|m| **m == 0.).count();
+    Ok(zeros as f64 / magnitudes.len() as f64)
+}
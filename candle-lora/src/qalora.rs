@@ -0,0 +1,374 @@
+//! Quantization-aware LoRA (QA-LoRA), see "QA-LoRA: Quantization-Aware Low-Rank Adaptation of
+//! Large Language Models" Xu et al. 2023 <https://arxiv.org/abs/2309.14717>.
+//!
+//! The base weight is quantized group-wise along the input features, each group of an output row
+//! having its own scale and minimum. The adapter sees the input average-pooled over the same
+//! groups, so its delta weight is constant within each group and merging only shifts the group
+//! minimums: the merged model keeps the integer codes and scales and has no quantization error
+//! on top of the base model's.
+
+use std::{collections::HashMap, ops::Mul, sync::Arc};
+
+use candle_core::{bail, DType, Module, Result, Shape, Tensor, D};
+use candle_nn::{init, Dropout, Linear, VarBuilder};
+use either::Either;
+
+use crate::{
+    adapters_enabled, get_lora_weight, lora_input,
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterSwap, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, MergeError,
+    MergeErrorOrError, Saveable, Trainable,
+};
+
+/// A frozen linear layer whose weight is quantized to `bits` bits in groups of `group_size`
+/// input features: `weight = code * scale + min`.
+///
+/// The dequantized weight is kept for the forward pass, the codes, scales and minimums are the
+/// quantized representation to export.
+#[derive(Debug, Clone)]
+pub struct GroupQuantizedLinear {
+    codes: Tensor,
+    scales: Tensor,
+    mins: Tensor,
+    bits: usize,
+    group_size: usize,
+    linear: Linear,
+}
+
+impl GroupQuantizedLinear {
+    /// Quantize the `(out_features, in_features)` `weight`, `in_features` must be a multiple of
+    /// `group_size`.
+    pub fn quantize(
+        weight: &Tensor,
+        bias: Option<&Tensor>,
+        bits: usize,
+        group_size: usize,
+    ) -> Result<Self> {
+        if !(1..=8).contains(&bits) {
+            bail!("QA-LoRA supports 1 to 8 bits, got {bits}");
+        }
+        let (out_features, in_features) = weight.dims2()?;
+        if group_size == 0 || !in_features.is_multiple_of(group_size) {
+            bail!("in_features {in_features} is not a multiple of the group size {group_size}");
+        }
+        let levels = ((1 << bits) - 1) as f64;
+        let groups = weight.detach().to_dtype(DType::F32)?.reshape((
+            out_features,
+            in_features / group_size,
+            group_size,
+        ))?;
+        let mins = groups.min_keepdim(D::Minus1)?;
+        let scales =
+            ((groups.max_keepdim(D::Minus1)? - &mins)? / levels)?.maximum(f32::MIN_POSITIVE)?;
+        let codes = groups
+            .broadcast_sub(&mins)?
+            .broadcast_div(&scales)?
+            .round()?
+            .clamp(0f64, levels)?
+            .to_dtype(DType::U8)?
+            .reshape((out_features, in_features))?;
+        Self::new(
+            codes,
+            scales.squeeze(D::Minus1)?,
+            mins.squeeze(D::Minus1)?,
+            bits,
+            bias,
+            weight.dtype(),
+        )
+    }
+
+    fn new(
+        codes: Tensor,
+        scales: Tensor,
+        mins: Tensor,
+        bits: usize,
+        bias: Option<&Tensor>,
+        dtype: DType,
+    ) -> Result<Self> {
+        let (out_features, in_features) = codes.dims2()?;
+        let num_groups = scales.dim(1)?;
+        let group_size = in_features / num_groups;
+        let weight = codes
+            .to_dtype(DType::F32)?
+            .reshape((out_features, num_groups, group_size))?
+            .broadcast_mul(&scales.unsqueeze(2)?)?
+            .broadcast_add(&mins.unsqueeze(2)?)?
+            .reshape((out_features, in_features))?
+            .to_dtype(dtype)?;
+        Ok(Self {
+            codes,
+            scales,
+            mins,
+            bits,
+            group_size,
+            linear: Linear::new(weight, bias.map(|bias| bias.detach())),
+        })
+    }
+
+    /// The same layer with the group minimums shifted by `delta`, of shape `(out_features,
+    /// num_groups)`.
+    fn shift_mins(&self, delta: &Tensor) -> Result<Self> {
+        Self::new(
+            self.codes.clone(),
+            self.scales.clone(),
+            (&self.mins + delta.to_dtype(DType::F32)?)?,
+            self.bits,
+            self.linear.bias(),
+            self.linear.weight().dtype(),
+        )
+    }
+
+    /// The `(out_features, in_features)` u8 codes.
+    pub fn codes(&self) -> &Tensor {
+        &self.codes
+    }
+
+    /// The `(out_features, num_groups)` f32 scales.
+    pub fn scales(&self) -> &Tensor {
+        &self.scales
+    }
+
+    /// The `(out_features, num_groups)` f32 group minimums.
+    pub fn mins(&self) -> &Tensor {
+        &self.mins
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn group_size(&self) -> usize {
+        self.group_size
+    }
+}
+
+impl Module for GroupQuantizedLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.linear.forward(x)
+    }
+}
+
+impl Saveable for GroupQuantizedLinear {
+    fn get_tensors(&self, _accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        unimplemented!("Saving not supported for frozen layers, only for candle_lora layers.");
+    }
+}
+
+impl LinearLayerLike for GroupQuantizedLinear {
+    fn bias(&self) -> Option<&Tensor> {
+        self.linear.bias()
+    }
+    fn weight(&self) -> &Tensor {
+        self.linear.weight()
+    }
+    fn shape(&self) -> &Shape {
+        self.weight().shape()
+    }
+}
+
+/// A LoRA adapter on a [`GroupQuantizedLinear`], with A taking the group-pooled input of
+/// `in_features / group_size` features.
+#[derive(Debug, Clone)]
+pub struct QaLoraLinear {
+    old: Arc<GroupQuantizedLinear>,
+    ff_a: Linear,
+    ff_b: Linear,
+    scale: Option<f64>,
+    dropout: Option<Arc<Dropout>>,
+    training: bool,
+    merged: bool,
+    prefix: String,
+    id: usize,
+}
+
+impl QaLoraLinear {
+    pub fn new(
+        old: &GroupQuantizedLinear,
+        linear_config: &LoraLinearConfig,
+        config: &LoraConfig,
+        vb: &VarBuilder,
+        id: usize,
+    ) -> Result<Self> {
+        let (_, in_features) = old.codes().dims2()?;
+        if in_features != linear_config.in_features {
+            bail!(
+                "in_features {} does not match the quantized layer's {in_features}",
+                linear_config.in_features
+            );
+        }
+        let a = get_lora_weight(
+            &vb.pp(format!("a{id}")),
+            (config.rank, in_features / old.group_size()),
+            config.a_init(config.rank),
+            0,
+        )?;
+        let rank = a.dim(0)?;
+        let b = vb.pp(format!("b{id}")).get_with_hints(
+            (linear_config.out_features, rank),
+            "weight",
+            init::ZERO,
+        )?;
+
+        let training = a.is_variable();
+        Ok(QaLoraLinear {
+            old: Arc::new(old.clone()),
+            ff_a: Linear::new(a, None),
+            ff_b: Linear::new(b, None),
+            scale: config.scale(rank),
+            dropout: config.dropout.map(|x| Arc::new(Dropout::new(x))),
+            training,
+            merged: false,
+            prefix: vb.prefix(),
+            id,
+        })
+    }
+
+    /// Apply the LoRA dropout or not, see [`LoraLinear::train`](crate::LoraLinear::train).
+    pub fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// The quantized base layer, whose minimums include the adapter once merged.
+    pub fn quantized(&self) -> &GroupQuantizedLinear {
+        &self.old
+    }
+
+    /// Average the input features over the quantization groups.
+    fn pool(&self, input: &Tensor) -> Result<Tensor> {
+        let mut dims = input.dims().to_vec();
+        let in_features = dims.pop().unwrap_or(0);
+        let group_size = self.old.group_size();
+        dims.extend([in_features / group_size, group_size]);
+        input.reshape(dims)?.mean(D::Minus1)
+    }
+
+    /// The `(out_features, num_groups)` shift of the group minimums equivalent to the adapter.
+    fn group_delta(&self) -> Result<Tensor> {
+        let delta = self.ff_b.weight().matmul(self.ff_a.weight())?;
+        let scale = self.scale.unwrap_or(0.) / self.old.group_size() as f64;
+        delta.mul(scale)
+    }
+}
+
+impl Merge for QaLoraLinear {
+    fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
+        let delta = self.group_delta().map_err(Either::Right)?;
+        let (out_features, num_groups) = delta.dims2().map_err(Either::Right)?;
+        let group_size = self.old.group_size();
+        delta
+            .unsqueeze(2)
+            .and_then(|delta| delta.broadcast_as((out_features, num_groups, group_size)))
+            .and_then(|delta| delta.reshape((out_features, num_groups * group_size)))
+            .map_err(Either::Right)
+    }
+
+    fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if self.merged {
+            Err(Either::Left(MergeError::AlreadyMerged))
+        } else {
+            let delta = self.group_delta().map_err(Either::Right)?;
+            self.old = Arc::new(self.old.shift_mins(&delta).map_err(Either::Right)?);
+            self.merged = true;
+            Ok(())
+        }
+    }
+
+    fn unmerge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if !self.merged {
+            Err(Either::Left(MergeError::NotMerged))
+        } else {
+            let delta = self
+                .group_delta()
+                .and_then(|delta| delta.neg())
+                .map_err(Either::Right)?;
+            self.old = Arc::new(self.old.shift_mins(&delta).map_err(Either::Right)?);
+            self.merged = false;
+            Ok(())
+        }
+    }
+}
+
+impl AdapterSwap for QaLoraLinear {
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.ff_a.weight().shape(), init::ZERO, 0)
+            .map_err(Either::Right)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.ff_b.weight().shape(), init::ZERO, 1)
+            .map_err(Either::Right)?;
+        let rank = a.dim(0).map_err(Either::Right)?;
+        swap_weights(self, self.merged, |layer| {
+            layer.ff_a = Linear::new(a, None);
+            layer.ff_b = Linear::new(b, None);
+            layer.scale = config.scale(rank);
+        })
+    }
+}
+
+impl Module for QaLoraLinear {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let mut result = self.old.forward(input)?;
+        if self.merged || !adapters_enabled() {
+            return Ok(result);
+        }
+        if let Some(scale) = self.scale {
+            let input_new = lora_input(self.dropout.as_deref(), self.training, input)?;
+            let pooled = self.pool(&input_new)?;
+            result = (result
+                + self
+                    .ff_b
+                    .forward(&self.ff_a.forward(&pooled)?)?
+                    .mul(scale)?)?;
+        }
+        Ok(result)
+    }
+}
+
+impl Saveable for QaLoraLinear {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        accum.insert(
+            self.prefix.clone() + &format!(".a{}.weight", self.id),
+            self.ff_a.weight().clone(),
+        );
+        accum.insert(
+            self.prefix.clone() + &format!(".b{}.weight", self.id),
+            self.ff_b.weight().clone(),
+        );
+        Ok(())
+    }
+}
+
+impl Trainable for QaLoraLinear {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        vec![
+            (
+                weight_name(&self.prefix, &format!("a{}", self.id)),
+                self.ff_a.weight().clone(),
+            ),
+            (
+                weight_name(&self.prefix, &format!("b{}", self.id)),
+                self.ff_b.weight().clone(),
+            ),
+        ]
+    }
+}
+
+impl LinearLayerLike for QaLoraLinear {
+    fn bias(&self) -> Option<&Tensor> {
+        self.old.bias()
+    }
+    fn weight(&self) -> &Tensor {
+        self.old.weight()
+    }
+    fn shape(&self) -> &Shape {
+        self.old.shape()
+    }
+}
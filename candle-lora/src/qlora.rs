@@ -0,0 +1,302 @@
+//! QLoRA, LoRA adapters over quantized (GGUF/GGML) base weights, see "QLoRA: Efficient Finetuning
+//! of Quantized LLMs" Dettmers et al. 2023 <https://arxiv.org/abs/2305.14314>.
+//!
+//! The base weight stays quantized and is applied with a quantized matmul, never dequantized as a
+//! whole, while the adapter is kept in the floating point dtype of its `VarBuilder`, e.g. f16 or
+//! bf16. Candle's quantized matmul has no backward pass, so gradients reach the adapters but do
+//! not flow back through the base weights.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+    ops::Mul,
+    sync::Arc,
+};
+
+use candle_core::{
+    bail,
+    quantized::{gguf_file, QMatMul, QTensor},
+    DType, Device, Module, Result, Tensor,
+};
+use candle_nn::{init, Dropout, Linear, VarBuilder};
+use either::Either;
+
+use crate::{
+    adapters_enabled, get_lora_weight, lora_input,
+    swap::{adapter_vb, swap_weights, weight_name},
+    AdapterSwap, LoraConfig, LoraLinearConfig, Merge, MergeError, MergeErrorOrError, Saveable,
+    Trainable,
+};
+
+/// A frozen linear layer with a quantized `(out_features, in_features)` weight.
+#[derive(Debug, Clone)]
+pub struct QuantizedLinear {
+    weight: Arc<QTensor>,
+    matmul: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl QuantizedLinear {
+    pub fn new(weight: QTensor, bias: Option<Tensor>) -> Result<Self> {
+        let weight = Arc::new(weight);
+        let (out_features, _) = weight.shape().dims2()?;
+        if let Some(bias) = &bias {
+            if bias.dims1()? != out_features {
+                bail!(
+                    "bias of shape {:?} does not match out_features {out_features}",
+                    bias.shape()
+                );
+            }
+        }
+        Ok(Self {
+            matmul: QMatMul::from_arc(weight.clone())?,
+            weight,
+            bias,
+        })
+    }
+
+    /// Load the layer `name` of a GGUF file, its weight `<name>.weight` and its bias
+    /// `<name>.bias` if there is one, e.g. `blk.0.attn_q`.
+    pub fn from_gguf<R: Read + Seek>(
+        content: &gguf_file::Content,
+        reader: &mut R,
+        name: &str,
+        device: &Device,
+    ) -> Result<Self> {
+        let weight = content.tensor(reader, &format!("{name}.weight"), device)?;
+        let bias_name = format!("{name}.bias");
+        let bias = if content.tensor_infos.contains_key(&bias_name) {
+            Some(
+                content
+                    .tensor(reader, &bias_name, device)?
+                    .dequantize(device)?,
+            )
+        } else {
+            None
+        };
+        Self::new(weight, bias)
+    }
+
+    /// The quantized weight.
+    pub fn qtensor(&self) -> &QTensor {
+        &self.weight
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+
+    /// `(out_features, in_features)`.
+    pub fn dims(&self) -> (usize, usize) {
+        let dims = self.weight.shape().dims();
+        (dims[0], dims[1])
+    }
+
+    /// The same layer with `delta` added to its dequantized weight, quantized again to the same
+    /// type.
+    fn requantize_with(&self, delta: &Tensor) -> Result<Self> {
+        let weight = self.weight.dequantize(&self.weight.device())?;
+        let weight = (weight + delta.to_dtype(DType::F32)?)?;
+        Self::new(
+            QTensor::quantize(&weight, self.weight.dtype())?,
+            self.bias.clone(),
+        )
+    }
+}
+
+impl Module for QuantizedLinear {
+    /// Apply the layer to `x` of any float dtype, the quantized matmul running in f32.
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let result = self
+            .matmul
+            .forward(&x.to_dtype(DType::F32)?.contiguous()?)?;
+        let result = match &self.bias {
+            Some(bias) => result.broadcast_add(&bias.to_dtype(DType::F32)?)?,
+            None => result,
+        };
+        result.to_dtype(x.dtype())
+    }
+}
+
+/// A LoRA adapter on a [`QuantizedLinear`]. The adapter has the dtype of the `VarBuilder` it is
+/// created with, the input being cast to it.
+#[derive(Debug, Clone)]
+pub struct LoraQuantizedLinear {
+    old: Arc<QuantizedLinear>,
+    /// The base layer before merging, restored when unmerging.
+    unmerged: Option<Arc<QuantizedLinear>>,
+    ff_a: Linear,
+    ff_b: Linear,
+    scale: Option<f64>,
+    dropout: Option<Arc<Dropout>>,
+    training: bool,
+    merged: bool,
+    prefix: String,
+    id: usize,
+}
+
+impl LoraQuantizedLinear {
+    pub fn new(
+        old: &QuantizedLinear,
+        linear_config: &LoraLinearConfig,
+        config: &LoraConfig,
+        vb: &VarBuilder,
+        id: usize,
+    ) -> Result<Self> {
+        let (out_features, in_features) = old.dims();
+        let features = (linear_config.in_features, linear_config.out_features);
+        if features != (in_features, out_features) {
+            let layer = (in_features, out_features);
+            bail!("features {features:?} do not match the quantized layer's {layer:?}");
+        }
+        let a = get_lora_weight(
+            &vb.pp(format!("a{id}")),
+            (config.rank, in_features),
+            config.a_init(config.rank),
+            0,
+        )?;
+        let rank = a.dim(0)?;
+        let b =
+            vb.pp(format!("b{id}"))
+                .get_with_hints((out_features, rank), "weight", init::ZERO)?;
+
+        let training = a.is_variable();
+        Ok(LoraQuantizedLinear {
+            old: Arc::new(old.clone()),
+            unmerged: None,
+            ff_a: Linear::new(a, None),
+            ff_b: Linear::new(b, None),
+            scale: config.scale(rank),
+            dropout: config.dropout.map(|x| Arc::new(Dropout::new(x))),
+            training,
+            merged: false,
+            prefix: vb.prefix(),
+            id,
+        })
+    }
+
+    /// Apply the LoRA dropout or not, see [`LoraLinear::train`](crate::LoraLinear::train).
+    pub fn train(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// The quantized base layer, which includes the adapter once merged.
+    pub fn quantized(&self) -> &QuantizedLinear {
+        &self.old
+    }
+}
+
+impl Merge for LoraQuantizedLinear {
+    fn get_delta_weight(&self) -> std::result::Result<Tensor, MergeErrorOrError> {
+        let result = self
+            .ff_b
+            .weight()
+            .matmul(self.ff_a.weight())
+            .map_err(Either::Right)?;
+        Ok(match self.scale {
+            Some(scale) => result.mul(scale).map_err(Either::Right)?,
+            None => result,
+        })
+    }
+
+    /// Add the adapter to the dequantized base weight and quantize it again, which adds a
+    /// quantization error. Unmerging restores the original quantized weight.
+    fn merge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        if self.merged {
+            Err(Either::Left(MergeError::AlreadyMerged))
+        } else {
+            let merged = self
+                .old
+                .requantize_with(&self.get_delta_weight()?)
+                .map_err(Either::Right)?;
+            self.unmerged = Some(std::mem::replace(&mut self.old, Arc::new(merged)));
+            self.merged = true;
+            Ok(())
+        }
+    }
+
+    fn unmerge_weights(&mut self) -> std::result::Result<(), MergeErrorOrError> {
+        match self.unmerged.take() {
+            Some(old) if self.merged => {
+                self.old = old;
+                self.merged = false;
+                Ok(())
+            }
+            _ => Err(Either::Left(MergeError::NotMerged)),
+        }
+    }
+}
+
+impl AdapterSwap for LoraQuantizedLinear {
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError> {
+        let a_vb = adapter_vb(vb, &self.prefix, &format!("a{}", self.id));
+        let a = get_lora_weight(&a_vb, self.ff_a.weight().shape(), init::ZERO, 0)
+            .map_err(Either::Right)?;
+        let b_vb = adapter_vb(vb, &self.prefix, &format!("b{}", self.id));
+        let b = get_lora_weight(&b_vb, self.ff_b.weight().shape(), init::ZERO, 1)
+            .map_err(Either::Right)?;
+        let rank = a.dim(0).map_err(Either::Right)?;
+        swap_weights(self, self.merged, |layer| {
+            layer.ff_a = Linear::new(a, None);
+            layer.ff_b = Linear::new(b, None);
+            layer.scale = config.scale(rank);
+        })
+    }
+}
+
+impl Module for LoraQuantizedLinear {
+    fn forward(&self, input: &Tensor) -> Result<Tensor> {
+        let mut result = self.old.forward(input)?;
+        if self.merged || !adapters_enabled() {
+            return Ok(result);
+        }
+        if let Some(scale) = self.scale {
+            let input_new = lora_input(self.dropout.as_deref(), self.training, input)?;
+            let input_new = input_new.to_dtype(self.ff_a.weight().dtype())?;
+            let lora = self
+                .ff_b
+                .forward(&self.ff_a.forward(&input_new)?)?
+                .mul(scale)?;
+            let lora = lora.to_dtype(result.dtype())?;
+            result = (result + lora)?;
+        }
+        Ok(result)
+    }
+}
+
+impl Saveable for LoraQuantizedLinear {
+    fn get_tensors(&self, accum: &mut HashMap<String, Tensor>) -> Result<()> {
+        accum.insert(
+            self.prefix.clone() + &format!(".a{}.weight", self.id),
+            self.ff_a.weight().clone(),
+        );
+        accum.insert(
+            self.prefix.clone() + &format!(".b{}.weight", self.id),
+            self.ff_b.weight().clone(),
+        );
+        Ok(())
+    }
+}
+
+impl Trainable for LoraQuantizedLinear {
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)> {
+        vec![
+            (
+                weight_name(&self.prefix, &format!("a{}", self.id)),
+                self.ff_a.weight().clone(),
+            ),
+            (
+                weight_name(&self.prefix, &format!("b{}", self.id)),
+                self.ff_b.weight().clone(),
+            ),
+        ]
+    }
+}
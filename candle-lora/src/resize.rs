@@ -0,0 +1,133 @@
+//! Resizing of the rank of adapters, to shrink them for deployment or to give the adapters of a
+//! weighted merge the same rank.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+
+use crate::{migration::lora_weight, peft_convert::lora_pair_names, svd::truncated_svd};
+
+/// The `(A name, B name)` pairs of the adapter `tensors`: the PEFT `lora_A`/`lora_B` and
+/// `lora_embedding_A`/`lora_embedding_B` pairs, and the candle-lora `a{idx}`/`b{idx}` pairs.
+fn adapter_pairs(tensors: &HashMap<String, Tensor>) -> Vec<(String, String)> {
+    let contains = |name: &str| tensors.contains_key(name);
+    let mut pairs = lora_pair_names(tensors.keys().map(String::as_str), contains)
+        .into_iter()
+        .map(|(_, a, b)| (a, b))
+        .collect::<Vec<_>>();
+    for name in tensors.keys() {
+        if let Some((module, 'a', idx)) = lora_weight(name) {
+            let b = format!("{module}.b{idx}.weight");
+            if contains(&b) {
+                pairs.push((name.clone(), b));
+            }
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+/// Resize the LoRA pairs of the adapter `tensors` to the rank `new_rank`
+///
+/// A smaller rank keeps the best approximation of rank `new_rank` of each delta `B A`, by a
+/// truncated SVD. A larger rank pads A with zero rows and B with zero columns. The products are
+/// rescaled by `new_rank / rank`, so that the resized adapter has the delta `alpha / rank * B A`
+/// of each layer with the same `alpha` and the scaling `alpha / new_rank`, e.g. to merge
+/// adapters of different ranks trained with the same alpha. With rsLoRA scaling,
+/// `alpha / sqrt(rank)`, the resized adapter keeps the deltas with the alpha
+/// `alpha * sqrt(rank / new_rank)`.
+///
+/// The pairs are PEFT `lora_A`/`lora_B` (and embedding) pairs or candle-lora `a{idx}`/`b{idx}`
+/// pairs, of linear and embedding layers or of convolutions whose B is a 1x1 kernel
+/// `(out, rank, 1, ..)`. The other tensors, e.g. DoRA magnitudes, are kept, and the dtypes of
+/// the pairs are kept.
+pub fn resize_adapter_rank(
+    tensors: &HashMap<String, Tensor>,
+    new_rank: usize,
+) -> Result<HashMap<String, Tensor>> {
+    if new_rank == 0 {
+        candle_core::bail!("cannot resize adapters to rank 0")
+    }
+    let pairs = adapter_pairs(tensors);
+    if pairs.is_empty() {
+        candle_core::bail!("the adapter has no LoRA pairs to resize")
+    }
+    let mut resized = tensors.clone();
+    for (a_name, b_name) in pairs {
+        let (a, b) = resize_pair(&a_name, &tensors[&a_name], &tensors[&b_name], new_rank)?;
+        resized.insert(a_name, a);
+        resized.insert(b_name, b);
+    }
+    Ok(resized)
+}
+
+/// The A `a`, named `a_name`, and the B `b` of rank `new_rank`, see [`resize_adapter_rank`].
+fn resize_pair(a_name: &str, a: &Tensor, b: &Tensor, new_rank: usize) -> Result<(Tensor, Tensor)> {
+    let rank = a.dim(0)?;
+    let (b_dims, a_dims) = (b.dims(), a.dims());
+    if b_dims.len() < 2 || b_dims[1] != rank || b_dims[2..].iter().any(|&dim| dim != 1) {
+        candle_core::bail!("cannot resize {a_name} {a_dims:?} with a B of shape {b_dims:?}")
+    }
+    let a_flat = a.flatten_from(1)?.to_dtype(DType::F32)?;
+    let b_flat = b.flatten_from(1)?.to_dtype(DType::F32)?;
+    let scale = new_rank as f64 / rank as f64;
+
+    let (new_a, new_b) = if new_rank >= rank {
+        let a_pad = Tensor::zeros((new_rank - rank, a_flat.dim(1)?), DType::F32, a.device())?;
+        let b_pad = Tensor::zeros((b_flat.dim(0)?, new_rank - rank), DType::F32, b.device())?;
+        (
+            Tensor::cat(&[&a_flat, &a_pad], 0)?,
+            Tensor::cat(&[&(b_flat * scale)?, &b_pad], 1)?,
+        )
+    } else {
+        let delta = (b_flat.matmul(&a_flat)? * scale)?;
+        let (u, s, v_t) = truncated_svd(&delta, new_rank)?;
+        let sqrt_s = s.sqrt()?;
+        let new_a = v_t.broadcast_mul(&sqrt_s.unsqueeze(1)?)?;
+        let new_b = u.broadcast_mul(&sqrt_s.unsqueeze(0)?)?;
+        // The SVD of a product of a smaller rank than `new_rank` is padded.
+        let padded = new_rank - new_a.dim(0)?;
+        let a_pad = Tensor::zeros((padded, new_a.dim(1)?), DType::F32, a.device())?;
+        let b_pad = Tensor::zeros((new_b.dim(0)?, padded), DType::F32, b.device())?;
+        (
+            Tensor::cat(&[&new_a, &a_pad], 0)?,
+            Tensor::cat(&[&new_b, &b_pad], 1)?,
+        )
+    };
+
+    let mut a_shape = a_dims.to_vec();
+    a_shape[0] = new_rank;
+    let mut b_shape = b_dims.to_vec();
+    b_shape[1] = new_rank;
+    Ok((
+        new_a.reshape(a_shape)?.to_dtype(a.dtype())?,
+        new_b.reshape(b_shape)?.to_dtype(b.dtype())?,
+    ))
+}
+
+/// Resize the adapter safetensors file `input_path` to the rank `new_rank` into `output_path`,
+/// see [`resize_adapter_rank`].
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::resize_checkpoint_rank;
+///
+/// resize_checkpoint_rank(
+///     "adapter_model.safetensors",
+///     "adapter_model.r8.safetensors",
+///     8,
+///     &Device::Cpu,
+/// )
+/// .unwrap();
+/// ```
+pub fn resize_checkpoint_rank(
+    input_path: &str,
+    output_path: &str,
+    new_rank: usize,
+    device: &Device,
+) -> Result<()> {
+    let tensors = candle_core::safetensors::load(input_path, device)?;
+    candle_core::safetensors::save(&resize_adapter_rank(&tensors, new_rank)?, output_path)?;
+    Ok(())
+}
@@ -0,0 +1,275 @@
+//! A registry of loaded adapters shared between the threads of an inference server.
+//!
+//! Adapters are handed out as [`Arc`] handles: a thread running an inference keeps its adapter
+//! alive while another thread replaces or removes it, and the lock is only held to look up or
+//! swap the handles, never while loading an adapter or running a model.
+//!
+//! A store with a memory cap evicts its least recently used adapters to stay under the cap, but
+//! never the adapters a thread holds a handle to: their memory would not be freed anyway.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+
+/// The tensors of an adapter loaded in an [`AdapterStore`].
+#[derive(Debug)]
+pub struct LoadedAdapter {
+    name: String,
+    tensors: HashMap<String, Tensor>,
+}
+
+impl LoadedAdapter {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tensors(&self) -> &HashMap<String, Tensor> {
+        &self.tensors
+    }
+
+    /// A `VarBuilder` over the adapter tensors, to build the LoRA layers of a model with it.
+    pub fn var_builder(&self, dtype: DType, device: &Device) -> VarBuilder<'static> {
+        VarBuilder::from_tensors(self.tensors.clone(), dtype, device)
+    }
+
+    /// The memory taken by the adapter tensors.
+    pub fn size_in_bytes(&self) -> usize {
+        self.tensors
+            .values()
+            .map(|tensor| tensor.elem_count() * tensor.dtype().size_in_bytes())
+            .sum()
+    }
+}
+
+/// An adapter of the store, with the time it was last used for the LRU eviction.
+#[derive(Debug)]
+struct Entry {
+    adapter: Arc<LoadedAdapter>,
+    last_used: AtomicU64,
+}
+
+impl Entry {
+    /// Whether a thread other than the store holds a handle to the adapter.
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.adapter) > 1
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    adapters: HashMap<String, Entry>,
+    capacity: Option<usize>,
+    // Bumped on each use, under the read lock too, so it is atomic.
+    clock: AtomicU64,
+}
+
+impl Entries {
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn insert(&mut self, adapter: Arc<LoadedAdapter>) -> Option<Arc<LoadedAdapter>> {
+        let entry = Entry {
+            adapter: adapter.clone(),
+            last_used: AtomicU64::new(self.tick()),
+        };
+        let replaced = self.adapters.insert(adapter.name.clone(), entry);
+        self.evict(Some(&adapter.name));
+        replaced.map(|entry| entry.adapter)
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        self.adapters
+            .values()
+            .map(|entry| entry.adapter.size_in_bytes())
+            .sum()
+    }
+
+    /// Evict the least recently used adapters which are not in use, other than `keep`, until
+    /// the adapters fit in the capacity. Returns the names of the evicted adapters.
+    fn evict(&mut self, keep: Option<&str>) -> Vec<String> {
+        let Some(capacity) = self.capacity else {
+            return Vec::new();
+        };
+        let mut size = self.size_in_bytes();
+        let mut candidates = self
+            .adapters
+            .iter()
+            .filter(|(name, entry)| Some(name.as_str()) != keep && !entry.in_use())
+            .map(|(name, entry)| (entry.last_used.load(Ordering::Relaxed), name.clone()))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        let mut evicted = Vec::new();
+        for (_, name) in candidates {
+            if size <= capacity {
+                break;
+            }
+            if let Some(entry) = self.adapters.remove(&name) {
+                size -= entry.adapter.size_in_bytes();
+                evicted.push(name);
+            }
+        }
+        evicted
+    }
+}
+
+/// A thread-safe registry of named adapters, for concurrent readers with occasional writers.
+///
+/// Cloning the store is cheap and gives another handle to the same adapters.
+///
+/// With a memory cap, see [`AdapterStore::with_capacity`], adding an adapter evicts the least
+/// recently used adapters no thread holds a handle to until the adapters fit in the cap. The cap
+/// is exceeded while the adapters over it are in use, or by a single adapter larger than it, and
+/// is enforced again by the next insertion or by [`AdapterStore::evict_unused`].
+///
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::AdapterStore;
+///
+/// let store = AdapterStore::new();
+/// store.load("style", "style.safetensors", &Device::Cpu).unwrap();
+///
+/// let worker = {
+///     let store = store.clone();
+///     std::thread::spawn(move || {
+///         let adapter = store.get("style").unwrap();
+///         // Build the model with `adapter.var_builder(..)` and run it, even while the
+///         // adapter is evicted from the store.
+///     })
+/// };
+/// store.remove("style");
+/// worker.join().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AdapterStore {
+    entries: Arc<RwLock<Entries>>,
+}
+
+impl AdapterStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store which keeps the memory of its adapter tensors under `capacity_bytes`, evicting
+    /// the least recently used adapters which are not in use.
+    pub fn with_capacity(capacity_bytes: usize) -> Self {
+        let store = Self::default();
+        store.write().capacity = Some(capacity_bytes);
+        store
+    }
+
+    /// The memory cap of the store, if any, see [`AdapterStore::with_capacity`].
+    pub fn capacity(&self) -> Option<usize> {
+        self.read().capacity
+    }
+
+    /// Set or remove the memory cap, evicting the adapters over a new cap which are not in use.
+    /// Returns the names of the evicted adapters.
+    pub fn set_capacity(&self, capacity_bytes: Option<usize>) -> Vec<String> {
+        let mut entries = self.write();
+        entries.capacity = capacity_bytes;
+        entries.evict(None)
+    }
+
+    // The map is consistent whenever the lock is released, so a panic of another thread holding
+    // it does not make the store unusable.
+    fn read(&self) -> RwLockReadGuard<'_, Entries> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Entries> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Add the adapter `name`, returning the adapter it replaces. Threads using the replaced
+    /// adapter keep it until they drop their handle. Adapters over the memory cap are evicted.
+    pub fn insert(
+        &self,
+        name: impl Into<String>,
+        tensors: HashMap<String, Tensor>,
+    ) -> Option<Arc<LoadedAdapter>> {
+        let adapter = Arc::new(LoadedAdapter {
+            name: name.into(),
+            tensors,
+        });
+        self.write().insert(adapter)
+    }
+
+    /// Load the adapter `name` from a safetensors file. The file is read without holding the
+    /// lock, so readers are not blocked meanwhile. Adapters over the memory cap are evicted, but
+    /// not this one, whose returned handle keeps it in use.
+    pub fn load<P: AsRef<Path>>(
+        &self,
+        name: impl Into<String>,
+        path: P,
+        device: &Device,
+    ) -> Result<Arc<LoadedAdapter>> {
+        let adapter = Arc::new(LoadedAdapter {
+            name: name.into(),
+            tensors: candle_core::safetensors::load(path, device)?,
+        });
+        self.write().insert(adapter.clone());
+        Ok(adapter)
+    }
+
+    /// A handle to the adapter `name`, which marks it as the most recently used one.
+    pub fn get(&self, name: &str) -> Option<Arc<LoadedAdapter>> {
+        let entries = self.read();
+        let entry = entries.adapters.get(name)?;
+        entry.last_used.store(entries.tick(), Ordering::Relaxed);
+        Some(entry.adapter.clone())
+    }
+
+    /// Evict the adapter `name`. Threads using it keep it until they drop their handle.
+    pub fn remove(&self, name: &str) -> Option<Arc<LoadedAdapter>> {
+        self.write()
+            .adapters
+            .remove(name)
+            .map(|entry| entry.adapter)
+    }
+
+    /// Evict the least recently used adapters which are not in use until the adapters fit in
+    /// the memory cap, e.g. once the threads holding the adapters over it dropped their handles.
+    /// Returns the names of the evicted adapters.
+    pub fn evict_unused(&self) -> Vec<String> {
+        self.write().evict(None)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.read().adapters.contains_key(name)
+    }
+
+    /// Whether a thread holds a handle to the adapter `name`, which keeps it from being evicted
+    /// by the memory cap.
+    pub fn in_use(&self, name: &str) -> bool {
+        self.read().adapters.get(name).is_some_and(Entry::in_use)
+    }
+
+    /// The memory taken by the tensors of the loaded adapters.
+    pub fn size_in_bytes(&self) -> usize {
+        self.read().size_in_bytes()
+    }
+
+    /// The names of the loaded adapters, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.read().adapters.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().adapters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().adapters.is_empty()
+    }
+}
@@ -0,0 +1,163 @@
+//! Truncated singular value decomposition of weight deltas, to factor them into LoRA matrices.
+//!
+//! candle has no SVD, so the top singular vectors are found by randomized subspace iteration:
+//! the large products run on the device of the matrix, and the range basis and the SVD of the
+//! small projected matrix, by one-sided Jacobi rotations, are computed in f64 on the CPU.
+
+use candle_core::{DType, Device, Result, Tensor};
+
+/// The number of extra directions sampled beyond the rank, for the top singular vectors to be
+/// found accurately.
+const OVERSAMPLES: usize = 8;
+/// The number of power iterations, which separate singular values that decay slowly.
+const POWER_ITERATIONS: usize = 4;
+/// The number of sweeps of Jacobi rotations after which the SVD is taken as converged.
+const MAX_SWEEPS: usize = 60;
+
+/// The `rank` largest singular triplets of the 2D `matrix`, `(U, S, V^T)` of shapes
+/// `(m, rank)`, `(rank,)` and `(rank, n)` in f32 on the device of `matrix`, `rank` being at most
+/// `min(m, n)`. The singular values are sorted in decreasing order.
+pub(crate) fn truncated_svd(matrix: &Tensor, rank: usize) -> Result<(Tensor, Tensor, Tensor)> {
+    let (m, n) = matrix.dims2()?;
+    let rank = rank.min(m).min(n);
+    let device = matrix.device();
+    let matrix = matrix.to_dtype(DType::F32)?;
+    let samples = (rank + OVERSAMPLES).min(m).min(n);
+
+    // An orthonormal basis `q` of the range of `matrix`, `(m, samples)`.
+    let omega = Tensor::randn(0f32, 1., (n, samples), device)?;
+    let mut q = orthonormal_columns(&matrix.matmul(&omega)?)?;
+    for _ in 0..POWER_ITERATIONS {
+        let z = orthonormal_columns(&matrix.t()?.matmul(&q)?)?;
+        q = orthonormal_columns(&matrix.matmul(&z)?)?;
+    }
+
+    // The SVD of the projection `q^T matrix = J S W^T`, from the orthogonalized columns of its
+    // transpose `matrix^T q J = W S`.
+    let projected = matrix.t()?.matmul(&q)?;
+    let (mut columns, rows) = to_columns(&projected)?;
+    let mut rotations = identity(samples);
+    jacobi_sweeps(&mut columns, &mut rotations);
+    let mut order = (0..samples)
+        .map(|j| (norm(&columns[j]), j))
+        .collect::<Vec<_>>();
+    order.sort_by(|a, b| b.0.total_cmp(&a.0));
+    order.truncate(rank);
+
+    let singular_values = order.iter().map(|&(s, _)| s as f32).collect::<Vec<_>>();
+    let mut w_t = Vec::with_capacity(rank * rows);
+    let mut j_top = vec![0f32; samples * rank];
+    for (k, &(s, j)) in order.iter().enumerate() {
+        let inv = if s > 0. { 1. / s } else { 0. };
+        w_t.extend(columns[j].iter().map(|x| (x * inv) as f32));
+        for (i, rotation) in rotations.iter().enumerate() {
+            j_top[i * rank + k] = rotation[j] as f32;
+        }
+    }
+    let j_top = Tensor::from_vec(j_top, (samples, rank), device)?;
+    let u = q.matmul(&j_top)?;
+    let s = Tensor::from_vec(singular_values, rank, device)?;
+    let v_t = Tensor::from_vec(w_t, (rank, rows), device)?;
+    Ok((u, s, v_t))
+}
+
+/// The columns of the 2D `matrix` in f64, and their length.
+fn to_columns(matrix: &Tensor) -> Result<(Vec<Vec<f64>>, usize)> {
+    let (rows, cols) = matrix.dims2()?;
+    let data = matrix
+        .to_device(&Device::Cpu)?
+        .t()?
+        .to_dtype(DType::F64)?
+        .to_vec2::<f64>()?;
+    debug_assert_eq!(data.len(), cols);
+    Ok((data, rows))
+}
+
+/// An orthonormal basis of the columns of the 2D `matrix`, by twice repeated modified
+/// Gram-Schmidt, the columns that are dependent on the previous ones being zero.
+fn orthonormal_columns(matrix: &Tensor) -> Result<Tensor> {
+    let (mut columns, rows) = to_columns(matrix)?;
+    for _ in 0..2 {
+        for j in 0..columns.len() {
+            let (done, rest) = columns.split_at_mut(j);
+            let column = &mut rest[0];
+            for basis in done.iter() {
+                let dot = dot(basis, column);
+                column
+                    .iter_mut()
+                    .zip(basis)
+                    .for_each(|(x, b)| *x -= dot * b);
+            }
+            let length = norm(column);
+            let scale = if length > f64::EPSILON {
+                1. / length
+            } else {
+                0.
+            };
+            column.iter_mut().for_each(|x| *x *= scale);
+        }
+    }
+    let cols = columns.len();
+    let data = columns.into_iter().flatten().map(|x| x as f32).collect();
+    Tensor::from_vec(data, (cols, rows), &Device::Cpu)?
+        .t()?
+        .contiguous()?
+        .to_device(matrix.device())
+}
+
+/// Rotate pairs of `columns` until they are orthogonal, one-sided Jacobi, accumulating the
+/// rotations in the rows of `rotations`.
+fn jacobi_sweeps(columns: &mut [Vec<f64>], rotations: &mut [Vec<f64>]) {
+    let cols = columns.len();
+    for _ in 0..MAX_SWEEPS {
+        let mut rotated = false;
+        for p in 0..cols {
+            for q in p + 1..cols {
+                let alpha = dot(&columns[p], &columns[p]);
+                let beta = dot(&columns[q], &columns[q]);
+                let gamma = dot(&columns[p], &columns[q]);
+                if gamma == 0. || gamma.abs() <= 1e-15 * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+                let zeta = (beta - alpha) / (2. * gamma);
+                let t = zeta.signum() / (zeta.abs() + (1. + zeta * zeta).sqrt());
+                let c = 1. / (1. + t * t).sqrt();
+                let s = c * t;
+                rotate(columns, p, q, c, s);
+                for rotation in rotations.iter_mut() {
+                    let (x, y) = (rotation[p], rotation[q]);
+                    rotation[p] = c * x - s * y;
+                    rotation[q] = s * x + c * y;
+                }
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+}
+
+/// Rotate the columns `p` and `q` by the angle of cosine `c` and sine `s`.
+fn rotate(columns: &mut [Vec<f64>], p: usize, q: usize, c: f64, s: f64) {
+    let (left, right) = columns.split_at_mut(q);
+    for (x, y) in left[p].iter_mut().zip(right[0].iter_mut()) {
+        let (xp, xq) = (*x, *y);
+        *x = c * xp - s * xq;
+        *y = s * xp + c * xq;
+    }
+}
+
+fn identity(n: usize) -> Vec<Vec<f64>> {
+    (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1. } else { 0. }).collect())
+        .collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn norm(a: &[f64]) -> f64 {
+    dot(a, a).sqrt()
+}
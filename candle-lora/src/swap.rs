@@ -0,0 +1,73 @@
+//! Hot-swapping of the adapter of LoRA layers: a long-running server switches between adapters
+//! on the same base model by replacing the LoRA weights only, the base weights being shared.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Result};
+use candle_nn::VarBuilder;
+
+use crate::{migration::lora_weight, LoraConfig, Merge, MergeErrorOrError, Saveable};
+
+/// A LoRA layer whose adapter can be replaced at runtime.
+pub trait AdapterSwap: Merge {
+    /// Replace the LoRA weights by those of another adapter, read from `vb` under the names the
+    /// layer was created with (e.g. `lora_llama.a3.weight`), and take its scale from `config`.
+    ///
+    /// The adapter may have another rank. Swapping the adapter of an unmerged layer is
+    /// O(adapter size); a merged layer is unmerged and merged again with the new adapter, which
+    /// rewrites its base weight. On error the layer keeps its adapter.
+    fn swap_adapter(
+        &mut self,
+        vb: &VarBuilder,
+        config: &LoraConfig,
+    ) -> std::result::Result<(), MergeErrorOrError>;
+}
+
+/// The `VarBuilder` of the weight `name` of a layer created under `prefix`.
+pub(crate) fn adapter_vb<'a>(vb: &VarBuilder<'a>, prefix: &str, name: &str) -> VarBuilder<'a> {
+    if prefix.is_empty() {
+        vb.pp(name)
+    } else {
+        vb.pp(format!("{prefix}.{name}"))
+    }
+}
+
+/// The full name of the weight `name` of a layer created under `prefix`, e.g.
+/// `lora_llama.a3.weight`, as in the `VarMap` the layer was created from.
+pub(crate) fn weight_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        format!("{name}.weight")
+    } else {
+        format!("{prefix}.{name}.weight")
+    }
+}
+
+/// Run `set`, which replaces the adapter of `layer`, unmerging and merging the layer around it
+/// if it is `merged`.
+pub(crate) fn swap_weights<L: Merge>(
+    layer: &mut L,
+    merged: bool,
+    set: impl FnOnce(&mut L),
+) -> std::result::Result<(), MergeErrorOrError> {
+    if merged {
+        layer.unmerge_weights()?;
+        set(layer);
+        layer.merge_weights()
+    } else {
+        set(layer);
+        Ok(())
+    }
+}
+
+/// The prefix and id of a LoRA layer and the dtype of its adapter, read from the name of its A
+/// weight. Tied-LoRA layers, whose A weight is shared, have none.
+pub(crate) fn adapter_id(layer: &dyn Saveable) -> Result<Option<(String, usize, DType)>> {
+    let mut tensors = HashMap::new();
+    layer.get_tensors(&mut tensors)?;
+    Ok(tensors
+        .iter()
+        .find_map(|(name, tensor)| match lora_weight(name) {
+            Some((prefix, 'a', id)) => Some((prefix.to_string(), id, tensor.dtype())),
+            _ => None,
+        }))
+}
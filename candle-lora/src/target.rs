@@ -0,0 +1,117 @@
+//! Selective targeting of the converted layers, mirroring PEFT's `target_modules`,
+//! `layers_to_transform` and `layers_pattern`.
+//!
+//! A [`TargetSpec`] set with [`crate::LoraConfig::with_target`] is matched against the path of
+//! each selected layer, the prefix of the `VarBuilder` given to [`crate::Lora::convert_model`]
+//! followed by the layer name, e.g. `model.layers.20.self_attn.lora_llama_csa.q_proj` for the
+//! models of `candle-lora-transformers`. Layers it does not target are left as they are and take
+//! no `a{id}`/`b{id}` id.
+
+use std::ops::RangeInclusive;
+
+use candle_core::{bail, Result};
+use candle_nn::VarBuilder;
+use regex::Regex;
+
+/// The names PEFT looks for in front of the layer index, `layers.N`, `h.N` and the like.
+const COMMON_LAYERS_PATTERN: &str = "layers|h|block|blocks|layer";
+
+/// Which of the selected layers to convert: those matching any module pattern, in the given
+/// layer index ranges. An empty spec targets every layer.
+#[derive(Debug, Clone)]
+pub struct TargetSpec {
+    modules: Vec<Regex>,
+    layers: Vec<RangeInclusive<usize>>,
+    layers_pattern: Regex,
+}
+
+impl Default for TargetSpec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TargetSpec {
+    pub fn new() -> Self {
+        Self {
+            modules: Vec::new(),
+            layers: Vec::new(),
+            layers_pattern: layers_regex(COMMON_LAYERS_PATTERN).unwrap(),
+        }
+    }
+
+    /// Target the layers whose path matches the regular expression `pattern` anywhere, e.g.
+    /// `self_attn` or `(q|v)_proj$`.
+    pub fn with_module_pattern(mut self, pattern: &str) -> Result<Self> {
+        match Regex::new(pattern) {
+            Ok(regex) => self.modules.push(regex),
+            Err(e) => bail!("invalid module pattern {pattern}: {e}"),
+        }
+        Ok(self)
+    }
+
+    /// Target only the layers whose index is in `layers`, like PEFT's `layers_to_transform`.
+    /// Layers without an index in their path are then not targeted.
+    pub fn with_layers(mut self, layers: RangeInclusive<usize>) -> Self {
+        self.layers.push(layers);
+        self
+    }
+
+    /// Read the layer index after the path component matching `pattern`, e.g. `layers` or
+    /// `blocks|h`, instead of the common names, like PEFT's `layers_pattern`.
+    pub fn with_layers_pattern(mut self, pattern: &str) -> Result<Self> {
+        self.layers_pattern = layers_regex(pattern)?;
+        Ok(self)
+    }
+
+    /// The layer index in `path`, e.g. 20 for `model.layers.20.self_attn.q_proj`.
+    pub fn layer_index(&self, path: &str) -> Option<usize> {
+        self.layers_pattern
+            .captures(path)
+            .and_then(|captures| captures[1].parse().ok())
+    }
+
+    /// Whether the layer at `path` is targeted.
+    pub fn matches(&self, path: &str) -> bool {
+        let module = self.modules.is_empty() || self.modules.iter().any(|m| m.is_match(path));
+        let layer = self.layers.is_empty()
+            || self
+                .layer_index(path)
+                .is_some_and(|index| self.layers.iter().any(|r| r.contains(&index)));
+        module && layer
+    }
+}
+
+fn layers_regex(pattern: &str) -> Result<Regex> {
+    match Regex::new(&format!(r"(?:^|\.)(?:{pattern})\.(\d+)(?:\.|$)")) {
+        Ok(regex) => Ok(regex),
+        Err(e) => bail!("invalid layers pattern {pattern}: {e}"),
+    }
+}
+
+/// The regular expression of a PEFT `rank_pattern` or `alpha_pattern` key, matching the layer
+/// paths whose last components match `pattern`.
+pub(crate) fn suffix_regex(pattern: &str) -> Result<Regex> {
+    match Regex::new(&format!(r"(?:^|\.)(?:{pattern})$")) {
+        Ok(regex) => Ok(regex),
+        Err(e) => bail!("invalid layer pattern {pattern}: {e}"),
+    }
+}
+
+/// The value of the first of `patterns` matching `path`.
+pub(crate) fn pattern_value<V: Copy>(patterns: &[(Regex, V)], path: &str) -> Option<V> {
+    patterns
+        .iter()
+        .find(|(regex, _)| regex.is_match(path))
+        .map(|&(_, value)| value)
+}
+
+/// The path a [`TargetSpec`] matches for the layer `name` converted under `vb`.
+pub(crate) fn layer_path(vb: &VarBuilder, name: &impl std::fmt::Display) -> String {
+    let prefix = vb.prefix();
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{prefix}.{name}")
+    }
+}
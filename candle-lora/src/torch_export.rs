@@ -0,0 +1,365 @@
+//! Merging of PEFT adapters into base model weights and its inverse, the extraction of an
+//! adapter from a fine-tuned model, and export of merged or adapter weights as a PyTorch state
+//! dict (`torch.save` zip format), for models merged in Rust to be picked up by Python pipelines
+//! with `torch.load` or `from_pretrained`.
+
+use std::{collections::HashMap, io::Write, path::Path};
+
+use candle_core::{DType, Device, Result, Tensor};
+use safetensors::View;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    dora::channel_norms,
+    lora_scaling,
+    peft_convert::{
+        collect_lora_pairs, is_embedding_adapter, is_embedding_module, lora_magnitude, EMBEDDING_A,
+        EMBEDDING_B,
+    },
+    structural_order,
+    svd::truncated_svd,
+    PeftConfig,
+};
+
+/// Merge a PEFT adapter into the base model weights, keeping the transformers key names.
+///
+/// The delta `lora_alpha / r * B A` of each PEFT module `base_model.model.<module>` is added to
+/// `<module>.weight`, `r` being the rank of the module's A. Deltas of `fan_in_fan_out` layers
+/// (GPT-2 `Conv1D`) and of embedding adapters, `lora_embedding_A` and `lora_embedding_B`, are
+/// transposed to the base weight layout. Modules of DoRA adapters, with a
+/// `lora_magnitude_vector`, are rescaled to their magnitude per output channel. The sum is
+/// computed in f32 and converted back to the dtype of the base weight.
+pub fn merge_peft_adapter(
+    base: &HashMap<String, Tensor>,
+    adapter: &HashMap<String, Tensor>,
+    lora_alpha: f64,
+) -> Result<HashMap<String, Tensor>> {
+    merge_scaled(base, adapter, |rank| lora_scaling(lora_alpha, rank, false))
+}
+
+/// Merge a PEFT adapter into the base model weights with the scaling of its adapter `config`,
+/// `lora_alpha / sqrt(r)` for rsLoRA adapters, see [`merge_peft_adapter`].
+pub fn merge_peft_adapter_with_config(
+    base: &HashMap<String, Tensor>,
+    adapter: &HashMap<String, Tensor>,
+    config: &PeftConfig,
+) -> Result<HashMap<String, Tensor>> {
+    merge_scaled(base, adapter, |rank| {
+        lora_scaling(config.lora_alpha, rank, config.use_rslora)
+    })
+}
+
+/// Merge the PEFT `adapter` into `base`, the delta of a module of rank `r` scaled by
+/// `scaling(r)`.
+fn merge_scaled(
+    base: &HashMap<String, Tensor>,
+    adapter: &HashMap<String, Tensor>,
+    scaling: impl Fn(usize) -> f64,
+) -> Result<HashMap<String, Tensor>> {
+    let mut merged = base.clone();
+    for (peft_module, lora_a, lora_b) in collect_lora_pairs(adapter) {
+        let module = peft_module
+            .strip_prefix("base_model.model.")
+            .unwrap_or(&peft_module);
+        let name = format!("{module}.weight");
+        let Some(weight) = merged.get(&name) else {
+            candle_core::bail!("the base model has no weight {name} for the adapter")
+        };
+        let rank = lora_a.dim(0)?;
+        let delta = lora_b
+            .to_dtype(DType::F32)?
+            .matmul(&lora_a.to_dtype(DType::F32)?)?
+            .affine(scaling(rank), 0.)?
+            .to_device(weight.device())?;
+        // Embedding deltas are transposed even when the embedding weight is square.
+        let transposed = delta.dims() != weight.dims()
+            || is_embedding_adapter(&peft_module, |name| adapter.contains_key(name));
+        let delta = if !transposed {
+            delta
+        } else if delta.t()?.dims() == weight.dims() {
+            delta.t()?
+        } else {
+            candle_core::bail!(
+                "adapter delta of shape {:?} does not fit {name} of shape {:?}",
+                delta.dims(),
+                weight.dims()
+            )
+        };
+        let adapted = (weight.to_dtype(DType::F32)? + delta)?;
+        let adapted = match lora_magnitude(adapter, &peft_module) {
+            Some(magnitude) => {
+                // W' = m * (W + delta) / ||W + delta||, over the output channels.
+                let rows = if transposed { adapted.t()? } else { adapted };
+                let scale = (magnitude.to_dtype(DType::F32)?.to_device(rows.device())?
+                    / channel_norms(&rows)?)?;
+                let rows = rows.broadcast_mul(&scale.unsqueeze(1)?)?;
+                if transposed {
+                    rows.t()?
+                } else {
+                    rows
+                }
+            }
+            None => adapted,
+        };
+        merged.insert(name, adapted.to_dtype(weight.dtype())?);
+    }
+    Ok(merged)
+}
+
+/// Extract a PEFT LoRA adapter of rank `rank` from the difference between the weights of a fully
+/// fine-tuned model and of its base model, the inverse of [`merge_peft_adapter`].
+///
+/// The difference of each 2D `<module>.weight` that changed is factored by a truncated SVD,
+/// `U S V^T`, into `B = U sqrt(S)` and `A = sqrt(S) V^T`, the best approximation of rank `rank`
+/// of the difference, saved in f32 as `base_model.model.<module>.lora_A.weight` and
+/// `lora_B.weight`. Embeddings (`embed_tokens`, `wte`, ...) are saved as the
+/// `lora_embedding_A` and `lora_embedding_B` of the transposed difference. The deltas are not
+/// scaled: the adapter is merged, or loaded, with `lora_alpha` equal to `rank`. Layers of a
+/// smaller dimension get the rank of that dimension.
+///
+/// The other weights, e.g. the norms and biases, cannot be expressed as LoRA and are ignored,
+/// and the finetuned weights must have the names and shapes of the base weights.
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::extract_lora_from_diff;
+///
+/// let device = Device::Cpu;
+/// let base = candle_core::safetensors::load("base/model.safetensors", &device).unwrap();
+/// let finetuned = candle_core::safetensors::load("finetuned/model.safetensors", &device).unwrap();
+/// let adapter = extract_lora_from_diff(&base, &finetuned, 16).unwrap();
+/// candle_core::safetensors::save(&adapter, "adapter_model.safetensors").unwrap();
+/// ```
+pub fn extract_lora_from_diff(
+    base_weights: &HashMap<String, Tensor>,
+    finetuned_weights: &HashMap<String, Tensor>,
+    rank: usize,
+) -> Result<HashMap<String, Tensor>> {
+    if rank == 0 {
+        candle_core::bail!("cannot extract an adapter of rank 0")
+    }
+    let mut names = finetuned_weights.keys().collect::<Vec<_>>();
+    names.sort_by(|a, b| structural_order(a, b));
+
+    let mut adapter = HashMap::new();
+    for name in names {
+        let finetuned = &finetuned_weights[name];
+        let Some(base) = base_weights.get(name) else {
+            candle_core::bail!("the base model has no weight {name}")
+        };
+        if base.dims() != finetuned.dims() {
+            candle_core::bail!(
+                "{name} is {:?} in the finetuned model but {:?} in the base model",
+                finetuned.dims(),
+                base.dims()
+            )
+        }
+        let Some(module) = name.strip_suffix(".weight") else {
+            continue;
+        };
+        if finetuned.rank() != 2 {
+            continue;
+        }
+        let diff = (finetuned.to_dtype(DType::F32)? - base.to_dtype(DType::F32)?)?;
+        if diff.abs()?.max_all()?.to_scalar::<f32>()? == 0. {
+            continue;
+        }
+        let embedding = is_embedding_module(module);
+        let diff = if embedding { diff.t()? } else { diff };
+        let (u, s, v_t) = truncated_svd(&diff, rank)?;
+        let sqrt_s = s.sqrt()?;
+        let lora_b = u.broadcast_mul(&sqrt_s.unsqueeze(0)?)?;
+        let lora_a = v_t.broadcast_mul(&sqrt_s.unsqueeze(1)?)?;
+        let (a_name, b_name) = if embedding {
+            (
+                format!("base_model.model.{module}{EMBEDDING_A}"),
+                format!("base_model.model.{module}{EMBEDDING_B}"),
+            )
+        } else {
+            (
+                format!("base_model.model.{module}.lora_A.weight"),
+                format!("base_model.model.{module}.lora_B.weight"),
+            )
+        };
+        adapter.insert(a_name, lora_a);
+        adapter.insert(b_name, lora_b);
+    }
+    if adapter.is_empty() {
+        candle_core::bail!("the finetuned model has no 2D weight that differs from the base model")
+    }
+    Ok(adapter)
+}
+
+/// A minimal protocol 2 pickle writer for state dicts.
+struct Pickler {
+    buf: Vec<u8>,
+}
+
+impl Pickler {
+    fn op(&mut self, op: u8) {
+        self.buf.push(op);
+    }
+
+    fn global(&mut self, module: &str, name: &str) {
+        self.op(b'c');
+        self.buf
+            .extend_from_slice(format!("{module}\n{name}\n").as_bytes());
+    }
+
+    fn unicode(&mut self, s: &str) {
+        self.op(b'X');
+        self.buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn int(&mut self, value: usize) {
+        if let Ok(value) = u8::try_from(value) {
+            self.op(b'K');
+            self.op(value);
+        } else if let Ok(value) = i32::try_from(value) {
+            self.op(b'J');
+            self.buf.extend_from_slice(&value.to_le_bytes());
+        } else {
+            // LONG1 with an 8 byte little-endian two's complement, always positive here.
+            self.op(0x8a);
+            self.op(9);
+            self.buf.extend_from_slice(&(value as u64).to_le_bytes());
+            self.op(0);
+        }
+    }
+
+    fn tuple(&mut self, values: &[usize]) {
+        self.op(b'(');
+        for value in values {
+            self.int(*value);
+        }
+        self.op(b't');
+    }
+
+    fn empty_ordered_dict(&mut self) {
+        self.global("collections", "OrderedDict");
+        self.op(b')');
+        self.op(b'R');
+    }
+}
+
+fn storage_class(dtype: DType) -> Result<&'static str> {
+    Ok(match dtype {
+        DType::F32 => "FloatStorage",
+        DType::F64 => "DoubleStorage",
+        DType::F16 => "HalfStorage",
+        DType::BF16 => "BFloat16Storage",
+        DType::U8 => "ByteStorage",
+        DType::I64 => "LongStorage",
+        dtype => candle_core::bail!("PyTorch has no storage for {dtype:?}"),
+    })
+}
+
+/// Save tensors as a PyTorch state dict loadable with `torch.load`, e.g. as the
+/// `pytorch_model.bin` of a transformers model.
+///
+/// Works for merged model weights as well as raw adapter weights, e.g. a PEFT
+/// `adapter_model.bin`. `u32` tensors, which PyTorch does not have, are saved as `int64`.
+pub fn save_torch_state_dict<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    path: P,
+) -> Result<()> {
+    let mut names = tensors.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut pickler = Pickler { buf: vec![0x80, 2] };
+    pickler.empty_ordered_dict();
+    pickler.op(b'(');
+    let mut storages = Vec::with_capacity(names.len());
+    for (idx, name) in names.iter().enumerate() {
+        let tensor = &tensors[*name];
+        let tensor = match tensor.dtype() {
+            DType::U32 => tensor.to_dtype(DType::I64)?,
+            _ => tensor.clone(),
+        };
+        let dims = tensor.dims().to_vec();
+        let mut strides = vec![1; dims.len()];
+        for dim in (0..dims.len().saturating_sub(1)).rev() {
+            strides[dim] = strides[dim + 1] * dims[dim + 1];
+        }
+
+        pickler.unicode(name);
+        pickler.global("torch._utils", "_rebuild_tensor_v2");
+        pickler.op(b'(');
+        // Persistent id of the storage.
+        pickler.op(b'(');
+        pickler.unicode("storage");
+        pickler.global("torch", storage_class(tensor.dtype())?);
+        pickler.unicode(&idx.to_string());
+        pickler.unicode("cpu");
+        pickler.int(tensor.elem_count());
+        pickler.op(b't');
+        pickler.op(b'Q');
+        pickler.int(0);
+        pickler.tuple(&dims);
+        pickler.tuple(&strides);
+        pickler.op(0x89);
+        pickler.empty_ordered_dict();
+        pickler.op(b't');
+        pickler.op(b'R');
+        storages.push(tensor);
+    }
+    pickler.op(b'u');
+    pickler.op(b'.');
+
+    let mut zip = ZipWriter::new(std::fs::File::create(path)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("archive/data.pkl", options)?;
+    zip.write_all(&pickler.buf)?;
+    zip.start_file("archive/byteorder", options)?;
+    zip.write_all(b"little")?;
+    for (idx, tensor) in storages.iter().enumerate() {
+        let data = View::data(&tensor);
+        let options = options.large_file(data.len() >= u32::MAX as usize);
+        zip.start_file(format!("archive/data/{idx}"), options)?;
+        zip.write_all(&data)?;
+    }
+    zip.start_file("archive/version", options)?;
+    zip.write_all(b"3\n")?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// Merge a PEFT adapter directory into the base model safetensors files and save the result
+/// as a PyTorch state dict, see [`merge_peft_adapter_with_config`] and
+/// [`save_torch_state_dict`].
+///
+/// # Example
+/// ```no_run
+/// use candle_core::Device;
+/// use candle_lora::export_merged_to_pytorch;
+///
+/// export_merged_to_pytorch(
+///     &["path/to/model.safetensors"],
+///     "path/to/peft_adapter",
+///     "path/to/pytorch_model.bin",
+///     &Device::Cpu,
+/// ).unwrap();
+/// ```
+pub fn export_merged_to_pytorch<P: AsRef<Path>>(
+    base_paths: &[P],
+    peft_dir: &str,
+    output_path: &str,
+    device: &Device,
+) -> Result<()> {
+    let peft_dir = Path::new(peft_dir);
+    let config = std::fs::read_to_string(peft_dir.join("adapter_config.json"))?;
+    let config = match serde_json::from_str::<PeftConfig>(&config) {
+        Ok(config) => config,
+        Err(e) => candle_core::bail!("invalid adapter_config.json: {e}"),
+    };
+    let adapter =
+        candle_core::safetensors::load(peft_dir.join("adapter_model.safetensors"), device)?;
+    let mut base = HashMap::new();
+    for path in base_paths {
+        base.extend(candle_core::safetensors::load(path, device)?);
+    }
+    let merged = merge_peft_adapter_with_config(&base, &adapter, &config)?;
+    save_torch_state_dict(&merged, output_path)
+}
@@ -0,0 +1,607 @@
+//! Optimization of LoRA adapters: the trainable variables of the LoRA layers, learning rate
+//! schedules, gradient clipping, mixed precision, regularization of the weight deltas and a
+//! trainer applying them around a candle optimizer, with gradient checkpointing and memory
+//! profiling.
+
+use std::{collections::HashSet, f64::consts::PI};
+
+use candle_core::{backprop::GradStore, DType, Device, Result, Tensor, TensorId, Var};
+use candle_nn::{Optimizer, VarBuilder, VarMap};
+use serde::Deserialize;
+
+use crate::{
+    activation_bytes, all_reduce_grads, checkpointed_backward, tensors_bytes, BlockFn,
+    Communicator, GradientCheckpointing, MemoryStats, Merge, UnfreezeSchedule,
+};
+
+/// LoRA layers whose adapter can be trained.
+///
+/// The layers detach their base weights, so only the adapter receives gradients.
+pub trait Trainable {
+    /// The adapter tensors of the layer by variable name, e.g. `lora_llama.a0.weight`. Tensors
+    /// shared by several layers, as with Tied-LoRA, have the same name in each.
+    fn trainable_tensors(&self) -> Vec<(String, Tensor)>;
+
+    /// The adapter variables of the layer, for a `candle_nn::Optimizer`. The layer must have
+    /// been created from a `VarMap`, adapters loaded from files are constants.
+    fn trainable_params(&self) -> Result<Vec<Var>> {
+        self.trainable_tensors()
+            .into_iter()
+            .map(|(name, tensor)| trainable_var(&name, &tensor))
+            .collect()
+    }
+}
+
+/// The variable of the adapter tensor `name`, which shares its storage.
+pub(crate) fn trainable_var(name: &str, tensor: &Tensor) -> Result<Var> {
+    if !tensor.is_variable() {
+        candle_core::bail!("{name} is not a variable, create the layer from a VarMap to train it")
+    }
+    Var::from_tensor(tensor)
+}
+
+/// A `VarBuilder` over constant copies of the variables of `varmap`, e.g. a base model created
+/// or trained from a `VarMap`, sharing their storage. The layers built from it are frozen, only
+/// the adapters created from another `VarMap` are trained.
+pub fn freeze_base_weights(varmap: &VarMap, dtype: DType, device: &Device) -> VarBuilder<'static> {
+    let tensors = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| (name.clone(), var.as_tensor().detach()))
+        .collect();
+    VarBuilder::from_tensors(tensors, dtype, device)
+}
+
+/// Learning rate schedules, named as in HuggingFace transformers. All of them start with a linear
+/// warmup from 0 over `warmup_steps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LrScheduler {
+    /// Constant after the warmup.
+    #[default]
+    Constant,
+    /// Linear decay to 0 at `total_steps`.
+    Linear,
+    /// Half cosine decay to 0 at `total_steps`.
+    Cosine,
+}
+
+fn default_learning_rate() -> f64 {
+    2e-4
+}
+
+fn default_init_scale() -> f64 {
+    65536.
+}
+
+fn default_growth_factor() -> f64 {
+    2.
+}
+
+fn default_backoff_factor() -> f64 {
+    0.5
+}
+
+fn default_growth_interval() -> usize {
+    2000
+}
+
+/// Half precision training of adapters whose variables are f16 or bf16, as PyTorch AMP.
+///
+/// The optimizer updates f32 master copies of the half precision variables, which are written
+/// back after each step, and the loss is multiplied by a dynamic scale so that small gradients do
+/// not underflow. Steps whose gradients overflow are skipped and the scale is reduced.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MixedPrecisionConfig {
+    #[serde(default = "default_init_scale")]
+    pub init_scale: f64,
+    /// Factor applied to the scale after `growth_interval` steps without overflow.
+    #[serde(default = "default_growth_factor")]
+    pub growth_factor: f64,
+    /// Factor applied to the scale when the gradients overflow.
+    #[serde(default = "default_backoff_factor")]
+    pub backoff_factor: f64,
+    #[serde(default = "default_growth_interval")]
+    pub growth_interval: usize,
+}
+
+impl Default for MixedPrecisionConfig {
+    fn default() -> Self {
+        Self {
+            init_scale: default_init_scale(),
+            growth_factor: default_growth_factor(),
+            backoff_factor: default_backoff_factor(),
+            growth_interval: default_growth_interval(),
+        }
+    }
+}
+
+/// Dynamic loss scale, see [`MixedPrecisionConfig`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LossScaler {
+    scale: f64,
+    good_steps: usize,
+    config: MixedPrecisionConfig,
+}
+
+impl LossScaler {
+    pub fn new(config: MixedPrecisionConfig) -> Self {
+        Self {
+            scale: config.init_scale,
+            good_steps: 0,
+            config,
+        }
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Update the scale after a step, `found_inf` telling whether the gradients overflowed.
+    pub fn update(&mut self, found_inf: bool) {
+        if found_inf {
+            self.scale *= self.config.backoff_factor;
+            self.good_steps = 0;
+        } else {
+            self.good_steps += 1;
+            if self.good_steps == self.config.growth_interval {
+                self.scale *= self.config.growth_factor;
+                self.good_steps = 0;
+            }
+        }
+    }
+}
+
+/// Optimization settings of a LoRA training run, e.g. read from the `training` section of a JSON
+/// configuration.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TrainingConfig {
+    /// Peak learning rate, reached at the end of the warmup.
+    #[serde(default = "default_learning_rate")]
+    pub learning_rate: f64,
+    #[serde(default)]
+    pub lr_scheduler: LrScheduler,
+    #[serde(default)]
+    pub warmup_steps: usize,
+    /// Number of optimizer steps of the run, required by the decaying schedules.
+    #[serde(default)]
+    pub total_steps: usize,
+    /// Clip the global norm of the gradients to this value.
+    #[serde(default)]
+    pub max_grad_norm: Option<f64>,
+    /// Train half precision variables with f32 master copies and loss scaling.
+    #[serde(default)]
+    pub mixed_precision: Option<MixedPrecisionConfig>,
+    /// Coefficient of the [`delta_l2_penalty`] added by [`LoraTrainer::regularize`], keeping the
+    /// adapted weights close to the base model.
+    #[serde(default)]
+    pub delta_l2: Option<f64>,
+    /// Gradually unfreeze the layer groups given to [`LoraTrainer::with_layer_groups`].
+    #[serde(default)]
+    pub unfreeze: Option<UnfreezeSchedule>,
+    /// Recompute the activations of the model blocks during backpropagation rather than keeping
+    /// them, see [`LoraTrainer::checkpointed_step`].
+    #[serde(default)]
+    pub gradient_checkpointing: Option<GradientCheckpointing>,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self {
+            learning_rate: default_learning_rate(),
+            lr_scheduler: LrScheduler::default(),
+            warmup_steps: 0,
+            total_steps: 0,
+            max_grad_norm: None,
+            mixed_precision: None,
+            delta_l2: None,
+            unfreeze: None,
+            gradient_checkpointing: None,
+        }
+    }
+}
+
+impl TrainingConfig {
+    /// Learning rate of the optimizer step `step`, counted from 0.
+    pub fn learning_rate_at(&self, step: usize) -> f64 {
+        if step < self.warmup_steps {
+            return self.learning_rate * step as f64 / self.warmup_steps as f64;
+        }
+        let decay_steps = self.total_steps.saturating_sub(self.warmup_steps).max(1);
+        let progress = ((step - self.warmup_steps) as f64 / decay_steps as f64).min(1.);
+        let factor = match self.lr_scheduler {
+            LrScheduler::Constant => 1.,
+            LrScheduler::Linear => 1. - progress,
+            LrScheduler::Cosine => 0.5 * (1. + (PI * progress).cos()),
+        };
+        self.learning_rate * factor
+    }
+}
+
+/// Global L2 norm of the gradients of `vars`, which is not finite when any gradient overflowed.
+pub fn grad_norm(grads: &GradStore, vars: &[Var]) -> Result<f64> {
+    let mut squared_sum = 0f64;
+    for var in vars {
+        if let Some(grad) = grads.get(var.as_tensor()) {
+            squared_sum += grad
+                .to_dtype(DType::F32)?
+                .sqr()?
+                .sum_all()?
+                .to_scalar::<f32>()? as f64;
+        }
+    }
+    Ok(squared_sum.sqrt())
+}
+
+/// Scale the gradients of `vars` so that their global L2 norm is at most `max_norm`, as
+/// `torch.nn.utils.clip_grad_norm_`. Returns the norm before clipping.
+pub fn clip_grad_norm(grads: &mut GradStore, vars: &[Var], max_norm: f64) -> Result<f64> {
+    let total_norm = grad_norm(grads, vars)?;
+    let clip_coef = max_norm / (total_norm + 1e-6);
+    if clip_coef < 1. {
+        for var in vars {
+            if let Some(grad) = grads.remove(var.as_tensor()) {
+                grads.insert(var.as_tensor(), (grad * clip_coef)?);
+            }
+        }
+    }
+    Ok(total_norm)
+}
+
+/// Sum of the squared Frobenius norms of the weight deltas `scale * B·A` of `layers`, computed
+/// layer by layer in f32 and differentiable with respect to the adapters.
+pub fn delta_l2_penalty(layers: &[&dyn Merge]) -> Result<Tensor> {
+    let mut penalty: Option<Tensor> = None;
+    for layer in layers {
+        let delta = layer
+            .get_delta_weight()
+            .map_err(|e| e.either(|e| candle_core::Error::Msg(e.to_string()), |e| e))?;
+        let norm = delta.to_dtype(DType::F32)?.sqr()?.sum_all()?;
+        penalty = Some(match penalty {
+            Some(penalty) => (penalty + norm)?,
+            None => norm,
+        });
+    }
+    match penalty {
+        Some(penalty) => Ok(penalty),
+        None => candle_core::bail!("no layer to regularize"),
+    }
+}
+
+/// Statistics of an optimizer step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepInfo {
+    /// Index of the step, counted from 0.
+    pub step: usize,
+    /// Learning rate used for the step.
+    pub learning_rate: f64,
+    /// Global gradient norm before clipping, when clipping is enabled.
+    pub grad_norm: Option<f64>,
+    /// Loss scale the gradients were computed with, in mixed precision.
+    pub loss_scale: Option<f64>,
+    /// Whether the update was skipped because the gradients overflowed.
+    pub skipped: bool,
+    /// The memory of the step, with [`LoraTrainer::with_memory_profiling`].
+    pub memory: Option<MemoryStats>,
+}
+
+/// Takes optimizer steps on the LoRA variables following a [`TrainingConfig`].
+///
+/// `vars` should only hold the LoRA variables, e.g. those of the `VarMap` the LoRA layers were
+/// created from, while the base weights are loaded as constants.
+pub struct LoraTrainer<O: Optimizer> {
+    optimizer: O,
+    vars: Vec<Var>,
+    /// The variables updated by the optimizer: f32 master copies of the half precision `vars`
+    /// in mixed precision, `vars` otherwise.
+    masters: Vec<Var>,
+    loss_scaler: Option<LossScaler>,
+    communicator: Option<Box<dyn Communicator>>,
+    /// Variables whose gradients are dropped before the optimizer step.
+    frozen: HashSet<TensorId>,
+    layer_groups: Vec<Vec<Var>>,
+    /// The bytes of the base weights, when the memory is profiled.
+    base_bytes: Option<usize>,
+    peak_memory: Option<MemoryStats>,
+    config: TrainingConfig,
+    step: usize,
+}
+
+impl<O: Optimizer> LoraTrainer<O> {
+    /// Create the optimizer of `vars`. Its learning rate is overwritten by the schedule of
+    /// `config` at each step.
+    pub fn new(
+        vars: Vec<Var>,
+        optimizer_config: O::Config,
+        config: TrainingConfig,
+    ) -> Result<Self> {
+        let masters = match config.mixed_precision {
+            Some(_) => vars
+                .iter()
+                .map(|var| match var.dtype() {
+                    DType::F16 | DType::BF16 => Var::from_tensor(&var.to_dtype(DType::F32)?),
+                    _ => Ok(var.clone()),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vars.clone(),
+        };
+        let mut optimizer = O::new(masters.clone(), optimizer_config)?;
+        optimizer.set_learning_rate(config.learning_rate_at(0));
+        Ok(Self {
+            optimizer,
+            vars,
+            masters,
+            loss_scaler: config.mixed_precision.clone().map(LossScaler::new),
+            communicator: None,
+            frozen: HashSet::new(),
+            layer_groups: Vec::new(),
+            base_bytes: None,
+            peak_memory: None,
+            config,
+            step: 0,
+        })
+    }
+
+    /// Train data-parallel: the gradients are averaged over the processes of `communicator`
+    /// before each step. Every process should start from the same adapter weights.
+    pub fn with_communicator(mut self, communicator: Box<dyn Communicator>) -> Self {
+        self.communicator = Some(communicator);
+        self
+    }
+
+    pub fn communicator(&self) -> Option<&dyn Communicator> {
+        self.communicator.as_deref()
+    }
+
+    /// The variables of each model layer, from the first layer to the last, e.g. from
+    /// [`crate::layer_groups`]. The layers are unfrozen following `config.unfreeze`.
+    pub fn with_layer_groups(mut self, layer_groups: Vec<Vec<Var>>) -> Self {
+        self.layer_groups = layer_groups;
+        self.apply_unfreeze_schedule();
+        self
+    }
+
+    pub fn layer_groups(&self) -> &[Vec<Var>] {
+        &self.layer_groups
+    }
+
+    /// Freeze or unfreeze `vars`: the gradients of frozen variables are ignored by the
+    /// optimizer. The unfreezing schedule, when set, overrides this for the layer groups at each
+    /// step.
+    pub fn set_trainable(&mut self, vars: &[Var], trainable: bool) {
+        for var in vars {
+            if trainable {
+                self.frozen.remove(&var.as_tensor().id());
+            } else {
+                self.frozen.insert(var.as_tensor().id());
+            }
+        }
+    }
+
+    /// Freeze or unfreeze the variables of the layer group `layer`.
+    pub fn set_layer_trainable(&mut self, layer: usize, trainable: bool) -> Result<()> {
+        let Some(group) = self.layer_groups.get(layer).cloned() else {
+            candle_core::bail!(
+                "layer {layer} out of range, got {} layer groups",
+                self.layer_groups.len()
+            )
+        };
+        self.set_trainable(&group, trainable);
+        Ok(())
+    }
+
+    pub fn is_trainable(&self, var: &Var) -> bool {
+        !self.frozen.contains(&var.as_tensor().id())
+    }
+
+    fn apply_unfreeze_schedule(&mut self) {
+        let Some(schedule) = &self.config.unfreeze else {
+            return;
+        };
+        let num_layers = self.layer_groups.len();
+        for (layer, group) in self.layer_groups.iter().enumerate() {
+            let trainable = schedule.is_trainable(layer, self.step, num_layers);
+            for var in group {
+                if trainable {
+                    self.frozen.remove(&var.as_tensor().id());
+                } else {
+                    self.frozen.insert(var.as_tensor().id());
+                }
+            }
+        }
+    }
+
+    /// Report the [`MemoryStats`] of each step in its [`StepInfo`], `base_bytes` being the
+    /// memory of the frozen base weights, e.g. the [`tensors_bytes`] of their `VarMap` or the
+    /// size of their safetensors files.
+    pub fn with_memory_profiling(mut self, base_bytes: usize) -> Self {
+        self.base_bytes = Some(base_bytes);
+        self
+    }
+
+    /// The memory of the step with the most memory so far, with
+    /// [`LoraTrainer::with_memory_profiling`].
+    pub fn peak_memory(&self) -> Option<MemoryStats> {
+        self.peak_memory
+    }
+
+    /// The memory of a step whose activations take `activation_bytes`, recorded in the peak.
+    fn profile_memory(&mut self, activation_bytes: usize) -> Option<MemoryStats> {
+        let base_bytes = self.base_bytes?;
+        let masters = self
+            .masters
+            .iter()
+            .zip(self.vars.iter())
+            .filter(|(master, var)| master.as_tensor().id() != var.as_tensor().id())
+            .map(|(master, _)| master.as_tensor());
+        let stats = MemoryStats {
+            adapter_bytes: tensors_bytes(self.vars.iter().map(Var::as_tensor).chain(masters)),
+            base_bytes,
+            activation_bytes,
+        };
+        if self
+            .peak_memory
+            .is_none_or(|peak| stats.total_bytes() > peak.total_bytes())
+        {
+            self.peak_memory = Some(stats);
+        }
+        Some(stats)
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    pub fn config(&self) -> &TrainingConfig {
+        &self.config
+    }
+
+    pub fn vars(&self) -> &[Var] {
+        &self.vars
+    }
+
+    /// Number of steps taken so far, skipped steps excluded.
+    pub fn steps(&self) -> usize {
+        self.step
+    }
+
+    pub fn loss_scaler(&self) -> Option<&LossScaler> {
+        self.loss_scaler.as_ref()
+    }
+
+    /// Add the [`delta_l2_penalty`] of `layers` weighted by `config.delta_l2` to `loss`, or return
+    /// `loss` unchanged when `delta_l2` is not set.
+    pub fn regularize(&self, loss: &Tensor, layers: &[&dyn Merge]) -> Result<Tensor> {
+        match self.config.delta_l2 {
+            Some(coef) if coef > 0. => {
+                let penalty = (delta_l2_penalty(layers)? * coef)?;
+                loss.to_dtype(DType::F32)? + penalty
+            }
+            _ => Ok(loss.clone()),
+        }
+    }
+
+    /// Backpropagate `loss` and take an optimizer step with clipped gradients and the scheduled
+    /// learning rate. In mixed precision the loss is scaled before backpropagation.
+    pub fn backward_step(&mut self, loss: &Tensor) -> Result<StepInfo> {
+        let activation_bytes = match self.base_bytes {
+            Some(_) => activation_bytes(loss),
+            None => 0,
+        };
+        let grads = match &self.loss_scaler {
+            Some(scaler) => (loss.to_dtype(DType::F32)? * scaler.scale())?.backward()?,
+            None => loss.backward()?,
+        };
+        self.step(grads, activation_bytes)
+    }
+
+    /// Run `input` through the model `blocks`, e.g. its LoRA transformer layers, and `loss_fn`,
+    /// then take an optimizer step as [`LoraTrainer::backward_step`]. With
+    /// `config.gradient_checkpointing`, the activations of the blocks are recomputed during
+    /// backpropagation, see [`checkpointed_backward`].
+    ///
+    /// Returns the loss with the information of the step.
+    pub fn checkpointed_step<L>(
+        &mut self,
+        input: &Tensor,
+        blocks: &mut [BlockFn],
+        loss_fn: L,
+    ) -> Result<(Tensor, StepInfo)>
+    where
+        L: FnOnce(&Tensor) -> Result<Tensor>,
+    {
+        let Some(checkpointing) = &self.config.gradient_checkpointing else {
+            let mut hidden = input.clone();
+            for block in blocks.iter_mut() {
+                hidden = block(&hidden)?;
+            }
+            let loss = loss_fn(&hidden)?;
+            let info = self.backward_step(&loss)?;
+            return Ok((loss, info));
+        };
+        let loss_scale = self.loss_scaler.as_ref().map(LossScaler::scale);
+        let mut unscaled_loss = None;
+        let output = checkpointed_backward(input, blocks, checkpointing, &self.vars, |hidden| {
+            let loss = loss_fn(hidden)?;
+            unscaled_loss = Some(loss.detach());
+            match loss_scale {
+                Some(scale) => loss.to_dtype(DType::F32)? * scale,
+                None => Ok(loss),
+            }
+        })?;
+        let loss = unscaled_loss.unwrap_or(output.loss);
+        let info = self.step(output.grads, output.activation_bytes)?;
+        Ok((loss, info))
+    }
+
+    /// Take an optimizer step with `grads`, clipped and with the scheduled learning rate. In
+    /// mixed precision, `grads` are those of the loss multiplied by the current loss scale.
+    pub fn step_with_grads(&mut self, grads: GradStore) -> Result<StepInfo> {
+        self.step(grads, 0)
+    }
+
+    fn step(&mut self, mut grads: GradStore, activation_bytes: usize) -> Result<StepInfo> {
+        if let Some(communicator) = &self.communicator {
+            all_reduce_grads(&mut grads, &self.vars, communicator.as_ref())?;
+        }
+        self.apply_unfreeze_schedule();
+        for var in &self.vars {
+            if self.frozen.contains(&var.as_tensor().id()) {
+                grads.remove(var.as_tensor());
+            }
+        }
+        let learning_rate = self.config.learning_rate_at(self.step);
+        let loss_scale = self.loss_scaler.as_ref().map(LossScaler::scale);
+        let memory = self.profile_memory(activation_bytes);
+        if let Some(scale) = loss_scale {
+            // Move the unscaled f32 gradients to the master variables.
+            for (var, master) in self.vars.iter().zip(self.masters.iter()) {
+                if let Some(grad) = grads.remove(var.as_tensor()) {
+                    let grad = (grad.to_dtype(DType::F32)? / scale)?;
+                    grads.insert(master.as_tensor(), grad);
+                }
+            }
+            let found_inf = !grad_norm(&grads, &self.masters)?.is_finite();
+            if let Some(scaler) = self.loss_scaler.as_mut() {
+                scaler.update(found_inf);
+            }
+            if found_inf {
+                return Ok(StepInfo {
+                    step: self.step,
+                    learning_rate,
+                    grad_norm: None,
+                    loss_scale,
+                    skipped: true,
+                    memory,
+                });
+            }
+        }
+        let grad_norm = match self.config.max_grad_norm {
+            Some(max_norm) => Some(clip_grad_norm(&mut grads, &self.masters, max_norm)?),
+            None => None,
+        };
+        self.optimizer.set_learning_rate(learning_rate);
+        self.optimizer.step(&grads)?;
+        for (var, master) in self.vars.iter().zip(self.masters.iter()) {
+            if var.as_tensor().id() != master.as_tensor().id() {
+                var.set(&master.to_dtype(var.dtype())?)?;
+            }
+        }
+        let info = StepInfo {
+            step: self.step,
+            learning_rate,
+            grad_norm,
+            loss_scale,
+            skipped: false,
+            memory,
+        };
+        self.step += 1;
+        Ok(info)
+    }
+}
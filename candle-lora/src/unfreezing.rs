@@ -0,0 +1,79 @@
+//! Gradual unfreezing: the adapters of the model layers are made trainable one after the other
+//! over the course of a run, e.g. starting from the layers closest to the output.
+
+use std::collections::BTreeMap;
+
+use candle_core::Var;
+use candle_nn::VarMap;
+use serde::Deserialize;
+
+/// Which end of the model is unfrozen first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnfreezeOrder {
+    /// From the last layer, closest to the output, down to the first.
+    #[default]
+    TopDown,
+    /// From the first layer up to the last.
+    BottomUp,
+}
+
+fn default_initial_layers() -> usize {
+    1
+}
+
+/// Unfreezes `initial_layers` layers at the start of the run, then one more layer every
+/// `interval` steps.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct UnfreezeSchedule {
+    #[serde(default)]
+    pub order: UnfreezeOrder,
+    #[serde(default = "default_initial_layers")]
+    pub initial_layers: usize,
+    /// Steps between two unfrozen layers, all the layers are trainable from the start when 0.
+    pub interval: usize,
+}
+
+impl UnfreezeSchedule {
+    /// Number of trainable layers at `step`, counted from 0.
+    pub fn num_trainable(&self, step: usize, num_layers: usize) -> usize {
+        match step.checked_div(self.interval) {
+            Some(unfrozen) => (self.initial_layers + unfrozen).min(num_layers),
+            None => num_layers,
+        }
+    }
+
+    /// Whether the layer `layer` of `num_layers`, counted from the input, is trainable at `step`.
+    pub fn is_trainable(&self, layer: usize, step: usize, num_layers: usize) -> bool {
+        let num_trainable = self.num_trainable(step, num_layers);
+        match self.order {
+            UnfreezeOrder::TopDown => layer + num_trainable >= num_layers,
+            UnfreezeOrder::BottomUp => layer < num_trainable,
+        }
+    }
+}
+
+/// Index of the model layer of a variable, the first number of its dotted name, e.g. 3 for
+/// `model.layers.3.self_attn.q_proj.a0.weight`.
+fn layer_index(name: &str) -> Option<usize> {
+    name.split('.').find_map(|part| part.parse().ok())
+}
+
+/// The variables of `varmap` grouped by model layer, from the first layer to the last. Variables
+/// whose name has no layer index are left out.
+pub fn layer_groups(varmap: &VarMap) -> Vec<Vec<Var>> {
+    let data = varmap.data().lock().unwrap();
+    let mut groups: BTreeMap<usize, Vec<(&String, &Var)>> = BTreeMap::new();
+    for (name, var) in data.iter() {
+        if let Some(layer) = layer_index(name) {
+            groups.entry(layer).or_default().push((name, var));
+        }
+    }
+    groups
+        .into_values()
+        .map(|mut group| {
+            group.sort_by_key(|(name, _)| *name);
+            group.into_iter().map(|(_, var)| var.clone()).collect()
+        })
+        .collect()
+}
@@ -0,0 +1,264 @@
+//! Validation of a PEFT LoRA adapter against the base model it is meant for, so that a malformed
+//! adapter is reported by module before inference rather than as a shape error inside a matmul.
+
+use std::collections::{BTreeMap, HashMap};
+
+use candle_core::{bail, DType, Result, Tensor};
+use thiserror::Error;
+
+use crate::structural_order;
+
+/// The shapes and dtype of the layers of a base model an adapter is checked against.
+#[derive(Debug, Clone, Default)]
+pub struct BaseModelConfig {
+    /// `(out_features, in_features)` of the linear layers, `(num_embeddings, embedding_dim)` of
+    /// the embeddings, by path or by module name.
+    modules: HashMap<String, (usize, usize)>,
+    dtype: Option<DType>,
+}
+
+impl BaseModelConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the linear layer `name`, a path like `model.layers.0.self_attn.q_proj` or a module
+    /// name like `q_proj` for the layers of every block. The longest name matching the end of
+    /// an adapted module applies.
+    pub fn with_module(mut self, name: &str, out_features: usize, in_features: usize) -> Self {
+        self.modules
+            .insert(name.to_string(), (out_features, in_features));
+        self
+    }
+
+    /// Expect the adapter tensors to have the dtype of the base model.
+    pub fn with_dtype(mut self, dtype: DType) -> Self {
+        self.dtype = Some(dtype);
+        self
+    }
+
+    /// The layers of a Llama-style model (Llama, Mistral, Qwen2 and the like), by module name.
+    pub fn llama(
+        hidden_size: usize,
+        intermediate_size: usize,
+        num_attention_heads: usize,
+        num_key_value_heads: usize,
+        vocab_size: usize,
+    ) -> Self {
+        let kv_size = hidden_size / num_attention_heads * num_key_value_heads;
+        Self::new()
+            .with_module("q_proj", hidden_size, hidden_size)
+            .with_module("k_proj", kv_size, hidden_size)
+            .with_module("v_proj", kv_size, hidden_size)
+            .with_module("o_proj", hidden_size, hidden_size)
+            .with_module("gate_proj", intermediate_size, hidden_size)
+            .with_module("up_proj", intermediate_size, hidden_size)
+            .with_module("down_proj", hidden_size, intermediate_size)
+            .with_module("lm_head", vocab_size, hidden_size)
+            .with_module("embed_tokens", vocab_size, hidden_size)
+    }
+
+    /// The linear layers of the base model `weights`, the 2D `<path>.weight` tensors, with
+    /// their dtype if they all have the same.
+    pub fn from_weights(weights: &HashMap<String, Tensor>) -> Self {
+        let mut config = Self::new();
+        let mut dtypes = weights.values().map(|weight| weight.dtype());
+        let dtype = dtypes.next();
+        config.dtype = dtype.filter(|&dtype| dtypes.all(|other| other == dtype));
+        for (name, weight) in weights {
+            if let (Some(path), Ok((out_features, in_features))) =
+                (name.strip_suffix(".weight"), weight.dims2())
+            {
+                config
+                    .modules
+                    .insert(path.to_string(), (out_features, in_features));
+            }
+        }
+        config
+    }
+
+    /// The `(out_features, in_features)` of the adapted `module`, from the longest of the names
+    /// of the config matching the end of its path.
+    fn module_shape(&self, module: &str) -> Option<(usize, usize)> {
+        let mut path = module;
+        loop {
+            if let Some(&shape) = self.modules.get(path) {
+                return Some(shape);
+            }
+            path = path.split_once('.')?.1;
+        }
+    }
+}
+
+/// A problem of an adapted module found by [`validate_adapter`].
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ValidationIssue {
+    #[error("{module}: lora_A has no lora_B")]
+    MissingB { module: String },
+    #[error("{module}: lora_B has no lora_A")]
+    MissingA { module: String },
+    #[error("{module}: lora_A of rank {a_rank} and lora_B of rank {b_rank}")]
+    RankMismatch {
+        module: String,
+        a_rank: usize,
+        b_rank: usize,
+    },
+    #[error("{module}: lora_A and lora_B are transposed, lora_A should be (rank, in_features)")]
+    Transposed { module: String },
+    #[error("{module}: the adapter is (out, in) = {found:?}, the base layer {expected:?}")]
+    ShapeMismatch {
+        module: String,
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+    #[error("{module}: the adapter is {found:?}, expected {expected:?}")]
+    DtypeMismatch {
+        module: String,
+        expected: DType,
+        found: DType,
+    },
+    /// Only reported when the config lists layers.
+    #[error("{module}: the base model has no such layer")]
+    UnknownModule { module: String },
+}
+
+/// The result of [`validate_adapter`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationReport {
+    /// The number of adapted modules, with an A or a B matrix.
+    pub num_modules: usize,
+    /// The problems found, in the structural order of the modules.
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// An error listing the issues, if any.
+    pub fn into_result(self) -> Result<()> {
+        if self.is_valid() {
+            return Ok(());
+        }
+        let issues = self.issues.iter().map(|issue| issue.to_string());
+        bail!("invalid adapter: {}", issues.collect::<Vec<_>>().join("; "))
+    }
+}
+
+/// Check the PEFT LoRA `adapter_tensors` (`<module>.lora_A.weight`, `<module>.lora_B.weight`)
+/// against the base model described by `base_model_config`: each A must have its B, of the
+/// same rank, and be `(rank, in_features)` with B `(out_features, rank)` for the shape of the
+/// base layer, with the dtype of the base model and of each other. Embedding adapters
+/// (`<module>.lora_embedding_A`, `<module>.lora_embedding_B`) are `(rank, num_embeddings)` and
+/// `(embedding_dim, rank)` for a `(num_embeddings, embedding_dim)` embedding.
+pub fn validate_adapter(
+    base_model_config: &BaseModelConfig,
+    adapter_tensors: &HashMap<String, Tensor>,
+) -> ValidationReport {
+    let mut modules = BTreeMap::<&str, (Option<&Tensor>, Option<&Tensor>)>::new();
+    let mut embeddings = Vec::new();
+    for (name, tensor) in adapter_tensors {
+        let name = name.strip_suffix(".weight").unwrap_or(name);
+        if let Some(module) = name.strip_suffix(".lora_A") {
+            modules.entry(module).or_default().0 = Some(tensor);
+        } else if let Some(module) = name.strip_suffix(".lora_B") {
+            modules.entry(module).or_default().1 = Some(tensor);
+        } else if let Some(module) = name.strip_suffix(".lora_embedding_A") {
+            modules.entry(module).or_default().0 = Some(tensor);
+            embeddings.push(module);
+        } else if let Some(module) = name.strip_suffix(".lora_embedding_B") {
+            modules.entry(module).or_default().1 = Some(tensor);
+        }
+    }
+    let mut modules = modules.into_iter().collect::<Vec<_>>();
+    modules.sort_by(|a, b| structural_order(a.0, b.0));
+
+    let mut issues = Vec::new();
+    for &(module, pair) in &modules {
+        let (a, b) = match pair {
+            (Some(a), Some(b)) => (a, b),
+            (Some(_), None) => {
+                let module = module.to_string();
+                issues.push(ValidationIssue::MissingB { module });
+                continue;
+            }
+            _ => {
+                let module = module.to_string();
+                issues.push(ValidationIssue::MissingA { module });
+                continue;
+            }
+        };
+        let embedding = embeddings.contains(&module);
+        issues.extend(check_pair(base_model_config, module, a, b, embedding));
+    }
+    ValidationReport {
+        num_modules: modules.len(),
+        issues,
+    }
+}
+
+/// The issues of the `a` and `b` matrices of `module`, an embedding if `embedding`.
+fn check_pair(
+    base: &BaseModelConfig,
+    module: &str,
+    a: &Tensor,
+    b: &Tensor,
+    embedding: bool,
+) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let expected = base.dtype.unwrap_or(a.dtype());
+    for found in [a.dtype(), b.dtype()] {
+        if found != expected {
+            let module = module.to_string();
+            issues.push(ValidationIssue::DtypeMismatch {
+                module,
+                expected,
+                found,
+            });
+            break;
+        }
+    }
+
+    // Conv adapters have more dims, only their ranks are checked.
+    let (a_dims, b_dims) = (a.dims(), b.dims());
+    if a_dims.len() < 2 || b_dims.len() < 2 {
+        return issues;
+    }
+    let (a0, a1, b0, b1) = (a_dims[0], a_dims[1], b_dims[0], b_dims[1]);
+    let found = if a0 == b1 {
+        (b0, a1)
+    } else if a1 == b0 && a1 < a0 && b0 < b1 && a_dims.len() == 2 && b_dims.len() == 2 {
+        // The shared dimension is the rank, smaller than the features.
+        issues.push(ValidationIssue::Transposed {
+            module: module.to_string(),
+        });
+        (b1, a0)
+    } else {
+        issues.push(ValidationIssue::RankMismatch {
+            module: module.to_string(),
+            a_rank: a0,
+            b_rank: b1,
+        });
+        return issues;
+    };
+    if a_dims.len() != 2 || b_dims.len() != 2 {
+        return issues;
+    }
+    let found = if embedding { (found.1, found.0) } else { found };
+    match base.module_shape(module) {
+        Some(expected) if expected != found => {
+            let module = module.to_string();
+            issues.push(ValidationIssue::ShapeMismatch {
+                module,
+                expected,
+                found,
+            });
+        }
+        None if !base.modules.is_empty() => issues.push(ValidationIssue::UnknownModule {
+            module: module.to_string(),
+        }),
+        _ => {}
+    }
+    issues
+}
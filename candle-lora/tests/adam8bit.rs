@@ -0,0 +1,68 @@
+use candle_core::{DType, Device, Result, Tensor, Var};
+use candle_lora::{Adam8bit, ParamsAdam8bit};
+use candle_nn::{AdamW, Optimizer, ParamsAdamW};
+
+/// Minimize `|x - target|^2` and return the final `x`.
+fn fit<O: Optimizer>(config: O::Config, target: &Tensor, steps: usize) -> Result<(Tensor, O)> {
+    let x = Var::zeros(target.shape(), DType::F32, target.device())?;
+    let mut optimizer = O::new(vec![x.clone()], config)?;
+    for _ in 0..steps {
+        let loss = (x.as_tensor() - target)?.sqr()?.sum_all()?;
+        optimizer.backward_step(&loss)?;
+    }
+    Ok((x.as_tensor().clone(), optimizer))
+}
+
+#[test]
+fn adam8bit_tracks_adamw() -> Result<()> {
+    let device = Device::Cpu;
+    let target = Tensor::randn(0f32, 1., (64, 128), &device)?;
+    let (lr, weight_decay) = (0.05, 0.);
+    let (x_8bit, optimizer) = fit::<Adam8bit>(
+        ParamsAdam8bit {
+            lr,
+            weight_decay,
+            ..Default::default()
+        },
+        &target,
+        50,
+    )?;
+    let (x_32bit, _) = fit::<AdamW>(
+        ParamsAdamW {
+            lr,
+            weight_decay,
+            ..Default::default()
+        },
+        &target,
+        50,
+    )?;
+
+    let error = |x: &Tensor| -> Result<f32> { (x - &target)?.sqr()?.mean_all()?.to_scalar() };
+    let initial_error = error(&target.zeros_like()?)?;
+    assert!(error(&x_8bit)? < 0.1 * initial_error);
+    let gap: f32 = (&x_8bit - &x_32bit)?.abs()?.mean_all()?.to_scalar()?;
+    assert!(gap < 0.05, "8-bit and 32-bit AdamW diverged by {gap}");
+
+    // Two bytes per element and one f32 scale per block for each moment, a quarter of AdamW.
+    assert_eq!(optimizer.state_bytes(), 2 * (64 * 128 + 32 * 4));
+    Ok(())
+}
+
+#[test]
+fn adam8bit_keeps_small_vars_in_f32() -> Result<()> {
+    let device = Device::Cpu;
+    let target = Tensor::new(&[1f32, -2., 3.], &device)?;
+    let (x, optimizer) = fit::<Adam8bit>(
+        ParamsAdam8bit {
+            lr: 0.1,
+            weight_decay: 0.,
+            ..Default::default()
+        },
+        &target,
+        200,
+    )?;
+    assert_eq!(optimizer.state_bytes(), 2 * 3 * 4);
+    let gap: f32 = (x - &target)?.abs()?.max_all()?.to_scalar()?;
+    assert!(gap < 0.05);
+    Ok(())
+}
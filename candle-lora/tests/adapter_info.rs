@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{convert_peft_to_candle_lora, AdapterFormat, AdapterInfo};
+
+#[test]
+fn peft_and_converted_adapters() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_info_peft.safetensors");
+    let out_path = dir.join("candle_lora_info_out.safetensors");
+    let mut tensors = HashMap::new();
+    for (layer, rank) in [(10, 8), (2, 4)] {
+        let module = format!("base_model.model.model.layers.{layer}.self_attn.q_proj");
+        tensors.insert(
+            format!("{module}.lora_A.weight"),
+            Tensor::zeros((rank, 32), DType::BF16, &device)?,
+        );
+        tensors.insert(
+            format!("{module}.lora_B.weight"),
+            Tensor::zeros((16, rank), DType::BF16, &device)?,
+        );
+    }
+    tensors.insert(
+        "base_model.model.model.layers.2.self_attn.q_proj.lora_magnitude_vector".to_string(),
+        Tensor::ones(16, DType::BF16, &device)?,
+    );
+    candle_core::safetensors::save(&tensors, &peft_path)?;
+
+    let info = AdapterInfo::from_file(&peft_path)?;
+    assert_eq!(info.format, AdapterFormat::Peft);
+    assert_eq!(info.num_tensors, 5);
+    let num_params = 4 * 48 + 16 + 8 * 48;
+    assert_eq!(info.num_params, num_params);
+    assert_eq!(info.memory_bytes, 2 * num_params);
+    let layer = &info.layers[0];
+    assert_eq!(
+        layer.name,
+        "base_model.model.model.layers.2.self_attn.q_proj"
+    );
+    assert_eq!((layer.index, layer.rank), (None, 4));
+    assert_eq!(
+        (layer.a_shape.as_slice(), layer.b_shape.as_slice()),
+        (&[4, 32][..], &[16, 4][..])
+    );
+    assert_eq!((layer.dtype, layer.num_params), (DType::BF16, 4 * 48 + 16));
+    assert_eq!(info.layers[1].rank, 8);
+    assert_eq!(
+        info.ranks().into_iter().collect::<Vec<_>>(),
+        [(4, 1), (8, 1)]
+    );
+
+    convert_peft_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+    let converted = AdapterInfo::from_file(&out_path)?;
+    assert_eq!(converted.format, AdapterFormat::CandleLora);
+    assert_eq!(converted.num_params, num_params);
+    let layers = converted
+        .layers
+        .iter()
+        .map(|layer| {
+            (
+                layer.name.as_str(),
+                layer.index,
+                layer.rank,
+                layer.num_params,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        layers,
+        [
+            ("lora_llama", Some(0), 4, 4 * 48 + 16),
+            ("lora_llama", Some(1), 8, 8 * 48)
+        ]
+    );
+
+    // Files of other tensors hold no adapter.
+    let other = HashMap::from([(
+        "embed.weight".to_string(),
+        Tensor::zeros(4, DType::F32, &device)?,
+    )]);
+    candle_core::safetensors::save(&other, &out_path)?;
+    let info = AdapterInfo::from_file(&out_path)?;
+    assert_eq!(info.format, AdapterFormat::Unknown);
+    assert!(info.layers.is_empty());
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
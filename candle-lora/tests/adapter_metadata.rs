@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    convert_peft_dir_to_candle_lora, load_with_metadata, migrate_adapter_file, save_with_metadata,
+    AdapterMetadata, Migration, MODULES_METADATA_KEY,
+};
+
+const MODULES: [&str; 2] = [
+    "base_model.model.model.layers.0.self_attn.q_proj",
+    "base_model.model.model.layers.0.self_attn.v_proj",
+];
+
+fn peft_dir(name: &str) -> Result<std::path::PathBuf> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let mut tensors = HashMap::new();
+    for module in MODULES {
+        tensors.insert(
+            format!("{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (4, 8), &device)?,
+        );
+        tensors.insert(
+            format!("{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (8, 4), &device)?,
+        );
+    }
+    candle_core::safetensors::save(&tensors, dir.join("adapter_model.safetensors"))?;
+    std::fs::write(
+        dir.join("adapter_config.json"),
+        r#"{"peft_type": "LORA", "r": 4, "lora_alpha": 16, "lora_dropout": 0.1,
+            "target_modules": ["q_proj", "v_proj"]}"#,
+    )?;
+    Ok(dir)
+}
+
+#[test]
+fn converted_adapters_describe_themselves() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = peft_dir("candle_lora_metadata_converted")?;
+    let output = dir.join("converted.safetensors");
+    convert_peft_dir_to_candle_lora(
+        dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "lora_llama",
+        &device,
+    )?;
+
+    let (tensors, metadata) = load_with_metadata(&output, &device)?;
+    assert_eq!(tensors.len(), 4);
+    let metadata = metadata.expect("the converted adapter has metadata");
+    assert_eq!(metadata.source_format, "peft");
+    assert_eq!((metadata.rank, metadata.alpha), (4, Some(16.)));
+    assert_eq!(metadata.dropout, Some(0.1));
+    assert_eq!(metadata.target_modules, MODULES);
+    assert_eq!(metadata.architecture.as_deref(), Some("prefix:lora_llama"));
+    assert_eq!(metadata.candle_lora_version, env!("CARGO_PKG_VERSION"));
+    assert!(metadata.lora_config().is_some());
+    assert_eq!(AdapterMetadata::read(&output)?, Some(metadata));
+
+    // The modules of the indices are recorded, so the adapter migrates without them.
+    let named = dir.join("named.safetensors");
+    migrate_adapter_file(
+        &output,
+        &named,
+        &Migration::ToNamed {
+            prefix: "lora_llama".to_string(),
+            modules: None,
+        },
+    )?;
+    let (tensors, metadata) = load_with_metadata(&named, &device)?;
+    assert!(tensors.contains_key(&format!("{}.a0.weight", MODULES[1])));
+    let metadata = metadata.expect("the migration keeps the metadata");
+    assert_eq!(metadata.architecture.as_deref(), Some("named"));
+    assert_eq!(metadata.rank, 4);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn metadata_roundtrips_and_stamps_keep_other_metadata() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join("candle_lora_metadata_stamp");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let tensors = HashMap::from([
+        (
+            "model.layers.0.self_attn.q_proj.a0.weight".to_string(),
+            Tensor::zeros((2, 8), DType::F32, &device)?,
+        ),
+        (
+            "model.layers.0.self_attn.q_proj.b0.weight".to_string(),
+            Tensor::zeros((8, 2), DType::F32, &device)?,
+        ),
+    ]);
+
+    let mut metadata = AdapterMetadata::new("candle-lora", 2).with_architecture("named");
+    metadata.alpha = Some(4.);
+    metadata.target_modules = vec!["q_proj".to_string()];
+    let path = dir.join("saved.safetensors");
+    save_with_metadata(&tensors, &metadata, &path)?;
+    let (loaded, read) = load_with_metadata(&path, &device)?;
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(read.as_ref(), Some(&metadata));
+
+    // Files without metadata load with none, and can be stamped afterwards.
+    let plain = dir.join("plain.safetensors");
+    candle_core::safetensors::save(&tensors, &plain)?;
+    assert_eq!(load_with_metadata(&plain, &device)?.1, None);
+    let indexed = dir.join("indexed.safetensors");
+    migrate_adapter_file(
+        &plain,
+        &indexed,
+        &Migration::ToIndexed {
+            prefix: "lora".to_string(),
+        },
+    )?;
+    metadata.architecture = Some("prefix:lora".to_string());
+    metadata.stamp(&indexed)?;
+    assert_eq!(AdapterMetadata::read(&indexed)?, Some(metadata));
+
+    let data = std::fs::read(&indexed)?;
+    let (_, header) = safetensors::SafeTensors::read_metadata(&data).unwrap();
+    let modules = &header.metadata().as_ref().unwrap()[&format!("{MODULES_METADATA_KEY}.lora")];
+    assert_eq!(modules, r#"["model.layers.0.self_attn.q_proj"]"#);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    AdapterSwap, Conv2dLayerLike, EmbeddingLayerLike, LinearLayerLike, Lora, LoraConfig,
+    LoraConv2dConfig, LoraEmbeddingConfig, LoraLinear, LoraLinearConfig, Merge,
+    SelectedLayersBuilder,
+};
+use candle_nn::{Conv2d, Conv2dConfig, Embedding, Linear, VarBuilder};
+
+mod common;
+use common::{adapter_vb, max_abs_diff, multi_layer_adapter};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Proj,
+    Conv,
+    Embed,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Proj => write!(f, "proj"),
+            Self::Conv => write!(f, "conv"),
+            Self::Embed => write!(f, "embed"),
+        }
+    }
+}
+
+#[test]
+fn swapped_model_matches_a_freshly_converted_one() -> Result<()> {
+    let device = Device::Cpu;
+    let proj = Linear::new(Tensor::randn(0f32, 1., (6, 4), &device)?, None);
+    let conv = Conv2d::new(
+        Tensor::randn(0f32, 1., (4, 3, 3, 3), &device)?,
+        None,
+        Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        },
+    );
+    let embed = Embedding::new(Tensor::randn(0f32, 1., (10, 4), &device)?, 4);
+    let convert = |vb: &VarBuilder| {
+        let selected = SelectedLayersBuilder::new()
+            .add_linear_layers(
+                HashMap::from([(ModelLayers::Proj, &proj as &dyn LinearLayerLike)]),
+                LoraLinearConfig::new(4, 6),
+            )
+            .add_conv2d_layers(
+                HashMap::from([(ModelLayers::Conv, &conv as &dyn Conv2dLayerLike)]),
+                LoraConv2dConfig::new(3, 4),
+            )
+            .add_embed_layers(
+                HashMap::from([(ModelLayers::Embed, &embed as &dyn EmbeddingLayerLike)]),
+                LoraEmbeddingConfig::new(10, 4),
+            )
+            .build();
+        Lora::convert_model(selected, LoraConfig::new(2, 4., None), &vb.pp("lora"))
+    };
+
+    let style = multi_layer_adapter(2, &device)?;
+    let domain = multi_layer_adapter(4, &device)?;
+    let config = LoraConfig::new(2, 4., None);
+    let mut model = convert(&style)?;
+    let expected = convert(&domain)?;
+
+    let xs = Tensor::randn(0f32, 1., (3, 4), &device)?;
+    let images = Tensor::randn(0f32, 1., (1, 3, 5, 5), &device)?;
+    let ids = Tensor::new(&[1u32, 7, 3], &device)?;
+    let check = |model: &candle_lora::NewLayers<ModelLayers>| -> Result<()> {
+        let proj = &model.linear[&ModelLayers::Proj];
+        let expected_proj = &expected.linear[&ModelLayers::Proj];
+        assert!(max_abs_diff(&proj.forward(&xs)?, &expected_proj.forward(&xs)?)? < 1e-4);
+        let conv = &model.conv2d[&ModelLayers::Conv];
+        let expected_conv = &expected.conv2d[&ModelLayers::Conv];
+        assert!(max_abs_diff(&conv.forward(&images)?, &expected_conv.forward(&images)?)? < 1e-3);
+        let embed = &model.embed[&ModelLayers::Embed];
+        let expected_embed = &expected.embed[&ModelLayers::Embed];
+        assert!(max_abs_diff(&embed.forward(&ids)?, &expected_embed.forward(&ids)?)? < 1e-4);
+        Ok(())
+    };
+
+    Lora::swap_adapter(&mut model, &style, &config).unwrap();
+    Lora::swap_adapter(&mut model, &domain, &config).unwrap();
+    check(&model)?;
+
+    // A swap failing on one layer leaves the whole model as it was.
+    let partial = adapter_vb(
+        [(
+            "lora.a0.weight",
+            Tensor::zeros((2, 4), DType::F32, &device)?,
+        )],
+        &device,
+    );
+    assert!(Lora::swap_adapter(&mut model, &partial, &config).is_err());
+    check(&model)?;
+
+    // Merged layers are merged again with the new adapter.
+    Lora::swap_adapter(&mut model, &style, &config).unwrap();
+    Lora::merge_all(&mut model).unwrap();
+    Lora::swap_adapter(&mut model, &domain, &config).unwrap();
+    check(&model)?;
+    Ok(())
+}
+
+#[test]
+fn swap_keeps_the_base_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (6, 4), &device)?, None);
+    let style = multi_layer_adapter(2, &device)?;
+    let mut layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(4, 6),
+        &LoraConfig::new(2, 4., None),
+        &style.pp("lora"),
+        0,
+    )?;
+    layer.merge_weights().unwrap();
+    for rank in [4, 8, 2] {
+        layer
+            .swap_adapter(
+                &multi_layer_adapter(rank, &device)?,
+                &LoraConfig::new(2, 4., None),
+            )
+            .unwrap();
+    }
+    layer.unmerge_weights().unwrap();
+    assert!(max_abs_diff(layer.weight(), base.weight())? < 1e-4);
+    Ok(())
+}
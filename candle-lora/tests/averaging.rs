@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{average_adapters, average_checkpoints, last_checkpoints};
+
+fn checkpoint(value: f32, device: &Device) -> Result<HashMap<String, Tensor>> {
+    let mut tensors = HashMap::new();
+    tensors.insert(
+        "lora_a.weight".to_string(),
+        Tensor::full(value, (2, 3), device)?,
+    );
+    tensors.insert(
+        "lora_b.weight".to_string(),
+        Tensor::full(value, (3, 2), device)?.to_dtype(DType::F16)?,
+    );
+    Ok(tensors)
+}
+
+#[test]
+fn weighted_average() -> Result<()> {
+    let device = Device::Cpu;
+    let checkpoints = [checkpoint(1., &device)?, checkpoint(4., &device)?];
+
+    let averaged = average_adapters(&checkpoints, None)?;
+    assert_eq!(
+        averaged["lora_a.weight"].to_vec2::<f32>()?,
+        [[2.5; 3], [2.5; 3]]
+    );
+    assert_eq!(averaged["lora_b.weight"].dtype(), DType::F16);
+
+    let averaged = average_adapters(&checkpoints, Some(&[1., 2.]))?;
+    assert_eq!(
+        averaged["lora_b.weight"]
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?,
+        [[3.; 2], [3.; 2], [3.; 2]]
+    );
+
+    assert!(average_adapters(&checkpoints, Some(&[1.])).is_err());
+    assert!(average_adapters(&checkpoints, Some(&[1., -1.])).is_err());
+    let mut missing = checkpoint(1., &device)?;
+    missing.remove("lora_b.weight");
+    assert!(average_adapters(&[checkpoint(1., &device)?, missing.clone()], None).is_err());
+    assert!(average_adapters(&[missing, checkpoint(1., &device)?], None).is_err());
+    Ok(())
+}
+
+#[test]
+fn averages_the_last_checkpoints_of_a_run() -> Result<()> {
+    let device = Device::Cpu;
+    let run_dir = std::env::temp_dir().join("candle_lora_averaging_run");
+    let _ = std::fs::remove_dir_all(&run_dir);
+    std::fs::create_dir_all(run_dir.join("checkpoint-1000"))?;
+    for (name, value) in [
+        ("checkpoint-200.safetensors", 100.),
+        ("checkpoint-900.safetensors", 2.),
+        ("checkpoint-1000/adapter_model.safetensors", 4.),
+    ] {
+        candle_core::safetensors::save(&checkpoint(value, &device)?, run_dir.join(name))?;
+    }
+    std::fs::write(run_dir.join("trainer_state-2000.json"), "{}")?;
+
+    let paths = last_checkpoints(&run_dir, 2)?;
+    assert_eq!(
+        paths,
+        [
+            run_dir.join("checkpoint-900.safetensors"),
+            run_dir.join("checkpoint-1000/adapter_model.safetensors"),
+        ]
+    );
+    assert_eq!(last_checkpoints(&run_dir, 10)?.len(), 3);
+
+    let out_path = run_dir.join("averaged.safetensors");
+    average_checkpoints(&paths, None, out_path.to_str().unwrap(), &device)?;
+    let averaged = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(
+        averaged["lora_a.weight"].to_vec2::<f32>()?,
+        [[3.; 3], [3.; 3]]
+    );
+    Ok(())
+}
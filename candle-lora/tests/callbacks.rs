@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+
+use candle_core::{Device, Result, Var};
+use candle_lora::{
+    EarlyStopping, FitConfig, LoraTrainer, StepInfo, TrainerCallback, TrainerControl,
+    TrainingConfig,
+};
+use candle_nn::SGD;
+
+#[derive(Default)]
+struct Recorder {
+    steps: Vec<usize>,
+    evals: Vec<(usize, f64)>,
+    saves: Vec<(usize, PathBuf)>,
+}
+
+impl TrainerCallback for Recorder {
+    fn on_step(&mut self, info: &StepInfo, _loss: f64) -> Result<TrainerControl> {
+        self.steps.push(info.step);
+        Ok(TrainerControl::Continue)
+    }
+
+    fn on_eval(&mut self, step: usize, eval_loss: f64) -> Result<TrainerControl> {
+        self.evals.push((step, eval_loss));
+        Ok(TrainerControl::Continue)
+    }
+
+    fn on_save(&mut self, step: usize, path: &Path) -> Result<()> {
+        self.saves.push((step, path.to_path_buf()));
+        Ok(())
+    }
+}
+
+#[test]
+fn early_stopping_waits_for_patience() -> Result<()> {
+    let mut early_stopping = EarlyStopping::new(2, 0.1);
+    assert_eq!(early_stopping.on_eval(1, 1.)?, TrainerControl::Continue);
+    assert_eq!(early_stopping.on_eval(2, 0.5)?, TrainerControl::Continue);
+    // Not better by more than min_delta.
+    assert_eq!(early_stopping.on_eval(3, 0.45)?, TrainerControl::Continue);
+    assert_eq!(early_stopping.on_eval(4, 0.6)?, TrainerControl::Stop);
+    assert_eq!(early_stopping.best_loss(), Some(0.5));
+    assert_eq!(early_stopping.best_step(), Some(2));
+    Ok(())
+}
+
+#[test]
+fn fit_calls_back_and_stops_early() -> Result<()> {
+    let device = Device::Cpu;
+    let x = Var::new(&[0f32], &device)?;
+    let config = TrainingConfig {
+        learning_rate: 1.,
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<SGD>::new(vec![x.clone()], 1., config)?;
+    let fit_config = FitConfig {
+        epochs: 10,
+        eval_steps: Some(2),
+        save_steps: Some(3),
+    };
+    // The evaluation loss stops improving after the fourth step.
+    let mut eval_losses = [3., 2., 2.5, 2.5, 1.].into_iter();
+    let mut eval_fn = || Ok(eval_losses.next().unwrap());
+    let mut save_fn = |step: usize| Ok(PathBuf::from(format!("checkpoint-{step}")));
+    let mut recorder = Recorder::default();
+    let mut early_stopping = EarlyStopping::new(2, 0.);
+
+    let summary = trainer.fit(
+        &[1f32, 2.],
+        &fit_config,
+        |&c| x.as_tensor().affine(c as f64, 0.)?.sum_all(),
+        Some(&mut eval_fn),
+        Some(&mut save_fn),
+        &mut [&mut recorder, &mut early_stopping],
+    )?;
+    assert!(summary.stopped_early);
+    assert_eq!(summary.steps, 8);
+    assert_eq!(summary.last_eval_loss, Some(2.5));
+    assert_eq!(recorder.steps, (0..8).collect::<Vec<_>>());
+    assert_eq!(recorder.evals, [(2, 3.), (4, 2.), (6, 2.5), (8, 2.5)]);
+    assert_eq!(
+        recorder.saves,
+        [
+            (3, PathBuf::from("checkpoint-3")),
+            (6, PathBuf::from("checkpoint-6"))
+        ]
+    );
+    assert_eq!(early_stopping.best_step(), Some(4));
+    // Four epochs of the gradients 1 and 2.
+    assert_eq!(x.to_vec1::<f32>()?, [-12.]);
+    Ok(())
+}
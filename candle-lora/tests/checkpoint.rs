@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    load_adapter_config, LinearLayerLike, Lora, LoraConfig, LoraLinearConfig, NewLayers,
+    SelectedLayersBuilder, ADAPTER_CONFIG_FILE, ADAPTER_WEIGHTS_FILE,
+};
+use candle_nn::{Linear, Optimizer, VarBuilder, VarMap, SGD};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Up,
+    Down,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Up => write!(f, "up"),
+            Self::Down => write!(f, "down"),
+        }
+    }
+}
+
+fn convert(
+    up: &Linear,
+    down: &Linear,
+    config: LoraConfig,
+    varmap: &VarMap,
+) -> Result<NewLayers<ModelLayers>> {
+    let layers = HashMap::from([
+        (ModelLayers::Up, up as &dyn LinearLayerLike),
+        (ModelLayers::Down, down as &dyn LinearLayerLike),
+    ]);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(8, 8))
+        .build();
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, &Device::Cpu);
+    Lora::convert_model(selected, config, &vb.pp("lora"))
+}
+
+fn forward(new: &NewLayers<ModelLayers>, xs: &Tensor) -> Result<Tensor> {
+    new.linear[&ModelLayers::Down].forward(&new.linear[&ModelLayers::Up].forward(xs)?)
+}
+
+#[test]
+fn resume_training_from_checkpoint() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join("candle_lora_checkpoint_resume");
+    let up = Linear::new(Tensor::randn(0f32, 1., (8, 8), &device)?, None);
+    let down = Linear::new(Tensor::randn(0f32, 1., (8, 8), &device)?, None);
+    let xs = Tensor::randn(0f32, 1., (4, 8), &device)?;
+
+    let config = LoraConfig::new(2, 4., None);
+    let varmap = VarMap::new();
+    let new = convert(&up, &down, config.clone(), &varmap)?;
+    let mut sgd = SGD::new(Lora::trainable_params(&new)?, 0.01)?;
+    for _ in 0..3 {
+        sgd.backward_step(&forward(&new, &xs)?.sqr()?.mean_all()?)?;
+    }
+    Lora::save_adapter(&new, &config, Some(3), &dir)?;
+
+    // Only the adapter is saved, under the names of its variables.
+    let saved = candle_core::safetensors::load(dir.join(ADAPTER_WEIGHTS_FILE), &device)?;
+    let mut names = saved.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    let mut expected = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(names, expected);
+    let adapter_config = load_adapter_config(&dir)?;
+    assert_eq!(adapter_config.rank, 2);
+    assert_eq!(adapter_config.alpha, 4.);
+    assert_eq!(adapter_config.dropout, None);
+    assert_eq!(adapter_config.target_modules, ["down", "up"]);
+    assert_eq!(adapter_config.step, Some(3));
+
+    // Loading before converting the model resumes with the trained variables.
+    let resumed_map = VarMap::new();
+    let loaded = Lora::load_adapter(&dir, &resumed_map, &device)?;
+    assert_eq!(loaded, adapter_config);
+    let resumed = convert(&up, &down, loaded.lora_config(), &resumed_map)?;
+    let trained = forward(&new, &xs)?;
+    let diff = (forward(&resumed, &xs)? - &trained)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+    let params = Lora::trainable_params(&resumed)?;
+    assert_eq!(params.len(), 4);
+
+    // Loading after converting sets the variables of the layers.
+    let fresh_map = VarMap::new();
+    let fresh = convert(&up, &down, config, &fresh_map)?;
+    Lora::load_adapter(&dir, &fresh_map, &device)?;
+    let diff = (forward(&fresh, &xs)? - &trained)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+
+    // The variables must have the shapes of the checkpoint.
+    let other_map = VarMap::new();
+    convert(&up, &down, LoraConfig::new(4, 4., None), &other_map)?;
+    assert!(Lora::load_adapter(&dir, &other_map, &device).is_err());
+
+    // The metadata of the weights is enough without the config file, the step aside.
+    std::fs::remove_file(dir.join(ADAPTER_CONFIG_FILE))?;
+    let from_metadata = load_adapter_config(&dir)?;
+    assert_eq!(from_metadata.step, None);
+    assert_eq!((from_metadata.rank, from_metadata.alpha), (2, 4.));
+    assert_eq!(from_metadata.target_modules, ["down", "up"]);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+//! Helpers shared by the integration tests, each of which uses some of them.
+#![allow(dead_code)]
+
+use candle_core::{DType, Device, Result, Tensor, Var};
+use candle_nn::{VarBuilder, VarMap};
+
+/// The largest absolute difference between the elements of `a` and `b`, compared in f32.
+pub fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a.to_dtype(DType::F32)? - b.to_dtype(DType::F32)?)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()
+}
+
+/// A VarBuilder over the adapter weights `tensors`, as loaded from a file.
+pub fn adapter_vb<K: ToString>(
+    tensors: impl IntoIterator<Item = (K, Tensor)>,
+    device: &Device,
+) -> VarBuilder<'static> {
+    let tensors = tensors
+        .into_iter()
+        .map(|(name, tensor)| (name.to_string(), tensor))
+        .collect();
+    VarBuilder::from_tensors(tensors, DType::F32, device)
+}
+
+/// A VarBuilder over a VarMap holding the adapter weights `tensors` as variables in `dtype`, as
+/// a trained adapter whose training goes on.
+pub fn trainable_adapter_vb<K: ToString>(
+    tensors: impl IntoIterator<Item = (K, Tensor)>,
+    dtype: DType,
+    device: &Device,
+) -> Result<VarBuilder<'static>> {
+    let varmap = VarMap::new();
+    {
+        let mut data = varmap.data().lock().unwrap();
+        for (name, tensor) in tensors {
+            data.insert(name.to_string(), Var::from_tensor(&tensor)?);
+        }
+    }
+    Ok(VarBuilder::from_varmap(&varmap, dtype, device))
+}
+
+/// An adapter of the given rank for a `(6, 4)` linear layer (id 0), a 3x3 conv2d layer of 3 to 4
+/// channels (id 1) and an embedding of 10 tokens in 4 dimensions (id 2), under `lora`.
+pub fn multi_layer_adapter(rank: usize, device: &Device) -> Result<VarBuilder<'static>> {
+    let tensors = [
+        (
+            "lora.a0.weight",
+            Tensor::randn(0f32, 1., (rank, 4), device)?,
+        ),
+        (
+            "lora.b0.weight",
+            Tensor::randn(0f32, 1., (6, rank), device)?,
+        ),
+        (
+            "lora.a1.weight",
+            Tensor::randn(0f32, 1., (rank, 3, 3, 3), device)?,
+        ),
+        (
+            "lora.b1.weight",
+            Tensor::randn(0f32, 1., (4, rank, 1, 1), device)?,
+        ),
+        (
+            "lora.a2.weight",
+            Tensor::randn(0f32, 1., (rank, 10), device)?,
+        ),
+        (
+            "lora.b2.weight",
+            Tensor::randn(0f32, 1., (4, rank), device)?,
+        ),
+    ];
+    Ok(adapter_vb(tensors, device))
+}
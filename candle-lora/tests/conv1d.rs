@@ -16,6 +16,12 @@ fn conv1d() -> candle_core::Result<()> {
         Conv,
     }
 
+    impl std::fmt::Display for ModelLayers {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "conv")
+        }
+    }
+
     #[derive(Debug)]
     struct Model {
         conv: Arc<dyn Conv1dLayerLike>,
@@ -84,7 +90,7 @@ fn conv1d() -> candle_core::Result<()> {
     let loraconfig = LoraConfig::new(1, 1., None);
 
     //Create new LoRA layers from our layers
-    let new_layers = Lora::convert_model(selected, loraconfig, &vb);
+    let new_layers = Lora::convert_model(selected, loraconfig, &vb)?;
 
     //Custom methods to implement
     model.insert_new(new_layers);
@@ -97,3 +103,49 @@ fn conv1d() -> candle_core::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn grouped_conv1d_forward_matches_merged() -> candle_core::Result<()> {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Module, Tensor};
+    use candle_lora::{LoraConv1d, LoraConv1dConfig};
+    use candle_nn::{Conv1d, Conv1dConfig};
+
+    let device = Device::Cpu;
+    let config = Conv1dConfig {
+        groups: 2,
+        ..Default::default()
+    };
+    let base = Conv1d::new(Tensor::randn(0f32, 1., (6, 2, 1), &device)?, None, config);
+    // A is (rank, in) and B (out / groups, rank), B A the (6, 2, 1) delta kernel.
+    let tensors = HashMap::from([
+        (
+            "lora.a0.weight".to_string(),
+            Tensor::randn(0f32, 1., (2, 4), &device)?,
+        ),
+        (
+            "lora.b0.weight".to_string(),
+            Tensor::randn(0f32, 1., (3, 2), &device)?,
+        ),
+    ]);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let mut layer = LoraConv1d::new(
+        &base,
+        &LoraConv1dConfig::new(1, 4, 6),
+        &LoraConfig::new(2, 4., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (2, 4, 5), &device)?;
+    let expected = layer.merged_conv1d().unwrap().forward(&xs)?;
+    let diff = |a: &Tensor| -> candle_core::Result<f32> {
+        (a - &expected)?.abs()?.max_all()?.to_scalar::<f32>()
+    };
+    assert!(diff(&layer.forward(&xs)?)? < 1e-4);
+    assert!(diff(&base.forward(&xs)?)? > 1e-3);
+    candle_lora::Merge::merge_weights(&mut layer).unwrap();
+    assert!(diff(&layer.forward(&xs)?)? < 1e-4);
+    Ok(())
+}
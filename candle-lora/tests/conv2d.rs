@@ -16,6 +16,12 @@ fn conv2d() -> candle_core::Result<()> {
         Conv,
     }
 
+    impl std::fmt::Display for ModelLayers {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "conv")
+        }
+    }
+
     #[derive(Debug)]
     struct Model {
         conv: Arc<dyn Conv2dLayerLike>,
@@ -99,7 +105,7 @@ fn conv2d() -> candle_core::Result<()> {
     let loraconfig = LoraConfig::new(1, 1., None);
 
     //Create new LoRA layers from our layers
-    let new_layers = Lora::convert_model(selected, loraconfig, &vb);
+    let new_layers = Lora::convert_model(selected, loraconfig, &vb)?;
 
     //Custom methods to implement
     model.insert_new(new_layers);
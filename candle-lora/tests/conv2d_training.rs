@@ -0,0 +1,156 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use candle_core::{DType, Device, Module, Result, Tensor, Var, D};
+use candle_lora::{
+    Conv2dLayerLike, Lora, LoraConfig, LoraConv2d, LoraConv2dConfig, LoraTrainer, Merge,
+    SelectedLayersBuilder, TrainingConfig,
+};
+use candle_nn::{AdamW, Conv2d, Conv2dConfig, ParamsAdamW, VarBuilder, VarMap};
+
+#[derive(PartialEq, Eq, Hash)]
+enum ModelLayers {
+    Conv,
+}
+
+impl Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "conv")
+    }
+}
+
+/// A 3x3 padded convolution, ReLU and global average pooling followed by a frozen linear head.
+struct Model {
+    conv: Arc<dyn Conv2dLayerLike>,
+    head: Tensor,
+}
+
+impl Module for Model {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        // One image per convolution: the kernel gradient of a batched conv2d is wrong in
+        // candle 0.9.1.
+        let features = (0..xs.dim(0)?)
+            .map(|i| {
+                self.conv
+                    .forward(&xs.narrow(0, i, 1)?)?
+                    .relu()?
+                    .mean((2, 3))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&features, 0)?.matmul(&self.head)
+    }
+}
+
+/// Images of horizontal stripes, label 0, and vertical stripes, label 1, with shifted phases.
+fn stripes(device: &Device) -> Result<(Tensor, Tensor)> {
+    let size = 8;
+    let mut images = vec![];
+    let mut labels = vec![];
+    for label in 0..2u32 {
+        for phase in 0..2 {
+            let mut image = vec![0f32; size * size];
+            for y in 0..size {
+                for x in 0..size {
+                    let coord = if label == 0 { y } else { x };
+                    image[y * size + x] = if (coord + phase) % 2 == 0 { 1. } else { -1. };
+                }
+            }
+            images.extend(image);
+            labels.push(label);
+        }
+    }
+    Ok((
+        Tensor::from_vec(images, (4, 1, size, size), device)?,
+        Tensor::new(labels, device)?,
+    ))
+}
+
+#[test]
+fn conv2d_adapter_learns_image_classes() -> Result<()> {
+    let device = Device::Cpu;
+    // A Laplacian, which responds the same to both classes, and a fixed head reading the first
+    // two channels as class 0 and the last two as class 1.
+    let laplacian = Tensor::new(&[[0f32, 1., 0.], [1., -4., 1.], [0., 1., 0.]], &device)?;
+    let weight = laplacian.reshape((1, 1, 3, 3))?.repeat((4, 1, 1, 1))?;
+    let head = Tensor::new(&[[1f32, -1.], [1., -1.], [-1., 1.], [-1., 1.]], &device)?;
+    let cfg = Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let mut model = Model {
+        conv: Arc::new(Conv2d::new(weight.clone(), None, cfg)),
+        head,
+    };
+
+    let mut conv2d_layers = HashMap::new();
+    conv2d_layers.insert(ModelLayers::Conv, &*model.conv);
+    let selected = SelectedLayersBuilder::new()
+        .add_conv2d_layers(conv2d_layers, LoraConv2dConfig::new(1, 4))
+        .build();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let new_layers = Lora::convert_model(selected, LoraConfig::new(4, 4., None), &vb.pp("lora"))?;
+    for (_, conv) in new_layers.conv2d {
+        model.conv = Arc::new(conv);
+    }
+
+    let (images, labels) = stripes(&device)?;
+    let loss_fn = || candle_nn::loss::cross_entropy(&model.forward(&images)?, &labels);
+    let initial_loss = loss_fn()?.to_scalar::<f32>()?;
+
+    let config = TrainingConfig {
+        learning_rate: 0.01,
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<AdamW>::new(varmap.all_vars(), ParamsAdamW::default(), config)?;
+    for _ in 0..100 {
+        trainer.backward_step(&loss_fn()?)?;
+    }
+    let final_loss = loss_fn()?.to_scalar::<f32>()?;
+    assert!(
+        final_loss < initial_loss / 4.,
+        "{initial_loss} -> {final_loss}"
+    );
+
+    let predictions = model.forward(&images)?.argmax(D::Minus1)?;
+    assert_eq!(predictions.to_vec1::<u32>()?, labels.to_vec1::<u32>()?);
+    // Only the adapter was trained.
+    let frozen = (model.conv.weight() - &weight)?.abs()?.sum_all()?;
+    assert_eq!(frozen.to_scalar::<f32>()?, 0.);
+    Ok(())
+}
+
+#[test]
+fn conv2d_merge_matches_padded_strided_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = Conv2dConfig {
+        padding: 1,
+        stride: 2,
+        ..Default::default()
+    };
+    let weight = Tensor::randn(0f32, 1., (6, 3, 3, 3), &device)?;
+    let conv = Conv2d::new(weight, None, cfg);
+
+    // Non-zero A and B, as after training.
+    let varmap = VarMap::new();
+    for (shape, name) in [((2, 3, 3, 3), "a0.weight"), ((6, 2, 1, 1), "b0.weight")] {
+        let var = Var::randn(0f32, 1., shape, &device)?;
+        varmap.data().lock().unwrap().insert(name.to_string(), var);
+    }
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut lora = LoraConv2d::new(
+        &conv,
+        &LoraConv2dConfig::new(3, 6),
+        &LoraConfig::new(2, 4., None),
+        &vb,
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (2, 3, 9, 9), &device)?;
+    let unmerged = lora.forward(&xs)?;
+    assert_eq!(unmerged.dims(), &[2, 6, 5, 5]);
+    lora.merge_weights().unwrap();
+    let merged = lora.forward(&xs)?;
+    let diff = (unmerged - merged)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "max difference {diff}");
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use candle_core::{Device, Module, Result, Tensor};
+use candle_lora::{
+    LoraConfig, LoraConvTranspose1d, LoraConvTranspose2d, LoraConvTransposeConfig, Merge,
+};
+use candle_nn::{
+    ConvTranspose1d, ConvTranspose1dConfig, ConvTranspose2d, ConvTranspose2dConfig, VarBuilder,
+};
+
+mod common;
+use common::{adapter_vb, max_abs_diff};
+
+/// An adapter of rank 2 with a nonzero B of the shape of `weight`.
+fn adapter(weight: &Tensor, device: &Device) -> Result<VarBuilder<'static>> {
+    let mut b_shape = weight.dims().to_vec();
+    b_shape[0] = 2;
+    let tensors = [
+        (
+            "lora.a0.weight",
+            Tensor::randn(0f32, 1., (2, weight.dim(0)?), device)?,
+        ),
+        ("lora.b0.weight", Tensor::randn(0f32, 1., b_shape, device)?),
+    ];
+    Ok(adapter_vb(tensors, device))
+}
+
+#[test]
+fn conv_transpose1d_forward_matches_merged() -> Result<()> {
+    let device = Device::Cpu;
+    let config = ConvTranspose1dConfig {
+        stride: 2,
+        groups: 2,
+        ..Default::default()
+    };
+    // (in, out / groups, kernel)
+    let weight = Tensor::randn(0f32, 1., (4, 3, 3), &device)?;
+    let bias = Tensor::randn(0f32, 1., 6, &device)?;
+    let base = ConvTranspose1d::new(weight.clone(), Some(bias), config);
+    let vb = adapter(&weight, &device)?;
+    let mut layer = LoraConvTranspose1d::new(
+        &base,
+        &LoraConvTransposeConfig::new(4, 6),
+        &LoraConfig::new(2, 4., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (2, 4, 5), &device)?;
+    let expected = layer.merged_conv_transpose1d()?.forward(&xs)?;
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+    assert!(max_abs_diff(&base.forward(&xs)?, &expected)? > 1e-3);
+
+    layer.merge_weights().unwrap();
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+    assert!(layer.merge_weights().is_err());
+    layer.unmerge_weights().unwrap();
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn conv_transpose2d_forward_matches_merged() -> Result<()> {
+    let device = Device::Cpu;
+    let config = ConvTranspose2dConfig {
+        stride: 2,
+        padding: 1,
+        ..Default::default()
+    };
+    let weight = Tensor::randn(0f32, 1., (3, 5, 4, 4), &device)?;
+    let base = ConvTranspose2d::new(weight.clone(), None, config);
+    let vb = adapter(&weight, &device)?;
+    let mut layer = LoraConvTranspose2d::new(
+        &base,
+        &LoraConvTransposeConfig::new(3, 5),
+        &LoraConfig::new(2, 4., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (1, 3, 6, 6), &device)?;
+    let expected = layer.merged_conv_transpose2d()?.forward(&xs)?;
+    assert_eq!(expected.dims(), &[1, 5, 12, 12]);
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+
+    // The delta kernel is A^T B, scaled by alpha / r.
+    let delta = (vb
+        .get((2, 3), "lora.a0.weight")?
+        .t()?
+        .matmul(&vb.get((2, 5, 4, 4), "lora.b0.weight")?.flatten_from(1)?)?
+        * 2.)?
+        .reshape(weight.shape())?;
+    assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-4);
+
+    layer.merge_weights().unwrap();
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+    layer.unmerge_weights().unwrap();
+    assert!(layer.unmerge_weights().is_err());
+    Ok(())
+}
+
+#[test]
+fn channels_must_match_the_layer() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (4, 3, 3), &device)?;
+    let base = ConvTranspose1d::new(weight.clone(), None, Default::default());
+    let vb = adapter(&weight, &device)?;
+    let layer = LoraConvTranspose1d::new(
+        &base,
+        &LoraConvTransposeConfig::new(3, 4),
+        &LoraConfig::new(2, 4., None),
+        &vb.pp("lora"),
+        0,
+    );
+    assert!(layer.is_err());
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    convert_peft_to_candle_lora_traced_with_hooks, ConversionHooks, ConvertOptions,
+    TracedArchitecture,
+};
+
+#[test]
+fn hooks_adapt_a_fused_transposed_checkpoint() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_hooks_peft.safetensors");
+    let out_path = dir.join("candle_lora_hooks_out.safetensors");
+
+    // A checkpoint with a fused qkv projection, its A stored as (in_features, rank), in f16.
+    let lora_b = Tensor::arange(0f32, 24., &device)?.reshape((12, 2))?;
+    let mut peft = HashMap::new();
+    peft.insert(
+        "transformer.blocks.0.attn.qkv_proj.lora_A.weight".to_string(),
+        Tensor::ones((8, 2), DType::F16, &device)?,
+    );
+    peft.insert(
+        "transformer.blocks.0.attn.qkv_proj.lora_B.weight".to_string(),
+        lora_b.to_dtype(DType::F16)?,
+    );
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    let hooks = ConversionHooks::new()
+        .rename("transformer.blocks.", "model.layers.")
+        .rename(".attn.", ".self_attn.")
+        .transpose(".lora_A")
+        .split_fused("qkv_proj", &["q_proj", "k_proj", "v_proj"])
+        .cast(DType::F32);
+    convert_peft_to_candle_lora_traced_with_hooks(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Granite,
+        &device,
+        &hooks,
+        &ConvertOptions::default(),
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 6);
+    for (i, proj) in ["q_proj", "k_proj", "v_proj"].iter().enumerate() {
+        let prefix = format!("model.layers.0.self_attn.{proj}.traced_lora_linear");
+        let a = &converted[&format!("{prefix}.a0.weight")];
+        assert_eq!(a.dims(), &[2, 8]);
+        assert_eq!(a.dtype(), DType::F32);
+        let b = &converted[&format!("{prefix}.b0.weight")];
+        assert_eq!(
+            b.to_vec2::<f32>()?,
+            lora_b.narrow(0, i * 4, 4)?.to_vec2::<f32>()?
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn hooks_drop_and_reject_collisions() -> Result<()> {
+    let device = Device::Cpu;
+    let mut tensors = HashMap::new();
+    for name in ["a.lora_A.weight", "b.lora_A.weight", "a.magnitude"] {
+        tensors.insert(name.to_string(), Tensor::zeros(2, DType::F32, &device)?);
+    }
+
+    let dropped = ConversionHooks::new()
+        .drop_matching(".magnitude")
+        .apply(tensors.clone())?;
+    assert_eq!(dropped.len(), 2);
+    assert!(!dropped.contains_key("a.magnitude"));
+
+    assert!(ConversionHooks::new()
+        .rename("b.", "a.")
+        .apply(tensors)
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,276 @@
+//! Round trips of synthetic PEFT adapters of several architectures through the candle-lora
+//! formats, and the golden conversion of the adapter checked in under `tests/fixtures`.
+//!
+//! The golden names of `tests/fixtures/peft_llama_12_layers/candle_lora_names.txt` are rewritten
+//! from the current conversion when `CANDLE_LORA_UPDATE_FIXTURES` is set, for changes of the
+//! naming that are intended.
+
+use std::{collections::HashMap, path::Path};
+
+use candle_core::{Device, Result, Tensor};
+use candle_lora::{
+    convert_candle_lora_to_peft, convert_peft_dir_to_candle_lora, convert_peft_to_candle_lora,
+    migrate_to_indexed, migrate_to_named, structural_order,
+};
+
+mod common;
+use common::max_abs_diff;
+
+const PREFIX: &str = "lora_llama";
+
+/// A xorshift generator, for the shapes of the synthetic adapters to be reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+
+    fn range(&mut self, low: usize, high: usize) -> usize {
+        low + self.below(high - low + 1)
+    }
+}
+
+/// The module templates of the adapted layers of each architecture, `{}` being the layer.
+const ARCHITECTURES: [(&str, &[&str]); 6] = [
+    (
+        "llama",
+        &[
+            "model.layers.{}.self_attn.q_proj",
+            "model.layers.{}.self_attn.k_proj",
+            "model.layers.{}.self_attn.v_proj",
+            "model.layers.{}.self_attn.o_proj",
+            "model.layers.{}.mlp.gate_proj",
+            "model.layers.{}.mlp.up_proj",
+            "model.layers.{}.mlp.down_proj",
+        ],
+    ),
+    (
+        "gpt2",
+        &["transformer.h.{}.attn.c_attn", "transformer.h.{}.mlp.c_fc"],
+    ),
+    (
+        "bert",
+        &[
+            "bert.encoder.layer.{}.attention.self.query",
+            "bert.encoder.layer.{}.attention.self.value",
+        ],
+    ),
+    (
+        "t5",
+        &[
+            "encoder.block.{}.layer.0.SelfAttention.q",
+            "decoder.block.{}.layer.0.SelfAttention.v",
+            "decoder.block.{}.layer.1.EncDecAttention.q",
+        ],
+    ),
+    (
+        "phi",
+        &["model.layers.{}.self_attn.dense", "model.layers.{}.mlp.fc1"],
+    ),
+    (
+        "falcon",
+        &["transformer.h.{}.self_attention.query_key_value"],
+    ),
+];
+
+/// A PEFT adapter of the modules of `templates` in up to 13 layers, of random ranks and sizes.
+fn synthetic_adapter(
+    templates: &[&str],
+    rng: &mut Rng,
+    device: &Device,
+) -> Result<(Vec<String>, HashMap<String, Tensor>)> {
+    let num_layers = rng.range(1, 13);
+    let mut modules = Vec::new();
+    let mut tensors = HashMap::new();
+    for layer in 0..num_layers {
+        for template in templates {
+            // Some layers are not adapted, as with `layers_to_transform`.
+            if rng.below(5) == 0 {
+                continue;
+            }
+            let module = template.replace("{}", &layer.to_string());
+            let (rank, in_features, out_features) =
+                (rng.range(1, 8), rng.range(2, 12), rng.range(2, 12));
+            tensors.insert(
+                format!("base_model.model.{module}.lora_A.weight"),
+                Tensor::randn(0f32, 1., (rank, in_features), device)?,
+            );
+            tensors.insert(
+                format!("base_model.model.{module}.lora_B.weight"),
+                Tensor::randn(0f32, 1., (out_features, rank), device)?,
+            );
+            modules.push(module);
+        }
+    }
+    modules.sort_by(|a, b| structural_order(a, b));
+    Ok((modules, tensors))
+}
+
+/// The delta `B A` of the PEFT module `module`.
+fn peft_delta(tensors: &HashMap<String, Tensor>, module: &str) -> Result<Tensor> {
+    let a = &tensors[&format!("base_model.model.{module}.lora_A.weight")];
+    let b = &tensors[&format!("base_model.model.{module}.lora_B.weight")];
+    b.matmul(a)
+}
+
+#[test]
+fn synthetic_adapters_round_trip_with_their_deltas() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_roundtrip_peft.safetensors");
+    let candle_path = dir.join("candle_lora_roundtrip_candle.safetensors");
+    let back_path = dir.join("candle_lora_roundtrip_back.safetensors");
+
+    for (seed, (architecture, templates)) in ARCHITECTURES.iter().cycle().take(18).enumerate() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ seed as u64);
+        let (modules, peft) = synthetic_adapter(templates, &mut rng, &device)?;
+        if modules.is_empty() {
+            continue;
+        }
+        candle_core::safetensors::save(&peft, &peft_path)?;
+        convert_peft_to_candle_lora(
+            peft_path.to_str().unwrap(),
+            candle_path.to_str().unwrap(),
+            PREFIX,
+        )?;
+
+        // The index-based weights hold the deltas of the modules in structural order.
+        let converted = candle_core::safetensors::load(&candle_path, &device)?;
+        assert_eq!(converted.len(), peft.len(), "{architecture}");
+        for (idx, module) in modules.iter().enumerate() {
+            let a = &converted[&format!("{PREFIX}.a{idx}.weight")];
+            let b = &converted[&format!("{PREFIX}.b{idx}.weight")];
+            let diff = max_abs_diff(&b.matmul(a)?, &peft_delta(&peft, module)?)?;
+            assert_eq!(diff, 0., "{architecture} {module}");
+        }
+
+        // Back to PEFT, the same tensors.
+        convert_candle_lora_to_peft(
+            candle_path.to_str().unwrap(),
+            back_path.to_str().unwrap(),
+            PREFIX,
+            &modules,
+            &device,
+        )?;
+        let back = candle_core::safetensors::load(&back_path, &device)?;
+        assert_eq!(back.len(), peft.len(), "{architecture}");
+        for module in &modules {
+            let diff = max_abs_diff(&peft_delta(&back, module)?, &peft_delta(&peft, module)?)?;
+            assert_eq!(diff, 0., "{architecture} {module}");
+        }
+
+        // Through the name-based naming and back to the same indices.
+        let named = migrate_to_named(&converted, PREFIX, &modules)?;
+        for module in &modules {
+            let a = &named[&format!("{module}.a0.weight")];
+            let b = &named[&format!("{module}.b0.weight")];
+            let diff = max_abs_diff(&b.matmul(a)?, &peft_delta(&peft, module)?)?;
+            assert_eq!(diff, 0., "{architecture} {module}");
+        }
+        let (indexed, indexed_modules) = migrate_to_indexed(&named, PREFIX)?;
+        assert_eq!(indexed_modules, modules, "{architecture}");
+        let mut names = indexed.keys().collect::<Vec<_>>();
+        let mut expected = converted.keys().collect::<Vec<_>>();
+        names.sort();
+        expected.sort();
+        assert_eq!(names, expected, "{architecture}");
+    }
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&candle_path)?;
+    std::fs::remove_file(&back_path)?;
+    Ok(())
+}
+
+#[test]
+fn golden_llama_conversion() -> Result<()> {
+    let device = Device::Cpu;
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/peft_llama_12_layers");
+    let golden_path = fixture.join("candle_lora_names.txt");
+    let out_path = std::env::temp_dir().join("candle_lora_golden_llama.safetensors");
+
+    let report = convert_peft_dir_to_candle_lora(
+        fixture.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        PREFIX,
+        &device,
+    )?;
+    let peft = candle_core::safetensors::load(fixture.join("adapter_model.safetensors"), &device)?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    std::fs::remove_file(&out_path)?;
+
+    if std::env::var_os("CANDLE_LORA_UPDATE_FIXTURES").is_some() {
+        write_golden_names(&golden_path, &peft, &converted)?;
+    }
+    let golden = std::fs::read_to_string(&golden_path)?;
+    let golden = golden
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.split_once(' ').unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(converted.len(), golden.len());
+    for (candle_name, peft_name) in golden {
+        let diff = max_abs_diff(&converted[candle_name], &peft[peft_name])?;
+        assert_eq!(diff, 0., "{candle_name} is not {peft_name}");
+    }
+
+    assert_eq!(
+        (report.rank, report.alpha, report.scaling),
+        (2, Some(4.), Some(2.))
+    );
+    assert_eq!(report.num_modules, 24);
+    assert_eq!(
+        report.target_modules[20..],
+        [
+            "base_model.model.model.layers.10.self_attn.q_proj",
+            "base_model.model.model.layers.10.self_attn.v_proj",
+            "base_model.model.model.layers.11.self_attn.q_proj",
+            "base_model.model.model.layers.11.self_attn.v_proj",
+        ]
+    );
+    assert_eq!(converted["lora_llama.a20.weight"].dims(), &[4, 8]);
+    Ok(())
+}
+
+/// Write the golden names of the conversion `converted` of `peft`, each converted tensor
+/// being matched with the PEFT tensor of the same values.
+fn write_golden_names(
+    path: &Path,
+    peft: &HashMap<String, Tensor>,
+    converted: &HashMap<String, Tensor>,
+) -> Result<()> {
+    // By index, A before B.
+    let index = |name: &str| {
+        name.split('.')
+            .nth(1)
+            .and_then(|c| c[1..].parse::<usize>().ok())
+    };
+    let mut names = converted.keys().collect::<Vec<_>>();
+    names.sort_by_key(|name| (index(name), name.as_str()));
+    let mut lines = vec![
+        "# The candle-lora tensors of `convert_peft_dir_to_candle_lora(.., \"lora_llama\", ..)` \
+         and the PEFT"
+            .to_string(),
+        "# tensors they are converted from, the modules being indexed in structural order."
+            .to_string(),
+    ];
+    for name in names {
+        let tensor = &converted[name];
+        let mut sources = Vec::new();
+        for (peft_name, peft_tensor) in peft {
+            if peft_tensor.dims() == tensor.dims() && max_abs_diff(peft_tensor, tensor)? == 0. {
+                sources.push(peft_name);
+            }
+        }
+        let [source] = sources[..] else {
+            candle_core::bail!("{name} matches {} PEFT tensors", sources.len())
+        };
+        lines.push(format!("{name} {source}"));
+    }
+    std::fs::write(path, lines.join("\n") + "\n")?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    AdapterDevice, DeviceMap, LinearLayerLike, Lora, LoraConfig, LoraLinear, LoraLinearConfig,
+    SelectedLayersBuilder, TargetSpec,
+};
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+#[test]
+fn device_map_rules_and_base_layers() -> Result<()> {
+    let base = Device::Cpu;
+    // Layers 0 and 1 on the first device, 2 to 4 on the second.
+    let map = DeviceMap::split_layers(5, &[Device::Cpu, Device::Cpu]);
+    for layer in 0..5 {
+        let path = format!("model.layers.{layer}.self_attn.q_proj");
+        assert!(map.device(&path, &base).is_some(), "{path}");
+    }
+    assert!(map.device("model.layers.5.mlp.up_proj", &base).is_none());
+    assert!(map.device("lm_head", &base).is_none());
+
+    let map = map
+        .with_device(
+            TargetSpec::new().with_module_pattern("lm_head$")?,
+            Device::Cpu,
+        )
+        .with_base_layer_devices();
+    assert!(map.device("lm_head", &base).is_some());
+    assert!(map
+        .device("model.embed_tokens", &base)
+        .is_some_and(|device| device.same_device(&base)));
+
+    // More devices than layers leaves the extra devices without layers.
+    let map = DeviceMap::split_layers(2, &[Device::Cpu, Device::Cpu, Device::Cpu]);
+    assert!(map.device("layers.1.fc", &base).is_some());
+    assert!(map.device("layers.2.fc", &base).is_none());
+    Ok(())
+}
+
+#[test]
+fn placed_adapters_compute_the_same_outputs() -> Result<()> {
+    let device = Device::Cpu;
+    let names = (0..4)
+        .map(|i| format!("layers.{i}.q_proj"))
+        .collect::<Vec<_>>();
+    let weight = Tensor::randn(0f32, 1., (6, 6), &device)?;
+    let linear = Linear::new(weight, None);
+    let selected = || {
+        let layers = names
+            .iter()
+            .map(|name| (name.clone(), &linear as &dyn LinearLayerLike))
+            .collect::<HashMap<_, _>>();
+        SelectedLayersBuilder::new()
+            .add_linear_layers(layers, LoraLinearConfig::new(6, 6))
+            .build()
+    };
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let plain = Lora::convert_model(selected(), LoraConfig::new(2, 4., None), &vb.pp("model"))?;
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        if name.contains(".b") {
+            var.set(&Tensor::randn(0f32, 1., var.shape(), &device)?)?;
+        }
+    }
+    let map = DeviceMap::split_layers(4, &[Device::Cpu, Device::Cpu]).with_base_layer_devices();
+    let config = LoraConfig::new(2, 4., None).with_device_map(map);
+    let mut placed = Lora::convert_model(selected(), config, &vb.pp("model"))?;
+    assert_eq!(varmap.all_vars().len(), 8);
+
+    let xs = Tensor::randn(0f32, 1., (3, 6), &device)?;
+    let max_diff = |a: &LoraLinear, b: &LoraLinear| -> Result<f32> {
+        (a.forward(&xs)? - b.forward(&xs)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()
+    };
+    for name in &names {
+        assert_eq!(max_diff(&plain.linear[name], &placed.linear[name])?, 0.);
+        let moved = placed.linear[name].to_device(&device)?;
+        assert_eq!(max_diff(&plain.linear[name], &moved)?, 0.);
+    }
+
+    // Moving to the device the adapters are on keeps them trainable.
+    Lora::to_device(&mut placed, &device)?;
+    assert_eq!(Lora::trainable_params(&placed)?.len(), 8);
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use candle_core::{DType, Device, Result, Tensor, Var};
+use candle_lora::{all_reduce_grads, Communicator, LoraTrainer, TrainingConfig};
+use candle_nn::SGD;
+
+/// Rank 0 of a two-process run whose other process always sends `other`.
+struct FakePeer {
+    other: Tensor,
+}
+
+impl Communicator for FakePeer {
+    fn rank(&self) -> usize {
+        0
+    }
+
+    fn world_size(&self) -> usize {
+        2
+    }
+
+    fn all_reduce_sum(&self, xs: &Tensor) -> Result<Tensor> {
+        xs + &self.other
+    }
+}
+
+#[test]
+fn grads_are_averaged_over_processes() -> Result<()> {
+    let device = Device::Cpu;
+    let a = Var::new(&[[1f32, 2.], [3., 4.]], &device)?;
+    let b = Var::new(&[1f32], &device)?;
+    let unused = Var::new(&[0f32, 0.], &device)?;
+    let loss = ((a.as_tensor() * 2.)?.sum_all()? + (b.as_tensor() * 4.)?.sum_all()?)?;
+    let mut grads = loss.backward()?;
+    let peer = FakePeer {
+        other: Tensor::new(&[0f32, 0., 0., 4., 0., 6., 8.], &device)?,
+    };
+
+    all_reduce_grads(&mut grads, &[a.clone(), b.clone(), unused.clone()], &peer)?;
+    assert_eq!(
+        grads.get(&a).unwrap().to_vec2::<f32>()?,
+        [[1., 1.], [1., 3.]]
+    );
+    assert_eq!(grads.get(&b).unwrap().to_vec1::<f32>()?, [2.]);
+    assert_eq!(grads.get(&unused).unwrap().to_vec1::<f32>()?, [3., 4.]);
+    assert_eq!(grads.get(&a).unwrap().dtype(), DType::F32);
+    Ok(())
+}
+
+#[test]
+fn trainer_steps_with_the_mean_gradient() -> Result<()> {
+    let device = Device::Cpu;
+    let x = Var::new(&[0f32], &device)?;
+    let config = TrainingConfig {
+        learning_rate: 1.,
+        ..Default::default()
+    };
+    let peer = FakePeer {
+        other: Tensor::new(&[3f32], &device)?,
+    };
+    let mut trainer =
+        LoraTrainer::<SGD>::new(vec![x.clone()], 1., config)?.with_communicator(Box::new(peer));
+    assert_eq!(trainer.communicator().unwrap().world_size(), 2);
+
+    // The local gradient is 1, the other process has 3.
+    let loss = x.as_tensor().sum_all()?;
+    trainer.backward_step(&loss)?;
+    assert_eq!(x.to_vec1::<f32>()?, [-2.]);
+    Ok(())
+}
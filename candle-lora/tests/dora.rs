@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_lora::{
+    convert_candle_lora_to_peft, convert_peft_to_candle_lora_with_manifest, merge_peft_adapter,
+    LoraConfig, LoraConv2d, LoraConv2dConfig, LoraLinear, LoraLinearConfig, Merge, Saveable,
+};
+use candle_nn::{Conv2d, Conv2dConfig, Linear, VarBuilder, VarMap};
+
+mod common;
+use common::{max_abs_diff, trainable_adapter_vb};
+
+fn row_norms(weight: &Tensor) -> Result<Tensor> {
+    weight.flatten_from(1)?.sqr()?.sum(D::Minus1)?.sqrt()
+}
+
+#[test]
+fn fresh_dora_linear_starts_as_its_base() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (8, 16), &device)?;
+    let base = Linear::new(weight.clone(), None);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let lora = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(16, 8).with_dora(),
+        &LoraConfig::new(4, 8., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (3, 16), &device)?;
+    assert!(max_abs_diff(&lora.forward(&xs)?, &base.forward(&xs)?)? < 1e-5);
+    // The offset from the base norms is trained, the magnitude itself is saved.
+    assert!(varmap
+        .data()
+        .lock()
+        .unwrap()
+        .contains_key("lora.dm0.weight"));
+    let mut saved = HashMap::new();
+    lora.get_tensors(&mut saved)?;
+    assert!(max_abs_diff(&saved["lora.m0.weight"], &row_norms(&weight)?)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn dora_linear_reloads_without_prefix() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (8, 16), &device)?, None);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let linear_config = LoraLinearConfig::new(16, 8).with_dora();
+    let config = LoraConfig::new(4, 8., None);
+    let lora = LoraLinear::new(&base, &linear_config, &config, &vb, 0)?;
+    // Trained values for all the adapter weights.
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 1., var.shape(), &device)?)?;
+    }
+
+    let mut saved = HashMap::new();
+    lora.get_tensors(&mut saved)?;
+    let mut names = saved.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a0.weight", "b0.weight", "m0.weight"]);
+    let loaded_vb = VarBuilder::from_tensors(saved, DType::F32, &device);
+    let loaded = LoraLinear::new(&base, &linear_config, &config, &loaded_vb, 0)?;
+    let xs = Tensor::randn(0f32, 1., (3, 16), &device)?;
+    assert!(max_abs_diff(&loaded.forward(&xs)?, &lora.forward(&xs)?)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn dora_linear_rescales_and_merges() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (8, 16), &device)?;
+    let base = Linear::new(weight.clone(), None);
+    let a = Tensor::randn(0f32, 1., (4, 16), &device)?;
+    let b = Tensor::randn(0f32, 1., (8, 4), &device)?;
+    let m = Tensor::rand(1f32, 2., 8, &device)?;
+    let vb = trainable_adapter_vb(
+        [
+            ("lora.a0.weight", a.clone()),
+            ("lora.b0.weight", b.clone()),
+            ("lora.m0.weight", m.clone()),
+        ],
+        DType::F32,
+        &device,
+    )?;
+    let mut lora = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(16, 8).with_dora(),
+        &LoraConfig::new(4, 8., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    // W' = m * (W + 2 B A) / ||W + 2 B A||
+    let adapted = (&weight + (b.matmul(&a)? * 2.)?)?;
+    let scale = (&m / row_norms(&adapted)?)?;
+    let expected_weight = adapted.broadcast_mul(&scale.unsqueeze(1)?)?;
+    assert!(max_abs_diff(&row_norms(&expected_weight)?, &m)? < 1e-4);
+    let xs = Tensor::randn(0f32, 1., (2, 3, 16), &device)?;
+    let expected = Linear::new(expected_weight.clone(), None).forward(&xs)?;
+    let unmerged = lora.forward(&xs)?;
+    assert!(max_abs_diff(&unmerged, &expected)? < 1e-4);
+    let delta = lora.get_delta_weight().unwrap();
+    assert!(max_abs_diff(&(&weight + delta)?, &expected_weight)? < 1e-4);
+
+    lora.merge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &unmerged)? < 1e-4);
+    lora.unmerge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &unmerged)? < 1e-4);
+
+    let mut saved = HashMap::new();
+    lora.get_tensors(&mut saved)?;
+    assert!(max_abs_diff(&saved["lora.m0.weight"], &m)? < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn dora_conv2d_matches_its_merged_weight() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (6, 3, 3, 3), &device)?;
+    let conv_config = Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let base = Conv2d::new(weight.clone(), None, conv_config);
+    let vb = trainable_adapter_vb(
+        [
+            (
+                "lora.a0.weight",
+                Tensor::randn(0f32, 1., (2, 3, 3, 3), &device)?,
+            ),
+            (
+                "lora.b0.weight",
+                Tensor::randn(0f32, 1., (6, 2, 1, 1), &device)?,
+            ),
+            ("lora.m0.weight", Tensor::rand(1f32, 2., 6, &device)?),
+        ],
+        DType::F32,
+        &device,
+    )?;
+    let mut lora = LoraConv2d::new(
+        &base,
+        &LoraConv2dConfig::new(3, 6).with_dora(),
+        &LoraConfig::new(2, 2., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (1, 3, 5, 5), &device)?;
+    let delta = lora.get_delta_weight().unwrap();
+    let merged_weight = (&weight + delta)?;
+    let expected = Conv2d::new(merged_weight, None, conv_config).forward(&xs)?;
+    let unmerged = lora.forward(&xs)?;
+    assert!(max_abs_diff(&unmerged, &expected)? < 1e-4);
+
+    lora.merge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &unmerged)? < 1e-4);
+    lora.unmerge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &unmerged)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn peft_conversions_keep_magnitudes() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_dora_peft.safetensors");
+    let out_path = dir.join("candle_lora_dora_out.safetensors");
+    let back_path = dir.join("candle_lora_dora_back.safetensors");
+
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let magnitude = Tensor::rand(1f32, 2., 8, &device)?;
+    let mut peft = HashMap::new();
+    peft.insert(
+        format!("{module}.lora_A.weight"),
+        Tensor::randn(0f32, 1., (4, 16), &device)?,
+    );
+    peft.insert(
+        format!("{module}.lora_B.weight"),
+        Tensor::randn(0f32, 1., (8, 4), &device)?,
+    );
+    peft.insert(format!("{module}.lora_magnitude_vector"), magnitude.clone());
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    let manifest = convert_peft_to_candle_lora_with_manifest(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(
+        manifest.entries[0].magnitude.as_deref(),
+        Some("lora_llama.m0.weight")
+    );
+    assert_eq!(manifest.peft_module("lora_llama.m0.weight"), Some(module));
+    manifest.verify(&converted)?;
+    assert!(max_abs_diff(&converted["lora_llama.m0.weight"], &magnitude)? < 1e-6);
+
+    convert_candle_lora_to_peft(
+        out_path.to_str().unwrap(),
+        back_path.to_str().unwrap(),
+        "lora_llama",
+        &["model.layers.0.self_attn.q_proj".to_string()],
+        &device,
+    )?;
+    let back = candle_core::safetensors::load(&back_path, &device)?;
+    assert_eq!(back.len(), 3);
+    assert!(
+        max_abs_diff(
+            &back[&format!("{module}.lora_magnitude_vector")],
+            &magnitude
+        )? < 1e-6
+    );
+
+    // Merging the DoRA adapter gives each output row its magnitude as norm.
+    let mut base = HashMap::new();
+    base.insert(
+        "model.layers.0.self_attn.q_proj.weight".to_string(),
+        Tensor::randn(0f32, 1., (8, 16), &device)?,
+    );
+    let merged = merge_peft_adapter(&base, &peft, 16.)?;
+    let norms = row_norms(&merged["model.layers.0.self_attn.q_proj.weight"])?;
+    assert!(max_abs_diff(&norms, &magnitude)? < 1e-4);
+
+    for path in [peft_path, out_path, back_path] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    disable_adapters, dpo_loss, DpoBatch, DpoConfig, DpoTrainer, Lora, LoraConfig,
+    LoraLinearConfig, LoraTrainer, NewLayers, SelectedLayersBuilder, TrainingConfig,
+};
+use candle_nn::{AdamW, Linear, ParamsAdamW, VarBuilder, VarMap};
+
+#[derive(PartialEq, Eq, Hash)]
+enum ModelLayers {
+    Head,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "head")
+    }
+}
+
+/// A LoRA linear head over fixed token features, `(batch, seq_len)` ids to logits.
+fn lora_head(varmap: &VarMap, device: &Device) -> Result<(Tensor, impl Module)> {
+    let features = Tensor::randn(0f32, 1., (4, 8), device)?;
+    let base = Linear::new(Tensor::randn(0f32, 1., (4, 8), device)?, None);
+    let mut linear_layers = HashMap::new();
+    linear_layers.insert(
+        ModelLayers::Head,
+        &base as &dyn candle_lora::LinearLayerLike,
+    );
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(linear_layers, LoraLinearConfig::new(8, 4))
+        .build();
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, device);
+    let NewLayers { mut linear, .. } =
+        Lora::convert_model(selected, LoraConfig::new(2, 4., None), &vb)?;
+    Ok((features, linear.remove(&ModelLayers::Head).unwrap()))
+}
+
+#[test]
+fn dpo_loss_without_preference_is_ln2() -> Result<()> {
+    let device = Device::Cpu;
+    let logps = Tensor::new(&[-3f32, -1.5], &device)?;
+    let output = dpo_loss(&logps, &logps, &logps, &logps, &DpoConfig::default())?;
+    let loss = output.loss.to_scalar::<f32>()?;
+    assert!((loss - std::f32::consts::LN_2).abs() < 1e-6);
+    assert_eq!(output.reward_margin()?, 0.);
+    Ok(())
+}
+
+#[test]
+fn disabled_adapters_give_base_outputs() -> Result<()> {
+    let device = Device::Cpu;
+    let mut varmap = VarMap::new();
+    let (features, head) = lora_head(&varmap, &device)?;
+    let base = head.forward(&features)?;
+    varmap.set_one("b0.weight", Tensor::ones((4, 2), DType::F32, &device)?)?;
+
+    let adapted = head.forward(&features)?;
+    let disabled = {
+        let _disabled = disable_adapters();
+        head.forward(&features)?
+    };
+    let restored = head.forward(&features)?;
+
+    let diff = |u: &Tensor, v: &Tensor| -> Result<f32> { (u - v)?.abs()?.max_all()?.to_scalar() };
+    assert_eq!(diff(&base, &disabled)?, 0.);
+    assert!(diff(&base, &adapted)? > 0.);
+    assert_eq!(diff(&adapted, &restored)?, 0.);
+    Ok(())
+}
+
+#[test]
+fn dpo_training_prefers_chosen() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let (features, head) = lora_head(&varmap, &device)?;
+    let mut forward = |ids: &Tensor| -> Result<Tensor> {
+        let (b, t) = ids.dims2()?;
+        let xs = features.embedding(&ids.flatten_all()?)?;
+        head.forward(&xs)?.reshape((b, t, 4))
+    };
+
+    let ids = |tokens: &[u32]| Tensor::new(tokens, &device)?.unsqueeze(0);
+    let mask = Tensor::new(&[[0f32, 1., 1.]], &device)?;
+    let batch = DpoBatch {
+        chosen_ids: ids(&[0, 1, 2])?,
+        chosen_mask: mask.clone(),
+        rejected_ids: ids(&[0, 3, 3])?,
+        rejected_mask: mask,
+    };
+
+    let config = TrainingConfig {
+        learning_rate: 0.05,
+        max_grad_norm: Some(1.),
+        ..Default::default()
+    };
+    let trainer = LoraTrainer::<AdamW>::new(varmap.all_vars(), ParamsAdamW::default(), config)?;
+    let mut trainer = DpoTrainer::new(trainer, DpoConfig::default());
+
+    let first = trainer.evaluate(&mut forward, &batch)?;
+    assert!((first.loss.to_scalar::<f32>()? - std::f32::consts::LN_2).abs() < 1e-5);
+    trainer.train(
+        &mut forward,
+        std::slice::from_ref(&batch),
+        20,
+        |_, _| Ok(()),
+    )?;
+    let last = trainer.evaluate(&mut forward, &batch)?;
+    assert!(last.loss.to_scalar::<f32>()? < first.loss.to_scalar::<f32>()?);
+    assert_eq!(last.reward_accuracy()?, 1.);
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    LinearLayerLike, Lora, LoraConfig, LoraLinear, LoraLinearConfig, SelectedLayersBuilder,
+};
+use candle_nn::{init, Linear, VarBuilder, VarMap};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Proj,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "proj")
+    }
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+}
+
+#[test]
+fn dropout_applies_in_training_mode_only() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (16, 16), &device)?, None);
+    let xs = Tensor::randn(0f32, 1., (4, 16), &device)?;
+    let config = LoraConfig::new(4, 8., Some(0.5));
+
+    // A trainable layer starts in training mode, with a non-zero B so the adapter matters.
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    vb.pp("b0")
+        .get_with_hints((16, 4), "weight", init::Init::Const(0.5))?;
+    let layers = HashMap::from([(ModelLayers::Proj, &base as &dyn LinearLayerLike)]);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(16, 16))
+        .build();
+    let mut new = Lora::convert_model(selected, config.clone(), &vb)?;
+    let layer = &new.linear[&ModelLayers::Proj];
+    assert!(layer.is_training());
+    assert!(max_diff(&layer.forward(&xs)?, &layer.forward(&xs)?)? > 0.);
+
+    // In inference mode the outputs are deterministic.
+    Lora::train(&mut new, false);
+    let layer = &new.linear[&ModelLayers::Proj];
+    assert!(!layer.is_training());
+    assert_eq!(max_diff(&layer.forward(&xs)?, &layer.forward(&xs)?)?, 0.);
+
+    // Layers loaded from files start in inference mode.
+    let tensors = HashMap::from([
+        (
+            "a0.weight".to_string(),
+            Tensor::randn(0f32, 1., (4, 16), &device)?,
+        ),
+        (
+            "b0.weight".to_string(),
+            Tensor::randn(0f32, 1., (16, 4), &device)?,
+        ),
+    ]);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let mut loaded = LoraLinear::new(&base, &LoraLinearConfig::new(16, 16), &config, &vb, 0)?;
+    assert!(!loaded.is_training());
+    let eval = loaded.forward(&xs)?;
+    assert_eq!(max_diff(&eval, &loaded.forward(&xs)?)?, 0.);
+    loaded.train(true);
+    assert!(max_diff(&eval, &loaded.forward(&xs)?)? > 0.);
+    Ok(())
+}
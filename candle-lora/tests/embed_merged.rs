@@ -16,6 +16,12 @@ fn embed() -> candle_core::Result<()> {
         Embed,
     }
 
+    impl std::fmt::Display for ModelLayers {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "embed")
+        }
+    }
+
     #[derive(Debug)]
     struct Model {
         embed: Arc<dyn EmbeddingLayerLike>,
@@ -78,7 +84,7 @@ fn embed() -> candle_core::Result<()> {
     let loraconfig = LoraConfig::new(1, 1., None);
 
     //Create new LoRA layers from our layers
-    let new_layers = Lora::convert_model(selected, loraconfig, &vb);
+    let new_layers = Lora::convert_model(selected, loraconfig, &vb)?;
 
     //Custom methods to implement
     model.insert_new(new_layers);
@@ -0,0 +1,179 @@
+//! Conversion and merging of the PEFT adapters of embeddings, `lora_embedding_A` of
+//! `(rank, num_embeddings)` and `lora_embedding_B` of `(embedding_dim, rank)`.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    convert_candle_lora_to_peft, convert_peft_dir_to_candle_lora_typed,
+    convert_peft_to_candle_lora, merge_peft_adapter, validate_adapter, BaseModelConfig, LoraConfig,
+    LoraEmbedding, LoraEmbeddingConfig,
+};
+use candle_nn::{Embedding, VarBuilder};
+
+mod common;
+use common::max_abs_diff;
+
+const EMBED: &str = "model.embed_tokens";
+const Q_PROJ: &str = "model.layers.0.self_attn.q_proj";
+
+/// A PEFT adapter of rank 4 of a `(vocab_size, hidden_size)` embedding and a q_proj.
+fn peft_adapter(
+    vocab_size: usize,
+    hidden_size: usize,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let tensor = |shape: (usize, usize)| Tensor::randn(0f32, 1., shape, device);
+    Ok(HashMap::from([
+        (
+            format!("base_model.model.{EMBED}.lora_embedding_A"),
+            tensor((4, vocab_size))?,
+        ),
+        (
+            format!("base_model.model.{EMBED}.lora_embedding_B"),
+            tensor((hidden_size, 4))?,
+        ),
+        (
+            format!("base_model.model.{Q_PROJ}.lora_A.weight"),
+            tensor((4, hidden_size))?,
+        ),
+        (
+            format!("base_model.model.{Q_PROJ}.lora_B.weight"),
+            tensor((hidden_size, 4))?,
+        ),
+    ]))
+}
+
+#[test]
+fn embedding_adapters_convert_and_load() -> Result<()> {
+    let device = Device::Cpu;
+    let (vocab_size, hidden_size) = (32, 8);
+    let peft = peft_adapter(vocab_size, hidden_size, &device)?;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_embedding_peft.safetensors");
+    let candle_path = dir.join("candle_lora_embedding_candle.safetensors");
+    let back_path = dir.join("candle_lora_embedding_back.safetensors");
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        candle_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+    // The embedding is indexed after the linear layers, as `Lora::convert_model` does.
+    let converted = candle_core::safetensors::load(&candle_path, &device)?;
+    assert_eq!(converted.len(), 4);
+    let embedding_a = &peft[&format!("base_model.model.{EMBED}.lora_embedding_A")];
+    let embedding_b = &peft[&format!("base_model.model.{EMBED}.lora_embedding_B")];
+    assert_eq!(
+        max_abs_diff(&converted["lora_llama.a1.weight"], embedding_a)?,
+        0.
+    );
+    assert_eq!(
+        max_abs_diff(&converted["lora_llama.b1.weight"], embedding_b)?,
+        0.
+    );
+
+    // The converted embedding adds the rows of the transposed delta.
+    let weight = Tensor::randn(0f32, 1., (vocab_size, hidden_size), &device)?;
+    let base = Embedding::new(weight.clone(), hidden_size);
+    let vb = VarBuilder::from_tensors(converted, DType::F32, &device);
+    let lora = LoraEmbedding::new(
+        &base,
+        &LoraEmbeddingConfig::new(vocab_size, hidden_size),
+        &LoraConfig::new(4, 8., None),
+        &vb.pp("lora_llama"),
+        1,
+    )?;
+    let delta = embedding_b.matmul(embedding_a)?.t()?.affine(2., 0.)?;
+    let ids = Tensor::new(&[0u32, 5, 31], &device)?;
+    let expected = (weight + delta)?.index_select(&ids, 0)?;
+    assert!(max_abs_diff(&lora.forward(&ids)?, &expected)? < 1e-5);
+
+    // Back to the PEFT names of embeddings.
+    convert_candle_lora_to_peft(
+        candle_path.to_str().unwrap(),
+        back_path.to_str().unwrap(),
+        "lora_llama",
+        &[Q_PROJ.to_string(), EMBED.to_string()],
+        &device,
+    )?;
+    let back = candle_core::safetensors::load(&back_path, &device)?;
+    assert_eq!(back.len(), peft.len());
+    for (name, tensor) in &peft {
+        assert_eq!(max_abs_diff(&back[name], tensor)?, 0., "{name}");
+    }
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&candle_path)?;
+    std::fs::remove_file(&back_path)?;
+    Ok(())
+}
+
+#[test]
+fn square_embeddings_merge_transposed() -> Result<()> {
+    let device = Device::Cpu;
+    let peft = peft_adapter(8, 8, &device)?;
+    let embed_weight = Tensor::randn(0f32, 1., (8, 8), &device)?;
+    let q_weight = Tensor::randn(0f32, 1., (8, 8), &device)?;
+    let base = HashMap::from([
+        (format!("{EMBED}.weight"), embed_weight.clone()),
+        (format!("{Q_PROJ}.weight"), q_weight.clone()),
+    ]);
+
+    let merged = merge_peft_adapter(&base, &peft, 8.)?;
+    let delta = |a: &str, b: &str| {
+        peft[&format!("base_model.model.{b}")]
+            .matmul(&peft[&format!("base_model.model.{a}")])?
+            .affine(2., 0.)
+    };
+    let embed_delta = delta(
+        &format!("{EMBED}.lora_embedding_A"),
+        &format!("{EMBED}.lora_embedding_B"),
+    )?;
+    let q_delta = delta(
+        &format!("{Q_PROJ}.lora_A.weight"),
+        &format!("{Q_PROJ}.lora_B.weight"),
+    )?;
+    let expected = (embed_weight + embed_delta.t()?)?;
+    assert!(max_abs_diff(&merged[&format!("{EMBED}.weight")], &expected)? < 1e-5);
+    let expected = (q_weight + q_delta)?;
+    assert!(max_abs_diff(&merged[&format!("{Q_PROJ}.weight")], &expected)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn real_embeddings_are_kept_over_dummies() -> Result<()> {
+    let device = Device::Cpu;
+    let (vocab_size, hidden_size) = (32, 8);
+    let peft = peft_adapter(vocab_size, hidden_size, &device)?;
+    let dir = std::env::temp_dir().join("candle_lora_embedding_typed");
+    std::fs::create_dir_all(&dir)?;
+    candle_core::safetensors::save(&peft, dir.join("adapter_model.safetensors"))?;
+    std::fs::write(
+        dir.join("adapter_config.json"),
+        r#"{"peft_type": "LORA", "r": 4, "lora_alpha": 8, "target_modules": ["q_proj", "embed_tokens"]}"#,
+    )?;
+    let out_path = dir.join("converted.safetensors");
+
+    convert_peft_dir_to_candle_lora_typed(
+        dir.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        &device,
+        Some((vocab_size, hidden_size)),
+    )?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let embedding_a = &peft[&format!("base_model.model.{EMBED}.lora_embedding_A")];
+    assert_eq!(
+        max_abs_diff(&converted["lora_llama.a0.weight"], embedding_a)?,
+        0.
+    );
+    assert!(converted.contains_key("lora_llama_csa.a0.weight"));
+    std::fs::remove_dir_all(&dir)?;
+
+    let config = BaseModelConfig::llama(hidden_size, 16, 2, 2, vocab_size).with_dtype(DType::F32);
+    let report = validate_adapter(&config, &peft);
+    assert_eq!(report.num_modules, 2);
+    assert!(report.is_valid(), "{:?}", report.issues);
+    Ok(())
+}
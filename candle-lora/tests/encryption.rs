@@ -0,0 +1,70 @@
+#![cfg(feature = "encryption")]
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    encrypt_adapter_file, load_encrypted, save_encrypted, save_with_fingerprint,
+    BaseModelFingerprint, FINGERPRINT_METADATA_KEY,
+};
+
+fn adapter(device: &Device) -> Result<HashMap<String, Tensor>> {
+    Ok(HashMap::from([
+        (
+            "lora.a0.weight".to_string(),
+            Tensor::arange(0f32, 8., device)?.reshape((2, 4))?,
+        ),
+        (
+            "lora.b0.weight".to_string(),
+            Tensor::zeros((4, 2), DType::F32, device)?,
+        ),
+    ]))
+}
+
+#[test]
+fn encrypted_adapters_round_trip() -> Result<()> {
+    let device = Device::Cpu;
+    let path = std::env::temp_dir().join("candle_lora_encrypted.safetensors.enc");
+    let key = [7u8; 32];
+    save_encrypted(&adapter(&device)?, &key, &path)?;
+
+    // Nothing readable at rest.
+    let at_rest = std::fs::read(&path)?;
+    assert!(candle_core::safetensors::load_buffer(&at_rest, &device).is_err());
+    assert!(!at_rest.windows(6).any(|w| w == b"lora.a"));
+
+    let loaded = load_encrypted(&path, &key, &device)?;
+    assert_eq!(
+        loaded["lora.a0.weight"].to_vec2::<f32>()?,
+        [[0., 1., 2., 3.], [4., 5., 6., 7.]]
+    );
+    assert!(load_encrypted(&path, &[8u8; 32], &device).is_err());
+
+    // A tampered payload fails authentication.
+    let mut tampered = at_rest.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    std::fs::write(&path, tampered)?;
+    assert!(load_encrypted(&path, &key, &device).is_err());
+    Ok(())
+}
+
+#[test]
+fn encrypting_a_file_keeps_its_metadata() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let plain = dir.join("candle_lora_encrypt_input.safetensors");
+    let encrypted = dir.join("candle_lora_encrypt_output.safetensors.enc");
+    let key = [1u8; 32];
+    let fingerprint = BaseModelFingerprint::from_config("{}")?;
+    save_with_fingerprint(&adapter(&device)?, &fingerprint, &plain)?;
+    encrypt_adapter_file(&plain, &encrypted, &key)?;
+
+    let data = candle_lora::decrypt_safetensors(&std::fs::read(&encrypted)?, &key)?;
+    let (_, header) = safetensors::SafeTensors::read_metadata(&data).unwrap();
+    assert_eq!(
+        header.metadata().as_ref().unwrap()[FINGERPRINT_METADATA_KEY],
+        fingerprint.to_string()
+    );
+    Ok(())
+}
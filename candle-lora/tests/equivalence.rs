@@ -0,0 +1,114 @@
+#![cfg(feature = "equivalence")]
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{LoraConfig, LoraLinear, LoraLinearConfig, ReferenceOutputs, Tolerance};
+use candle_nn::{Linear, VarBuilder};
+
+/// A stack of two adapted linear layers: the PEFT tensors and the reference outputs computed
+/// as PEFT does, `W x + alpha / r * B A x`.
+fn reference(device: &Device) -> Result<(Vec<Tensor>, HashMap<String, Tensor>, ReferenceOutputs)> {
+    let weights = (0..2)
+        .map(|_| Tensor::randn(0f32, 0.5, (6, 6), device))
+        .collect::<Result<Vec<_>>>()?;
+    let mut adapter = HashMap::new();
+    let input_ids = Tensor::new(&[[3i64, 1, 4, 1]], device)?;
+    let mut hidden = Tensor::randn(0f32, 1., (1, 4, 6), device)?;
+    let mut outputs = HashMap::from([
+        ("input_ids".to_string(), input_ids),
+        ("hidden_states.0".to_string(), hidden.clone()),
+    ]);
+    for (i, weight) in weights.iter().enumerate() {
+        let a = Tensor::randn(0f32, 1., (2, 6), device)?;
+        let b = Tensor::randn(0f32, 1., (6, 2), device)?;
+        let lora = hidden
+            .broadcast_matmul(&a.t()?)?
+            .broadcast_matmul(&b.t()?)?;
+        hidden = (hidden.broadcast_matmul(&weight.t()?)? + (lora * 2.)?)?;
+        outputs.insert(format!("hidden_states.{}", i + 1), hidden.clone());
+        adapter.insert(format!("lora.a{i}.weight"), a);
+        adapter.insert(format!("lora.b{i}.weight"), b);
+    }
+    let logits = hidden.sum(2)?;
+    outputs.insert("logits".to_string(), logits);
+    Ok((weights, adapter, ReferenceOutputs::from_tensors(outputs)))
+}
+
+/// The outputs of the candle-lora layers, with the given alpha for each layer.
+fn outputs(
+    weights: &[Tensor],
+    adapter: &HashMap<String, Tensor>,
+    reference: &ReferenceOutputs,
+    alphas: [f64; 2],
+) -> Result<Vec<(String, Tensor)>> {
+    let device = Device::Cpu;
+    let vb = VarBuilder::from_tensors(adapter.clone(), DType::F32, &device);
+    let mut hidden = reference.hidden_states()[0].clone();
+    let mut outputs = vec![("hidden_states.0".to_string(), hidden.clone())];
+    for (i, (weight, alpha)) in weights.iter().zip(alphas).enumerate() {
+        let layer = LoraLinear::new(
+            &Linear::new(weight.clone(), None),
+            &LoraLinearConfig::new(6, 6),
+            &LoraConfig::new(2, alpha, None),
+            &vb.pp("lora"),
+            i,
+        )?;
+        hidden = layer.forward(&hidden)?;
+        outputs.push((format!("hidden_states.{}", i + 1), hidden.clone()));
+    }
+    outputs.push(("logits".to_string(), hidden.sum(2)?));
+    Ok(outputs)
+}
+
+#[test]
+fn matching_outputs_are_equivalent() -> Result<()> {
+    let device = Device::Cpu;
+    let (weights, adapter, reference) = reference(&device)?;
+    assert_eq!(reference.hidden_states().len(), 3);
+    assert_eq!(reference.input_ids()?.to_vec2::<u32>()?, [[3, 1, 4, 1]]);
+
+    let outputs = outputs(&weights, &adapter, &reference, [4., 4.])?;
+    let report = reference.compare(&outputs, Tolerance::default())?;
+    assert!(report.is_equivalent(), "{report}");
+    assert_eq!(report.outputs.len(), 4);
+    assert!(report.outputs.iter().all(|output| output.max_abs < 1e-4));
+    report.into_result()
+}
+
+#[test]
+fn the_first_divergent_layer_is_reported() -> Result<()> {
+    let device = Device::Cpu;
+    let (weights, adapter, reference) = reference(&device)?;
+    // A wrong alpha in the second layer only.
+    let outputs = outputs(&weights, &adapter, &reference, [4., 8.])?;
+    let report = reference.compare(&outputs, Tolerance::default())?;
+    assert!(!report.is_equivalent());
+    let divergence = report.first_divergence().unwrap();
+    assert_eq!(divergence.name, "hidden_states.2");
+    assert!(divergence.max_abs > 1e-2);
+    assert!(divergence.num_mismatched > 0);
+    assert!(report.outputs[1].is_within_tolerance());
+    assert!(report.to_string().contains("hidden_states.2: max abs"));
+    let err = report.into_result().unwrap_err();
+    assert!(err.to_string().contains("hidden_states.2"), "{err}");
+
+    // A loose enough tolerance accepts it.
+    let report = reference.compare(&outputs, Tolerance::new(1e3, 0.))?;
+    assert!(report.is_equivalent());
+    Ok(())
+}
+
+#[test]
+fn outputs_need_a_reference_of_their_shape() -> Result<()> {
+    let device = Device::Cpu;
+    let (_, _, reference) = reference(&device)?;
+    let output = Tensor::zeros((1, 4, 6), DType::F32, &device)?;
+    let missing = [("hidden_states.7".to_string(), output.clone())];
+    assert!(reference.compare(&missing, Tolerance::default()).is_err());
+    let wrong_shape = [("logits".to_string(), output)];
+    assert!(reference
+        .compare(&wrong_shape, Tolerance::default())
+        .is_err());
+    Ok(())
+}
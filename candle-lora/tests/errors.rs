@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use candle_core::{Device, Result, Tensor};
+use candle_lora::{
+    convert_peft_dir_to_candle_lora, convert_peft_to_candle_lora_with_options, load_peft_adapter,
+    load_peft_weights, ConvertOptions, LoraError, NewLayers,
+};
+
+const MODULE: &str = "base_model.model.model.layers.0.self_attn.q_proj";
+
+/// A PEFT directory holding `tensors`, and `config` as `adapter_config.json` if any.
+fn peft_dir(
+    name: &str,
+    tensors: &HashMap<String, Tensor>,
+    config: Option<&str>,
+) -> Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    if !tensors.is_empty() {
+        candle_core::safetensors::save(tensors, dir.join("adapter_model.safetensors"))?;
+    }
+    if let Some(config) = config {
+        std::fs::write(dir.join("adapter_config.json"), config)?;
+    }
+    Ok(dir)
+}
+
+fn lora_pair(rank_a: usize, rank_b: usize) -> Result<HashMap<String, Tensor>> {
+    let device = Device::Cpu;
+    Ok(HashMap::from([
+        (
+            format!("{MODULE}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (rank_a, 8), &device)?,
+        ),
+        (
+            format!("{MODULE}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (8, rank_b), &device)?,
+        ),
+    ]))
+}
+
+#[test]
+fn missing_files_are_reported_by_kind() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = peft_dir("candle_lora_errors_missing", &HashMap::new(), None)?;
+    let dir_str = dir.to_str().unwrap();
+    match load_peft_weights(dir_str, &device) {
+        Err(LoraError::MissingAdapterFile { dir }) => assert_eq!(dir, dir_str),
+        other => panic!("expected MissingAdapterFile, got {other:?}"),
+    }
+
+    let dir = peft_dir("candle_lora_errors_missing", &lora_pair(4, 4)?, None)?;
+    let mut new = NewLayers::<String> {
+        linear: HashMap::new(),
+        conv1d: HashMap::new(),
+        conv2d: HashMap::new(),
+        embed: HashMap::new(),
+    };
+    match load_peft_adapter(dir.to_str().unwrap(), &mut new, &device) {
+        Err(LoraError::MissingAdapterConfig { .. }) => {}
+        other => panic!("expected MissingAdapterConfig, got {other:?}"),
+    }
+
+    std::fs::write(dir.join("adapter_config.json"), "{")?;
+    match load_peft_adapter(dir.to_str().unwrap(), &mut new, &device) {
+        Err(LoraError::InvalidJson { path, .. }) => {
+            assert_eq!(path, dir.join("adapter_config.json"))
+        }
+        other => panic!("expected InvalidJson, got {other:?}"),
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn config_and_rank_errors_are_reported_by_kind() -> Result<()> {
+    let device = Device::Cpu;
+    let output = std::env::temp_dir().join("candle_lora_errors_config.safetensors");
+    let output = output.to_str().unwrap();
+
+    let config = r#"{"peft_type": "IA3", "r": 4, "lora_alpha": 8, "target_modules": ["q_proj"]}"#;
+    let dir = peft_dir("candle_lora_errors_config", &lora_pair(4, 4)?, Some(config))?;
+    match convert_peft_dir_to_candle_lora(dir.to_str().unwrap(), output, "lora", &device) {
+        Err(LoraError::UnsupportedPeftType { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("LORA", "IA3"))
+        }
+        other => panic!("expected UnsupportedPeftType, got {other:?}"),
+    }
+
+    let config = r#"{"peft_type": "LORA", "r": 8, "lora_alpha": 8, "target_modules": ["q_proj"]}"#;
+    let dir = peft_dir("candle_lora_errors_config", &lora_pair(4, 4)?, Some(config))?;
+    match convert_peft_dir_to_candle_lora(dir.to_str().unwrap(), output, "lora", &device) {
+        Err(LoraError::ConfigRankMismatch { r, ranks }) => assert_eq!((r, ranks), (8, vec![4])),
+        other => panic!("expected ConfigRankMismatch, got {other:?}"),
+    }
+
+    let dir = peft_dir("candle_lora_errors_config", &lora_pair(4, 2)?, None)?;
+    let peft_path = dir.join("adapter_model.safetensors");
+    let options = ConvertOptions::new();
+    match convert_peft_to_candle_lora_with_options(
+        peft_path.to_str().unwrap(),
+        output,
+        "lora",
+        &options,
+    ) {
+        Err(LoraError::RankMismatch {
+            layer,
+            expected,
+            found,
+        }) => assert_eq!((layer.as_str(), expected, found), (MODULE, 4, 2)),
+        other => panic!("expected RankMismatch, got {other:?}"),
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn strict_conversion_reports_unpaired_and_unconverted_tensors() -> Result<()> {
+    let device = Device::Cpu;
+    let output = std::env::temp_dir().join("candle_lora_errors_strict.safetensors");
+    let output = output.to_str().unwrap();
+    let mut tensors = lora_pair(4, 4)?;
+    tensors.insert(
+        format!("{MODULE}.bias"),
+        Tensor::zeros(8, candle_core::DType::F32, &device)?,
+    );
+    let dir = peft_dir("candle_lora_errors_strict", &tensors, None)?;
+    let peft_path = dir.join("adapter_model.safetensors");
+    let peft_path = peft_path.to_str().unwrap();
+
+    let strict = ConvertOptions::new().with_strict(true);
+    match convert_peft_to_candle_lora_with_options(peft_path, output, "lora", &strict) {
+        Err(LoraError::UnconvertedTensors { names }) => {
+            assert_eq!(names, [format!("{MODULE}.bias")])
+        }
+        other => panic!("expected UnconvertedTensors, got {other:?}"),
+    }
+
+    let unpaired = "base_model.model.model.layers.1.self_attn.q_proj.lora_A.weight".to_string();
+    tensors.insert(
+        unpaired.clone(),
+        Tensor::zeros((4, 8), candle_core::DType::F32, &device)?,
+    );
+    let dir = peft_dir("candle_lora_errors_strict", &tensors, None)?;
+    match convert_peft_to_candle_lora_with_options(peft_path, output, "lora", &strict) {
+        Err(LoraError::UnpairedTensor { name }) => assert_eq!(name, unpaired),
+        other => panic!("expected UnpairedTensor, got {other:?}"),
+    }
+
+    // Out of strict mode, both are skipped.
+    let report = convert_peft_to_candle_lora_with_options(
+        peft_path,
+        output,
+        "lora",
+        &ConvertOptions::new(),
+    )?;
+    assert_eq!(report.skipped.len(), 2);
+    std::fs::remove_dir_all(&dir)?;
+    std::fs::remove_file(output)?;
+    Ok(())
+}
+
+#[test]
+fn lora_errors_convert_to_candle_errors() -> Result<()> {
+    let dir = std::env::temp_dir().join("candle_lora_errors_candle");
+    let load = || -> Result<HashMap<String, Tensor>> {
+        Ok(load_peft_weights(dir.to_str().unwrap(), &Device::Cpu)?)
+    };
+    let err = load().unwrap_err();
+    assert!(
+        err.to_string().starts_with("no adapter weights found in"),
+        "{err}"
+    );
+
+    // Wrapped candle errors are unwrapped.
+    let err: candle_core::Error = LoraError::Candle(candle_core::Error::UnwrapNone).into();
+    assert!(matches!(err, candle_core::Error::UnwrapNone));
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{extract_lora_from_diff, merge_peft_adapter};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn low_rank_differences_are_extracted_exactly() -> Result<()> {
+    let device = Device::Cpu;
+    let randn = |shape: (usize, usize)| Tensor::randn(0f32, 1., shape, &device);
+    let q_proj = "model.layers.0.self_attn.q_proj.weight";
+    let v_proj = "model.layers.0.self_attn.v_proj.weight";
+    let embed = "model.embed_tokens.weight";
+    let norm = "model.norm.weight";
+    let base = HashMap::from([
+        (q_proj.to_string(), randn((24, 16))?),
+        (v_proj.to_string(), randn((8, 16))?),
+        (embed.to_string(), randn((40, 16))?),
+        (norm.to_string(), Tensor::ones(16, DType::F32, &device)?),
+    ]);
+    let mut finetuned = base.clone();
+    // Differences of rank 3 and 2, and a changed norm.
+    let q_delta = randn((24, 3))?.matmul(&randn((3, 16))?)?;
+    let embed_delta = randn((40, 2))?.matmul(&randn((2, 16))?)?;
+    finetuned.insert(q_proj.to_string(), (&base[q_proj] + &q_delta)?);
+    finetuned.insert(embed.to_string(), (&base[embed] + &embed_delta)?);
+    finetuned.insert(norm.to_string(), (&base[norm] * 2.)?);
+
+    let adapter = extract_lora_from_diff(&base, &finetuned, 4)?;
+    let mut names = adapter.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "base_model.model.model.embed_tokens.lora_embedding_A",
+            "base_model.model.model.embed_tokens.lora_embedding_B",
+            "base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight",
+            "base_model.model.model.layers.0.self_attn.q_proj.lora_B.weight",
+        ]
+    );
+    let q_a = &adapter["base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight"];
+    assert_eq!(q_a.dims(), &[4, 16]);
+    let embed_a = &adapter["base_model.model.model.embed_tokens.lora_embedding_A"];
+    assert_eq!(embed_a.dims(), &[4, 40]);
+
+    // Merged with lora_alpha = rank, the adapter gives back the finetuned weights.
+    let merged = merge_peft_adapter(&base, &adapter, 4.)?;
+    for name in [q_proj, v_proj, embed] {
+        assert!(
+            max_abs_diff(&merged[name], &finetuned[name])? < 1e-4,
+            "{name}"
+        );
+    }
+    assert_eq!(max_abs_diff(&merged[norm], &base[norm])?, 0.);
+    Ok(())
+}
+
+#[test]
+fn truncation_keeps_the_largest_singular_values() -> Result<()> {
+    let device = Device::Cpu;
+    let singular_values = [10f32, 5., 2., 1., 0.5, 0.25];
+    let mut diff = vec![0f32; 12 * 8];
+    for (i, s) in singular_values.iter().enumerate() {
+        diff[i * 8 + i] = *s;
+    }
+    let name = "model.layers.0.mlp.up_proj.weight".to_string();
+    let base = HashMap::from([(name.clone(), Tensor::zeros((12, 8), DType::F32, &device)?)]);
+    let finetuned = HashMap::from([(name.clone(), Tensor::from_vec(diff, (12, 8), &device)?)]);
+
+    let adapter = extract_lora_from_diff(&base, &finetuned, 2)?;
+    let merged = merge_peft_adapter(&base, &adapter, 2.)?;
+    // The error of the best approximation of rank 2 is that of the other singular values.
+    let error = (&merged[&name] - &finetuned[&name])?
+        .sqr()?
+        .sum_all()?
+        .sqrt()?
+        .to_scalar::<f32>()?;
+    let expected = singular_values[2..]
+        .iter()
+        .map(|s| s * s)
+        .sum::<f32>()
+        .sqrt();
+    assert!((error - expected).abs() < 1e-3, "{error} {expected}");
+    Ok(())
+}
+
+#[test]
+fn mismatched_models_are_rejected() -> Result<()> {
+    let device = Device::Cpu;
+    let name = "lm_head.weight".to_string();
+    let base = HashMap::from([(name.clone(), Tensor::zeros((8, 4), DType::F32, &device)?)]);
+    let finetuned = HashMap::from([(name.clone(), Tensor::ones((9, 4), DType::F32, &device)?)]);
+    assert!(extract_lora_from_diff(&base, &finetuned, 2).is_err());
+    assert!(extract_lora_from_diff(&base, &base, 2).is_err());
+    let finetuned = HashMap::from([(name, Tensor::ones((8, 4), DType::F32, &device)?)]);
+    assert!(extract_lora_from_diff(&base, &finetuned, 0).is_err());
+    assert!(extract_lora_from_diff(&base, &finetuned, 2).is_ok());
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    convert_peft_to_candle_lora, load_verified, read_fingerprint, save_with_fingerprint,
+    stamp_fingerprint, BaseModelFingerprint,
+};
+
+fn base_model(value: f32, device: &Device) -> Result<HashMap<String, Tensor>> {
+    Ok(HashMap::from([
+        (
+            "layers.0.weight".to_string(),
+            Tensor::full(value, (4, 4), device)?,
+        ),
+        (
+            "layers.1.weight".to_string(),
+            Tensor::ones((4, 4), DType::F32, device)?,
+        ),
+    ]))
+}
+
+#[test]
+fn fingerprints_tell_base_models_apart() -> Result<()> {
+    let device = Device::Cpu;
+    let base = BaseModelFingerprint::from_tensors(&base_model(0.5, &device)?);
+    assert!(base.as_str().starts_with("sha256:"));
+    assert_eq!(
+        base,
+        BaseModelFingerprint::from_tensors(&base_model(0.5, &device)?)
+    );
+    assert_ne!(
+        base,
+        BaseModelFingerprint::from_tensors(&base_model(0.25, &device)?)
+    );
+
+    assert_eq!(
+        BaseModelFingerprint::from_config(r#"{"hidden_size": 64, "num_layers": 2}"#)?,
+        BaseModelFingerprint::from_config("{\"num_layers\":2,\n \"hidden_size\":64}")?
+    );
+    assert!(BaseModelFingerprint::from_config("not json").is_err());
+    Ok(())
+}
+
+#[test]
+fn adapters_for_another_base_are_rejected() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let path = dir.join("candle_lora_fingerprint_adapter.safetensors");
+    let base = BaseModelFingerprint::from_tensors(&base_model(0.5, &device)?);
+    let other = BaseModelFingerprint::from_tensors(&base_model(0.25, &device)?);
+
+    let adapter = HashMap::from([(
+        "lora.a0.weight".to_string(),
+        Tensor::ones((2, 4), DType::F32, &device)?,
+    )]);
+    save_with_fingerprint(&adapter, &base, &path)?;
+    assert_eq!(read_fingerprint(&path)?, Some(base.clone()));
+    let loaded = load_verified(&path, &base, true, &device)?;
+    assert_eq!(loaded["lora.a0.weight"].dims(), &[2, 4]);
+    assert!(load_verified(&path, &other, false, &device).is_err());
+
+    // Adapters without a fingerprint load unless one is required.
+    let legacy_path = dir.join("candle_lora_fingerprint_legacy.safetensors");
+    candle_core::safetensors::save(&adapter, &legacy_path)?;
+    assert_eq!(read_fingerprint(&legacy_path)?, None);
+    assert!(load_verified(&legacy_path, &base, false, &device).is_ok());
+    assert!(load_verified(&legacy_path, &base, true, &device).is_err());
+    Ok(())
+}
+
+#[test]
+fn converted_adapters_can_be_stamped() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_fingerprint_peft.safetensors");
+    let out_path = dir.join("candle_lora_fingerprint_converted.safetensors");
+    let peft = HashMap::from([
+        (
+            "base_model.model.layers.0.q_proj.lora_A.weight".to_string(),
+            Tensor::ones((2, 4), DType::F32, &device)?,
+        ),
+        (
+            "base_model.model.layers.0.q_proj.lora_B.weight".to_string(),
+            Tensor::zeros((4, 2), DType::F32, &device)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&peft, &peft_path)?;
+    convert_peft_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora",
+    )?;
+
+    let base = BaseModelFingerprint::from_config(r#"{"hidden_size": 4}"#)?;
+    stamp_fingerprint(&out_path, &base)?;
+    let loaded = load_verified(&out_path, &base, true, &device)?;
+    assert_eq!(loaded.len(), 2);
+    Ok(())
+}
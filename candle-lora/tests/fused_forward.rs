@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    disable_adapters, AdapterSwap, LinearLayerLike, Lora, LoraConfig, LoraForward, LoraLinear,
+    LoraLinearConfig, Merge, SelectedLayersBuilder,
+};
+use candle_nn::{init, Linear, VarBuilder, VarMap};
+
+mod common;
+use common::{adapter_vb, max_abs_diff};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Proj,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "proj")
+    }
+}
+
+fn adapter(device: &Device) -> Result<VarBuilder<'static>> {
+    let tensors = [
+        ("a0.weight", Tensor::randn(0f32, 1., (4, 16), device)?),
+        ("b0.weight", Tensor::randn(0f32, 1., (8, 4), device)?),
+    ];
+    Ok(adapter_vb(tensors, device))
+}
+
+#[test]
+fn cached_forwards_match_the_factored_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(
+        Tensor::randn(0f32, 1., (8, 16), &device)?,
+        Some(Tensor::randn(0f32, 1., 8, &device)?),
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 3, 16), &device)?;
+    let config = LoraConfig::new(4, 8., None);
+    let original = adapter(&device)?;
+    let mut layer = LoraLinear::new(&base, &LoraLinearConfig::new(16, 8), &config, &original, 0)?;
+    let factored = layer.forward(&xs)?;
+
+    for forward in [LoraForward::CachedDelta, LoraForward::FusedWeight] {
+        layer.set_forward(forward);
+        assert_eq!(layer.forward_mode(), forward);
+        assert!(max_abs_diff(&layer.forward(&xs)?, &factored)? < 1e-3);
+        // The cached weight is reused, and the adapter can still be disabled or merged.
+        assert!(max_abs_diff(&layer.forward(&xs)?, &factored)? < 1e-3);
+        let disabled = {
+            let _disabled = disable_adapters();
+            layer.forward(&xs)?
+        };
+        assert!(max_abs_diff(&disabled, &base.forward(&xs)?)? < 1e-5);
+        layer.merge_weights().unwrap();
+        assert!(max_abs_diff(&layer.forward(&xs)?, &factored)? < 1e-3);
+        layer.unmerge_weights().unwrap();
+        assert!(max_abs_diff(&layer.forward(&xs)?, &factored)? < 1e-3);
+
+        // Swapping the adapter drops the cached weight.
+        let swapped = adapter(&device)?;
+        let mut expected = layer.clone();
+        expected.set_forward(LoraForward::Factored);
+        expected.swap_adapter(&swapped, &config).unwrap();
+        layer.swap_adapter(&swapped, &config).unwrap();
+        let output = layer.forward(&xs)?;
+        assert!(max_abs_diff(&output, &factored)? > 1e-2);
+        assert!(max_abs_diff(&output, &expected.forward(&xs)?)? < 1e-3);
+        layer.set_forward(LoraForward::Factored);
+        layer.swap_adapter(&original, &config).unwrap();
+    }
+    Ok(())
+}
+
+#[test]
+fn trainable_adapters_are_not_cached() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (8, 16), &device)?, None);
+    let xs = Tensor::randn(0f32, 1., (2, 16), &device)?;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    vb.pp("b0")
+        .get_with_hints((8, 4), "weight", init::Init::Const(0.5))?;
+    let layers = HashMap::from([(ModelLayers::Proj, &base as &dyn LinearLayerLike)]);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(16, 8))
+        .build();
+    let mut new = Lora::convert_model(selected, LoraConfig::new(4, 4., None), &vb)?;
+    Lora::train(&mut new, false);
+    Lora::set_forward(&mut new, LoraForward::FusedWeight);
+    let layer = &new.linear[&ModelLayers::Proj];
+    assert_eq!(layer.forward_mode(), LoraForward::FusedWeight);
+    let before = layer.forward(&xs)?;
+
+    // An optimizer step updates the variables in place, which a cached weight would miss.
+    let b = varmap.data().lock().unwrap()["b0.weight"].clone();
+    b.set(&Tensor::ones((8, 4), DType::F32, &device)?)?;
+    let after = layer.forward(&xs)?;
+    assert!(max_abs_diff(&before, &after)? > 1e-2);
+    let delta_x = xs.matmul(&layer.get_delta_weight().unwrap().t()?)?;
+    let expected = (base.forward(&xs)? + delta_x)?;
+    assert!(max_abs_diff(&after, &expected)? < 1e-3);
+    Ok(())
+}
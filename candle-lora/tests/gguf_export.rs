@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use candle_core::{
+    quantized::{gguf_file, GgmlDType},
+    Device, Result, Tensor,
+};
+use candle_lora::{gguf_tensor_name, save_gguf, GgufQuantization};
+
+/// The weights of a two-layer Llama-style model of hidden size 256, 4 query heads of dimension
+/// 64 and one key-value head, whose down projections have rows of 96 features.
+fn llama_weights(device: &Device) -> Result<HashMap<String, Tensor>> {
+    let mut weights = HashMap::from([
+        (
+            "model.embed_tokens.weight".to_string(),
+            Tensor::randn(0f32, 1., (16, 256), device)?,
+        ),
+        (
+            "model.norm.weight".to_string(),
+            Tensor::ones(256, candle_core::DType::F32, device)?,
+        ),
+        (
+            "lm_head.weight".to_string(),
+            Tensor::randn(0f32, 1., (16, 256), device)?,
+        ),
+    ]);
+    for layer in 0..2 {
+        let shapes = [
+            ("self_attn.q_proj", (256, 256)),
+            ("self_attn.k_proj", (64, 256)),
+            ("self_attn.v_proj", (64, 256)),
+            ("self_attn.o_proj", (256, 256)),
+            ("mlp.up_proj", (96, 256)),
+            ("mlp.down_proj", (256, 96)),
+        ];
+        for (module, shape) in shapes {
+            weights.insert(
+                format!("model.layers.{layer}.{module}.weight"),
+                Tensor::randn(0f32, 1., shape, device)?,
+            );
+        }
+        weights.insert(
+            format!("model.layers.{layer}.input_layernorm.weight"),
+            Tensor::ones(256, candle_core::DType::F32, device)?,
+        );
+    }
+    Ok(weights)
+}
+
+fn read_gguf(path: &std::path::Path) -> Result<(std::fs::File, gguf_file::Content)> {
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    Ok((file, content))
+}
+
+#[test]
+fn tensors_are_renamed_to_gguf_names() {
+    let names = [
+        ("model.embed_tokens.weight", "token_embd.weight"),
+        (
+            "model.layers.3.self_attn.q_proj.weight",
+            "blk.3.attn_q.weight",
+        ),
+        ("model.layers.3.self_attn.k_proj.bias", "blk.3.attn_k.bias"),
+        (
+            "model.layers.3.mlp.down_proj.weight",
+            "blk.3.ffn_down.weight",
+        ),
+        (
+            "model.layers.3.post_attention_layernorm.weight",
+            "blk.3.ffn_norm.weight",
+        ),
+        ("model.norm.weight", "output_norm.weight"),
+        ("lm_head.weight", "output.weight"),
+        ("model.rotary_emb.inv_freq", "model.rotary_emb.inv_freq"),
+    ];
+    for (name, gguf_name) in names {
+        assert_eq!(gguf_tensor_name(name), gguf_name);
+    }
+    assert_eq!(
+        "q4_k_m".parse::<GgufQuantization>().unwrap(),
+        GgufQuantization::Q4KM
+    );
+    assert_eq!(
+        "Q8_0".parse::<GgufQuantization>().unwrap(),
+        GgufQuantization::Q8_0
+    );
+    assert!("Q5_K_S".parse::<GgufQuantization>().is_err());
+}
+
+#[test]
+fn q4_k_m_keeps_more_bits_where_llama_cpp_does() -> Result<()> {
+    let device = Device::Cpu;
+    let path = std::env::temp_dir().join("candle_lora_q4_k_m.gguf");
+    save_gguf(
+        &llama_weights(&device)?,
+        path.to_str().unwrap(),
+        GgufQuantization::Q4KM,
+        &[],
+    )?;
+
+    let (_, content) = read_gguf(&path)?;
+    let dtype = |name: &str| content.tensor_infos[name].ggml_dtype;
+    assert_eq!(dtype("blk.0.attn_q.weight"), GgmlDType::Q4K);
+    assert_eq!(dtype("blk.0.attn_v.weight"), GgmlDType::Q4K);
+    // The last layer and the output layer in Q6_K.
+    assert_eq!(dtype("blk.1.attn_v.weight"), GgmlDType::Q6K);
+    assert_eq!(dtype("output.weight"), GgmlDType::Q6K);
+    // Rows of 96 features fall back to Q8_0, vectors stay f32.
+    assert_eq!(dtype("blk.0.ffn_down.weight"), GgmlDType::Q8_0);
+    assert_eq!(dtype("blk.0.attn_norm.weight"), GgmlDType::F32);
+    assert_eq!(content.tensor_infos.len(), 17);
+    assert_eq!(content.metadata["general.file_type"].to_u32()?, 15);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn q8_0_round_trips_with_rotary_permutation() -> Result<()> {
+    let device = Device::Cpu;
+    let path = std::env::temp_dir().join("candle_lora_q8_0.gguf");
+    let weights = llama_weights(&device)?;
+    let architecture = gguf_file::Value::String("llama".to_string());
+    let (head_count, head_count_kv) = (gguf_file::Value::U32(4), gguf_file::Value::U32(1));
+    save_gguf(
+        &weights,
+        path.to_str().unwrap(),
+        GgufQuantization::Q8_0,
+        &[
+            ("general.architecture", &architecture),
+            ("llama.attention.head_count", &head_count),
+            ("llama.attention.head_count_kv", &head_count_kv),
+        ],
+    )?;
+
+    let (mut file, content) = read_gguf(&path)?;
+    assert_eq!(
+        content.metadata["general.architecture"].to_string()?,
+        "llama"
+    );
+    let load = |file: &mut std::fs::File, name: &str| {
+        content.tensor(file, name, &device)?.dequantize(&device)
+    };
+    let max_abs_diff =
+        |a: &Tensor, b: &Tensor| -> Result<f32> { (a - b)?.abs()?.max_all()?.to_scalar::<f32>() };
+
+    let up = load(&mut file, "blk.0.ffn_up.weight")?;
+    assert!(max_abs_diff(&up, &weights["model.layers.0.mlp.up_proj.weight"])? < 0.05);
+
+    // The rotary halves of each head are interleaved: the second row of the key head is the
+    // first row of its second half.
+    let k = load(&mut file, "blk.0.attn_k.weight")?;
+    let hf_k = &weights["model.layers.0.self_attn.k_proj.weight"];
+    assert!(max_abs_diff(&k.get(0)?, &hf_k.get(0)?)? < 0.05);
+    assert!(max_abs_diff(&k.get(1)?, &hf_k.get(32)?)? < 0.05);
+    assert!(max_abs_diff(&k.get(2)?, &hf_k.get(1)?)? < 0.05);
+    let q = load(&mut file, "blk.1.attn_q.weight")?;
+    let hf_q = &weights["model.layers.1.self_attn.q_proj.weight"];
+    assert!(max_abs_diff(&q.get(65)?, &hf_q.get(96)?)? < 0.05);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
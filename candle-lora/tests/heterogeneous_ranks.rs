@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    load_peft_adapter, LinearLayerLike, Lora, LoraConfig, LoraConv2d, LoraConv2dConfig, LoraLinear,
+    LoraLinearConfig, Merge, SelectedLayersBuilder,
+};
+use candle_nn::{Conv2d, Conv2dConfig, Linear, VarBuilder, VarMap};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn linear_layers_keep_their_stored_ranks() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (6, 8), &device)?, None);
+    // An adapter whose layers have ranks 2 and 8, loaded with a default rank of 4.
+    let mut tensors = HashMap::new();
+    for (id, rank) in [(0, 2), (1, 8)] {
+        tensors.insert(
+            format!("lora.a{id}.weight"),
+            Tensor::randn(0f32, 1., (rank, 8), &device)?,
+        );
+        tensors.insert(
+            format!("lora.b{id}.weight"),
+            Tensor::randn(0f32, 1., (6, rank), &device)?,
+        );
+    }
+    let vb = VarBuilder::from_tensors(tensors.clone(), DType::F32, &device);
+    let config = LoraConfig::new(4, 16., None);
+
+    let xs = Tensor::randn(0f32, 1., (3, 8), &device)?;
+    for (id, rank) in [(0, 2), (1, 8)] {
+        let mut layer = LoraLinear::new(
+            &base,
+            &LoraLinearConfig::new(8, 6),
+            &config,
+            &vb.pp("lora"),
+            id,
+        )?;
+        // The scale is alpha over the layer's own rank.
+        let delta = (tensors[&format!("lora.b{id}.weight")]
+            .matmul(&tensors[&format!("lora.a{id}.weight")])?
+            * (16. / rank as f64))?;
+        assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+        let expected = xs.matmul(&(base.weight() + &delta)?.t()?)?;
+        assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+        layer.merge_weights().unwrap();
+        assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+    }
+
+    // Other dimensions still have to match.
+    let wrong_features = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(7, 6),
+        &config,
+        &vb.pp("lora"),
+        0,
+    );
+    assert!(wrong_features.is_err());
+    Ok(())
+}
+
+#[test]
+fn conv2d_layers_keep_their_stored_ranks() -> Result<()> {
+    let device = Device::Cpu;
+    let cfg = Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let base = Conv2d::new(Tensor::randn(0f32, 1., (4, 3, 3, 3), &device)?, None, cfg);
+    let tensors = HashMap::from([
+        (
+            "lora.a0.weight".to_string(),
+            Tensor::randn(0f32, 1., (6, 3, 3, 3), &device)?,
+        ),
+        (
+            "lora.b0.weight".to_string(),
+            Tensor::randn(0f32, 1., (4, 6, 1, 1), &device)?,
+        ),
+    ]);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let mut layer = LoraConv2d::new(
+        &base,
+        &LoraConv2dConfig::new(3, 4),
+        &LoraConfig::new(2, 6., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (1, 3, 5, 5), &device)?;
+    let unmerged = layer.forward(&xs)?;
+    layer.merge_weights().unwrap();
+    assert!(max_abs_diff(&layer.forward(&xs)?, &unmerged)? < 1e-3);
+    Ok(())
+}
+
+#[test]
+fn rank_and_alpha_patterns() -> Result<()> {
+    let device = Device::Cpu;
+    let modules = ["self_attn.q_proj", "self_attn.v_proj"];
+    let base = Linear::new(Tensor::randn(0f32, 1., (8, 8), &device)?, None);
+    let convert = |config: LoraConfig, varmap: &VarMap| {
+        let layers = modules
+            .iter()
+            .map(|module| (format!("layers.0.{module}"), &base as &dyn LinearLayerLike))
+            .collect::<HashMap<_, _>>();
+        let selected = SelectedLayersBuilder::new()
+            .add_linear_layers(layers, LoraLinearConfig::new(8, 8))
+            .build();
+        let vb = VarBuilder::from_varmap(varmap, DType::F32, &device);
+        Lora::convert_model(selected, config, &vb.pp("lora"))
+    };
+
+    // Fresh layers take the rank of their pattern.
+    let varmap = VarMap::new();
+    let config = LoraConfig::new(2, 4., None).with_rank_pattern("v_proj", 4)?;
+    convert(config, &varmap)?;
+    let data = varmap.data().lock().unwrap();
+    assert_eq!(data["lora.a0.weight"].dims(), [2, 8]);
+    assert_eq!(data["lora.a1.weight"].dims(), [4, 8]);
+    drop(data);
+    assert!(LoraConfig::new(2, 4., None)
+        .with_alpha_pattern("(", 1.)
+        .is_err());
+
+    // A PEFT adapter with its own rank and alpha for v_proj is scaled as PEFT does.
+    let peft_dir = std::env::temp_dir().join("candle_lora_rank_alpha_patterns");
+    std::fs::create_dir_all(&peft_dir)?;
+    let mut peft = HashMap::new();
+    for (module, rank) in modules.iter().zip([2, 4]) {
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (rank, 8), &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (8, rank), &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, peft_dir.join("adapter_model.safetensors"))?;
+    std::fs::write(
+        peft_dir.join("adapter_config.json"),
+        r#"{"peft_type": "LORA", "r": 2, "lora_alpha": 4, "target_modules": ["q_proj", "v_proj"],
+            "rank_pattern": {"v_proj": 4}, "alpha_pattern": {"self_attn\\.v_proj": 32}}"#,
+    )?;
+
+    let mut new = convert(LoraConfig::new(2, 4., None), &VarMap::new())?;
+    let report = load_peft_adapter(peft_dir.to_str().unwrap(), &mut new, &device)?;
+    assert_eq!(report.rank_pattern["v_proj"], 4);
+    assert_eq!(report.alpha_pattern["self_attn\\.v_proj"], 32.);
+    for (module, scale) in modules.iter().zip([4. / 2., 32. / 4.]) {
+        let peft_module = format!("base_model.model.model.layers.0.{module}");
+        let delta = (peft[&format!("{peft_module}.lora_B.weight")]
+            .matmul(&peft[&format!("{peft_module}.lora_A.weight")])?
+            * scale)?;
+        let layer = &new.linear[&format!("layers.0.{module}")];
+        assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+    }
+
+    // Converting a model with the config of the report gives the layers the same scales.
+    let mut tensors = HashMap::new();
+    for (id, module) in modules.iter().enumerate() {
+        let peft_module = format!("base_model.model.model.layers.0.{module}");
+        tensors.insert(
+            format!("lora.a{id}.weight"),
+            peft[&format!("{peft_module}.lora_A.weight")].clone(),
+        );
+        tensors.insert(
+            format!("lora.b{id}.weight"),
+            peft[&format!("{peft_module}.lora_B.weight")].clone(),
+        );
+    }
+    let layers = modules
+        .iter()
+        .map(|module| (format!("layers.0.{module}"), &base as &dyn LinearLayerLike))
+        .collect::<HashMap<_, _>>();
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(8, 8))
+        .build();
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let converted = Lora::convert_model(selected, report.lora_config().unwrap(), &vb.pp("lora"))?;
+    for (name, layer) in &converted.linear {
+        let diff = max_abs_diff(
+            &layer.get_delta_weight().unwrap(),
+            &new.linear[name].get_delta_weight().unwrap(),
+        )?;
+        assert!(diff < 1e-5);
+    }
+
+    std::fs::remove_dir_all(&peft_dir)?;
+    Ok(())
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "hub")]
+
+use std::collections::HashMap;
+
+use candle_core::{Device, Result, Tensor};
+use candle_lora::PeftAdapter;
+
+#[test]
+fn local_adapter_is_converted_once() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join("candle_lora_hub_adapter");
+    std::fs::create_dir_all(&dir)?;
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let peft = HashMap::from([
+        (
+            format!("{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (4, 8), &device)?,
+        ),
+        (
+            format!("{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (8, 4), &device)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&peft, dir.join("adapter_model.safetensors"))?;
+    assert!(PeftAdapter::from_dir(&dir).is_err());
+    std::fs::write(
+        dir.join("adapter_config.json"),
+        r#"{"peft_type": "LORA", "r": 4, "lora_alpha": 8, "target_modules": ["q_proj"]}"#,
+    )?;
+
+    let adapter = PeftAdapter::from_dir(&dir)?;
+    assert_eq!(adapter.config().r, 4);
+    let converted = adapter.convert("lora_llama", &device)?;
+    assert_eq!(converted, dir.join("candle_lora.lora_llama.safetensors"));
+    assert_eq!(
+        candle_core::safetensors::load(&converted, &device)?.len(),
+        2
+    );
+
+    // The cached file is reused.
+    std::fs::remove_file(dir.join("adapter_model.safetensors"))?;
+    assert_eq!(adapter.convert("lora_llama", &device)?, converted);
+    assert!(adapter.convert("other", &device).is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
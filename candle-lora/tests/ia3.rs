@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    convert_peft_ia3_to_candle_lora, AdapterSwap, Ia3Linear, Ia3LinearConfig, LoraConfig, Merge,
+    PeftIa3Config, Saveable,
+};
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+mod common;
+use common::{max_abs_diff, trainable_adapter_vb};
+
+fn adapter(name: &str, scaling: &Tensor, device: &Device) -> Result<VarBuilder<'static>> {
+    trainable_adapter_vb([(name, scaling.clone())], DType::F32, device)
+}
+
+#[test]
+fn ia3_rescales_outputs_and_inputs() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (8, 16), &device)?;
+    let bias = Tensor::randn(0f32, 1., 8, &device)?;
+    let base = Linear::new(weight.clone(), Some(bias.clone()));
+    let xs = Tensor::randn(0f32, 1., (2, 3, 16), &device)?;
+
+    // A fresh layer computes its base layer.
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let fresh = Ia3Linear::new(&base, &Ia3LinearConfig::new(16, 8), &vb.pp("ia3"), 0)?;
+    assert!(max_abs_diff(&fresh.forward(&xs)?, &base.forward(&xs)?)? < 1e-6);
+
+    let l = Tensor::randn(0f32, 1., 8, &device)?;
+    let vb = adapter("ia3.l0.weight", &l, &device)?;
+    let mut ia3 = Ia3Linear::new(&base, &Ia3LinearConfig::new(16, 8), &vb.pp("ia3"), 0)?;
+    let expected = base.forward(&xs)?.broadcast_mul(&l)?;
+    assert!(max_abs_diff(&ia3.forward(&xs)?, &expected)? < 1e-5);
+    ia3.merge_weights().unwrap();
+    assert!(max_abs_diff(&ia3.forward(&xs)?, &expected)? < 1e-5);
+    assert!(ia3.merge_weights().is_err());
+    ia3.unmerge_weights().unwrap();
+    assert!(max_abs_diff(&ia3.forward(&xs)?, &expected)? < 1e-5);
+
+    let l = Tensor::randn(0f32, 1., 16, &device)?;
+    let vb = adapter("ia3.l1.weight", &l, &device)?;
+    let config = Ia3LinearConfig::new(16, 8).with_feedforward();
+    let mut ia3 = Ia3Linear::new(&base, &config, &vb.pp("ia3"), 1)?;
+    let expected = base.forward(&xs.broadcast_mul(&l)?)?;
+    assert!(max_abs_diff(&ia3.forward(&xs)?, &expected)? < 1e-5);
+    ia3.merge_weights().unwrap();
+    assert!(max_abs_diff(&ia3.forward(&xs)?, &expected)? < 1e-5);
+
+    // Swapping a merged layer merges the new vector.
+    let swapped = Tensor::randn(0f32, 1., 16, &device)?;
+    let vb = adapter("ia3.l1.weight", &swapped, &device)?;
+    ia3.swap_adapter(&vb, &LoraConfig::new(1, 1., None))
+        .unwrap();
+    let expected = base.forward(&xs.broadcast_mul(&swapped)?)?;
+    assert!(max_abs_diff(&ia3.forward(&xs)?, &expected)? < 1e-5);
+
+    let mut saved = HashMap::new();
+    ia3.get_tensors(&mut saved)?;
+    assert!(max_abs_diff(&saved["ia3.l1.weight"], &swapped)? < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn peft_ia3_conversion() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_ia3_peft.safetensors");
+    let out_path = dir.join("candle_lora_ia3_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for (layer, module, shape) in [
+        (1, "self_attn.k_proj", (8, 1)),
+        (0, "mlp.down_proj", (1, 32)),
+        (0, "self_attn.v_proj", (8, 1)),
+    ] {
+        peft.insert(
+            format!("base_model.model.model.layers.{layer}.{module}.ia3_l"),
+            Tensor::ones(shape, DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_ia3_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "ia3_llama",
+        &device,
+    )?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&out_path)?;
+
+    assert_eq!(converted.len(), 3);
+    // The attention comes before the MLP in the structural order.
+    assert_eq!(converted["ia3_llama.l0.weight"].dims(), &[8]);
+    assert_eq!(converted["ia3_llama.l1.weight"].dims(), &[32]);
+    assert_eq!(converted["ia3_llama.l2.weight"].dims(), &[8]);
+
+    let config: PeftIa3Config = serde_json::from_str(
+        r#"{"peft_type": "IA3", "target_modules": ["k_proj", "v_proj", "down_proj"],
+            "feedforward_modules": ["down_proj"]}"#,
+    )
+    .unwrap();
+    assert!(config.is_feedforward("model.layers.0.mlp.down_proj"));
+    assert!(!config.is_feedforward("model.layers.0.self_attn.v_proj"));
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    convert_peft_to_candle_lora, structural_order, LinearLayerLike, Lora, LoraConfig,
+    LoraLinearConfig, Saveable, SelectedLayersBuilder,
+};
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+#[test]
+fn layer_numbers_and_projections_follow_the_model() {
+    assert_eq!(
+        structural_order("layers.2.self_attn.q_proj", "layers.10.self_attn.q_proj"),
+        Ordering::Less
+    );
+
+    let mut names = [
+        "model.layers.1.mlp.down_proj",
+        "model.layers.1.self_attn.v_proj",
+        "model.layers.0.mlp.up_proj",
+        "model.layers.1.self_attn.o_proj",
+        "model.layers.1.self_attn.q_proj",
+        "lm_head",
+        "model.layers.0.mlp.gate_proj",
+        "model.layers.1.self_attn.k_proj",
+    ];
+    names.sort_by(|a, b| structural_order(a, b));
+    assert_eq!(
+        names,
+        [
+            "model.layers.0.mlp.gate_proj",
+            "model.layers.0.mlp.up_proj",
+            "model.layers.1.self_attn.q_proj",
+            "model.layers.1.self_attn.k_proj",
+            "model.layers.1.self_attn.v_proj",
+            "model.layers.1.self_attn.o_proj",
+            "model.layers.1.mlp.down_proj",
+            "lm_head",
+        ]
+    );
+}
+
+#[test]
+fn peft_indices_follow_the_layer_numbers() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_indexing_peft.safetensors");
+    let out_path = dir.join("candle_lora_indexing_out.safetensors");
+
+    // Each A is filled with a value identifying its module.
+    let mut peft = HashMap::new();
+    for layer in 0..12 {
+        for (i, module) in ["q_proj", "v_proj"].iter().enumerate() {
+            let name = format!("base_model.model.model.layers.{layer}.self_attn.{module}");
+            let id = (layer * 2 + i) as f32;
+            peft.insert(
+                format!("{name}.lora_A.weight"),
+                Tensor::full(id, (2, 4), &device)?,
+            );
+            peft.insert(
+                format!("{name}.lora_B.weight"),
+                Tensor::zeros((4, 2), DType::F32, &device)?,
+            );
+        }
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    for idx in 0..24 {
+        let a = &converted[&format!("lora_llama.a{idx}.weight")];
+        assert_eq!(a.max_all()?.to_scalar::<f32>()?, idx as f32);
+    }
+    Ok(())
+}
+
+#[test]
+fn convert_model_ids_follow_the_layer_names() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::zeros((4, 4), DType::F32, &device)?, None);
+
+    let names = ["layers.10.q_proj", "layers.2.v_proj", "layers.2.q_proj"];
+    let mut linear_layers = HashMap::new();
+    for name in names {
+        linear_layers.insert(name.to_string(), &base as &dyn LinearLayerLike);
+    }
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(linear_layers, LoraLinearConfig::new(4, 4))
+        .build();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let new_layers = Lora::convert_model(selected, LoraConfig::new(2, 2., None), &vb.pp("lora"))?;
+
+    for (name, id) in [
+        ("layers.2.q_proj", 0),
+        ("layers.2.v_proj", 1),
+        ("layers.10.q_proj", 2),
+    ] {
+        let mut tensors = HashMap::new();
+        new_layers.linear[name].get_tensors(&mut tensors)?;
+        assert!(tensors.contains_key(&format!("lora.a{id}.weight")));
+    }
+    Ok(())
+}
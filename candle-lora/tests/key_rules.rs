@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{convert_peft_to_candle_lora_with_rules, ConvertOptions, KeyRules};
+
+const RULES: &str = r#"
+rules:
+  - pattern: '^base_model\.model\.transformer\.h\.(\d+)\.attn\.c_attn$'
+    template: 'model.layers.$1.self_attn.qkv_proj.traced_lora_linear'
+  - pattern: '\.mlp\.'
+    template: 'lora_mlp'
+    numbered: true
+  - pattern: 'lm_head'
+"#;
+
+fn save_peft(path: &std::path::Path, modules: &[&str], device: &Device) -> Result<()> {
+    let mut peft = HashMap::new();
+    for (i, module) in modules.iter().enumerate() {
+        peft.insert(
+            format!("base_model.model.{module}.lora_A.weight"),
+            Tensor::full(i as f32, (2, 4), device)?,
+        );
+        peft.insert(
+            format!("base_model.model.{module}.lora_B.weight"),
+            Tensor::zeros((4, 2), DType::F32, device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, path)
+}
+
+#[test]
+fn yaml_rules_drive_the_conversion() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let rules_path = dir.join("candle_lora_key_rules.yaml");
+    let peft_path = dir.join("candle_lora_key_rules_peft.safetensors");
+    let out_path = dir.join("candle_lora_key_rules_out.safetensors");
+    std::fs::write(&rules_path, RULES)?;
+    let modules = [
+        "transformer.h.0.attn.c_attn",
+        "transformer.h.1.attn.c_attn",
+        "transformer.h.0.mlp.c_fc",
+        "transformer.h.10.mlp.c_fc",
+        "transformer.h.2.mlp.c_fc",
+        "lm_head",
+        "transformer.wte",
+    ];
+    save_peft(&peft_path, &modules, &device)?;
+
+    let rules = KeyRules::from_file(&rules_path)?;
+    let convert = |options: &ConvertOptions| {
+        convert_peft_to_candle_lora_with_rules(
+            peft_path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            &rules,
+            &device,
+            options,
+        )
+    };
+    // No rule matches the embedding, while lm_head is dropped on purpose.
+    let e = convert(&ConvertOptions::new().with_strict(true)).unwrap_err();
+    assert!(e.to_string().contains("transformer.wte"), "{e}");
+    assert!(!e.to_string().contains("lm_head"), "{e}");
+    let report = convert(&ConvertOptions::default())?;
+    assert_eq!(
+        report.skipped,
+        [
+            "base_model.model.lm_head.lora_A.weight",
+            "base_model.model.lm_head.lora_B.weight",
+            "base_model.model.transformer.wte.lora_A.weight",
+            "base_model.model.transformer.wte.lora_B.weight",
+        ]
+    );
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let mut names = converted.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "lora_mlp.a0.weight",
+            "lora_mlp.a1.weight",
+            "lora_mlp.a2.weight",
+            "lora_mlp.b0.weight",
+            "lora_mlp.b1.weight",
+            "lora_mlp.b2.weight",
+            "model.layers.0.self_attn.qkv_proj.traced_lora_linear.a0.weight",
+            "model.layers.0.self_attn.qkv_proj.traced_lora_linear.b0.weight",
+            "model.layers.1.self_attn.qkv_proj.traced_lora_linear.a0.weight",
+            "model.layers.1.self_attn.qkv_proj.traced_lora_linear.b0.weight",
+        ]
+    );
+    // The mlp layers are numbered by layer: h.0, h.2, h.10.
+    for (idx, module) in [2., 4., 3.].iter().enumerate() {
+        let a = &converted[&format!("lora_mlp.a{idx}.weight")];
+        assert_eq!(a.max_all()?.to_scalar::<f32>()?, *module);
+    }
+    Ok(())
+}
+
+#[test]
+fn json_rules_and_errors() -> Result<()> {
+    let rules = KeyRules::from_json_str(
+        r#"{"rules": [{"pattern": "layers\\.(?<layer>\\d+)\\.(\\w+)$", "template": "l${layer}_$2"}]}"#,
+    )?;
+    assert_eq!(
+        rules.resolve("model.layers.3.q_proj"),
+        Some(("l3_q_proj".to_string(), false))
+    );
+    assert_eq!(rules.resolve("lm_head"), None);
+
+    assert!(KeyRules::from_json_str(r#"{"rules": [{"pattern": "("}]}"#).is_err());
+    assert!(KeyRules::from_yaml_str("rules: 3").is_err());
+
+    // Two modules mapped to the same name without numbering.
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_key_rules_collision.safetensors");
+    save_peft(&peft_path, &["layers.0.q_proj", "layers.1.q_proj"], &device)?;
+    let rules = KeyRules::from_json_str(r#"{"rules": [{"pattern": "q_proj", "template": "q"}]}"#)?;
+    assert!(convert_peft_to_candle_lora_with_rules(
+        peft_path.to_str().unwrap(),
+        dir.join("candle_lora_key_rules_collision_out.safetensors")
+            .to_str()
+            .unwrap(),
+        &rules,
+        &device,
+        &ConvertOptions::default(),
+    )
+    .is_err());
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    convert_kohya_to_candle_lora, LoraConfig, LoraConv2d, LoraConv2dConfig, LoraLinear,
+    LoraLinearConfig, Merge,
+};
+use candle_nn::{Conv2d, Conv2dConfig, Linear, VarBuilder};
+
+mod common;
+use common::max_abs_diff;
+
+const TO_Q: &str = "lora_unet_down_blocks_0_attentions_0_transformer_blocks_0_attn1_to_q";
+const PROJ_IN: &str = "lora_unet_down_blocks_0_attentions_0_proj_in";
+const CONV1: &str = "lora_unet_down_blocks_0_resnets_0_conv1";
+const TE_Q_PROJ: &str = "lora_te_text_model_encoder_layers_0_self_attn_q_proj";
+const TE2_FC1: &str = "lora_te2_text_model_encoder_layers_0_mlp_fc1";
+
+/// A kohya checkpoint of linear, 1x1 and 3x3 convolution modules of a UNet and two text
+/// encoders, the last without alpha.
+fn kohya_tensors(device: &Device) -> Result<HashMap<String, Tensor>> {
+    let modules = [
+        (TO_Q, vec![4, 8], vec![6, 4], Some(2f32)),
+        (PROJ_IN, vec![4, 8, 1, 1], vec![8, 4, 1, 1], Some(2.)),
+        (CONV1, vec![8, 4, 3, 3], vec![4, 8, 1, 1], Some(4.)),
+        (TE_Q_PROJ, vec![4, 8], vec![8, 4], Some(2.)),
+        (TE2_FC1, vec![4, 8], vec![16, 4], None),
+    ];
+    let mut tensors = HashMap::new();
+    for (module, down, up, alpha) in modules {
+        tensors.insert(
+            format!("{module}.lora_down.weight"),
+            Tensor::randn(0f32, 1., down, device)?,
+        );
+        tensors.insert(
+            format!("{module}.lora_up.weight"),
+            Tensor::randn(0f32, 1., up, device)?,
+        );
+        if let Some(alpha) = alpha {
+            tensors.insert(format!("{module}.alpha"), Tensor::new(alpha, device)?);
+        }
+    }
+    Ok(tensors)
+}
+
+#[test]
+fn kohya_modules_are_converted_to_module_paths() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let kohya_path = dir.join("candle_lora_kohya.safetensors");
+    let out_path = dir.join("candle_lora_kohya_out.safetensors");
+    let kohya = kohya_tensors(&device)?;
+    candle_core::safetensors::save(&kohya, &kohya_path)?;
+
+    let report = convert_kohya_to_candle_lora(
+        kohya_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        None,
+    )?;
+    assert_eq!(
+        report.target_modules,
+        [
+            "text_encoder.text_model.encoder.layers.0.self_attn.q_proj",
+            "text_encoder_2.text_model.encoder.layers.0.mlp.fc1",
+            "unet.down_blocks.0.attentions.0.proj_in",
+            "unet.down_blocks.0.attentions.0.transformer_blocks.0.attn1.to_q",
+            "unet.down_blocks.0.resnets.0.conv1",
+        ]
+    );
+    assert_eq!((report.num_modules, report.num_tensors), (5, 10));
+    assert_eq!(
+        (report.rank, report.alpha, report.scaling),
+        (4, Some(2.), Some(0.5))
+    );
+    assert_eq!(
+        report.rank_pattern.into_iter().collect::<Vec<_>>(),
+        [(r"unet\.down_blocks\.0\.resnets\.0\.conv1".to_string(), 8)]
+    );
+    assert_eq!(
+        report.alpha_pattern.into_iter().collect::<Vec<_>>(),
+        [
+            (
+                r"text_encoder_2\.text_model\.encoder\.layers\.0\.mlp\.fc1".to_string(),
+                4.
+            ),
+            (r"unet\.down_blocks\.0\.resnets\.0\.conv1".to_string(), 4.),
+        ]
+    );
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let path = "unet.down_blocks.0.attentions.0.transformer_blocks.0.attn1.to_q";
+    assert_eq!(
+        converted[&format!("{path}.b0.weight")].dims(),
+        kohya[&format!("{TO_Q}.lora_up.weight")].dims()
+    );
+
+    // Given the modules of the base model, their paths are taken as they are.
+    let base_modules =
+        ["down_blocks.0.attentions.0.transformer_blocks.0.attn1.to_q"].map(String::from);
+    let only_to_q = HashMap::from([
+        (
+            format!("{TO_Q}.lora_down.weight"),
+            kohya[&format!("{TO_Q}.lora_down.weight")].clone(),
+        ),
+        (
+            format!("{TO_Q}.lora_up.weight"),
+            kohya[&format!("{TO_Q}.lora_up.weight")].clone(),
+        ),
+    ]);
+    candle_core::safetensors::save(&only_to_q, &kohya_path)?;
+    let report = convert_kohya_to_candle_lora(
+        kohya_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        Some(&base_modules),
+    )?;
+    assert_eq!(report.target_modules, [path]);
+    // Without alpha, alpha is the rank.
+    assert_eq!(report.scaling, Some(1.));
+
+    // Modules missing from the base model and other LoRA variants are errors.
+    assert!(convert_kohya_to_candle_lora(
+        kohya_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        Some(&["down_blocks.0.attentions.0.proj_in".to_string()]),
+    )
+    .is_err());
+    let loha = HashMap::from([(
+        format!("{TO_Q}.hada_w1_a"),
+        Tensor::zeros((4, 8), DType::F32, &device)?,
+    )]);
+    candle_core::safetensors::save(&loha, &kohya_path)?;
+    assert!(convert_kohya_to_candle_lora(
+        kohya_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        None
+    )
+    .is_err());
+
+    std::fs::remove_file(&kohya_path)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
+
+#[test]
+fn converted_kohya_adapters_load_into_linear_and_conv_layers() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let kohya_path = dir.join("candle_lora_kohya_load.safetensors");
+    let out_path = dir.join("candle_lora_kohya_load_out.safetensors");
+    let kohya = kohya_tensors(&device)?;
+    candle_core::safetensors::save(&kohya, &kohya_path)?;
+    let report = convert_kohya_to_candle_lora(
+        kohya_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        None,
+    )?;
+    let config = report.lora_config().unwrap();
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let vb = VarBuilder::from_tensors(converted, DType::F32, &device);
+    let unet = vb.pp("unet").pp("down_blocks.0");
+    let up_down = |module: &str| -> Result<Tensor> {
+        let up = kohya[&format!("{module}.lora_up.weight")].flatten_from(1)?;
+        let down = kohya[&format!("{module}.lora_down.weight")].flatten_from(1)?;
+        up.matmul(&down)
+    };
+
+    // A linear layer, scaled by alpha / rank.
+    let base = Linear::new(Tensor::randn(0f32, 1., (6, 8), &device)?, None);
+    let layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(8, 6),
+        &config,
+        &unet.pp("attentions.0.transformer_blocks.0.attn1.to_q"),
+        0,
+    )?;
+    let delta = (up_down(TO_Q)? * 0.5)?;
+    assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+
+    // The 1x1 convolution LoRA of SD 1.x loads into the linear proj_in of SDXL.
+    let base = Linear::new(Tensor::randn(0f32, 1., (8, 8), &device)?, None);
+    let layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(8, 8),
+        &config,
+        &unet.pp("attentions.0.proj_in"),
+        0,
+    )?;
+    let delta = (up_down(PROJ_IN)? * 0.5)?;
+    assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+    let xs = Tensor::randn(0f32, 1., (2, 8), &device)?;
+    let expected = xs.matmul(&(base.weight() + &delta)?.t()?)?;
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+
+    // A 3x3 convolution, with the rank and alpha of its patterns.
+    let base = Conv2d::new(
+        Tensor::randn(0f32, 1., (4, 4, 3, 3), &device)?,
+        None,
+        Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        },
+    );
+    let layer = LoraConv2d::new(
+        &base,
+        &LoraConv2dConfig::new(4, 4),
+        &LoraConfig::new(8, 4., None),
+        &unet.pp("resnets.0.conv1"),
+        0,
+    )?;
+    let delta = (up_down(CONV1)? * 0.5)?.reshape((4, 4, 3, 3))?;
+    assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+    let xs = Tensor::randn(0f32, 1., (1, 4, 5, 5), &device)?;
+    let expected = Conv2d::new((base.weight() + &delta)?, None, *base.config()).forward(&xs)?;
+    assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+
+    std::fs::remove_file(&kohya_path)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
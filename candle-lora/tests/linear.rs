@@ -16,6 +16,12 @@ fn single_linear() -> candle_core::Result<()> {
         Layer,
     }
 
+    impl std::fmt::Display for ModelLayers {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "layer")
+        }
+    }
+
     #[derive(Debug)]
     struct Model {
         layer: Arc<dyn LinearLayerLike>,
@@ -73,7 +79,7 @@ fn single_linear() -> candle_core::Result<()> {
     let loraconfig = LoraConfig::new(1, 1., None);
 
     //Create new LoRA layers from our layers
-    let new_layers = Lora::convert_model(selected, loraconfig, &vb);
+    let new_layers = Lora::convert_model(selected, loraconfig, &vb)?;
 
     //Custom methods to implement
     model.insert_new(new_layers);
@@ -86,3 +92,37 @@ fn single_linear() -> candle_core::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn mismatched_adapter_is_an_error() -> candle_core::Result<()> {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_lora::{LinearLayerLike, Lora, LoraLinearConfig};
+    use candle_nn::Linear;
+
+    let device = Device::Cpu;
+    let layer = Linear::new(Tensor::zeros((10, 10), DType::F32, &device)?, None);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(
+            HashMap::from([("layer", &layer as &dyn LinearLayerLike)]),
+            LoraLinearConfig::new(10, 10),
+        )
+        .build();
+
+    // An adapter of a layer with 8 inputs rather than 10.
+    let tensors = HashMap::from([
+        (
+            "a0.weight".to_string(),
+            Tensor::zeros((1, 8), DType::F32, &device)?,
+        ),
+        (
+            "b0.weight".to_string(),
+            Tensor::zeros((10, 1), DType::F32, &device)?,
+        ),
+    ]);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    assert!(Lora::convert_model(selected, LoraConfig::new(1, 1., None), &vb).is_err());
+
+    Ok(())
+}
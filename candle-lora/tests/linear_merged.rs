@@ -16,6 +16,12 @@ fn linear() -> candle_core::Result<()> {
         Layer,
     }
 
+    impl std::fmt::Display for ModelLayers {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "layer")
+        }
+    }
+
     #[derive(Debug)]
     struct Model {
         layer: Arc<dyn LinearLayerLike>,
@@ -76,7 +82,7 @@ fn linear() -> candle_core::Result<()> {
     let loraconfig = LoraConfig::new(1, 1., None);
 
     //Create new LoRA layers from our layers
-    let new_layers = Lora::convert_model(selected, loraconfig, &vb);
+    let new_layers = Lora::convert_model(selected, loraconfig, &vb)?;
 
     //Custom methods to implement
     model.insert_new(new_layers);
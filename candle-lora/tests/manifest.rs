@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use candle_core::{Device, Result, Tensor};
+use candle_lora::{
+    convert_peft_to_candle_lora_with_manifest, load_with_manifest, ConversionManifest,
+};
+
+#[test]
+fn manifest_maps_peft_modules_to_indices() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_manifest_peft.safetensors");
+    let output_path = dir.join("candle_lora_manifest_converted.safetensors");
+    let manifest_path = dir.join("candle_lora_manifest.json");
+
+    let mut peft = HashMap::new();
+    for (layer, rank) in [(10, 2), (2, 4)] {
+        let module = format!("base_model.model.model.layers.{layer}.self_attn.q_proj");
+        peft.insert(
+            format!("{module}.lora_A.weight"),
+            Tensor::zeros((rank, 8), candle_core::DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("{module}.lora_B.weight"),
+            Tensor::zeros((8, rank), candle_core::DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    let manifest = convert_peft_to_candle_lora_with_manifest(
+        peft_path.to_str().unwrap(),
+        output_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+    assert_eq!(
+        manifest.candle_names("base_model.model.model.layers.2.self_attn.q_proj"),
+        Some(("lora_llama.a0.weight", "lora_llama.b0.weight"))
+    );
+    assert_eq!(
+        manifest.peft_module("lora_llama.b1.weight"),
+        Some("base_model.model.model.layers.10.self_attn.q_proj")
+    );
+    assert_eq!(manifest.entries[1].a_shape, [2, 8]);
+
+    manifest.save(&manifest_path)?;
+    let manifest = ConversionManifest::load(&manifest_path)?;
+    let tensors = load_with_manifest(&output_path, &manifest, &device)?;
+    assert_eq!(tensors.len(), 4);
+
+    // An adapter whose layers were indexed in another order is rejected.
+    let mut swapped = tensors.clone();
+    swapped.insert(
+        "lora_llama.a0.weight".to_string(),
+        tensors["lora_llama.a1.weight"].clone(),
+    );
+    assert!(manifest.verify(&swapped).is_err());
+    let mut extra = tensors;
+    extra.insert(
+        "lora_llama.a2.weight".to_string(),
+        Tensor::zeros(1, candle_core::DType::F32, &device)?,
+    );
+    assert!(manifest.verify(&extra).is_err());
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use candle_core::{DType, Device, Module, Result, Tensor, Var};
+use candle_lora::{
+    activation_bytes, checkpointed_backward, tensors_bytes, BlockFn, GradientCheckpointing,
+    LoraConfig, LoraLinear, LoraLinearConfig, LoraTrainer, TrainingConfig,
+};
+use candle_nn::{Linear, VarBuilder, VarMap, SGD};
+
+const NUM_BLOCKS: usize = 4;
+
+/// Residual LoRA blocks over frozen random weights, with their adapters and a trainable input
+/// scale in `varmap`.
+fn model(varmap: &mut VarMap, device: &Device) -> Result<(Vec<LoraLinear>, Var)> {
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, device);
+    let layers = (0..NUM_BLOCKS)
+        .map(|i| {
+            let base = Linear::new(Tensor::randn(0f32, 0.3, (8, 8), device)?, None);
+            LoraLinear::new(
+                &base,
+                &LoraLinearConfig::new(8, 8),
+                &LoraConfig::new(2, 4., None),
+                &vb.pp(format!("block{i}")),
+                0,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Random B, for the A to have gradients.
+    for i in 0..NUM_BLOCKS {
+        varmap.set_one(
+            format!("block{i}.b0.weight"),
+            Tensor::randn(0f32, 0.3, (8, 2), device)?,
+        )?;
+    }
+    let scale = vb.get_with_hints(8, "embed.scale", candle_nn::init::ONE)?;
+    Ok((layers, Var::from_tensor(&scale)?))
+}
+
+fn block(layer: &LoraLinear) -> impl FnMut(&Tensor) -> Result<Tensor> + '_ {
+    move |x| layer.forward(x)?.tanh()? + x
+}
+
+fn loss(hidden: &Tensor) -> Result<Tensor> {
+    hidden.sqr()?.mean_all()
+}
+
+#[test]
+fn checkpointed_gradients_match_plain_backpropagation() -> Result<()> {
+    let device = Device::Cpu;
+    let mut varmap = VarMap::new();
+    let (layers, scale) = model(&mut varmap, &device)?;
+    let vars = varmap.all_vars();
+    let x = Tensor::randn(0f32, 1., (16, 8), &device)?;
+    let input = x.broadcast_mul(scale.as_tensor())?;
+
+    let mut hidden = input.clone();
+    for layer in &layers {
+        hidden = block(layer)(&hidden)?;
+    }
+    let plain_loss = loss(&hidden)?;
+    let plain_activations = activation_bytes(&plain_loss);
+    let plain = plain_loss.backward()?;
+
+    for blocks_per_segment in [1, 3, NUM_BLOCKS] {
+        let mut fns = layers.iter().map(block).collect::<Vec<_>>();
+        let mut blocks = fns.iter_mut().map(|f| f as BlockFn).collect::<Vec<_>>();
+        let checkpointing = GradientCheckpointing { blocks_per_segment };
+        let output = checkpointed_backward(&input, &mut blocks, &checkpointing, &vars, loss)?;
+        assert_eq!(
+            output.loss.to_scalar::<f32>()?,
+            plain_loss.to_scalar::<f32>()?
+        );
+        for var in &vars {
+            let expected = plain.get(var).unwrap();
+            let diff = (output.grads.get(var).unwrap() - expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-5, "{blocks_per_segment}: {diff}");
+        }
+        if blocks_per_segment == 1 {
+            assert!(output.activation_bytes < plain_activations);
+        }
+    }
+
+    let checkpointing = GradientCheckpointing {
+        blocks_per_segment: 0,
+    };
+    assert!(checkpointed_backward(&input, &mut [], &checkpointing, &vars, loss).is_err());
+    Ok(())
+}
+
+#[test]
+fn checkpointed_steps_match_plain_steps_and_report_memory() -> Result<()> {
+    let device = Device::Cpu;
+    let mut varmap = VarMap::new();
+    let (layers, scale) = model(&mut varmap, &device)?;
+    let vars = varmap.all_vars();
+    let initial = vars
+        .iter()
+        .map(|var| var.as_tensor().copy())
+        .collect::<Result<Vec<_>>>()?;
+    let x = Tensor::randn(0f32, 1., (16, 8), &device)?;
+
+    let mut updated = Vec::new();
+    for gradient_checkpointing in [None, Some(GradientCheckpointing::default())] {
+        for (var, initial) in vars.iter().zip(&initial) {
+            var.set(initial)?;
+        }
+        let config = TrainingConfig {
+            learning_rate: 0.1,
+            gradient_checkpointing,
+            ..Default::default()
+        };
+        let mut trainer =
+            LoraTrainer::<SGD>::new(vars.clone(), 0.1, config)?.with_memory_profiling(1024);
+        let mut fns = layers.iter().map(block).collect::<Vec<_>>();
+        let mut blocks = fns.iter_mut().map(|f| f as BlockFn).collect::<Vec<_>>();
+        let input = x.broadcast_mul(scale.as_tensor())?;
+        let (loss, info) = trainer.checkpointed_step(&input, &mut blocks, loss)?;
+        assert!(loss.to_scalar::<f32>()? > 0.);
+
+        let memory = info.memory.unwrap();
+        assert_eq!(
+            memory.adapter_bytes,
+            tensors_bytes(vars.iter().map(Var::as_tensor))
+        );
+        assert_eq!(memory.base_bytes, 1024);
+        assert!(memory.activation_bytes > 0);
+        assert_eq!(trainer.peak_memory(), Some(memory));
+        updated.push(
+            vars.iter()
+                .map(|var| var.as_tensor().flatten_all()?.to_vec1::<f32>())
+                .collect::<Result<Vec<_>>>()?,
+        );
+    }
+    for (plain, checkpointed) in updated[0].iter().zip(&updated[1]) {
+        for (u, v) in plain.iter().zip(checkpointed) {
+            assert!((u - v).abs() < 1e-5, "{u} {v}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn gradient_checkpointing_from_json() {
+    let config: TrainingConfig = serde_json::from_str(r#"{"gradient_checkpointing": {}}"#).unwrap();
+    assert_eq!(
+        config.gradient_checkpointing,
+        Some(GradientCheckpointing {
+            blocks_per_segment: 1
+        })
+    );
+    assert_eq!(TrainingConfig::default().gradient_checkpointing, None);
+}
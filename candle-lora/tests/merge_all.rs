@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    Conv2dLayerLike, EmbeddingLayerLike, LinearLayerLike, Lora, LoraConfig, LoraConv2dConfig,
+    LoraEmbeddingConfig, LoraLinearConfig, SelectedLayersBuilder,
+};
+use candle_nn::{Conv2d, Conv2dConfig, Embedding, Linear, VarBuilder};
+
+mod common;
+use common::max_abs_diff;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Proj,
+    Conv,
+    Embed,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Proj => write!(f, "proj"),
+            Self::Conv => write!(f, "conv"),
+            Self::Embed => write!(f, "embed"),
+        }
+    }
+}
+
+#[test]
+fn merge_all_folds_every_layer() -> Result<()> {
+    let device = Device::Cpu;
+    let proj = Linear::new(
+        Tensor::randn(0f32, 1., (6, 4), &device)?,
+        Some(Tensor::randn(0f32, 1., 6, &device)?),
+    );
+    let conv_cfg = Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let conv = Conv2d::new(
+        Tensor::randn(0f32, 1., (4, 3, 3, 3), &device)?,
+        None,
+        conv_cfg,
+    );
+    let embed = Embedding::new(Tensor::randn(0f32, 1., (10, 4), &device)?, 4);
+
+    // Ids follow the layer kinds: linear, then conv2d, then embedding.
+    let tensors = HashMap::from([
+        ("a0.weight", Tensor::randn(0f32, 1., (2, 4), &device)?),
+        ("b0.weight", Tensor::randn(0f32, 1., (6, 2), &device)?),
+        ("a1.weight", Tensor::randn(0f32, 1., (2, 3, 3, 3), &device)?),
+        ("b1.weight", Tensor::randn(0f32, 1., (4, 2, 1, 1), &device)?),
+        ("a2.weight", Tensor::randn(0f32, 1., (2, 10), &device)?),
+        ("b2.weight", Tensor::randn(0f32, 1., (4, 2), &device)?),
+    ])
+    .into_iter()
+    .map(|(name, tensor)| (name.to_string(), tensor))
+    .collect();
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(
+            HashMap::from([(ModelLayers::Proj, &proj as &dyn LinearLayerLike)]),
+            LoraLinearConfig::new(4, 6),
+        )
+        .add_conv2d_layers(
+            HashMap::from([(ModelLayers::Conv, &conv as &dyn Conv2dLayerLike)]),
+            LoraConv2dConfig::new(3, 4),
+        )
+        .add_embed_layers(
+            HashMap::from([(ModelLayers::Embed, &embed as &dyn EmbeddingLayerLike)]),
+            LoraEmbeddingConfig::new(10, 4),
+        )
+        .build();
+    let mut new = Lora::convert_model(selected, LoraConfig::new(2, 4., None), &vb)?;
+
+    let xs = Tensor::randn(0f32, 1., (3, 4), &device)?;
+    let images = Tensor::randn(0f32, 1., (1, 3, 5, 5), &device)?;
+    let ids = Tensor::new(&[1u32, 7, 3], &device)?;
+    let proj_out = new.linear[&ModelLayers::Proj].forward(&xs)?;
+    let conv_out = new.conv2d[&ModelLayers::Conv].forward(&images)?;
+    let embed_out = new.embed[&ModelLayers::Embed].forward(&ids)?;
+
+    // The plain layers compute the same outputs without the adapters.
+    let merged_proj = new.linear[&ModelLayers::Proj].merged_linear().unwrap();
+    assert!(max_abs_diff(&merged_proj.forward(&xs)?, &proj_out)? < 1e-4);
+    let merged_conv = new.conv2d[&ModelLayers::Conv].merged_conv2d().unwrap();
+    assert!(max_abs_diff(&merged_conv.forward(&images)?, &conv_out)? < 1e-3);
+    let merged_embed = new.embed[&ModelLayers::Embed].merged_embedding().unwrap();
+    assert!(max_abs_diff(&merged_embed.forward(&ids)?, &embed_out)? < 1e-4);
+
+    let path = std::env::temp_dir().join("candle_lora_merge_all.safetensors");
+    Lora::save_merged(&new, &path).unwrap();
+    let saved = candle_core::safetensors::load(&path, &device)?;
+    assert_eq!(saved.len(), 4);
+    assert!(max_abs_diff(&saved["proj.weight"], merged_proj.weight())? < 1e-6);
+    assert!(max_abs_diff(&saved["proj.bias"], proj.bias().unwrap())? < 1e-6);
+    assert_eq!(saved["conv.weight"].dims(), [4, 3, 3, 3]);
+    assert_eq!(saved["embed.weight"].dims(), [10, 4]);
+
+    Lora::merge_all(&mut new).unwrap();
+    assert!(max_abs_diff(&new.linear[&ModelLayers::Proj].forward(&xs)?, &proj_out)? < 1e-4);
+    assert!(max_abs_diff(&new.conv2d[&ModelLayers::Conv].forward(&images)?, &conv_out)? < 1e-3);
+    assert!(max_abs_diff(&new.embed[&ModelLayers::Embed].forward(&ids)?, &embed_out)? < 1e-4);
+    // Merging twice is a no-op, and the merged tensors stay the same.
+    Lora::merge_all(&mut new).unwrap();
+    let merged = Lora::merged_tensors(&new).unwrap();
+    assert!(max_abs_diff(&merged["proj.weight"], &saved["proj.weight"])? < 1e-6);
+    Ok(())
+}
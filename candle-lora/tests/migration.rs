@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use candle_core::{Device, Result, Tensor};
+use candle_lora::{
+    migrate_adapter_file, migrate_to_indexed, migrate_to_named, Migration, MODULES_METADATA_KEY,
+};
+use safetensors::SafeTensors;
+
+fn legacy_adapter(device: &Device) -> Result<HashMap<String, Tensor>> {
+    let mut tensors = HashMap::new();
+    for idx in 0..3 {
+        tensors.insert(
+            format!("lora_llama.a{idx}.weight"),
+            Tensor::full(idx as f32, (2, 4), device)?,
+        );
+        tensors.insert(
+            format!("lora_llama.b{idx}.weight"),
+            Tensor::full(-(idx as f32), (4, 2), device)?,
+        );
+    }
+    tensors.insert(
+        "lm_head.weight".to_string(),
+        Tensor::ones((4, 4), candle_core::DType::F32, device)?,
+    );
+    Ok(tensors)
+}
+
+fn modules() -> Vec<String> {
+    [
+        "model.layers.2.self_attn.q_proj",
+        "model.layers.2.self_attn.v_proj",
+        "model.layers.10.self_attn.q_proj",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn value(tensor: &Tensor) -> Result<f32> {
+    tensor.flatten_all()?.get(0)?.to_scalar::<f32>()
+}
+
+#[test]
+fn legacy_names_round_trip() -> Result<()> {
+    let device = Device::Cpu;
+    let legacy = legacy_adapter(&device)?;
+    let named = migrate_to_named(&legacy, "lora_llama", &modules())?;
+    assert_eq!(named.len(), legacy.len());
+    assert_eq!(
+        value(&named["model.layers.10.self_attn.q_proj.a0.weight"])?,
+        2.
+    );
+    assert_eq!(
+        value(&named["model.layers.2.self_attn.v_proj.b0.weight"])?,
+        -1.
+    );
+    assert!(named.contains_key("lm_head.weight"));
+
+    let (indexed, order) = migrate_to_indexed(&named, "lora_llama")?;
+    assert_eq!(order, modules());
+    assert_eq!(indexed.len(), legacy.len());
+    for (name, tensor) in &legacy {
+        assert_eq!(value(&indexed[name])?, value(tensor)?);
+    }
+
+    // Every index needs a module.
+    assert!(migrate_to_named(&legacy, "lora_llama", &modules()[..2]).is_err());
+    Ok(())
+}
+
+#[test]
+fn adapter_files_keep_their_metadata() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let legacy = dir.join("candle_lora_migration_legacy.safetensors");
+    let named = dir.join("candle_lora_migration_named.safetensors");
+    let indexed = dir.join("candle_lora_migration_indexed.safetensors");
+    let metadata = HashMap::from([("format".to_string(), "pt".to_string())]);
+    safetensors::serialize_to_file(legacy_adapter(&device)?.iter(), &Some(metadata), &legacy)?;
+
+    let to_named = |modules| Migration::ToNamed {
+        prefix: "lora_llama".to_string(),
+        modules,
+    };
+    assert!(migrate_adapter_file(&legacy, &named, &to_named(None)).is_err());
+    migrate_adapter_file(&legacy, &named, &to_named(Some(modules())))?;
+    let to_indexed = Migration::ToIndexed {
+        prefix: "lora_llama".to_string(),
+    };
+    migrate_adapter_file(&named, &indexed, &to_indexed)?;
+
+    let data = std::fs::read(&indexed)?;
+    let (_, header) = SafeTensors::read_metadata(&data)?;
+    let metadata = header.metadata().clone().unwrap();
+    assert_eq!(metadata["format"], "pt");
+    let recorded: Vec<String> =
+        serde_json::from_str(&metadata[&format!("{MODULES_METADATA_KEY}.lora_llama")]).unwrap();
+    assert_eq!(recorded, modules());
+
+    // The recorded modules are enough to migrate back.
+    migrate_adapter_file(&indexed, &named, &to_named(None))?;
+    let data = std::fs::read(&named)?;
+    let (_, header) = SafeTensors::read_metadata(&data)?;
+    let metadata = header.metadata().clone().unwrap();
+    assert_eq!(metadata.len(), 1);
+    let tensors = candle_core::safetensors::load(&named, &device)?;
+    assert_eq!(
+        value(&tensors["model.layers.10.self_attn.q_proj.b0.weight"])?,
+        -2.
+    );
+    Ok(())
+}
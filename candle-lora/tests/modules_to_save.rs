@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    apply_modules_to_save, convert_candle_lora_to_peft_dir, convert_peft_dir_to_candle_lora,
+    LoraConfig,
+};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn full_weights_are_converted_and_applied() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join("candle_lora_modules_to_save");
+    let back_dir = dir.join("back");
+    std::fs::create_dir_all(&dir)?;
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let lm_head = Tensor::randn(0f32, 1., (32, 8), &device)?;
+    let embed_tokens = Tensor::randn(0f32, 1., (32, 8), &device)?;
+    let peft = HashMap::from([
+        (
+            format!("{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (2, 8), &device)?,
+        ),
+        (
+            format!("{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (8, 2), &device)?,
+        ),
+        // As saved by `save_pretrained`, and in the state dict of a `PeftModel`.
+        (
+            "base_model.model.lm_head.weight".to_string(),
+            lm_head.clone(),
+        ),
+        (
+            "base_model.model.model.embed_tokens.modules_to_save.default.weight".to_string(),
+            embed_tokens.clone(),
+        ),
+    ]);
+    candle_core::safetensors::save(&peft, dir.join("adapter_model.safetensors"))?;
+    std::fs::write(
+        dir.join("adapter_config.json"),
+        r#"{"peft_type": "LORA", "r": 2, "lora_alpha": 4, "target_modules": ["q_proj"],
+            "modules_to_save": ["lm_head"]}"#,
+    )?;
+    let out_path = dir.join("converted.safetensors");
+
+    let report = convert_peft_dir_to_candle_lora(
+        dir.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora_llama",
+        &device,
+    )?;
+    assert_eq!(
+        report.modules_to_save,
+        ["lm_head.weight", "model.embed_tokens.weight"]
+    );
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.num_tensors, 4);
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(
+        max_abs_diff(&converted["modules_to_save.lm_head.weight"], &lm_head)?,
+        0.
+    );
+
+    // The full weights replace the base weights, in their dtype.
+    let mut base = HashMap::from([
+        (
+            "lm_head.weight".to_string(),
+            Tensor::zeros((32, 8), DType::F16, &device)?,
+        ),
+        (
+            "model.embed_tokens.weight".to_string(),
+            Tensor::zeros((32, 8), DType::F16, &device)?,
+        ),
+        (
+            "model.norm.weight".to_string(),
+            Tensor::ones(8, DType::F16, &device)?,
+        ),
+    ]);
+    let replaced = apply_modules_to_save(&mut base, &converted)?;
+    assert_eq!(replaced, ["lm_head.weight", "model.embed_tokens.weight"]);
+    assert_eq!(base["lm_head.weight"].dtype(), DType::F16);
+    assert!(max_abs_diff(&base["lm_head.weight"], &lm_head)? < 1e-2);
+    assert!(max_abs_diff(&base["model.embed_tokens.weight"], &embed_tokens)? < 1e-2);
+
+    // A resized vocabulary or a missing weight replaces nothing.
+    let mut resized = HashMap::from([
+        (
+            "lm_head.weight".to_string(),
+            Tensor::zeros((30, 8), DType::F32, &device)?,
+        ),
+        (
+            "model.embed_tokens.weight".to_string(),
+            Tensor::zeros((32, 8), DType::F32, &device)?,
+        ),
+    ]);
+    let e = apply_modules_to_save(&mut resized, &converted).unwrap_err();
+    assert!(e.to_string().contains("[32, 8]"), "{e}");
+    assert_eq!(
+        max_abs_diff(
+            &resized["model.embed_tokens.weight"],
+            &embed_tokens.zeros_like()?
+        )?,
+        0.
+    );
+    resized.remove("lm_head.weight");
+    assert!(apply_modules_to_save(&mut resized, &converted).is_err());
+
+    // Back to PEFT, with the modules in the adapter config.
+    convert_candle_lora_to_peft_dir(
+        out_path.to_str().unwrap(),
+        back_dir.to_str().unwrap(),
+        "lora_llama",
+        &["model.layers.0.self_attn.q_proj".to_string()],
+        &LoraConfig::new(2, 4., None),
+        "base",
+        &device,
+    )?;
+    let back = candle_core::safetensors::load(back_dir.join("adapter_model.safetensors"), &device)?;
+    assert_eq!(
+        max_abs_diff(
+            &back["base_model.model.model.embed_tokens.weight"],
+            &embed_tokens
+        )?,
+        0.
+    );
+    let config = std::fs::read_to_string(back_dir.join("adapter_config.json"))?;
+    let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+    assert_eq!(
+        config["modules_to_save"],
+        serde_json::json!(["embed_tokens", "lm_head"])
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use candle_core::{Device, Module, Result, Tensor};
+use candle_lora::{
+    select_batch_adapters, Conv2dLayerLike, EmbeddingLayerLike, LinearLayerLike, Lora, LoraConfig,
+    LoraConv1d, LoraConv1dConfig, LoraConv2dConfig, LoraEmbeddingConfig, LoraLinear,
+    LoraLinearConfig, Merge, MultiAdapter, NewLayers, SelectedLayersBuilder, DEFAULT_ADAPTER,
+};
+use candle_nn::{Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, Embedding, Linear, VarBuilder};
+
+mod common;
+use common::{adapter_vb, max_abs_diff, multi_layer_adapter};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Proj,
+    Conv,
+    Embed,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Proj => write!(f, "proj"),
+            Self::Conv => write!(f, "conv"),
+            Self::Embed => write!(f, "embed"),
+        }
+    }
+}
+
+#[test]
+fn weighted_adapters_combine_their_outputs() -> Result<()> {
+    let device = Device::Cpu;
+    let proj = Linear::new(Tensor::randn(0f32, 1., (6, 4), &device)?, None);
+    let conv = Conv2d::new(
+        Tensor::randn(0f32, 1., (4, 3, 3, 3), &device)?,
+        None,
+        Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        },
+    );
+    let embed = Embedding::new(Tensor::randn(0f32, 1., (10, 4), &device)?, 4);
+    let config = LoraConfig::new(2, 4., None);
+    let convert = |vb: &VarBuilder| {
+        let selected = SelectedLayersBuilder::new()
+            .add_linear_layers(
+                HashMap::from([(ModelLayers::Proj, &proj as &dyn LinearLayerLike)]),
+                LoraLinearConfig::new(4, 6),
+            )
+            .add_conv2d_layers(
+                HashMap::from([(ModelLayers::Conv, &conv as &dyn Conv2dLayerLike)]),
+                LoraConv2dConfig::new(3, 4),
+            )
+            .add_embed_layers(
+                HashMap::from([(ModelLayers::Embed, &embed as &dyn EmbeddingLayerLike)]),
+                LoraEmbeddingConfig::new(10, 4),
+            )
+            .build();
+        Lora::convert_model(selected, config.clone(), &vb.pp("lora"))
+    };
+
+    let style = multi_layer_adapter(2, &device)?;
+    let domain = multi_layer_adapter(4, &device)?;
+    let mut model = convert(&style)?;
+    let style_model = convert(&style)?;
+    let domain_model = convert(&domain)?;
+    Lora::add_adapter(&mut model, "domain", &domain, &config)?;
+    Lora::set_adapter_weights(&mut model, &[(DEFAULT_ADAPTER, 0.7), ("domain", 0.3)])?;
+
+    // The base outputs are weighted by 0.7 + 0.3 = 1 too.
+    let xs = Tensor::randn(0f32, 1., (3, 4), &device)?;
+    let images = Tensor::randn(0f32, 1., (1, 3, 5, 5), &device)?;
+    let ids = Tensor::new(&[1u32, 7, 3], &device)?;
+    let check = |model: &NewLayers<ModelLayers>, tolerance: f32| -> Result<()> {
+        let weighted = |style: Tensor, domain: Tensor| (style * 0.7)? + (domain * 0.3)?;
+        let proj = ModelLayers::Proj;
+        let expected = weighted(
+            style_model.linear[&proj].forward(&xs)?,
+            domain_model.linear[&proj].forward(&xs)?,
+        )?;
+        let actual = model.linear[&proj].forward(&xs)?;
+        assert!(max_abs_diff(&actual, &expected)? < tolerance);
+        let conv = ModelLayers::Conv;
+        let expected = weighted(
+            style_model.conv2d[&conv].forward(&images)?,
+            domain_model.conv2d[&conv].forward(&images)?,
+        )?;
+        let actual = model.conv2d[&conv].forward(&images)?;
+        assert!(max_abs_diff(&actual, &expected)? < tolerance);
+        let embed = ModelLayers::Embed;
+        let expected = weighted(
+            style_model.embed[&embed].forward(&ids)?,
+            domain_model.embed[&embed].forward(&ids)?,
+        )?;
+        let actual = model.embed[&embed].forward(&ids)?;
+        assert!(max_abs_diff(&actual, &expected)? < tolerance);
+        Ok(())
+    };
+    check(&model, 1e-4)?;
+
+    // Merging merges the weighted combination, which is then fixed.
+    let mut merged = convert(&style)?;
+    Lora::add_adapter(&mut merged, "domain", &domain, &config)?;
+    Lora::set_adapter_weights(&mut merged, &[(DEFAULT_ADAPTER, 0.7), ("domain", 0.3)])?;
+    Lora::merge_all(&mut merged).unwrap();
+    check(&merged, 1e-3)?;
+    assert!(Lora::set_adapter_weights(&mut merged, &[("domain", 1.)]).is_err());
+
+    // Baking gives a single adapter of rank 2 + 4 computing the same outputs.
+    Lora::bake_adapter_weights(&mut model)?;
+    check(&model, 1e-4)?;
+    let tensors = {
+        let mut tensors = HashMap::new();
+        candle_lora::Saveable::get_tensors(&model.linear[&ModelLayers::Proj], &mut tensors)?;
+        tensors
+    };
+    assert_eq!(tensors["lora.a0.weight"].dims(), &[6, 4]);
+    assert_eq!(tensors["lora.b0.weight"].dims(), &[6, 6]);
+    Ok(())
+}
+
+#[test]
+fn batch_elements_select_their_adapters() -> Result<()> {
+    let device = Device::Cpu;
+    let proj = Linear::new(Tensor::randn(0f32, 1., (6, 4), &device)?, None);
+    let conv = Conv2d::new(
+        Tensor::randn(0f32, 1., (4, 3, 3, 3), &device)?,
+        None,
+        Conv2dConfig::default(),
+    );
+    let embed = Embedding::new(Tensor::randn(0f32, 1., (10, 4), &device)?, 4);
+    let config = LoraConfig::new(2, 4., None);
+    let convert = |vb: &VarBuilder| {
+        let selected = SelectedLayersBuilder::new()
+            .add_linear_layers(
+                HashMap::from([(ModelLayers::Proj, &proj as &dyn LinearLayerLike)]),
+                LoraLinearConfig::new(4, 6),
+            )
+            .add_conv2d_layers(
+                HashMap::from([(ModelLayers::Conv, &conv as &dyn Conv2dLayerLike)]),
+                LoraConv2dConfig::new(3, 4),
+            )
+            .add_embed_layers(
+                HashMap::from([(ModelLayers::Embed, &embed as &dyn EmbeddingLayerLike)]),
+                LoraEmbeddingConfig::new(10, 4),
+            )
+            .build();
+        Lora::convert_model(selected, config.clone(), &vb.pp("lora"))
+    };
+
+    // Adapters of different ranks, padded in the stacks.
+    let style = multi_layer_adapter(2, &device)?;
+    let domain = multi_layer_adapter(4, &device)?;
+    let mut model = convert(&style)?;
+    let style_model = convert(&style)?;
+    let domain_model = convert(&domain)?;
+    Lora::add_adapter(&mut model, "domain", &domain, &config)?;
+
+    let xs = Tensor::randn(0f32, 1., (3, 5, 4), &device)?;
+    let ids = Tensor::new(&[[1u32, 7], [3, 3], [0, 9]], &device)?;
+    let proj = &model.linear[&ModelLayers::Proj];
+    let embed = &model.embed[&ModelLayers::Embed];
+    let (batched, batched_embed) = {
+        let _adapters = select_batch_adapters(&[0, 1, 1]);
+        // Conv layers do not select adapters per element.
+        let images = Tensor::randn(0f32, 1., (3, 3, 5, 5), &device)?;
+        assert!(model.conv2d[&ModelLayers::Conv].forward(&images).is_err());
+        (proj.forward(&xs)?, embed.forward(&ids)?)
+    };
+    for (i, reference) in [&style_model, &domain_model, &domain_model]
+        .into_iter()
+        .enumerate()
+    {
+        let expected = reference.linear[&ModelLayers::Proj].forward(&xs.get(i)?)?;
+        assert!(max_abs_diff(&batched.get(i)?, &expected)? < 1e-4);
+        let expected = reference.embed[&ModelLayers::Embed].forward(&ids.get(i)?)?;
+        assert!(max_abs_diff(&batched_embed.get(i)?, &expected)? < 1e-4);
+    }
+
+    // Without the selection, the default adapter applies again.
+    let expected = style_model.linear[&ModelLayers::Proj].forward(&xs)?;
+    assert!(max_abs_diff(&proj.forward(&xs)?, &expected)? < 1e-5);
+
+    // The selection must cover the batch with adapters of the layer.
+    let _adapters = select_batch_adapters(&[0, 2, 1]);
+    assert!(proj.forward(&xs).is_err());
+    let _adapters = select_batch_adapters(&[0, 1]);
+    assert!(proj.forward(&xs).is_err());
+    Ok(())
+}
+
+#[test]
+fn weighted_conv1d_adapters_match_their_merged_kernel() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Conv1d::new(
+        Tensor::randn(0f32, 1., (4, 3, 1), &device)?,
+        None,
+        Conv1dConfig::default(),
+    );
+    let adapter = |rank: usize| -> Result<VarBuilder<'static>> {
+        let tensors = [
+            (
+                "lora.a0.weight",
+                Tensor::randn(0f32, 1., (rank, 3), &device)?,
+            ),
+            (
+                "lora.b0.weight",
+                Tensor::randn(0f32, 1., (4, rank), &device)?,
+            ),
+        ];
+        Ok(adapter_vb(tensors, &device))
+    };
+    let config = LoraConfig::new(1, 2., None);
+    // The LoRA kernel of conv1d layers is only defined for a kernel size of 1.
+    let conv_config = LoraConv1dConfig::new(1, 3, 4);
+    let layer = |vb: &VarBuilder| LoraConv1d::new(&base, &conv_config, &config, &vb.pp("lora"), 0);
+    let (first, second) = (adapter(1)?, adapter(3)?);
+    let mut combined = layer(&first)?;
+    combined.add_adapter("second", &second, &config)?;
+    combined.set_adapter_weights(&[(DEFAULT_ADAPTER, 0.5), ("second", 2.)])?;
+
+    let expected_delta = ((layer(&first)?.get_delta_weight().unwrap() * 0.5)?
+        + (layer(&second)?.get_delta_weight().unwrap() * 2.)?)?;
+    assert!(max_abs_diff(&combined.get_delta_weight().unwrap(), &expected_delta)? < 1e-4);
+
+    let xs = Tensor::randn(0f32, 1., (1, 3, 7), &device)?;
+    let expected =
+        Conv1d::new((base.weight() + &expected_delta)?, None, *base.config()).forward(&xs)?;
+    assert!(max_abs_diff(&combined.forward(&xs)?, &expected)? < 1e-4);
+
+    combined.bake_adapter_weights()?;
+    assert!(max_abs_diff(&combined.get_delta_weight().unwrap(), &expected_delta)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn adapters_are_registered_by_name() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (6, 4), &device)?, None);
+    let vb = multi_layer_adapter(2, &device)?;
+    let config = LoraConfig::new(2, 4., None);
+    let mut layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(4, 6),
+        &config,
+        &vb.pp("lora"),
+        0,
+    )?;
+    let xs = Tensor::randn(0f32, 1., (3, 4), &device)?;
+    let default = layer.forward(&xs)?;
+
+    layer.add_adapter("style", &multi_layer_adapter(4, &device)?, &config)?;
+    layer.add_adapter("domain", &multi_layer_adapter(2, &device)?, &config)?;
+    assert_eq!(layer.adapter_names(), [DEFAULT_ADAPTER, "style", "domain"]);
+    assert!(layer.add_adapter(DEFAULT_ADAPTER, &vb, &config).is_err());
+    assert!(layer.set_adapter_weights(&[("unknown", 1.)]).is_err());
+
+    // The default adapter alone with a weight of 1 is the layer without weights.
+    layer.set_adapter_weights(&[(DEFAULT_ADAPTER, 1.)])?;
+    assert!(max_abs_diff(&layer.forward(&xs)?, &default)? < 1e-5);
+
+    // Removing the last weighted adapter goes back to the default adapter.
+    layer.set_adapter_weights(&[("style", 1.)])?;
+    assert!(max_abs_diff(&layer.forward(&xs)?, &default)? > 1e-3);
+    assert!(layer.remove_adapter("style")?);
+    assert!(!layer.remove_adapter("style")?);
+    assert_eq!(layer.adapter_names(), [DEFAULT_ADAPTER, "domain"]);
+    assert!(max_abs_diff(&layer.forward(&xs)?, &default)? < 1e-5);
+
+    // A baked layer without weights is unchanged.
+    layer.bake_adapter_weights()?;
+    assert!(max_abs_diff(&layer.forward(&xs)?, &default)? < 1e-5);
+    Ok(())
+}
@@ -0,0 +1,1167 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    convert_candle_lora_to_peft, convert_candle_lora_to_peft_dir, convert_peft_dir_to_candle_lora,
+    convert_peft_dir_to_candle_lora_typed, convert_peft_to_candle_lora,
+    convert_peft_to_candle_lora_traced, convert_peft_to_candle_lora_traced_with_hooks,
+    convert_peft_to_candle_lora_typed, convert_peft_to_candle_lora_typed_with_hooks,
+    convert_peft_to_candle_lora_with_hooks, convert_peft_to_candle_lora_with_manifest,
+    convert_peft_to_candle_lora_with_options, load_peft_adapter, load_peft_weights,
+    save_torch_state_dict, ConversionHooks, ConvertOptions, LinearLayerLike, Lora, LoraConfig,
+    LoraLinearConfig, PeftConfig, SelectedLayersBuilder, TracedArchitecture,
+};
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+#[test]
+fn traced_granite() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_granite_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_granite_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for module in ["self_attn.q_proj", "mlp.down_proj"] {
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_B.weight"),
+            Tensor::zeros((32, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Granite,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 4);
+    assert_eq!(
+        converted["model.layers.0.self_attn.q_proj.traced_lora_linear.a0.weight"].dims(),
+        &[4, 16]
+    );
+    assert_eq!(
+        converted["model.layers.0.mlp.down_proj.traced_lora_linear.b0.weight"].dims(),
+        &[32, 4]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn traced_starcoder2_module_path() {
+    assert_eq!(
+        TracedArchitecture::Starcoder2.module_path("base_model.model.model.layers.3.mlp.c_fc"),
+        "model.layers.3.mlp.c_fc"
+    );
+}
+
+#[test]
+fn traced_deepseek2_skips_experts() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_deepseek2_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_deepseek2_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for module in ["self_attn.kv_a_proj_with_mqa", "mlp.experts.3.up_proj"] {
+        peft.insert(
+            format!("base_model.model.model.layers.1.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.1.{module}.lora_B.weight"),
+            Tensor::zeros((8, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    let report = convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::DeepSeekV2,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let mut names = converted.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "model.layers.1.self_attn.kv_a_proj_with_mqa.traced_lora_linear.a0.weight",
+            "model.layers.1.self_attn.kv_a_proj_with_mqa.traced_lora_linear.b0.weight",
+        ]
+    );
+    assert_eq!(report.num_tensors, 2);
+    assert_eq!(
+        report.skipped,
+        [
+            "base_model.model.model.layers.1.mlp.experts.3.up_proj.lora_A.weight",
+            "base_model.model.model.layers.1.mlp.experts.3.up_proj.lora_B.weight",
+        ]
+    );
+
+    // Strict conversions reject the experts, and the adapters are cast to the target dtype.
+    let convert = |options: &ConvertOptions| {
+        convert_peft_to_candle_lora_traced_with_hooks(
+            peft_path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            TracedArchitecture::DeepSeekV2,
+            &device,
+            &ConversionHooks::default(),
+            options,
+        )
+    };
+    std::fs::remove_file(&out_path)?;
+    let e = convert(&ConvertOptions::new().with_strict(true)).unwrap_err();
+    assert!(e.to_string().contains("experts.3.up_proj"), "{e}");
+    assert!(!out_path.exists());
+    convert(&ConvertOptions::new().with_target_dtype(DType::BF16))?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert!(converted.values().all(|t| t.dtype() == DType::BF16));
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
+
+#[test]
+fn traced_internlm2_splits_wqkv() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_internlm2_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_internlm2_out.safetensors");
+
+    // 4 query heads sharing 2 key-value heads, head_dim 2: rows are [q q k v q q k v] per head.
+    let lora_b = Tensor::arange(0f32, 16., &device)?.reshape((16, 1))?;
+    let mut peft = HashMap::new();
+    peft.insert(
+        "base_model.model.model.layers.0.attention.wqkv.lora_A.weight".to_string(),
+        Tensor::ones((1, 8), DType::F32, &device)?,
+    );
+    peft.insert(
+        "base_model.model.model.layers.0.attention.wqkv.lora_B.weight".to_string(),
+        lora_b,
+    );
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::InternLM2 {
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+        },
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let b = |proj: &str| -> Result<Vec<f32>> {
+        converted[&format!("model.layers.0.attention.{proj}.traced_lora_linear.b0.weight")]
+            .flatten_all()?
+            .to_vec1()
+    };
+    assert_eq!(b("q_proj")?, [0., 1., 2., 3., 8., 9., 10., 11.]);
+    assert_eq!(b("k_proj")?, [4., 5., 12., 13.]);
+    assert_eq!(b("v_proj")?, [6., 7., 14., 15.]);
+    assert_eq!(
+        converted["model.layers.0.attention.k_proj.traced_lora_linear.a0.weight"].dims(),
+        &[1, 8]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn traced_internlm2_renames_llama_style_modules() {
+    let arch = TracedArchitecture::InternLM2 {
+        num_attention_heads: 32,
+        num_key_value_heads: 8,
+    };
+    assert_eq!(
+        arch.module_path("base_model.model.model.layers.2.self_attn.o_proj"),
+        "model.layers.2.attention.wo"
+    );
+    assert_eq!(
+        arch.module_path("base_model.model.model.layers.2.self_attn.q_proj"),
+        "model.layers.2.attention.q_proj"
+    );
+    assert_eq!(
+        arch.module_path("base_model.model.model.layers.2.mlp.up_proj"),
+        "model.layers.2.feed_forward.w3"
+    );
+}
+
+#[test]
+fn traced_yi() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_yi_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_yi_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for module in ["self_attn.k_proj", "mlp.gate_proj"] {
+        peft.insert(
+            format!("base_model.model.model.layers.5.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.5.{module}.lora_B.weight"),
+            Tensor::zeros((8, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Yi,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let mut names = converted.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "model.layers.5.mlp.gate_proj.traced_lora_linear.a0.weight",
+            "model.layers.5.mlp.gate_proj.traced_lora_linear.b0.weight",
+            "model.layers.5.self_attn.k_proj.traced_lora_linear.a0.weight",
+            "model.layers.5.self_attn.k_proj.traced_lora_linear.b0.weight",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn traced_phi3_keeps_fused_projections() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_phi3_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_phi3_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for module in ["self_attn.qkv_proj", "mlp.gate_up_proj"] {
+        peft.insert(
+            format!("base_model.model.model.layers.3.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.3.{module}.lora_B.weight"),
+            Tensor::zeros((48, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Phi3,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let mut names = converted.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "model.layers.3.mlp.gate_up_proj.traced_lora_linear.a0.weight",
+            "model.layers.3.mlp.gate_up_proj.traced_lora_linear.b0.weight",
+            "model.layers.3.self_attn.qkv_proj.traced_lora_linear.a0.weight",
+            "model.layers.3.self_attn.qkv_proj.traced_lora_linear.b0.weight",
+        ]
+    );
+    assert_eq!(
+        converted["model.layers.3.self_attn.qkv_proj.traced_lora_linear.b0.weight"].dims(),
+        [48, 4]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn traced_baichuan_splits_w_pack() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_baichuan_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_baichuan_out.safetensors");
+
+    let mut peft = HashMap::new();
+    peft.insert(
+        "base_model.model.model.layers.0.self_attn.W_pack.lora_A.weight".to_string(),
+        Tensor::ones((1, 4), DType::F32, &device)?,
+    );
+    peft.insert(
+        "base_model.model.model.layers.0.self_attn.W_pack.lora_B.weight".to_string(),
+        Tensor::arange(0f32, 12., &device)?.reshape((12, 1))?,
+    );
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Baichuan,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 6);
+    let b = |proj: &str| -> Result<Vec<f32>> {
+        converted[&format!("model.layers.0.self_attn.{proj}.traced_lora_linear.b0.weight")]
+            .flatten_all()?
+            .to_vec1()
+    };
+    assert_eq!(b("q_proj")?, [0., 1., 2., 3.]);
+    assert_eq!(b("k_proj")?, [4., 5., 6., 7.]);
+    assert_eq!(b("v_proj")?, [8., 9., 10., 11.]);
+
+    Ok(())
+}
+
+#[test]
+fn traced_chatglm_keeps_fused_projections() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_chatglm_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_chatglm_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for (module, out_features) in [
+        ("self_attention.query_key_value", 24),
+        ("mlp.dense_h_to_4h", 32),
+    ] {
+        peft.insert(
+            format!("base_model.model.transformer.encoder.layers.2.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.transformer.encoder.layers.2.{module}.lora_B.weight"),
+            Tensor::zeros((out_features, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::ChatGLM,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 4);
+    assert_eq!(
+        converted[
+            "transformer.encoder.layers.2.self_attention.query_key_value.traced_lora_linear.b0.weight"
+        ]
+        .dims(),
+        &[24, 4]
+    );
+    assert_eq!(
+        converted["transformer.encoder.layers.2.mlp.dense_h_to_4h.traced_lora_linear.b0.weight"]
+            .dims(),
+        &[32, 4]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn traced_marian_encoder_decoder() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_marian_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_marian_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for module in [
+        "encoder.layers.0.self_attn.q_proj",
+        "decoder.layers.0.encoder_attn.v_proj",
+        "decoder.layers.1.fc2",
+    ] {
+        peft.insert(
+            format!("base_model.model.model.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.{module}.lora_B.weight"),
+            Tensor::zeros((16, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Marian,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 6);
+    for module in [
+        "encoder.layers.0.self_attn.q_proj",
+        "decoder.layers.0.encoder_attn.v_proj",
+        "decoder.layers.1.fc2",
+    ] {
+        assert!(converted.contains_key(&format!("model.{module}.traced_lora_linear.a0.weight")));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn traced_t5_encoder_and_decoder_blocks() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_t5_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_t5_out.safetensors");
+
+    let mut peft = HashMap::new();
+    for module in [
+        "encoder.block.0.layer.0.SelfAttention.q",
+        "decoder.block.1.layer.1.EncDecAttention.v",
+        "decoder.block.1.layer.2.DenseReluDense.wo",
+        "shared",
+    ] {
+        peft.insert(
+            format!("base_model.model.{module}.lora_A.weight"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.{module}.lora_B.weight"),
+            Tensor::zeros((16, 4), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::T5,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    // The adapter of the shared embedding is dropped.
+    assert_eq!(converted.len(), 6);
+    for module in [
+        "encoder.block.0.layer.0.SelfAttention.q",
+        "decoder.block.1.layer.1.EncDecAttention.v",
+        "decoder.block.1.layer.2.DenseReluDense.wo",
+    ] {
+        assert!(converted.contains_key(&format!("{module}.traced_lora_linear.b0.weight")));
+    }
+    assert!(TracedArchitecture::T5.adapts("lm_head"));
+
+    Ok(())
+}
+
+#[test]
+fn traced_whisper_adapts_cross_attention() {
+    let arch = TracedArchitecture::Whisper;
+    let module = arch.module_path("base_model.model.model.decoder.layers.3.encoder_attn.k_proj");
+    assert_eq!(module, "model.decoder.layers.3.encoder_attn.k_proj");
+    assert!(arch.adapts(&module));
+    assert!(arch.adapts("model.encoder.layers.0.fc1"));
+    assert!(!arch.adapts("model.encoder.conv1"));
+    assert!(!arch.adapts("proj_out"));
+}
+
+#[test]
+fn traced_wav2vec2_adapts_encoder_layers() {
+    let arch = TracedArchitecture::Wav2Vec2;
+    assert!(arch.adapts("base_model.model.hubert.encoder.layers.4.feed_forward.output_dense"));
+    assert!(!arch.adapts("base_model.model.wav2vec2.feature_projection.projection"));
+    assert_eq!(
+        arch.module_path("base_model.model.wav2vec2.encoder.layers.4.attention.k_proj"),
+        "wav2vec2.encoder.layers.4.attention.k_proj"
+    );
+}
+
+#[test]
+fn traced_musicgen_decoder_adapters() {
+    let arch = TracedArchitecture::Musicgen;
+    assert_eq!(
+        arch.module_path("base_model.model.decoder.model.decoder.layers.7.self_attn.v_proj"),
+        "decoder.model.decoder.layers.7.self_attn.v_proj"
+    );
+    assert_eq!(
+        arch.module_path("base_model.model.model.decoder.layers.7.fc1"),
+        "decoder.model.decoder.layers.7.fc1"
+    );
+    assert!(!arch.adapts("base_model.model.text_encoder.encoder.block.0.layer.0.SelfAttention.q"));
+}
+
+#[test]
+fn traced_sam_image_encoder_adapters() {
+    let arch = TracedArchitecture::Sam;
+    assert_eq!(
+        arch.module_path("base_model.model.image_encoder.blocks.3.attn.qkv"),
+        "image_encoder.blocks.3.attn.qkv"
+    );
+    assert_eq!(
+        arch.module_path("base_model.model.vision_encoder.layers.3.mlp.lin2"),
+        "image_encoder.blocks.3.mlp.lin2"
+    );
+    assert!(arch.adapts("image_encoder.blocks.3.attn.proj"));
+    assert!(!arch.adapts("mask_decoder.transformer.layers.0.self_attn.q_proj"));
+}
+
+#[test]
+fn traced_rwkv_native_lora_naming() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let lora_path = dir.join("candle_lora_traced_rwkv_lora.safetensors");
+    let out_path = dir.join("candle_lora_traced_rwkv_out.safetensors");
+
+    let mut lora = HashMap::new();
+    for module in ["att.receptance", "ffn.value"] {
+        lora.insert(
+            format!("blocks.2.{module}.lora_A"),
+            Tensor::zeros((8, 64), DType::F32, &device)?,
+        );
+        lora.insert(
+            format!("blocks.2.{module}.lora_B"),
+            Tensor::zeros((64, 8), DType::F32, &device)?,
+        );
+    }
+    // Fully trained parameters saved alongside the LoRA pairs are not converted.
+    lora.insert(
+        "blocks.2.ln1.weight".to_string(),
+        Tensor::zeros(64, DType::F32, &device)?,
+    );
+    candle_core::safetensors::save(&lora, &lora_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        lora_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Rwkv,
+        &device,
+    )?;
+
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 4);
+    assert!(
+        converted.contains_key("rwkv.blocks.2.attention.receptance.traced_lora_linear.a0.weight")
+    );
+    assert!(converted.contains_key("rwkv.blocks.2.feed_forward.value.traced_lora_linear.b0.weight"));
+    assert_eq!(
+        TracedArchitecture::Rwkv.module_path("base_model.model.rwkv.blocks.0.attention.gate"),
+        "rwkv.blocks.0.attention.gate"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn traced_mpt_keeps_fused_wqkv() {
+    let arch = TracedArchitecture::Mpt;
+    let module = arch.module_path("base_model.model.transformer.blocks.5.attn.Wqkv");
+    assert_eq!(module, "transformer.blocks.5.attn.Wqkv");
+    assert!(arch.adapts(&module));
+    assert!(arch.adapts("transformer.blocks.5.ffn.down_proj"));
+    assert!(!arch.adapts("transformer.wte"));
+}
+
+#[test]
+fn traced_gpt_neox_keeps_fused_query_key_value() {
+    let arch = TracedArchitecture::GptNeoX;
+    let module = arch.module_path("base_model.model.gpt_neox.layers.3.attention.query_key_value");
+    assert_eq!(module, "gpt_neox.layers.3.attention.query_key_value");
+    assert!(arch.adapts(&module));
+    assert!(arch.adapts("gpt_neox.layers.3.mlp.dense_4h_to_h"));
+    assert!(!arch.adapts("embed_out"));
+}
+
+#[test]
+fn traced_bloom_splits_query_key_value() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_traced_bloom_peft.safetensors");
+    let out_path = dir.join("candle_lora_traced_bloom_out.safetensors");
+
+    let mut peft = HashMap::new();
+    peft.insert(
+        "base_model.model.transformer.h.0.self_attention.query_key_value.lora_A.weight".to_string(),
+        Tensor::ones((1, 4), DType::F32, &device)?,
+    );
+    peft.insert(
+        "base_model.model.transformer.h.0.self_attention.query_key_value.lora_B.weight".to_string(),
+        Tensor::arange(0f32, 12., &device)?.reshape((12, 1))?,
+    );
+    candle_core::safetensors::save(&peft, &peft_path)?;
+
+    convert_peft_to_candle_lora_traced(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        TracedArchitecture::Bloom {
+            num_attention_heads: 2,
+        },
+        &device,
+    )?;
+
+    // Two heads of two rows, laid out as [q0, k0, v0, q1, k1, v1].
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 6);
+    let b = |proj: &str| -> Result<Vec<f32>> {
+        converted[&format!("transformer.h.0.self_attention.{proj}.traced_lora_linear.b0.weight")]
+            .flatten_all()?
+            .to_vec1()
+    };
+    assert_eq!(b("q_proj")?, [0., 1., 6., 7.]);
+    assert_eq!(b("k_proj")?, [2., 3., 8., 9.]);
+    assert_eq!(b("v_proj")?, [4., 5., 10., 11.]);
+
+    Ok(())
+}
+
+#[test]
+fn candle_lora_to_peft_round_trip() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_reverse_peft.safetensors");
+    let candle_path = dir.join("candle_lora_reverse_candle.safetensors");
+    let back_path = dir.join("candle_lora_reverse_back.safetensors");
+
+    let modules = [
+        "model.layers.0.self_attn.q_proj",
+        "model.layers.0.self_attn.v_proj",
+        "model.layers.1.self_attn.q_proj",
+    ];
+    let mut peft = HashMap::new();
+    for (i, module) in modules.iter().enumerate() {
+        let rank = if i == 2 { 8 } else { 4 };
+        peft.insert(
+            format!("base_model.model.{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (rank, 16), &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (16, rank), &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, &peft_path)?;
+    convert_peft_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        candle_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+
+    let modules = modules.map(String::from);
+    convert_candle_lora_to_peft(
+        candle_path.to_str().unwrap(),
+        back_path.to_str().unwrap(),
+        "lora_llama",
+        &modules,
+        &device,
+    )?;
+    let back = candle_core::safetensors::load(&back_path, &device)?;
+    assert_eq!(back.len(), peft.len());
+    for (name, tensor) in &peft {
+        let diff = (&back[name] - tensor)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert_eq!(diff, 0.);
+    }
+
+    // Every index needs a module.
+    assert!(convert_candle_lora_to_peft(
+        candle_path.to_str().unwrap(),
+        back_path.to_str().unwrap(),
+        "lora_llama",
+        &modules[..2],
+        &device,
+    )
+    .is_err());
+
+    let out_dir = dir.join("candle_lora_reverse_peft_dir");
+    convert_candle_lora_to_peft_dir(
+        candle_path.to_str().unwrap(),
+        out_dir.to_str().unwrap(),
+        "lora_llama",
+        &modules,
+        &LoraConfig::new(4, 8., Some(0.1)),
+        "meta-llama/Llama-2-7b-hf",
+        &device,
+    )?;
+    let config = std::fs::read_to_string(out_dir.join("adapter_config.json"))?;
+    let parsed: PeftConfig = serde_json::from_str(&config).unwrap();
+    assert_eq!(parsed.r, 4);
+    assert_eq!(parsed.lora_alpha, 8.);
+    assert_eq!(parsed.target_modules, ["q_proj", "v_proj"]);
+    assert_eq!(parsed.base_model_name_or_path, "meta-llama/Llama-2-7b-hf");
+    let json: serde_json::Value = serde_json::from_str(&config).unwrap();
+    assert_eq!(
+        json["rank_pattern"],
+        serde_json::json!({"model.layers.1.self_attn.q_proj": 8})
+    );
+    let tensors =
+        candle_core::safetensors::load(out_dir.join("adapter_model.safetensors"), &device)?;
+    assert_eq!(tensors.len(), peft.len());
+    Ok(())
+}
+
+#[test]
+fn traced_candle_lora_to_peft() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let traced_path = dir.join("candle_lora_reverse_traced.safetensors");
+    let peft_path = dir.join("candle_lora_reverse_traced_peft.safetensors");
+    let tensors = HashMap::from([
+        (
+            "model.layers.0.mlp.down_proj.traced_lora_linear.a0.weight".to_string(),
+            Tensor::zeros((2, 8), DType::F32, &device)?,
+        ),
+        (
+            "model.layers.0.mlp.down_proj.traced_lora_linear.b0.weight".to_string(),
+            Tensor::zeros((4, 2), DType::F32, &device)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&tensors, &traced_path)?;
+    convert_candle_lora_to_peft(
+        traced_path.to_str().unwrap(),
+        peft_path.to_str().unwrap(),
+        "lora_llama",
+        &[],
+        &device,
+    )?;
+    let peft = candle_core::safetensors::load(&peft_path, &device)?;
+    assert_eq!(
+        peft["base_model.model.model.layers.0.mlp.down_proj.lora_A.weight"].dims(),
+        [2, 8]
+    );
+    assert_eq!(
+        peft["base_model.model.model.layers.0.mlp.down_proj.lora_B.weight"].dims(),
+        [4, 2]
+    );
+    Ok(())
+}
+
+#[test]
+fn typed_dir_conversion_reports_config() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_dir = dir.join("candle_lora_typed_report");
+    let out_path = dir.join("candle_lora_typed_report_out.safetensors");
+    std::fs::create_dir_all(&peft_dir)?;
+
+    let mut peft = HashMap::new();
+    for (module, rank) in [
+        ("self_attn.v_proj", 8),
+        ("self_attn.q_proj", 8),
+        ("mlp.up_proj", 4),
+    ] {
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_A.weight"),
+            Tensor::zeros((rank, 16), DType::F32, &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_B.weight"),
+            Tensor::zeros((16, rank), DType::F32, &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, peft_dir.join("adapter_model.safetensors"))?;
+    let config = |r: usize| {
+        format!(
+            r#"{{"peft_type": "LORA", "r": {r}, "lora_alpha": 16, "lora_dropout": 0.05,
+                "target_modules": ["q_proj", "v_proj", "up_proj"]}}"#
+        )
+    };
+    std::fs::write(peft_dir.join("adapter_config.json"), config(8))?;
+
+    let report = convert_peft_dir_to_candle_lora_typed(
+        peft_dir.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        &device,
+        Some((100, 16)),
+    )?;
+    assert_eq!(report.rank, 8);
+    assert_eq!(report.alpha, Some(16.));
+    assert_eq!(report.scaling, Some(2.));
+    assert_eq!(report.dropout, Some(0.05));
+    assert_eq!(
+        report.target_modules,
+        [
+            "base_model.model.model.layers.0.self_attn.q_proj",
+            "base_model.model.model.layers.0.self_attn.v_proj",
+            "base_model.model.model.layers.0.mlp.up_proj",
+        ]
+    );
+    assert_eq!(report.num_modules, 3);
+    assert_eq!(report.num_tensors, 8);
+    assert!(report.lora_config().is_some());
+
+    // The dummy embeddings have the rank of the adapter.
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted.len(), 8);
+    assert_eq!(converted["lora_llama.a0.weight"].dims(), [8, 100]);
+    assert_eq!(converted["lora_llama.b0.weight"].dims(), [16, 8]);
+    // The MLP projections have their own prefix, the attention projections theirs.
+    assert_eq!(converted["lora_llama_mlp.a0.weight"].dims(), [4, 16]);
+    assert_eq!(converted["lora_llama_csa.a1.weight"].dims(), [8, 16]);
+    assert!(!converted.keys().any(|k| k.starts_with("lora_llama_block.")));
+
+    // A config whose rank no module has is rejected.
+    std::fs::write(peft_dir.join("adapter_config.json"), config(16))?;
+    assert!(convert_peft_dir_to_candle_lora(
+        peft_dir.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora_llama",
+        &device,
+    )
+    .is_err());
+
+    std::fs::remove_dir_all(&peft_dir)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
+
+#[test]
+fn load_peft_adapter_in_memory() -> Result<()> {
+    let device = Device::Cpu;
+    let peft_dir = std::env::temp_dir().join("candle_lora_load_peft_adapter");
+    std::fs::create_dir_all(&peft_dir)?;
+
+    let modules = ["self_attn.v_proj", "self_attn.q_proj"];
+    let mut peft = HashMap::new();
+    for module in modules {
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (2, 8), &device)?,
+        );
+        peft.insert(
+            format!("base_model.model.model.layers.0.{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (8, 2), &device)?,
+        );
+    }
+    candle_core::safetensors::save(&peft, peft_dir.join("adapter_model.safetensors"))?;
+    std::fs::write(
+        peft_dir.join("adapter_config.json"),
+        r#"{"peft_type": "LORA", "r": 2, "lora_alpha": 8, "target_modules": ["q_proj", "v_proj"]}"#,
+    )?;
+
+    let bases = modules.map(|_| Tensor::randn(0f32, 1., (8, 8), &device));
+    let bases = bases
+        .into_iter()
+        .map(|weight| Ok(Linear::new(weight?, None)))
+        .collect::<Result<Vec<_>>>()?;
+    let layers = modules
+        .iter()
+        .zip(&bases)
+        .map(|(module, base)| {
+            let name = format!("model.layers.0.{module}");
+            (name, base as &dyn LinearLayerLike)
+        })
+        .collect::<HashMap<_, _>>();
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(8, 8))
+        .build();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut new = Lora::convert_model(selected, LoraConfig::new(2, 8., None), &vb.pp("lora"))?;
+
+    let report = load_peft_adapter(peft_dir.to_str().unwrap(), &mut new, &device)?;
+    assert_eq!(report.num_modules, 2);
+    assert_eq!(report.scaling, Some(4.));
+
+    let xs = Tensor::randn(0f32, 1., (3, 8), &device)?;
+    for (module, base) in modules.iter().zip(&bases) {
+        let peft_module = format!("base_model.model.model.layers.0.{module}");
+        let a = &peft[&format!("{peft_module}.lora_A.weight")];
+        let b = &peft[&format!("{peft_module}.lora_B.weight")];
+        let delta = (b.matmul(a)? * 4.)?;
+        let expected = Linear::new((base.weight() + delta)?, None).forward(&xs)?;
+        let layer = &new.linear[&format!("model.layers.0.{module}")];
+        let diff = (layer.forward(&xs)? - expected)?.abs()?.max_all()?;
+        assert!(diff.to_scalar::<f32>()? < 1e-4);
+    }
+
+    std::fs::remove_dir_all(&peft_dir)?;
+    Ok(())
+}
+
+#[test]
+fn sharded_and_pytorch_peft_dirs() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let out_path = dir.join("candle_lora_sharded_out.safetensors");
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let lora_a = Tensor::randn(0f32, 1., (2, 8), &device)?;
+    let lora_b = Tensor::randn(0f32, 1., (8, 2), &device)?;
+    let shards = [
+        (
+            "adapter_model-00001-of-00002.safetensors",
+            "lora_A",
+            &lora_a,
+        ),
+        (
+            "adapter_model-00002-of-00002.safetensors",
+            "lora_B",
+            &lora_b,
+        ),
+    ];
+
+    // Shards listed by an index.
+    let sharded_dir = dir.join("candle_lora_sharded_peft");
+    std::fs::create_dir_all(&sharded_dir)?;
+    let mut weight_map = serde_json::Map::new();
+    for (file, kind, tensor) in shards {
+        let name = format!("{module}.{kind}.weight");
+        let shard = HashMap::from([(name.clone(), tensor.clone())]);
+        candle_core::safetensors::save(&shard, sharded_dir.join(file))?;
+        weight_map.insert(name, file.into());
+    }
+    let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+    std::fs::write(
+        sharded_dir.join("adapter_model.safetensors.index.json"),
+        index.to_string(),
+    )?;
+
+    // A PyTorch checkpoint.
+    let bin_dir = dir.join("candle_lora_bin_peft");
+    std::fs::create_dir_all(&bin_dir)?;
+    let tensors = HashMap::from([
+        (format!("{module}.lora_A.weight"), lora_a.clone()),
+        (format!("{module}.lora_B.weight"), lora_b.clone()),
+    ]);
+    save_torch_state_dict(&tensors, bin_dir.join("adapter_model.bin"))?;
+
+    for peft_dir in [&sharded_dir, &bin_dir] {
+        let report = convert_peft_dir_to_candle_lora(
+            peft_dir.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            "lora_llama",
+            &device,
+        )?;
+        assert_eq!(report.target_modules, [module]);
+        let converted = candle_core::safetensors::load(&out_path, &device)?;
+        for (name, expected) in [("a0", &lora_a), ("b0", &lora_b)] {
+            let diff = (&converted[&format!("lora_llama.{name}.weight")] - expected)?;
+            assert_eq!(diff.abs()?.max_all()?.to_scalar::<f32>()?, 0.);
+        }
+    }
+
+    // A shard missing a tensor of the index is an error.
+    std::fs::remove_file(sharded_dir.join(shards[1].0))?;
+    assert!(load_peft_weights(sharded_dir.to_str().unwrap(), &device).is_err());
+
+    std::fs::remove_dir_all(&sharded_dir)?;
+    std::fs::remove_dir_all(&bin_dir)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
+
+#[test]
+fn streamed_conversion_matches_loaded_conversion() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_streamed_peft.safetensors");
+    let streamed_path = dir.join("candle_lora_streamed_out.safetensors");
+    let loaded_path = dir.join("candle_lora_loaded_out.safetensors");
+    let mut tensors = HashMap::new();
+    for layer in [0, 1, 10] {
+        for proj in ["q_proj", "v_proj"] {
+            let module = format!("base_model.model.model.layers.{layer}.self_attn.{proj}");
+            let lora_a = Tensor::randn(0f32, 1., (4, 16), &device)?.to_dtype(DType::BF16)?;
+            let lora_b = Tensor::randn(0f32, 1., (16, 4), &device)?.to_dtype(DType::BF16)?;
+            tensors.insert(format!("{module}.lora_A.weight"), lora_a);
+            tensors.insert(format!("{module}.lora_B.weight"), lora_b);
+        }
+    }
+    let magnitude = Tensor::ones(16, DType::BF16, &device)?;
+    tensors.insert(
+        "base_model.model.model.layers.1.self_attn.q_proj.lora_magnitude_vector".to_string(),
+        magnitude,
+    );
+    candle_core::safetensors::save(&tensors, &peft_path)?;
+
+    let manifest = convert_peft_to_candle_lora_with_manifest(
+        peft_path.to_str().unwrap(),
+        streamed_path.to_str().unwrap(),
+        "lora_llama",
+    )?;
+    convert_peft_to_candle_lora_with_hooks(
+        peft_path.to_str().unwrap(),
+        loaded_path.to_str().unwrap(),
+        "lora_llama",
+        &device,
+        &ConversionHooks::default(),
+    )?;
+
+    // The tensors are written as they are, in the structural order of their modules.
+    let streamed = candle_core::safetensors::load(&streamed_path, &device)?;
+    let loaded = candle_core::safetensors::load(&loaded_path, &device)?;
+    assert_eq!(streamed.len(), 13);
+    assert_eq!(streamed.len(), loaded.len());
+    for (name, tensor) in &loaded {
+        assert_eq!(streamed[name].dtype(), DType::BF16);
+        let diff = (&streamed[name].to_dtype(DType::F32)? - tensor.to_dtype(DType::F32)?)?;
+        assert_eq!(diff.abs()?.max_all()?.to_scalar::<f32>()?, 0., "{name}");
+    }
+    assert_eq!(
+        manifest.entries[2].peft_module,
+        "base_model.model.model.layers.1.self_attn.q_proj"
+    );
+    assert_eq!(
+        manifest.entries[2].magnitude.as_deref(),
+        Some("lora_llama.m2.weight")
+    );
+    assert_eq!(manifest.entries[4].a_shape, [4, 16]);
+    assert!(manifest.entries[4]
+        .peft_module
+        .contains("layers.10.self_attn.q_proj"));
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&streamed_path)?;
+    std::fs::remove_file(&loaded_path)?;
+    Ok(())
+}
+
+#[test]
+fn conversion_casts_to_the_target_dtype() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_dtype_peft.safetensors");
+    let out_path = dir.join("candle_lora_dtype_out.safetensors");
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let lora_a = Tensor::randn(0f32, 1., (2, 8), &device)?;
+    let lora_b = Tensor::randn(0f32, 1., (8, 2), &device)?;
+    let save = |lora_a: &Tensor, lora_b: &Tensor| {
+        let tensors = HashMap::from([
+            (format!("{module}.lora_A.weight"), lora_a.clone()),
+            (format!("{module}.lora_B.weight"), lora_b.clone()),
+        ]);
+        candle_core::safetensors::save(&tensors, &peft_path)
+    };
+    let convert = |options: &ConvertOptions| {
+        convert_peft_to_candle_lora_with_options(
+            peft_path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            "lora_llama",
+            options,
+        )
+    };
+    save(&lora_a, &lora_b)?;
+
+    for dtype in [DType::BF16, DType::F16] {
+        convert(&ConvertOptions::new().with_target_dtype(dtype))?;
+        let converted = candle_core::safetensors::load(&out_path, &device)?;
+        let a = &converted["lora_llama.a0.weight"];
+        assert_eq!(a.dtype(), dtype);
+        let diff = (a.to_dtype(DType::F32)? - &lora_a)?.abs()?.max_all()?;
+        assert!(diff.to_scalar::<f32>()? < 1e-2);
+    }
+    convert(&ConvertOptions::default())?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert_eq!(converted["lora_llama.b0.weight"].dtype(), DType::F32);
+
+    // f64 and integer adapters, and integer targets, are errors.
+    assert!(convert(&ConvertOptions::new().with_target_dtype(DType::U8)).is_err());
+    save(&lora_a.to_dtype(DType::F64)?, &lora_b)?;
+    let e = convert(&ConvertOptions::default()).unwrap_err();
+    assert!(e.to_string().contains("f64"), "{e}");
+    save(&lora_a, &lora_b.to_dtype(DType::U8)?)?;
+    assert!(convert(&ConvertOptions::new().with_target_dtype(DType::BF16)).is_err());
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
+
+#[test]
+fn unconverted_tensors_are_reported_or_rejected() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join("candle_lora_skipped_peft.safetensors");
+    let out_path = dir.join("candle_lora_skipped_out.safetensors");
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let tensors = HashMap::from([
+        (
+            format!("{module}.lora_A.weight"),
+            Tensor::zeros((2, 8), DType::F32, &device)?,
+        ),
+        (
+            format!("{module}.lora_B.weight"),
+            Tensor::zeros((8, 2), DType::F32, &device)?,
+        ),
+        (
+            format!("{module}.lora_magnitude_vector"),
+            Tensor::ones(8, DType::F32, &device)?,
+        ),
+        (
+            format!("{module}.lora_B.bias"),
+            Tensor::zeros(8, DType::F32, &device)?,
+        ),
+        (
+            "base_model.model.lm_head.modules_to_save.default.weight".to_string(),
+            Tensor::zeros((16, 8), DType::F32, &device)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&tensors, &peft_path)?;
+    let convert = |options: &ConvertOptions| {
+        convert_peft_to_candle_lora_with_options(
+            peft_path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            "lora_llama",
+            options,
+        )
+    };
+
+    // The DoRA magnitude and the full weight are converted, the bias is listed.
+    let report = convert(&ConvertOptions::new())?;
+    assert_eq!(report.num_tensors, 4);
+    assert_eq!(report.skipped, [format!("{module}.lora_B.bias")]);
+    assert_eq!(report.modules_to_save, ["lm_head.weight"]);
+    std::fs::remove_file(&out_path)?;
+
+    // Strict conversions fail without writing the output.
+    let e = convert(&ConvertOptions::new().with_strict(true)).unwrap_err();
+    assert!(e.to_string().contains("lora_B.bias"), "{e}");
+    assert!(!out_path.exists());
+
+    // The conversions of loaded tensors report them too.
+    let report = convert_peft_to_candle_lora_typed(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        &device,
+        None,
+    )?;
+    assert_eq!(report.skipped.len(), 1);
+    std::fs::remove_file(&out_path)?;
+    assert!(convert_peft_to_candle_lora_typed_with_hooks(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        &device,
+        None,
+        &ConversionHooks::default(),
+        &ConvertOptions::new().with_strict(true),
+    )
+    .is_err());
+    assert!(!out_path.exists());
+    convert_peft_to_candle_lora_typed_with_hooks(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        &device,
+        Some((16, 8)),
+        &ConversionHooks::default(),
+        &ConvertOptions::new().with_target_dtype(DType::F16),
+    )?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    assert!(converted.values().all(|t| t.dtype() == DType::F16));
+
+    std::fs::remove_file(&peft_path)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    EmbeddingLayerLike, LinearLayerLike, Lora, LoraConfig, LoraEmbeddingConfig, LoraLinearConfig,
+    SelectedLayersBuilder,
+};
+use candle_nn::{Embedding, Linear, VarBuilder, VarMap};
+
+#[derive(PartialEq, Eq, Hash)]
+enum ModelLayers {
+    Embed,
+    Proj,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Embed => write!(f, "embed_tokens"),
+            Self::Proj => write!(f, "q_proj"),
+        }
+    }
+}
+
+#[test]
+fn layer_kinds_get_their_own_configs() -> Result<()> {
+    let device = Device::Cpu;
+    let proj = Linear::new(Tensor::zeros((6, 6), DType::F32, &device)?, None);
+    let embed = Embedding::new(Tensor::zeros((10, 6), DType::F32, &device)?, 6);
+
+    let convert = |embed_config: Option<LoraConfig>| -> Result<HashMap<String, Vec<usize>>> {
+        let mut builder = SelectedLayersBuilder::new()
+            .add_linear_layers(
+                HashMap::from([(ModelLayers::Proj, &proj as &dyn LinearLayerLike)]),
+                LoraLinearConfig::new(6, 6),
+            )
+            .add_embed_layers(
+                HashMap::from([(ModelLayers::Embed, &embed as &dyn EmbeddingLayerLike)]),
+                LoraEmbeddingConfig::new(10, 6),
+            );
+        if let Some(embed_config) = embed_config {
+            builder = builder.with_embed_lora_config(embed_config);
+        }
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        Lora::convert_model(builder.build(), LoraConfig::new(2, 4., None), &vb)?;
+        let shapes = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), var.dims().to_vec()))
+            .collect();
+        Ok(shapes)
+    };
+
+    let uniform = convert(None)?;
+    assert_eq!(uniform["a0.weight"], [2, 6]);
+    assert_eq!(uniform["a1.weight"], [2, 10]);
+
+    let per_kind = convert(Some(LoraConfig::new(8, 16., None)))?;
+    assert_eq!(per_kind["a0.weight"], [2, 6]);
+    assert_eq!(per_kind["a1.weight"], [8, 10]);
+    assert_eq!(per_kind["b1.weight"], [6, 8]);
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+use candle_lora::{
+    convert_peft_prompt_to_candle_lora, PeftPromptConfig, PrefixKeyValues, PrefixTuningConfig,
+    PromptEmbedding,
+};
+use candle_nn::{VarBuilder, VarMap};
+
+fn peft_config(peft_type: &str, prefix_projection: bool) -> PeftPromptConfig {
+    serde_json::from_value(serde_json::json!({
+        "peft_type": peft_type,
+        "task_type": "CAUSAL_LM",
+        "num_virtual_tokens": 2,
+        "token_dim": 6,
+        "num_transformer_submodules": 1,
+        "num_attention_heads": 2,
+        "num_layers": 2,
+        "prefix_projection": prefix_projection,
+    }))
+    .unwrap()
+}
+
+fn convert(
+    name: &str,
+    config: &PeftPromptConfig,
+    embeddings: Tensor,
+) -> Result<VarBuilder<'static>> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_path = dir.join(format!("candle_lora_{name}_peft.safetensors"));
+    let out_path = dir.join(format!("candle_lora_{name}_out.safetensors"));
+    let peft = HashMap::from([("prompt_embeddings".to_string(), embeddings)]);
+    candle_core::safetensors::save(&peft, &peft_path)?;
+    convert_peft_prompt_to_candle_lora(
+        peft_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        config,
+        &device,
+    )?;
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    Ok(VarBuilder::from_tensors(converted, DType::F32, &device))
+}
+
+#[test]
+fn prompt_embeddings_are_prepended() -> Result<()> {
+    let device = Device::Cpu;
+    let config = peft_config("PROMPT_TUNING", false);
+    let embeddings = Tensor::arange(0f32, 12., &device)?.reshape((2, 6))?;
+    let vb = convert("prompt_tuning", &config, embeddings.clone())?;
+    let prompt = PromptEmbedding::new(&config.prompt_tuning_config()?, &vb)?;
+    assert_eq!(prompt.num_virtual_tokens(), 2);
+
+    let inputs = Tensor::ones((3, 4, 6), DType::F32, &device)?;
+    let prepended = prompt.prepend(&inputs)?;
+    assert_eq!(prepended.dims(), [3, 6, 6]);
+    for b in 0..3 {
+        assert_eq!(
+            prepended.i((b, 0..2))?.to_vec2::<f32>()?,
+            embeddings.to_vec2::<f32>()?
+        );
+        assert_eq!(
+            prepended.i((b, 2..))?.to_vec2::<f32>()?,
+            inputs.i(b)?.to_vec2::<f32>()?
+        );
+    }
+    assert!(prompt
+        .prepend(&Tensor::ones((1, 4, 5), DType::F32, &device)?)
+        .is_err());
+    assert!(config.prefix_tuning_config().is_err());
+    Ok(())
+}
+
+#[test]
+fn prefix_key_values_follow_the_peft_layout() -> Result<()> {
+    let device = Device::Cpu;
+    let config = peft_config("PREFIX_TUNING", false);
+    // (num_virtual_tokens, num_layers * 2 * token_dim)
+    let embeddings = Tensor::arange(0f32, 48., &device)?.reshape((2, 24))?;
+    let vb = convert("prefix_tuning", &config, embeddings)?;
+    let prefix = PrefixKeyValues::new(&config.prefix_tuning_config()?, &vb)?;
+    assert_eq!(prefix.num_layers(), 2);
+    assert_eq!(prefix.num_virtual_tokens(), 2);
+
+    // PEFT views the embeddings as (tokens, layers * 2, heads, head_dim): the keys of layer 1
+    // are the third block of 6 columns and its values the fourth.
+    let (keys, values) = prefix.layer(1).unwrap();
+    assert_eq!(keys.dims(), [2, 2, 3]);
+    assert_eq!(keys.i((1, 1, 2))?.to_scalar::<f32>()?, 41.);
+    assert_eq!(values.i((0, 0, 0))?.to_scalar::<f32>()?, 18.);
+    assert!(prefix.layer(2).is_none());
+
+    let k = Tensor::zeros((3, 2, 4, 3), DType::F32, &device)?;
+    let (k, v) = prefix.prepend(1, &k, &k)?;
+    assert_eq!(k.dims(), [3, 2, 6, 3]);
+    assert_eq!(
+        k.i((2, 0..2, 0..2))?.to_vec3::<f32>()?,
+        keys.to_vec3::<f32>()?
+    );
+    assert_eq!(
+        v.i((1, 0..2, 0..2))?.to_vec3::<f32>()?,
+        values.to_vec3::<f32>()?
+    );
+    let wrong_heads = Tensor::zeros((1, 4, 1, 3), DType::F32, &device)?;
+    assert!(prefix.prepend(0, &wrong_heads, &wrong_heads).is_err());
+    Ok(())
+}
+
+#[test]
+fn prompt_conversion_checks_the_adapter() -> Result<()> {
+    let device = Device::Cpu;
+    // A LoRA config is rejected.
+    let mut lora = peft_config("PROMPT_TUNING", false);
+    lora.peft_type = "LORA".to_string();
+    assert!(lora.validate().is_err());
+
+    // Embeddings of the wrong shape.
+    let config = peft_config("PREFIX_TUNING", false);
+    let embeddings = Tensor::zeros((2, 6), DType::F32, &device)?;
+    assert!(convert("prefix_wrong_shape", &config, embeddings.clone()).is_err());
+
+    // The unprojected embeddings PEFT saves in inference mode with a prefix projection.
+    let config = peft_config("PREFIX_TUNING", true);
+    let err = match convert("prefix_projection", &config, embeddings) {
+        Ok(_) => panic!("converted the unprojected prefix embeddings"),
+        Err(err) => err,
+    };
+    assert!(err.to_string().contains("projection"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn trainable_prefix_key_values_are_created() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = PrefixTuningConfig::new(4, 3, 2, 8);
+    let prefix = PrefixKeyValues::new(&config, &vb)?;
+    assert_eq!(prefix.num_layers(), 3);
+    assert_eq!(prefix.layer(0).unwrap().0.dims(), [2, 4, 8]);
+    let vars = varmap.data().lock().unwrap();
+    assert_eq!(vars["prefix_key_values.weight"].dims(), [4, 96]);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{adapter_sparsity, prune_adapter, prune_checkpoint, prune_varmap, Pruning};
+use candle_nn::VarMap;
+
+fn adapter(device: &Device) -> Result<HashMap<String, Tensor>> {
+    Ok(HashMap::from([
+        (
+            "lora.a0.weight".to_string(),
+            Tensor::new(&[[0.01f32, -2.], [0.5, -0.02]], device)?,
+        ),
+        (
+            "lora.b0.weight".to_string(),
+            Tensor::new(&[[4f32, -3.], [1., 2.]], device)?.to_dtype(DType::F16)?,
+        ),
+    ]))
+}
+
+#[test]
+fn threshold_and_per_tensor_sparsity() -> Result<()> {
+    let device = Device::Cpu;
+    let pruned = prune_adapter(&adapter(&device)?, Pruning::Threshold(0.1))?;
+    assert_eq!(
+        pruned["lora.a0.weight"].to_vec2::<f32>()?,
+        [[0., -2.], [0.5, 0.]]
+    );
+    assert_eq!(pruned["lora.b0.weight"].dtype(), DType::F16);
+    assert_eq!(adapter_sparsity(&pruned)?, 0.25);
+
+    // Half of each tensor, whatever the scale of its entries.
+    let pruned = prune_adapter(&adapter(&device)?, Pruning::Sparsity(0.5))?;
+    assert_eq!(
+        pruned["lora.a0.weight"].to_vec2::<f32>()?,
+        [[0., -2.], [0.5, 0.]]
+    );
+    assert_eq!(
+        pruned["lora.b0.weight"]
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?,
+        [[4., -3.], [0., 0.]]
+    );
+
+    assert!(prune_adapter(&adapter(&device)?, Pruning::Sparsity(1.5)).is_err());
+    assert!(prune_adapter(&adapter(&device)?, Pruning::Threshold(-1.)).is_err());
+    Ok(())
+}
+
+#[test]
+fn global_sparsity_prunes_the_smallest_tensor_more() -> Result<()> {
+    let device = Device::Cpu;
+    let pruned = prune_adapter(&adapter(&device)?, Pruning::GlobalSparsity(0.5))?;
+    assert_eq!(
+        pruned["lora.a0.weight"].to_vec2::<f32>()?,
+        [[0., -2.], [0., 0.]]
+    );
+    assert_eq!(
+        pruned["lora.b0.weight"]
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?,
+        [[4., -3.], [0., 2.]]
+    );
+    assert_eq!(adapter_sparsity(&pruned)?, 0.5);
+    let unpruned = prune_adapter(&adapter(&device)?, Pruning::GlobalSparsity(0.))?;
+    assert_eq!(adapter_sparsity(&unpruned)?, 0.);
+    Ok(())
+}
+
+#[test]
+fn prune_varmap_and_checkpoint() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    for (name, tensor) in adapter(&device)? {
+        let var = candle_core::Var::from_tensor(&tensor.to_dtype(DType::F32)?)?;
+        varmap.data().lock().unwrap().insert(name, var);
+    }
+    prune_varmap(&varmap, Pruning::Threshold(0.1))?;
+    let data = varmap.data().lock().unwrap();
+    assert_eq!(
+        data["lora.a0.weight"].to_vec2::<f32>()?,
+        [[0., -2.], [0.5, 0.]]
+    );
+    drop(data);
+
+    let dir = std::env::temp_dir();
+    let input = dir.join("candle_lora_pruning_in.safetensors");
+    let output = dir.join("candle_lora_pruning_out.safetensors");
+    candle_core::safetensors::save(&adapter(&device)?, &input)?;
+    prune_checkpoint(
+        input.to_str().unwrap(),
+        output.to_str().unwrap(),
+        Pruning::Sparsity(0.25),
+        &device,
+    )?;
+    let pruned = candle_core::safetensors::load(&output, &device)?;
+    assert_eq!(adapter_sparsity(&pruned)?, 0.25);
+    Ok(())
+}
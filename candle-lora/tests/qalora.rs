@@ -0,0 +1,113 @@
+use candle_core::{DType, Device, Module, Result, Tensor, Var};
+use candle_lora::{
+    GroupQuantizedLinear, LinearLayerLike, LoraConfig, LoraLinearConfig, LoraTrainer, Merge,
+    QaLoraLinear, TrainingConfig,
+};
+use candle_nn::{AdamW, ParamsAdamW, VarBuilder, VarMap};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn group_quantization_error_is_bounded() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (6, 16), &device)?;
+    let quantized = GroupQuantizedLinear::quantize(&weight, None, 4, 8)?;
+    assert_eq!(quantized.codes().dims(), &[6, 16]);
+    assert_eq!(quantized.scales().dims(), &[6, 2]);
+    let max_scale = quantized.scales().max_all()?.to_scalar::<f32>()?;
+    assert!(max_abs_diff(quantized.weight(), &weight)? <= max_scale / 2. + 1e-6);
+    assert!(quantized.codes().max_all()?.to_scalar::<u8>()? <= 15);
+
+    assert!(GroupQuantizedLinear::quantize(&weight, None, 4, 5).is_err());
+    assert!(GroupQuantizedLinear::quantize(&weight, None, 9, 8).is_err());
+    Ok(())
+}
+
+#[test]
+fn merge_keeps_codes_and_matches_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (6, 16), &device)?;
+    let bias = Tensor::randn(0f32, 1., 6, &device)?;
+    let quantized = GroupQuantizedLinear::quantize(&weight, Some(&bias), 4, 4)?;
+
+    // Non-zero A and B, as after training.
+    let varmap = VarMap::new();
+    for (shape, name) in [((2, 4), "a0.weight"), ((6, 2), "b0.weight")] {
+        let var = Var::randn(0f32, 1., shape, &device)?;
+        varmap.data().lock().unwrap().insert(name.to_string(), var);
+    }
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut lora = QaLoraLinear::new(
+        &quantized,
+        &LoraLinearConfig::new(16, 6),
+        &LoraConfig::new(2, 4., None),
+        &vb,
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (3, 5, 16), &device)?;
+    let unmerged = lora.forward(&xs)?;
+    let delta = lora.get_delta_weight().unwrap();
+    assert_eq!(delta.dims(), &[6, 16]);
+    // The delta is constant within each group of 4 input features.
+    let groups = delta.reshape((6, 4, 4))?;
+    let spread = (groups.max_keepdim(2)? - groups.min_keepdim(2)?)?;
+    assert_eq!(spread.max_all()?.to_scalar::<f32>()?, 0.);
+
+    lora.merge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &unmerged)? < 1e-4);
+    let merged = lora.quantized();
+    assert_eq!(
+        merged.codes().to_vec2::<u8>()?,
+        quantized.codes().to_vec2::<u8>()?
+    );
+    assert_eq!(
+        merged.scales().to_vec2::<f32>()?,
+        quantized.scales().to_vec2::<f32>()?
+    );
+    assert!(max_abs_diff(merged.weight(), &(quantized.weight() + &delta)?)? < 1e-5);
+
+    lora.unmerge_weights().unwrap();
+    assert!(max_abs_diff(lora.quantized().mins(), quantized.mins())? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn trained_adapter_merges_without_quantization_error() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (4, 8), &device)?;
+    let quantized = GroupQuantizedLinear::quantize(&weight, None, 3, 4)?;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let mut lora = QaLoraLinear::new(
+        &quantized,
+        &LoraLinearConfig::new(8, 4),
+        &LoraConfig::new(2, 2., None),
+        &vb,
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (32, 8), &device)?;
+    let target = Tensor::randn(0f32, 1., (32, 4), &device)?;
+    let loss_fn = |lora: &QaLoraLinear| (lora.forward(&xs)? - &target)?.sqr()?.mean_all();
+    let initial_loss = loss_fn(&lora)?.to_scalar::<f32>()?;
+    let config = TrainingConfig {
+        learning_rate: 0.05,
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<AdamW>::new(varmap.all_vars(), ParamsAdamW::default(), config)?;
+    for _ in 0..50 {
+        trainer.backward_step(&loss_fn(&lora)?)?;
+    }
+    let trained = lora.forward(&xs)?;
+    assert!(loss_fn(&lora)?.to_scalar::<f32>()? < initial_loss);
+
+    lora.merge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &trained)? < 1e-4);
+    assert_eq!(
+        lora.quantized().codes().to_vec2::<u8>()?,
+        quantized.codes().to_vec2::<u8>()?
+    );
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Device, Module, Result, Tensor,
+};
+use candle_lora::{
+    AdapterSwap, LoraConfig, LoraLinearConfig, LoraQuantizedLinear, Merge, QuantizedLinear,
+};
+use candle_nn::{Linear, VarBuilder};
+
+mod common;
+use common::{max_abs_diff, trainable_adapter_vb};
+
+/// The quantized matmul quantizes its input too, so outputs are compared relatively.
+fn assert_close(actual: &Tensor, expected: &Tensor, relative: f32) -> Result<()> {
+    let magnitude = expected.abs()?.max_all()?.to_scalar::<f32>()?;
+    let diff = max_abs_diff(actual, expected)?;
+    assert!(
+        diff <= relative * magnitude,
+        "{diff} > {relative} * {magnitude}"
+    );
+    Ok(())
+}
+
+/// Non-zero A and B, as after training, in `dtype`.
+fn adapter(dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+    let tensors = [
+        ("lora.a0.weight", Tensor::randn(0f32, 1., (4, 64), device)?),
+        ("lora.b0.weight", Tensor::randn(0f32, 1., (8, 4), device)?),
+    ];
+    trainable_adapter_vb(
+        tensors
+            .into_iter()
+            .map(|(name, tensor)| Ok((name, tensor.to_dtype(dtype)?)))
+            .collect::<Result<Vec<_>>>()?,
+        dtype,
+        device,
+    )
+}
+
+#[test]
+fn adapter_applies_on_the_quantized_base() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (8, 64), &device)?;
+    let bias = Tensor::randn(0f32, 1., 8, &device)?;
+    let base = QuantizedLinear::new(
+        QTensor::quantize(&weight, GgmlDType::Q4_0)?,
+        Some(bias.clone()),
+    )?;
+    let vb = adapter(DType::F16, &device)?;
+    let lora = LoraQuantizedLinear::new(
+        &base,
+        &LoraLinearConfig::new(64, 8),
+        &LoraConfig::new(4, 8., None),
+        &vb.pp("lora"),
+        0,
+    )?;
+
+    // The quantized base matches the dequantized weight, the adapter is computed in f16.
+    let dequantized = base.qtensor().dequantize(&device)?;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 64), &device)?;
+    let delta = lora.get_delta_weight().unwrap().to_dtype(DType::F32)?;
+    let expected = Linear::new((dequantized + delta)?, Some(bias)).forward(&xs)?;
+    let actual = lora.forward(&xs)?;
+    assert_eq!(actual.dtype(), DType::F32);
+    assert_close(&actual, &expected, 0.02)?;
+
+    assert!(LoraQuantizedLinear::new(
+        &base,
+        &LoraLinearConfig::new(32, 8),
+        &LoraConfig::new(4, 8., None),
+        &vb.pp("lora"),
+        0,
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn base_layers_load_from_gguf() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = QTensor::quantize(&Tensor::randn(0f32, 1., (8, 64), &device)?, GgmlDType::Q8_0)?;
+    let bias = QTensor::quantize(&Tensor::randn(0f32, 1., 8, &device)?, GgmlDType::F32)?;
+    let path = std::env::temp_dir().join("candle_lora_qlora_base.gguf");
+    {
+        let mut file = std::fs::File::create(&path)?;
+        gguf_file::write(
+            &mut file,
+            &[],
+            &[
+                ("blk.0.attn_q.weight", &weight),
+                ("blk.0.attn_q.bias", &bias),
+                ("blk.0.attn_k.weight", &weight),
+            ],
+        )?;
+    }
+
+    let mut file = std::fs::File::open(&path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    let q = QuantizedLinear::from_gguf(&content, &mut file, "blk.0.attn_q", &device)?;
+    let k = QuantizedLinear::from_gguf(&content, &mut file, "blk.0.attn_k", &device)?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(q.dims(), (8, 64));
+    assert_eq!(q.qtensor().dtype(), GgmlDType::Q8_0);
+    assert_eq!(q.bias().unwrap().dims(), &[8]);
+    assert!(k.bias().is_none());
+    let xs = Tensor::randn(0f32, 1., (3, 64), &device)?;
+    let expected = Linear::new(weight.dequantize(&device)?, None).forward(&xs)?;
+    assert_close(&k.forward(&xs)?, &expected, 0.02)?;
+    Ok(())
+}
+
+#[test]
+fn merge_requantizes_and_unmerge_restores() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (8, 64), &device)?;
+    let base = QuantizedLinear::new(QTensor::quantize(&weight, GgmlDType::Q8_0)?, None)?;
+    let mut lora = LoraQuantizedLinear::new(
+        &base,
+        &LoraLinearConfig::new(64, 8),
+        &LoraConfig::new(4, 8., None),
+        &adapter(DType::F32, &device)?.pp("lora"),
+        0,
+    )?;
+
+    let xs = Tensor::randn(0f32, 1., (3, 64), &device)?;
+    let unmerged = lora.forward(&xs)?;
+    lora.merge_weights().unwrap();
+    assert_eq!(lora.quantized().qtensor().dtype(), GgmlDType::Q8_0);
+    assert_close(&lora.forward(&xs)?, &unmerged, 0.05)?;
+    assert!(lora.merge_weights().is_err());
+
+    lora.unmerge_weights().unwrap();
+    assert!(max_abs_diff(&lora.forward(&xs)?, &unmerged)? < 1e-5);
+    assert!(lora.unmerge_weights().is_err());
+
+    // A swap of a merged layer merges the new adapter.
+    lora.merge_weights().unwrap();
+    lora.swap_adapter(
+        &adapter(DType::F32, &device)?,
+        &LoraConfig::new(4, 8., None),
+    )
+    .unwrap();
+    lora.unmerge_weights().unwrap();
+    assert!(max_abs_diff(&lora.quantized().forward(&xs)?, &base.forward(&xs)?)? < 1e-5);
+    Ok(())
+}
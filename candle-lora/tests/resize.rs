@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{resize_adapter_rank, resize_checkpoint_rank};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn smaller_ranks_keep_the_scaled_deltas() -> Result<()> {
+    let device = Device::Cpu;
+    let module = "base_model.model.model.layers.0.self_attn.q_proj";
+    let (a_name, b_name) = (
+        format!("{module}.lora_A.weight"),
+        format!("{module}.lora_B.weight"),
+    );
+    let magnitude = format!("{module}.lora_magnitude_vector");
+    // A rank 8 adapter whose delta is of rank 2.
+    let a = Tensor::randn(0f32, 1., (8, 16), &device)?;
+    let b = Tensor::randn(0f32, 1., (12, 2), &device)?.matmul(&Tensor::randn(
+        0f32,
+        1.,
+        (2, 8),
+        &device,
+    )?)?;
+    let tensors = HashMap::from([
+        (a_name.clone(), a.clone()),
+        (b_name.clone(), b.clone()),
+        (magnitude.clone(), Tensor::ones(12, DType::F32, &device)?),
+    ]);
+
+    let resized = resize_adapter_rank(&tensors, 4)?;
+    assert_eq!(resized[&a_name].dims(), &[4, 16]);
+    assert_eq!(resized[&b_name].dims(), &[12, 4]);
+    assert_eq!(
+        max_abs_diff(&resized[&magnitude], &tensors[&magnitude])?,
+        0.
+    );
+    // alpha / 4 * B' A' = alpha / 8 * B A.
+    let delta = resized[&b_name].matmul(&resized[&a_name])?;
+    let expected = (b.matmul(&a)? * 0.5)?;
+    assert!(max_abs_diff(&delta, &expected)? < 1e-4);
+
+    // Truncation keeps the largest singular value of diag(4, 3, 2, 1).
+    let tensors = HashMap::from([
+        (a_name.clone(), Tensor::eye(4, DType::F32, &device)?),
+        (
+            b_name.clone(),
+            Tensor::eye(4, DType::F32, &device)?
+                .broadcast_mul(&Tensor::new(&[4f32, 3., 2., 1.], &device)?)?,
+        ),
+    ]);
+    let resized = resize_adapter_rank(&tensors, 1)?;
+    let delta = resized[&b_name].matmul(&resized[&a_name])?;
+    let expected = Tensor::eye(4, DType::F32, &device)?
+        .broadcast_mul(&Tensor::new(&[1f32, 0., 0., 0.], &device)?)?;
+    assert!(max_abs_diff(&delta, &expected)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn larger_ranks_are_zero_padded() -> Result<()> {
+    let device = Device::Cpu;
+    let a = Tensor::randn(0f32, 1., (2, 3, 3, 3), &device)?;
+    let b = Tensor::randn(0f32, 1., (5, 2, 1, 1), &device)?;
+    let linear_a = Tensor::randn(0f32, 1., (2, 8), &device)?.to_dtype(DType::BF16)?;
+    let linear_b = Tensor::randn(0f32, 1., (4, 2), &device)?.to_dtype(DType::BF16)?;
+    let tensors = HashMap::from([
+        ("unet.conv_in.a0.weight".to_string(), a.clone()),
+        ("unet.conv_in.b0.weight".to_string(), b.clone()),
+        ("lora_llama.a3.weight".to_string(), linear_a.clone()),
+        ("lora_llama.b3.weight".to_string(), linear_b.clone()),
+    ]);
+
+    let resized = resize_adapter_rank(&tensors, 6)?;
+    let new_a = &resized["unet.conv_in.a0.weight"];
+    let new_b = &resized["unet.conv_in.b0.weight"];
+    assert_eq!(new_a.dims(), &[6, 3, 3, 3]);
+    assert_eq!(new_b.dims(), &[5, 6, 1, 1]);
+    assert_eq!(max_abs_diff(&new_a.narrow(0, 0, 2)?, &a)?, 0.);
+    assert_eq!(
+        new_a
+            .narrow(0, 2, 4)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?,
+        0.
+    );
+    let expected = (b.flatten_from(1)? * 3.)?;
+    assert!(max_abs_diff(&new_b.flatten_from(1)?.narrow(1, 0, 2)?, &expected)? < 1e-5);
+
+    let new_a = &resized["lora_llama.a3.weight"];
+    assert_eq!(new_a.dims(), &[6, 8]);
+    assert_eq!(new_a.dtype(), DType::BF16);
+    assert_eq!(resized["lora_llama.b3.weight"].dims(), &[4, 6]);
+    Ok(())
+}
+
+#[test]
+fn checkpoints_are_resized() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let input = dir.join("candle_lora_resize_in.safetensors");
+    let output = dir.join("candle_lora_resize_out.safetensors");
+    let tensors = HashMap::from([
+        (
+            "lora_llama.a0.weight".to_string(),
+            Tensor::randn(0f32, 1., (8, 16), &device)?,
+        ),
+        (
+            "lora_llama.b0.weight".to_string(),
+            Tensor::randn(0f32, 1., (16, 8), &device)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&tensors, &input)?;
+
+    resize_checkpoint_rank(
+        input.to_str().unwrap(),
+        output.to_str().unwrap(),
+        2,
+        &device,
+    )?;
+    let resized = candle_core::safetensors::load(&output, &device)?;
+    assert_eq!(resized["lora_llama.a0.weight"].dims(), &[2, 16]);
+    assert_eq!(resized["lora_llama.b0.weight"].dims(), &[16, 2]);
+
+    assert!(resize_adapter_rank(&tensors, 0).is_err());
+    let no_pairs = HashMap::from([(
+        "lora_llama.a0.weight".to_string(),
+        tensors["lora_llama.a0.weight"].clone(),
+    )]);
+    assert!(resize_adapter_rank(&no_pairs, 2).is_err());
+
+    std::fs::remove_file(&input)?;
+    std::fs::remove_file(&output)?;
+    Ok(())
+}
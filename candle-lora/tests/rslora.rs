@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{
+    convert_peft_dir_to_candle_lora, merge_peft_adapter, merge_peft_adapter_with_config,
+    LoraConfig, LoraLinear, LoraLinearConfig, Merge, PeftConfig,
+};
+use candle_nn::{Linear, VarBuilder};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn rslora_scales_by_the_square_root_of_the_rank() -> Result<()> {
+    let device = Device::Cpu;
+    let base = Linear::new(Tensor::randn(0f32, 1., (6, 8), &device)?, None);
+    let a = Tensor::randn(0f32, 1., (4, 8), &device)?;
+    let b = Tensor::randn(0f32, 1., (6, 4), &device)?;
+    let tensors = HashMap::from([
+        ("lora.a0.weight".to_string(), a.clone()),
+        ("lora.b0.weight".to_string(), b.clone()),
+    ]);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let xs = Tensor::randn(0f32, 1., (3, 8), &device)?;
+
+    // alpha / r = 2, alpha / sqrt(r) = 4.
+    for (use_rslora, scale) in [(false, 2.), (true, 4.)] {
+        let config = LoraConfig::new(4, 8., None).with_rslora(use_rslora);
+        let mut layer = LoraLinear::new(
+            &base,
+            &LoraLinearConfig::new(8, 6),
+            &config,
+            &vb.pp("lora"),
+            0,
+        )?;
+        let delta = (b.matmul(&a)? * scale)?;
+        assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+        let expected = xs.matmul(&(base.weight() + &delta)?.t()?)?;
+        assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+        let merged = layer.merged_linear().unwrap();
+        assert!(max_abs_diff(&merged.forward(&xs)?, &expected)? < 1e-4);
+        layer.merge_weights().unwrap();
+        assert!(max_abs_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+    }
+    Ok(())
+}
+
+#[test]
+fn peft_rslora_adapters_keep_their_scaling() -> Result<()> {
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let peft_dir = dir.join("candle_lora_rslora");
+    let out_path = dir.join("candle_lora_rslora_out.safetensors");
+    std::fs::create_dir_all(&peft_dir)?;
+
+    let module = "model.layers.0.self_attn.q_proj";
+    let adapter = HashMap::from([
+        (
+            format!("base_model.model.{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (4, 8), &device)?,
+        ),
+        (
+            format!("base_model.model.{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (6, 4), &device)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&adapter, peft_dir.join("adapter_model.safetensors"))?;
+    let config = r#"{"peft_type": "LORA", "r": 4, "lora_alpha": 16, "use_rslora": true,
+        "target_modules": ["q_proj"]}"#;
+    std::fs::write(peft_dir.join("adapter_config.json"), config)?;
+
+    let report = convert_peft_dir_to_candle_lora(
+        peft_dir.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "lora",
+        &device,
+    )?;
+    assert!(report.use_rslora);
+    assert_eq!(report.scaling, Some(8.));
+
+    // The converted adapter runs with the rsLoRA scaling.
+    let converted = candle_core::safetensors::load(&out_path, &device)?;
+    let vb = VarBuilder::from_tensors(converted, DType::F32, &device);
+    let base = Linear::new(Tensor::randn(0f32, 1., (6, 8), &device)?, None);
+    let layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(8, 6),
+        &report.lora_config().unwrap(),
+        &vb.pp("lora"),
+        0,
+    )?;
+    let delta = (adapter[&format!("base_model.model.{module}.lora_B.weight")]
+        .matmul(&adapter[&format!("base_model.model.{module}.lora_A.weight")])?
+        * 8.)?;
+    assert!(max_abs_diff(&layer.get_delta_weight().unwrap(), &delta)? < 1e-5);
+
+    // Merging into the base weights too, unlike merging with the plain alpha.
+    let weights = HashMap::from([(format!("{module}.weight"), base.weight().clone())]);
+    let config = serde_json::from_str::<PeftConfig>(config).unwrap();
+    let merged = merge_peft_adapter_with_config(&weights, &adapter, &config)?;
+    let expected = (base.weight() + &delta)?;
+    let name = format!("{module}.weight");
+    assert!(max_abs_diff(&merged[&name], &expected)? < 1e-5);
+    let plain = merge_peft_adapter(&weights, &adapter, 16.)?;
+    assert!(max_abs_diff(&plain[&name], &expected)? > 1e-3);
+
+    std::fs::remove_dir_all(&peft_dir)?;
+    std::fs::remove_file(&out_path)?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::{collections::HashMap, sync::Arc, thread};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{AdapterStore, LoraConfig, LoraLinear, LoraLinearConfig};
+use candle_nn::{Linear, Module};
+
+fn adapter(value: f32, device: &Device) -> Result<HashMap<String, Tensor>> {
+    Ok(HashMap::from([
+        (
+            "lora.a0.weight".to_string(),
+            Tensor::full(value, (2, 4), device)?,
+        ),
+        (
+            "lora.b0.weight".to_string(),
+            Tensor::full(value, (4, 2), device)?,
+        ),
+    ]))
+}
+
+#[test]
+fn handles_outlive_eviction() -> Result<()> {
+    let device = Device::Cpu;
+    let store = AdapterStore::new();
+    assert!(store.insert("style", adapter(1., &device)?).is_none());
+    store.insert("domain", adapter(2., &device)?);
+    assert_eq!(store.names(), ["domain", "style"]);
+
+    let style = store.get("style").unwrap();
+    assert_eq!(style.size_in_bytes(), 2 * 8 * 4);
+    let replaced = store.insert("style", adapter(3., &device)?).unwrap();
+    assert!(Arc::ptr_eq(&replaced, &style));
+    store.remove("style");
+    assert!(!store.contains("style"));
+
+    // The handle still builds layers after the adapter left the store.
+    let base = Linear::new(Tensor::zeros((4, 4), DType::F32, &device)?, None);
+    let layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(4, 4),
+        &LoraConfig::new(2, 2., None),
+        &style.var_builder(DType::F32, &device).pp("lora"),
+        0,
+    )?;
+    let out = layer.forward(&Tensor::ones((1, 4), DType::F32, &device)?)?;
+    assert_eq!(out.sum_all()?.to_scalar::<f32>()?, 32.);
+    Ok(())
+}
+
+#[test]
+fn concurrent_readers_and_writer() -> Result<()> {
+    let device = Device::Cpu;
+    let store = AdapterStore::new();
+    store.insert("shared", adapter(1., &device)?);
+
+    let readers = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    let adapter = store.get("shared").unwrap();
+                    let value = adapter.tensors()["lora.a0.weight"]
+                        .mean_all()
+                        .unwrap()
+                        .to_scalar::<f32>()
+                        .unwrap();
+                    assert!(value == 1. || value == 2.);
+                    let _ = store.get("transient");
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for i in 0..50 {
+        store.insert("shared", adapter((1 + i % 2) as f32, &device)?);
+        store.insert("transient", adapter(0., &device)?);
+        store.remove("transient");
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(store.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn memory_cap_evicts_least_recently_used_unused_adapters() -> Result<()> {
+    let device = Device::Cpu;
+    // Each adapter takes 64 bytes, two of them fit.
+    let store = AdapterStore::with_capacity(150);
+    assert_eq!(store.capacity(), Some(150));
+    store.insert("a", adapter(1., &device)?);
+    store.insert("b", adapter(2., &device)?);
+    assert_eq!(store.size_in_bytes(), 128);
+
+    // "a" is used more recently than "b", which is evicted.
+    let _ = store.get("a");
+    store.insert("c", adapter(3., &device)?);
+    assert_eq!(store.names(), ["a", "c"]);
+
+    // Adapters in use are kept, even over the cap.
+    let a = store.get("a").unwrap();
+    let c = store.get("c").unwrap();
+    assert!(store.in_use("a"));
+    store.insert("d", adapter(4., &device)?);
+    assert_eq!(store.names(), ["a", "c", "d"]);
+    assert_eq!(store.size_in_bytes(), 192);
+    drop(a);
+    assert!(!store.in_use("a"));
+    assert_eq!(store.evict_unused(), ["a"]);
+    assert_eq!(store.names(), ["c", "d"]);
+
+    // A handle returned by `load` keeps the loaded adapter.
+    let path = std::env::temp_dir().join("candle_lora_store_capacity.safetensors");
+    candle_core::safetensors::save(&adapter(5., &device)?, &path)?;
+    let e = store.load("e", &path, &device)?;
+    assert_eq!(store.names(), ["c", "e"]);
+    assert_eq!(store.set_capacity(Some(64)), Vec::<String>::new());
+    drop((c, e));
+    assert_eq!(store.evict_unused(), ["c"]);
+    assert!(store.set_capacity(None).is_empty());
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{
+    LinearLayerLike, Lora, LoraConfig, LoraLinearConfig, PeftConfig, SelectedLayersBuilder,
+    TargetSpec,
+};
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+#[test]
+fn target_spec_matches_modules_and_layers() -> Result<()> {
+    let spec = TargetSpec::new()
+        .with_module_pattern(r"self_attn\.(q|v)_proj$")?
+        .with_layers(20..=31);
+    assert!(spec.matches("model.layers.20.self_attn.q_proj"));
+    assert!(spec.matches("model.layers.31.self_attn.v_proj"));
+    assert!(!spec.matches("model.layers.19.self_attn.q_proj"));
+    assert!(!spec.matches("model.layers.20.self_attn.k_proj"));
+    assert!(!spec.matches("model.layers.20.mlp.up_proj"));
+    // Layers without an index are not in any range.
+    assert!(!spec.matches("self_attn.q_proj"));
+    assert_eq!(spec.layer_index("transformer.h.7.attn.c_attn"), Some(7));
+
+    let spec = TargetSpec::new()
+        .with_layers(1..=1)
+        .with_layers_pattern("blocks")?;
+    assert!(spec.matches("encoder.blocks.1.attn"));
+    assert!(!spec.matches("encoder.layers.1.attn"));
+    assert!(TargetSpec::new().matches("lm_head"));
+    assert!(TargetSpec::new().with_module_pattern("(").is_err());
+    Ok(())
+}
+
+#[test]
+fn only_targeted_layers_are_converted() -> Result<()> {
+    let device = Device::Cpu;
+    let names = (0..4)
+        .flat_map(|i| [format!("layers.{i}.q_proj"), format!("layers.{i}.up_proj")])
+        .collect::<Vec<_>>();
+    let linear = Linear::new(Tensor::zeros((6, 6), DType::F32, &device)?, None);
+    let layers = names
+        .iter()
+        .map(|name| (name.clone(), &linear as &dyn LinearLayerLike))
+        .collect::<HashMap<_, _>>();
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(6, 6))
+        .build();
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let target = TargetSpec::new()
+        .with_module_pattern("q_proj$")?
+        .with_layers(2..=3);
+    let config = LoraConfig::new(2, 4., None).with_target(target);
+    let new = Lora::convert_model(selected, config, &vb.pp("model"))?;
+
+    let mut converted = new.linear.keys().cloned().collect::<Vec<_>>();
+    converted.sort();
+    assert_eq!(converted, ["layers.2.q_proj", "layers.3.q_proj"]);
+    // The converted layers are numbered among themselves.
+    let mut vars = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    vars.sort();
+    assert_eq!(
+        vars,
+        [
+            "model.a0.weight",
+            "model.a1.weight",
+            "model.b0.weight",
+            "model.b1.weight"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn peft_config_target_spec() -> Result<()> {
+    let config: PeftConfig = serde_json::from_str(
+        r#"{"peft_type": "LORA", "r": 8, "lora_alpha": 16, "target_modules": ["q_proj", "v_proj"],
+            "layers_to_transform": [0, 2], "layers_pattern": "layers"}"#,
+    )
+    .unwrap();
+    assert_eq!(config.layers_to_transform, Some(vec![0, 2]));
+    assert_eq!(config.layers_pattern, Some(vec!["layers".to_string()]));
+    let spec = config.target_spec()?;
+    assert!(spec.matches("model.layers.2.self_attn.lora_llama_csa.v_proj"));
+    assert!(!spec.matches("model.layers.1.self_attn.lora_llama_csa.v_proj"));
+    assert!(!spec.matches("model.layers.0.self_attn.lora_llama_csa.k_proj"));
+    assert!(!spec.matches("model.layers.0.self_attn.lora_llama_csa.xq_proj"));
+
+    let config: PeftConfig = serde_json::from_str(
+        r#"{"peft_type": "LORA", "r": 8, "lora_alpha": 16, "target_modules": ["q_proj"],
+            "layers_to_transform": 3}"#,
+    )
+    .unwrap();
+    assert_eq!(config.layers_to_transform, Some(vec![3]));
+    assert_eq!(config.layers_pattern, None);
+    assert!(config.target_spec()?.matches("transformer.h.3.attn.q_proj"));
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_lora::{LoraConfig, LoraLinear, LoraLinearConfig, Merge, Saveable, TiedLoraConfig};
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+mod common;
+use common::max_abs_diff;
+
+fn base_layers(device: &Device) -> Result<Vec<Linear>> {
+    (0..3)
+        .map(|_| Ok(Linear::new(Tensor::randn(0f32, 1., (6, 8), device)?, None)))
+        .collect()
+}
+
+fn lora_layers(bases: &[Linear], vb: &VarBuilder, config: &LoraConfig) -> Result<Vec<LoraLinear>> {
+    bases
+        .iter()
+        .enumerate()
+        .map(|(i, base)| {
+            LoraLinear::new(
+                base,
+                &LoraLinearConfig::new(8, 6),
+                config,
+                &vb.pp(format!("layers.{i}.lora_linear")),
+                i,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn tied_layers_share_a_and_b() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = LoraConfig::new(4, 8., None).with_tied(TiedLoraConfig::default());
+    let layers = lora_layers(&base_layers(&device)?, &vb, &config)?;
+
+    let mut names = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "layers.0.lora_linear.u0.weight",
+            "layers.0.lora_linear.v0.weight",
+            "layers.1.lora_linear.u1.weight",
+            "layers.1.lora_linear.v1.weight",
+            "layers.2.lora_linear.u2.weight",
+            "layers.2.lora_linear.v2.weight",
+            "tied_lora.a_8.weight",
+            "tied_lora.b_6.weight",
+        ]
+    );
+    // 4 * 8 + 6 * 4 shared and 6 + 4 per layer, instead of 3 * (4 * 8 + 6 * 4).
+    let num_params = varmap
+        .all_vars()
+        .iter()
+        .map(|var| var.elem_count())
+        .sum::<usize>();
+    assert_eq!(num_params, 56 + 3 * 10);
+
+    let mut tensors = HashMap::new();
+    for layer in &layers {
+        layer.get_tensors(&mut tensors)?;
+    }
+    let mut saved = tensors.keys().cloned().collect::<Vec<_>>();
+    saved.sort();
+    assert_eq!(saved, names);
+    Ok(())
+}
+
+#[test]
+fn untied_b_is_per_layer() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let tied = TiedLoraConfig {
+        share_b: false,
+        ..Default::default()
+    };
+    let config = LoraConfig::new(4, 8., None).with_tied(tied);
+    lora_layers(&base_layers(&device)?, &vb, &config)?;
+    let data = varmap.data().lock().unwrap();
+    assert!(data.contains_key("tied_lora.a_8.weight"));
+    assert!(!data.contains_key("tied_lora.b_6.weight"));
+    assert!(data.contains_key("layers.2.lora_linear.b2.weight"));
+    Ok(())
+}
+
+#[test]
+fn tied_merge_and_reload_match_forward() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = LoraConfig::new(4, 8., None).with_tied(TiedLoraConfig::default());
+    let bases = base_layers(&device)?;
+    let mut layers = lora_layers(&bases, &vb, &config)?;
+    // Trained values for all the adapter weights.
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 1., var.shape(), &device)?)?;
+    }
+
+    let xs = Tensor::randn(0f32, 1., (2, 8), &device)?;
+    let outputs = layers
+        .iter()
+        .map(|layer| layer.forward(&xs))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut tensors = HashMap::new();
+    for layer in &layers {
+        layer.get_tensors(&mut tensors)?;
+    }
+    let loaded_vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let loaded = lora_layers(&bases, &loaded_vb, &config)?;
+    for (i, layer) in layers.iter_mut().enumerate() {
+        let reloaded = loaded[i].forward(&xs)?;
+        assert!(max_abs_diff(&reloaded, &outputs[i])? < 1e-5);
+        layer.merge_weights().unwrap();
+        assert!(max_abs_diff(&layer.forward(&xs)?, &outputs[i])? < 1e-4);
+    }
+    Ok(())
+}
+
+#[test]
+fn tied_layer_reloads_without_prefix() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = LoraConfig::new(4, 8., None).with_tied(TiedLoraConfig::default());
+    let base = &base_layers(&device)?[0];
+    let linear_config = LoraLinearConfig::new(8, 6);
+    let layer = LoraLinear::new(base, &linear_config, &config, &vb, 0)?;
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 1., var.shape(), &device)?)?;
+    }
+
+    let mut tensors = HashMap::new();
+    layer.get_tensors(&mut tensors)?;
+    assert!(tensors.contains_key("u0.weight"));
+    assert!(tensors.contains_key("v0.weight"));
+    let loaded_vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let loaded = LoraLinear::new(base, &linear_config, &config, &loaded_vb, 0)?;
+    let xs = Tensor::randn(0f32, 1., (2, 8), &device)?;
+    assert!(max_abs_diff(&loaded.forward(&xs)?, &layer.forward(&xs)?)? < 1e-5);
+    Ok(())
+}
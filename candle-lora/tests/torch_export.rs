@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use candle_core::{pickle::PthTensors, DType, Device, Result, Tensor};
+use candle_lora::{merge_peft_adapter, save_torch_state_dict};
+
+mod common;
+use common::max_abs_diff;
+
+#[test]
+fn state_dict_round_trips_through_the_pth_reader() -> Result<()> {
+    let device = Device::Cpu;
+    let tensors = HashMap::from([
+        (
+            "model.layers.0.self_attn.q_proj.weight".to_string(),
+            Tensor::randn(0f32, 1., (300, 7), &device)?,
+        ),
+        (
+            "model.norm.weight".to_string(),
+            Tensor::randn(0f32, 1., 7, &device)?.to_dtype(DType::F16)?,
+        ),
+        (
+            "position_ids".to_string(),
+            Tensor::arange(0u32, 5, &device)?,
+        ),
+    ]);
+    let path = std::env::temp_dir().join("candle_lora_torch_export.bin");
+    save_torch_state_dict(&tensors, &path)?;
+
+    let pth = PthTensors::new(&path, None)?;
+    assert_eq!(pth.tensor_infos().len(), 3);
+    for (name, tensor) in &tensors {
+        let loaded = pth.get(name)?.unwrap();
+        assert_eq!(loaded.dims(), tensor.dims());
+        let expected = match tensor.dtype() {
+            DType::U32 => tensor.to_dtype(DType::I64)?,
+            _ => tensor.clone(),
+        };
+        assert_eq!(loaded.dtype(), expected.dtype());
+        let diff = max_abs_diff(
+            &loaded.to_dtype(DType::F32)?,
+            &expected.to_dtype(DType::F32)?,
+        )?;
+        assert_eq!(diff, 0.);
+    }
+    Ok(())
+}
+
+#[test]
+fn merged_peft_adapter_uses_transformers_names() -> Result<()> {
+    let device = Device::Cpu;
+    let base = HashMap::from([
+        (
+            "model.layers.0.self_attn.q_proj.weight".to_string(),
+            Tensor::randn(0f32, 1., (6, 8), &device)?,
+        ),
+        (
+            "transformer.h.0.attn.c_attn.weight".to_string(),
+            Tensor::randn(0f32, 1., (8, 6), &device)?,
+        ),
+        (
+            "model.norm.weight".to_string(),
+            Tensor::randn(0f32, 1., 8, &device)?,
+        ),
+    ]);
+    let mut adapter = HashMap::new();
+    for module in [
+        "model.layers.0.self_attn.q_proj",
+        "transformer.h.0.attn.c_attn",
+    ] {
+        adapter.insert(
+            format!("base_model.model.{module}.lora_A.weight"),
+            Tensor::randn(0f32, 1., (2, 8), &device)?,
+        );
+        adapter.insert(
+            format!("base_model.model.{module}.lora_B.weight"),
+            Tensor::randn(0f32, 1., (6, 2), &device)?,
+        );
+    }
+    let merged = merge_peft_adapter(&base, &adapter, 8.)?;
+    assert_eq!(merged.len(), 3);
+
+    let delta = |module: &str| -> Result<Tensor> {
+        adapter[&format!("base_model.model.{module}.lora_B.weight")]
+            .matmul(&adapter[&format!("base_model.model.{module}.lora_A.weight")])?
+            * 4.
+    };
+    let name = "model.layers.0.self_attn.q_proj.weight";
+    let expected = (&base[name] + delta("model.layers.0.self_attn.q_proj")?)?;
+    assert!(max_abs_diff(&merged[name], &expected)? < 1e-5);
+    // fan_in_fan_out weights get the transposed delta.
+    let name = "transformer.h.0.attn.c_attn.weight";
+    let expected = (&base[name] + delta("transformer.h.0.attn.c_attn")?.t()?)?;
+    assert!(max_abs_diff(&merged[name], &expected)? < 1e-5);
+    assert_eq!(
+        max_abs_diff(&merged["model.norm.weight"], &base["model.norm.weight"])?,
+        0.
+    );
+
+    let missing = HashMap::from([(
+        "model.norm.weight".to_string(),
+        base["model.norm.weight"].clone(),
+    )]);
+    assert!(merge_peft_adapter(&missing, &adapter, 8.).is_err());
+    Ok(())
+}
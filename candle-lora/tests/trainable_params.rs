@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor, Var};
+use candle_lora::{
+    freeze_base_weights, LinearLayerLike, Lora, LoraConfig, LoraInit, LoraLinear, LoraLinearConfig,
+    SelectedLayersBuilder, TiedLoraConfig, Trainable,
+};
+use candle_nn::{linear_no_bias, Optimizer, VarBuilder, VarMap, SGD};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum ModelLayers {
+    Up,
+    Down,
+}
+
+impl std::fmt::Display for ModelLayers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Up => write!(f, "up"),
+            Self::Down => write!(f, "down"),
+        }
+    }
+}
+
+#[test]
+fn only_the_adapters_are_trained() -> Result<()> {
+    let device = Device::Cpu;
+
+    // A base model created from a VarMap, frozen before adapting it.
+    let base_map = VarMap::new();
+    linear_no_bias(
+        8,
+        8,
+        VarBuilder::from_varmap(&base_map, DType::F32, &device).pp("up"),
+    )?;
+    linear_no_bias(
+        8,
+        8,
+        VarBuilder::from_varmap(&base_map, DType::F32, &device).pp("down"),
+    )?;
+    let base_vb = freeze_base_weights(&base_map, DType::F32, &device);
+    let up = linear_no_bias(8, 8, base_vb.pp("up"))?;
+    let down = linear_no_bias(8, 8, base_vb.pp("down"))?;
+    assert!(!up.weight().is_variable());
+    let base_weight = up.weight().copy()?;
+
+    let layers = HashMap::from([
+        (ModelLayers::Up, &up as &dyn LinearLayerLike),
+        (ModelLayers::Down, &down as &dyn LinearLayerLike),
+    ]);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(8, 8))
+        .build();
+    let lora_map = VarMap::new();
+    let lora_vb = VarBuilder::from_varmap(&lora_map, DType::F32, &device);
+    let new = Lora::convert_model(selected, LoraConfig::new(2, 4., None), &lora_vb.pp("lora"))?;
+
+    let params = Lora::trainable_params(&new)?;
+    assert_eq!(params.len(), 4);
+    assert_eq!(lora_map.all_vars().len(), 4);
+
+    let mut sgd = SGD::new(params, 0.01)?;
+    let xs = Tensor::randn(0f32, 1., (4, 8), &device)?;
+    let model = |xs: &Tensor| -> Result<Tensor> {
+        new.linear[&ModelLayers::Down].forward(&new.linear[&ModelLayers::Up].forward(xs)?)
+    };
+    let initial = model(&xs)?;
+    for _ in 0..3 {
+        sgd.backward_step(&model(&xs)?.sqr()?.mean_all()?)?;
+    }
+    // The adapter layers see the updated variables, the base weights are untouched.
+    let trained = model(&xs)?;
+    assert!((initial - trained)?.abs()?.sum_all()?.to_scalar::<f32>()? > 0.);
+    assert_eq!(
+        up.weight().flatten_all()?.to_vec1::<f32>()?,
+        base_weight.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn tied_matrices_are_given_once() -> Result<()> {
+    let device = Device::Cpu;
+    let up = candle_nn::Linear::new(Tensor::randn(0f32, 1., (8, 8), &device)?, None);
+    let down = candle_nn::Linear::new(Tensor::randn(0f32, 1., (8, 8), &device)?, None);
+    let layers = HashMap::from([
+        (ModelLayers::Up, &up as &dyn LinearLayerLike),
+        (ModelLayers::Down, &down as &dyn LinearLayerLike),
+    ]);
+    let selected = SelectedLayersBuilder::new()
+        .add_linear_layers(layers, LoraLinearConfig::new(8, 8))
+        .build();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = LoraConfig::new(2, 4., None).with_tied(TiedLoraConfig::default());
+    let new = Lora::convert_model(selected, config, &vb.pp("lora"))?;
+
+    // One shared A and B, and the u and v vectors of each layer.
+    assert_eq!(Lora::trainable_params(&new)?.len(), 6);
+    assert_eq!(varmap.all_vars().len(), 6);
+    Ok(())
+}
+
+#[test]
+fn param_groups_split_a_b_and_magnitudes() -> Result<()> {
+    let device = Device::Cpu;
+    let up = candle_nn::Linear::new(Tensor::randn(0f32, 1., (6, 8), &device)?, None);
+    let convert = |linear_config: LoraLinearConfig, config: LoraConfig| {
+        let layers = HashMap::from([(ModelLayers::Up, &up as &dyn LinearLayerLike)]);
+        let selected = SelectedLayersBuilder::new()
+            .add_linear_layers(layers, linear_config)
+            .build();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        Lora::convert_model(selected, config, &vb.pp("lora"))
+    };
+    let dims = |vars: &[Var]| vars.iter().map(|v| v.dims().to_vec()).collect::<Vec<_>>();
+
+    let new = convert(
+        LoraLinearConfig::new(8, 6).with_dora(),
+        LoraConfig::new(2, 4., None),
+    )?;
+    let (a, b, magnitudes) = Lora::lora_param_groups(&new)?;
+    assert_eq!(dims(&a), [[2, 8]]);
+    assert_eq!(dims(&b), [[6, 2]]);
+    assert_eq!(dims(&magnitudes), [[6]]);
+
+    // Tied matrices are grouped with A and B, their scaling vectors with the magnitudes.
+    let config = LoraConfig::new(2, 4., None).with_tied(TiedLoraConfig::default());
+    let new = convert(LoraLinearConfig::new(8, 6), config)?;
+    let (a, b, vectors) = Lora::lora_param_groups(&new)?;
+    assert_eq!(dims(&a), [[2, 8]]);
+    assert_eq!(dims(&b), [[6, 2]]);
+    assert_eq!(vectors.len(), 2);
+
+    // An optimizer over B only, as with a LoRA+ learning rate of 0 for A, leaves A as it is.
+    let new = convert(LoraLinearConfig::new(8, 6), LoraConfig::new(2, 4., None))?;
+    let (a, b, _) = Lora::lora_param_groups(&new)?;
+    let initial_a = a[0].as_tensor().copy()?;
+    let mut sgd = SGD::new(b, 0.1)?;
+    let xs = Tensor::randn(0f32, 1., (4, 8), &device)?;
+    for _ in 0..2 {
+        let loss = (new.linear[&ModelLayers::Up].forward(&xs)? - 1.)?
+            .sqr()?
+            .mean_all()?;
+        sgd.backward_step(&loss)?;
+    }
+    let diff = (a[0].as_tensor() - initial_a)?.abs()?.max_all()?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    Ok(())
+}
+
+#[test]
+fn gaussian_init_and_constant_adapters() -> Result<()> {
+    let device = Device::Cpu;
+    let base = candle_nn::Linear::new(Tensor::randn(0f32, 1., (16, 512), &device)?, None);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = LoraConfig::new(4, 8., None).with_init(LoraInit::Gaussian);
+    let lora = LoraLinear::new(&base, &LoraLinearConfig::new(512, 16), &config, &vb, 0)?;
+
+    let tensors = lora.trainable_tensors();
+    assert_eq!(tensors[0].0, "a0.weight");
+    let std = tensors[0].1.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?;
+    assert!((std - 0.25).abs() < 0.02, "{std}");
+    assert_eq!(tensors[1].1.abs()?.sum_all()?.to_scalar::<f32>()?, 0.);
+
+    // Adapters loaded from files cannot be trained.
+    let vb = VarBuilder::from_tensors(
+        HashMap::from([
+            (
+                "a0.weight".to_string(),
+                Tensor::zeros((4, 512), DType::F32, &device)?,
+            ),
+            (
+                "b0.weight".to_string(),
+                Tensor::zeros((16, 4), DType::F32, &device)?,
+            ),
+        ]),
+        DType::F32,
+        &device,
+    );
+    let lora = LoraLinear::new(&base, &LoraLinearConfig::new(512, 16), &config, &vb, 0)?;
+    assert!(lora.trainable_params().is_err());
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use candle_core::{backprop::GradStore, DType, Device, Result, Tensor, Var};
+use candle_lora::{
+    clip_grad_norm, delta_l2_penalty, LoraConfig, LoraLinear, LoraLinearConfig, LoraTrainer,
+    LossScaler, LrScheduler, Merge, MixedPrecisionConfig, TrainingConfig,
+};
+use candle_nn::{Linear, Optimizer, VarBuilder, VarMap, SGD};
+
+#[test]
+fn learning_rate_schedules() {
+    let config = |lr_scheduler| TrainingConfig {
+        learning_rate: 1.,
+        lr_scheduler,
+        warmup_steps: 10,
+        total_steps: 110,
+        ..Default::default()
+    };
+    let close = |u: f64, v: f64| (u - v).abs() < 1e-9;
+
+    for scheduler in [
+        LrScheduler::Constant,
+        LrScheduler::Linear,
+        LrScheduler::Cosine,
+    ] {
+        let config = config(scheduler);
+        assert!(close(config.learning_rate_at(0), 0.));
+        assert!(close(config.learning_rate_at(5), 0.5));
+        assert!(close(config.learning_rate_at(10), 1.));
+    }
+    assert!(close(
+        config(LrScheduler::Constant).learning_rate_at(200),
+        1.
+    ));
+    assert!(close(config(LrScheduler::Linear).learning_rate_at(60), 0.5));
+    assert!(close(config(LrScheduler::Linear).learning_rate_at(200), 0.));
+    assert!(close(config(LrScheduler::Cosine).learning_rate_at(60), 0.5));
+    assert!(close(config(LrScheduler::Cosine).learning_rate_at(110), 0.));
+}
+
+#[test]
+fn training_config_from_json() {
+    let config: TrainingConfig = serde_json::from_str(
+        r#"{"learning_rate": 1e-4, "lr_scheduler": "cosine", "warmup_steps": 100,
+            "total_steps": 1000, "max_grad_norm": 1.0}"#,
+    )
+    .unwrap();
+    assert_eq!(config.lr_scheduler, LrScheduler::Cosine);
+    assert_eq!(config.max_grad_norm, Some(1.));
+
+    let config: TrainingConfig = serde_json::from_str("{}").unwrap();
+    assert_eq!(config, TrainingConfig::default());
+}
+
+#[test]
+fn clipping_scales_the_global_norm() -> Result<()> {
+    let device = Device::Cpu;
+    let a = Var::new(&[1f32, 2.], &device)?;
+    let b = Var::new(&[3f32], &device)?;
+    // d/dx sum(x * c) = c, so the gradients are (3, 4) and (12): a global norm of 13.
+    let loss = ((a.as_tensor() * Tensor::new(&[3f32, 4.], &device)?)?.sum_all()?
+        + (b.as_tensor() * 12.)?.sum_all()?)?;
+    let mut grads: GradStore = loss.backward()?;
+    let vars = [a.clone(), b.clone()];
+
+    let norm = clip_grad_norm(&mut grads, &vars, 26.)?;
+    assert!((norm - 13.).abs() < 1e-5);
+    assert_eq!(grads.get(&a).unwrap().to_vec1::<f32>()?, [3., 4.]);
+
+    let norm = clip_grad_norm(&mut grads, &vars, 1.3)?;
+    assert!((norm - 13.).abs() < 1e-5);
+    let clipped = grads.get(&b).unwrap().to_vec1::<f32>()?;
+    assert!((clipped[0] - 1.2).abs() < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn trainer_follows_the_schedule() -> Result<()> {
+    let device = Device::Cpu;
+    let x = Var::new(&[0f32], &device)?;
+    let config = TrainingConfig {
+        learning_rate: 0.1,
+        lr_scheduler: LrScheduler::Linear,
+        warmup_steps: 1,
+        total_steps: 3,
+        max_grad_norm: Some(0.5),
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<SGD>::new(vec![x.clone()], 1., config)?;
+    let mut learning_rates = vec![];
+    for _ in 0..3 {
+        // The gradient is -1 and clipped to -0.5.
+        let loss = x.as_tensor().neg()?.sum_all()?;
+        let info = trainer.backward_step(&loss)?;
+        assert_eq!(info.grad_norm, Some(1.));
+        learning_rates.push(info.learning_rate);
+    }
+    assert_eq!(trainer.steps(), 3);
+    assert_eq!(trainer.optimizer().learning_rate(), learning_rates[2]);
+    let expected = 0.5 * learning_rates.iter().sum::<f64>();
+    assert!((x.to_vec1::<f32>()?[0] as f64 - expected).abs() < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn loss_scaler_backs_off_and_grows() {
+    let mut scaler = LossScaler::new(MixedPrecisionConfig {
+        init_scale: 1024.,
+        growth_interval: 2,
+        ..Default::default()
+    });
+    scaler.update(true);
+    assert_eq!(scaler.scale(), 512.);
+    scaler.update(false);
+    assert_eq!(scaler.scale(), 512.);
+    scaler.update(false);
+    assert_eq!(scaler.scale(), 1024.);
+}
+
+#[test]
+fn mixed_precision_keeps_small_updates() -> Result<()> {
+    let device = Device::Cpu;
+    let x = Var::from_tensor(&Tensor::new(&[1f32], &device)?.to_dtype(DType::F16)?)?;
+    let config = TrainingConfig {
+        learning_rate: 1e-4,
+        mixed_precision: Some(MixedPrecisionConfig {
+            init_scale: 1024.,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<SGD>::new(vec![x.clone()], 1., config)?;
+    for _ in 0..20 {
+        // Each step is below the f16 resolution around 1, only the f32 master sees it.
+        let loss = x.to_dtype(DType::F32)?.sum_all()?;
+        let info = trainer.backward_step(&loss)?;
+        assert!(!info.skipped);
+        assert_eq!(info.loss_scale, Some(1024.));
+    }
+    assert_eq!(x.dtype(), DType::F16);
+    let value = x.to_dtype(DType::F32)?.to_vec1::<f32>()?[0];
+    assert!((value - 0.998).abs() < 5e-4, "{value}");
+    Ok(())
+}
+
+#[test]
+fn mixed_precision_skips_overflowing_steps() -> Result<()> {
+    let device = Device::Cpu;
+    let x = Var::from_tensor(&Tensor::new(&[1f32], &device)?.to_dtype(DType::F16)?)?;
+    let config = TrainingConfig {
+        learning_rate: 0.1,
+        mixed_precision: Some(MixedPrecisionConfig::default()),
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<SGD>::new(vec![x.clone()], 1., config)?;
+    // The scaled gradient 16 * scale overflows f16 until the scale is down to 2048.
+    let mut skipped = 0;
+    loop {
+        let loss = (x.to_dtype(DType::F32)? * 16.)?.sum_all()?;
+        let info = trainer.backward_step(&loss)?;
+        if !info.skipped {
+            assert_eq!(info.loss_scale, Some(2048.));
+            break;
+        }
+        assert_eq!(trainer.steps(), 0);
+        assert_eq!(x.to_dtype(DType::F32)?.to_vec1::<f32>()?, [1.]);
+        skipped += 1;
+    }
+    assert_eq!(skipped, 5);
+    assert_eq!(trainer.steps(), 1);
+    let value = x.to_dtype(DType::F32)?.to_vec1::<f32>()?[0];
+    assert!((value + 0.6).abs() < 1e-3, "{value}");
+    Ok(())
+}
+
+#[test]
+fn delta_l2_shrinks_the_adapter_delta() -> Result<()> {
+    let device = Device::Cpu;
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let base = Linear::new(Tensor::zeros((3, 2), DType::F32, &device)?, None);
+    let layer = LoraLinear::new(
+        &base,
+        &LoraLinearConfig::new(2, 3),
+        &LoraConfig::new(1, 2., None),
+        &vb,
+        0,
+    )?;
+    varmap.set_one("a0.weight", Tensor::new(&[[1f32, 2.]], &device)?)?;
+    varmap.set_one("b0.weight", Tensor::new(&[[1f32], [0.], [1.]], &device)?)?;
+
+    // The delta is 2 * [[1, 2], [0, 0], [1, 2]].
+    let penalty = delta_l2_penalty(&[&layer, &layer])?.to_scalar::<f32>()?;
+    assert_eq!(penalty, 2. * 40.);
+
+    let config = TrainingConfig {
+        learning_rate: 0.01,
+        delta_l2: Some(1.),
+        ..Default::default()
+    };
+    let mut trainer = LoraTrainer::<SGD>::new(varmap.all_vars(), 1., config)?;
+    let loss = Tensor::new(0f32, &device)?;
+    let regularized = trainer.regularize(&loss, &[&layer])?;
+    assert_eq!(regularized.to_scalar::<f32>()?, 40.);
+    for _ in 0..10 {
+        let regularized = trainer.regularize(&loss, &[&layer])?;
+        trainer.backward_step(&regularized)?;
+    }
+    let penalty = delta_l2_penalty(&[&layer])?.to_scalar::<f32>()?;
+    assert!(penalty < 20., "{penalty}");
+    assert!(layer.get_delta_weight().is_ok());
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{layer_groups, LoraTrainer, TrainingConfig, UnfreezeOrder, UnfreezeSchedule};
+use candle_nn::{VarBuilder, VarMap, SGD};
+
+#[test]
+fn schedule_unfreezes_one_layer_per_interval() {
+    let schedule = UnfreezeSchedule {
+        order: UnfreezeOrder::TopDown,
+        initial_layers: 1,
+        interval: 10,
+    };
+    assert_eq!(schedule.num_trainable(0, 4), 1);
+    assert_eq!(schedule.num_trainable(25, 4), 3);
+    assert_eq!(schedule.num_trainable(1000, 4), 4);
+    assert!(schedule.is_trainable(3, 0, 4));
+    assert!(!schedule.is_trainable(2, 9, 4));
+    assert!(schedule.is_trainable(2, 10, 4));
+
+    let bottom_up = UnfreezeSchedule {
+        order: UnfreezeOrder::BottomUp,
+        ..schedule
+    };
+    assert!(bottom_up.is_trainable(0, 0, 4));
+    assert!(!bottom_up.is_trainable(3, 0, 4));
+
+    let schedule: UnfreezeSchedule =
+        serde_json::from_str(r#"{"order": "bottom_up", "interval": 0}"#).unwrap();
+    assert_eq!(schedule.initial_layers, 1);
+    assert_eq!(schedule.num_trainable(0, 4), 4);
+}
+
+#[test]
+fn trainer_only_updates_unfrozen_layers() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let layers = (0..3)
+        .map(|i| {
+            vb.pp(format!("model.layers.{i}.q_proj")).get_with_hints(
+                2,
+                "a0.weight",
+                candle_nn::init::ZERO,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let head = vb.get_with_hints(2, "lm_head.a0.weight", candle_nn::init::ZERO)?;
+
+    let groups = layer_groups(&varmap);
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[1][0].as_tensor().id(), layers[1].id());
+
+    let config = TrainingConfig {
+        learning_rate: 1.,
+        unfreeze: Some(UnfreezeSchedule {
+            order: UnfreezeOrder::TopDown,
+            initial_layers: 1,
+            interval: 2,
+        }),
+        ..Default::default()
+    };
+    let mut trainer =
+        LoraTrainer::<SGD>::new(varmap.all_vars(), 1., config)?.with_layer_groups(groups);
+    let values = || -> Result<Vec<f32>> {
+        layers
+            .iter()
+            .chain([&head])
+            .map(|t| Ok(t.to_vec1::<f32>()?[0]))
+            .collect()
+    };
+    for _ in 0..3 {
+        let loss = Tensor::stack(&[&layers[0], &layers[1], &layers[2], &head], 0)?
+            .neg()?
+            .sum_all()?;
+        trainer.backward_step(&loss)?;
+    }
+    // Steps 0 and 1 train the last layer, step 2 the last two; the head has no layer index.
+    assert_eq!(values()?, [0., 1., 3., 3.]);
+
+    trainer.set_layer_trainable(0, true)?;
+    assert!(trainer.set_layer_trainable(3, true).is_err());
+    assert!(trainer.is_trainable(&trainer.layer_groups()[0][0]));
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_lora::{validate_adapter, BaseModelConfig, ValidationIssue};
+
+fn peft_name(module: &str, matrix: &str) -> String {
+    format!("base_model.model.model.layers.0.{module}.lora_{matrix}.weight")
+}
+
+#[test]
+fn malformed_adapters_are_reported() -> Result<()> {
+    let device = Device::Cpu;
+    let zeros = |shape: (usize, usize), dtype| Tensor::zeros(shape, dtype, &device);
+    // hidden 16, intermediate 32, 4 heads, 2 kv heads: k_proj and v_proj are (8, 16).
+    let base = BaseModelConfig::llama(16, 32, 4, 2, 100).with_dtype(DType::F32);
+
+    let mut adapter = HashMap::new();
+    for (module, a, b) in [
+        ("self_attn.q_proj", (4, 16), (16, 4)),
+        ("self_attn.k_proj", (4, 16), (8, 4)),
+        ("mlp.down_proj", (4, 32), (16, 4)),
+    ] {
+        adapter.insert(peft_name(module, "A"), zeros(a, DType::F32)?);
+        adapter.insert(peft_name(module, "B"), zeros(b, DType::F32)?);
+    }
+    let report = validate_adapter(&base, &adapter);
+    assert_eq!(report.num_modules, 3);
+    assert!(report.is_valid());
+    assert!(report.into_result().is_ok());
+
+    let module = |name: &str| format!("base_model.model.model.layers.0.{name}");
+    // Transposed A and B.
+    adapter.insert(
+        peft_name("self_attn.v_proj", "A"),
+        zeros((16, 4), DType::F32)?,
+    );
+    adapter.insert(
+        peft_name("self_attn.v_proj", "B"),
+        zeros((4, 8), DType::F32)?,
+    );
+    // Ranks that do not match.
+    adapter.insert(
+        peft_name("self_attn.o_proj", "A"),
+        zeros((4, 16), DType::F32)?,
+    );
+    adapter.insert(
+        peft_name("self_attn.o_proj", "B"),
+        zeros((16, 8), DType::F32)?,
+    );
+    // The hidden size of another model.
+    adapter.insert(peft_name("mlp.gate_proj", "A"), zeros((4, 24), DType::F32)?);
+    adapter.insert(peft_name("mlp.gate_proj", "B"), zeros((32, 4), DType::F32)?);
+    // An A without its B, and a B of another dtype.
+    adapter.insert(peft_name("mlp.up_proj", "A"), zeros((4, 16), DType::F32)?);
+    adapter.insert(
+        peft_name("self_attn.q_proj", "B"),
+        zeros((16, 4), DType::F16)?,
+    );
+    // A module the base model does not have.
+    adapter.insert(peft_name("mlp.fc1", "A"), zeros((4, 16), DType::F32)?);
+    adapter.insert(peft_name("mlp.fc1", "B"), zeros((16, 4), DType::F32)?);
+
+    let report = validate_adapter(&base, &adapter);
+    assert_eq!(report.num_modules, 8);
+    assert_eq!(
+        report.issues,
+        [
+            ValidationIssue::DtypeMismatch {
+                module: module("self_attn.q_proj"),
+                expected: DType::F32,
+                found: DType::F16,
+            },
+            ValidationIssue::Transposed {
+                module: module("self_attn.v_proj"),
+            },
+            ValidationIssue::RankMismatch {
+                module: module("self_attn.o_proj"),
+                a_rank: 4,
+                b_rank: 8,
+            },
+            ValidationIssue::ShapeMismatch {
+                module: module("mlp.gate_proj"),
+                expected: (32, 16),
+                found: (32, 24),
+            },
+            ValidationIssue::MissingB {
+                module: module("mlp.up_proj"),
+            },
+            ValidationIssue::UnknownModule {
+                module: module("mlp.fc1"),
+            },
+        ]
+    );
+    let error = report.into_result().unwrap_err().to_string();
+    assert!(
+        error.contains("mlp.up_proj: lora_A has no lora_B"),
+        "{error}"
+    );
+    Ok(())
+}
+
+#[test]
+fn base_model_config_from_weights() -> Result<()> {
+    let device = Device::Cpu;
+    let weights = HashMap::from([
+        (
+            "model.layers.0.self_attn.q_proj.weight".to_string(),
+            Tensor::zeros((16, 16), DType::BF16, &device)?,
+        ),
+        (
+            "model.norm.weight".to_string(),
+            Tensor::zeros(16, DType::BF16, &device)?,
+        ),
+    ]);
+    let base = BaseModelConfig::from_weights(&weights);
+    let adapter = HashMap::from([
+        (
+            peft_name("self_attn.q_proj", "A"),
+            Tensor::zeros((4, 16), DType::F32, &device)?,
+        ),
+        (
+            peft_name("self_attn.q_proj", "B"),
+            Tensor::zeros((16, 4), DType::F32, &device)?,
+        ),
+    ]);
+    let report = validate_adapter(&base, &adapter);
+    assert_eq!(
+        report.issues,
+        [ValidationIssue::DtypeMismatch {
+            module: "base_model.model.model.layers.0.self_attn.q_proj".to_string(),
+            expected: DType::BF16,
+            found: DType::F32,
+        }]
+    );
+    Ok(())
+}